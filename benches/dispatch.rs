@@ -0,0 +1,26 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use redis_starter_rust::{command::CommandParser, resp::Entry};
+
+fn request(parts: &[&str]) -> Vec<Entry> {
+    parts
+        .iter()
+        .map(|part| Entry::Text(part.to_string()))
+        .collect()
+}
+
+/// Cost of turning a decoded request into a command, for a command near the
+/// top of the table, one near the bottom and an unknown one.
+fn dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dispatch");
+    let requests: [&[&str]; 4] = [&["PING"], &["GET", "key"], &["XLEN", "stream"], &["FOOBAR"]];
+    for parts in requests {
+        let args = request(parts);
+        group.bench_function(parts[0], |b| {
+            b.iter(|| CommandParser::new(black_box(&args)).is_ok())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
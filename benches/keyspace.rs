@@ -0,0 +1,74 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use redis_starter_rust::storage::{DashMapStorage, Data, InMemoryStorage, Storage, Value};
+use tokio::runtime::{Builder, Runtime};
+
+const KEYS: usize = 10_000;
+const TASKS: usize = 8;
+const OPS_PER_TASK: usize = 1_000;
+
+fn value() -> Value {
+    Value {
+        value: Data::String(b"value".to_vec()),
+        expiry: None,
+    }
+}
+
+/// Runs `TASKS` clients at once against `storage`, each reading four keys
+/// for every one it writes, keys spread over the whole keyspace.
+async fn mixed_load(storage: Arc<dyn Storage>) {
+    let clients: Vec<_> = (0..TASKS)
+        .map(|task| {
+            let storage = Arc::clone(&storage);
+            tokio::spawn(async move {
+                for op in 0..OPS_PER_TASK {
+                    let key = ((task * 7919 + op * 31) % KEYS).to_string();
+                    if op % 5 == 0 {
+                        storage.set(key, value()).await;
+                    } else {
+                        storage.get(&key).await;
+                    }
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.await.unwrap();
+    }
+}
+
+fn filled(runtime: &Runtime, storage: Arc<dyn Storage>) -> Arc<dyn Storage> {
+    runtime.block_on(async {
+        for key in 0..KEYS {
+            storage.set(key.to_string(), value()).await;
+        }
+    });
+    storage
+}
+
+/// The RwLock shards of the default keyspace against a DashMap, with
+/// clients on several threads contending for them.
+fn keyspace(c: &mut Criterion) {
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(4)
+        .build()
+        .unwrap();
+    let storages = [
+        (
+            "sharded",
+            filled(&runtime, Arc::new(InMemoryStorage::new())),
+        ),
+        ("dashmap", filled(&runtime, Arc::new(DashMapStorage::new()))),
+    ];
+    let mut group = c.benchmark_group("mixed_load");
+    for (name, storage) in storages {
+        group.bench_function(name, |b| {
+            b.iter(|| runtime.block_on(mixed_load(Arc::clone(&storage))))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, keyspace);
+criterion_main!(benches);
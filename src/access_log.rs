@@ -0,0 +1,270 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Magic bytes opening every access log file, including the format version.
+const MAGIC: &[u8; 8] = b"RESIPAL1";
+
+/// Number of rotated files kept next to the live one (`<path>.1` ... `<path>.N`).
+const ROTATED_FILES: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessOp {
+    Get,
+    Set,
+    Del,
+}
+
+impl AccessOp {
+    fn code(self) -> u8 {
+        match self {
+            AccessOp::Get => 0,
+            AccessOp::Set => 1,
+            AccessOp::Del => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> io::Result<Self> {
+        match code {
+            0 => Ok(AccessOp::Get),
+            1 => Ok(AccessOp::Set),
+            2 => Ok(AccessOp::Del),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown access op {}", code),
+            )),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            AccessOp::Get => "get",
+            AccessOp::Set => "set",
+            AccessOp::Del => "del",
+        }
+    }
+}
+
+/// One recorded key access.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Access {
+    pub timestamp_ms: u64,
+    pub op: AccessOp,
+    pub hit: bool,
+    pub key: String,
+}
+
+impl Access {
+    /// Record layout: u64 LE timestamp, u8 op, u8 hit, u32 LE key length, key.
+    fn write_to(&self, out: &mut impl Write) -> io::Result<usize> {
+        out.write_all(&self.timestamp_ms.to_le_bytes())?;
+        out.write_all(&[self.op.code(), self.hit as u8])?;
+        out.write_all(&(self.key.len() as u32).to_le_bytes())?;
+        out.write_all(self.key.as_bytes())?;
+        Ok(14 + self.key.len())
+    }
+
+    /// Reads the next record, `None` at a clean end of input.
+    fn read_from(input: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut timestamp = [0u8; 8];
+        match input.read_exact(&mut timestamp) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let mut fixed = [0u8; 6];
+        input.read_exact(&mut fixed)?;
+        let key_len = u32::from_le_bytes(fixed[2..6].try_into().unwrap()) as usize;
+        let mut key = vec![0u8; key_len];
+        input.read_exact(&mut key)?;
+
+        Ok(Some(Access {
+            timestamp_ms: u64::from_le_bytes(timestamp),
+            op: AccessOp::from_code(fixed[0])?,
+            hit: fixed[1] != 0,
+            key: String::from_utf8_lossy(&key).to_string(),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct LogFile {
+    writer: BufWriter<File>,
+    written: u64,
+}
+
+/// Sampled, size-rotated binary log of key accesses, meant to be replayed
+/// offline against different eviction policies.
+///
+/// Sampling is decided per key rather than per access, so every access to a
+/// sampled key is kept and reuse distances stay meaningful in the trace.
+#[derive(Debug)]
+pub struct AccessLog {
+    path: PathBuf,
+    sample_rate: f64,
+    max_bytes: u64,
+    file: Mutex<LogFile>,
+}
+
+impl AccessLog {
+    pub fn open(path: &str, sample_rate: f64, max_bytes: u64) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let file = open_log_file(&path)?;
+        Ok(Self {
+            path,
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            max_bytes,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, key: &str, op: AccessOp, hit: bool) {
+        if !self.is_sampled(key) {
+            return;
+        }
+        let access = Access {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or_default(),
+            op,
+            hit,
+            key: key.to_string(),
+        };
+        if let Err(err) = self.append(&access) {
+            eprintln!("failed writing access log: {}", err);
+        }
+    }
+
+    /// Pushes buffered records to disk. Records are buffered between calls,
+    /// so callers are expected to flush periodically.
+    pub fn flush(&self) -> io::Result<()> {
+        self.file.lock().unwrap().writer.flush()
+    }
+
+    fn is_sampled(&self, key: &str) -> bool {
+        if self.sample_rate >= 1.0 {
+            return true;
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() as f64 / u64::MAX as f64) < self.sample_rate
+    }
+
+    fn append(&self, access: &Access) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.written += access.write_to(&mut file.writer)? as u64;
+        if file.written >= self.max_bytes {
+            file.writer.flush()?;
+            rotate(&self.path)?;
+            *file = open_log_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        if let Ok(file) = self.file.get_mut() {
+            let _ = file.writer.flush();
+        }
+    }
+}
+
+fn open_log_file(path: &Path) -> io::Result<LogFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut written = file.metadata()?.len();
+    let mut writer = BufWriter::new(file);
+    if written == 0 {
+        writer.write_all(MAGIC)?;
+        written = MAGIC.len() as u64;
+    }
+    Ok(LogFile { writer, written })
+}
+
+fn rotated_path(path: &Path, generation: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", generation));
+    PathBuf::from(rotated)
+}
+
+/// Shifts `<path>.N-1` to `<path>.N` and so on, dropping the oldest file.
+fn rotate(path: &Path) -> io::Result<()> {
+    for generation in (1..ROTATED_FILES).rev() {
+        let from = rotated_path(path, generation);
+        if from.exists() {
+            fs::rename(&from, rotated_path(path, generation + 1))?;
+        }
+    }
+    fs::rename(path, rotated_path(path, 1))
+}
+
+/// Converts a binary access log into CSV with a header row.
+pub fn export_csv(path: &str, out: &mut impl Write) -> io::Result<()> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a resip access log",
+        ));
+    }
+
+    writeln!(out, "timestamp_ms,op,hit,key")?;
+    while let Some(access) = Access::read_from(&mut input)? {
+        writeln!(
+            out,
+            "{},{},{},{}",
+            access.timestamp_ms,
+            access.op.name(),
+            access.hit as u8,
+            csv_field(&access.key)
+        )?;
+    }
+    Ok(())
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_round_trip_records() {
+        let given = Access {
+            timestamp_ms: 1_700_000_000_000,
+            op: AccessOp::Set,
+            hit: true,
+            key: "user:42".to_string(),
+        };
+
+        let mut buf = Vec::new();
+        let written = given.write_to(&mut buf).unwrap();
+        assert_eq!(written, buf.len());
+
+        let mut input = buf.as_slice();
+        assert_eq!(Access::read_from(&mut input).unwrap(), Some(given));
+        assert_eq!(Access::read_from(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn should_quote_csv_fields() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
@@ -0,0 +1,983 @@
+//! The append only file: every write is logged as a request in RESP, the
+//! way clients send them, so the dataset can be rebuilt on startup by
+//! running them all again. Rewriting it starts it over from the dataset as
+//! it stands, as an RDB payload or as the requests that rebuild it.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::{
+    client::ClientState,
+    command::{self, CommandParser},
+    functions::FUNCTIONS,
+    rdb::{self, RdbWriter},
+    resp::{self, format_double, Entry, Limits},
+    storage::{unix_time_ms, Data, Expiry, Storage, StreamId, Value},
+};
+
+/// Most members a rewrite adds in one request, as on Redis.
+const ITEMS_PER_REQUEST: usize = 64;
+
+/// When what was appended is flushed to disk, Redis's `appendfsync`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AppendFsync {
+    /// After every write, before it is replied to.
+    Always,
+    /// Once a second, losing at most that much on a crash.
+    #[default]
+    Everysec,
+    /// Whenever the operating system sees fit.
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(name: &str) -> Option<AppendFsync> {
+        match name.to_lowercase().as_str() {
+            "always" => Some(AppendFsync::Always),
+            "everysec" => Some(AppendFsync::Everysec),
+            "no" => Some(AppendFsync::No),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::Everysec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+/// How the append only file is doing, reported by `INFO persistence`.
+#[derive(Debug)]
+pub struct AofStatus {
+    /// Set by the server once it logs writes to a file.
+    pub enabled: AtomicBool,
+    pub rewrite_in_progress: AtomicBool,
+    pub last_rewrite_ok: AtomicBool,
+    /// Whether the last write to the file, or sync of it, went through.
+    pub last_write_ok: AtomicBool,
+}
+
+pub static AOF_STATUS: AofStatus = AofStatus {
+    enabled: AtomicBool::new(false),
+    rewrite_in_progress: AtomicBool::new(false),
+    last_rewrite_ok: AtomicBool::new(true),
+    last_write_ok: AtomicBool::new(true),
+};
+
+impl AofStatus {
+    /// The AOF fields of the `# Persistence` section of INFO.
+    pub fn info(&self) -> String {
+        let load = |flag: &AtomicBool| flag.load(Ordering::Relaxed);
+        let status = |flag: &AtomicBool| if load(flag) { "ok" } else { "err" };
+        format!(
+            "aof_enabled:{}\r\n\
+             aof_rewrite_in_progress:{}\r\n\
+             aof_last_bgrewrite_status:{}\r\n\
+             aof_last_write_status:{}\r\n",
+            load(&self.enabled) as u8,
+            load(&self.rewrite_in_progress) as u8,
+            status(&self.last_rewrite_ok),
+            status(&self.last_write_ok),
+        )
+    }
+
+    /// Notes how writing to the file went, passing the outcome on.
+    fn written<T>(&self, outcome: io::Result<T>) -> io::Result<T> {
+        self.last_write_ok.store(outcome.is_ok(), Ordering::Relaxed);
+        outcome
+    }
+}
+
+#[derive(Debug)]
+struct Log {
+    file: File,
+    fsync: AppendFsync,
+    /// Whether consecutive APPENDs to a key are written as one.
+    coalesce_appends: bool,
+    /// What was appended since a rewrite began, to follow what it writes.
+    rewrite: Option<Vec<u8>>,
+    /// The key of APPENDs held back to be written as one, and what they
+    /// appended so far.
+    appending: Option<(Entry, Vec<u8>)>,
+}
+
+impl Log {
+    /// Writes the APPENDs held back, if any.
+    fn write_appending(&mut self) -> io::Result<()> {
+        let Some((key, appended)) = self.appending.take() else {
+            return Ok(());
+        };
+        let mut buf = BytesMut::new();
+        Entry::Array(vec![arg("APPEND"), key, Entry::Bulk(appended.into())]).encode(&mut buf);
+        self.file.write_all(&buf)
+    }
+}
+
+#[derive(Debug)]
+pub struct Aof {
+    path: PathBuf,
+    rdb_preamble: bool,
+    log: Mutex<Log>,
+}
+
+impl Aof {
+    /// Opens the file at `path` to append to it, creating it if needed.
+    pub fn open(path: impl AsRef<Path>, fsync: AppendFsync) -> io::Result<Aof> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Aof {
+            path,
+            rdb_preamble: true,
+            log: Mutex::new(Log {
+                file,
+                fsync,
+                coalesce_appends: false,
+                rewrite: None,
+                appending: None,
+            }),
+        })
+    }
+
+    /// Whether rewrites write the dataset as an RDB payload, quicker to
+    /// write and load, rather than as requests. Redis's
+    /// `aof-use-rdb-preamble`, on by default.
+    pub fn with_rdb_preamble(mut self, rdb_preamble: bool) -> Self {
+        self.rdb_preamble = rdb_preamble;
+        self
+    }
+
+    /// Whether consecutive APPENDs to a key are written as one, held back
+    /// until a write to something else or the file is next flushed, so
+    /// logging a value built up a piece at a time takes one request rather
+    /// than one per piece. Never done while every write is synced, as a
+    /// write replied to must be on disk then, nor while rewriting.
+    pub fn with_coalesce_appends(self, coalesce_appends: bool) -> Self {
+        self.set_coalesce_appends(coalesce_appends);
+        self
+    }
+
+    pub fn coalesce_appends(&self) -> bool {
+        self.log.lock().unwrap().coalesce_appends
+    }
+
+    /// Coalesces APPENDs or not from the next write on, writing those held
+    /// back when turned off.
+    pub fn set_coalesce_appends(&self, coalesce_appends: bool) {
+        let mut log = self.log.lock().unwrap();
+        log.coalesce_appends = coalesce_appends;
+        if !coalesce_appends {
+            let _ = AOF_STATUS.written(log.write_appending());
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn fsync(&self) -> AppendFsync {
+        self.log.lock().unwrap().fsync
+    }
+
+    /// Syncs as `fsync` says from the next write on.
+    pub fn set_fsync(&self, fsync: AppendFsync) {
+        let mut log = self.log.lock().unwrap();
+        log.fsync = fsync;
+        if fsync == AppendFsync::Always {
+            let _ = AOF_STATUS.written(log.write_appending());
+        }
+    }
+
+    /// Logs the write `request`, which was replied `reply` and gave a key
+    /// `expiry` if any. It is written right away, and synced too if every
+    /// write is to be.
+    pub fn append(
+        &self,
+        request: &[Entry],
+        reply: &Entry,
+        expiry: Option<Expiry>,
+    ) -> io::Result<()> {
+        let Some(request) = propagated(request, reply, expiry) else {
+            return Ok(());
+        };
+        let request = Entry::Array(request);
+        let mut buf = BytesMut::new();
+        request.encode(&mut buf);
+        self.write(&request, &buf)
+    }
+
+    /// `append` for a write already made into what is `propagated`, and
+    /// `encoded` too as replicas are sent it.
+    pub fn write(&self, request: &Entry, encoded: &[u8]) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let coalesce =
+            log.coalesce_appends && log.fsync != AppendFsync::Always && log.rewrite.is_none();
+        if let (true, Some((key, value))) = (coalesce, appended(request)) {
+            if let Some((held, appended)) = &mut log.appending {
+                if held == key {
+                    appended.extend_from_slice(value);
+                    return Ok(());
+                }
+            }
+            let written = log.write_appending();
+            log.appending = Some((key.clone(), value.to_vec()));
+            return AOF_STATUS.written(written);
+        }
+        if let Some(rewrite) = &mut log.rewrite {
+            rewrite.extend_from_slice(encoded);
+        }
+        let written = log
+            .write_appending()
+            .and_then(|_| log.file.write_all(encoded))
+            .and_then(|_| match log.fsync {
+                AppendFsync::Always => log.file.sync_data(),
+                _ => Ok(()),
+            });
+        AOF_STATUS.written(written)
+    }
+
+    /// Writes what is held back to be written as one, without syncing it.
+    pub fn flush(&self) -> io::Result<()> {
+        AOF_STATUS.written(self.log.lock().unwrap().write_appending())
+    }
+
+    /// Flushes everything appended so far to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let synced = log.write_appending().and_then(|_| log.file.sync_data());
+        AOF_STATUS.written(synced)
+    }
+
+    /// Starts a rewrite, which is to write the dataset as it stands now:
+    /// nothing may be appended until it has been copied. False if a
+    /// rewrite is under way already.
+    pub fn begin_rewrite(&self) -> bool {
+        let mut log = self.log.lock().unwrap();
+        if log.rewrite.is_some() {
+            return false;
+        }
+        // The dataset copied holds them already.
+        let _ = AOF_STATUS.written(log.write_appending());
+        log.rewrite = Some(Vec::new());
+        AOF_STATUS
+            .rewrite_in_progress
+            .store(true, Ordering::Relaxed);
+        true
+    }
+
+    /// Whether a rewrite is under way.
+    pub fn rewriting(&self) -> bool {
+        self.log.lock().unwrap().rewrite.is_some()
+    }
+
+    /// Ends a rewrite by putting in place of the file one holding `entries`,
+    /// the dataset as it stood when the rewrite began, followed by what was
+    /// appended since, then appending to that. The file is left alone if
+    /// this fails.
+    pub fn finish_rewrite(&self, entries: &[(String, Value)], used_mem: usize) -> io::Result<()> {
+        let finished = self.rewrite(entries, used_mem);
+        AOF_STATUS
+            .last_rewrite_ok
+            .store(finished.is_ok(), Ordering::Relaxed);
+        AOF_STATUS
+            .rewrite_in_progress
+            .store(false, Ordering::Relaxed);
+        finished
+    }
+
+    fn rewrite(&self, entries: &[(String, Value)], used_mem: usize) -> io::Result<()> {
+        let written = match self.rdb_preamble {
+            true => self.write_rdb(entries, used_mem),
+            false => self.write_requests(entries),
+        };
+        let mut log = self.log.lock().unwrap();
+        let appended = log.rewrite.take().unwrap_or_default();
+        match written? {
+            Rewritten::Rdb(writer) => writer.finish_with(&appended)?,
+            Rewritten::Requests { mut file, temp } => {
+                let finished = file
+                    .write_all(&appended)
+                    .and_then(|_| file.flush())
+                    .and_then(|_| file.get_ref().sync_all())
+                    .and_then(|_| rdb::put_in_place(&temp, &self.path));
+                if finished.is_err() {
+                    let _ = fs::remove_file(&temp);
+                }
+                finished?;
+            }
+        }
+        log.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    fn write_rdb(&self, entries: &[(String, Value)], used_mem: usize) -> io::Result<Rewritten> {
+        let mut writer = RdbWriter::create(&self.path.to_string_lossy(), used_mem)?;
+        for (key, value) in entries {
+            writer.write_entry(key, value)?;
+        }
+        Ok(Rewritten::Rdb(writer))
+    }
+
+    fn write_requests(&self, entries: &[(String, Value)]) -> io::Result<Rewritten> {
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(format!(".tmp-{}", std::process::id()));
+        let temp = PathBuf::from(temp);
+        let mut file = BufWriter::new(File::create(&temp)?);
+        let mut buf = BytesMut::new();
+        // Function libraries are part of the dataset too.
+        for library in FUNCTIONS.libraries() {
+            let load = vec![
+                arg("FUNCTION"),
+                arg("LOAD"),
+                arg("REPLACE"),
+                arg(library.code),
+            ];
+            Entry::Array(load).encode(&mut buf);
+        }
+        for (key, value) in entries {
+            for request in rebuild(key, value) {
+                Entry::Array(request).encode(&mut buf);
+            }
+            if let Err(err) = file.write_all(&buf) {
+                let _ = fs::remove_file(&temp);
+                return Err(err);
+            }
+            buf.clear();
+        }
+        Ok(Rewritten::Requests { file, temp })
+    }
+}
+
+/// A rewritten file, short of what was appended while it was written.
+enum Rewritten {
+    Rdb(RdbWriter),
+    Requests {
+        file: BufWriter<File>,
+        /// Where it is written, next to the file it replaces.
+        temp: PathBuf,
+    },
+}
+
+/// The requests that make `key` hold `value` again. Only strings can have
+/// expiries set by requests here, so the others are written without theirs;
+/// and a stream's last ID can only be told by adding it, so one whose
+/// newest entries were deleted starts over from its last one left.
+fn rebuild(key: &str, value: &Value) -> Vec<Vec<Entry>> {
+    let key = arg(key);
+    let mut requests = Vec::new();
+    match &value.value {
+        Data::String(bytes) => {
+            let mut request = vec![arg("SET"), key, Entry::Bulk(Bytes::copy_from_slice(bytes))];
+            if let Some(expiry) = value.expiry {
+                request.extend([arg("PXAT"), arg(expiry.unix_ms().to_string())]);
+            }
+            requests.push(request);
+        }
+        Data::Set(set) => {
+            let members: Vec<_> = set.iter().collect();
+            for chunk in members.chunks(ITEMS_PER_REQUEST) {
+                let mut request = vec![arg("SADD"), key.clone()];
+                request.extend(chunk.iter().map(|member| arg(member.as_ref())));
+                requests.push(request);
+            }
+        }
+        Data::SortedSet(zset) => {
+            let members: Vec<_> = zset.iter().collect();
+            for chunk in members.chunks(ITEMS_PER_REQUEST) {
+                let mut request = vec![arg("ZADD"), key.clone()];
+                for (member, score) in chunk {
+                    request.extend([arg(format_double(*score)), arg(*member)]);
+                }
+                requests.push(request);
+            }
+        }
+        Data::Stream(stream) => {
+            let entries: Vec<_> = stream.range(StreamId::MIN, StreamId::MAX).collect();
+            if entries.is_empty() {
+                // Streams left empty are made by adding an entry trimmed
+                // right away. No ID is below 0-1, so that of one never
+                // added to starts there.
+                let last_id = stream.last_id().max(StreamId { ms: 0, seq: 1 });
+                requests.push(vec![
+                    arg("XADD"),
+                    key.clone(),
+                    arg("MAXLEN"),
+                    arg("0"),
+                    arg(last_id.to_string()),
+                    arg("x"),
+                    arg("y"),
+                ]);
+            }
+            for (id, fields) in entries {
+                let mut request = vec![arg("XADD"), key.clone(), arg(id.to_string())];
+                for (field, value) in fields {
+                    request.extend([arg(field.as_str()), arg(value.as_str())]);
+                }
+                requests.push(request);
+            }
+            for (name, group) in stream.groups() {
+                requests.push(vec![
+                    arg("XGROUP"),
+                    arg("CREATE"),
+                    key.clone(),
+                    arg(name),
+                    arg(group.last_delivered().to_string()),
+                ]);
+                for consumer in group.consumers() {
+                    requests.push(vec![
+                        arg("XGROUP"),
+                        arg("CREATECONSUMER"),
+                        key.clone(),
+                        arg(name),
+                        arg(consumer),
+                    ]);
+                }
+                // Claimed back as they were delivered.
+                for (id, pending) in group.pending() {
+                    requests.push(vec![
+                        arg("XCLAIM"),
+                        key.clone(),
+                        arg(name),
+                        arg(pending.consumer.as_str()),
+                        arg("0"),
+                        arg(id.to_string()),
+                        arg("TIME"),
+                        arg(pending.delivered_ms.to_string()),
+                        arg("RETRYCOUNT"),
+                        arg(pending.delivery_count.to_string()),
+                        arg("FORCE"),
+                        arg("JUSTID"),
+                    ]);
+                }
+            }
+        }
+    }
+    requests
+}
+
+/// The key and value of an APPEND `request`.
+fn appended(request: &Entry) -> Option<(&Entry, &[u8])> {
+    let Entry::Array(request) = request else {
+        return None;
+    };
+    let [command, key, value] = &request[..] else {
+        return None;
+    };
+    if !text(command)?.eq_ignore_ascii_case("APPEND") {
+        return None;
+    }
+    match value {
+        Entry::Text(text) => Some((key, text.as_bytes())),
+        Entry::Bulk(bytes) => Some((key, bytes)),
+        _ => None,
+    }
+}
+
+fn text(entry: &Entry) -> Option<&str> {
+    match entry {
+        Entry::Text(text) => Some(text),
+        _ => None,
+    }
+}
+
+fn arg(text: impl Into<String>) -> Entry {
+    Entry::Text(text.into())
+}
+
+/// The IDs of stream entries replied by XCLAIM, with or without JUSTID.
+fn claimed_ids(reply: &Entry) -> Vec<Entry> {
+    let Entry::Array(entries) = reply else {
+        return Vec::new();
+    };
+    entries
+        .iter()
+        .filter_map(|entry| match entry {
+            Entry::Text(id) => Some(arg(id.as_str())),
+            Entry::Array(entry) => entry.first().cloned(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The request that redoes what `request` did given it was replied
+/// `reply`, none if it failed, ran a script, whose writes go on their
+/// own, or loaded an RDB file, after which everything starts over. Those whose effect depends on when or by chance they
+/// ran are pinned down to what they did: expiries are made absolute, the
+/// `expiry` the key was given rather than one worked out again now, stream
+/// IDs and popped members spelled out and claims limited to what was
+/// claimed. Entries read by consumer groups still count as delivered when
+/// they are replayed.
+pub fn propagated(request: &[Entry], reply: &Entry, expiry: Option<Expiry>) -> Option<Vec<Entry>> {
+    let name = request.first().and_then(text).unwrap_or_default();
+    let mut request = request.to_vec();
+    // Scripts go as the writes they made instead.
+    if matches!(
+        name.to_uppercase().as_str(),
+        "EVAL" | "EVALSHA" | "FCALL" | "DEBUG"
+    ) {
+        return None;
+    }
+    if matches!(reply, Entry::Error(..) | Entry::Nil | Entry::NullArray) {
+        return None;
+    }
+    match name.to_uppercase().as_str() {
+        "SET" => {
+            for at in (3..request.len().saturating_sub(1)).step_by(2) {
+                let relative = text(&request[at]).is_some_and(|option| {
+                    option.eq_ignore_ascii_case("EX") || option.eq_ignore_ascii_case("PX")
+                });
+                if let Some(expiry) = expiry.filter(|_| relative) {
+                    request[at] = arg("PXAT");
+                    request[at + 1] = arg(expiry.unix_ms().to_string());
+                }
+            }
+        }
+        "XADD" => {
+            // The ID follows NOMKSTREAM and `MAXLEN|MINID [=|~] threshold
+            // [LIMIT count]`, in any order.
+            let mut at = 2;
+            while let Some(option) = request.get(at).and_then(text) {
+                match option.to_uppercase().as_str() {
+                    "NOMKSTREAM" => at += 1,
+                    "MAXLEN" | "MINID" => {
+                        at += 1;
+                        if matches!(request.get(at).and_then(text), Some("=" | "~")) {
+                            at += 1;
+                        }
+                        at += 1;
+                        if request
+                            .get(at)
+                            .and_then(text)
+                            .is_some_and(|limit| limit.eq_ignore_ascii_case("LIMIT"))
+                        {
+                            at += 2;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+            if at < request.len() {
+                request[at] = reply.clone();
+            }
+        }
+        "SPOP" => {
+            let popped = match reply {
+                Entry::Text(member) => vec![arg(member.as_str())],
+                Entry::Array(members) | Entry::Set(members) => members.clone(),
+                _ => Vec::new(),
+            };
+            if popped.is_empty() {
+                return None;
+            }
+            request = [arg("SREM"), request[1].clone()]
+                .into_iter()
+                .chain(popped)
+                .collect();
+        }
+        "RESTORE" | "RESTORE-ASKING" => {
+            request[0] = arg("RESTORE");
+            let absolute = request
+                .iter()
+                .skip(4)
+                .filter_map(text)
+                .any(|option| option.eq_ignore_ascii_case("ABSTTL"));
+            if let Some(expiry) = expiry.filter(|_| !absolute) {
+                request[2] = arg(expiry.unix_ms().to_string());
+                request.push(arg("ABSTTL"));
+            }
+        }
+        // What left is gone from here, unless only copied.
+        "MIGRATE" => {
+            let copied = request
+                .iter()
+                .skip(6)
+                .filter_map(text)
+                .take_while(|option| !option.eq_ignore_ascii_case("KEYS"))
+                .any(|option| option.eq_ignore_ascii_case("COPY"));
+            if copied || *reply != Entry::ok() {
+                return None;
+            }
+            request = std::iter::once(arg("DEL"))
+                .chain(command::keys(&request).into_iter().map(arg))
+                .collect();
+        }
+        "XCLAIM" => {
+            let ids = claimed_ids(reply);
+            if ids.is_empty() {
+                return None;
+            }
+            let options: Vec<_> = request
+                .iter()
+                .skip(5)
+                .skip_while(|arg| text(arg).and_then(|id| StreamId::parse(id, 0)).is_some())
+                .cloned()
+                .collect();
+            let timed = options.iter().filter_map(text).any(|option| {
+                option.eq_ignore_ascii_case("IDLE") || option.eq_ignore_ascii_case("TIME")
+            });
+            request.truncate(4);
+            request.push(arg("0"));
+            request.extend(ids);
+            request.extend(options);
+            if !timed {
+                request.extend([arg("TIME"), arg(unix_time_ms().to_string())]);
+            }
+        }
+        "XAUTOCLAIM" => {
+            // Deleted entries are dropped from the group by claiming them.
+            let ids: Vec<_> = match reply {
+                Entry::Array(reply) if reply.len() == 3 => claimed_ids(&reply[1])
+                    .into_iter()
+                    .chain(claimed_ids(&reply[2]))
+                    .collect(),
+                _ => Vec::new(),
+            };
+            if ids.is_empty() {
+                return None;
+            }
+            let just_id = request
+                .iter()
+                .filter_map(text)
+                .any(|option| option.eq_ignore_ascii_case("JUSTID"));
+            request.truncate(4);
+            request[0] = arg("XCLAIM");
+            request.push(arg("0"));
+            request.extend(ids);
+            request.extend([arg("TIME"), arg(unix_time_ms().to_string())]);
+            if just_id {
+                request.push(arg("JUSTID"));
+            }
+        }
+        _ => {}
+    }
+    Some(request)
+}
+
+/// Loads the file at `path` into `storage`, the keys of the RDB payload it
+/// starts with if rewritten with one then every request after it,
+/// returning how many requests there were. A missing file holds none. Like
+/// Redis, a file cut short by a crash is loaded up to its last whole
+/// request and trimmed to it, so appending carries on from there.
+pub async fn replay(path: impl AsRef<Path>, storage: &dyn Storage) -> io::Result<usize> {
+    let path = path.as_ref();
+    let mut bytes = match fs::read(path) {
+        Ok(bytes) => Bytes::from(bytes),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let len = bytes.len();
+    if bytes.starts_with(b"REDIS") {
+        let keys = rdb::parse_rdb(&mut bytes).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "failed parsing the RDB preamble of append only file: {}",
+                    err
+                ),
+            )
+        })?;
+        for (key, value) in keys {
+            storage.set(key, value).await;
+        }
+    }
+    let mut buf = BytesMut::from(&bytes[..]);
+    // Whatever was accepted once is loaded back.
+    let limits = Limits {
+        max_bulk_len: usize::MAX,
+        max_multibulk_len: usize::MAX,
+    };
+    let mut client = ClientState::new(0);
+    let mut replayed = 0;
+    while !buf.is_empty() {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        if buf[0] != b'*' {
+            return Err(invalid(format!(
+                "bad request in append only file at byte {}",
+                len - buf.len()
+            )));
+        }
+        let request = match resp::decode(&mut buf, &limits) {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                let valid = len - buf.len();
+                eprintln!(
+                    "append only file ends with an incomplete request, truncating it to {} bytes",
+                    valid
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(valid as u64)?;
+                break;
+            }
+            Err(err) => return Err(invalid(err.0)),
+        };
+        let cmd = CommandParser::new(&request).map_err(|_| {
+            invalid(format!(
+                "unknown or malformed command in append only file: {:?}",
+                request.first()
+            ))
+        })?;
+        cmd.execute(storage, &mut client).await.map_err(|_| {
+            invalid(format!(
+                "failed replaying {:?} from append only file",
+                request.first()
+            ))
+        })?;
+        replayed += 1;
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::storage::InMemoryStorage;
+
+    fn request(args: &[&str]) -> Vec<Entry> {
+        args.iter()
+            .map(|arg| Entry::Text(arg.to_string()))
+            .collect()
+    }
+
+    async fn run(storage: &dyn Storage, args: &[&str]) -> Entry {
+        CommandParser::new(&request(args))
+            .unwrap()
+            .execute(storage, &mut ClientState::new(0))
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn should_pin_down_what_writes_did() {
+        // The key expires when it was set to, however late this runs.
+        let expiry = Expiry::from_unix_ms(unix_time_ms() + 100_000);
+        let logged = propagated(
+            &request(&["SET", "k", "v", "EX", "100"]),
+            &Entry::ok(),
+            Some(expiry),
+        );
+        let at = expiry.unix_ms().to_string();
+        assert_eq!(logged, Some(request(&["SET", "k", "v", "PXAT", &at])));
+        let logged = propagated(
+            &request(&["RESTORE", "k", "100000", "payload"]),
+            &Entry::ok(),
+            Some(expiry),
+        );
+        assert_eq!(
+            logged,
+            Some(request(&["RESTORE", "k", &at, "payload", "ABSTTL"]))
+        );
+
+        let logged = propagated(
+            &request(&["XADD", "s", "MAXLEN", "~", "10", "*", "f", "v"]),
+            &arg("5-0"),
+            None,
+        );
+        assert_eq!(
+            logged,
+            Some(request(&[
+                "XADD", "s", "MAXLEN", "~", "10", "5-0", "f", "v"
+            ]))
+        );
+
+        let logged = propagated(
+            &request(&["SPOP", "s", "2"]),
+            &Entry::Array(vec![arg("a"), arg("b")]),
+            None,
+        );
+        assert_eq!(logged, Some(request(&["SREM", "s", "a", "b"])));
+
+        // Only what was idle long enough got claimed.
+        let logged = propagated(
+            &request(&["XCLAIM", "s", "g", "c", "1000", "1-0", "2-0", "JUSTID"]),
+            &Entry::Array(vec![arg("2-0")]),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            logged[..7],
+            request(&["XCLAIM", "s", "g", "c", "0", "2-0", "JUSTID"])
+        );
+        assert_eq!(logged[7], arg("TIME"));
+
+        assert!(propagated(
+            &request(&["SET", "k", "v"]),
+            &Entry::error("ERR", "no"),
+            None
+        )
+        .is_none());
+        assert!(propagated(&request(&["BZPOPMIN", "z", "1"]), &Entry::NullArray, None).is_none());
+    }
+
+    #[tokio::test]
+    async fn should_replay_what_was_appended() {
+        let path = std::env::temp_dir().join(format!("appendonly-{}.aof", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let aof = Aof::open(&path, AppendFsync::Always).unwrap();
+        let expiry = Expiry::after(Duration::from_secs(100));
+        aof.append(
+            &request(&["SET", "k", "v", "PX", "100000"]),
+            &Entry::ok(),
+            expiry,
+        )
+        .unwrap();
+        aof.append(&request(&["SADD", "s", "a", "b"]), &Entry::Int(2), None)
+            .unwrap();
+        aof.append(&request(&["SPOP", "s"]), &arg("a"), None)
+            .unwrap();
+        aof.append(&request(&["XADD", "x", "*", "f", "v"]), &arg("7-1"), None)
+            .unwrap();
+        let whole = fs::metadata(&path).unwrap().len();
+        // Cut short by a crash in the middle of a request.
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"*3\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1")
+            .unwrap();
+
+        let storage = InMemoryStorage::new();
+        let replayed = replay(&path, &storage).await;
+        let len = fs::metadata(&path).unwrap().len();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(replayed.unwrap(), 4);
+        assert!(storage.get("k").await.unwrap().expiry.is_some());
+        let members = run(&storage, &["SMEMBERS", "s"]).await;
+        assert_eq!(members, Entry::Set(vec![arg("b")]));
+        let entries = run(&storage, &["XRANGE", "x", "-", "+"]).await;
+        assert!(format!("{:?}", entries).contains("7-1"));
+        assert_eq!(len, whole);
+    }
+
+    #[tokio::test]
+    async fn should_coalesce_appends_to_a_key() {
+        let path = std::env::temp_dir().join(format!("coalesced-{}.aof", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let aof = Aof::open(&path, AppendFsync::Everysec)
+            .unwrap()
+            .with_coalesce_appends(true);
+        for (args, len) in [
+            (&["APPEND", "k", "a"][..], 1),
+            (&["APPEND", "k", "b"], 2),
+            (&["APPEND", "other", "x"], 1),
+            (&["APPEND", "k", "c"], 3),
+            (&["APPEND", "k", "d"], 4),
+        ] {
+            aof.append(&request(args), &Entry::Int(len), None).unwrap();
+        }
+        // The last ones are only held back so far.
+        let written = fs::read(&path).unwrap();
+        aof.sync().unwrap();
+
+        let storage = InMemoryStorage::new();
+        let replayed = replay(&path, &storage).await;
+        fs::remove_file(&path).unwrap();
+        assert!(!written.windows(2).any(|window| window == b"cd"));
+        assert_eq!(replayed.unwrap(), 3);
+        assert_eq!(
+            run(&storage, &["GET", "k"]).await,
+            Entry::Bulk("abcd".into())
+        );
+        assert_eq!(
+            run(&storage, &["GET", "other"]).await,
+            Entry::Bulk("x".into())
+        );
+    }
+
+    #[test]
+    fn should_not_hold_appends_back_while_syncing_every_write() {
+        let path = std::env::temp_dir().join(format!("always-{}.aof", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let aof = Aof::open(&path, AppendFsync::Always)
+            .unwrap()
+            .with_coalesce_appends(true);
+        aof.append(&request(&["APPEND", "k", "a"]), &Entry::Int(1), None)
+            .unwrap();
+        let written = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(written, b"*3\r\n$6\r\nAPPEND\r\n$1\r\nk\r\n$1\r\na\r\n");
+    }
+
+    #[tokio::test]
+    async fn should_rewrite_with_or_without_an_rdb_preamble() {
+        let storage = InMemoryStorage::new();
+        for args in [
+            &["SET", "string", "value", "PX", "100000"][..],
+            &["SADD", "set", "a", "1"],
+            &["ZADD", "zset", "1.5", "a", "-inf", "b"],
+            &["XADD", "stream", "1-1", "f", "v"],
+            &["XGROUP", "CREATE", "stream", "group", "0"],
+            &[
+                "XREADGROUP",
+                "GROUP",
+                "group",
+                "consumer",
+                "STREAMS",
+                "stream",
+                ">",
+            ],
+            &["XADD", "empty", "MAXLEN", "0", "5-5", "f", "v"],
+        ] {
+            run(&storage, args).await;
+        }
+
+        for rdb_preamble in [true, false] {
+            let path = std::env::temp_dir().join(format!(
+                "rewrite-{}-{}.aof",
+                rdb_preamble,
+                std::process::id()
+            ));
+            let _ = fs::remove_file(&path);
+            let aof = Aof::open(&path, AppendFsync::No)
+                .unwrap()
+                .with_rdb_preamble(rdb_preamble);
+            aof.append(&request(&["SET", "gone", "value"]), &Entry::ok(), None)
+                .unwrap();
+            assert!(aof.begin_rewrite());
+            assert!(!aof.begin_rewrite());
+            let entries = storage.snapshot().await;
+            // Written while the rewrite is under way, then after it.
+            aof.append(&request(&["SADD", "set", "b"]), &Entry::Int(1), None)
+                .unwrap();
+            aof.finish_rewrite(&entries, storage.used_memory()).unwrap();
+            aof.append(&request(&["SADD", "set", "c"]), &Entry::Int(1), None)
+                .unwrap();
+
+            let rewritten = fs::read(&path).unwrap();
+            let loaded = InMemoryStorage::new();
+            let replayed = replay(&path, &loaded).await;
+            fs::remove_file(&path).unwrap();
+            assert_eq!(rewritten.starts_with(b"REDIS"), rdb_preamble);
+            replayed.unwrap();
+            assert!(loaded.get("gone").await.is_none());
+            let string = loaded.get("string").await.unwrap();
+            assert_eq!(string.expiry, storage.get("string").await.unwrap().expiry);
+            let members = run(&loaded, &["SCARD", "set"]).await;
+            assert_eq!(members, Entry::Int(4));
+            let zset = loaded.get("zset").await.unwrap();
+            assert_eq!(zset.value, storage.get("zset").await.unwrap().value);
+            assert!(loaded.get("empty").await.is_some());
+            for args in [
+                &["XRANGE", "stream", "-", "+"][..],
+                &["XPENDING", "stream", "group"],
+                &["XLEN", "empty"],
+            ] {
+                assert_eq!(run(&loaded, args).await, run(&storage, args).await);
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use tokio::{
+    sync::Notify,
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    command::{BlockingCommand, CommandError},
+    locks::KeyLocks,
+    resp::Entry,
+    storage::Storage,
+};
+
+/// Clients parked on keys by blocking commands, woken whenever one of those
+/// keys is written.
+#[derive(Debug, Default)]
+pub struct Waiters {
+    keys: StdMutex<HashMap<String, Vec<Arc<Notify>>>>,
+}
+
+impl Waiters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parks a new waiter on `keys`. Registering before checking the keys
+    /// guarantees no write slips in between the check and the registration:
+    /// a wake that arrives before the waiter starts awaiting is kept as a
+    /// permit by the `Notify`.
+    pub fn register(&self, keys: &[String]) -> Arc<Notify> {
+        let notify = Arc::new(Notify::new());
+        let mut map = self.keys.lock().unwrap();
+        for key in keys {
+            map.entry(key.clone())
+                .or_default()
+                .push(Arc::clone(&notify));
+        }
+        notify
+    }
+
+    pub fn unregister(&self, keys: &[String], notify: &Arc<Notify>) {
+        let mut map = self.keys.lock().unwrap();
+        for key in keys {
+            if let Some(waiters) = map.get_mut(key) {
+                waiters.retain(|waiter| !Arc::ptr_eq(waiter, notify));
+                if waiters.is_empty() {
+                    map.remove(key);
+                }
+            }
+        }
+    }
+
+    /// Wakes every waiter parked on `key`. They all retry and the ones that
+    /// find nothing left to serve park themselves again.
+    pub fn wake(&self, key: &str) {
+        if let Some(waiters) = self.keys.lock().unwrap().remove(key) {
+            for waiter in waiters {
+                waiter.notify_one();
+            }
+        }
+    }
+}
+
+/// Runs `cmd` until it can be served or its timeout elapses. Each attempt
+/// holds `keys`, those the request names, exclusively like any other write,
+/// which are released while waiting so other writers can make progress.
+/// `served` is given the reply while still holding them, to log the write
+/// in order with the others.
+pub async fn execute_blocking(
+    storage: &dyn Storage,
+    locks: &KeyLocks,
+    keys: &[impl AsRef<str>],
+    cmd: &dyn BlockingCommand,
+    served: impl FnOnce(&Entry),
+) -> Result<Entry, CommandError> {
+    // A timeout too far away to represent is as good as none.
+    let deadline = cmd
+        .timeout()
+        .and_then(|timeout| Instant::now().checked_add(timeout));
+    let waiters = storage.waiters();
+
+    loop {
+        let notify = waiters.register(cmd.keys());
+        let write = locks.write(keys).await;
+        let attempt = cmd.try_execute(storage).await;
+        if !matches!(attempt, Ok(None)) {
+            waiters.unregister(cmd.keys(), &notify);
+        }
+        if let Some(reply) = attempt? {
+            served(&reply);
+            return Ok(reply);
+        }
+        drop(write);
+
+        let woken = match deadline {
+            Some(deadline) => timeout_at(deadline, notify.notified()).await.is_ok(),
+            None => {
+                notify.notified().await;
+                true
+            }
+        };
+        waiters.unregister(cmd.keys(), &notify);
+
+        if !woken {
+            return Ok(Entry::NullArray);
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! State a client carries from one command to the next.
+//!
+//! Storage is shared by every connection; whatever only concerns the client
+//! that sent a command (its name, selected database, ...) lives here and is
+//! handed to `Command::execute` next to the storage. What other clients may
+//! look at through CLIENT LIST is mirrored into the `CLIENTS` registry, and
+//! CLIENT PAUSE holds every client through `PAUSE`. Its subscriptions end
+//! along with it, as does the tracking of keys it read.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    mem,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::{mpsc, Notify},
+    time::sleep_until,
+};
+
+use crate::{
+    pubsub::{Inbox, Message, PUBSUB},
+    resp::{Entry, Protocol},
+    scripting::Effect,
+    server::config::ConfigRequest,
+    storage::Expiry,
+    tracking::{Invalidation, Invalidator, TrackingOptions, TRACKING},
+};
+
+pub struct ClientState {
+    id: u64,
+    /// Set with CLIENT SETNAME or HELLO SETNAME.
+    pub name: Option<String>,
+    /// Database selected with SELECT.
+    pub db: usize,
+    /// Whether the client may run commands, which without a configured
+    /// password it always can.
+    pub authenticated: bool,
+    /// Whether the server asked for a password as of the client's last
+    /// command, for RESET to leave the client as unauthenticated as a new
+    /// one.
+    pub password_required: bool,
+    /// Channels the client is subscribed to.
+    pub subscriptions: HashSet<String>,
+    /// Glob patterns of channels the client is subscribed to.
+    pub patterns: HashSet<String>,
+    /// Shard channels the client is subscribed to.
+    pub shard_subscriptions: HashSet<String>,
+    inbox: Inbox,
+    /// Messages published on the channels the client is subscribed to, for
+    /// the server to pass on.
+    pub messages: mpsc::UnboundedReceiver<Message>,
+    invalidator: Invalidator,
+    /// Keys that changed since the client, or one redirecting to it, read
+    /// them, for the server to pass on.
+    pub invalidations: mpsc::UnboundedReceiver<Invalidation>,
+    /// How the client tracks the keys it reads, if it does.
+    pub tracking: Option<TrackingOptions>,
+    /// Set by CLIENT CACHING for whether the next command's keys are
+    /// remembered despite OPTIN or OPTOUT.
+    pub caching: Option<bool>,
+    /// Replies a command sends ahead of the one it returns, such as
+    /// SUBSCRIBE confirming each channel in turn.
+    pub replies: Vec<Entry>,
+    /// Protocol replies are encoded in, switched with HELLO.
+    pub protocol: Protocol,
+    /// Set by SHUTDOWN once the server may stop.
+    pub shutdown: bool,
+    /// Set by BGREWRITEAOF for the server, which keeps the append only
+    /// file, to rewrite it.
+    pub rewrite_aof: bool,
+    /// Set with DEBUG QUICKACK for the client's requests to be acknowledged
+    /// right away.
+    pub quickack: bool,
+    /// Set by DEBUG LOADRDB once it replaced keys wholesale, for the server
+    /// to start the append only file and replicas over from the dataset.
+    pub reloaded: bool,
+    /// Set by CONFIG GET and CONFIG SET for the server, which most
+    /// parameters belong to, to answer.
+    pub config: Option<ConfigRequest>,
+    /// Port the client serves its own clients on, told with REPLCONF
+    /// listening-port by replicas.
+    pub listening_port: Option<u16>,
+    /// Set by PSYNC to the replication ID and offset the client asked to go
+    /// on from, for the server to make it one of its replicas.
+    pub sync_replica: Option<(String, i64)>,
+    /// Set by writes giving a key an expiry to the one it got, for what is
+    /// logged and propagated to name it rather than one worked out later.
+    pub expiry: Option<Expiry>,
+    /// Set by scripts that may write to the writes they made, each with
+    /// where it goes, for the server to log and propagate those rather
+    /// than the script.
+    pub script_effects: Option<Vec<Effect>>,
+    /// Set by WAIT for the server to wait for that many replicas to catch
+    /// up, for at most that long.
+    pub wait_replicas: Option<(usize, Option<Duration>)>,
+    /// Set by REPLICAOF for the server to follow the master at that host
+    /// and port instead, or to stop following one with `None`.
+    pub replicaof: Option<Option<(String, u16)>>,
+    /// Set by ASKING for the next command to be served for a slot being
+    /// imported.
+    pub asking: bool,
+}
+
+impl ClientState {
+    pub fn new(id: u64) -> ClientState {
+        let (inbox, messages) = mpsc::unbounded_channel();
+        let (invalidator, invalidations) = mpsc::unbounded_channel();
+        ClientState {
+            id,
+            name: None,
+            db: 0,
+            authenticated: true,
+            password_required: false,
+            subscriptions: HashSet::new(),
+            patterns: HashSet::new(),
+            shard_subscriptions: HashSet::new(),
+            inbox,
+            messages,
+            invalidator,
+            invalidations,
+            tracking: None,
+            caching: None,
+            replies: Vec::new(),
+            protocol: Protocol::default(),
+            shutdown: false,
+            rewrite_aof: false,
+            reloaded: false,
+            quickack: false,
+            config: None,
+            listening_port: None,
+            sync_replica: None,
+            expiry: None,
+            script_effects: None,
+            wait_replicas: None,
+            replicaof: None,
+            asking: false,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Where messages for the client are sent.
+    pub fn inbox(&self) -> &Inbox {
+        &self.inbox
+    }
+
+    /// Where invalidations for the client are sent.
+    pub fn invalidator(&self) -> &Invalidator {
+        &self.invalidator
+    }
+
+    /// How many channels and patterns the client is subscribed to.
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.len() + self.patterns.len()
+    }
+
+    /// Whether the client is subscribed to anything, which on RESP2 limits
+    /// the commands it may run.
+    pub fn subscribed(&self) -> bool {
+        self.subscription_count() > 0 || !self.shard_subscriptions.is_empty()
+    }
+
+    /// Puts the client back the way it connected, as RESET does. Like on
+    /// Redis its id and name are kept, and it has to authenticate again if
+    /// a password is required.
+    pub fn reset(&mut self) {
+        let mut reset = ClientState::new(self.id);
+        reset.name = self.name.take();
+        reset.password_required = self.password_required;
+        reset.authenticated = !self.password_required;
+        // Clients redirecting their invalidations here still reach it.
+        mem::swap(&mut reset.invalidator, &mut self.invalidator);
+        mem::swap(&mut reset.invalidations, &mut self.invalidations);
+        *self = reset;
+    }
+}
+
+impl Drop for ClientState {
+    fn drop(&mut self) {
+        for channel in &self.subscriptions {
+            PUBSUB.unsubscribe(channel, self.id);
+        }
+        for pattern in &self.patterns {
+            PUBSUB.punsubscribe(pattern, self.id);
+        }
+        for channel in &self.shard_subscriptions {
+            PUBSUB.sunsubscribe(channel, self.id);
+        }
+        if self.tracking.is_some() {
+            TRACKING.disable(self.id);
+        }
+    }
+}
+
+/// Every connected client, by id.
+pub static CLIENTS: Clients = Clients {
+    clients: Mutex::new(BTreeMap::new()),
+};
+
+pub struct Clients {
+    clients: Mutex<BTreeMap<u64, Arc<Registered>>>,
+}
+
+struct Registered {
+    info: Mutex<ClientInfo>,
+    killed: Notify,
+    /// Where invalidations other clients redirect here go.
+    invalidator: Mutex<Option<Invalidator>>,
+}
+
+/// What CLIENT LIST and CLIENT INFO report about a client.
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub laddr: SocketAddr,
+    pub name: Option<String>,
+    pub created: Instant,
+    pub last_interaction: Instant,
+    /// Lowercase name of the command run last or being run.
+    pub last_command: String,
+    pub db: usize,
+    pub subscriptions: usize,
+    pub pattern_subscriptions: usize,
+    pub shard_subscriptions: usize,
+    pub protocol: Protocol,
+}
+
+impl ClientInfo {
+    /// Renders the client as a line of CLIENT LIST, with the fields Redis
+    /// reports that apply here.
+    pub fn describe(&self, now: Instant) -> String {
+        format!(
+            "id={} addr={} laddr={} name={} age={} idle={} flags=N db={} sub={} psub={} \
+             ssub={} multi=-1 cmd={} user=default resp={}\n",
+            self.id,
+            self.addr,
+            self.laddr,
+            self.name.as_deref().unwrap_or(""),
+            now.duration_since(self.created).as_secs(),
+            now.duration_since(self.last_interaction).as_secs(),
+            self.db,
+            self.subscriptions,
+            self.pattern_subscriptions,
+            self.shard_subscriptions,
+            self.last_command,
+            self.protocol.version(),
+        )
+    }
+}
+
+impl Clients {
+    /// Lists a newly connected client until the returned registration is
+    /// dropped.
+    pub fn register(&'static self, id: u64, addr: SocketAddr, laddr: SocketAddr) -> Registration {
+        let now = Instant::now();
+        let entry = Arc::new(Registered {
+            info: Mutex::new(ClientInfo {
+                id,
+                addr,
+                laddr,
+                name: None,
+                created: now,
+                last_interaction: now,
+                last_command: "NULL".to_string(),
+                db: 0,
+                subscriptions: 0,
+                pattern_subscriptions: 0,
+                shard_subscriptions: 0,
+                protocol: Protocol::default(),
+            }),
+            killed: Notify::new(),
+            invalidator: Mutex::new(None),
+        });
+        self.clients.lock().unwrap().insert(id, Arc::clone(&entry));
+        Registration {
+            clients: self,
+            id,
+            entry,
+        }
+    }
+
+    /// Every connected client, ordered by id.
+    pub fn list(&self) -> Vec<ClientInfo> {
+        self.clients
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.info.lock().unwrap().clone())
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<ClientInfo> {
+        let clients = self.clients.lock().unwrap();
+        clients
+            .get(&id)
+            .map(|entry| entry.info.lock().unwrap().clone())
+    }
+
+    /// Where invalidations redirected to the client `id` go, if it is
+    /// connected.
+    pub fn invalidator(&self, id: u64) -> Option<Invalidator> {
+        let clients = self.clients.lock().unwrap();
+        let entry = clients.get(&id)?;
+        let invalidator = entry.invalidator.lock().unwrap().clone();
+        invalidator
+    }
+
+    /// Closes every client `matches` selects and returns how many there
+    /// were. They are unlisted right away and closed by their own task as
+    /// soon as it notices.
+    pub fn kill(&self, matches: impl Fn(&ClientInfo) -> bool) -> usize {
+        let mut clients = self.clients.lock().unwrap();
+        let killed: Vec<u64> = clients
+            .iter()
+            .filter(|(_, entry)| matches(&entry.info.lock().unwrap()))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &killed {
+            if let Some(entry) = clients.remove(id) {
+                entry.killed.notify_one();
+            }
+        }
+        killed.len()
+    }
+}
+
+/// A client's place in the registry, held by the task serving it.
+pub struct Registration {
+    clients: &'static Clients,
+    id: u64,
+    entry: Arc<Registered>,
+}
+
+impl Registration {
+    /// Records that `client` is running `command` right now.
+    pub fn update(&self, client: &ClientState, command: &str) {
+        let mut info = self.entry.info.lock().unwrap();
+        info.last_interaction = Instant::now();
+        if !info.last_command.eq_ignore_ascii_case(command) {
+            info.last_command = command.to_lowercase();
+        }
+        if info.name != client.name {
+            info.name = client.name.clone();
+        }
+        info.db = client.db;
+        info.subscriptions = client.subscriptions.len();
+        info.pattern_subscriptions = client.patterns.len();
+        info.shard_subscriptions = client.shard_subscriptions.len();
+        info.protocol = client.protocol;
+    }
+
+    /// Lets other clients redirect their invalidations to `client`.
+    pub fn accept_invalidations(&self, client: &ClientState) {
+        *self.entry.invalidator.lock().unwrap() = Some(client.invalidator().clone());
+    }
+
+    /// Completes once the client has been killed with CLIENT KILL.
+    pub async fn killed(&self) {
+        self.entry.killed.notified().await
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.clients.clients.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Which commands CLIENT PAUSE holds back, from the least to the most.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PauseMode {
+    Write,
+    All,
+}
+
+/// Set by CLIENT PAUSE until it runs out or CLIENT UNPAUSE.
+pub static PAUSE: Pause = Pause {
+    state: Mutex::new(None),
+    changed: Notify::const_new(),
+};
+
+pub struct Pause {
+    state: Mutex<Option<(Instant, PauseMode)>>,
+    changed: Notify,
+}
+
+impl Pause {
+    /// Holds back commands `mode` covers until `until`. Like on Redis, a
+    /// pause already in effect is only ever extended and made stricter.
+    pub fn pause(&self, until: Instant, mode: PauseMode) {
+        let mut state = self.state.lock().unwrap();
+        *state = match *state {
+            Some((end, current)) if end > Instant::now() => {
+                Some((end.max(until), current.max(mode)))
+            }
+            _ => Some((until, mode)),
+        };
+    }
+
+    /// Lets every paused client go on right away.
+    pub fn unpause(&self) {
+        *self.state.lock().unwrap() = None;
+        self.changed.notify_waiters();
+    }
+
+    /// When a command, writing to the dataset or not, may run again if it
+    /// is held back now.
+    fn until(&self, write: bool) -> Option<Instant> {
+        match *self.state.lock().unwrap() {
+            Some((until, mode)) if until > Instant::now() && (write || mode == PauseMode::All) => {
+                Some(until)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether a command, writing to the dataset or not, is held back.
+    pub fn holds(&self, write: bool) -> bool {
+        self.until(write).is_some()
+    }
+
+    /// Completes once a command, writing to the dataset or not, may run.
+    pub async fn wait(&self, write: bool) {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            let Some(until) = self.until(write) else {
+                return;
+            };
+            tokio::select! {
+                _ = sleep_until(until.into()) => {}
+                _ = changed => {}
+            }
+        }
+    }
+}
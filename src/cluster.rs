@@ -0,0 +1,578 @@
+//! This node's view of the cluster when cluster mode is enabled: who it is,
+//! the other nodes it was told of and which node serves each of the hash
+//! slots keys are spread over, as CLUSTER and `INFO cluster` report it.
+//! Requests for keys in slots served elsewhere are redirected there, and
+//! while a slot moves to another node, requests for its keys that already
+//! left are asked of that node. Nodes find each other and agree on which
+//! serves each slot over the cluster bus.
+
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    crc16::crc16,
+    replication::{random_id, REPLICATION},
+    resp::Entry,
+};
+
+pub mod bus;
+
+/// Hash slots keys are spread over.
+pub const SLOTS: u16 = 16384;
+
+/// How far the cluster bus port is from the one clients connect to unless
+/// told otherwise.
+pub const BUS_PORT_OFFSET: u16 = 10000;
+
+#[derive(Debug)]
+pub struct ClusterState {
+    topology: Mutex<Topology>,
+    /// Milliseconds a node may leave a ping unanswered before it is taken
+    /// for failing, Redis's `cluster-node-timeout`.
+    pub node_timeout: AtomicU64,
+}
+
+/// The nodes known and the slots each serves.
+#[derive(Debug)]
+struct Topology {
+    /// This node first once cluster mode is enabled, then the others.
+    nodes: Vec<Node>,
+    /// Which of `nodes` serves each slot, if any. Empty until cluster mode
+    /// is enabled.
+    owners: Vec<Option<usize>>,
+    /// Slots this node serves whose keys are being moved, to which node.
+    migrating: BTreeMap<u16, usize>,
+    /// Slots whose keys are being moved here, from which node.
+    importing: BTreeMap<u16, usize>,
+    /// The highest epoch heard of, which new claims on slots go beyond.
+    current_epoch: u64,
+}
+
+/// What CLUSTER SETSLOT makes of a slot, naming nodes by ID.
+#[derive(Debug, PartialEq)]
+pub enum SlotState {
+    /// Keys of the slot, served here, are being moved to the node.
+    Migrating(String),
+    /// Keys of the slot, served by the node, are being moved here.
+    Importing(String),
+    /// No keys of the slot are being moved anymore.
+    Stable,
+    /// The node serves the slot from now on, the move being over.
+    Node(String),
+}
+
+/// Where a request for some keys is served.
+#[derive(Debug, PartialEq)]
+pub enum Route {
+    Here,
+    /// Elsewhere or nowhere, as the error says.
+    Redirect(Entry),
+    /// Here if every key still is, else elsewhere as the error says: the
+    /// slot is being moved.
+    IfPresent(Entry),
+}
+
+#[derive(Debug)]
+struct Node {
+    /// 40 hex digits naming the node.
+    id: String,
+    /// Where clients reach the node.
+    ip: String,
+    port: u16,
+    /// Where nodes reach the node's cluster bus, 0 if they don't.
+    bus_port: u16,
+    /// The epoch the node claimed its slots in: of two nodes claiming a
+    /// slot, that of the higher epoch serves it.
+    config_epoch: u64,
+    /// Met but not heard from yet, under a made up ID until it tells its own.
+    handshake: bool,
+    /// Unix time in milliseconds of the oldest ping the node didn't answer
+    /// yet, 0 if none.
+    ping_sent: u64,
+    /// Unix time in milliseconds the node was last heard from.
+    pong_received: u64,
+    /// Whether a ping went unanswered for longer than the node timeout.
+    pfail: bool,
+    /// Whether this node's link to the node's cluster bus is up.
+    connected: bool,
+}
+
+impl Node {
+    fn new(id: &str, ip: &str, port: u16, bus_port: u16) -> Self {
+        Node {
+            id: id.to_string(),
+            ip: ip.to_string(),
+            port,
+            bus_port,
+            config_epoch: 0,
+            handshake: false,
+            ping_sent: 0,
+            pong_received: 0,
+            pfail: false,
+            connected: false,
+        }
+    }
+}
+
+pub static CLUSTER: ClusterState = ClusterState {
+    topology: Mutex::new(Topology {
+        nodes: Vec::new(),
+        owners: Vec::new(),
+        migrating: BTreeMap::new(),
+        importing: BTreeMap::new(),
+        current_epoch: 0,
+    }),
+    node_timeout: AtomicU64::new(15000),
+};
+
+/// The slot `key` hashes to. Only the part between the first `{` and the
+/// `}` after it is hashed if it isn't empty, for keys sharing that tag to
+/// share a slot.
+pub fn key_slot(key: &str) -> u16 {
+    let key = key.as_bytes();
+    let tagged = key.iter().position(|&byte| byte == b'{').and_then(|open| {
+        let tag = &key[open + 1..];
+        let close = tag.iter().position(|&byte| byte == b'}')?;
+        (close > 0).then(|| &tag[..close])
+    });
+    crc16(tagged.unwrap_or(key)) % SLOTS
+}
+
+/// Parses slots and ranges of them such as `0-5460 6000`.
+pub fn parse_slot_ranges(ranges: &str) -> Result<Vec<(u16, u16)>, String> {
+    ranges
+        .split_whitespace()
+        .map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            match (parse_slot(start), parse_slot(end)) {
+                (Some(start), Some(end)) if start <= end => Ok((start, end)),
+                _ => Err(format!("invalid slot range {}", range)),
+            }
+        })
+        .collect()
+}
+
+/// A slot number, if `slot` is one.
+pub fn parse_slot(slot: &str) -> Option<u16> {
+    slot.parse().ok().filter(|&slot| slot < SLOTS)
+}
+
+impl ClusterState {
+    /// Enables cluster mode, with clients reaching this node at `ip` and
+    /// `port`, and other nodes at `bus_port`. It serves no slots yet.
+    pub fn enable(&self, ip: &str, port: u16, bus_port: u16) {
+        let mut topology = self.topology.lock().unwrap();
+        let mut node = Node::new(&random_id(), ip, port, bus_port);
+        node.connected = true;
+        topology.nodes = vec![node];
+        topology.owners = vec![None; usize::from(SLOTS)];
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.topology.lock().unwrap().nodes.is_empty()
+    }
+
+    /// The 40 hex digits naming this node, picked at random.
+    pub fn myid(&self) -> String {
+        let topology = self.topology.lock().unwrap();
+        topology
+            .nodes
+            .first()
+            .map(|node| node.id.clone())
+            .unwrap_or_default()
+    }
+
+    /// Makes this node serve the slots in `ranges`, none of which may be
+    /// served already.
+    pub fn add_slots(&self, ranges: &[(u16, u16)]) -> Result<(), String> {
+        self.topology.lock().unwrap().assign(ranges, 0)
+    }
+
+    /// Stops serving the slots in `ranges`, all of which must be served by
+    /// some node.
+    pub fn del_slots(&self, ranges: &[(u16, u16)]) -> Result<(), String> {
+        let mut topology = self.topology.lock().unwrap();
+        let slots = || ranges.iter().flat_map(|&(start, end)| start..=end);
+        if let Some(slot) = slots().find(|&slot| topology.owners[usize::from(slot)].is_none()) {
+            return Err(format!("Slot {} is already unassigned", slot));
+        }
+        for slot in slots() {
+            topology.owners[usize::from(slot)] = None;
+            topology.migrating.remove(&slot);
+            topology.importing.remove(&slot);
+        }
+        Ok(())
+    }
+
+    /// Tells of another node, named `id`, which clients reach at `ip` and
+    /// `port`, nodes at `bus_port` unless 0, and which serves the slots in
+    /// `ranges`.
+    pub fn add_node(
+        &self,
+        id: &str,
+        ip: &str,
+        port: u16,
+        bus_port: u16,
+        ranges: &[(u16, u16)],
+    ) -> Result<(), String> {
+        let mut topology = self.topology.lock().unwrap();
+        if topology.nodes.iter().any(|node| node.id == id) {
+            return Err(format!("node {} is already known", id));
+        }
+        topology.nodes.push(Node::new(id, ip, port, bus_port));
+        let node = topology.nodes.len() - 1;
+        topology.assign(ranges, node).inspect_err(|_| {
+            topology.nodes.pop();
+        })
+    }
+
+    /// Has this node meet the one whose cluster bus is at `ip` and
+    /// `bus_port`, which joins the cluster once it answered, as CLUSTER
+    /// MEET does.
+    pub fn meet(&self, ip: &str, port: u16, bus_port: u16) -> Result<(), String> {
+        if ip.parse::<IpAddr>().is_err() || bus_port == 0 {
+            return Err(format!("Invalid node address specified: {}:{}", ip, port));
+        }
+        let mut topology = self.topology.lock().unwrap();
+        if topology.at(ip, bus_port).is_none() {
+            let mut node = Node::new(&random_id(), ip, port, bus_port);
+            node.handshake = true;
+            topology.nodes.push(node);
+        }
+        Ok(())
+    }
+
+    /// Changes what is going on with `slot`, as CLUSTER SETSLOT does.
+    pub fn set_slot(&self, slot: u16, state: &SlotState) -> Result<(), String> {
+        let mut topology = self.topology.lock().unwrap();
+        let owner = topology.owners[usize::from(slot)];
+        match state {
+            SlotState::Migrating(id) => {
+                let node = topology.find(id)?;
+                if owner != Some(0) {
+                    return Err(format!("I'm not the owner of hash slot {}", slot));
+                }
+                if node == 0 {
+                    return Err("Can't migrate a slot to myself".to_string());
+                }
+                topology.migrating.insert(slot, node);
+            }
+            SlotState::Importing(id) => {
+                let node = topology.find(id)?;
+                if owner == Some(0) {
+                    return Err(format!("I'm already the owner of hash slot {}", slot));
+                }
+                if node == 0 {
+                    return Err("Can't import a slot from myself".to_string());
+                }
+                topology.importing.insert(slot, node);
+            }
+            SlotState::Stable => {
+                topology.migrating.remove(&slot);
+                topology.importing.remove(&slot);
+            }
+            SlotState::Node(id) => {
+                let node = topology.find(id)?;
+                topology.migrating.remove(&slot);
+                topology.importing.remove(&slot);
+                topology.owners[usize::from(slot)] = Some(node);
+                // Claimed in a newer epoch for the other nodes to take it
+                // from the one that served it, without asking them first.
+                if node == 0 && owner != Some(0) {
+                    topology.bump_epoch();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether this node serves `slot`.
+    pub fn serves(&self, slot: u16) -> bool {
+        self.topology.lock().unwrap().owners[usize::from(slot)] == Some(0)
+    }
+
+    /// Where a request for `keys` is served, `asking` if the client sent
+    /// ASKING first. Keys hashing to different slots are served nowhere.
+    pub fn route(&self, keys: &[impl AsRef<str>], asking: bool) -> Route {
+        let mut slots = keys.iter().map(|key| key_slot(key.as_ref()));
+        let Some(slot) = slots.next() else {
+            return Route::Here;
+        };
+        if slots.any(|other| other != slot) {
+            return Route::Redirect(Entry::error(
+                "CROSSSLOT",
+                "Keys in request don't hash to the same slot",
+            ));
+        }
+        let topology = self.topology.lock().unwrap();
+        let redirect = |code: &str, node: usize| {
+            let node = &topology.nodes[node];
+            Entry::error(code, format!("{} {}:{}", slot, node.ip, node.port))
+        };
+        match topology.owners[usize::from(slot)] {
+            Some(0) => match topology.migrating.get(&slot) {
+                Some(&node) => Route::IfPresent(redirect("ASK", node)),
+                None => Route::Here,
+            },
+            // Keys moved here are only found once the client was told to
+            // ask, and none can be missing while some are still to come.
+            _ if asking && topology.importing.contains_key(&slot) => match keys.len() {
+                1 => Route::Here,
+                _ => Route::IfPresent(Entry::error(
+                    "TRYAGAIN",
+                    "Multiple keys request during rehashing of slot",
+                )),
+            },
+            Some(node) => Route::Redirect(redirect("MOVED", node)),
+            None => Route::Redirect(Entry::error("CLUSTERDOWN", "Hash slot not served")),
+        }
+    }
+
+    /// The reply to CLUSTER INFO.
+    pub fn info(&self) -> String {
+        let topology = self.topology.lock().unwrap();
+        let assigned = topology.owners.iter().flatten().count();
+        let pfail = topology
+            .owners
+            .iter()
+            .flatten()
+            .filter(|&&node| topology.nodes[node].pfail)
+            .count();
+        let serving = (0..topology.nodes.len())
+            .filter(|&node| topology.owners.contains(&Some(node)))
+            .count();
+        format!(
+            "cluster_state:{}\r\n\
+             cluster_slots_assigned:{}\r\n\
+             cluster_slots_ok:{}\r\n\
+             cluster_slots_pfail:{}\r\n\
+             cluster_slots_fail:0\r\n\
+             cluster_known_nodes:{}\r\n\
+             cluster_size:{}\r\n\
+             cluster_current_epoch:{}\r\n\
+             cluster_my_epoch:{}\r\n",
+            if assigned == usize::from(SLOTS) {
+                "ok"
+            } else {
+                "fail"
+            },
+            assigned,
+            assigned - pfail,
+            pfail,
+            topology.nodes.len(),
+            serving,
+            topology.current_epoch,
+            topology.nodes[0].config_epoch,
+        )
+    }
+
+    /// The reply to CLUSTER NODES: a line per node, with what this node
+    /// knows of it and the slots it serves.
+    pub fn nodes(&self) -> String {
+        let topology = self.topology.lock().unwrap();
+        let ranges = topology.ranges();
+        let mut nodes = String::new();
+        for (at, node) in topology.nodes.iter().enumerate() {
+            let flags = match at {
+                0 => "myself,master",
+                _ if node.handshake => "handshake",
+                _ if node.pfail => "master,fail?",
+                _ => "master",
+            };
+            nodes.push_str(&format!(
+                "{} {}:{}@{} {} - {} {} {} {}",
+                node.id,
+                node.ip,
+                node.port,
+                node.bus_port,
+                flags,
+                node.ping_sent,
+                node.pong_received,
+                node.config_epoch,
+                if node.connected {
+                    "connected"
+                } else {
+                    "disconnected"
+                },
+            ));
+            for &(start, end, _) in ranges.iter().filter(|&&(_, _, owner)| owner == at) {
+                match start == end {
+                    true => nodes.push_str(&format!(" {}", start)),
+                    false => nodes.push_str(&format!(" {}-{}", start, end)),
+                }
+            }
+            // Only this node knows of the slots it moves.
+            if at == 0 {
+                for (slot, &to) in &topology.migrating {
+                    nodes.push_str(&format!(" [{}->-{}]", slot, topology.nodes[to].id));
+                }
+                for (slot, &from) in &topology.importing {
+                    nodes.push_str(&format!(" [{}-<-{}]", slot, topology.nodes[from].id));
+                }
+            }
+            nodes.push('\n');
+        }
+        nodes
+    }
+
+    /// The reply to CLUSTER SLOTS: each range of slots with the node
+    /// serving it.
+    pub fn slots(&self) -> Entry {
+        let topology = self.topology.lock().unwrap();
+        let ranges = topology.ranges().into_iter().map(|(start, end, node)| {
+            let node = &topology.nodes[node];
+            Entry::Array(vec![
+                Entry::Int(start.into()),
+                Entry::Int(end.into()),
+                Entry::Array(vec![
+                    Entry::Text(node.ip.clone()),
+                    Entry::Int(node.port.into()),
+                    Entry::Text(node.id.clone()),
+                    Entry::Map(vec![]),
+                ]),
+            ])
+        });
+        Entry::Array(ranges.collect())
+    }
+
+    /// The reply to CLUSTER SHARDS: every node as a shard of its own, with
+    /// the slots it serves as pairs of bounds.
+    pub fn shards(&self) -> Entry {
+        let topology = self.topology.lock().unwrap();
+        let ranges = topology.ranges();
+        let text = |text: &str| Entry::Text(text.to_string());
+        let shards = topology.nodes.iter().enumerate().map(|(at, node)| {
+            let bounds = ranges
+                .iter()
+                .filter(|&&(_, _, owner)| owner == at)
+                .flat_map(|&(start, end, _)| [Entry::Int(start.into()), Entry::Int(end.into())]);
+            // Only how far this node is in its own stream is known.
+            let offset = match at {
+                0 => REPLICATION.offset.load(Ordering::Relaxed) as i64,
+                _ => 0,
+            };
+            let node = Entry::Map(vec![
+                (text("id"), Entry::Text(node.id.clone())),
+                (text("port"), Entry::Int(node.port.into())),
+                (text("ip"), Entry::Text(node.ip.clone())),
+                (text("endpoint"), Entry::Text(node.ip.clone())),
+                (text("role"), text("master")),
+                (text("replication-offset"), Entry::Int(offset)),
+                (text("health"), text("online")),
+            ]);
+            Entry::Map(vec![
+                (text("slots"), Entry::Array(bounds.collect())),
+                (text("nodes"), Entry::Array(vec![node])),
+            ])
+        });
+        Entry::Array(shards.collect())
+    }
+
+    /// Renders the `# Cluster` section of INFO.
+    pub fn info_section(&self) -> String {
+        format!("# Cluster\r\ncluster_enabled:{}\r\n", self.enabled() as u8)
+    }
+}
+
+impl Topology {
+    /// Which of `nodes` has its cluster bus at `ip` and `bus_port`.
+    fn at(&self, ip: &str, bus_port: u16) -> Option<usize> {
+        self.nodes
+            .iter()
+            .position(|node| node.ip == ip && node.bus_port == bus_port)
+    }
+
+    /// Forgets of `node`, which no longer serves or moves any slot.
+    fn remove(&mut self, node: usize) {
+        self.nodes.remove(node);
+        let shift = |other: usize| match other.cmp(&node) {
+            std::cmp::Ordering::Less => Some(other),
+            std::cmp::Ordering::Equal => None,
+            std::cmp::Ordering::Greater => Some(other - 1),
+        };
+        for owner in &mut self.owners {
+            *owner = owner.and_then(shift);
+        }
+        for moving in [&mut self.migrating, &mut self.importing] {
+            *moving = moving
+                .iter()
+                .filter_map(|(&slot, &other)| Some((slot, shift(other)?)))
+                .collect();
+        }
+    }
+
+    /// Moves on to a new epoch for this node to claim its slots in.
+    fn bump_epoch(&mut self) {
+        self.current_epoch += 1;
+        self.nodes[0].config_epoch = self.current_epoch;
+    }
+
+    /// Which of `nodes` is named `id`.
+    fn find(&self, id: &str) -> Result<usize, String> {
+        self.nodes
+            .iter()
+            .position(|node| node.id == id)
+            .ok_or_else(|| format!("I don't know about node {}", id))
+    }
+
+    /// Makes `node` serve the slots in `ranges`, none of which may be
+    /// served already.
+    fn assign(&mut self, ranges: &[(u16, u16)], node: usize) -> Result<(), String> {
+        let slots = || ranges.iter().flat_map(|&(start, end)| start..=end);
+        if let Some(slot) = slots().find(|&slot| self.owners[usize::from(slot)].is_some()) {
+            return Err(format!("Slot {} is already busy", slot));
+        }
+        for slot in slots() {
+            self.owners[usize::from(slot)] = Some(node);
+        }
+        Ok(())
+    }
+
+    /// Every run of slots served by the same node, with that node, in order.
+    fn ranges(&self) -> Vec<(u16, u16, usize)> {
+        let mut ranges: Vec<(u16, u16, usize)> = Vec::new();
+        for (slot, owner) in (0..SLOTS).zip(&self.owners) {
+            let Some(owner) = *owner else {
+                continue;
+            };
+            match ranges.last_mut() {
+                Some((_, end, node)) if *node == owner && *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot, owner)),
+            }
+        }
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_hash_keys_to_the_slots_redis_does() {
+        assert_eq!(key_slot("foo"), 12182);
+        assert_eq!(key_slot("bar"), 5061);
+        assert_eq!(key_slot("{user1000}.following"), key_slot("user1000"));
+        assert_eq!(key_slot("{user1000}.followers"), key_slot("user1000"));
+        // Empty or unclosed tags don't count.
+        assert_eq!(key_slot("foo{}{bar}"), crc16(b"foo{}{bar}") % SLOTS);
+        assert_eq!(key_slot("foo{bar"), crc16(b"foo{bar") % SLOTS);
+        assert_eq!(key_slot("foo{{bar}}zap"), key_slot("{bar"));
+    }
+
+    #[test]
+    fn should_parse_slot_ranges() {
+        assert_eq!(
+            parse_slot_ranges("0-5460 6000"),
+            Ok(vec![(0, 5460), (6000, 6000)])
+        );
+        assert!(parse_slot_ranges("10-5").is_err());
+        assert!(parse_slot_ranges("16384").is_err());
+    }
+}
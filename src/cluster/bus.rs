@@ -0,0 +1,551 @@
+//! The cluster bus nodes talk to each other over, on a port of their own.
+//! Every node pings each other node it knows of, which answers with a pong;
+//! a node met for the first time is sent a meet instead, for it to learn
+//! of this one too. Each message tells of its sender, the slots it serves
+//! and the epoch it claimed them in, for nodes to agree on which serves a
+//! slot, and gossips about a few other nodes, for nodes to find each
+//! other. Nodes that leave a ping unanswered for longer than the node
+//! timeout are taken for failing.
+//!
+//! Messages are arrays of bulk strings: the type, then the sender's ID,
+//! IP, port, bus port, current epoch, config epoch and slot ranges such as
+//! `0-5460 6000`, then the ID, IP, port and bus port of each node gossiped
+//! about.
+
+use std::{
+    sync::{atomic::Ordering, Mutex},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use rand::seq::IteratorRandom;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    select,
+    task::JoinHandle,
+    time::{interval, sleep, timeout},
+};
+
+use super::{parse_slot_ranges, Node, Topology, CLUSTER};
+use crate::{
+    replication::random_id,
+    resp::{self, Entry, Limits, ProtocolError},
+    storage::unix_time_ms,
+};
+
+/// How often links are set up to nodes without one, and nodes are checked
+/// for failing, like Redis's cluster cron.
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often each node is pinged.
+const PING_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long connecting to another node's bus may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many other nodes each message gossips about.
+const GOSSIP_NODES: usize = 3;
+
+/// Where a node's bus is: its IP and bus port.
+type Addr = (String, u16);
+
+/// The links to other nodes' buses.
+static LINKS: Mutex<Vec<(Addr, JoinHandle<()>)>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Meet,
+    Ping,
+    Pong,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Peer {
+    id: String,
+    ip: String,
+    port: u16,
+    bus_port: u16,
+}
+
+#[derive(Debug, PartialEq)]
+struct Message {
+    kind: Kind,
+    sender: Peer,
+    current_epoch: u64,
+    config_epoch: u64,
+    slots: Vec<(u16, u16)>,
+    gossip: Vec<Peer>,
+}
+
+impl Peer {
+    fn of(node: &Node) -> Self {
+        Peer {
+            id: node.id.clone(),
+            ip: node.ip.clone(),
+            port: node.port,
+            bus_port: node.bus_port,
+        }
+    }
+
+    fn encode(&self, into: &mut Vec<String>) {
+        into.extend([
+            self.id.clone(),
+            self.ip.clone(),
+            self.port.to_string(),
+            self.bus_port.to_string(),
+        ]);
+    }
+
+    fn decode(fields: &[String]) -> Option<Self> {
+        let [id, ip, port, bus_port] = fields else {
+            return None;
+        };
+        Some(Peer {
+            id: id.clone(),
+            ip: ip.clone(),
+            port: port.parse().ok()?,
+            bus_port: bus_port.parse().ok()?,
+        })
+    }
+}
+
+impl Message {
+    fn encode(&self) -> BytesMut {
+        let kind = match self.kind {
+            Kind::Meet => "MEET",
+            Kind::Ping => "PING",
+            Kind::Pong => "PONG",
+        };
+        let mut fields = vec![kind.to_string()];
+        self.sender.encode(&mut fields);
+        let slots = self
+            .slots
+            .iter()
+            .map(|(start, end)| format!("{}-{}", start, end));
+        fields.extend([
+            self.current_epoch.to_string(),
+            self.config_epoch.to_string(),
+            slots.collect::<Vec<_>>().join(" "),
+        ]);
+        for peer in &self.gossip {
+            peer.encode(&mut fields);
+        }
+        let mut buf = BytesMut::new();
+        Entry::Array(fields.into_iter().map(Entry::Text).collect()).encode(&mut buf);
+        buf
+    }
+
+    fn decode(entries: &[Entry]) -> Option<Self> {
+        let fields = entries
+            .iter()
+            .map(|entry| match entry {
+                Entry::Text(field) => Some(field.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if fields.len() < 8 || (fields.len() - 8) % 4 != 0 {
+            return None;
+        }
+        let kind = match fields[0].as_str() {
+            "MEET" => Kind::Meet,
+            "PING" => Kind::Ping,
+            "PONG" => Kind::Pong,
+            _ => return None,
+        };
+        Some(Message {
+            kind,
+            sender: Peer::decode(&fields[1..5])?,
+            current_epoch: fields[5].parse().ok()?,
+            config_epoch: fields[6].parse().ok()?,
+            slots: parse_slot_ranges(&fields[7]).ok()?,
+            gossip: fields[8..]
+                .chunks(4)
+                .map(Peer::decode)
+                .collect::<Option<_>>()?,
+        })
+    }
+}
+
+/// Answers the messages of the nodes connecting to `listener`.
+pub async fn accept(listener: TcpListener) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        tokio::spawn(answer(stream));
+    }
+}
+
+async fn answer(mut stream: TcpStream) {
+    let mut buf = BytesMut::new();
+    while matches!(stream.read_buf(&mut buf).await, Ok(read) if read > 0) {
+        loop {
+            let message = match resp::decode(&mut buf, &Limits::default()) {
+                Ok(Some(entries)) => Message::decode(&entries),
+                Ok(None) => break,
+                Err(_) => return,
+            };
+            let reply = message.and_then(|message| {
+                let mut topology = CLUSTER.topology.lock().unwrap();
+                topology.receive(&message, None, unix_time_ms())
+            });
+            if let Some(reply) = reply {
+                if stream.write_all(&reply.encode()).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Keeps a link up to every other node whose bus is known, and takes those
+/// that stopped answering for failing.
+pub async fn cron() {
+    loop {
+        sleep(CRON_INTERVAL).await;
+        let now = unix_time_ms();
+        let node_timeout = CLUSTER.node_timeout.load(Ordering::Relaxed);
+        let peers = {
+            let mut topology = CLUSTER.topology.lock().unwrap();
+            topology.check_failures(now, node_timeout);
+            topology.peers()
+        };
+        let mut links = LINKS.lock().unwrap();
+        links.retain(|(addr, link)| {
+            let kept = peers.contains(addr) && !link.is_finished();
+            if !kept {
+                link.abort();
+            }
+            kept
+        });
+        for addr in peers {
+            if links.iter().any(|(linked, _)| *linked == addr) {
+                continue;
+            }
+            // A node nobody can connect to fails like one that doesn't answer.
+            if let Some(node) = CLUSTER.topology.lock().unwrap().node_at(&addr) {
+                if node.ping_sent == 0 {
+                    node.ping_sent = now;
+                }
+            }
+            let link = tokio::spawn(link(addr.clone()));
+            links.push((addr, link));
+        }
+    }
+}
+
+/// Pings the node whose bus is at `addr` until the link breaks or the node
+/// is forgotten, taking in its pongs.
+async fn link(addr: Addr) {
+    let connect = TcpStream::connect((addr.0.as_str(), addr.1));
+    let Ok(Ok(stream)) = timeout(CONNECT_TIMEOUT, connect).await else {
+        return;
+    };
+    connected(&addr, true);
+    let (mut reader, mut writer) = stream.into_split();
+    let mut buf = BytesMut::new();
+    let mut pings = interval(PING_INTERVAL);
+    loop {
+        select! {
+            _ = pings.tick() => {
+                let message = {
+                    let mut topology = CLUSTER.topology.lock().unwrap();
+                    topology.ping(&addr, unix_time_ms())
+                };
+                let Some(message) = message else {
+                    break;
+                };
+                if writer.write_all(&message.encode()).await.is_err() {
+                    break;
+                }
+            }
+            read = reader.read_buf(&mut buf) => {
+                if !matches!(read, Ok(read) if read > 0) {
+                    break;
+                }
+                if receive_all(&mut buf, &addr).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    connected(&addr, false);
+}
+
+/// Takes in every message in `buf` received over the link to `addr`.
+fn receive_all(buf: &mut BytesMut, addr: &Addr) -> Result<(), ProtocolError> {
+    while let Some(entries) = resp::decode(buf, &Limits::default())? {
+        if let Some(message) = Message::decode(&entries) {
+            let mut topology = CLUSTER.topology.lock().unwrap();
+            topology.receive(&message, Some(addr), unix_time_ms());
+        }
+    }
+    Ok(())
+}
+
+fn connected(addr: &Addr, connected: bool) {
+    if let Some(node) = CLUSTER.topology.lock().unwrap().node_at(addr) {
+        node.connected = connected;
+    }
+}
+
+impl Topology {
+    fn node_at(&mut self, (ip, bus_port): &Addr) -> Option<&mut Node> {
+        let node = self.at(ip, *bus_port)?;
+        self.nodes.get_mut(node).filter(|_| node > 0)
+    }
+
+    /// Where the buses of the other nodes are, for those whose are known.
+    fn peers(&self) -> Vec<Addr> {
+        self.nodes
+            .iter()
+            .skip(1)
+            .filter(|node| node.bus_port != 0)
+            .map(|node| (node.ip.clone(), node.bus_port))
+            .collect()
+    }
+
+    /// Flags the nodes that left a ping unanswered for longer than `timeout`
+    /// milliseconds as failing, and forgets those met that never answered.
+    fn check_failures(&mut self, now: u64, timeout: u64) {
+        for node in self.nodes.iter_mut().skip(1) {
+            if node.ping_sent != 0 && now.saturating_sub(node.ping_sent) > timeout {
+                node.pfail = true;
+            }
+        }
+        while let Some(node) = self
+            .nodes
+            .iter()
+            .position(|node| node.handshake && node.pfail)
+        {
+            self.remove(node);
+        }
+    }
+
+    /// The message pinging the node whose bus is at `addr`, or meeting it
+    /// if it never answered, if it is still known.
+    fn ping(&mut self, addr: &Addr, now: u64) -> Option<Message> {
+        let node = self.at(&addr.0, addr.1).filter(|&node| node > 0)?;
+        let kind = match self.nodes[node].handshake {
+            true => Kind::Meet,
+            false => Kind::Ping,
+        };
+        if self.nodes[node].ping_sent == 0 {
+            self.nodes[node].ping_sent = now;
+        }
+        Some(self.message(kind, Some(node)))
+    }
+
+    /// A message of `kind` from this node, gossiping about nodes other
+    /// than the one it is sent `to`.
+    fn message(&self, kind: Kind, to: Option<usize>) -> Message {
+        let me = &self.nodes[0];
+        let gossip = (1..self.nodes.len())
+            .filter(|&node| Some(node) != to)
+            .map(|node| &self.nodes[node])
+            .filter(|node| !node.handshake && node.bus_port != 0)
+            .choose_multiple(&mut rand::thread_rng(), GOSSIP_NODES);
+        Message {
+            kind,
+            sender: Peer::of(me),
+            current_epoch: self.current_epoch,
+            config_epoch: me.config_epoch,
+            slots: self
+                .ranges()
+                .into_iter()
+                .filter(|&(_, _, owner)| owner == 0)
+                .map(|(start, end, _)| (start, end))
+                .collect(),
+            gossip: gossip.into_iter().map(Peer::of).collect(),
+        }
+    }
+
+    /// Takes in `message`, received over the link to the node at `link` or
+    /// from a node that connected, returning the reply if it needs one.
+    fn receive(&mut self, message: &Message, link: Option<&Addr>, now: u64) -> Option<Message> {
+        let sender = &message.sender;
+        let met = link.and_then(|(ip, bus_port)| {
+            self.at(ip, *bus_port)
+                .filter(|&node| self.nodes[node].handshake)
+        });
+        let known = self.nodes.iter().position(|node| node.id == sender.id);
+        let node = match (known, message.kind) {
+            // Met before, or met twice: the node is only known once.
+            (Some(node), _) => match met {
+                Some(met) => {
+                    self.remove(met);
+                    self.nodes.iter().position(|node| node.id == sender.id)?
+                }
+                None => node,
+            },
+            (None, Kind::Meet) => {
+                let peer = Node::new(&sender.id, &sender.ip, sender.port, sender.bus_port);
+                self.nodes.push(peer);
+                self.nodes.len() - 1
+            }
+            // The node met answered, telling its ID.
+            (None, Kind::Pong) => {
+                let met = met?;
+                self.nodes[met].id = sender.id.clone();
+                self.nodes[met].handshake = false;
+                met
+            }
+            // Nodes are only taken in once met.
+            (None, Kind::Ping) => return None,
+        };
+        if node == 0 {
+            return None;
+        }
+
+        let peer = &mut self.nodes[node];
+        peer.ip = sender.ip.clone();
+        peer.port = sender.port;
+        peer.bus_port = sender.bus_port;
+        peer.config_epoch = message.config_epoch;
+        peer.pong_received = now;
+        peer.pfail = false;
+        if message.kind == Kind::Pong {
+            peer.ping_sent = 0;
+        }
+        self.current_epoch = self.current_epoch.max(message.current_epoch);
+
+        // Slots go to whichever node claimed them in the latest epoch.
+        let slots = message.slots.iter().flat_map(|&(start, end)| start..=end);
+        for slot in slots {
+            let owner = &mut self.owners[usize::from(slot)];
+            match *owner {
+                Some(owner) if owner == node => continue,
+                Some(owner) if self.nodes[owner].config_epoch >= message.config_epoch => continue,
+                _ => *owner = Some(node),
+            }
+            self.migrating.remove(&slot);
+        }
+        // Two nodes claiming slots in the same epoch couldn't tell whose
+        // claim wins, so that of the greater ID moves on to a new one.
+        if message.config_epoch == self.nodes[0].config_epoch && sender.id < self.nodes[0].id {
+            self.bump_epoch();
+        }
+
+        for peer in &message.gossip {
+            let known = self.nodes.iter().any(|node| node.id == peer.id)
+                || self.at(&peer.ip, peer.bus_port).is_some();
+            if !known && peer.bus_port != 0 {
+                let mut node = Node::new(&random_id(), &peer.ip, peer.port, peer.bus_port);
+                node.handshake = true;
+                self.nodes.push(node);
+            }
+        }
+
+        match message.kind {
+            Kind::Meet | Kind::Ping => Some(self.message(Kind::Pong, Some(node))),
+            Kind::Pong => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::{BUS_PORT_OFFSET, SLOTS};
+
+    fn peer(id: &str, port: u16) -> Peer {
+        Peer {
+            id: id.repeat(40),
+            ip: "127.0.0.1".to_string(),
+            port,
+            bus_port: port + BUS_PORT_OFFSET,
+        }
+    }
+
+    fn topology(id: &str, port: u16) -> Topology {
+        let mut topology = Topology {
+            nodes: Vec::new(),
+            owners: vec![None; usize::from(SLOTS)],
+            migrating: Default::default(),
+            importing: Default::default(),
+            current_epoch: 0,
+        };
+        let me = peer(id, port);
+        topology
+            .nodes
+            .push(Node::new(&me.id, &me.ip, me.port, me.bus_port));
+        topology
+    }
+
+    #[test]
+    fn should_decode_what_was_encoded() {
+        let message = Message {
+            kind: Kind::Ping,
+            sender: peer("a", 7000),
+            current_epoch: 3,
+            config_epoch: 2,
+            slots: vec![(0, 5460), (6000, 6000)],
+            gossip: vec![peer("b", 7001), peer("c", 7002)],
+        };
+        let mut buf = message.encode();
+        let entries = resp::decode(&mut buf, &Limits::default()).unwrap().unwrap();
+        assert_eq!(Message::decode(&entries), Some(message));
+    }
+
+    #[test]
+    fn should_agree_on_slots_once_met() {
+        let mut a = topology("a", 7000);
+        let mut b = topology("b", 7001);
+        a.assign(&[(0, 99)], 0).unwrap();
+        b.assign(&[(100, 199)], 0).unwrap();
+
+        a.nodes.push(Node::new("made up", "127.0.0.1", 7001, 17001));
+        a.nodes[1].handshake = true;
+        let meet = a.ping(&("127.0.0.1".to_string(), 17001), 1).unwrap();
+        assert_eq!(meet.kind, Kind::Meet);
+        let pong = b.receive(&meet, None, 1).unwrap();
+        assert_eq!(pong.kind, Kind::Pong);
+        assert_eq!(
+            a.receive(&pong, Some(&("127.0.0.1".to_string(), 17001)), 2),
+            None
+        );
+
+        assert_eq!(a.nodes[1].id, "b".repeat(40));
+        assert!(!a.nodes[1].handshake);
+        assert_eq!(a.nodes[1].ping_sent, 0);
+        assert_eq!(a.owners[150], Some(1));
+        assert_eq!(b.owners[50], Some(1));
+        // Both claimed their slots in epoch 0, so the greater ID moved on.
+        assert_eq!((a.current_epoch, b.current_epoch), (1, 1));
+        assert_eq!((a.nodes[0].config_epoch, a.nodes[1].config_epoch), (0, 1));
+    }
+
+    #[test]
+    fn should_hand_slots_to_the_latest_claim() {
+        let mut a = topology("a", 7000);
+        a.nodes
+            .push(Node::new(&"b".repeat(40), "127.0.0.1", 7001, 17001));
+        a.assign(&[(0, 99)], 0).unwrap();
+        a.bump_epoch();
+        let mut ping = Message {
+            kind: Kind::Ping,
+            sender: peer("b", 7001),
+            current_epoch: 1,
+            config_epoch: 1,
+            slots: vec![(50, 50)],
+            gossip: vec![peer("c", 7002)],
+        };
+        a.receive(&ping, None, 1).unwrap();
+        assert_eq!(a.owners[50], Some(0));
+        ping.current_epoch = 2;
+        ping.config_epoch = 2;
+        a.receive(&ping, None, 1).unwrap();
+        assert_eq!(a.owners[50], Some(1));
+        // Nodes gossiped about are met.
+        assert_eq!(a.nodes.len(), 3);
+        assert!(a.nodes[2].handshake);
+        assert_eq!(a.nodes[2].bus_port, 17002);
+
+        a.check_failures(20_000, 15_000);
+        assert_eq!(a.nodes.len(), 3);
+        a.ping(&("127.0.0.1".to_string(), 17002), 20_000);
+        a.check_failures(40_000, 15_000);
+        assert_eq!(a.nodes.len(), 2);
+    }
+}
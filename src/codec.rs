@@ -0,0 +1,143 @@
+//! RESP framing for tokio_util's `Framed`, so client connections and links
+//! to other servers split and render frames the same way.
+
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+    io,
+};
+
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::resp::{self, Entry, Limits, Partial, Protocol, ProtocolError};
+
+#[derive(Debug)]
+pub enum CodecError {
+    Protocol(ProtocolError),
+    Io(io::Error),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            CodecError::Protocol(err) => err.fmt(f),
+            CodecError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for CodecError {}
+
+impl From<ProtocolError> for CodecError {
+    fn from(err: ProtocolError) -> Self {
+        CodecError::Protocol(err)
+    }
+}
+
+impl From<io::Error> for CodecError {
+    fn from(err: io::Error) -> Self {
+        CodecError::Io(err)
+    }
+}
+
+/// Decodes requests, each an array of arguments or an inline command, and
+/// encodes entries in the protocol the peer negotiated.
+#[derive(Clone, Debug, Default)]
+pub struct RespCodec {
+    protocol: Protocol,
+    limits: Limits,
+    /// The request whose arguments are still arriving.
+    partial: Option<Partial>,
+}
+
+impl RespCodec {
+    pub fn new(protocol: Protocol) -> RespCodec {
+        RespCodec {
+            protocol,
+            limits: Limits::default(),
+            partial: None,
+        }
+    }
+
+    /// Rejects requests declaring lengths over `limits`.
+    pub fn with_limits(mut self, limits: Limits) -> RespCodec {
+        self.limits = limits;
+        self
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Bytes of a request that are decoded already while the rest of it is
+    /// awaited, no longer in the buffer decoded from.
+    pub fn partial_len(&self) -> usize {
+        self.partial.as_ref().map_or(0, Partial::len)
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Vec<Entry>;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<Entry>>, CodecError> {
+        Ok(resp::decode_partial(src, &mut self.partial, &self.limits)?)
+    }
+}
+
+impl Encoder<&Entry> for RespCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: &Entry, dst: &mut BytesMut) -> Result<(), CodecError> {
+        item.encode_as(self.protocol, dst);
+        Ok(())
+    }
+}
+
+impl Encoder<Entry> for RespCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: Entry, dst: &mut BytesMut) -> Result<(), CodecError> {
+        self.encode(&item, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::codec::Framed;
+
+    #[tokio::test]
+    async fn should_exchange_frames_over_framed() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, RespCodec::default());
+        let mut server = Framed::new(server, RespCodec::new(Protocol::Resp3));
+
+        let request = Entry::Array(vec![
+            Entry::Text("ECHO".to_string()),
+            Entry::Text("hey".to_string()),
+        ]);
+        client.send(&request).await.unwrap();
+        let Entry::Array(expected) = request else {
+            unreachable!()
+        };
+        assert_eq!(server.next().await.unwrap().unwrap(), expected);
+
+        // Replies follow the protocol of the sending side.
+        server.send(Entry::Nil).await.unwrap();
+        let mut reply = [0; 3];
+        client.get_mut().read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"_\r\n");
+
+        client.get_mut().write_all(b"*2\r\n$3").await.unwrap();
+        drop(client);
+        assert!(server.next().await.unwrap().is_err());
+    }
+}
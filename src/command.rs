@@ -1,16 +1,51 @@
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     error::Error,
     fmt::{Display, Formatter},
-    time::{Duration, Instant},
+    hash::{BuildHasherDefault, Hasher},
+    sync::OnceLock,
+    time::Duration,
 };
 
 use async_trait::async_trait;
 
 use crate::{
-    resp::{Array, Entry},
-    storage::{Storage, Value},
+    aof::AOF_STATUS,
+    client::ClientState,
+    cluster::CLUSTER,
+    notify::{NotifyFlags, KEYSPACE_EVENTS},
+    replication::REPLICATION,
+    resp::{Entry, Protocol},
+    server::config::ConfigRequest,
+    stats::STATS,
+    storage::{Data, Expiry, Storage, Value},
 };
 
+mod bitmap;
+mod client;
+mod cluster;
+mod debug;
+mod dump;
+mod functions;
+mod geo;
+mod hyperloglog;
+mod introspection;
+mod pubsub;
+mod replication;
+mod scripting;
+mod set;
+mod stream;
+mod zset;
+
+/// Reply sent when a command is run against a key holding another data type.
+fn wrong_type() -> Entry {
+    Entry::error(
+        "WRONGTYPE",
+        "Operation against a key holding the wrong kind of value",
+    )
+}
+
 #[derive(Debug, Clone)]
 pub struct CommandError;
 
@@ -24,89 +59,1021 @@ impl Error for CommandError {}
 
 #[async_trait]
 pub trait Command: Send + Sync {
-    async fn execute(&self, storage: &dyn Storage) -> Result<String, CommandError>;
+    /// Runs the command against the shared `storage` on behalf of `client`,
+    /// whose state it may read or change.
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError>;
+
+    /// Commands that can wait for data (BZPOPMIN, ...) expose their blocking
+    /// side here so the server can park them; `execute` alone never blocks.
+    fn as_blocking(&self) -> Option<&dyn BlockingCommand> {
+        None
+    }
+}
+
+/// A command that waits until one of its keys is able to serve it.
+#[async_trait]
+pub trait BlockingCommand: Send + Sync {
+    fn keys(&self) -> &[String];
+
+    /// How long to wait before replying with nil, `None` meaning forever.
+    fn timeout(&self) -> Option<Duration>;
+
+    /// Serves the command if one of its keys has data, `None` otherwise.
+    async fn try_execute(&self, storage: &dyn Storage) -> Result<Option<Entry>, CommandError>;
 }
 
 fn parse_arg(args: &[Entry], at: usize) -> Result<String, CommandError> {
     args.get(at)
         .and_then(|entry| match entry {
             Entry::Text(text) => Some(text.to_string()),
+            // Only what has to stay binary is read as bytes.
+            Entry::Bulk(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
             _ => None,
         })
         .ok_or(CommandError)
 }
 
+/// The argument at position `at` as the bytes the client sent.
+fn parse_bytes_arg(args: &[Entry], at: usize) -> Result<Vec<u8>, CommandError> {
+    args.get(at)
+        .and_then(|entry| match entry {
+            Entry::Text(text) => Some(text.as_bytes().to_vec()),
+            Entry::Bulk(bytes) => Some(bytes.to_vec()),
+            _ => None,
+        })
+        .ok_or(CommandError)
+}
+
+/// Parses a blocking timeout given in (possibly fractional) seconds, where
+/// zero means waiting forever.
+fn parse_timeout_arg(args: &[Entry], at: usize) -> Result<Option<Duration>, CommandError> {
+    let seconds = parse_arg(args, at)?
+        .parse::<f64>()
+        .map_err(|_| CommandError)?;
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(_) if seconds == 0.0 => Ok(None),
+        Ok(timeout) => Ok(Some(timeout)),
+        Err(_) => Err(CommandError),
+    }
+}
+
+fn parse_int_arg(args: &[Entry], at: usize) -> Result<i64, CommandError> {
+    parse_arg(args, at)?
+        .parse::<i64>()
+        .map_err(|_| CommandError)
+}
+
+/// Collects every text argument starting at position `from`.
+fn parse_rest(args: &[Entry], from: usize) -> Vec<String> {
+    (from..args.len())
+        .filter_map(|at| parse_arg(args, at).ok())
+        .collect()
+}
+
+/// Builds a command from its name, as listed in `COMMANDS`, and the full
+/// request.
+type Parse = fn(&str, &[Entry]) -> Result<Box<dyn Command>, CommandError>;
+
+/// How the server treats a command, as COMMAND INFO names it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flag {
+    /// Changes the dataset, so replicas refuse it, CLIENT PAUSE WRITE holds
+    /// it back and it is propagated.
+    Write,
+    Readonly,
+    /// May take more memory, so it is refused when over maxmemory with
+    /// nothing left to evict.
+    Denyoom,
+    Admin,
+    Pubsub,
+    /// Scripts may not run it, as it changes how the client is served or
+    /// runs scripts itself.
+    Noscript,
+    /// May wait for data to serve it.
+    Blocking,
+    /// Takes constant or logarithmic time.
+    Fast,
+    /// Where its keys are depends on its other arguments, which `keys`
+    /// knows.
+    Movablekeys,
+}
+
+impl Flag {
+    fn name(self) -> &'static str {
+        match self {
+            Flag::Write => "write",
+            Flag::Readonly => "readonly",
+            Flag::Denyoom => "denyoom",
+            Flag::Admin => "admin",
+            Flag::Pubsub => "pubsub",
+            Flag::Noscript => "noscript",
+            Flag::Blocking => "blocking",
+            Flag::Fast => "fast",
+            Flag::Movablekeys => "movablekeys",
+        }
+    }
+}
+
+/// What a command is about: the data type it works on, or the part of the
+/// server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Group {
+    /// Keys of any type.
+    Generic,
+    String,
+    Set,
+    SortedSet,
+    Bitmap,
+    Hyperloglog,
+    Geo,
+    Stream,
+    Pubsub,
+    Scripting,
+    Connection,
+    Server,
+    Cluster,
+}
+
+impl Group {
+    /// The ACL category every command of the group is in, if any.
+    fn category(self) -> Option<&'static str> {
+        match self {
+            Group::Generic => Some("keyspace"),
+            Group::String => Some("string"),
+            Group::Set => Some("set"),
+            Group::SortedSet => Some("sortedset"),
+            Group::Bitmap => Some("bitmap"),
+            Group::Hyperloglog => Some("hyperloglog"),
+            Group::Geo => Some("geo"),
+            Group::Stream => Some("stream"),
+            Group::Pubsub => Some("pubsub"),
+            Group::Scripting => Some("scripting"),
+            Group::Connection => Some("connection"),
+            Group::Server | Group::Cluster => None,
+        }
+    }
+}
+
+/// Every ACL category, in the order Redis lists them.
+const ACL_CATEGORIES: &[&str] = &[
+    "keyspace",
+    "read",
+    "write",
+    "set",
+    "sortedset",
+    "list",
+    "hash",
+    "string",
+    "bitmap",
+    "hyperloglog",
+    "geo",
+    "stream",
+    "pubsub",
+    "admin",
+    "fast",
+    "slow",
+    "blocking",
+    "dangerous",
+    "connection",
+    "transaction",
+    "scripting",
+];
+
+/// A supported command: what the server knows of it, and how to parse it.
+#[derive(Clone, Copy)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// As on Redis, the arity counts the command name and a negative one is
+    /// a minimum.
+    pub arity: i32,
+    pub group: Group,
+    pub flags: &'static [Flag],
+    /// Where the keys are, from the first position to the last, a negative
+    /// one counting back from the end, every `key_step`. All zero for
+    /// commands taking none, or none at fixed positions.
+    pub first_key: usize,
+    pub last_key: isize,
+    pub key_step: usize,
+    parse: Parse,
+}
+
+/// A command taking no keys, without flags.
+const fn command(name: &'static str, arity: i32, group: Group, parse: Parse) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        group,
+        flags: &[],
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        parse,
+    }
+}
+
+impl CommandSpec {
+    const fn flags(self, flags: &'static [Flag]) -> CommandSpec {
+        CommandSpec { flags, ..self }
+    }
+
+    const fn keys(self, first_key: usize, last_key: isize, key_step: usize) -> CommandSpec {
+        CommandSpec {
+            first_key,
+            last_key,
+            key_step,
+            ..self
+        }
+    }
+
+    /// Whether `request` has as many arguments as the arity allows.
+    pub fn accepts(&self, request: &[Entry]) -> bool {
+        let len = request.len() as i32;
+        (self.arity < 0 || len == self.arity) && len >= self.arity.abs()
+    }
+
+    pub fn has(&self, flag: Flag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    /// Whether the command is in the ACL `category`, which Redis derives
+    /// from its flags and group. Scripts are neither reads nor writes,
+    /// whatever they run.
+    pub fn in_category(&self, category: &str) -> bool {
+        let scripting = self.group == Group::Scripting;
+        match category {
+            "read" => self.has(Flag::Readonly) && !scripting,
+            "write" => self.has(Flag::Write) && !scripting,
+            "admin" | "dangerous" => self.has(Flag::Admin),
+            "pubsub" => self.has(Flag::Pubsub),
+            "fast" => self.has(Flag::Fast),
+            "slow" => !self.has(Flag::Fast),
+            "blocking" => self.has(Flag::Blocking),
+            category => self.group.category() == Some(category),
+        }
+    }
+
+    /// The ACL categories the command is in.
+    pub fn acl_categories(&self) -> Vec<&'static str> {
+        ACL_CATEGORIES
+            .iter()
+            .copied()
+            .filter(|category| self.in_category(category))
+            .collect()
+    }
+}
+
+/// Every supported command. Data type modules parse their whole family, so
+/// they appear once per command name.
+const COMMANDS: &[CommandSpec] = &[
+    command("PING", -1, Group::Connection, parse_ping).flags(&[Flag::Fast]),
+    command("AUTH", -2, Group::Connection, parse_auth).flags(&[Flag::Noscript, Flag::Fast]),
+    command("HELLO", -1, Group::Connection, parse_hello).flags(&[Flag::Noscript, Flag::Fast]),
+    command("RESET", 1, Group::Connection, parse_reset).flags(&[Flag::Noscript, Flag::Fast]),
+    command("SUBSCRIBE", -2, Group::Pubsub, pubsub::parse).flags(&[Flag::Pubsub, Flag::Noscript]),
+    command("UNSUBSCRIBE", -1, Group::Pubsub, pubsub::parse).flags(&[Flag::Pubsub, Flag::Noscript]),
+    command("PSUBSCRIBE", -2, Group::Pubsub, pubsub::parse).flags(&[Flag::Pubsub, Flag::Noscript]),
+    command("PUNSUBSCRIBE", -1, Group::Pubsub, pubsub::parse)
+        .flags(&[Flag::Pubsub, Flag::Noscript]),
+    command("SSUBSCRIBE", -2, Group::Pubsub, pubsub::parse)
+        .flags(&[Flag::Pubsub, Flag::Noscript])
+        .keys(1, -1, 1),
+    command("SUNSUBSCRIBE", -1, Group::Pubsub, pubsub::parse)
+        .flags(&[Flag::Pubsub, Flag::Noscript])
+        .keys(1, -1, 1),
+    command("PUBSUB", -2, Group::Pubsub, pubsub::parse).flags(&[Flag::Pubsub]),
+    command("PUBLISH", 3, Group::Pubsub, pubsub::parse).flags(&[Flag::Pubsub, Flag::Fast]),
+    // Scripts run as writes, for nothing to run while one does.
+    command("EVAL", -3, Group::Scripting, scripting::parse).flags(&[
+        Flag::Write,
+        Flag::Noscript,
+        Flag::Movablekeys,
+    ]),
+    command("EVALSHA", -3, Group::Scripting, scripting::parse).flags(&[
+        Flag::Write,
+        Flag::Noscript,
+        Flag::Movablekeys,
+    ]),
+    command("SCRIPT", -2, Group::Scripting, scripting::parse).flags(&[Flag::Noscript]),
+    command("FCALL", -3, Group::Scripting, functions::parse).flags(&[
+        Flag::Write,
+        Flag::Noscript,
+        Flag::Movablekeys,
+    ]),
+    command("FCALL_RO", -3, Group::Scripting, functions::parse).flags(&[
+        Flag::Readonly,
+        Flag::Noscript,
+        Flag::Movablekeys,
+    ]),
+    command("FUNCTION", -2, Group::Scripting, functions::parse).flags(&[Flag::Noscript]),
+    command("SPUBLISH", 3, Group::Pubsub, pubsub::parse)
+        .flags(&[Flag::Pubsub, Flag::Fast])
+        .keys(1, 1, 1),
+    command("CLIENT", -2, Group::Connection, client::parse),
+    command("CLUSTER", -2, Group::Cluster, cluster::parse),
+    command("ASKING", 1, Group::Cluster, cluster::parse).flags(&[Flag::Fast]),
+    command("ECHO", 2, Group::Connection, parse_echo).flags(&[Flag::Fast]),
+    command("GET", 2, Group::String, parse_get)
+        .flags(&[Flag::Readonly, Flag::Fast])
+        .keys(1, 1, 1),
+    command("SET", -3, Group::String, parse_set)
+        .flags(&[Flag::Write, Flag::Denyoom])
+        .keys(1, 1, 1),
+    command("APPEND", 3, Group::String, parse_append)
+        .flags(&[Flag::Write, Flag::Denyoom, Flag::Fast])
+        .keys(1, 1, 1),
+    command("DEL", -2, Group::Generic, parse_del)
+        .flags(&[Flag::Write])
+        .keys(1, -1, 1),
+    command("DUMP", 2, Group::Generic, dump::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("RESTORE", -4, Group::Generic, dump::parse)
+        .flags(&[Flag::Write, Flag::Denyoom])
+        .keys(1, 1, 1),
+    command("RESTORE-ASKING", -4, Group::Generic, dump::parse)
+        .flags(&[Flag::Write, Flag::Denyoom])
+        .keys(1, 1, 1),
+    command("MIGRATE", -6, Group::Generic, dump::parse).flags(&[Flag::Write, Flag::Movablekeys]),
+    // Answered by the server, so not from scripts.
+    command("CONFIG", -2, Group::Server, parse_config).flags(&[Flag::Admin, Flag::Noscript]),
+    command("SAVE", 1, Group::Server, parse_save).flags(&[Flag::Admin]),
+    command("BGSAVE", 1, Group::Server, parse_bgsave).flags(&[Flag::Admin]),
+    command("BGREWRITEAOF", 1, Group::Server, parse_bgrewriteaof)
+        .flags(&[Flag::Admin, Flag::Noscript]),
+    command("LASTSAVE", 1, Group::Server, parse_lastsave).flags(&[Flag::Fast]),
+    command("SHUTDOWN", -1, Group::Server, parse_shutdown).flags(&[Flag::Admin, Flag::Noscript]),
+    command("KEYS", 2, Group::Generic, parse_keys).flags(&[Flag::Readonly]),
+    command("OBJECT", -2, Group::Generic, parse_object)
+        .flags(&[Flag::Readonly])
+        .keys(2, 2, 1),
+    command("DEBUG", -2, Group::Server, debug::parse).flags(&[Flag::Admin]),
+    command("INFO", -1, Group::Server, parse_info),
+    command("COMMAND", -1, Group::Server, introspection::parse),
+    command("REPLCONF", -1, Group::Server, replication::parse)
+        .flags(&[Flag::Admin, Flag::Noscript]),
+    command("PSYNC", 3, Group::Server, replication::parse).flags(&[Flag::Admin, Flag::Noscript]),
+    command("WAIT", 3, Group::Generic, replication::parse).flags(&[Flag::Noscript]),
+    command("REPLICAOF", 3, Group::Server, replication::parse)
+        .flags(&[Flag::Admin, Flag::Noscript]),
+    command("SLAVEOF", 3, Group::Server, replication::parse).flags(&[Flag::Admin, Flag::Noscript]),
+    command("SADD", -3, Group::Set, set::parse)
+        .flags(&[Flag::Write, Flag::Denyoom, Flag::Fast])
+        .keys(1, 1, 1),
+    command("SREM", -3, Group::Set, set::parse)
+        .flags(&[Flag::Write, Flag::Fast])
+        .keys(1, 1, 1),
+    command("SMEMBERS", 2, Group::Set, set::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("SISMEMBER", 3, Group::Set, set::parse)
+        .flags(&[Flag::Readonly, Flag::Fast])
+        .keys(1, 1, 1),
+    command("SCARD", 2, Group::Set, set::parse)
+        .flags(&[Flag::Readonly, Flag::Fast])
+        .keys(1, 1, 1),
+    command("SPOP", -2, Group::Set, set::parse)
+        .flags(&[Flag::Write, Flag::Fast])
+        .keys(1, 1, 1),
+    command("SRANDMEMBER", -2, Group::Set, set::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("SMOVE", 4, Group::Set, set::parse)
+        .flags(&[Flag::Write, Flag::Denyoom, Flag::Fast])
+        .keys(1, 2, 1),
+    command("SSCAN", -3, Group::Set, set::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("ZADD", -4, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Write, Flag::Denyoom, Flag::Fast])
+        .keys(1, 1, 1),
+    command("ZSCORE", 3, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Readonly, Flag::Fast])
+        .keys(1, 1, 1),
+    command("ZCARD", 2, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Readonly, Flag::Fast])
+        .keys(1, 1, 1),
+    command("ZREM", -3, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Write, Flag::Fast])
+        .keys(1, 1, 1),
+    command("ZINCRBY", 4, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Write, Flag::Denyoom, Flag::Fast])
+        .keys(1, 1, 1),
+    command("ZRANK", -3, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Readonly, Flag::Fast])
+        .keys(1, 1, 1),
+    command("ZREVRANK", -3, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Readonly, Flag::Fast])
+        .keys(1, 1, 1),
+    command("ZRANGE", -4, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("ZRANGEBYSCORE", -4, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("ZRANGEBYLEX", -4, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("ZPOPMIN", -2, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Write, Flag::Fast])
+        .keys(1, 1, 1),
+    command("ZPOPMAX", -2, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Write, Flag::Fast])
+        .keys(1, 1, 1),
+    command("BZPOPMIN", -3, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Write, Flag::Blocking, Flag::Fast])
+        .keys(1, -2, 1),
+    command("BZPOPMAX", -3, Group::SortedSet, zset::parse)
+        .flags(&[Flag::Write, Flag::Blocking, Flag::Fast])
+        .keys(1, -2, 1),
+    command("ZMPOP", -4, Group::SortedSet, zset::parse).flags(&[Flag::Write, Flag::Movablekeys]),
+    command("BZMPOP", -5, Group::SortedSet, zset::parse).flags(&[
+        Flag::Write,
+        Flag::Blocking,
+        Flag::Movablekeys,
+    ]),
+    command("SETBIT", 4, Group::Bitmap, bitmap::parse)
+        .flags(&[Flag::Write, Flag::Denyoom])
+        .keys(1, 1, 1),
+    command("GETBIT", 3, Group::Bitmap, bitmap::parse)
+        .flags(&[Flag::Readonly, Flag::Fast])
+        .keys(1, 1, 1),
+    command("BITCOUNT", -2, Group::Bitmap, bitmap::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("BITPOS", -3, Group::Bitmap, bitmap::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("BITOP", -4, Group::Bitmap, bitmap::parse)
+        .flags(&[Flag::Write, Flag::Denyoom])
+        .keys(2, -1, 1),
+    command("BITFIELD", -2, Group::Bitmap, bitmap::parse)
+        .flags(&[Flag::Write, Flag::Denyoom])
+        .keys(1, 1, 1),
+    command("BITFIELD_RO", -2, Group::Bitmap, bitmap::parse)
+        .flags(&[Flag::Readonly, Flag::Fast])
+        .keys(1, 1, 1),
+    command("GEOADD", -5, Group::Geo, geo::parse)
+        .flags(&[Flag::Write, Flag::Denyoom])
+        .keys(1, 1, 1),
+    command("GEOPOS", -2, Group::Geo, geo::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("GEODIST", -4, Group::Geo, geo::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("GEOSEARCH", -7, Group::Geo, geo::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("PFADD", -2, Group::Hyperloglog, hyperloglog::parse)
+        .flags(&[Flag::Write, Flag::Denyoom, Flag::Fast])
+        .keys(1, 1, 1),
+    command("PFCOUNT", -2, Group::Hyperloglog, hyperloglog::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, -1, 1),
+    command("PFMERGE", -2, Group::Hyperloglog, hyperloglog::parse)
+        .flags(&[Flag::Write, Flag::Denyoom])
+        .keys(1, -1, 1),
+    command("XADD", -5, Group::Stream, stream::parse)
+        .flags(&[Flag::Write, Flag::Denyoom, Flag::Fast])
+        .keys(1, 1, 1),
+    command("XLEN", 2, Group::Stream, stream::parse)
+        .flags(&[Flag::Readonly, Flag::Fast])
+        .keys(1, 1, 1),
+    command("XRANGE", -4, Group::Stream, stream::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("XREVRANGE", -4, Group::Stream, stream::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("XGROUP", -2, Group::Stream, stream::group::parse)
+        .flags(&[Flag::Write, Flag::Denyoom])
+        .keys(2, 2, 1),
+    command("XREADGROUP", -7, Group::Stream, stream::group::parse).flags(&[
+        Flag::Write,
+        Flag::Blocking,
+        Flag::Movablekeys,
+    ]),
+    command("XACK", -4, Group::Stream, stream::group::parse)
+        .flags(&[Flag::Write, Flag::Fast])
+        .keys(1, 1, 1),
+    command("XPENDING", -3, Group::Stream, stream::group::parse)
+        .flags(&[Flag::Readonly])
+        .keys(1, 1, 1),
+    command("XCLAIM", -6, Group::Stream, stream::group::parse)
+        .flags(&[Flag::Write, Flag::Fast])
+        .keys(1, 1, 1),
+    command("XAUTOCLAIM", -6, Group::Stream, stream::group::parse)
+        .flags(&[Flag::Write, Flag::Fast])
+        .keys(1, 1, 1),
+];
+
+/// FUNCTION subcommands changing the libraries, which are part of the
+/// dataset. Other subcommands are flagged as their command is.
+const WRITE_FUNCTION_SUBCOMMANDS: &[&str] = &["LOAD", "DELETE", "FLUSH", "RESTORE"];
+
+/// Whether the command named by the first argument of `request` is
+/// flagged with `flag`.
+fn flagged(request: &[Entry], flag: Flag) -> bool {
+    match request.first() {
+        Some(Entry::Text(name)) => command_table()
+            .get(&*command_name(name))
+            .is_some_and(|spec| spec.has(flag)),
+        _ => false,
+    }
+}
+
+/// Whether the command named by the first argument of `request` may change
+/// the dataset.
+pub fn is_write(request: &[Entry]) -> bool {
+    match request.first() {
+        Some(Entry::Text(name)) if command_name(name) == "DEBUG" => matches!(
+            request.get(1),
+            Some(Entry::Text(subcommand)) if subcommand.eq_ignore_ascii_case("LOADRDB")
+        ),
+        Some(Entry::Text(name)) if command_name(name) == "FUNCTION" => matches!(
+            request.get(1),
+            Some(Entry::Text(subcommand))
+                if WRITE_FUNCTION_SUBCOMMANDS.contains(&&*command_name(subcommand))
+        ),
+        _ => flagged(request, Flag::Write),
+    }
+}
+
+/// Whether `request` may get to keys it doesn't name, and so has to hold
+/// every key while it runs: scripts, DEBUG, which may reload the dataset,
+/// and writes naming no key such as FUNCTION LOAD.
+pub fn reaches_every_key(request: &[Entry]) -> bool {
+    let Some(Entry::Text(name)) = request.first() else {
+        return false;
+    };
+    let name = command_name(name);
+    let script = command_table()
+        .get(&*name)
+        .is_some_and(|spec| spec.group == Group::Scripting && spec.has(Flag::Movablekeys));
+    script || name == "DEBUG" || (is_write(request) && keys(request).is_empty())
+}
+
+/// The keys `request` names, for cluster mode to tell which node serves
+/// them and for COMMAND GETKEYS. Binary keys are read like `parse_arg`
+/// reads them.
+pub fn keys(request: &[Entry]) -> Vec<Cow<'_, str>> {
+    let Some(Entry::Text(name)) = request.first() else {
+        return vec![];
+    };
+    // Counted keys follow their count.
+    let counted = |at: usize| match request.get(at) {
+        Some(Entry::Text(count)) => count
+            .parse()
+            .map_or(0..0, |count: usize| at + 1..(at + 1).saturating_add(count)),
+        _ => 0..0,
+    };
+    let mut step = 1;
+    // Commands flagged movablekeys are worked out from their arguments.
+    let positions = match &*command_name(name) {
+        "ZMPOP" => counted(1),
+        "EVAL" | "EVALSHA" | "FCALL" | "FCALL_RO" => counted(2),
+        "BZMPOP" => counted(2),
+        // Several keys follow KEYS in place of the one key.
+        "MIGRATE" => match request.get(3) {
+            Some(Entry::Text(key)) if key.is_empty() => {
+                let keys = request.iter().position(
+                    |arg| matches!(arg, Entry::Text(arg) if arg.eq_ignore_ascii_case("KEYS")),
+                );
+                keys.map_or(0..0, |at| at + 1..request.len())
+            }
+            _ => 3..4,
+        },
+        // As many keys as IDs follow STREAMS.
+        "XREADGROUP" => {
+            let streams = request.iter().position(
+                |arg| matches!(arg, Entry::Text(arg) if arg.eq_ignore_ascii_case("STREAMS")),
+            );
+            match streams {
+                Some(at) => at + 1..at + 1 + (request.len() - at - 1) / 2,
+                None => 0..0,
+            }
+        }
+        name => match command_table().get(name) {
+            Some(spec) if spec.first_key > 0 => {
+                step = spec.key_step;
+                let last = match spec.last_key {
+                    last if last < 0 => request.len().saturating_add_signed(last),
+                    last => last as usize,
+                };
+                spec.first_key..last + 1
+            }
+            _ => 0..0,
+        },
+    };
+    // Counts are as the client sent them, so past the end is cut off.
+    (positions.start..positions.end.min(request.len()))
+        .step_by(step)
+        .filter_map(|at| match request.get(at) {
+            Some(Entry::Text(key)) => Some(Cow::Borrowed(key.as_str())),
+            Some(Entry::Bulk(key)) => Some(String::from_utf8_lossy(key)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether the command named by the first argument of `request` is refused
+/// once memory is full.
+pub fn is_denyoom(request: &[Entry]) -> bool {
+    flagged(request, Flag::Denyoom)
+}
+
+/// What clients subscribed to channels may run on RESP2, where their
+/// replies couldn't be told apart from messages otherwise.
+const SUBSCRIBER_COMMANDS: &[&str] = &[
+    "SUBSCRIBE",
+    "UNSUBSCRIBE",
+    "PSUBSCRIBE",
+    "PUNSUBSCRIBE",
+    "SSUBSCRIBE",
+    "SUNSUBSCRIBE",
+    "PING",
+    "RESET",
+];
+
+/// Whether the command named by the first argument of `request` may be run
+/// by a client subscribed to channels on RESP2.
+pub fn is_subscriber_command(request: &[Entry]) -> bool {
+    match request.first() {
+        Some(Entry::Text(name)) => SUBSCRIBER_COMMANDS.contains(&&*command_name(name)),
+        _ => false,
+    }
+}
+
+/// Whether scripts may run the command named by the first argument of
+/// `request`.
+fn is_scriptable(request: &[Entry]) -> bool {
+    match request.first() {
+        Some(_) => !flagged(request, Flag::Noscript),
+        None => false,
+    }
+}
+
+/// FNV-1a, much cheaper than the default SipHash on short command names.
+struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        FnvHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+type CommandTable = HashMap<&'static str, &'static CommandSpec, BuildHasherDefault<FnvHasher>>;
+
+/// Lookup table over `COMMANDS`, built on first use.
+fn command_table() -> &'static CommandTable {
+    static TABLE: OnceLock<CommandTable> = OnceLock::new();
+    TABLE.get_or_init(|| COMMANDS.iter().map(|spec| (spec.name, spec)).collect())
+}
+
+/// `name` in upper case, as listed in `COMMANDS`. Most clients already send
+/// upper case names, which are borrowed rather than copied.
+fn command_name(name: &str) -> Cow<'_, str> {
+    if name.bytes().any(|byte| byte.is_ascii_lowercase()) {
+        Cow::Owned(name.to_ascii_uppercase())
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// Why a request could not be turned into a command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// No command goes by the first argument.
+    Unknown,
+    /// The command was given too few or too many arguments.
+    Arity,
+    /// The arguments are of the right number but not understood.
+    Syntax,
+}
+
+impl ParseError {
+    /// The error reply for `request`, worded as on Redis.
+    pub fn reply(&self, request: &[Entry]) -> Entry {
+        let arg = |at: usize| match request.get(at) {
+            Some(Entry::Text(text)) => text.chars().take(128).collect(),
+            _ => String::new(),
+        };
+        match self {
+            ParseError::Unknown => {
+                let mut message =
+                    format!("unknown command '{}', with args beginning with: ", arg(0));
+                for at in 1..request.len() {
+                    message.push_str(&format!("'{}' ", arg(at)));
+                }
+                Entry::error("ERR", message)
+            }
+            ParseError::Arity => Entry::error(
+                "ERR",
+                format!(
+                    "wrong number of arguments for '{}' command",
+                    arg(0).to_lowercase()
+                ),
+            ),
+            ParseError::Syntax => Entry::error("ERR", "syntax error"),
+        }
+    }
+}
+
 pub struct CommandParser;
 
 impl CommandParser {
-    pub fn new(args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(args: &[Entry]) -> Result<Box<dyn Command>, ParseError> {
         // Extract the first argument (command name)
         let cmd = match args.first() {
-            Some(Entry::Text(cmd)) => cmd.as_str(),
-            _ => return Err(CommandError), // Return an error if the command name is missing or invalid
+            Some(Entry::Text(cmd)) => command_name(cmd),
+            _ => return Err(ParseError::Unknown), // The command name is missing or invalid
         };
 
-        // Match the command name to create the corresponding command
-        let cmd_kind: Box<dyn Command> = match cmd {
-            "PING" => Box::new(PingCommand {}),
-
-            "ECHO" => {
-                let echo_args = args
-                    .iter()
-                    .skip(1)
-                    .filter_map(|entry| match entry {
-                        Entry::Text(text) => Some(text.clone()),
-                        _ => None, // Skip non-text arguments
-                    })
-                    .collect();
-
-                Box::new(EchoCommand { args: echo_args })
-            }
+        let spec = command_table().get(&*cmd).ok_or(ParseError::Unknown)?;
+        if !spec.accepts(args) {
+            return Err(ParseError::Arity);
+        }
+        (spec.parse)(&cmd, args).map_err(|_| ParseError::Syntax)
+    }
+}
 
-            "GET" => Box::new(GetCommand {
-                key: parse_arg(args, 1)?,
-            }),
-
-            "SET" => {
-                let key = parse_arg(args, 1)?;
-                let value = parse_arg(args, 2)?;
-
-                let expiry = if args.len() == 5 {
-                    args.get(4).and_then(|entry| match entry {
-                        Entry::Text(number) => {
-                            let number = number.parse::<u64>().expect("could not parse number");
-                            Some(Instant::now() + Duration::from_millis(number))
-                        }
-                        _ => None,
-                    })
-                } else {
-                    None
-                };
+fn parse_ping(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    if args.len() > 2 {
+        return Err(CommandError);
+    }
+    Ok(Box::new(PingCommand {
+        message: parse_arg(args, 1).ok(),
+    }))
+}
 
-                Box::new(SetCommand { key, value, expiry })
-            }
+fn parse_echo(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let echo_args = args
+        .iter()
+        .skip(1)
+        .filter_map(|entry| match entry {
+            Entry::Text(text) => Some(text.clone()),
+            _ => None, // Skip non-text arguments
+        })
+        .collect();
 
-            "CONFIG" => {
-                let key = parse_arg(args, 2)?;
-                Box::new(ConfigGetCommand { key })
-            }
+    Ok(Box::new(EchoCommand { args: echo_args }))
+}
+
+fn parse_get(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    Ok(Box::new(GetCommand {
+        key: parse_arg(args, 1)?,
+    }))
+}
+
+fn parse_del(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    Ok(Box::new(DelCommand {
+        keys: parse_rest(args, 1),
+    }))
+}
+
+fn parse_set(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let key = parse_arg(args, 1)?;
+    let value = parse_arg(args, 2)?;
+
+    let mut expiry = None;
+    let mut at = 3;
+    while at < args.len() {
+        let option = parse_arg(args, at)?.to_uppercase();
+        let time = match u64::try_from(parse_int_arg(args, at + 1)?) {
+            Ok(time) if time > 0 && expiry.is_none() => time,
+            _ => return Err(CommandError),
+        };
+        // Expiries past what a timestamp can hold are rejected, as Redis does.
+        expiry = match option.as_str() {
+            "EX" => Expiry::after(Duration::from_secs(time)),
+            "PX" => Expiry::after(Duration::from_millis(time)),
+            "EXAT" => time.checked_mul(1000).map(Expiry::from_unix_ms),
+            "PXAT" => Some(Expiry::from_unix_ms(time)),
+            _ => return Err(CommandError),
+        };
+        if expiry.is_none() {
+            return Err(CommandError);
+        }
+        at += 2;
+    }
+
+    Ok(Box::new(SetCommand { key, value, expiry }))
+}
+
+fn parse_append(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    Ok(Box::new(AppendCommand {
+        key: parse_arg(args, 1)?,
+        value: parse_bytes_arg(args, 2)?,
+    }))
+}
+
+fn parse_config(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let subcommand = parse_arg(args, 1)?.to_uppercase();
+    let request = match (subcommand.as_str(), args.len()) {
+        ("GET", 3..) => ConfigRequest::Get(parse_rest(args, 2)),
+        ("SET", len) if len >= 4 && len.is_multiple_of(2) => ConfigRequest::Set(
+            (2..len)
+                .step_by(2)
+                .map(|at| Ok((parse_arg(args, at)?, parse_arg(args, at + 1)?)))
+                .collect::<Result<_, _>>()?,
+        ),
+        ("REWRITE", 2) => ConfigRequest::Rewrite,
+        _ => return Err(CommandError),
+    };
+    Ok(Box::new(ConfigCommand { request }))
+}
+
+fn parse_save(_: &str, _: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    Ok(Box::new(SaveCommand))
+}
 
-            "SAVE" => Box::new(SaveCommand),
+fn parse_bgsave(_: &str, _: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    Ok(Box::new(BgsaveCommand))
+}
+
+fn parse_bgrewriteaof(_: &str, _: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    Ok(Box::new(BgrewriteaofCommand))
+}
+
+fn parse_lastsave(_: &str, _: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    Ok(Box::new(LastsaveCommand))
+}
+
+fn parse_auth(_: &str, _: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    Ok(Box::new(AuthCommand))
+}
+
+fn parse_reset(_: &str, _: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    Ok(Box::new(ResetCommand))
+}
+
+fn parse_shutdown(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let mut save = None;
+    let mut force = false;
+    for at in 1..args.len() {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            "SAVE" if save.is_none() => save = Some(true),
+            "NOSAVE" if save.is_none() => save = Some(false),
+            // Nothing is waited for before stopping anyway.
+            "NOW" => {}
+            "FORCE" => force = true,
+            _ => return Err(CommandError),
+        }
+    }
+    Ok(Box::new(ShutdownCommand {
+        save: save.unwrap_or(true),
+        force,
+    }))
+}
+
+fn parse_keys(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let key = parse_arg(args, 1)?;
+    Ok(Box::new(KeysCommand { key }))
+}
+
+fn parse_object(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    if args.len() != 3 {
+        return Err(CommandError);
+    }
+    let key = parse_arg(args, 2)?;
+    match parse_arg(args, 1)?.to_uppercase().as_str() {
+        "ENCODING" => Ok(Box::new(ObjectEncodingCommand { key })),
+        "FREQ" => Ok(Box::new(ObjectFreqCommand { key })),
+        _ => Err(CommandError),
+    }
+}
 
-            "KEYS" => {
-                let key = parse_arg(args, 1)?;
-                Box::new(KeysCommand { key })
+fn parse_info(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let section = match args.len() {
+        1 => None,
+        _ => Some(parse_arg(args, 1)?.to_lowercase()),
+    };
+    Ok(Box::new(InfoCommand { section }))
+}
+
+/// Redis version reported to clients, matching the commands implemented.
+const REDIS_VERSION: &str = "7.2.0";
+
+/// HELLO switches the protocol replies are encoded in and may name the
+/// client. Its errors are worded differently from other commands, so
+/// rejected requests are only answered once executed.
+///
+/// The server checks the password of its AUTH option first. Without one
+/// configured, `AUTH default <any password>` succeeds like on Redis.
+fn parse_hello(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    Ok(Box::new(HelloCommand {
+        request: parse_hello_options(args),
+    }))
+}
+
+/// What a HELLO request asks to change.
+struct Hello {
+    protocol: Option<Protocol>,
+    name: Option<String>,
+}
+
+fn parse_hello_options(args: &[Entry]) -> Result<Hello, Entry> {
+    let syntax_error = || Entry::error("ERR", "syntax error");
+    let protocol = match args.get(1) {
+        None => {
+            return Ok(Hello {
+                protocol: None,
+                name: None,
+            })
+        }
+        Some(_) => match parse_int_arg(args, 1) {
+            Ok(2) => Protocol::Resp2,
+            Ok(3) => Protocol::Resp3,
+            Ok(_) => return Err(Entry::error("NOPROTO", "unsupported protocol version")),
+            Err(_) => {
+                return Err(Entry::error(
+                    "ERR",
+                    "Protocol version is not an integer or out of range",
+                ))
             }
+        },
+    };
 
-            "INFO" => {
-                // let key = parse_arg(args, 1)?;
-                Box::new(InfoCommand {})
+    let mut name = None;
+    let mut at = 2;
+    while at < args.len() {
+        let option = parse_arg(args, at).map_err(|_| syntax_error())?;
+        match option.to_uppercase().as_str() {
+            "AUTH" => {
+                let user = parse_arg(args, at + 1).map_err(|_| syntax_error())?;
+                parse_arg(args, at + 2).map_err(|_| syntax_error())?;
+                if user != "default" {
+                    return Err(Entry::error(
+                        "WRONGPASS",
+                        "invalid username-password pair or user is disabled.",
+                    ));
+                }
+                at += 3;
             }
+            "SETNAME" => {
+                let client_name = parse_arg(args, at + 1).map_err(|_| syntax_error())?;
+                client::check_name(&client_name)?;
+                name = Some(client_name);
+                at += 2;
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+    Ok(Hello {
+        protocol: Some(protocol),
+        name,
+    })
+}
 
-            _ => return Err(CommandError), // Unknown command
+pub struct HelloCommand {
+    request: Result<Hello, Entry>,
+}
+
+#[async_trait]
+impl Command for HelloCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let hello = match &self.request {
+            Ok(hello) => hello,
+            Err(reply) => return Ok(reply.clone()),
         };
+        if let Some(protocol) = hello.protocol {
+            client.protocol = protocol;
+        }
+        if let Some(name) = &hello.name {
+            client.name = Some(name.clone());
+        }
 
-        Ok(cmd_kind)
+        let text = |text: &str| Entry::Text(text.to_string());
+        Ok(Entry::Map(vec![
+            (text("server"), text("redis")),
+            (text("version"), text(REDIS_VERSION)),
+            (text("proto"), Entry::Int(client.protocol.version())),
+            (text("id"), Entry::Int(client.id() as i64)),
+            (text("mode"), text("standalone")),
+            (text("role"), text("master")),
+            (text("modules"), Entry::Array(vec![])),
+        ]))
     }
 }
 
@@ -116,23 +1083,74 @@ pub struct GetCommand {
 
 #[async_trait]
 impl Command for GetCommand {
-    async fn execute(&self, storage: &dyn Storage) -> Result<String, CommandError> {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
         match storage.get(&self.key).await {
-            Some(value) => {
-                let msg = Entry::SimpleText(value.value.to_string());
-                Ok(msg.to_string())
+            Some(Value {
+                value: Data::String(value),
+                ..
+            }) => {
+                let msg = Entry::Bulk(value.into());
+                Ok(msg)
             }
-            None => Ok(Entry::Nil.to_string()),
+            Some(_) => Ok(wrong_type()),
+            None => Ok(Entry::Nil),
         }
     }
 }
 
-pub struct PingCommand;
+/// AUTH, whose password the server checks before running it, so it only
+/// runs once the client authenticated.
+pub struct AuthCommand;
+
+#[async_trait]
+impl Command for AuthCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        Ok(Entry::ok())
+    }
+}
+
+pub struct ResetCommand;
+
+#[async_trait]
+impl Command for ResetCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        client.reset();
+        Ok(Entry::SimpleText("RESET".to_string()))
+    }
+}
+
+/// Replies PONG, or the message if given one.
+pub struct PingCommand {
+    message: Option<String>,
+}
 
 #[async_trait]
 impl Command for PingCommand {
-    async fn execute(&self, _: &dyn Storage) -> Result<String, CommandError> {
-        Ok(Entry::SimpleText("PONG".to_string()).to_string())
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        // Subscribers on RESP2 are replied in the shape of a message.
+        if client.subscribed() && client.protocol == Protocol::Resp2 {
+            let message = self.message.clone().unwrap_or_default();
+            return Ok(Entry::Array(vec![
+                Entry::Text("pong".to_string()),
+                Entry::Text(message),
+            ]));
+        }
+        Ok(match &self.message {
+            Some(message) => Entry::Text(message.clone()),
+            None => Entry::SimpleText("PONG".to_string()),
+        })
     }
 }
 
@@ -143,9 +1161,9 @@ pub struct EchoCommand {
 
 #[async_trait]
 impl Command for EchoCommand {
-    async fn execute(&self, _: &dyn Storage) -> Result<String, CommandError> {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
         let msg = Entry::SimpleText(self.args.join("\r\n"));
-        Ok(msg.to_string())
+        Ok(msg)
     }
 }
 
@@ -153,50 +1171,138 @@ impl Command for EchoCommand {
 pub struct SetCommand {
     key: String,
     value: String,
-    expiry: Option<Instant>,
+    expiry: Option<Expiry>,
 }
 
 #[async_trait]
 impl Command for SetCommand {
-    async fn execute(&self, storage: &dyn Storage) -> Result<String, CommandError> {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
         storage
             .set(
                 self.key.clone(),
                 Value {
-                    value: self.value.clone(),
+                    value: Data::String(self.value.clone().into_bytes()),
                     expiry: self.expiry,
                 },
             )
             .await;
-        Ok(Entry::SimpleText("OK".to_string()).to_string())
+        KEYSPACE_EVENTS.notify(NotifyFlags::STRING, "set", &self.key);
+        if self.expiry.is_some() {
+            KEYSPACE_EVENTS.notify(NotifyFlags::GENERIC, "expire", &self.key);
+        }
+        client.expiry = self.expiry;
+        Ok(Entry::SimpleText("OK".to_string()))
     }
 }
 
-pub struct ConfigGetCommand {
+/// Appends to the string at `key`, created if missing, replying how long
+/// it is now.
+pub struct AppendCommand {
     key: String,
+    value: Vec<u8>,
+}
+
+#[async_trait]
+impl Command for AppendCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let appended = storage
+            .update_if_with(&self.key, |value| {
+                let stored = value.get_or_insert_with(|| Value {
+                    value: Data::String(Vec::new()),
+                    expiry: None,
+                });
+                let Data::String(string) = &mut stored.value else {
+                    return (None, false);
+                };
+                string.extend_from_slice(&self.value);
+                (Some(string.len()), true)
+            })
+            .await;
+        let Some(len) = appended else {
+            return Ok(wrong_type());
+        };
+        KEYSPACE_EVENTS.notify(NotifyFlags::STRING, "append", &self.key);
+        Ok(Entry::Int(len as i64))
+    }
+}
+
+/// Deletes keys of any type, replying how many there were.
+pub struct DelCommand {
+    keys: Vec<String>,
 }
 
 #[async_trait]
-impl Command for ConfigGetCommand {
-    async fn execute(&self, storage: &dyn Storage) -> Result<String, CommandError> {
-        let config = storage.config().await;
-        match self.key.as_str() {
-            "dir" => {
-                let msg = Array(vec![
-                    Entry::Text(self.key.to_string()),
-                    Entry::Text(config.dir),
-                ]);
-                Ok(msg.to_string())
+impl Command for DelCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let mut deleted = 0;
+        for key in &self.keys {
+            if storage.del(key).await {
+                KEYSPACE_EVENTS.notify(NotifyFlags::GENERIC, "del", key);
+                deleted += 1;
             }
-            "dbfilename" => {
-                let msg = Array(vec![
-                    Entry::Text(self.key.to_string()),
-                    Entry::Text(config.path),
-                ]);
-                Ok(msg.to_string())
+        }
+        Ok(Entry::Int(deleted))
+    }
+}
+
+/// CONFIG GET, SET or REWRITE, answered by the server.
+pub struct ConfigCommand {
+    request: ConfigRequest,
+}
+
+#[async_trait]
+impl Command for ConfigCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        client.config = Some(self.request.clone());
+        Ok(Entry::ok())
+    }
+}
+
+/// Stops the server, saving the dataset first unless asked not to. The
+/// server notices through the client state and closes the connection
+/// without replying.
+pub struct ShutdownCommand {
+    save: bool,
+    /// Stop even if saving failed.
+    force: bool,
+}
+
+#[async_trait]
+impl Command for ShutdownCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        if self.save {
+            if let Err(err) = storage.save().await {
+                eprintln!("failed saving before shutdown: {}", err);
+                if !self.force {
+                    return Ok(Entry::error(
+                        "ERR",
+                        "Errors trying to SHUTDOWN. Check logs.",
+                    ));
+                }
             }
-            _ => Ok(Entry::Nil.to_string()),
         }
+        client.shutdown = true;
+        Ok(Entry::ok())
     }
 }
 
@@ -204,9 +1310,64 @@ pub struct SaveCommand;
 
 #[async_trait]
 impl Command for SaveCommand {
-    async fn execute(&self, storage: &dyn Storage) -> Result<String, CommandError> {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        if storage.persistence().in_progress {
+            return Ok(Entry::error("ERR", "Background save already in progress"));
+        }
         storage.save().await.map_err(|_| CommandError)?;
-        Ok(Entry::Nil.to_string())
+        Ok(Entry::Nil)
+    }
+}
+
+/// Saves the dataset without making the client wait for it.
+pub struct BgsaveCommand;
+
+#[async_trait]
+impl Command for BgsaveCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        match storage.bgsave() {
+            true => Ok(Entry::SimpleText("Background saving started".to_string())),
+            false => Ok(Entry::error("ERR", "Background save already in progress")),
+        }
+    }
+}
+
+/// Asks the server to rewrite the append only file, which replies with an
+/// error instead if it can't start.
+pub struct BgrewriteaofCommand;
+
+#[async_trait]
+impl Command for BgrewriteaofCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        client.rewrite_aof = true;
+        Ok(Entry::SimpleText(
+            "Background append only file rewriting started".to_string(),
+        ))
+    }
+}
+
+pub struct LastsaveCommand;
+
+#[async_trait]
+impl Command for LastsaveCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        Ok(Entry::Int(storage.persistence().last_save as i64))
     }
 }
 
@@ -216,21 +1377,661 @@ pub struct KeysCommand {
 
 #[async_trait]
 impl Command for KeysCommand {
-    async fn execute(&self, storage: &dyn Storage) -> Result<String, CommandError> {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
         match storage.keys(&self.key).await {
-            None => Ok(Entry::Nil.to_string()),
-            Some(v) => Ok(Array(v.iter().map(|k| Entry::Text(k.clone())).collect()).to_string()),
+            None => Ok(Entry::Nil),
+            Some(v) => Ok(Entry::Array(
+                v.iter().map(|k| Entry::Text(k.clone())).collect(),
+            )),
+        }
+    }
+}
+
+/// How the value at a key is kept, nil for missing keys.
+pub struct ObjectEncodingCommand {
+    key: String,
+}
+
+#[async_trait]
+impl Command for ObjectEncodingCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        match storage.get(&self.key).await {
+            Some(value) => Ok(Entry::Text(value.value.encoding().to_string())),
+            None => Ok(Entry::Nil),
+        }
+    }
+}
+
+/// The access frequency LFU eviction goes by of a key, nil for missing keys.
+pub struct ObjectFreqCommand {
+    key: String,
+}
+
+#[async_trait]
+impl Command for ObjectFreqCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        if !storage.maxmemory().policy.is_lfu() {
+            return Ok(Entry::error(
+                "ERR",
+                "An LFU maxmemory policy is not selected, access frequency not tracked",
+            ));
+        }
+        match storage.frequency(&self.key).await {
+            Some(frequency) => Ok(Entry::Int(frequency.into())),
+            None => Ok(Entry::Nil),
         }
     }
 }
 
 pub struct InfoCommand {
-    // key: String,
+    section: Option<String>,
 }
 
 #[async_trait]
 impl Command for InfoCommand {
-    async fn execute(&self, _storage: &dyn Storage) -> Result<String, CommandError> {
-        Ok(Entry::Text("role:master".to_string()).to_string())
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let replication = REPLICATION.info();
+        let maxmemory = storage.maxmemory();
+        let memory = format!(
+            "# Memory\r\nused_memory:{}\r\nmaxmemory:{}\r\nmaxmemory_policy:{}\r\n",
+            storage.used_memory(),
+            maxmemory.limit,
+            maxmemory.policy,
+        );
+        let saves = storage.persistence();
+        let persistence = format!(
+            "# Persistence\r\n\
+             rdb_changes_since_last_save:{}\r\n\
+             rdb_bgsave_in_progress:{}\r\n\
+             rdb_last_save_time:{}\r\n\
+             rdb_last_bgsave_status:{}\r\n\
+             {}",
+            saves.changes_since_save,
+            saves.in_progress as u8,
+            saves.last_save,
+            if saves.last_save_ok { "ok" } else { "err" },
+            AOF_STATUS.info(),
+        );
+        let info = match self.section.as_deref() {
+            None | Some("all" | "default" | "everything") => format!(
+                "{}\r\n{}\r\n{}\r\n{}\r\n{}",
+                replication,
+                memory,
+                persistence,
+                STATS.info(),
+                CLUSTER.info_section(),
+            ),
+            Some("replication") => replication,
+            Some("cluster") => CLUSTER.info_section(),
+            Some("memory") => memory,
+            Some("persistence") => persistence,
+            Some("stats") => STATS.info(),
+            Some(_) => String::new(),
+        };
+        Ok(Entry::Text(info))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{InMemoryStorage, Maxmemory, MaxmemoryPolicy, RdbStorage};
+
+    #[test]
+    fn should_register_each_command_once() {
+        assert_eq!(command_table().len(), COMMANDS.len());
+    }
+
+    #[test]
+    fn should_dispatch_regardless_of_case() {
+        let parse = |args: &[&str]| {
+            let args: Vec<Entry> = args
+                .iter()
+                .map(|arg| Entry::Text(arg.to_string()))
+                .collect();
+            CommandParser::new(&args).is_ok()
+        };
+        assert!(parse(&["ping"]));
+        assert!(parse(&["Set", "key", "value", "px", "100"]));
+        assert!(parse(&["set", "key", "value", "Ex", "1"]));
+        assert!(parse(&["config", "get", "DIR"]));
+        assert!(parse(&[
+            "xgroup", "create", "key", "group", "$", "mkstream"
+        ]));
+
+        assert!(!parse(&["set", "key", "value", "ex", "0"]));
+        assert!(!parse(&["set", "key", "value", "px", "1", "ex", "1"]));
+        assert!(!parse(&["config", "set", "dir"]));
+    }
+
+    #[test]
+    fn should_find_the_keys_requests_name() {
+        let keys = |args: &[&str]| {
+            let args: Vec<Entry> = args
+                .iter()
+                .map(|arg| Entry::Text(arg.to_string()))
+                .collect();
+            keys(&args)
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys(&["get", "a"]), ["a"]);
+        assert_eq!(keys(&["SMOVE", "a", "b", "member"]), ["a", "b"]);
+        assert_eq!(keys(&["BZPOPMIN", "a", "b", "0"]), ["a", "b"]);
+        assert_eq!(
+            keys(&["BITOP", "AND", "dest", "a", "b"]),
+            ["dest", "a", "b"]
+        );
+        assert_eq!(keys(&["ZMPOP", "2", "a", "b", "MIN"]), ["a", "b"]);
+        assert_eq!(keys(&["BZMPOP", "0", "1", "a", "MAX"]), ["a"]);
+        assert_eq!(
+            keys(&[
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "c",
+                "STREAMS",
+                "a",
+                "b",
+                ">",
+                ">"
+            ]),
+            ["a", "b"]
+        );
+        assert_eq!(keys(&["XGROUP", "CREATE", "a", "g", "$"]), ["a"]);
+        assert_eq!(keys(&["MIGRATE", "host", "6379", "a", "0", "0"]), ["a"]);
+        assert_eq!(
+            keys(&["MIGRATE", "host", "6379", "", "0", "0", "COPY", "KEYS", "a", "b"]),
+            ["a", "b"]
+        );
+        assert!(keys(&["PING"]).is_empty());
+        // Counts larger than what follows take only what is there.
+        assert_eq!(
+            keys(&["EVAL", "return 1", "9223372036854775807", "a"]),
+            ["a"]
+        );
+        assert_eq!(
+            keys(&["EVALSHA", "sha", "18446744073709551615", "a", "b"]),
+            ["a", "b"]
+        );
+        assert_eq!(keys(&["FCALL", "f", "9223372036854775807", "a"]), ["a"]);
+        assert_eq!(keys(&["FCALL_RO", "f", "9223372036854775807"]), [""; 0]);
+
+        let binary = [Entry::Text("GET".into()), Entry::Bulk(b"\xffa"[..].into())];
+        assert_eq!(super::keys(&binary), ["\u{fffd}a"]);
+    }
+
+    #[test]
+    fn should_explain_rejected_requests() {
+        let reply = |args: &[&str]| {
+            let args: Vec<Entry> = args
+                .iter()
+                .map(|arg| Entry::Text(arg.to_string()))
+                .collect();
+            match CommandParser::new(&args) {
+                Ok(_) => panic!("{:?} should be rejected", args),
+                Err(err) => err.reply(&args).to_string(),
+            }
+        };
+        assert_eq!(
+            reply(&["Get"]),
+            "-ERR wrong number of arguments for 'get' command\r\n"
+        );
+        assert_eq!(
+            reply(&["SCARD", "a", "b"]),
+            "-ERR wrong number of arguments for 'scard' command\r\n"
+        );
+        assert_eq!(
+            reply(&["FOO", "a", "b"]),
+            "-ERR unknown command 'FOO', with args beginning with: 'a' 'b' \r\n"
+        );
+        assert_eq!(reply(&["SET", "k", "v", "ZZ"]), "-ERR syntax error\r\n");
+        assert_eq!(
+            reply(&["SET", "k", "v", "EX", "1", "PXAT", "1"]),
+            "-ERR syntax error\r\n"
+        );
+        // Values that would overflow instead of being served.
+        reply(&["SET", "k", "v", "EX", "9223372036854775807"]);
+        reply(&["SET", "k", "v", "EXAT", "9223372036854775807"]);
+        reply(&["SRANDMEMBER", "k", "-9223372036854775808"]);
+    }
+
+    async fn run(client: &mut ClientState, args: &[&str]) -> Entry {
+        let args: Vec<Entry> = args
+            .iter()
+            .map(|arg| Entry::Text(arg.to_string()))
+            .collect();
+        let command = CommandParser::new(&args).unwrap();
+        command
+            .execute(&InMemoryStorage::new(), client)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_describe_commands_from_their_metadata() {
+        let request = |args: &[&str]| -> Vec<Entry> {
+            args.iter()
+                .map(|arg| Entry::Text(arg.to_string()))
+                .collect()
+        };
+        assert!(is_write(&request(&["set", "k", "v"])));
+        assert!(!is_write(&request(&["GET", "k"])));
+        assert!(is_write(&request(&["FUNCTION", "delete", "lib"])));
+        assert!(!is_write(&request(&["FUNCTION", "LIST"])));
+        assert!(is_denyoom(&request(&["SADD", "k", "m"])));
+        assert!(!is_denyoom(&request(&["SREM", "k", "m"])));
+        assert!(!is_scriptable(&request(&["EVAL", "return 1", "0"])));
+        assert!(is_scriptable(&request(&["GET", "k"])));
+
+        let mut client = ClientState::new(1);
+        let text = |text: &str| Entry::Text(text.to_string());
+        let reply = run(&mut client, &["COMMAND", "INFO", "get", "nope"]).await;
+        let Entry::Array(described) = reply else {
+            panic!("COMMAND INFO should reply with an array");
+        };
+        assert_eq!(
+            described[0],
+            Entry::Array(vec![
+                text("get"),
+                Entry::Int(2),
+                Entry::Set(vec![text("readonly"), text("fast")]),
+                Entry::Int(1),
+                Entry::Int(1),
+                Entry::Int(1),
+                Entry::Set(vec![text("@read"), text("@string"), text("@fast")]),
+                Entry::Array(vec![]),
+                Entry::Array(vec![]),
+                Entry::Array(vec![]),
+            ])
+        );
+        assert_eq!(described[1], Entry::Nil);
+        assert_eq!(
+            run(&mut client, &["COMMAND", "COUNT"]).await,
+            Entry::Int(COMMANDS.len() as i64)
+        );
+        assert_eq!(
+            run(
+                &mut client,
+                &["COMMAND", "LIST", "FILTERBY", "PATTERN", "zpop*"]
+            )
+            .await,
+            Entry::Array(vec![text("zpopmin"), text("zpopmax")])
+        );
+        let Entry::Array(blocking) = run(
+            &mut client,
+            &["COMMAND", "LIST", "FILTERBY", "ACLCAT", "blocking"],
+        )
+        .await
+        else {
+            panic!("COMMAND LIST should reply with an array");
+        };
+        assert!(blocking.contains(&text("bzpopmin")));
+        assert!(!blocking.contains(&text("zpopmin")));
+
+        assert_eq!(
+            run(
+                &mut client,
+                &["COMMAND", "GETKEYS", "BITOP", "AND", "d", "a", "b"]
+            )
+            .await,
+            Entry::Array(vec![text("d"), text("a"), text("b")])
+        );
+        assert_eq!(
+            run(
+                &mut client,
+                &["COMMAND", "GETKEYS", "EVAL", "return 1", "1", "k"]
+            )
+            .await,
+            Entry::Array(vec![text("k")])
+        );
+        assert_eq!(
+            run(&mut client, &["COMMAND", "GETKEYS", "PING"]).await,
+            Entry::error("ERR", "The command has no key arguments")
+        );
+        assert_eq!(
+            run(&mut client, &["COMMAND", "GETKEYS", "GET"]).await,
+            Entry::error("ERR", "Invalid number of arguments specified for command")
+        );
+    }
+
+    #[tokio::test]
+    async fn should_negotiate_protocol_with_hello() {
+        let mut client = ClientState::new(7);
+
+        let reply = run(&mut client, &["HELLO", "3", "SETNAME", "worker"]).await;
+        let Entry::Map(fields) = reply else {
+            panic!("HELLO should reply with a map");
+        };
+        assert!(fields.contains(&(Entry::Text("proto".to_string()), Entry::Int(3))));
+        assert!(fields.contains(&(Entry::Text("id".to_string()), Entry::Int(7))));
+        assert_eq!(client.name.as_deref(), Some("worker"));
+
+        run(&mut client, &["HELLO"]).await;
+        assert_eq!(client.protocol, Protocol::Resp3);
+        let reply = run(&mut client, &["HELLO", "4"]).await;
+        assert!(matches!(reply, Entry::Error(code, _) if code == "NOPROTO"));
+        let reply = run(&mut client, &["HELLO", "2", "AUTH", "default"]).await;
+        assert!(matches!(reply, Entry::Error(code, _) if code == "ERR"));
+        assert_eq!(client.protocol, Protocol::Resp3);
+    }
+
+    #[tokio::test]
+    async fn should_reset_client_state() {
+        let mut client = ClientState::new(7);
+        run(&mut client, &["HELLO", "3", "SETNAME", "worker"]).await;
+        client.db = 3;
+        let reply = run(&mut client, &["RESET"]).await;
+        assert_eq!(reply, Entry::SimpleText("RESET".to_string()));
+        assert_eq!(client.protocol, Protocol::Resp2);
+        assert_eq!(client.db, 0);
+        assert_eq!(client.name.as_deref(), Some("worker"));
+        assert_eq!(client.id(), 7);
+        assert!(client.authenticated);
+
+        client.password_required = true;
+        run(&mut client, &["RESET"]).await;
+        assert!(!client.authenticated);
+    }
+
+    #[tokio::test]
+    async fn should_check_tracking_options() {
+        let mut client = ClientState::new(9);
+        let error = |message: &str| Entry::error("ERR", message);
+        let reply = run(&mut client, &["CLIENT", "TRACKING", "ON", "PREFIX", "a"]).await;
+        assert_eq!(
+            reply,
+            error("PREFIX option requires BCAST mode to be enabled")
+        );
+        let reply = run(
+            &mut client,
+            &["CLIENT", "TRACKING", "ON", "REDIRECT", "404"],
+        )
+        .await;
+        assert_eq!(
+            reply,
+            error("The client ID you want redirect to does not exist")
+        );
+        assert_eq!(
+            run(&mut client, &["CLIENT", "GETREDIR"]).await,
+            Entry::Int(-1)
+        );
+
+        let reply = run(&mut client, &["CLIENT", "TRACKING", "ON", "OPTIN"]).await;
+        assert_eq!(reply, Entry::ok());
+        assert_eq!(
+            run(&mut client, &["CLIENT", "GETREDIR"]).await,
+            Entry::Int(0)
+        );
+        let reply = run(&mut client, &["CLIENT", "CACHING", "NO"]).await;
+        assert_eq!(
+            reply,
+            error("CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.")
+        );
+        let reply = run(&mut client, &["CLIENT", "CACHING", "YES"]).await;
+        assert_eq!(reply, Entry::ok());
+        assert_eq!(client.caching, Some(true));
+        let reply = run(&mut client, &["CLIENT", "TRACKING", "ON", "BCAST"]).await;
+        assert!(matches!(reply, Entry::Error(..)), "{:?}", reply);
+
+        let reply = run(&mut client, &["CLIENT", "TRACKING", "OFF"]).await;
+        assert_eq!(reply, Entry::ok());
+        assert_eq!(client.tracking, None);
+    }
+
+    #[tokio::test]
+    async fn should_confirm_each_subscription() {
+        let mut client = ClientState::new(8);
+        let push = |kind: &str, channel: &str, count: i64| {
+            Entry::Push(vec![
+                Entry::Text(kind.to_string()),
+                Entry::Text(channel.to_string()),
+                Entry::Int(count),
+            ])
+        };
+        let reply = run(&mut client, &["SUBSCRIBE", "sub:a", "sub:b", "sub:a"]).await;
+        assert_eq!(
+            client.replies,
+            [push("subscribe", "sub:a", 1), push("subscribe", "sub:b", 2)]
+        );
+        assert_eq!(reply, push("subscribe", "sub:a", 2));
+        client.replies.clear();
+
+        let reply = run(&mut client, &["PUBLISH", "sub:b", "hello"]).await;
+        assert_eq!(reply, Entry::Int(1));
+        let message = client.messages.try_recv().unwrap();
+        assert_eq!(
+            (message.channel.as_str(), message.message.as_str()),
+            ("sub:b", "hello")
+        );
+        let reply = run(&mut client, &["PING"]).await;
+        assert_eq!(
+            reply,
+            Entry::Array(vec![
+                Entry::Text("pong".to_string()),
+                Entry::Text(String::new())
+            ])
+        );
+
+        let reply = run(&mut client, &["UNSUBSCRIBE"]).await;
+        assert_eq!(client.replies, [push("unsubscribe", "sub:a", 1)]);
+        client.replies.clear();
+        assert_eq!(reply, push("unsubscribe", "sub:b", 0));
+        let reply = run(&mut client, &["UNSUBSCRIBE"]).await;
+        assert_eq!(
+            reply,
+            Entry::Push(vec![
+                Entry::Text("unsubscribe".to_string()),
+                Entry::Nil,
+                Entry::Int(0)
+            ])
+        );
+        let reply = run(&mut client, &["PUBLISH", "sub:b", "hello"]).await;
+        assert_eq!(reply, Entry::Int(0));
+        assert_eq!(
+            run(&mut client, &["PING", "hi"]).await,
+            Entry::Text("hi".to_string())
+        );
+
+        // Patterns count along with channels.
+        run(&mut client, &["SUBSCRIBE", "sub:a"]).await;
+        let reply = run(&mut client, &["PSUBSCRIBE", "sub:*"]).await;
+        assert_eq!(reply, push("psubscribe", "sub:*", 2));
+        let reply = run(&mut client, &["PUBLISH", "sub:a", "hello"]).await;
+        assert_eq!(reply, Entry::Int(2));
+        let reply = run(&mut client, &["PUNSUBSCRIBE", "sub:*", "sub:b*"]).await;
+        assert_eq!(client.replies, [push("punsubscribe", "sub:*", 1)]);
+        assert_eq!(reply, push("punsubscribe", "sub:b*", 1));
+
+        // Shard channels are counted apart, and only they are published to.
+        client.replies.clear();
+        let reply = run(&mut client, &["SSUBSCRIBE", "sub:a"]).await;
+        assert_eq!(reply, push("ssubscribe", "sub:a", 1));
+        let reply = run(&mut client, &["SPUBLISH", "sub:a", "hello"]).await;
+        assert_eq!(reply, Entry::Int(1));
+        let reply = run(&mut client, &["SUNSUBSCRIBE"]).await;
+        assert_eq!(reply, push("sunsubscribe", "sub:a", 0));
+        assert!(client.subscribed());
+    }
+
+    #[tokio::test]
+    async fn should_reload_the_dataset() {
+        let dir = std::env::temp_dir().join(format!("reload-{}", std::process::id()));
+        let storage = RdbStorage::new(dir.to_str().unwrap(), "dump.rdb");
+        let mut client = ClientState::new(1);
+        let mut replies = vec![];
+        for args in [
+            &["SADD", "set", "a", "b"][..],
+            &["DEBUG", "RELOAD"],
+            &["SCARD", "set"],
+        ] {
+            let args: Vec<Entry> = args
+                .iter()
+                .map(|arg| Entry::Text(arg.to_string()))
+                .collect();
+            let command = CommandParser::new(&args).unwrap();
+            replies.push(command.execute(&storage, &mut client).await.unwrap());
+        }
+        let saved = dir.join("dump.rdb").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(replies[1], Entry::ok());
+        assert_eq!(replies[2], Entry::Int(2));
+        assert!(saved);
+    }
+
+    #[tokio::test]
+    async fn should_toggle_quickack_for_the_client_only() {
+        let mut client = ClientState::new(1);
+        assert_eq!(
+            run(&mut client, &["DEBUG", "QUICKACK", "1"]).await,
+            Entry::ok()
+        );
+        assert!(client.quickack);
+        assert!(!ClientState::new(2).quickack);
+        assert_eq!(
+            run(&mut client, &["debug", "quickack", "0"]).await,
+            Entry::ok()
+        );
+        assert!(!client.quickack);
+        let args = [Entry::Text("DEBUG".into()), Entry::Text("QUICKACK".into())];
+        assert!(CommandParser::new(&args).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_append_to_strings() {
+        let storage = InMemoryStorage::new();
+        let mut client = ClientState::new(1);
+        let mut replies = vec![];
+        for args in [
+            &["APPEND", "string", "ab"][..],
+            &["APPEND", "string", "cd"],
+            &["SADD", "set", "a"],
+            &["APPEND", "set", "b"],
+            &["GET", "string"],
+        ] {
+            let args: Vec<Entry> = args
+                .iter()
+                .map(|arg| Entry::Text(arg.to_string()))
+                .collect();
+            let command = CommandParser::new(&args).unwrap();
+            replies.push(command.execute(&storage, &mut client).await.unwrap());
+        }
+        assert_eq!(replies[..2], [Entry::Int(2), Entry::Int(4)]);
+        assert_eq!(replies[3], wrong_type());
+        assert_eq!(replies[4], Entry::Bulk("abcd".into()));
+    }
+
+    #[tokio::test]
+    async fn should_delete_keys_of_any_type() {
+        let storage = InMemoryStorage::new();
+        let mut client = ClientState::new(1);
+        let mut replies = vec![];
+        for args in [
+            &["SET", "string", "v"][..],
+            &["SADD", "set", "a"],
+            &["DEL", "string", "set", "missing"],
+            &["DEL", "string"],
+        ] {
+            let args: Vec<Entry> = args
+                .iter()
+                .map(|arg| Entry::Text(arg.to_string()))
+                .collect();
+            let command = CommandParser::new(&args).unwrap();
+            replies.push(command.execute(&storage, &mut client).await.unwrap());
+        }
+        assert_eq!(replies[2], Entry::Int(2));
+        assert_eq!(replies[3], Entry::Int(0));
+        assert!(storage.get("set").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn should_report_access_frequency_under_lfu() {
+        let lfu = InMemoryStorage::new().with_maxmemory(Maxmemory {
+            limit: 0,
+            policy: MaxmemoryPolicy::AllKeysLfu,
+        });
+        let mut client = ClientState::new(1);
+        let mut replies = vec![];
+        for args in [
+            &["SET", "key", "v"][..],
+            &["OBJECT", "FREQ", "key"],
+            &["GET", "key"],
+            &["OBJECT", "FREQ", "key"],
+            &["OBJECT", "FREQ", "missing"],
+        ] {
+            let args: Vec<Entry> = args
+                .iter()
+                .map(|arg| Entry::Text(arg.to_string()))
+                .collect();
+            let command = CommandParser::new(&args).unwrap();
+            replies.push(command.execute(&lfu, &mut client).await.unwrap());
+        }
+        // New keys start at 5, the first access always counting.
+        assert_eq!(replies[1], Entry::Int(5));
+        assert_eq!(replies[3], Entry::Int(6));
+        assert_eq!(replies[4], Entry::Nil);
+
+        let reply = run(&mut client, &["OBJECT", "FREQ", "key"]).await;
+        assert!(matches!(reply, Entry::Error(code, _) if code == "ERR"));
+    }
+
+    #[tokio::test]
+    async fn should_notify_keyspace_events() {
+        KEYSPACE_EVENTS.configure(NotifyFlags::parse("KEA").unwrap());
+        let mut events = KEYSPACE_EVENTS.subscribe();
+        let storage = InMemoryStorage::new();
+        let mut client = ClientState::new(1);
+        for args in [
+            &["SET", "notified", "v", "PX", "100"][..],
+            &["SADD", "notified:set", "a"],
+            &["SREM", "notified:set", "a"],
+        ] {
+            let args: Vec<Entry> = args
+                .iter()
+                .map(|arg| Entry::Text(arg.to_string()))
+                .collect();
+            let command = CommandParser::new(&args).unwrap();
+            command.execute(&storage, &mut client).await.unwrap();
+        }
+
+        let mut received = vec![];
+        while let Ok(event) = events.try_recv() {
+            // Other tests may be writing at the same time.
+            if event.channel.contains("notified") || event.message.starts_with("notified") {
+                received.push(format!("{} {}", event.channel, event.message));
+            }
+        }
+        assert_eq!(
+            received,
+            [
+                "__keyspace@0__:notified set",
+                "__keyevent@0__:set notified",
+                "__keyspace@0__:notified expire",
+                "__keyevent@0__:expire notified",
+                "__keyspace@0__:notified:set sadd",
+                "__keyevent@0__:sadd notified:set",
+                "__keyspace@0__:notified:set srem",
+                "__keyevent@0__:srem notified:set",
+                "__keyspace@0__:notified:set del",
+                "__keyevent@0__:del notified:set",
+            ]
+        );
     }
 }
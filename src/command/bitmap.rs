@@ -0,0 +1,466 @@
+use async_trait::async_trait;
+
+use crate::{
+    client::ClientState,
+    notify::{NotifyFlags, KEYSPACE_EVENTS},
+    resp::Entry,
+    storage::{
+        bitmap::{self, BitOp, BitRange, BitUnit, FieldType, Overflow},
+        Data, Expiry, Storage, Value,
+    },
+};
+
+use super::{parse_arg, parse_int_arg, parse_rest, wrong_type, Command, CommandError};
+
+/// Highest bit offset SETBIT accepts, keeping values under 512MB.
+const MAX_BIT_OFFSET: u64 = (1 << 32) - 1;
+
+pub fn parse(cmd: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    if cmd == "BITOP" {
+        return Ok(Box::new(parse_bitop(args)?));
+    }
+    let key = parse_arg(args, 1)?;
+
+    let cmd_kind: Box<dyn Command> = match cmd {
+        "SETBIT" if args.len() == 4 => Box::new(SetBitCommand {
+            key,
+            offset: parse_offset(args, 2)?,
+            value: parse_bit(args, 3)?,
+        }),
+
+        "GETBIT" if args.len() == 3 => Box::new(GetBitCommand {
+            key,
+            offset: parse_offset(args, 2)?,
+        }),
+
+        "BITCOUNT" => {
+            let range = match args.len() {
+                2 => None,
+                4 | 5 => Some(parse_range(args, 2)?),
+                _ => return Err(CommandError),
+            };
+            Box::new(BitCountCommand { key, range })
+        }
+
+        "BITPOS" => {
+            let bit = parse_bit(args, 2)?;
+            let (range, has_end) = match args.len() {
+                // Without an end the range runs to the end of the value.
+                3 | 4 => {
+                    let start = match args.len() {
+                        4 => parse_int_arg(args, 3)?,
+                        _ => 0,
+                    };
+                    let range = BitRange {
+                        start,
+                        end: -1,
+                        unit: BitUnit::Byte,
+                    };
+                    (range, false)
+                }
+                5 | 6 => (parse_range(args, 3)?, true),
+                _ => return Err(CommandError),
+            };
+            Box::new(BitPosCommand {
+                key,
+                bit,
+                range,
+                has_end,
+            })
+        }
+
+        "BITFIELD" | "BITFIELD_RO" => Box::new(parse_bitfield(key, args, cmd == "BITFIELD_RO")?),
+
+        _ => return Err(CommandError),
+    };
+
+    Ok(cmd_kind)
+}
+
+/// Parses the `GET`, `SET`, `INCRBY` and `OVERFLOW` subcommands of BITFIELD,
+/// `read_only` allowing `GET` alone.
+fn parse_bitfield(
+    key: String,
+    args: &[Entry],
+    read_only: bool,
+) -> Result<BitFieldCommand, CommandError> {
+    let mut ops = Vec::new();
+    let mut overflow = Overflow::Wrap;
+
+    let mut at = 2;
+    while at < args.len() {
+        let subcommand = parse_arg(args, at)?.to_uppercase();
+        if subcommand == "OVERFLOW" && !read_only {
+            overflow = match parse_arg(args, at + 1)?.to_uppercase().as_str() {
+                "WRAP" => Overflow::Wrap,
+                "SAT" => Overflow::Sat,
+                "FAIL" => Overflow::Fail,
+                _ => return Err(CommandError),
+            };
+            at += 2;
+            continue;
+        }
+
+        let ty = FieldType::parse(&parse_arg(args, at + 1)?).ok_or(CommandError)?;
+        let offset = parse_field_offset(&parse_arg(args, at + 2)?, ty)?;
+        let op = match subcommand.as_str() {
+            "GET" => {
+                at += 3;
+                FieldOp::Get
+            }
+            "SET" if !read_only => {
+                at += 4;
+                FieldOp::Set(parse_int_arg(args, at - 1)?)
+            }
+            "INCRBY" if !read_only => {
+                at += 4;
+                FieldOp::IncrBy(parse_int_arg(args, at - 1)?)
+            }
+            _ => return Err(CommandError),
+        };
+        ops.push(FieldAccess {
+            op,
+            ty,
+            offset,
+            overflow,
+        });
+    }
+
+    Ok(BitFieldCommand { key, ops })
+}
+
+/// Parses a bit offset, or `#n` for the `n`-th field of type `ty`.
+fn parse_field_offset(arg: &str, ty: FieldType) -> Result<usize, CommandError> {
+    let offset: u64 = match arg.strip_prefix('#') {
+        Some(index) => index
+            .parse::<u64>()
+            .ok()
+            .and_then(|index| index.checked_mul(ty.bits as u64)),
+        None => arg.parse().ok(),
+    }
+    .ok_or(CommandError)?;
+
+    if offset + ty.bits as u64 - 1 > MAX_BIT_OFFSET {
+        return Err(CommandError);
+    }
+    Ok(offset as usize)
+}
+
+fn parse_bitop(args: &[Entry]) -> Result<BitOpCommand, CommandError> {
+    let op = match parse_arg(args, 1)?.to_uppercase().as_str() {
+        "AND" => BitOp::And,
+        "OR" => BitOp::Or,
+        "XOR" => BitOp::Xor,
+        "NOT" => BitOp::Not,
+        _ => return Err(CommandError),
+    };
+    let dest = parse_arg(args, 2)?;
+    let keys = parse_rest(args, 3);
+    if keys.is_empty() || (op == BitOp::Not && keys.len() != 1) {
+        return Err(CommandError);
+    }
+
+    Ok(BitOpCommand { op, dest, keys })
+}
+
+fn parse_offset(args: &[Entry], at: usize) -> Result<usize, CommandError> {
+    match u64::try_from(parse_int_arg(args, at)?) {
+        Ok(offset) if offset <= MAX_BIT_OFFSET => Ok(offset as usize),
+        _ => Err(CommandError),
+    }
+}
+
+fn parse_bit(args: &[Entry], at: usize) -> Result<bool, CommandError> {
+    match parse_arg(args, at)?.as_str() {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(CommandError),
+    }
+}
+
+/// Parses `start end [BYTE|BIT]` starting at `at`.
+fn parse_range(args: &[Entry], at: usize) -> Result<BitRange, CommandError> {
+    let unit = match args.get(at + 2) {
+        None => BitUnit::Byte,
+        Some(_) => match parse_arg(args, at + 2)?.to_uppercase().as_str() {
+            "BYTE" => BitUnit::Byte,
+            "BIT" => BitUnit::Bit,
+            _ => return Err(CommandError),
+        },
+    };
+    Ok(BitRange {
+        start: parse_int_arg(args, at)?,
+        end: parse_int_arg(args, at + 1)?,
+        unit,
+    })
+}
+
+/// Loads the string stored at `key` together with its expiry. Missing keys
+/// yield `Ok(None)` and keys of another type yield the wrong_type reply as `Err`.
+async fn load_string(
+    storage: &dyn Storage,
+    key: &str,
+) -> Result<Option<(Vec<u8>, Option<Expiry>)>, Entry> {
+    match storage.get(key).await {
+        Some(Value {
+            value: Data::String(value),
+            expiry,
+        }) => Ok(Some((value, expiry))),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+/// Changes the string stored at `key` in place with `f`, in one step no other
+/// client can come between, handing it an empty string if the key is missing.
+/// A missing key `f` leaves empty is not created. Keys of another type are
+/// left alone and yield the wrong_type reply as `Err`.
+async fn update_string<T: Send>(
+    storage: &dyn Storage,
+    key: &str,
+    f: impl FnOnce(&mut Vec<u8>) -> T + Send,
+) -> Result<T, Entry> {
+    storage
+        .update_with(key, |value| {
+            let existed = value.is_some();
+            let stored = value.get_or_insert_with(|| Value {
+                value: Data::String(Vec::new()),
+                expiry: None,
+            });
+            let Data::String(string) = &mut stored.value else {
+                return Err(wrong_type());
+            };
+            let result = f(string);
+            if !existed && string.is_empty() {
+                *value = None;
+            }
+            Ok(result)
+        })
+        .await
+}
+
+pub struct SetBitCommand {
+    key: String,
+    offset: usize,
+    value: bool,
+}
+
+#[async_trait]
+impl Command for SetBitCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let previous = match update_string(storage, &self.key, |value| {
+            bitmap::set_bit(value, self.offset, self.value)
+        })
+        .await
+        {
+            Ok(previous) => previous,
+            Err(reply) => return Ok(reply),
+        };
+        KEYSPACE_EVENTS.notify(NotifyFlags::STRING, "setbit", &self.key);
+        Ok(Entry::Int(previous as i64))
+    }
+}
+
+pub struct GetBitCommand {
+    key: String,
+    offset: usize,
+}
+
+#[async_trait]
+impl Command for GetBitCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        match load_string(storage, &self.key).await {
+            Ok(found) => {
+                let (value, _) = found.unwrap_or_default();
+                Ok(Entry::Int(bitmap::get_bit(&value, self.offset) as i64))
+            }
+            Err(reply) => Ok(reply),
+        }
+    }
+}
+
+pub struct BitCountCommand {
+    key: String,
+    range: Option<BitRange>,
+}
+
+#[async_trait]
+impl Command for BitCountCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        match load_string(storage, &self.key).await {
+            Ok(found) => {
+                let (value, _) = found.unwrap_or_default();
+                let count = bitmap::count(&value, self.range);
+                Ok(Entry::Int(count as i64))
+            }
+            Err(reply) => Ok(reply),
+        }
+    }
+}
+
+pub struct BitPosCommand {
+    key: String,
+    bit: bool,
+    range: BitRange,
+    has_end: bool,
+}
+
+#[async_trait]
+impl Command for BitPosCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let position = match load_string(storage, &self.key).await {
+            // A missing key reads as an endless run of zeros.
+            Ok(None) if self.bit => -1,
+            Ok(None) => 0,
+            Ok(Some((value, _))) => bitmap::position(&value, self.bit, self.range, self.has_end),
+            Err(reply) => return Ok(reply),
+        };
+        Ok(Entry::Int(position as i64))
+    }
+}
+
+pub struct BitOpCommand {
+    op: BitOp,
+    dest: String,
+    keys: Vec<String>,
+}
+
+#[async_trait]
+impl Command for BitOpCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        // The sources are read and the destination written in one step, so
+        // no client changes a source in between.
+        let mut keys = vec![self.dest.as_str()];
+        keys.extend(self.keys.iter().map(String::as_str));
+        let stored = storage
+            .update_many_with(&keys, |view| {
+                let mut sources = Vec::with_capacity(self.keys.len());
+                for key in &self.keys {
+                    match view.get(key).map(|value| &value.value) {
+                        Some(Data::String(value)) => sources.push(value.as_slice()),
+                        Some(_) => return Err(wrong_type()),
+                        None => sources.push(&[]),
+                    }
+                }
+
+                let result = bitmap::bit_op(self.op, &sources);
+                let len = result.len();
+                let dest = view.get_mut(&self.dest);
+                let existed = dest.is_some();
+                *dest = (!result.is_empty()).then(|| Value {
+                    value: Data::String(result),
+                    expiry: None,
+                });
+                Ok((len, existed))
+            })
+            .await;
+        let (len, existed) = match stored {
+            Ok(stored) => stored,
+            Err(reply) => return Ok(reply),
+        };
+        if len > 0 {
+            KEYSPACE_EVENTS.notify(NotifyFlags::STRING, "set", &self.dest);
+        } else if existed {
+            KEYSPACE_EVENTS.notify(NotifyFlags::GENERIC, "del", &self.dest);
+        }
+        Ok(Entry::Int(len as i64))
+    }
+}
+
+enum FieldOp {
+    Get,
+    Set(i64),
+    IncrBy(i64),
+}
+
+struct FieldAccess {
+    op: FieldOp,
+    ty: FieldType,
+    offset: usize,
+    /// Policy in effect when the access was parsed.
+    overflow: Overflow,
+}
+
+pub struct BitFieldCommand {
+    key: String,
+    ops: Vec<FieldAccess>,
+}
+
+#[async_trait]
+impl Command for BitFieldCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let updated = update_string(storage, &self.key, |value| {
+            let mut changed = false;
+            let mut replies = Vec::with_capacity(self.ops.len());
+            for access in &self.ops {
+                let current = bitmap::get_field(value, access.offset, access.ty);
+                let written = match access.op {
+                    FieldOp::Get => {
+                        replies.push(Entry::Int(current));
+                        continue;
+                    }
+                    FieldOp::Set(new) => {
+                        // Unsigned fields take the two's complement bits of negative values.
+                        let new = if access.ty.signed {
+                            new as i128
+                        } else {
+                            new as u64 as i128
+                        };
+                        access
+                            .ty
+                            .add(new, 0, access.overflow)
+                            .map(|written| (written, current))
+                    }
+                    FieldOp::IncrBy(delta) => access
+                        .ty
+                        .add(current as i128, delta as i128, access.overflow)
+                        .map(|written| (written, written)),
+                };
+
+                match written {
+                    Some((written, reply)) => {
+                        bitmap::set_field(value, access.offset, access.ty, written);
+                        changed = true;
+                        replies.push(Entry::Int(reply));
+                    }
+                    None => replies.push(Entry::Nil),
+                }
+            }
+            (replies, changed)
+        })
+        .await;
+        let (replies, changed) = match updated {
+            Ok(updated) => updated,
+            Err(reply) => return Ok(reply),
+        };
+
+        if changed {
+            KEYSPACE_EVENTS.notify(NotifyFlags::STRING, "setbit", &self.key);
+        }
+        Ok(Entry::Array(replies))
+    }
+}
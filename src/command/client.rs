@@ -0,0 +1,442 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    client::{ClientInfo, ClientState, PauseMode, CLIENTS, PAUSE},
+    resp::Entry,
+    storage::Storage,
+    tracking::{TrackingOptions, TRACKING},
+};
+
+use super::{parse_arg, parse_int_arg, Command, CommandError};
+
+pub fn parse(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let subcommand = parse_arg(args, 1)?.to_uppercase();
+    let cmd_kind: Box<dyn Command> = match (subcommand.as_str(), args.len()) {
+        ("ID", 2) => Box::new(ClientIdCommand),
+        ("GETNAME", 2) => Box::new(ClientGetNameCommand),
+        ("SETNAME", 3) => Box::new(ClientSetNameCommand {
+            name: parse_arg(args, 2)?,
+        }),
+        ("INFO", 2) => Box::new(ClientInfoCommand),
+        ("LIST", _) => Box::new(parse_list(args)?),
+        ("KILL", 3) => Box::new(ClientKillCommand {
+            filters: vec![Filter::Addr(parse_addr(args, 2)?)],
+            skip_me: false,
+            legacy: true,
+        }),
+        ("KILL", _) => Box::new(parse_kill(args)?),
+        ("PAUSE", 3 | 4) => Box::new(parse_pause(args)?),
+        ("UNPAUSE", 2) => Box::new(ClientUnpauseCommand),
+        ("TRACKING", 3..) => Box::new(parse_tracking(args)?),
+        ("CACHING", 3) => Box::new(ClientCachingCommand {
+            caching: match parse_arg(args, 2)?.to_uppercase().as_str() {
+                "YES" => true,
+                "NO" => false,
+                _ => return Err(CommandError),
+            },
+        }),
+        ("GETREDIR", 2) => Box::new(ClientGetredirCommand),
+        _ => return Err(CommandError),
+    };
+    Ok(cmd_kind)
+}
+
+fn parse_addr(args: &[Entry], at: usize) -> Result<SocketAddr, CommandError> {
+    parse_arg(args, at)?.parse().map_err(|_| CommandError)
+}
+
+fn parse_list(args: &[Entry]) -> Result<ClientListCommand, CommandError> {
+    let mut ids = None;
+    let mut at = 2;
+    while at < args.len() {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            // Every client served here is a normal one.
+            "TYPE" => {
+                let kind = parse_arg(args, at + 1)?.to_lowercase();
+                match kind.as_str() {
+                    "normal" => {}
+                    "master" | "replica" | "pubsub" => ids = Some(vec![]),
+                    _ => return Err(CommandError),
+                }
+                at += 2;
+            }
+            "ID" if at + 1 < args.len() => {
+                let parsed = (at + 1..args.len())
+                    .map(|at| u64::try_from(parse_int_arg(args, at)?).map_err(|_| CommandError))
+                    .collect::<Result<_, _>>()?;
+                ids = Some(parsed);
+                at = args.len();
+            }
+            _ => return Err(CommandError),
+        }
+    }
+    Ok(ClientListCommand { ids })
+}
+
+fn parse_kill(args: &[Entry]) -> Result<ClientKillCommand, CommandError> {
+    let mut command = ClientKillCommand {
+        filters: vec![],
+        skip_me: true,
+        legacy: false,
+    };
+    let mut at = 2;
+    while at < args.len() {
+        let filter = parse_arg(args, at)?.to_uppercase();
+        match filter.as_str() {
+            "ID" => {
+                let id = u64::try_from(parse_int_arg(args, at + 1)?).map_err(|_| CommandError)?;
+                command.filters.push(Filter::Id(id));
+            }
+            "ADDR" => command
+                .filters
+                .push(Filter::Addr(parse_addr(args, at + 1)?)),
+            "LADDR" => command
+                .filters
+                .push(Filter::LocalAddr(parse_addr(args, at + 1)?)),
+            "SKIPME" => {
+                command.skip_me = match parse_arg(args, at + 1)?.to_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(CommandError),
+                }
+            }
+            _ => return Err(CommandError),
+        }
+        at += 2;
+    }
+    Ok(command)
+}
+
+fn parse_pause(args: &[Entry]) -> Result<ClientPauseCommand, CommandError> {
+    let timeout = u64::try_from(parse_int_arg(args, 2)?).map_err(|_| CommandError)?;
+    let mode = match args.len() {
+        3 => PauseMode::All,
+        _ => match parse_arg(args, 3)?.to_uppercase().as_str() {
+            "WRITE" => PauseMode::Write,
+            "ALL" => PauseMode::All,
+            _ => return Err(CommandError),
+        },
+    };
+    let until = Instant::now()
+        .checked_add(Duration::from_millis(timeout))
+        .ok_or(CommandError)?;
+    Ok(ClientPauseCommand { until, mode })
+}
+
+fn parse_tracking(args: &[Entry]) -> Result<ClientTrackingCommand, CommandError> {
+    let mut options = TrackingOptions::default();
+    let on = match parse_arg(args, 2)?.to_uppercase().as_str() {
+        "ON" => true,
+        "OFF" => false,
+        _ => return Err(CommandError),
+    };
+    let mut at = 3;
+    while at < args.len() {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            "REDIRECT" => {
+                let id = u64::try_from(parse_int_arg(args, at + 1)?).map_err(|_| CommandError)?;
+                options.redirect = Some(id);
+                at += 1;
+            }
+            "PREFIX" => {
+                options.prefixes.push(parse_arg(args, at + 1)?);
+                at += 1;
+            }
+            "BCAST" => options.bcast = true,
+            "OPTIN" => options.optin = true,
+            "OPTOUT" => options.optout = true,
+            "NOLOOP" => options.noloop = true,
+            _ => return Err(CommandError),
+        }
+        at += 1;
+    }
+    Ok(ClientTrackingCommand {
+        options: on.then_some(options),
+    })
+}
+
+/// Client names are shown space separated by CLIENT LIST, so like Redis
+/// only printable characters other than space are allowed.
+pub(super) fn check_name(name: &str) -> Result<(), Entry> {
+    if name.bytes().any(|byte| !(b'!'..=b'~').contains(&byte)) {
+        return Err(Entry::error(
+            "ERR",
+            "Client names cannot contain spaces, newlines or special characters.",
+        ));
+    }
+    Ok(())
+}
+
+pub struct ClientIdCommand;
+
+#[async_trait]
+impl Command for ClientIdCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        Ok(Entry::Int(client.id() as i64))
+    }
+}
+
+pub struct ClientGetNameCommand;
+
+#[async_trait]
+impl Command for ClientGetNameCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        match &client.name {
+            Some(name) => Ok(Entry::Text(name.clone())),
+            None => Ok(Entry::Nil),
+        }
+    }
+}
+
+pub struct ClientSetNameCommand {
+    name: String,
+}
+
+#[async_trait]
+impl Command for ClientSetNameCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        if let Err(reply) = check_name(&self.name) {
+            return Ok(reply);
+        }
+        // An empty name removes the current one.
+        client.name = Some(self.name.clone()).filter(|name| !name.is_empty());
+        Ok(Entry::ok())
+    }
+}
+
+pub struct ClientInfoCommand;
+
+#[async_trait]
+impl Command for ClientInfoCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        match CLIENTS.get(client.id()) {
+            Some(info) => Ok(Entry::Text(info.describe(Instant::now()))),
+            None => Ok(Entry::Text(String::new())),
+        }
+    }
+}
+
+pub struct ClientListCommand {
+    /// Only these clients when given.
+    ids: Option<Vec<u64>>,
+}
+
+#[async_trait]
+impl Command for ClientListCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        let now = Instant::now();
+        let list: String = CLIENTS
+            .list()
+            .iter()
+            .filter(|info| match &self.ids {
+                Some(ids) => ids.contains(&info.id),
+                None => true,
+            })
+            .map(|info| info.describe(now))
+            .collect();
+        Ok(Entry::Text(list))
+    }
+}
+
+enum Filter {
+    Id(u64),
+    Addr(SocketAddr),
+    LocalAddr(SocketAddr),
+}
+
+impl Filter {
+    fn matches(&self, info: &ClientInfo) -> bool {
+        match self {
+            Filter::Id(id) => info.id == *id,
+            Filter::Addr(addr) => info.addr == *addr,
+            Filter::LocalAddr(addr) => info.laddr == *addr,
+        }
+    }
+}
+
+pub struct ClientKillCommand {
+    /// Clients must match all of them to be killed.
+    filters: Vec<Filter>,
+    skip_me: bool,
+    /// `CLIENT KILL addr`, which replies OK instead of a count.
+    legacy: bool,
+}
+
+#[async_trait]
+impl Command for ClientKillCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let me = client.id();
+        let killed = CLIENTS.kill(|info| {
+            !(self.skip_me && info.id == me) && self.filters.iter().all(|f| f.matches(info))
+        });
+        match (self.legacy, killed) {
+            (true, 0) => Ok(Entry::error("ERR", "No such client")),
+            (true, _) => Ok(Entry::ok()),
+            (false, killed) => Ok(Entry::Int(killed as i64)),
+        }
+    }
+}
+
+pub struct ClientPauseCommand {
+    until: Instant,
+    mode: PauseMode,
+}
+
+#[async_trait]
+impl Command for ClientPauseCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        PAUSE.pause(self.until, self.mode);
+        Ok(Entry::ok())
+    }
+}
+
+pub struct ClientUnpauseCommand;
+
+#[async_trait]
+impl Command for ClientUnpauseCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        PAUSE.unpause();
+        Ok(Entry::ok())
+    }
+}
+
+/// Turns tracking the keys the client reads on with `options`, or off.
+pub struct ClientTrackingCommand {
+    options: Option<TrackingOptions>,
+}
+
+impl ClientTrackingCommand {
+    /// Why `options` can't be used by `client`, if they can't.
+    fn refusal(options: &TrackingOptions, client: &ClientState) -> Option<&'static str> {
+        if !options.prefixes.is_empty() && !options.bcast {
+            return Some("PREFIX option requires BCAST mode to be enabled");
+        }
+        if options.optin && options.optout {
+            return Some("You can't use both OPTIN and OPTOUT.");
+        }
+        if options.bcast && (options.optin || options.optout) {
+            return Some("OPTIN and OPTOUT are not compatible with BCAST");
+        }
+        match &client.tracking {
+            Some(tracking) if tracking.bcast != options.bcast => Some(
+                "You can't switch BCAST mode on/off before disabling tracking for this client, \
+                 and then re-enabling it with a different mode.",
+            ),
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl Command for ClientTrackingCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let Some(options) = &self.options else {
+            if client.tracking.take().is_some() {
+                TRACKING.disable(client.id());
+            }
+            return Ok(Entry::ok());
+        };
+        if let Some(refusal) = Self::refusal(options, client) {
+            return Ok(Entry::error("ERR", refusal));
+        }
+        let to = match options.redirect {
+            Some(id) => match CLIENTS.invalidator(id) {
+                Some(to) => to,
+                None => {
+                    return Ok(Entry::error(
+                        "ERR",
+                        "The client ID you want redirect to does not exist",
+                    ))
+                }
+            },
+            None => client.invalidator().clone(),
+        };
+        TRACKING.enable(client.id(), options.clone(), to);
+        client.tracking = Some(options.clone());
+        Ok(Entry::ok())
+    }
+}
+
+/// Has the keys the next command reads remembered, or not, despite OPTIN or
+/// OPTOUT.
+pub struct ClientCachingCommand {
+    caching: bool,
+}
+
+#[async_trait]
+impl Command for ClientCachingCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let refusal = match &client.tracking {
+            Some(tracking) if tracking.optin || tracking.optout => {
+                match (self.caching, tracking.optin) {
+                    (true, false) => {
+                        "CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode."
+                    }
+                    (false, true) => {
+                        "CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode."
+                    }
+                    _ => {
+                        client.caching = Some(self.caching);
+                        return Ok(Entry::ok());
+                    }
+                }
+            }
+            _ => {
+                "CLIENT CACHING can be called only when the client is in tracking mode \
+                 with OPTIN or OPTOUT mode enabled"
+            }
+        };
+        Ok(Entry::error("ERR", refusal))
+    }
+}
+
+/// The client invalidations are redirected to: 0 if none, -1 if the client
+/// is not tracking keys.
+pub struct ClientGetredirCommand;
+
+#[async_trait]
+impl Command for ClientGetredirCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        Ok(Entry::Int(match &client.tracking {
+            Some(tracking) => tracking.redirect.map_or(0, |id| id as i64),
+            None => -1,
+        }))
+    }
+}
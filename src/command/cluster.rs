@@ -0,0 +1,294 @@
+use async_trait::async_trait;
+
+use crate::{
+    client::ClientState,
+    cluster::{self, SlotState, BUS_PORT_OFFSET, CLUSTER},
+    resp::Entry,
+    storage::Storage,
+};
+
+use super::{parse_arg, parse_int_arg, Command, CommandError};
+
+pub fn parse(name: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    if name == "ASKING" {
+        return Ok(Box::new(AskingCommand));
+    }
+    let subcommand = parse_arg(args, 1)?.to_uppercase();
+    let cmd_kind: Box<dyn Command> = match (subcommand.as_str(), args.len()) {
+        ("INFO", 2) => Box::new(ClusterInfoCommand),
+        ("MYID", 2) => Box::new(ClusterMyidCommand),
+        ("SLOTS", 2) => Box::new(ClusterSlotsCommand),
+        ("SHARDS", 2) => Box::new(ClusterShardsCommand),
+        ("NODES", 2) => Box::new(ClusterNodesCommand),
+        ("MEET", 4 | 5) => {
+            let port = parse_port(args, 3)?;
+            Box::new(ClusterMeetCommand {
+                ip: parse_arg(args, 2)?,
+                port,
+                bus_port: match args.len() {
+                    5 => parse_port(args, 4)?,
+                    _ => port.checked_add(BUS_PORT_OFFSET).ok_or(CommandError)?,
+                },
+            })
+        }
+        ("KEYSLOT", 3) => Box::new(ClusterKeyslotCommand {
+            key: parse_arg(args, 2)?,
+        }),
+        ("SETSLOT", 4 | 5) => Box::new(ClusterSetslotCommand {
+            slot: parse_slot(args, 2)?,
+            state: match (parse_arg(args, 3)?.to_uppercase().as_str(), args.len()) {
+                ("MIGRATING", 5) => SlotState::Migrating(parse_arg(args, 4)?),
+                ("IMPORTING", 5) => SlotState::Importing(parse_arg(args, 4)?),
+                ("NODE", 5) => SlotState::Node(parse_arg(args, 4)?),
+                ("STABLE", 4) => SlotState::Stable,
+                _ => return Err(CommandError),
+            },
+        }),
+        ("COUNTKEYSINSLOT", 3) => Box::new(ClusterKeysInSlotCommand {
+            slot: parse_slot(args, 2)?,
+            count: None,
+        }),
+        ("GETKEYSINSLOT", 4) => Box::new(ClusterKeysInSlotCommand {
+            slot: parse_slot(args, 2)?,
+            count: Some(usize::try_from(parse_int_arg(args, 3)?).map_err(|_| CommandError)?),
+        }),
+        ("ADDSLOTS" | "DELSLOTS", 3..) => Box::new(ClusterSlotsChangeCommand {
+            add: subcommand == "ADDSLOTS",
+            ranges: parse_slots(args, 1)?,
+        }),
+        ("ADDSLOTSRANGE" | "DELSLOTSRANGE", 4..) if args.len().is_multiple_of(2) => {
+            Box::new(ClusterSlotsChangeCommand {
+                add: subcommand == "ADDSLOTSRANGE",
+                ranges: parse_slots(args, 2)?,
+            })
+        }
+        _ => return Err(CommandError),
+    };
+    Ok(cmd_kind)
+}
+
+fn parse_port(args: &[Entry], at: usize) -> Result<u16, CommandError> {
+    parse_arg(args, at)?.parse().map_err(|_| CommandError)
+}
+
+fn parse_slot(args: &[Entry], at: usize) -> Result<u16, CommandError> {
+    cluster::parse_slot(&parse_arg(args, at)?).ok_or(CommandError)
+}
+
+/// The slots from the third argument on, `per_range` at a time: single
+/// slots or bounds of ranges of them.
+fn parse_slots(args: &[Entry], per_range: usize) -> Result<Vec<(u16, u16)>, CommandError> {
+    let slots = (2..args.len())
+        .map(|at| parse_slot(args, at))
+        .collect::<Result<Vec<_>, _>>()?;
+    slots
+        .chunks(per_range)
+        .map(|range| match *range {
+            [slot] => Ok((slot, slot)),
+            [start, end] if start <= end => Ok((start, end)),
+            _ => Err(CommandError),
+        })
+        .collect()
+}
+
+/// `reply` if cluster mode is enabled, which every CLUSTER subcommand needs.
+fn when_enabled(reply: impl FnOnce() -> Entry) -> Result<Entry, CommandError> {
+    match CLUSTER.enabled() {
+        true => Ok(reply()),
+        false => Ok(disabled()),
+    }
+}
+
+fn disabled() -> Entry {
+    Entry::error("ERR", "This instance has cluster support disabled")
+}
+
+pub struct ClusterInfoCommand;
+
+#[async_trait]
+impl Command for ClusterInfoCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        when_enabled(|| Entry::Text(CLUSTER.info()))
+    }
+}
+
+pub struct ClusterMyidCommand;
+
+#[async_trait]
+impl Command for ClusterMyidCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        when_enabled(|| Entry::Text(CLUSTER.myid()))
+    }
+}
+
+/// Which node serves each range of slots.
+pub struct ClusterSlotsCommand;
+
+#[async_trait]
+impl Command for ClusterSlotsCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        when_enabled(|| CLUSTER.slots())
+    }
+}
+
+/// The nodes serving each set of slots, the successor of CLUSTER SLOTS.
+pub struct ClusterShardsCommand;
+
+#[async_trait]
+impl Command for ClusterShardsCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        when_enabled(|| CLUSTER.shards())
+    }
+}
+
+/// Every node known, with its state and the slots it serves.
+pub struct ClusterNodesCommand;
+
+#[async_trait]
+impl Command for ClusterNodesCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        when_enabled(|| Entry::Text(CLUSTER.nodes()))
+    }
+}
+
+/// Has this node meet another, for both to join the same cluster.
+pub struct ClusterMeetCommand {
+    ip: String,
+    port: u16,
+    bus_port: u16,
+}
+
+#[async_trait]
+impl Command for ClusterMeetCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        when_enabled(|| match CLUSTER.meet(&self.ip, self.port, self.bus_port) {
+            Ok(()) => Entry::ok(),
+            Err(err) => Entry::error("ERR", err),
+        })
+    }
+}
+
+/// The slot a key hashes to, which needs no cluster mode.
+pub struct ClusterKeyslotCommand {
+    key: String,
+}
+
+#[async_trait]
+impl Command for ClusterKeyslotCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        Ok(Entry::Int(cluster::key_slot(&self.key).into()))
+    }
+}
+
+/// Makes this node serve slots, or stop serving them.
+pub struct ClusterSlotsChangeCommand {
+    add: bool,
+    ranges: Vec<(u16, u16)>,
+}
+
+#[async_trait]
+impl Command for ClusterSlotsChangeCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        when_enabled(|| {
+            let changed = match self.add {
+                true => CLUSTER.add_slots(&self.ranges),
+                false => CLUSTER.del_slots(&self.ranges),
+            };
+            match changed {
+                Ok(()) => Entry::ok(),
+                Err(err) => Entry::error("ERR", err),
+            }
+        })
+    }
+}
+
+/// Keys of `slot` this node holds: how many, or up to `count` of them.
+pub struct ClusterKeysInSlotCommand {
+    slot: u16,
+    count: Option<usize>,
+}
+
+#[async_trait]
+impl Command for ClusterKeysInSlotCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        if !CLUSTER.enabled() {
+            return Ok(disabled());
+        }
+        let keys = keys_in_slot(storage, self.slot).await;
+        Ok(match self.count {
+            Some(count) => Entry::Array(keys.into_iter().take(count).map(Entry::Text).collect()),
+            None => Entry::Int(keys.len() as i64),
+        })
+    }
+}
+
+async fn keys_in_slot(storage: &dyn Storage, slot: u16) -> Vec<String> {
+    let mut keys: Vec<String> = storage
+        .keys("*")
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|key| cluster::key_slot(key) == slot)
+        .collect();
+    keys.sort_unstable();
+    keys
+}
+
+/// Moves a slot between nodes: marks it migrating or importing while its
+/// keys are moved with MIGRATE, then hands it to its new node.
+pub struct ClusterSetslotCommand {
+    slot: u16,
+    state: SlotState,
+}
+
+#[async_trait]
+impl Command for ClusterSetslotCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        if !CLUSTER.enabled() {
+            return Ok(disabled());
+        }
+        // Giving the slot away before its keys left would strand them.
+        if let SlotState::Node(id) = &self.state {
+            if CLUSTER.serves(self.slot)
+                && *id != CLUSTER.myid()
+                && !keys_in_slot(storage, self.slot).await.is_empty()
+            {
+                return Ok(Entry::error(
+                    "ERR",
+                    "I still hold keys in this hash slot but the slot is being given to a different node",
+                ));
+            }
+        }
+        Ok(match CLUSTER.set_slot(self.slot, &self.state) {
+            Ok(()) => Entry::ok(),
+            Err(err) => Entry::error("ERR", err),
+        })
+    }
+}
+
+/// Lets the next command use a slot being imported, as told by an ASK
+/// redirection.
+pub struct AskingCommand;
+
+#[async_trait]
+impl Command for AskingCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        if !CLUSTER.enabled() {
+            return Ok(disabled());
+        }
+        client.asking = true;
+        Ok(Entry::ok())
+    }
+}
@@ -0,0 +1,205 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use crate::{
+    aof::AOF_STATUS,
+    client::ClientState,
+    rdb,
+    replication::REPLICATION,
+    resp::Entry,
+    storage::{Storage, ACTIVE_EXPIRE},
+};
+
+use super::{parse_arg, parse_int_arg, Command, CommandError};
+
+pub fn parse(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let subcommand = parse_arg(args, 1)?.to_uppercase();
+    let cmd_kind: Box<dyn Command> = match (subcommand.as_str(), args.len()) {
+        ("OBJECT", 3) => Box::new(DebugObjectCommand {
+            key: parse_arg(args, 2)?,
+        }),
+        ("SLEEP", 3) => {
+            let duration = parse_arg(args, 2)?
+                .parse::<f64>()
+                .ok()
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                .ok_or(CommandError)?;
+            Box::new(DebugSleepCommand { duration })
+        }
+        ("RELOAD", 2) => Box::new(DebugReloadCommand),
+        ("LOADRDB", 3 | 4) => {
+            let merge = match args.get(3) {
+                None => false,
+                Some(_) => match parse_arg(args, 3)?.to_uppercase().as_str() {
+                    "REPLACE" => false,
+                    "MERGE" => true,
+                    _ => return Err(CommandError),
+                },
+            };
+            Box::new(DebugLoadRdbCommand {
+                path: parse_arg(args, 2)?,
+                merge,
+            })
+        }
+        ("SET-ACTIVE-EXPIRE", 3) => Box::new(DebugSetActiveExpireCommand {
+            enabled: parse_int_arg(args, 2)? != 0,
+        }),
+        ("QUICKACK", 3) => Box::new(DebugQuickackCommand {
+            enabled: parse_int_arg(args, 2)? != 0,
+        }),
+        _ => return Err(CommandError),
+    };
+    Ok(cmd_kind)
+}
+
+/// How the value at a key is kept, in the format Redis test suites parse.
+/// There is no address nor reference count to speak of, so those are
+/// always the same.
+pub struct DebugObjectCommand {
+    key: String,
+}
+
+#[async_trait]
+impl Command for DebugObjectCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let Some(value) = storage.get(&self.key).await else {
+            return Ok(Entry::error("ERR", "no such key"));
+        };
+        Ok(Entry::SimpleText(format!(
+            "Value at:0x0 refcount:1 encoding:{} serializedlength:{}",
+            value.value.encoding(),
+            value.serialized_len()
+        )))
+    }
+}
+
+/// Holds the connection for a while. Like any DEBUG it holds every key
+/// shared meanwhile, so writes, and reads queued behind them, wait for it to end
+/// much as the whole server would on Redis.
+pub struct DebugSleepCommand {
+    duration: Duration,
+}
+
+#[async_trait]
+impl Command for DebugSleepCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        tokio::time::sleep(self.duration).await;
+        Ok(Entry::ok())
+    }
+}
+
+/// Saves the dataset and loads it straight back, so what doesn't survive
+/// a restart goes now. Storage without a file to save to keeps its data.
+pub struct DebugReloadCommand;
+
+#[async_trait]
+impl Command for DebugReloadCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        if let Err(err) = storage.save().await {
+            eprintln!("DEBUG RELOAD failed saving: {}", err);
+            return Ok(Entry::error(
+                "ERR",
+                "Error trying to save the DB, inspect the server logs for more information",
+            ));
+        }
+        if let Err(err) = storage.load().await {
+            eprintln!("DEBUG RELOAD failed loading: {}", err);
+            return Ok(Entry::error(
+                "ERR",
+                "Error trying to load the RDB dump, check server logs",
+            ));
+        }
+        Ok(Entry::ok())
+    }
+}
+
+/// Loads the RDB file at `path` into the running server, replying with how
+/// many keys it held. They replace the whole dataset, or only the keys of
+/// the same name when merging. It is a write, but of a file only this
+/// server has, so rather than logged and propagated the append only file
+/// is rewritten and replicas resynchronized from the dataset it leaves.
+/// Replicas take their dataset from their master alone.
+pub struct DebugLoadRdbCommand {
+    path: String,
+    merge: bool,
+}
+
+#[async_trait]
+impl Command for DebugLoadRdbCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        if REPLICATION.master().is_some() {
+            return Ok(Entry::error("ERR", "LOADRDB is not allowed on replicas"));
+        }
+        // It would be rewritten from the dataset before the load.
+        if AOF_STATUS.rewrite_in_progress.load(Ordering::Relaxed) {
+            return Ok(Entry::error(
+                "ERR",
+                "Background append only file rewriting already in progress",
+            ));
+        }
+        let bytes = match tokio::fs::read(&self.path).await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                let message = format!("Error reading {}: {}", self.path, err);
+                return Ok(Entry::error("ERR", message));
+            }
+        };
+        let keys = match rdb::parse_rdb(&mut Bytes::from(bytes)) {
+            Ok(keys) => keys,
+            Err(err) => {
+                let message = format!("Error parsing {}: {}", self.path, err);
+                return Ok(Entry::error("ERR", message));
+            }
+        };
+        let loaded = keys.len();
+        storage.load_keys(keys, self.merge).await;
+        client.reloaded = true;
+        Ok(Entry::Int(loaded as i64))
+    }
+}
+
+/// Turns deleting keys that expired without being accessed on or off, so
+/// tests can watch them expire lazily.
+pub struct DebugSetActiveExpireCommand {
+    enabled: bool,
+}
+
+#[async_trait]
+impl Command for DebugSetActiveExpireCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        ACTIVE_EXPIRE.store(self.enabled, Ordering::Relaxed);
+        Ok(Entry::ok())
+    }
+}
+
+/// Turns acknowledging the client's requests right away, TCP_QUICKACK, on
+/// or off for its connection.
+pub struct DebugQuickackCommand {
+    enabled: bool,
+}
+
+#[async_trait]
+impl Command for DebugQuickackCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        client.quickack = self.enabled;
+        Ok(Entry::ok())
+    }
+}
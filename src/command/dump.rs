@@ -0,0 +1,297 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{
+    client::ClientState,
+    cluster::CLUSTER,
+    notify::{NotifyFlags, KEYSPACE_EVENTS},
+    rdb,
+    resp::Entry,
+    storage::{unix_time_ms, Expiry, Storage, Value},
+};
+
+use super::{parse_arg, parse_bytes_arg, parse_int_arg, parse_rest, Command, CommandError};
+
+/// How long MIGRATE waits on the target when told 0.
+const DEFAULT_MIGRATE_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub fn parse(name: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let cmd_kind: Box<dyn Command> = match name {
+        "DUMP" => Box::new(DumpCommand {
+            key: parse_arg(args, 1)?,
+        }),
+        "RESTORE" | "RESTORE-ASKING" => Box::new(parse_restore(args)?),
+        "MIGRATE" => Box::new(parse_migrate(args)?),
+        _ => return Err(CommandError),
+    };
+    Ok(cmd_kind)
+}
+
+fn parse_restore(args: &[Entry]) -> Result<RestoreCommand, CommandError> {
+    let mut restore = RestoreCommand {
+        key: parse_arg(args, 1)?,
+        ttl: parse_int_arg(args, 2)?,
+        payload: parse_bytes_arg(args, 3)?,
+        replace: false,
+        absolute: false,
+    };
+    let mut at = 4;
+    while at < args.len() {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            "REPLACE" => restore.replace = true,
+            "ABSTTL" => restore.absolute = true,
+            // How long ago and how often the key was accessed, which is
+            // started over here.
+            "IDLETIME" | "FREQ" if parse_int_arg(args, at + 1)? >= 0 => at += 1,
+            _ => return Err(CommandError),
+        }
+        at += 1;
+    }
+    Ok(restore)
+}
+
+fn parse_migrate(args: &[Entry]) -> Result<MigrateCommand, CommandError> {
+    let key = parse_arg(args, 3)?;
+    let timeout = match parse_int_arg(args, 5)? {
+        timeout if timeout > 0 => Duration::from_millis(timeout as u64),
+        _ => DEFAULT_MIGRATE_TIMEOUT,
+    };
+    let mut migrate = MigrateCommand {
+        host: parse_arg(args, 1)?,
+        port: parse_arg(args, 2)?.parse().map_err(|_| CommandError)?,
+        keys: vec![key.clone()],
+        db: parse_int_arg(args, 4)?,
+        timeout,
+        copy: false,
+        replace: false,
+        auth: Vec::new(),
+    };
+    let mut at = 6;
+    while at < args.len() {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            "COPY" => migrate.copy = true,
+            "REPLACE" => migrate.replace = true,
+            "AUTH" => {
+                migrate.auth = vec![parse_arg(args, at + 1)?];
+                at += 1;
+            }
+            "AUTH2" => {
+                migrate.auth = vec![parse_arg(args, at + 1)?, parse_arg(args, at + 2)?];
+                at += 2;
+            }
+            // Several keys are named after KEYS in place of the one key.
+            "KEYS" if key.is_empty() => {
+                migrate.keys = parse_rest(args, at + 1);
+                break;
+            }
+            _ => return Err(CommandError),
+        }
+        at += 1;
+    }
+    Ok(migrate)
+}
+
+/// Serializes the value under a key the way RESTORE takes it back.
+pub struct DumpCommand {
+    key: String,
+}
+
+#[async_trait]
+impl Command for DumpCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        Ok(match storage.get(&self.key).await {
+            Some(value) => Entry::Bulk(rdb::dump_value(&value.value).into()),
+            None => Entry::Nil,
+        })
+    }
+}
+
+/// Creates a key from what DUMP serialized, living for `ttl` milliseconds,
+/// until that Unix time in milliseconds if `absolute`, or forever with 0.
+pub struct RestoreCommand {
+    key: String,
+    ttl: i64,
+    payload: Vec<u8>,
+    /// Whether a key already there is replaced rather than refused.
+    replace: bool,
+    absolute: bool,
+}
+
+#[async_trait]
+impl Command for RestoreCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let expiry = match u64::try_from(self.ttl) {
+            Ok(0) => None,
+            Ok(unix_ms) if self.absolute => Some(Expiry::from_unix_ms(unix_ms)),
+            Ok(ttl) => match Expiry::after(Duration::from_millis(ttl)) {
+                Some(expiry) => Some(expiry),
+                None => return Ok(Entry::error("ERR", "Invalid TTL value, must be >= 0")),
+            },
+            Err(_) => return Ok(Entry::error("ERR", "Invalid TTL value, must be >= 0")),
+        };
+        let value = match rdb::restore_value(&self.payload) {
+            Ok(value) => value,
+            Err(err) => return Ok(Entry::error("ERR", err)),
+        };
+        // Like on Redis, a key restored already expired is only deleted.
+        let expired = expiry.is_some_and(|expiry| expiry.has_passed(unix_time_ms()));
+        let restored = storage
+            .update_with(&self.key, |stored| {
+                if stored.is_some() && !self.replace {
+                    return false;
+                }
+                *stored = (!expired).then_some(Value { value, expiry });
+                true
+            })
+            .await;
+        if !restored {
+            return Ok(Entry::error("BUSYKEY", "Target key name already exists."));
+        }
+        if !expired {
+            KEYSPACE_EVENTS.notify(NotifyFlags::GENERIC, "restore", &self.key);
+        }
+        client.expiry = expiry;
+        Ok(Entry::ok())
+    }
+}
+
+/// Moves keys to the server at `host` and `port` by restoring what DUMP
+/// would serialize there, then deleting them here unless told to `copy`.
+/// Keys stay put unless the target restored every one of them.
+pub struct MigrateCommand {
+    host: String,
+    port: u16,
+    keys: Vec<String>,
+    db: i64,
+    timeout: Duration,
+    copy: bool,
+    replace: bool,
+    /// What the target is sent after AUTH, if anything.
+    auth: Vec<String>,
+}
+
+impl MigrateCommand {
+    /// The requests restoring `keys` on the target, preceded by those
+    /// authenticating and selecting the database.
+    fn requests(&self, keys: &[(&String, Value)]) -> Vec<Entry> {
+        let text = |text: &str| Entry::Text(text.to_string());
+        let mut requests = Vec::new();
+        if !self.auth.is_empty() {
+            let auth = std::iter::once(text("AUTH")).chain(self.auth.iter().map(|arg| text(arg)));
+            requests.push(Entry::Array(auth.collect()));
+        }
+        if self.db != 0 {
+            requests.push(Entry::Array(vec![
+                text("SELECT"),
+                text(&self.db.to_string()),
+            ]));
+        }
+        // Cluster nodes only take keys of slots they are importing from
+        // clients that asked.
+        let restore = match CLUSTER.enabled() {
+            true => "RESTORE-ASKING",
+            false => "RESTORE",
+        };
+        for (key, value) in keys {
+            let ttl = value.expiry.map_or(0, |expiry| {
+                expiry.unix_ms().saturating_sub(unix_time_ms()).max(1)
+            });
+            let mut request = vec![
+                text(restore),
+                text(key),
+                text(&ttl.to_string()),
+                Entry::Bulk(rdb::dump_value(&value.value).into()),
+            ];
+            if self.replace {
+                request.push(text("REPLACE"));
+            }
+            requests.push(Entry::Array(request));
+        }
+        requests
+    }
+
+    /// Sends `requests` to the target, returning the first error replied to
+    /// any, or why the target could not be talked to.
+    async fn send(&self, requests: &[Entry]) -> Result<Option<String>, Entry> {
+        let io_error = |doing: &str| Entry::error("IOERR", format!("error or timeout {}", doing));
+        let stream = timeout(self.timeout, TcpStream::connect((&*self.host, self.port)))
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .ok_or_else(|| io_error("connecting to the client"))?;
+        let (reader, mut writer) = stream.into_split();
+        let mut buf = BytesMut::new();
+        for request in requests {
+            request.encode(&mut buf);
+        }
+        timeout(self.timeout, writer.write_all(&buf))
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .ok_or_else(|| io_error("writing to target instance"))?;
+
+        let mut reader = BufReader::new(reader);
+        let mut error = None;
+        for _ in requests {
+            let mut reply = String::new();
+            match timeout(self.timeout, reader.read_line(&mut reply)).await {
+                Ok(Ok(read)) if read > 0 => {}
+                _ => return Err(io_error("reading to target instance")),
+            }
+            if let Some(message) = reply.trim_end().strip_prefix('-') {
+                error.get_or_insert_with(|| message.to_string());
+            }
+        }
+        Ok(error)
+    }
+}
+
+#[async_trait]
+impl Command for MigrateCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let mut keys = Vec::new();
+        for key in &self.keys {
+            if let Some(value) = storage.get(key).await {
+                keys.push((key, value));
+            }
+        }
+        if keys.is_empty() {
+            return Ok(Entry::SimpleText("NOKEY".to_string()));
+        }
+        match self.send(&self.requests(&keys)).await {
+            Ok(None) => {}
+            Ok(Some(error)) => {
+                let message = format!("Target instance replied with error: {}", error);
+                return Ok(Entry::error("ERR", message));
+            }
+            Err(failed) => return Ok(failed),
+        }
+        if !self.copy {
+            for (key, _) in keys {
+                if storage.del(key).await {
+                    KEYSPACE_EVENTS.notify(NotifyFlags::GENERIC, "del", key);
+                }
+            }
+        }
+        Ok(Entry::ok())
+    }
+}
@@ -0,0 +1,267 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::task;
+
+use crate::{
+    client::ClientState,
+    functions::{self, Library, RestorePolicy, FUNCTIONS},
+    glob::glob_match,
+    rdb,
+    resp::Entry,
+    storage::Storage,
+};
+
+use super::{
+    parse_arg, parse_bytes_arg, parse_int_arg,
+    scripting::{serve_script, split_keys},
+    Command, CommandError,
+};
+
+pub fn parse(name: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let cmd_kind: Box<dyn Command> = match name {
+        "FCALL" | "FCALL_RO" => Box::new(FcallCommand {
+            function: parse_arg(args, 1)?,
+            numkeys: parse_int_arg(args, 2)?,
+            args: (3..args.len())
+                .map(|at| parse_bytes_arg(args, at).map(Bytes::from))
+                .collect::<Result<_, _>>()?,
+            read_only: name == "FCALL_RO",
+        }),
+        "FUNCTION" => parse_function(args)?,
+        _ => return Err(CommandError),
+    };
+    Ok(cmd_kind)
+}
+
+fn parse_function(args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let subcommand = parse_arg(args, 1)?.to_uppercase();
+    let cmd_kind: Box<dyn Command> = match (subcommand.as_str(), args.len()) {
+        ("LOAD", 3) => Box::new(FunctionLoadCommand {
+            code: parse_arg(args, 2)?,
+            replace: false,
+        }),
+        ("LOAD", 4) if parse_arg(args, 2)?.eq_ignore_ascii_case("REPLACE") => {
+            Box::new(FunctionLoadCommand {
+                code: parse_arg(args, 3)?,
+                replace: true,
+            })
+        }
+        ("LIST", _) => Box::new(parse_function_list(args)?),
+        ("DELETE", 3) => Box::new(FunctionDeleteCommand {
+            library: parse_arg(args, 2)?,
+        }),
+        ("FLUSH", 2) => Box::new(FunctionFlushCommand {
+            asynchronous: false,
+        }),
+        ("FLUSH", 3) => Box::new(FunctionFlushCommand {
+            asynchronous: match parse_arg(args, 2)?.to_uppercase().as_str() {
+                "ASYNC" => true,
+                "SYNC" => false,
+                _ => return Err(CommandError),
+            },
+        }),
+        ("DUMP", 2) => Box::new(FunctionDumpCommand),
+        ("RESTORE", 3 | 4) => Box::new(FunctionRestoreCommand {
+            payload: parse_bytes_arg(args, 2)?,
+            policy: match parse_arg(args, 3).ok().map(|policy| policy.to_uppercase()) {
+                None => RestorePolicy::Append,
+                Some(policy) => match policy.as_str() {
+                    "APPEND" => RestorePolicy::Append,
+                    "REPLACE" => RestorePolicy::Replace,
+                    "FLUSH" => RestorePolicy::Flush,
+                    _ => return Err(CommandError),
+                },
+            },
+        }),
+        _ => return Err(CommandError),
+    };
+    Ok(cmd_kind)
+}
+
+fn parse_function_list(args: &[Entry]) -> Result<FunctionListCommand, CommandError> {
+    let mut list = FunctionListCommand {
+        pattern: None,
+        with_code: false,
+    };
+    let mut at = 2;
+    while at < args.len() {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            "LIBRARYNAME" if list.pattern.is_none() => {
+                list.pattern = Some(parse_arg(args, at + 1)?);
+                at += 1;
+            }
+            "WITHCODE" => list.with_code = true,
+            _ => return Err(CommandError),
+        }
+        at += 1;
+    }
+    Ok(list)
+}
+
+/// Calls a function of a library loaded, with the first `numkeys`
+/// arguments as its keys and the rest as its arguments. With `read_only`,
+/// only functions flagged `no-writes` may be called, and the server runs
+/// them as reads.
+pub struct FcallCommand {
+    function: String,
+    numkeys: i64,
+    args: Vec<Bytes>,
+    read_only: bool,
+}
+
+#[async_trait]
+impl Command for FcallCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let Some((code, function)) = FUNCTIONS.find(&self.function) else {
+            return Ok(Entry::error("ERR", "Function not found"));
+        };
+        if self.read_only && !function.no_writes() {
+            return Ok(Entry::error(
+                "ERR",
+                "Can not execute a script with write flag using *_ro command.",
+            ));
+        }
+        let (keys, args) = match split_keys(self.numkeys, &self.args) {
+            Ok(split) => split,
+            Err(refused) => return Ok(refused),
+        };
+        let name = self.function.clone();
+        serve_script(storage, client, function.no_writes(), move |calls| {
+            functions::call(&code, &name, keys, args, calls)
+        })
+        .await
+    }
+}
+
+/// Loads a library, replacing one of the same name with `replace`, and
+/// replies with its name.
+pub struct FunctionLoadCommand {
+    code: String,
+    replace: bool,
+}
+
+#[async_trait]
+impl Command for FunctionLoadCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        let loaded = FUNCTIONS.load(&self.code, self.replace);
+        Ok(match loaded {
+            Ok(name) => Entry::Text(name),
+            Err(err) => Entry::error("ERR", err),
+        })
+    }
+}
+
+/// Every library, or those whose name matches a glob pattern, with the
+/// functions each registers and its code with `with_code`.
+pub struct FunctionListCommand {
+    pattern: Option<String>,
+    with_code: bool,
+}
+
+#[async_trait]
+impl Command for FunctionListCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        let text = |text: &str| Entry::Text(text.to_string());
+        let listed = FUNCTIONS
+            .libraries()
+            .into_iter()
+            .filter(|library| {
+                let pattern = self.pattern.as_deref();
+                pattern.is_none_or(|pattern| glob_match(pattern, &library.name))
+            })
+            .map(|library| {
+                let functions = library.functions.iter().map(|(name, function)| {
+                    Entry::Map(vec![
+                        (text("name"), text(name)),
+                        (
+                            text("description"),
+                            function.description.as_deref().map_or(Entry::Nil, text),
+                        ),
+                        (
+                            text("flags"),
+                            Entry::Set(function.flags.iter().map(|flag| text(flag)).collect()),
+                        ),
+                    ])
+                });
+                let mut fields = vec![
+                    (text("library_name"), text(&library.name)),
+                    (text("engine"), text("LUA")),
+                    (text("functions"), Entry::Array(functions.collect())),
+                ];
+                if self.with_code {
+                    fields.push((text("library_code"), text(&library.code)));
+                }
+                Entry::Map(fields)
+            });
+        Ok(Entry::Array(listed.collect()))
+    }
+}
+
+pub struct FunctionDeleteCommand {
+    library: String,
+}
+
+#[async_trait]
+impl Command for FunctionDeleteCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        Ok(match FUNCTIONS.delete(&self.library) {
+            true => Entry::ok(),
+            false => Entry::error("ERR", "Library not found"),
+        })
+    }
+}
+
+/// Deletes every library, freeing them in the background if `asynchronous`.
+pub struct FunctionFlushCommand {
+    asynchronous: bool,
+}
+
+#[async_trait]
+impl Command for FunctionFlushCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        let flushed = FUNCTIONS.flush();
+        if self.asynchronous {
+            task::spawn_blocking(move || drop(flushed));
+        }
+        Ok(Entry::ok())
+    }
+}
+
+/// Every library serialized, for FUNCTION RESTORE to load them back.
+pub struct FunctionDumpCommand;
+
+#[async_trait]
+impl Command for FunctionDumpCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        let dumped = rdb::dump_functions(&FUNCTIONS.libraries());
+        Ok(Entry::Bulk(dumped.into()))
+    }
+}
+
+/// Loads the libraries FUNCTION DUMP serialized, as `policy` says to treat
+/// those loaded already.
+pub struct FunctionRestoreCommand {
+    payload: Vec<u8>,
+    policy: RestorePolicy,
+}
+
+#[async_trait]
+impl Command for FunctionRestoreCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        let restored = rdb::restore_functions(&self.payload).and_then(|codes| {
+            let libraries = codes
+                .iter()
+                .map(|code| Library::load(code))
+                .collect::<Result<_, _>>()?;
+            FUNCTIONS.restore(libraries, self.policy)
+        });
+        Ok(match restored {
+            Ok(()) => Entry::ok(),
+            Err(err) => Entry::error("ERR", err),
+        })
+    }
+}
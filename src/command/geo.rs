@@ -0,0 +1,464 @@
+use async_trait::async_trait;
+
+use crate::{
+    client::ClientState,
+    notify::{NotifyFlags, KEYSPACE_EVENTS},
+    resp::Entry,
+    storage::{
+        geo::{self, Point, Shape},
+        ScoreBound, SortedSet, Storage,
+    },
+};
+
+use super::{
+    parse_arg, parse_int_arg,
+    zset::{load_zset, update_zset},
+    Command, CommandError,
+};
+
+pub fn parse(cmd: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let key = parse_arg(args, 1)?;
+
+    let cmd_kind: Box<dyn Command> = match cmd {
+        "GEOADD" => Box::new(parse_geoadd(key, args)?),
+
+        "GEOPOS" => Box::new(GeoPosCommand {
+            key,
+            members: (2..args.len())
+                .map(|at| parse_arg(args, at))
+                .collect::<Result<_, _>>()?,
+        }),
+
+        "GEODIST" => {
+            let unit = match args.len() {
+                4 => 1.0,
+                5 => parse_unit(&parse_arg(args, 4)?)?,
+                _ => return Err(CommandError),
+            };
+            Box::new(GeoDistCommand {
+                key,
+                from: parse_arg(args, 2)?,
+                to: parse_arg(args, 3)?,
+                unit,
+            })
+        }
+
+        "GEOSEARCH" => Box::new(parse_geosearch(key, args)?),
+
+        _ => return Err(CommandError),
+    };
+
+    Ok(cmd_kind)
+}
+
+fn parse_geoadd(key: String, args: &[Entry]) -> Result<GeoAddCommand, CommandError> {
+    let mut command = GeoAddCommand {
+        key,
+        ..Default::default()
+    };
+
+    let mut at = 2;
+    while at < args.len() {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            "NX" => command.nx = true,
+            "XX" => command.xx = true,
+            "CH" => command.ch = true,
+            _ => break,
+        }
+        at += 1;
+    }
+
+    let triples = &args[at..];
+    if command.nx && command.xx || triples.is_empty() || !triples.len().is_multiple_of(3) {
+        return Err(CommandError);
+    }
+    for at in (at..args.len()).step_by(3) {
+        command.items.push((
+            parse_coordinate(args, at)?,
+            parse_coordinate(args, at + 1)?,
+            parse_arg(args, at + 2)?,
+        ));
+    }
+
+    Ok(command)
+}
+
+fn parse_geosearch(key: String, args: &[Entry]) -> Result<GeoSearchCommand, CommandError> {
+    let mut origin = None;
+    let mut shape = None;
+    let mut order = None;
+    let mut count = None;
+    let mut any = false;
+    let (mut with_coord, mut with_dist, mut with_hash) = (false, false, false);
+
+    let mut at = 2;
+    while at < args.len() {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            "FROMMEMBER" if origin.is_none() => {
+                origin = Some(Origin::Member(parse_arg(args, at + 1)?));
+                at += 2;
+            }
+            "FROMLONLAT" if origin.is_none() => {
+                origin = Some(Origin::LonLat(
+                    parse_coordinate(args, at + 1)?,
+                    parse_coordinate(args, at + 2)?,
+                ));
+                at += 3;
+            }
+            "BYRADIUS" if shape.is_none() => {
+                let unit = parse_unit(&parse_arg(args, at + 2)?)?;
+                let radius = parse_length(args, at + 1)?;
+                shape = Some((Shape::Radius(radius * unit), unit));
+                at += 3;
+            }
+            "BYBOX" if shape.is_none() => {
+                let unit = parse_unit(&parse_arg(args, at + 3)?)?;
+                let box_shape = Shape::Box {
+                    width: parse_length(args, at + 1)? * unit,
+                    height: parse_length(args, at + 2)? * unit,
+                };
+                shape = Some((box_shape, unit));
+                at += 4;
+            }
+            "ASC" => {
+                order = Some(Order::Asc);
+                at += 1;
+            }
+            "DESC" => {
+                order = Some(Order::Desc);
+                at += 1;
+            }
+            "COUNT" => {
+                count = match usize::try_from(parse_int_arg(args, at + 1)?) {
+                    Ok(count) if count > 0 => Some(count),
+                    _ => return Err(CommandError),
+                };
+                at += 2;
+                if args.get(at).is_some() && parse_arg(args, at)?.eq_ignore_ascii_case("ANY") {
+                    any = true;
+                    at += 1;
+                }
+            }
+            "WITHCOORD" => {
+                with_coord = true;
+                at += 1;
+            }
+            "WITHDIST" => {
+                with_dist = true;
+                at += 1;
+            }
+            "WITHHASH" => {
+                with_hash = true;
+                at += 1;
+            }
+            _ => return Err(CommandError),
+        }
+    }
+
+    let (Some(origin), Some((shape, unit))) = (origin, shape) else {
+        return Err(CommandError);
+    };
+    // Limiting the results only makes sense on the closest ones unless ANY
+    // asked for whichever are found first.
+    let order = match order {
+        None if count.is_some() && !any => Some(Order::Asc),
+        order => order,
+    };
+
+    Ok(GeoSearchCommand {
+        key,
+        origin,
+        shape,
+        unit,
+        order,
+        count,
+        any,
+        with_coord,
+        with_dist,
+        with_hash,
+    })
+}
+
+fn parse_coordinate(args: &[Entry], at: usize) -> Result<f64, CommandError> {
+    match parse_arg(args, at)?.parse::<f64>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => Err(CommandError),
+    }
+}
+
+fn parse_length(args: &[Entry], at: usize) -> Result<f64, CommandError> {
+    match parse_coordinate(args, at)? {
+        length if length >= 0.0 => Ok(length),
+        _ => Err(CommandError),
+    }
+}
+
+/// Meters per `unit`.
+fn parse_unit(unit: &str) -> Result<f64, CommandError> {
+    match unit.to_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err(CommandError),
+    }
+}
+
+/// Renders a distance the way Redis does, with four decimals.
+fn format_distance(meters: f64, unit: f64) -> Entry {
+    Entry::Text(format!("{:.4}", meters / unit))
+}
+
+fn format_point(point: Point) -> Entry {
+    Entry::Array(vec![
+        Entry::Text(point.lon.to_string()),
+        Entry::Text(point.lat.to_string()),
+    ])
+}
+
+fn position(zset: &SortedSet, member: &str) -> Option<Point> {
+    zset.score(member).map(|score| geo::decode(score as u64))
+}
+
+#[derive(Default)]
+pub struct GeoAddCommand {
+    key: String,
+    items: Vec<(f64, f64, String)>,
+    nx: bool,
+    xx: bool,
+    ch: bool,
+}
+
+#[async_trait]
+impl Command for GeoAddCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let mut scores = Vec::with_capacity(self.items.len());
+        for (lon, lat, member) in &self.items {
+            match Point::new(*lon, *lat) {
+                Some(point) => scores.push((geo::encode(point) as f64, member)),
+                None => {
+                    return Ok(Entry::error(
+                        "ERR",
+                        format!("invalid longitude,latitude pair {:.6},{:.6}", lon, lat),
+                    ))
+                }
+            }
+        }
+
+        let counts = update_zset(storage, &self.key, |zset| {
+            let mut added = 0i64;
+            let mut changed = 0;
+            for (score, member) in scores {
+                match zset.score(member) {
+                    None if self.xx => continue,
+                    None => added += 1,
+                    Some(_) if self.nx => continue,
+                    Some(current) if current != score => changed += 1,
+                    Some(_) => {}
+                }
+                zset.insert(member.clone(), score);
+            }
+            (added, changed)
+        })
+        .await;
+        let (added, changed) = match counts {
+            Ok(counts) => counts,
+            Err(reply) => return Ok(reply),
+        };
+
+        // GEOADD is ZADD under the hood, and reported as such.
+        if added + changed > 0 {
+            KEYSPACE_EVENTS.notify(NotifyFlags::ZSET, "zadd", &self.key);
+        }
+        let reply = if self.ch { added + changed } else { added };
+        Ok(Entry::Int(reply))
+    }
+}
+
+pub struct GeoPosCommand {
+    key: String,
+    members: Vec<String>,
+}
+
+#[async_trait]
+impl Command for GeoPosCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let zset = match load_zset(storage, &self.key).await {
+            Ok(found) => found.map(|(zset, _)| zset).unwrap_or_default(),
+            Err(reply) => return Ok(reply),
+        };
+
+        let positions: Vec<Entry> = self
+            .members
+            .iter()
+            .map(|member| match position(&zset, member) {
+                Some(point) => format_point(point),
+                None => Entry::NullArray,
+            })
+            .collect();
+        Ok(Entry::Array(positions))
+    }
+}
+
+pub struct GeoDistCommand {
+    key: String,
+    from: String,
+    to: String,
+    unit: f64,
+}
+
+#[async_trait]
+impl Command for GeoDistCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let zset = match load_zset(storage, &self.key).await {
+            Ok(found) => found.map(|(zset, _)| zset).unwrap_or_default(),
+            Err(reply) => return Ok(reply),
+        };
+
+        match (position(&zset, &self.from), position(&zset, &self.to)) {
+            (Some(from), Some(to)) => Ok(format_distance(geo::distance(from, to), self.unit)),
+            _ => Ok(Entry::Nil),
+        }
+    }
+}
+
+enum Origin {
+    Member(String),
+    LonLat(f64, f64),
+}
+
+enum Order {
+    Asc,
+    Desc,
+}
+
+pub struct GeoSearchCommand {
+    key: String,
+    origin: Origin,
+    shape: Shape,
+    /// Meters per unit the shape was given in, which distances are reported in.
+    unit: f64,
+    order: Option<Order>,
+    count: Option<usize>,
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+}
+
+struct Found<'a> {
+    member: &'a str,
+    distance: f64,
+    hash: u64,
+    point: Point,
+}
+
+#[async_trait]
+impl Command for GeoSearchCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let zset = match load_zset(storage, &self.key).await {
+            Ok(Some((zset, _))) => zset,
+            Ok(None) => return Ok(Entry::Array(vec![])),
+            Err(reply) => return Ok(reply),
+        };
+
+        let center = match &self.origin {
+            Origin::Member(member) => match position(&zset, member) {
+                Some(point) => point,
+                None => {
+                    return Ok(Entry::error(
+                        "ERR",
+                        "could not decode requested zset member",
+                    ))
+                }
+            },
+            Origin::LonLat(lon, lat) => match Point::new(*lon, *lat) {
+                Some(point) => point,
+                None => {
+                    return Ok(Entry::error(
+                        "ERR",
+                        format!("invalid longitude,latitude pair {:.6},{:.6}", lon, lat),
+                    ))
+                }
+            },
+        };
+
+        let mut found = Vec::new();
+        'cells: for (min, max) in geo::search_ranges(center, self.shape) {
+            let min = ScoreBound {
+                value: min as f64,
+                exclusive: false,
+            };
+            let max = ScoreBound {
+                value: max as f64,
+                exclusive: true,
+            };
+            for (member, score) in zset.range_by_score(min, max) {
+                let hash = score as u64;
+                let point = geo::decode(hash);
+                if let Some(distance) = self.shape.distance_within(center, point) {
+                    found.push(Found {
+                        member,
+                        distance,
+                        hash,
+                        point,
+                    });
+                    if self.any && Some(found.len()) == self.count {
+                        break 'cells;
+                    }
+                }
+            }
+        }
+
+        match self.order {
+            Some(Order::Asc) => found.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+            Some(Order::Desc) => found.sort_by(|a, b| b.distance.total_cmp(&a.distance)),
+            None => {}
+        }
+        if let Some(count) = self.count {
+            found.truncate(count);
+        }
+
+        if !(self.with_coord || self.with_dist || self.with_hash) {
+            let members = found
+                .iter()
+                .map(|found| Entry::Text(found.member.to_string()))
+                .collect();
+            return Ok(Entry::Array(members));
+        }
+
+        let items: Vec<Entry> = found
+            .iter()
+            .map(|found| {
+                let mut fields = vec![Entry::Text(found.member.to_string())];
+                if self.with_dist {
+                    fields.push(format_distance(found.distance, self.unit));
+                }
+                if self.with_hash {
+                    fields.push(Entry::Int(found.hash as i64));
+                }
+                if self.with_coord {
+                    fields.push(format_point(found.point));
+                }
+                Entry::Array(fields)
+            })
+            .collect();
+        Ok(Entry::Array(items))
+    }
+}
@@ -0,0 +1,206 @@
+use async_trait::async_trait;
+
+use crate::{
+    client::ClientState,
+    notify::{NotifyFlags, KEYSPACE_EVENTS},
+    resp::Entry,
+    storage::{
+        hyperloglog::{HllError, HyperLogLog},
+        Data, Storage, Value,
+    },
+};
+
+use super::{parse_arg, parse_rest, wrong_type, Command, CommandError};
+
+pub fn parse(cmd: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let cmd_kind: Box<dyn Command> = match cmd {
+        "PFADD" => Box::new(PfAddCommand {
+            key: parse_arg(args, 1)?,
+            elements: parse_rest(args, 2),
+        }),
+
+        "PFCOUNT" => {
+            let keys = parse_rest(args, 1);
+            if keys.is_empty() {
+                return Err(CommandError);
+            }
+            Box::new(PfCountCommand { keys })
+        }
+
+        "PFMERGE" => Box::new(PfMergeCommand {
+            dest: parse_arg(args, 1)?,
+            sources: parse_rest(args, 2),
+        }),
+
+        _ => return Err(CommandError),
+    };
+
+    Ok(cmd_kind)
+}
+
+/// Decodes the HyperLogLog in `value`. Missing keys yield `Ok(None)`; other
+/// values yield the matching error reply as `Err`.
+fn decode_hll(value: Option<&Value>) -> Result<Option<HyperLogLog>, Entry> {
+    match value.map(|value| &value.value) {
+        Some(Data::String(value)) => match HyperLogLog::decode(value) {
+            Ok(hll) => Ok(Some(hll)),
+            Err(HllError::NotHll) => Err(Entry::error(
+                "WRONGTYPE",
+                "Key is not a valid HyperLogLog string value.",
+            )),
+            Err(HllError::Corrupted) => {
+                Err(Entry::error("INVALIDOBJ", "Corrupted HLL object detected"))
+            }
+        },
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+/// Loads the HyperLogLog stored at `key`, as `decode_hll` does.
+async fn load_hll(storage: &dyn Storage, key: &str) -> Result<Option<HyperLogLog>, Entry> {
+    decode_hll(storage.get(key).await.as_ref())
+}
+
+/// Writes `hll` to `value`, keeping the expiry of a value already there.
+fn store_hll(value: &mut Option<Value>, hll: &HyperLogLog) {
+    let encoded = Data::String(hll.encode());
+    match value {
+        Some(value) => value.value = encoded,
+        None => {
+            *value = Some(Value {
+                value: encoded,
+                expiry: None,
+            })
+        }
+    }
+}
+
+/// Changes the HyperLogLog stored at `key` with `f` in one step no other
+/// client can come between, handing it an empty one if the key is missing
+/// along with whether the key exists. `f` returns whether it changed the
+/// HyperLogLog, which is only written back then.
+async fn update_hll<T: Send>(
+    storage: &dyn Storage,
+    key: &str,
+    f: impl FnOnce(&mut HyperLogLog, bool) -> (T, bool) + Send,
+) -> Result<T, Entry> {
+    storage
+        .update_if_with(key, |value| {
+            let found = match decode_hll(value.as_ref()) {
+                Ok(found) => found,
+                Err(reply) => return (Err(reply), false),
+            };
+            let existed = found.is_some();
+            let mut hll = found.unwrap_or_default();
+            let (result, changed) = f(&mut hll, existed);
+            if changed {
+                store_hll(value, &hll);
+            }
+            (Ok(result), changed)
+        })
+        .await
+}
+
+pub struct PfAddCommand {
+    key: String,
+    elements: Vec<String>,
+}
+
+#[async_trait]
+impl Command for PfAddCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let changed = update_hll(storage, &self.key, |hll, existed| {
+            let mut changed = !existed;
+            for element in &self.elements {
+                changed |= hll.add(element.as_bytes());
+            }
+            (changed, changed)
+        })
+        .await;
+        let changed = match changed {
+            Ok(changed) => changed,
+            Err(reply) => return Ok(reply),
+        };
+        if changed {
+            KEYSPACE_EVENTS.notify(NotifyFlags::STRING, "pfadd", &self.key);
+        }
+        Ok(Entry::Int(changed as i64))
+    }
+}
+
+pub struct PfCountCommand {
+    keys: Vec<String>,
+}
+
+#[async_trait]
+impl Command for PfCountCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        // Only read: a cardinality worked out here isn't cached back in the
+        // key, which would be a write to log and propagate on every call.
+        if let [key] = self.keys.as_slice() {
+            return Ok(match load_hll(storage, key).await {
+                Ok(hll) => Entry::Int(hll.map_or(0, |mut hll| hll.count()) as i64),
+                Err(reply) => reply,
+            });
+        }
+
+        let mut union = HyperLogLog::new();
+        for key in &self.keys {
+            match load_hll(storage, key).await {
+                Ok(Some(hll)) => union.merge(&hll),
+                Ok(None) => {}
+                Err(reply) => return Ok(reply),
+            }
+        }
+        Ok(Entry::Int(union.count() as i64))
+    }
+}
+
+pub struct PfMergeCommand {
+    dest: String,
+    sources: Vec<String>,
+}
+
+#[async_trait]
+impl Command for PfMergeCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        // The sources are read and the destination written in one step, so
+        // no client changes a source in between.
+        let mut keys = vec![self.dest.as_str()];
+        keys.extend(self.sources.iter().map(String::as_str));
+        let merged = storage
+            .update_many_with(&keys, |view| {
+                let mut merged = decode_hll(view.get(&self.dest))?.unwrap_or_default();
+                for key in &self.sources {
+                    if let Some(hll) = decode_hll(view.get(key))? {
+                        merged.merge(&hll);
+                    }
+                }
+
+                // Like Redis, merge results are always stored dense.
+                merged.promote();
+                store_hll(view.get_mut(&self.dest), &merged);
+                Ok(())
+            })
+            .await;
+        if let Err(reply) = merged {
+            return Ok(reply);
+        }
+        // Redis reports merges as additions too.
+        KEYSPACE_EVENTS.notify(NotifyFlags::STRING, "pfadd", &self.dest);
+        Ok(Entry::ok())
+    }
+}
@@ -0,0 +1,153 @@
+use async_trait::async_trait;
+
+use crate::{client::ClientState, glob::glob_match, resp::Entry, storage::Storage};
+
+use super::{
+    command_name, command_table, keys, parse_arg, parse_rest, Command, CommandError, CommandSpec,
+    COMMANDS,
+};
+
+pub fn parse(_: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let Ok(subcommand) = parse_arg(args, 1) else {
+        return Ok(Box::new(CommandInfoCommand { names: Vec::new() }));
+    };
+    let cmd_kind: Box<dyn Command> = match (subcommand.to_uppercase().as_str(), args.len()) {
+        ("COUNT", 2) => Box::new(CommandCountCommand),
+        ("INFO", _) => Box::new(CommandInfoCommand {
+            names: parse_rest(args, 2),
+        }),
+        ("LIST", 2) => Box::new(CommandListCommand { filter: None }),
+        ("LIST", 5) if parse_arg(args, 2)?.eq_ignore_ascii_case("FILTERBY") => {
+            let value = parse_arg(args, 4)?;
+            let filter = match parse_arg(args, 3)?.to_uppercase().as_str() {
+                "ACLCAT" => Filter::AclCategory(value.to_lowercase()),
+                "PATTERN" => Filter::Pattern(value.to_lowercase()),
+                // No modules are ever loaded.
+                "MODULE" => Filter::Module,
+                _ => return Err(CommandError),
+            };
+            Box::new(CommandListCommand {
+                filter: Some(filter),
+            })
+        }
+        ("GETKEYS", 3..) => Box::new(CommandGetkeysCommand {
+            request: args[2..].to_vec(),
+        }),
+        _ => return Err(CommandError),
+    };
+    Ok(cmd_kind)
+}
+
+/// A command described as COMMAND INFO replies: its name, arity, flags and
+/// where its keys are, then its ACL categories. Tips, key specifications
+/// and subcommands are left empty.
+fn describe(spec: &CommandSpec) -> Entry {
+    let text = |text: &str| Entry::Text(text.to_string());
+    let flags = spec.flags.iter().map(|flag| text(flag.name()));
+    let categories = spec.acl_categories().into_iter();
+    Entry::Array(vec![
+        text(&spec.name.to_lowercase()),
+        Entry::Int(spec.arity as i64),
+        Entry::Set(flags.collect()),
+        Entry::Int(spec.first_key as i64),
+        Entry::Int(spec.last_key as i64),
+        Entry::Int(spec.key_step as i64),
+        Entry::Set(
+            categories
+                .map(|category| text(&format!("@{}", category)))
+                .collect(),
+        ),
+        Entry::Array(vec![]),
+        Entry::Array(vec![]),
+        Entry::Array(vec![]),
+    ])
+}
+
+pub struct CommandCountCommand;
+
+#[async_trait]
+impl Command for CommandCountCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        Ok(Entry::Int(COMMANDS.len() as i64))
+    }
+}
+
+/// Describes the commands named, nil for those there are none of, or every
+/// command if none is named.
+pub struct CommandInfoCommand {
+    names: Vec<String>,
+}
+
+#[async_trait]
+impl Command for CommandInfoCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        if self.names.is_empty() {
+            return Ok(Entry::Array(COMMANDS.iter().map(describe).collect()));
+        }
+        let described = self.names.iter().map(|name| {
+            command_table()
+                .get(&*command_name(name))
+                .map_or(Entry::Nil, |spec| describe(spec))
+        });
+        Ok(Entry::Array(described.collect()))
+    }
+}
+
+/// Which commands COMMAND LIST lists.
+enum Filter {
+    AclCategory(String),
+    /// A glob pattern of names in lower case.
+    Pattern(String),
+    Module,
+}
+
+/// The names of every command, or of those passing a filter.
+pub struct CommandListCommand {
+    filter: Option<Filter>,
+}
+
+#[async_trait]
+impl Command for CommandListCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        let listed = COMMANDS
+            .iter()
+            .filter(|spec| match &self.filter {
+                None => true,
+                Some(Filter::AclCategory(category)) => spec.in_category(category),
+                Some(Filter::Pattern(pattern)) => glob_match(pattern, &spec.name.to_lowercase()),
+                Some(Filter::Module) => false,
+            })
+            .map(|spec| Entry::Text(spec.name.to_lowercase()));
+        Ok(Entry::Array(listed.collect()))
+    }
+}
+
+/// The keys a request would name, without running it.
+pub struct CommandGetkeysCommand {
+    request: Vec<Entry>,
+}
+
+#[async_trait]
+impl Command for CommandGetkeysCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        let spec = match self.request.first() {
+            Some(Entry::Text(name)) => command_table().get(&*command_name(name)),
+            _ => None,
+        };
+        let Some(spec) = spec else {
+            return Ok(Entry::error("ERR", "Invalid command specified"));
+        };
+        if !spec.accepts(&self.request) {
+            return Ok(Entry::error(
+                "ERR",
+                "Invalid number of arguments specified for command",
+            ));
+        }
+        let keys = keys(&self.request);
+        if keys.is_empty() {
+            return Ok(Entry::error("ERR", "The command has no key arguments"));
+        }
+        let keys = keys.into_iter().map(|key| Entry::Text(key.to_string()));
+        Ok(Entry::Array(keys.collect()))
+    }
+}
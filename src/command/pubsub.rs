@@ -0,0 +1,263 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+
+use crate::{client::ClientState, pubsub::PUBSUB, resp::Entry, storage::Storage};
+
+use super::{parse_arg, parse_rest, Command, CommandError};
+
+pub fn parse(name: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let cmd_kind: Box<dyn Command> = match name {
+        "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" => Box::new(SubscribeCommand {
+            channels: parse_rest(args, 1),
+            namespace: Namespace::of(name),
+        }),
+        "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "SUNSUBSCRIBE" => Box::new(UnsubscribeCommand {
+            channels: parse_rest(args, 1),
+            namespace: Namespace::of(name),
+        }),
+        "PUBLISH" | "SPUBLISH" => Box::new(PublishCommand {
+            channel: parse_arg(args, 1)?,
+            message: parse_arg(args, 2)?,
+            shard: name == "SPUBLISH",
+        }),
+        "PUBSUB" => parse_pubsub(args)?,
+        _ => return Err(CommandError),
+    };
+    Ok(cmd_kind)
+}
+
+fn parse_pubsub(args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let subcommand = parse_arg(args, 1)?.to_uppercase();
+    let cmd_kind: Box<dyn Command> = match (subcommand.as_str(), args.len()) {
+        ("CHANNELS", 2 | 3) => Box::new(PubsubChannelsCommand {
+            pattern: parse_arg(args, 2).ok(),
+            shard: false,
+        }),
+        ("SHARDCHANNELS", 2 | 3) => Box::new(PubsubChannelsCommand {
+            pattern: parse_arg(args, 2).ok(),
+            shard: true,
+        }),
+        ("NUMSUB" | "SHARDNUMSUB", _) => Box::new(PubsubNumsubCommand {
+            channels: parse_rest(args, 2),
+            shard: subcommand == "SHARDNUMSUB",
+        }),
+        ("NUMPAT", 2) => Box::new(PubsubNumpatCommand),
+        _ => return Err(CommandError),
+    };
+    Ok(cmd_kind)
+}
+
+/// What a client subscribes to: channels, glob patterns of channels or
+/// shard channels.
+#[derive(Clone, Copy, PartialEq)]
+enum Namespace {
+    Channels,
+    Patterns,
+    Shard,
+}
+
+impl Namespace {
+    fn of(name: &str) -> Namespace {
+        match name {
+            "PSUBSCRIBE" | "PUNSUBSCRIBE" => Namespace::Patterns,
+            "SSUBSCRIBE" | "SUNSUBSCRIBE" => Namespace::Shard,
+            _ => Namespace::Channels,
+        }
+    }
+
+    /// How confirmations name subscribing, then unsubscribing.
+    fn kinds(self) -> (&'static str, &'static str) {
+        match self {
+            Namespace::Channels => ("subscribe", "unsubscribe"),
+            Namespace::Patterns => ("psubscribe", "punsubscribe"),
+            Namespace::Shard => ("ssubscribe", "sunsubscribe"),
+        }
+    }
+
+    fn subscribed(self, client: &mut ClientState) -> &mut HashSet<String> {
+        match self {
+            Namespace::Channels => &mut client.subscriptions,
+            Namespace::Patterns => &mut client.patterns,
+            Namespace::Shard => &mut client.shard_subscriptions,
+        }
+    }
+
+    /// How many subscriptions confirmations count: like on Redis, shard
+    /// channels are counted apart from the others.
+    fn count(self, client: &ClientState) -> usize {
+        match self {
+            Namespace::Shard => client.shard_subscriptions.len(),
+            _ => client.subscription_count(),
+        }
+    }
+
+    fn subscribe(self, channel: &str, client: &ClientState) {
+        match self {
+            Namespace::Channels => PUBSUB.subscribe(channel, client.id(), client.inbox()),
+            Namespace::Patterns => PUBSUB.psubscribe(channel, client.id(), client.inbox()),
+            Namespace::Shard => PUBSUB.ssubscribe(channel, client.id(), client.inbox()),
+        }
+    }
+
+    fn unsubscribe(self, channel: &str, client: &ClientState) {
+        match self {
+            Namespace::Channels => PUBSUB.unsubscribe(channel, client.id()),
+            Namespace::Patterns => PUBSUB.punsubscribe(channel, client.id()),
+            Namespace::Shard => PUBSUB.sunsubscribe(channel, client.id()),
+        }
+    }
+
+    /// The push confirming the client `kind` (subscribed or unsubscribed)
+    /// to `channel`, with how many subscriptions it has left.
+    fn confirmation(self, kind: &str, channel: Option<&str>, client: &ClientState) -> Entry {
+        Entry::Push(vec![
+            Entry::Text(kind.to_string()),
+            channel.map_or(Entry::Nil, |channel| Entry::Text(channel.to_string())),
+            Entry::Int(self.count(client) as i64),
+        ])
+    }
+}
+
+/// Replies with each of `confirmations` in turn, the last one as the reply
+/// of the command.
+fn confirm(client: &mut ClientState, mut confirmations: Vec<Entry>) -> Entry {
+    let last = confirmations.pop().unwrap_or(Entry::Nil);
+    client.replies.extend(confirmations);
+    last
+}
+
+/// Has the client receive what is published on channels from now on, on
+/// channels matching glob patterns or on shard channels.
+pub struct SubscribeCommand {
+    channels: Vec<String>,
+    namespace: Namespace,
+}
+
+#[async_trait]
+impl Command for SubscribeCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let (kind, _) = self.namespace.kinds();
+        let mut confirmations = Vec::new();
+        for channel in &self.channels {
+            if self.namespace.subscribed(client).insert(channel.clone()) {
+                self.namespace.subscribe(channel, client);
+            }
+            confirmations.push(self.namespace.confirmation(kind, Some(channel), client));
+        }
+        Ok(confirm(client, confirmations))
+    }
+}
+
+/// Stops the client receiving what is published on channels, or on any
+/// channel if none is named, and likewise for patterns and shard channels.
+pub struct UnsubscribeCommand {
+    channels: Vec<String>,
+    namespace: Namespace,
+}
+
+#[async_trait]
+impl Command for UnsubscribeCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let (_, kind) = self.namespace.kinds();
+        let channels = match self.channels.is_empty() {
+            true => {
+                let subscribed = self.namespace.subscribed(client);
+                let mut channels: Vec<String> = subscribed.iter().cloned().collect();
+                channels.sort_unstable();
+                channels
+            }
+            false => self.channels.clone(),
+        };
+        // Like on Redis, being subscribed to nothing is confirmed too.
+        if channels.is_empty() {
+            return Ok(self.namespace.confirmation(kind, None, client));
+        }
+        let mut confirmations = Vec::new();
+        for channel in &channels {
+            if self.namespace.subscribed(client).remove(channel) {
+                self.namespace.unsubscribe(channel, client);
+            }
+            confirmations.push(self.namespace.confirmation(kind, Some(channel), client));
+        }
+        Ok(confirm(client, confirmations))
+    }
+}
+
+/// Sends a message to the subscribers of a channel, or of a shard channel
+/// with `shard`, replying how many it went to.
+pub struct PublishCommand {
+    channel: String,
+    message: String,
+    shard: bool,
+}
+
+#[async_trait]
+impl Command for PublishCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        let received = match self.shard {
+            true => PUBSUB.spublish(&self.channel, &self.message),
+            false => PUBSUB.publish(&self.channel, &self.message),
+        };
+        Ok(Entry::Int(received as i64))
+    }
+}
+
+/// The channels with subscribers, or those matching a pattern, among shard
+/// channels with `shard`.
+pub struct PubsubChannelsCommand {
+    pattern: Option<String>,
+    shard: bool,
+}
+
+#[async_trait]
+impl Command for PubsubChannelsCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        let channels = match self.shard {
+            true => PUBSUB.shard_channels(self.pattern.as_deref()),
+            false => PUBSUB.channels(self.pattern.as_deref()),
+        };
+        Ok(Entry::Array(
+            channels.into_iter().map(Entry::Text).collect(),
+        ))
+    }
+}
+
+/// How many clients are subscribed to each channel, or shard channel with
+/// `shard`.
+pub struct PubsubNumsubCommand {
+    channels: Vec<String>,
+    shard: bool,
+}
+
+#[async_trait]
+impl Command for PubsubNumsubCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        let counts = self.channels.iter().map(|channel| {
+            let count = match self.shard {
+                true => PUBSUB.shard_numsub(channel),
+                false => PUBSUB.numsub(channel),
+            };
+            (Entry::Text(channel.clone()), Entry::Int(count as i64))
+        });
+        Ok(Entry::Map(counts.collect()))
+    }
+}
+
+/// How many patterns clients are subscribed to.
+pub struct PubsubNumpatCommand;
+
+#[async_trait]
+impl Command for PubsubNumpatCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        Ok(Entry::Int(PUBSUB.numpat() as i64))
+    }
+}
@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::{client::ClientState, replication::REPLICATION, resp::Entry, storage::Storage};
+
+use super::{parse_arg, Command, CommandError};
+
+pub fn parse(name: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let cmd_kind: Box<dyn Command> = match name {
+        "REPLCONF" => Box::new(parse_replconf(args)?),
+        "PSYNC" => Box::new(PsyncCommand {
+            replid: parse_arg(args, 1)?,
+            offset: parse_arg(args, 2)?.parse().map_err(|_| CommandError)?,
+        }),
+        "REPLICAOF" | "SLAVEOF" => Box::new(ReplicaofCommand {
+            host: parse_arg(args, 1)?,
+            port: parse_arg(args, 2)?,
+        }),
+        "WAIT" => Box::new(WaitCommand {
+            replicas: parse_arg(args, 1)?,
+            timeout: parse_arg(args, 2)?,
+        }),
+        _ => return Err(CommandError),
+    };
+    Ok(cmd_kind)
+}
+
+fn parse_replconf(args: &[Entry]) -> Result<ReplconfCommand, CommandError> {
+    if args.len().is_multiple_of(2) {
+        return Err(CommandError);
+    }
+    let mut listening_port = None;
+    for at in (1..args.len()).step_by(2) {
+        let value = parse_arg(args, at + 1)?;
+        match parse_arg(args, at)?.to_lowercase().as_str() {
+            "listening-port" => {
+                listening_port = Some(value.parse().map_err(|_| CommandError)?);
+            }
+            // Capabilities only matter to partial resynchronizations.
+            "capa" | "ip-address" => {}
+            _ => return Err(CommandError),
+        }
+    }
+    Ok(ReplconfCommand { listening_port })
+}
+
+/// What a replica tells about itself before asking to be synchronized.
+pub struct ReplconfCommand {
+    listening_port: Option<u16>,
+}
+
+#[async_trait]
+impl Command for ReplconfCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        if self.listening_port.is_some() {
+            client.listening_port = self.listening_port;
+        }
+        Ok(Entry::ok())
+    }
+}
+
+/// Asks to become a replica, going on from `offset` in the stream of
+/// `replid` if possible: the server replies and sends what the replica
+/// lacks itself.
+pub struct PsyncCommand {
+    replid: String,
+    offset: i64,
+}
+
+#[async_trait]
+impl Command for PsyncCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        client.sync_replica = Some((self.replid.clone(), self.offset));
+        Ok(Entry::ok())
+    }
+}
+
+/// Makes the server a replica of the master at `host` and `port`, or a
+/// master again with NO ONE. The server switches once replied.
+pub struct ReplicaofCommand {
+    host: String,
+    port: String,
+}
+
+#[async_trait]
+impl Command for ReplicaofCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        if self.host.eq_ignore_ascii_case("NO") && self.port.eq_ignore_ascii_case("ONE") {
+            client.replicaof = Some(None);
+            return Ok(Entry::ok());
+        }
+        let Ok(port) = self.port.parse::<u16>() else {
+            return Ok(Entry::error("ERR", "Invalid master port"));
+        };
+        let master = (self.host.clone(), port);
+        if REPLICATION.master().as_ref() == Some(&master) {
+            return Ok(Entry::SimpleText(
+                "OK Already connected to specified master".to_string(),
+            ));
+        }
+        client.replicaof = Some(Some(master));
+        Ok(Entry::ok())
+    }
+}
+
+/// Waits for `replicas` replicas to acknowledge every write made so far, for
+/// up to `timeout` milliseconds or forever with 0. The server does the
+/// waiting, so writes aren't held off meanwhile.
+pub struct WaitCommand {
+    replicas: String,
+    timeout: String,
+}
+
+#[async_trait]
+impl Command for WaitCommand {
+    async fn execute(
+        &self,
+        _: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        if REPLICATION.master().is_some() {
+            return Ok(Entry::error(
+                "ERR",
+                "WAIT cannot be used with replica instances.",
+            ));
+        }
+        let Ok(replicas) = self.replicas.parse::<i64>() else {
+            return Ok(Entry::error(
+                "ERR",
+                "value is not an integer or out of range",
+            ));
+        };
+        let timeout = match self.timeout.parse::<i64>() {
+            Ok(timeout) if timeout < 0 => {
+                return Ok(Entry::error("ERR", "timeout is negative"));
+            }
+            Ok(0) => None,
+            Ok(timeout) => Some(Duration::from_millis(timeout as u64)),
+            Err(_) => {
+                return Ok(Entry::error(
+                    "ERR",
+                    "timeout is not an integer or out of range",
+                ));
+            }
+        };
+        client.wait_replicas = Some((replicas.max(0) as usize, timeout));
+        Ok(Entry::ok())
+    }
+}
@@ -0,0 +1,226 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::{sync::mpsc, task};
+
+use crate::{
+    aof,
+    client::ClientState,
+    resp::Entry,
+    scripting::{self, Call, Effect, Repl, SCRIPTS},
+    storage::Storage,
+};
+
+use super::{
+    is_scriptable, is_write, parse_arg, parse_bytes_arg, parse_int_arg, parse_rest, Command,
+    CommandError, CommandParser, ParseError,
+};
+
+pub fn parse(name: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let script = match name {
+        "EVAL" => Script::Body(parse_arg(args, 1)?),
+        "EVALSHA" => Script::Sha(parse_arg(args, 1)?),
+        "SCRIPT" => return parse_script(args),
+        _ => return Err(CommandError),
+    };
+    Ok(Box::new(EvalCommand {
+        script,
+        numkeys: parse_int_arg(args, 2)?,
+        args: (3..args.len())
+            .map(|at| parse_bytes_arg(args, at).map(Bytes::from))
+            .collect::<Result<_, _>>()?,
+    }))
+}
+
+fn parse_script(args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let subcommand = parse_arg(args, 1)?.to_uppercase();
+    let cmd_kind: Box<dyn Command> = match (subcommand.as_str(), args.len()) {
+        ("LOAD", 3) => Box::new(ScriptLoadCommand {
+            body: parse_arg(args, 2)?,
+        }),
+        ("EXISTS", 3..) => Box::new(ScriptExistsCommand {
+            shas: parse_rest(args, 2),
+        }),
+        ("FLUSH", 2) => Box::new(ScriptFlushCommand {
+            asynchronous: false,
+        }),
+        ("FLUSH", 3) => Box::new(ScriptFlushCommand {
+            asynchronous: match parse_arg(args, 2)?.to_uppercase().as_str() {
+                "ASYNC" => true,
+                "SYNC" => false,
+                _ => return Err(CommandError),
+            },
+        }),
+        _ => return Err(CommandError),
+    };
+    Ok(cmd_kind)
+}
+
+enum Script {
+    Body(String),
+    /// The SHA-1 of a script cached already.
+    Sha(String),
+}
+
+/// Runs a Lua script, given or cached, with the first `numkeys` arguments
+/// as its keys and the rest as its arguments. The server runs it as a
+/// write, so no other command runs until it is done.
+pub struct EvalCommand {
+    script: Script,
+    numkeys: i64,
+    args: Vec<Bytes>,
+}
+
+#[async_trait]
+impl Command for EvalCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        client: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let body = match &self.script {
+            Script::Body(body) => body.clone(),
+            Script::Sha(sha) => match SCRIPTS.get(sha) {
+                Some(body) => body,
+                None => {
+                    return Ok(Entry::error(
+                        "NOSCRIPT",
+                        "No matching script. Please use EVAL.",
+                    ))
+                }
+            },
+        };
+        let (keys, args) = match split_keys(self.numkeys, &self.args) {
+            Ok(split) => split,
+            Err(refused) => return Ok(refused),
+        };
+        let sha = SCRIPTS.add(&body);
+        serve_script(storage, client, false, move |calls| {
+            scripting::run(&body, &sha, keys, args, calls)
+        })
+        .await
+    }
+}
+
+/// Splits `args` into the first `numkeys`, the keys, and the rest, or
+/// replies why it can't.
+pub(super) fn split_keys(numkeys: i64, args: &[Bytes]) -> Result<(Vec<Bytes>, Vec<Bytes>), Entry> {
+    match usize::try_from(numkeys) {
+        Ok(numkeys) if numkeys <= args.len() => {
+            Ok((args[..numkeys].to_vec(), args[numkeys..].to_vec()))
+        }
+        Ok(_) => Err(Entry::error(
+            "ERR",
+            "Number of keys can't be greater than number of args",
+        )),
+        Err(_) => Err(Entry::error("ERR", "Number of keys can't be negative")),
+    }
+}
+
+/// Runs `script` on a thread of its own, where it waits for each command it
+/// sends to be run here, and replies what it returned. With `read_only`,
+/// the script may not write; otherwise the writes it made are left in
+/// `client` for the server to log and propagate.
+pub(super) async fn serve_script(
+    storage: &dyn Storage,
+    client: &mut ClientState,
+    read_only: bool,
+    script: impl FnOnce(mpsc::UnboundedSender<Call>) -> Entry + Send + 'static,
+) -> Result<Entry, CommandError> {
+    let (calls, mut requests) = mpsc::unbounded_channel();
+    let script = task::spawn_blocking(move || script(calls));
+    let mut effects = Vec::new();
+    while let Some((request, repl, reply)) = requests.recv().await {
+        let replied = call(storage, client, &request, read_only, repl, &mut effects).await;
+        let _ = reply.send(replied);
+    }
+    if !read_only {
+        client.script_effects = Some(effects);
+    }
+    script.await.map_err(|_| CommandError)
+}
+
+/// Runs a command for a script, replying as the script sees it. What it
+/// writes is added to `effects`, to go where `repl` says.
+async fn call(
+    storage: &dyn Storage,
+    client: &mut ClientState,
+    request: &[Entry],
+    read_only: bool,
+    repl: Repl,
+    effects: &mut Vec<Effect>,
+) -> Entry {
+    if !is_scriptable(request) {
+        return Entry::error("ERR", "This Redis command is not allowed from script");
+    }
+    if read_only && is_write(request) {
+        return Entry::error(
+            "ERR",
+            "Write commands are not allowed from read-only scripts.",
+        );
+    }
+    match CommandParser::new(request) {
+        Ok(cmd) => {
+            let reply = cmd
+                .execute(storage, client)
+                .await
+                .unwrap_or_else(|_| Entry::error("ERR", "failed executing command"));
+            let expiry = client.expiry.take();
+            if is_write(request) && repl != Repl::NONE {
+                if let Some(effect) = aof::propagated(request, &reply, expiry) {
+                    effects.push((effect, repl));
+                }
+            }
+            reply
+        }
+        Err(ParseError::Unknown) => Entry::error("ERR", "Unknown Redis command called from script"),
+        Err(err) => err.reply(request),
+    }
+}
+
+/// Caches a script without running it, replying with its SHA-1.
+pub struct ScriptLoadCommand {
+    body: String,
+}
+
+#[async_trait]
+impl Command for ScriptLoadCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        if let Err(failed) = scripting::compile(&self.body) {
+            return Ok(failed);
+        }
+        Ok(Entry::Text(SCRIPTS.add(&self.body)))
+    }
+}
+
+/// Whether each script is cached, as 1 or 0.
+pub struct ScriptExistsCommand {
+    shas: Vec<String>,
+}
+
+#[async_trait]
+impl Command for ScriptExistsCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        let exists = self
+            .shas
+            .iter()
+            .map(|sha| Entry::Int(SCRIPTS.exists(sha) as i64))
+            .collect();
+        Ok(Entry::Array(exists))
+    }
+}
+
+/// Empties the script cache, freeing it in the background if `asynchronous`.
+pub struct ScriptFlushCommand {
+    asynchronous: bool,
+}
+
+#[async_trait]
+impl Command for ScriptFlushCommand {
+    async fn execute(&self, _: &dyn Storage, _: &mut ClientState) -> Result<Entry, CommandError> {
+        let flushed = SCRIPTS.flush();
+        if self.asynchronous {
+            task::spawn_blocking(move || drop(flushed));
+        }
+        Ok(Entry::ok())
+    }
+}
@@ -0,0 +1,503 @@
+use std::{
+    borrow::Cow,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use async_trait::async_trait;
+use rand::seq::{IteratorRandom, SliceRandom};
+
+use crate::{
+    client::ClientState,
+    glob::glob_match,
+    notify::{NotifyFlags, KEYSPACE_EVENTS},
+    resp::Entry,
+    storage::{Data, Expiry, Set, Storage, Value},
+};
+
+use super::{parse_arg, parse_int_arg, parse_rest, wrong_type, Command, CommandError};
+
+pub fn parse(cmd: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let key = parse_arg(args, 1)?;
+
+    let cmd_kind: Box<dyn Command> = match cmd {
+        "SADD" => Box::new(SAddCommand {
+            key,
+            members: non_empty(parse_rest(args, 2))?,
+        }),
+
+        "SREM" => Box::new(SRemCommand {
+            key,
+            members: non_empty(parse_rest(args, 2))?,
+        }),
+
+        "SMEMBERS" => Box::new(SMembersCommand { key }),
+
+        "SISMEMBER" => Box::new(SIsMemberCommand {
+            key,
+            member: parse_arg(args, 2)?,
+        }),
+
+        "SCARD" => Box::new(SCardCommand { key }),
+
+        "SPOP" => {
+            let count = match args.get(2) {
+                Some(_) => {
+                    let count = parse_int_arg(args, 2)?;
+                    Some(usize::try_from(count).map_err(|_| CommandError)?)
+                }
+                None => None,
+            };
+            Box::new(SPopCommand { key, count })
+        }
+
+        "SRANDMEMBER" => {
+            let count = match args.get(2) {
+                // Redis refuses negative counts it could not double safely.
+                Some(_) => match parse_int_arg(args, 2)? {
+                    count if count < -(i64::MAX / 2) => return Err(CommandError),
+                    count => Some(count),
+                },
+                None => None,
+            };
+            Box::new(SRandMemberCommand { key, count })
+        }
+
+        "SMOVE" => Box::new(SMoveCommand {
+            source: key,
+            destination: parse_arg(args, 2)?,
+            member: parse_arg(args, 3)?,
+        }),
+
+        "SSCAN" => {
+            let cursor = parse_arg(args, 2)?
+                .parse::<u64>()
+                .map_err(|_| CommandError)?;
+            let mut pattern = None;
+            let mut count = 10;
+
+            let mut at = 3;
+            while at < args.len() {
+                match parse_arg(args, at)?.to_uppercase().as_str() {
+                    "MATCH" => pattern = Some(parse_arg(args, at + 1)?),
+                    "COUNT" => {
+                        count = usize::try_from(parse_int_arg(args, at + 1)?)
+                            .map_err(|_| CommandError)?;
+                        if count == 0 {
+                            return Err(CommandError);
+                        }
+                    }
+                    _ => return Err(CommandError),
+                }
+                at += 2;
+            }
+
+            Box::new(SScanCommand {
+                key,
+                cursor,
+                pattern,
+                count,
+            })
+        }
+
+        _ => return Err(CommandError),
+    };
+
+    Ok(cmd_kind)
+}
+
+fn non_empty(members: Vec<String>) -> Result<Vec<String>, CommandError> {
+    if members.is_empty() {
+        return Err(CommandError);
+    }
+    Ok(members)
+}
+
+/// Loads the set stored at `key` together with its expiry. Missing keys yield
+/// `Ok(None)` and keys of another type yield the wrong_type reply as `Err`.
+async fn load_set(
+    storage: &dyn Storage,
+    key: &str,
+) -> Result<Option<(Set, Option<Expiry>)>, Entry> {
+    match storage.get(key).await {
+        Some(Value {
+            value: Data::Set(set),
+            expiry,
+        }) => Ok(Some((set, expiry))),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+/// Changes the set stored at `key` in place with `f`, in one step no other
+/// client can come between, handing it an empty set if the key is missing.
+/// The key goes once the set is empty, as Redis never keeps empty aggregates
+/// around. Keys of another type are left alone and yield the wrong_type reply
+/// as `Err`.
+async fn update_set<T: Send>(
+    storage: &dyn Storage,
+    key: &str,
+    f: impl FnOnce(&mut Set) -> T + Send,
+) -> Result<T, Entry> {
+    let (result, deleted) = storage
+        .update_with(key, |value| {
+            let existed = value.is_some();
+            let stored = value.get_or_insert_with(|| Value {
+                value: Data::Set(Set::new()),
+                expiry: None,
+            });
+            let Data::Set(set) = &mut stored.value else {
+                return (Err(wrong_type()), false);
+            };
+            let result = f(set);
+            let emptied = set.is_empty();
+            if emptied {
+                *value = None;
+            }
+            (Ok(result), existed && emptied)
+        })
+        .await;
+    if deleted {
+        KEYSPACE_EVENTS.notify(NotifyFlags::GENERIC, "del", key);
+    }
+    result
+}
+
+fn member_entries(members: impl IntoIterator<Item = impl Into<String>>) -> Vec<Entry> {
+    members.into_iter().map(|m| Entry::Text(m.into())).collect()
+}
+
+pub struct SAddCommand {
+    key: String,
+    members: Vec<String>,
+}
+
+#[async_trait]
+impl Command for SAddCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let added = update_set(storage, &self.key, |set| {
+            self.members
+                .iter()
+                .filter(|member| set.insert(member.to_string()))
+                .count()
+        })
+        .await;
+        match added {
+            Ok(added) => {
+                if added > 0 {
+                    KEYSPACE_EVENTS.notify(NotifyFlags::SET, "sadd", &self.key);
+                }
+                Ok(Entry::Int(added as i64))
+            }
+            Err(reply) => Ok(reply),
+        }
+    }
+}
+
+pub struct SRemCommand {
+    key: String,
+    members: Vec<String>,
+}
+
+#[async_trait]
+impl Command for SRemCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let removed = update_set(storage, &self.key, |set| {
+            let removed = self
+                .members
+                .iter()
+                .filter(|member| set.remove(member))
+                .count();
+            // Told before the key is deleted for being emptied.
+            if removed > 0 {
+                KEYSPACE_EVENTS.notify(NotifyFlags::SET, "srem", &self.key);
+            }
+            removed
+        })
+        .await;
+        match removed {
+            Ok(removed) => Ok(Entry::Int(removed as i64)),
+            Err(reply) => Ok(reply),
+        }
+    }
+}
+
+pub struct SMembersCommand {
+    key: String,
+}
+
+#[async_trait]
+impl Command for SMembersCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        match load_set(storage, &self.key).await {
+            Ok(Some((set, _))) => Ok(Entry::Set(member_entries(set.iter()))),
+            Ok(None) => Ok(Entry::Set(vec![])),
+            Err(reply) => Ok(reply),
+        }
+    }
+}
+
+pub struct SIsMemberCommand {
+    key: String,
+    member: String,
+}
+
+#[async_trait]
+impl Command for SIsMemberCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        match load_set(storage, &self.key).await {
+            Ok(Some((set, _))) => Ok(Entry::Int(set.contains(&self.member) as i64)),
+            Ok(None) => Ok(Entry::Int(0)),
+            Err(reply) => Ok(reply),
+        }
+    }
+}
+
+pub struct SCardCommand {
+    key: String,
+}
+
+#[async_trait]
+impl Command for SCardCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        match load_set(storage, &self.key).await {
+            Ok(Some((set, _))) => Ok(Entry::Int(set.len() as i64)),
+            Ok(None) => Ok(Entry::Int(0)),
+            Err(reply) => Ok(reply),
+        }
+    }
+}
+
+pub struct SPopCommand {
+    key: String,
+    count: Option<usize>,
+}
+
+#[async_trait]
+impl Command for SPopCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let popped = update_set(storage, &self.key, |set| {
+            let mut rng = rand::thread_rng();
+            let popped: Vec<String> = set
+                .iter()
+                .map(Cow::into_owned)
+                .choose_multiple(&mut rng, self.count.unwrap_or(1).min(set.len()));
+            for member in popped.iter() {
+                set.remove(member);
+            }
+            if !popped.is_empty() {
+                KEYSPACE_EVENTS.notify(NotifyFlags::SET, "spop", &self.key);
+            }
+            popped
+        })
+        .await;
+
+        match (popped, self.count) {
+            (Err(reply), _) => Ok(reply),
+            (Ok(popped), Some(_)) => Ok(Entry::Set(member_entries(&popped))),
+            (Ok(popped), None) => match popped.first() {
+                Some(member) => Ok(Entry::Text(member.clone())),
+                None => Ok(Entry::Nil),
+            },
+        }
+    }
+}
+
+pub struct SRandMemberCommand {
+    key: String,
+    count: Option<i64>,
+}
+
+#[async_trait]
+impl Command for SRandMemberCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let set = match load_set(storage, &self.key).await {
+            Ok(Some((set, _))) => set,
+            Ok(None) if self.count.is_some() => return Ok(Entry::Array(vec![])),
+            Ok(None) => return Ok(Entry::Nil),
+            Err(reply) => return Ok(reply),
+        };
+
+        let mut rng = rand::thread_rng();
+        match self.count {
+            None => {
+                let member = set
+                    .iter()
+                    .choose(&mut rng)
+                    .expect("stored sets are never empty");
+                Ok(Entry::Text(member.into_owned()))
+            }
+            // A positive count returns distinct members, capped at the set size.
+            Some(count) if count >= 0 => {
+                let count = (count as usize).min(set.len());
+                let members = set.iter().choose_multiple(&mut rng, count);
+                Ok(Entry::Array(member_entries(members)))
+            }
+            // A negative count may return the same member several times.
+            Some(count) => {
+                let members: Vec<Cow<str>> = set.iter().collect();
+                let picked = (0..count.unsigned_abs())
+                    .filter_map(|_| members.choose(&mut rng).cloned())
+                    .collect::<Vec<_>>();
+                Ok(Entry::Array(member_entries(picked)))
+            }
+        }
+    }
+}
+
+pub struct SMoveCommand {
+    source: String,
+    destination: String,
+    member: String,
+}
+
+#[async_trait]
+impl Command for SMoveCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        // Both sets change in one step, so no client ever sees the member
+        // in neither or both.
+        let keys = [self.source.as_str(), self.destination.as_str()];
+        let reply = storage
+            .update_many_with(&keys, |view| {
+                fn set(value: Option<&Value>) -> Result<Option<&Set>, Entry> {
+                    match value.map(|value| &value.value) {
+                        Some(Data::Set(set)) => Ok(Some(set)),
+                        Some(_) => Err(wrong_type()),
+                        None => Ok(None),
+                    }
+                }
+                let Some(source) = set(view.get(&self.source))? else {
+                    return Ok(0);
+                };
+                let present = source.contains(&self.member);
+                set(view.get(&self.destination))?;
+                if !present {
+                    return Ok(0);
+                }
+                if self.source == self.destination {
+                    return Ok(1);
+                }
+
+                let source = view.get_mut(&self.source);
+                let emptied = match source {
+                    Some(Value {
+                        value: Data::Set(set),
+                        ..
+                    }) => {
+                        set.remove(&self.member);
+                        set.is_empty()
+                    }
+                    _ => unreachable!("the source was checked to be a set"),
+                };
+                if emptied {
+                    *source = None;
+                }
+                let destination = view
+                    .get_mut(&self.destination)
+                    .get_or_insert_with(|| Value {
+                        value: Data::Set(Set::new()),
+                        expiry: None,
+                    });
+                if let Data::Set(set) = &mut destination.value {
+                    set.insert(self.member.clone());
+                }
+
+                KEYSPACE_EVENTS.notify(NotifyFlags::SET, "srem", &self.source);
+                if emptied {
+                    KEYSPACE_EVENTS.notify(NotifyFlags::GENERIC, "del", &self.source);
+                }
+                KEYSPACE_EVENTS.notify(NotifyFlags::SET, "sadd", &self.destination);
+                Ok(1)
+            })
+            .await;
+        match reply {
+            Ok(moved) => Ok(Entry::Int(moved)),
+            Err(reply) => Ok(reply),
+        }
+    }
+}
+
+pub struct SScanCommand {
+    key: String,
+    cursor: u64,
+    pattern: Option<String>,
+    count: usize,
+}
+
+/// Position of `member` in the scan order. Walking members by hash keeps the
+/// cursor meaningful across inserts and removals between SSCAN calls, so every
+/// member present for the whole iteration is returned at least once. Zero is
+/// reserved for the "iteration finished" cursor.
+fn scan_position(member: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    member.hash(&mut hasher);
+    hasher.finish().max(1)
+}
+
+#[async_trait]
+impl Command for SScanCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let set = match load_set(storage, &self.key).await {
+            Ok(found) => found.map(|(set, _)| set).unwrap_or_default(),
+            Err(reply) => return Ok(reply),
+        };
+
+        let mut members: Vec<(u64, Cow<str>)> =
+            set.iter().map(|m| (scan_position(&m), m)).collect();
+        members.sort();
+
+        let start = members.partition_point(|(position, _)| *position < self.cursor);
+        let remaining = &members[start..];
+        let (page, rest) = remaining.split_at(self.count.min(remaining.len()));
+        let next_cursor = rest.first().map(|(position, _)| *position).unwrap_or(0);
+
+        let matched = page
+            .iter()
+            .map(|(_, member)| member.as_ref())
+            .filter(|member| match &self.pattern {
+                Some(pattern) => glob_match(pattern, member),
+                None => true,
+            });
+
+        Ok(Entry::Array(vec![
+            Entry::Text(next_cursor.to_string()),
+            Entry::Array(member_entries(matched)),
+        ]))
+    }
+}
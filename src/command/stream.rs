@@ -0,0 +1,356 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+
+use crate::{
+    client::ClientState,
+    notify::{NotifyFlags, KEYSPACE_EVENTS},
+    resp::Entry,
+    storage::{Data, Expiry, Fields, NewId, Storage, Stream, StreamId, Value},
+};
+
+use super::{parse_arg, parse_int_arg, wrong_type, Command, CommandError};
+
+pub mod group;
+
+pub fn parse(cmd: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let key = parse_arg(args, 1)?;
+
+    let cmd_kind: Box<dyn Command> = match cmd {
+        "XADD" => Box::new(parse_xadd(key, args)?),
+
+        "XLEN" => Box::new(XLenCommand { key }),
+
+        "XRANGE" | "XREVRANGE" => {
+            let rev = cmd == "XREVRANGE";
+            // XREVRANGE takes its bounds as `end start`.
+            let (start, end) = if rev { (3, 2) } else { (2, 3) };
+            let start = parse_range_start(&parse_arg(args, start)?)?;
+            let end = parse_range_end(&parse_arg(args, end)?)?;
+
+            let count = match args.len() {
+                4 => None,
+                6 if parse_arg(args, 4)?.eq_ignore_ascii_case("COUNT") => {
+                    Some(usize::try_from(parse_int_arg(args, 5)?).unwrap_or(0))
+                }
+                _ => return Err(CommandError),
+            };
+
+            Box::new(XRangeCommand {
+                key,
+                start,
+                end,
+                rev,
+                count,
+            })
+        }
+
+        _ => return Err(CommandError),
+    };
+
+    Ok(cmd_kind)
+}
+
+fn parse_xadd(key: String, args: &[Entry]) -> Result<XAddCommand, CommandError> {
+    let mut no_mkstream = false;
+    let mut trim = None;
+
+    let mut at = 2;
+    loop {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            "NOMKSTREAM" => {
+                no_mkstream = true;
+                at += 1;
+            }
+            "MAXLEN" | "MINID" => {
+                let (parsed, next) = parse_trim(args, at)?;
+                trim = Some(parsed);
+                at = next;
+            }
+            _ => break,
+        }
+    }
+
+    let id = parse_new_id(&parse_arg(args, at)?)?;
+    let pairs = &args[at + 1..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Err(CommandError);
+    }
+    let fields = (at + 1..args.len())
+        .step_by(2)
+        .map(|at| Ok((parse_arg(args, at)?, parse_arg(args, at + 1)?)))
+        .collect::<Result<Fields, CommandError>>()?;
+
+    Ok(XAddCommand {
+        key,
+        id,
+        fields,
+        no_mkstream,
+        trim,
+    })
+}
+
+/// Parses `MAXLEN|MINID [=|~] threshold [LIMIT count]` starting at `at`,
+/// returning the strategy and the position following it.
+fn parse_trim(args: &[Entry], at: usize) -> Result<(Trim, usize), CommandError> {
+    let strategy = parse_arg(args, at)?.to_uppercase();
+    let mut at = at + 1;
+
+    let mut approximate = false;
+    match parse_arg(args, at)?.as_str() {
+        "~" => {
+            approximate = true;
+            at += 1;
+        }
+        "=" => at += 1,
+        _ => {}
+    }
+
+    let threshold = parse_arg(args, at)?;
+    let strategy = if strategy == "MAXLEN" {
+        TrimStrategy::MaxLen(threshold.parse().map_err(|_| CommandError)?)
+    } else {
+        TrimStrategy::MinId(StreamId::parse(&threshold, 0).ok_or(CommandError)?)
+    };
+    at += 1;
+
+    let mut limit = None;
+    if args.len() > at && parse_arg(args, at)?.eq_ignore_ascii_case("LIMIT") {
+        // LIMIT bounds the work of approximate trimming only.
+        if !approximate {
+            return Err(CommandError);
+        }
+        let count = usize::try_from(parse_int_arg(args, at + 1)?).map_err(|_| CommandError)?;
+        limit = (count > 0).then_some(count);
+        at += 2;
+    }
+
+    Ok((Trim { strategy, limit }, at))
+}
+
+fn parse_new_id(arg: &str) -> Result<NewId, CommandError> {
+    if arg == "*" {
+        return Ok(NewId::Auto);
+    }
+    if let Some(ms) = arg.strip_suffix("-*") {
+        return Ok(NewId::AutoSeq(ms.parse().map_err(|_| CommandError)?));
+    }
+    Ok(NewId::Explicit(
+        StreamId::parse(arg, 0).ok_or(CommandError)?,
+    ))
+}
+
+/// Parses the lower bound of a range: `-`, `(id` or `id` (missing sequence
+/// meaning 0).
+fn parse_range_start(arg: &str) -> Result<StreamId, CommandError> {
+    match arg {
+        "-" => Ok(StreamId::MIN),
+        "+" => Ok(StreamId::MAX),
+        _ => match arg.strip_prefix('(') {
+            Some(id) => StreamId::parse(id, 0)
+                .and_then(StreamId::next)
+                .ok_or(CommandError),
+            None => StreamId::parse(arg, 0).ok_or(CommandError),
+        },
+    }
+}
+
+/// Parses the upper bound of a range: `+`, `(id` or `id` (missing sequence
+/// meaning the largest one).
+fn parse_range_end(arg: &str) -> Result<StreamId, CommandError> {
+    match arg {
+        "-" => Ok(StreamId::MIN),
+        "+" => Ok(StreamId::MAX),
+        _ => match arg.strip_prefix('(') {
+            Some(id) => StreamId::parse(id, u64::MAX)
+                .and_then(StreamId::prev)
+                .ok_or(CommandError),
+            None => StreamId::parse(arg, u64::MAX).ok_or(CommandError),
+        },
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Loads the stream stored at `key` together with its expiry. Missing keys
+/// yield `Ok(None)` and keys of another type yield the wrong_type reply as `Err`.
+async fn load_stream(
+    storage: &dyn Storage,
+    key: &str,
+) -> Result<Option<(Stream, Option<Expiry>)>, Entry> {
+    match storage.get(key).await {
+        Some(Value {
+            value: Data::Stream(stream),
+            expiry,
+        }) => Ok(Some((stream, expiry))),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+/// Changes the stream stored at `key` in place with `f`, in one step no other
+/// client can come between. `f` returns its result along with whether it
+/// changed the stream, which counts as a write only then. A missing key is
+/// handed to `f` as a new stream if `create` is set, kept only if `f` changed
+/// it, and otherwise yields `Ok(None)`. Unlike other aggregates, streams are
+/// kept even when trimmed down to no entries so their last ID is not lost.
+/// Keys of another type yield the wrong_type reply as `Err`.
+async fn update_stream<T: Send>(
+    storage: &dyn Storage,
+    key: &str,
+    create: bool,
+    f: impl FnOnce(&mut Stream) -> (T, bool) + Send,
+) -> Result<Option<T>, Entry> {
+    storage
+        .update_if_with(key, |value| {
+            let existed = value.is_some();
+            if !existed && !create {
+                return (Ok(None), false);
+            }
+            let stored = value.get_or_insert_with(|| Value {
+                value: Data::Stream(Stream::new()),
+                expiry: None,
+            });
+            let Data::Stream(stream) = &mut stored.value else {
+                return (Err(wrong_type()), false);
+            };
+            let (result, changed) = f(stream);
+            if !existed && !changed {
+                *value = None;
+            }
+            (Ok(Some(result)), changed)
+        })
+        .await
+}
+
+/// Renders one entry as `[id, [field, value, ...]]`.
+fn entry_reply(id: &StreamId, fields: &Fields) -> Entry {
+    let fields = fields
+        .iter()
+        .flat_map(|(field, value)| [Entry::Text(field.clone()), Entry::Text(value.clone())])
+        .collect();
+    Entry::Array(vec![Entry::Text(id.to_string()), Entry::Array(fields)])
+}
+
+/// Renders entries as an array of `[id, [field, value, ...]]` pairs.
+fn entries_reply<'a>(entries: impl IntoIterator<Item = (&'a StreamId, &'a Fields)>) -> Entry {
+    let entries = entries
+        .into_iter()
+        .map(|(id, fields)| entry_reply(id, fields))
+        .collect();
+    Entry::Array(entries)
+}
+
+enum TrimStrategy {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+struct Trim {
+    strategy: TrimStrategy,
+    limit: Option<usize>,
+}
+
+pub struct XAddCommand {
+    key: String,
+    id: NewId,
+    fields: Fields,
+    no_mkstream: bool,
+    trim: Option<Trim>,
+}
+
+#[async_trait]
+impl Command for XAddCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let added = update_stream(storage, &self.key, !self.no_mkstream, |stream| {
+            let id = match stream.next_id(self.id, now_ms()) {
+                Some(id) => id,
+                None if self.id == NewId::Explicit(StreamId::MIN) => {
+                    return (Err("The ID specified in XADD must be greater than 0-0"), false)
+                }
+                None => return (Err(
+                    "The ID specified in XADD is equal or smaller than the target stream top item",
+                ), false),
+            };
+            stream.add(id, self.fields.clone());
+            KEYSPACE_EVENTS.notify(NotifyFlags::STREAM, "xadd", &self.key);
+
+            if let Some(trim) = &self.trim {
+                let trimmed = match trim.strategy {
+                    TrimStrategy::MaxLen(max_len) => stream.trim_max_len(max_len, trim.limit),
+                    TrimStrategy::MinId(min_id) => stream.trim_min_id(min_id, trim.limit),
+                };
+                if trimmed > 0 {
+                    KEYSPACE_EVENTS.notify(NotifyFlags::STREAM, "xtrim", &self.key);
+                }
+            }
+            (Ok(id), true)
+        })
+        .await;
+        match added {
+            Ok(Some(Ok(id))) => Ok(Entry::Text(id.to_string())),
+            Ok(Some(Err(message))) => Ok(Entry::error("ERR", message)),
+            Ok(None) => Ok(Entry::Nil),
+            Err(reply) => Ok(reply),
+        }
+    }
+}
+
+pub struct XLenCommand {
+    key: String,
+}
+
+#[async_trait]
+impl Command for XLenCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        match load_stream(storage, &self.key).await {
+            Ok(Some((stream, _))) => Ok(Entry::Int(stream.len() as i64)),
+            Ok(None) => Ok(Entry::Int(0)),
+            Err(reply) => Ok(reply),
+        }
+    }
+}
+
+pub struct XRangeCommand {
+    key: String,
+    start: StreamId,
+    end: StreamId,
+    rev: bool,
+    count: Option<usize>,
+}
+
+#[async_trait]
+impl Command for XRangeCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let stream = match load_stream(storage, &self.key).await {
+            Ok(found) => found.map(|(stream, _)| stream).unwrap_or_default(),
+            Err(reply) => return Ok(reply),
+        };
+
+        let count = self.count.unwrap_or(usize::MAX);
+        let range = stream.range(self.start, self.end);
+        let reply = if self.rev {
+            entries_reply(range.rev().take(count))
+        } else {
+            entries_reply(range.take(count))
+        };
+        Ok(reply)
+    }
+}
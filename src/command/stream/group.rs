@@ -0,0 +1,886 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::{
+    client::ClientState,
+    command::{
+        parse_arg, parse_int_arg, parse_rest, wrong_type, BlockingCommand, Command, CommandError,
+    },
+    notify::{NotifyFlags, KEYSPACE_EVENTS},
+    resp::Entry,
+    storage::{Claim, ClaimOptions, Data, Expiry, Fields, Storage, Stream, StreamId, Value},
+};
+
+use super::{
+    entries_reply, entry_reply, load_stream, now_ms, parse_range_end, parse_range_start,
+    update_stream,
+};
+
+const NO_KEY: &str = "The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.";
+
+pub fn parse(cmd: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let cmd_kind: Box<dyn Command> = match cmd {
+        "XGROUP" => Box::new(parse_xgroup(args)?),
+
+        "XREADGROUP" => Box::new(parse_xreadgroup(args)?),
+
+        "XACK" => {
+            let ids = parse_rest(args, 3)
+                .iter()
+                .map(|id| StreamId::parse(id, 0).ok_or(CommandError))
+                .collect::<Result<Vec<_>, _>>()?;
+            if ids.is_empty() {
+                return Err(CommandError);
+            }
+            Box::new(XAckCommand {
+                key: parse_arg(args, 1)?,
+                group: parse_arg(args, 2)?,
+                ids,
+            })
+        }
+
+        "XPENDING" => Box::new(parse_xpending(args)?),
+
+        "XCLAIM" => Box::new(parse_xclaim(args)?),
+
+        "XAUTOCLAIM" => Box::new(parse_xautoclaim(args)?),
+
+        _ => return Err(CommandError),
+    };
+
+    Ok(cmd_kind)
+}
+
+/// Parses a group's starting point, `$` meaning the stream's last ID.
+fn parse_start_id(arg: &str) -> Result<Option<StreamId>, CommandError> {
+    match arg {
+        "$" => Ok(None),
+        _ => StreamId::parse(arg, 0).map(Some).ok_or(CommandError),
+    }
+}
+
+/// Accepts a trailing `ENTRIESREAD n` at `at`. The lag it seeds is not
+/// tracked, so the value is only validated.
+fn parse_entries_read(args: &[Entry], at: usize) -> Result<(), CommandError> {
+    match args.len() - at {
+        0 => Ok(()),
+        2 if parse_arg(args, at)?.eq_ignore_ascii_case("ENTRIESREAD") => {
+            parse_int_arg(args, at + 1).map(|_| ())
+        }
+        _ => Err(CommandError),
+    }
+}
+
+fn parse_millis(args: &[Entry], at: usize) -> Result<u64, CommandError> {
+    u64::try_from(parse_int_arg(args, at)?).map_err(|_| CommandError)
+}
+
+fn parse_xgroup(args: &[Entry]) -> Result<XGroupCommand, CommandError> {
+    let subcommand = parse_arg(args, 1)?.to_uppercase();
+    let key = parse_arg(args, 2)?;
+    let group = parse_arg(args, 3)?;
+
+    let action = match subcommand.as_str() {
+        "CREATE" => {
+            let start = parse_start_id(&parse_arg(args, 4)?)?;
+            let mut at = 5;
+            let mk_stream =
+                args.len() > at && parse_arg(args, at)?.eq_ignore_ascii_case("MKSTREAM");
+            if mk_stream {
+                at += 1;
+            }
+            parse_entries_read(args, at)?;
+            GroupAction::Create { start, mk_stream }
+        }
+        "SETID" => {
+            let start = parse_start_id(&parse_arg(args, 4)?)?;
+            parse_entries_read(args, 5)?;
+            GroupAction::SetId(start)
+        }
+        "DESTROY" if args.len() == 4 => GroupAction::Destroy,
+        "CREATECONSUMER" if args.len() == 5 => GroupAction::CreateConsumer(parse_arg(args, 4)?),
+        "DELCONSUMER" if args.len() == 5 => GroupAction::DelConsumer(parse_arg(args, 4)?),
+        _ => return Err(CommandError),
+    };
+
+    Ok(XGroupCommand { key, group, action })
+}
+
+fn parse_xreadgroup(args: &[Entry]) -> Result<XReadGroupCommand, CommandError> {
+    if !parse_arg(args, 1)?.eq_ignore_ascii_case("GROUP") {
+        return Err(CommandError);
+    }
+    let group = parse_arg(args, 2)?;
+    let consumer = parse_arg(args, 3)?;
+
+    let mut count = None;
+    let mut block = None;
+    let mut no_ack = false;
+    let mut at = 4;
+    loop {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            "COUNT" => {
+                let parsed = parse_int_arg(args, at + 1)?;
+                count = (parsed > 0).then_some(parsed as usize);
+                at += 2;
+            }
+            "BLOCK" => {
+                let millis = parse_millis(args, at + 1)?;
+                block = Some((millis > 0).then(|| Duration::from_millis(millis)));
+                at += 2;
+            }
+            "NOACK" => {
+                no_ack = true;
+                at += 1;
+            }
+            "STREAMS" => break,
+            _ => return Err(CommandError),
+        }
+    }
+
+    let rest = parse_rest(args, at + 1);
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        return Err(CommandError);
+    }
+    let (keys, ids) = rest.split_at(rest.len() / 2);
+    let reads = ids
+        .iter()
+        .map(|id| match id.as_str() {
+            ">" => Ok(ReadFrom::New),
+            _ => StreamId::parse(id, 0)
+                .map(ReadFrom::Pending)
+                .ok_or(CommandError),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Reading pending entries always replies right away, so only reads of
+    // new entries wait.
+    let blocking = block.is_some() && reads.iter().all(|read| *read == ReadFrom::New);
+
+    Ok(XReadGroupCommand {
+        group,
+        consumer,
+        count,
+        no_ack,
+        keys: keys.to_vec(),
+        reads,
+        blocking,
+        timeout: block.flatten(),
+    })
+}
+
+fn parse_xpending(args: &[Entry]) -> Result<XPendingCommand, CommandError> {
+    let key = parse_arg(args, 1)?;
+    let group = parse_arg(args, 2)?;
+    if args.len() == 3 {
+        return Ok(XPendingCommand {
+            key,
+            group,
+            range: None,
+        });
+    }
+
+    let mut at = 3;
+    let mut min_idle_ms = 0;
+    if parse_arg(args, at)?.eq_ignore_ascii_case("IDLE") {
+        min_idle_ms = parse_millis(args, at + 1)?;
+        at += 2;
+    }
+    let start = parse_range_start(&parse_arg(args, at)?)?;
+    let end = parse_range_end(&parse_arg(args, at + 1)?)?;
+    let count = usize::try_from(parse_int_arg(args, at + 2)?).unwrap_or(0);
+    let consumer = match args.len() - at {
+        3 => None,
+        4 => Some(parse_arg(args, at + 3)?),
+        _ => return Err(CommandError),
+    };
+
+    Ok(XPendingCommand {
+        key,
+        group,
+        range: Some(PendingRange {
+            min_idle_ms,
+            start,
+            end,
+            count,
+            consumer,
+        }),
+    })
+}
+
+fn parse_xclaim(args: &[Entry]) -> Result<XClaimCommand, CommandError> {
+    let key = parse_arg(args, 1)?;
+    let group = parse_arg(args, 2)?;
+    let consumer = parse_arg(args, 3)?;
+    let min_idle_ms = parse_millis(args, 4)?;
+
+    let mut at = 5;
+    let mut ids = Vec::new();
+    while let Some(id) = parse_arg(args, at)
+        .ok()
+        .and_then(|arg| StreamId::parse(&arg, 0))
+    {
+        ids.push(id);
+        at += 1;
+    }
+    if ids.is_empty() {
+        return Err(CommandError);
+    }
+
+    let mut delivery = Delivery::Now;
+    let mut retry_count = None;
+    let mut force = false;
+    let mut just_id = false;
+    let mut last_id = None;
+    while at < args.len() {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            "IDLE" => {
+                delivery = Delivery::Idle(parse_millis(args, at + 1)?);
+                at += 2;
+            }
+            "TIME" => {
+                delivery = Delivery::At(parse_millis(args, at + 1)?);
+                at += 2;
+            }
+            "RETRYCOUNT" => {
+                retry_count = Some(parse_millis(args, at + 1)?);
+                at += 2;
+            }
+            "LASTID" => {
+                let id = StreamId::parse(&parse_arg(args, at + 1)?, 0).ok_or(CommandError)?;
+                last_id = Some(id);
+                at += 2;
+            }
+            "FORCE" => {
+                force = true;
+                at += 1;
+            }
+            "JUSTID" => {
+                just_id = true;
+                at += 1;
+            }
+            _ => return Err(CommandError),
+        }
+    }
+
+    Ok(XClaimCommand {
+        key,
+        group,
+        consumer,
+        ids,
+        min_idle_ms,
+        delivery,
+        retry_count,
+        force,
+        just_id,
+        last_id,
+    })
+}
+
+fn parse_xautoclaim(args: &[Entry]) -> Result<XAutoClaimCommand, CommandError> {
+    let key = parse_arg(args, 1)?;
+    let group = parse_arg(args, 2)?;
+    let consumer = parse_arg(args, 3)?;
+    let min_idle_ms = parse_millis(args, 4)?;
+    let start = parse_range_start(&parse_arg(args, 5)?)?;
+
+    let mut count = 100;
+    let mut just_id = false;
+    let mut at = 6;
+    while at < args.len() {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            "COUNT" => {
+                count = usize::try_from(parse_int_arg(args, at + 1)?).map_err(|_| CommandError)?;
+                if count == 0 {
+                    return Err(CommandError);
+                }
+                at += 2;
+            }
+            "JUSTID" => {
+                just_id = true;
+                at += 1;
+            }
+            _ => return Err(CommandError),
+        }
+    }
+
+    Ok(XAutoClaimCommand {
+        key,
+        group,
+        consumer,
+        min_idle_ms,
+        start,
+        count,
+        just_id,
+    })
+}
+
+fn no_group_message(key: &str, group: &str) -> String {
+    format!("No such key '{}' or consumer group '{}'", key, group)
+}
+
+fn no_group(key: &str, group: &str) -> Entry {
+    Entry::error("NOGROUP", no_group_message(key, group))
+}
+
+/// Renders pending entries read back by their consumer, with a nil in place
+/// of the fields of entries deleted since delivery.
+fn pending_entries_reply(entries: &[(StreamId, Option<Fields>)]) -> Entry {
+    let rendered = entries
+        .iter()
+        .map(|(id, fields)| match fields {
+            Some(fields) => entry_reply(id, fields),
+            None => Entry::Array(vec![Entry::Text(id.to_string()), Entry::NullArray]),
+        })
+        .collect();
+    Entry::Array(rendered)
+}
+
+fn ids_reply(ids: &[StreamId]) -> Entry {
+    Entry::Array(ids.iter().map(|id| Entry::Text(id.to_string())).collect())
+}
+
+/// Loads the stream at `key` for a group command, turning a missing key or
+/// group into the NOGROUP reply.
+async fn load_group_stream(
+    storage: &dyn Storage,
+    key: &str,
+    group: &str,
+) -> Result<(Stream, Option<Expiry>), Entry> {
+    match load_stream(storage, key).await? {
+        Some((stream, expiry)) if stream.group(group).is_some() => Ok((stream, expiry)),
+        _ => Err(no_group(key, group)),
+    }
+}
+
+/// Changes the stream at `key` for a group command as `update_stream` does,
+/// turning a missing key or group into the NOGROUP reply.
+async fn update_group_stream<T: Send>(
+    storage: &dyn Storage,
+    key: &str,
+    group: &str,
+    f: impl FnOnce(&mut Stream) -> (T, bool) + Send,
+) -> Result<T, Entry> {
+    let updated = update_stream(storage, key, false, |stream| {
+        if stream.group(group).is_none() {
+            return (None, false);
+        }
+        let (result, changed) = f(stream);
+        (Some(result), changed)
+    })
+    .await?;
+    updated.flatten().ok_or_else(|| no_group(key, group))
+}
+
+enum GroupAction {
+    /// Starts delivering after the given ID, `None` meaning the last one.
+    Create {
+        start: Option<StreamId>,
+        mk_stream: bool,
+    },
+    SetId(Option<StreamId>),
+    Destroy,
+    CreateConsumer(String),
+    DelConsumer(String),
+}
+
+pub struct XGroupCommand {
+    key: String,
+    group: String,
+    action: GroupAction,
+}
+
+#[async_trait]
+impl Command for XGroupCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let no_group = || {
+            Entry::error(
+                "NOGROUP",
+                format!(
+                    "No such consumer group '{}' for key name '{}'",
+                    self.group, self.key
+                ),
+            )
+        };
+        let mk_stream = matches!(
+            self.action,
+            GroupAction::Create {
+                mk_stream: true,
+                ..
+            }
+        );
+        let updated = update_stream(storage, &self.key, mk_stream, |stream| match &self.action {
+            GroupAction::Create { start, .. } => {
+                let start = start.unwrap_or(stream.last_id());
+                if !stream.create_group(&self.group, start) {
+                    let reply = Entry::error("BUSYGROUP", "Consumer Group name already exists");
+                    return ((reply, None), false);
+                }
+                ((Entry::ok(), Some("xgroup-create")), true)
+            }
+            GroupAction::SetId(start) => {
+                let start = start.unwrap_or(stream.last_id());
+                match stream.group_mut(&self.group) {
+                    Some(group) => group.set_last_delivered(start),
+                    None => return ((no_group(), None), false),
+                }
+                ((Entry::ok(), Some("xgroup-setid")), true)
+            }
+            GroupAction::Destroy => {
+                if !stream.destroy_group(&self.group) {
+                    return ((Entry::Int(0), None), false);
+                }
+                ((Entry::Int(1), Some("xgroup-destroy")), true)
+            }
+            GroupAction::CreateConsumer(consumer) => match stream.group_mut(&self.group) {
+                Some(group) => {
+                    let created = group.create_consumer(consumer, now_ms());
+                    let event = created.then_some("xgroup-createconsumer");
+                    ((Entry::Int(created as i64), event), created)
+                }
+                None => ((no_group(), None), false),
+            },
+            GroupAction::DelConsumer(consumer) => match stream.group_mut(&self.group) {
+                Some(group) => {
+                    let pending = group.delete_consumer(consumer);
+                    let event = pending.map(|_| "xgroup-delconsumer");
+                    (
+                        (Entry::Int(pending.unwrap_or(0) as i64), event),
+                        pending.is_some(),
+                    )
+                }
+                None => ((no_group(), None), false),
+            },
+        })
+        .await;
+        let (reply, event) = match updated {
+            Ok(Some(updated)) => updated,
+            Ok(None) => return Ok(Entry::error("ERR", NO_KEY)),
+            Err(reply) => return Ok(reply),
+        };
+
+        if let Some(event) = event {
+            KEYSPACE_EVENTS.notify(NotifyFlags::STREAM, event, &self.key);
+        }
+        Ok(reply)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ReadFrom {
+    /// `>`: entries never delivered to the group.
+    New,
+    /// The consumer's pending entries after the given ID.
+    Pending(StreamId),
+}
+
+pub struct XReadGroupCommand {
+    group: String,
+    consumer: String,
+    count: Option<usize>,
+    no_ack: bool,
+    keys: Vec<String>,
+    reads: Vec<ReadFrom>,
+    blocking: bool,
+    timeout: Option<Duration>,
+}
+
+impl XReadGroupCommand {
+    /// Serves the read, `None` meaning no new entries for any key. Every
+    /// stream is read in one step, and only those the read changes count as
+    /// written, as writing wakes other clients blocked on the key.
+    async fn read(&self, storage: &dyn Storage) -> Result<Option<Entry>, Entry> {
+        let keys: Vec<&str> = self.keys.iter().map(String::as_str).collect();
+        storage
+            .update_many_with(&keys, |view| {
+                for key in &self.keys {
+                    match view.get(key).map(|value| &value.value) {
+                        Some(Data::Stream(stream)) if stream.group(&self.group).is_some() => {}
+                        Some(Data::Stream(_)) | None => {
+                            let message = format!(
+                                "{} in XREADGROUP with GROUP option",
+                                no_group_message(key, &self.group)
+                            );
+                            return Err(Entry::error("NOGROUP", message));
+                        }
+                        Some(_) => return Err(wrong_type()),
+                    }
+                }
+
+                let now = now_ms();
+                let mut replies = Vec::new();
+                for (key, read) in self.keys.iter().zip(&self.reads) {
+                    let Some(Value {
+                        value: Data::Stream(stream),
+                        ..
+                    }) = view.get(key)
+                    else {
+                        unreachable!("every key was checked to be a stream");
+                    };
+                    let group = stream.group(&self.group).unwrap();
+                    let known = group.consumers().any(|name| name == self.consumer);
+                    // Empty reads of known consumers leave the stream as it was.
+                    let unchanged = *read == ReadFrom::New
+                        && known
+                        && group.last_delivered().next().is_none_or(|start| {
+                            stream.range(start, StreamId::MAX).next().is_none()
+                        });
+                    if unchanged {
+                        continue;
+                    }
+
+                    let Some(Value {
+                        value: Data::Stream(stream),
+                        ..
+                    }) = view.get_mut(key)
+                    else {
+                        unreachable!("every key was checked to be a stream");
+                    };
+                    let entries = match *read {
+                        ReadFrom::New => {
+                            let entries = stream.read_group(
+                                &self.group,
+                                &self.consumer,
+                                self.count,
+                                self.no_ack,
+                                now,
+                            );
+                            (!entries.is_empty()).then(|| {
+                                entries_reply(entries.iter().map(|(id, fields)| (id, fields)))
+                            })
+                        }
+                        ReadFrom::Pending(after) => {
+                            let entries = stream.read_pending(
+                                &self.group,
+                                &self.consumer,
+                                after,
+                                self.count,
+                                now,
+                            );
+                            Some(pending_entries_reply(&entries))
+                        }
+                    };
+                    if let Some(entries) = entries {
+                        replies.push(Entry::Array(vec![Entry::Text(key.clone()), entries]));
+                    }
+                }
+
+                if replies.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(Entry::Array(replies)))
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl Command for XReadGroupCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        match self.read(storage).await {
+            Ok(reply) => Ok(reply.unwrap_or(Entry::NullArray)),
+            Err(reply) => Ok(reply),
+        }
+    }
+
+    fn as_blocking(&self) -> Option<&dyn BlockingCommand> {
+        self.blocking.then_some(self as &dyn BlockingCommand)
+    }
+}
+
+#[async_trait]
+impl BlockingCommand for XReadGroupCommand {
+    fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    async fn try_execute(&self, storage: &dyn Storage) -> Result<Option<Entry>, CommandError> {
+        match self.read(storage).await {
+            Ok(reply) => Ok(reply),
+            Err(reply) => Ok(Some(reply)),
+        }
+    }
+}
+
+pub struct XAckCommand {
+    key: String,
+    group: String,
+    ids: Vec<StreamId>,
+}
+
+#[async_trait]
+impl Command for XAckCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let acked = update_group_stream(storage, &self.key, &self.group, |stream| {
+            let group = stream.group_mut(&self.group).unwrap();
+            let acked = self.ids.iter().filter(|id| group.ack(**id)).count();
+            (acked, acked > 0)
+        })
+        .await;
+        Ok(Entry::Int(acked.unwrap_or(0) as i64))
+    }
+}
+
+struct PendingRange {
+    min_idle_ms: u64,
+    start: StreamId,
+    end: StreamId,
+    count: usize,
+    consumer: Option<String>,
+}
+
+pub struct XPendingCommand {
+    key: String,
+    group: String,
+    /// Lists individual entries when given, otherwise replies with a summary.
+    range: Option<PendingRange>,
+}
+
+#[async_trait]
+impl Command for XPendingCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let stream = match load_group_stream(storage, &self.key, &self.group).await {
+            Ok((stream, _)) => stream,
+            Err(reply) => return Ok(reply),
+        };
+        let pending = stream.group(&self.group).unwrap().pending();
+
+        let Some(range) = &self.range else {
+            // Replies as [count, min id, max id, [[consumer, count], ...]].
+            let (Some(min), Some(max)) = (pending.keys().next(), pending.keys().next_back()) else {
+                return Ok(Entry::Array(vec![
+                    Entry::Int(0),
+                    Entry::Nil,
+                    Entry::Nil,
+                    Entry::NullArray,
+                ]));
+            };
+            let mut per_consumer: Vec<(&str, usize)> = Vec::new();
+            for entry in pending.values() {
+                match per_consumer
+                    .iter_mut()
+                    .find(|(name, _)| *name == entry.consumer)
+                {
+                    Some((_, count)) => *count += 1,
+                    None => per_consumer.push((&entry.consumer, 1)),
+                }
+            }
+            per_consumer.sort();
+            let consumers = per_consumer
+                .iter()
+                .map(|(name, count)| {
+                    Entry::Array(vec![
+                        Entry::Text(name.to_string()),
+                        Entry::Text(count.to_string()),
+                    ])
+                })
+                .collect();
+
+            return Ok(Entry::Array(vec![
+                Entry::Int(pending.len() as i64),
+                Entry::Text(min.to_string()),
+                Entry::Text(max.to_string()),
+                Entry::Array(consumers),
+            ]));
+        };
+
+        if range.start > range.end {
+            return Ok(Entry::Array(vec![]));
+        }
+        let now = now_ms();
+        let entries = pending
+            .range(range.start..=range.end)
+            .filter(|(_, entry)| {
+                range
+                    .consumer
+                    .as_ref()
+                    .is_none_or(|consumer| *consumer == entry.consumer)
+            })
+            .filter(|(_, entry)| now.saturating_sub(entry.delivered_ms) >= range.min_idle_ms)
+            .take(range.count)
+            .map(|(id, entry)| {
+                // Each entry as [id, consumer, idle ms, delivery count].
+                Entry::Array(vec![
+                    Entry::Text(id.to_string()),
+                    Entry::Text(entry.consumer.clone()),
+                    Entry::Int(now.saturating_sub(entry.delivered_ms) as i64),
+                    Entry::Int(entry.delivery_count as i64),
+                ])
+            })
+            .collect();
+        Ok(Entry::Array(entries))
+    }
+}
+
+/// Delivery time recorded for claimed entries.
+enum Delivery {
+    Now,
+    /// `IDLE ms`: as if delivered that long ago.
+    Idle(u64),
+    /// `TIME ms`: at the given Unix time.
+    At(u64),
+}
+
+pub struct XClaimCommand {
+    key: String,
+    group: String,
+    consumer: String,
+    ids: Vec<StreamId>,
+    min_idle_ms: u64,
+    delivery: Delivery,
+    retry_count: Option<u64>,
+    force: bool,
+    just_id: bool,
+    last_id: Option<StreamId>,
+}
+
+#[async_trait]
+impl Command for XClaimCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let now = now_ms();
+        let options = ClaimOptions {
+            min_idle_ms: self.min_idle_ms,
+            force: self.force,
+            delivered_ms: match self.delivery {
+                Delivery::Now => now,
+                Delivery::Idle(idle) => now.saturating_sub(idle),
+                Delivery::At(time) => time,
+            },
+            retry_count: self.retry_count,
+            just_id: self.just_id,
+        };
+
+        let claimed = update_group_stream(storage, &self.key, &self.group, |stream| {
+            if let Some(last_id) = self.last_id {
+                let group = stream.group_mut(&self.group).unwrap();
+                if last_id > group.last_delivered() {
+                    group.set_last_delivered(last_id);
+                }
+            }
+            let claimed: Vec<StreamId> = self
+                .ids
+                .iter()
+                .copied()
+                .filter(|id| {
+                    stream.claim(&self.group, &self.consumer, *id, &options, now) == Claim::Claimed
+                })
+                .collect();
+
+            let reply = if self.just_id {
+                ids_reply(&claimed)
+            } else {
+                entries_reply(
+                    claimed
+                        .iter()
+                        .filter_map(|id| stream.get(*id).map(|fields| (id, fields))),
+                )
+            };
+            (reply, true)
+        })
+        .await;
+        Ok(claimed.unwrap_or_else(|reply| reply))
+    }
+}
+
+pub struct XAutoClaimCommand {
+    key: String,
+    group: String,
+    consumer: String,
+    min_idle_ms: u64,
+    start: StreamId,
+    count: usize,
+    just_id: bool,
+}
+
+#[async_trait]
+impl Command for XAutoClaimCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let now = now_ms();
+        let options = ClaimOptions {
+            min_idle_ms: self.min_idle_ms,
+            force: false,
+            delivered_ms: now,
+            retry_count: None,
+            just_id: self.just_id,
+        };
+
+        let claimed = update_group_stream(storage, &self.key, &self.group, |stream| {
+            // Like Redis, scan at most ten entries per requested claim so a long
+            // list of busy entries cannot stall the server.
+            let mut attempts = self.count.saturating_mul(10);
+            let candidates: Vec<StreamId> = stream
+                .group(&self.group)
+                .unwrap()
+                .pending()
+                .range(self.start..)
+                .map(|(id, _)| *id)
+                .take(attempts.saturating_add(1))
+                .collect();
+
+            let mut cursor = StreamId::MIN;
+            let mut claimed = Vec::new();
+            let mut deleted = Vec::new();
+            for id in candidates {
+                if attempts == 0 || claimed.len() == self.count {
+                    cursor = id;
+                    break;
+                }
+                attempts -= 1;
+                match stream.claim(&self.group, &self.consumer, id, &options, now) {
+                    Claim::Claimed => claimed.push(id),
+                    Claim::Deleted => deleted.push(id),
+                    Claim::Skipped => {}
+                }
+            }
+
+            let entries = if self.just_id {
+                ids_reply(&claimed)
+            } else {
+                entries_reply(
+                    claimed
+                        .iter()
+                        .filter_map(|id| stream.get(*id).map(|fields| (id, fields))),
+                )
+            };
+
+            // Replies as [next cursor, claimed entries, deleted IDs].
+            let reply = Entry::Array(vec![
+                Entry::Text(cursor.to_string()),
+                entries,
+                ids_reply(&deleted),
+            ]);
+            (reply, true)
+        })
+        .await;
+        Ok(claimed.unwrap_or_else(|reply| reply))
+    }
+}
@@ -0,0 +1,774 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::{
+    client::ClientState,
+    notify::{NotifyFlags, KEYSPACE_EVENTS},
+    resp::Entry,
+    storage::{Data, Expiry, LexBound, ScoreBound, SortedSet, Storage, Value},
+};
+
+use super::{
+    parse_arg, parse_int_arg, parse_timeout_arg, wrong_type, BlockingCommand, Command, CommandError,
+};
+
+pub fn parse(cmd: &str, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let key = parse_arg(args, 1)?;
+
+    let cmd_kind: Box<dyn Command> = match cmd {
+        "ZADD" => parse_zadd(key, args)?,
+
+        "ZSCORE" => Box::new(ZScoreCommand {
+            key,
+            member: parse_arg(args, 2)?,
+        }),
+
+        "ZCARD" => Box::new(ZCardCommand { key }),
+
+        "ZREM" => {
+            let members: Vec<String> = (2..args.len())
+                .map(|at| parse_arg(args, at))
+                .collect::<Result<_, _>>()?;
+            if members.is_empty() {
+                return Err(CommandError);
+            }
+            Box::new(ZRemCommand { key, members })
+        }
+
+        "ZINCRBY" => Box::new(ZAddCommand {
+            key,
+            pairs: vec![(parse_score(&parse_arg(args, 2)?)?, parse_arg(args, 3)?)],
+            incr: true,
+            ..Default::default()
+        }),
+
+        "ZRANK" | "ZREVRANK" => {
+            let with_score = match args.get(3) {
+                Some(_) if parse_arg(args, 3)?.eq_ignore_ascii_case("WITHSCORE") => true,
+                Some(_) => return Err(CommandError),
+                None => false,
+            };
+            Box::new(ZRankCommand {
+                key,
+                member: parse_arg(args, 2)?,
+                rev: cmd == "ZREVRANK",
+                with_score,
+            })
+        }
+
+        "ZPOPMIN" | "ZPOPMAX" => {
+            let count = match args.get(2) {
+                Some(_) => {
+                    Some(usize::try_from(parse_int_arg(args, 2)?).map_err(|_| CommandError)?)
+                }
+                None => None,
+            };
+            Box::new(ZPopCommand {
+                key,
+                max: cmd == "ZPOPMAX",
+                count,
+            })
+        }
+
+        "BZPOPMIN" | "BZPOPMAX" => {
+            let keys: Vec<String> = (1..args.len() - 1)
+                .map(|at| parse_arg(args, at))
+                .collect::<Result<_, _>>()?;
+            if keys.is_empty() {
+                return Err(CommandError);
+            }
+            Box::new(BZPopCommand {
+                keys,
+                max: cmd == "BZPOPMAX",
+                timeout: parse_timeout_arg(args, args.len() - 1)?,
+            })
+        }
+
+        "ZMPOP" => Box::new(parse_zmpop(args, 1)?),
+
+        "BZMPOP" => {
+            let mut command = parse_zmpop(args, 2)?;
+            command.blocking = true;
+            command.timeout = parse_timeout_arg(args, 1)?;
+            Box::new(command)
+        }
+
+        "ZRANGE" => Box::new(parse_range(key, args, RangeKind::Rank, true)?),
+
+        "ZRANGEBYSCORE" => Box::new(parse_range(key, args, RangeKind::Score, false)?),
+
+        "ZRANGEBYLEX" => Box::new(parse_range(key, args, RangeKind::Lex, false)?),
+
+        _ => return Err(CommandError),
+    };
+
+    Ok(cmd_kind)
+}
+
+fn parse_zadd(key: String, args: &[Entry]) -> Result<Box<dyn Command>, CommandError> {
+    let mut command = ZAddCommand {
+        key,
+        ..Default::default()
+    };
+
+    let mut at = 2;
+    while at < args.len() {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            "NX" => command.nx = true,
+            "XX" => command.xx = true,
+            "GT" => command.gt = true,
+            "LT" => command.lt = true,
+            "CH" => command.ch = true,
+            "INCR" => command.incr = true,
+            _ => break,
+        }
+        at += 1;
+    }
+
+    let pairs = &args[at..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Err(CommandError);
+    }
+    for pair in (at..args.len()).step_by(2) {
+        let score = parse_score(&parse_arg(args, pair)?)?;
+        command.pairs.push((score, parse_arg(args, pair + 1)?));
+    }
+
+    let incompatible = (command.nx && (command.xx || command.gt || command.lt))
+        || (command.gt && command.lt)
+        || (command.incr && command.pairs.len() > 1);
+    if incompatible {
+        return Err(CommandError);
+    }
+
+    Ok(Box::new(command))
+}
+
+/// Parses `numkeys key [key ...] MIN|MAX [COUNT count]` starting at `at`.
+fn parse_zmpop(args: &[Entry], at: usize) -> Result<ZMPopCommand, CommandError> {
+    let numkeys = usize::try_from(parse_int_arg(args, at)?).map_err(|_| CommandError)?;
+    if numkeys == 0 {
+        return Err(CommandError);
+    }
+    let keys: Vec<String> = (at + 1..at + 1 + numkeys)
+        .map(|at| parse_arg(args, at))
+        .collect::<Result<_, _>>()?;
+
+    let mut at = at + 1 + numkeys;
+    let max = match parse_arg(args, at)?.to_uppercase().as_str() {
+        "MIN" => false,
+        "MAX" => true,
+        _ => return Err(CommandError),
+    };
+    at += 1;
+
+    let mut count = 1;
+    if at < args.len() {
+        if !parse_arg(args, at)?.eq_ignore_ascii_case("COUNT") || at + 2 != args.len() {
+            return Err(CommandError);
+        }
+        count = usize::try_from(parse_int_arg(args, at + 1)?).map_err(|_| CommandError)?;
+        if count == 0 {
+            return Err(CommandError);
+        }
+    }
+
+    Ok(ZMPopCommand {
+        keys,
+        max,
+        count,
+        blocking: false,
+        timeout: None,
+    })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RangeKind {
+    Rank,
+    Score,
+    Lex,
+}
+
+/// Parses the `key start stop [options]` tail shared by the range commands.
+/// Only ZRANGE itself accepts the BYSCORE, BYLEX and REV modifiers.
+fn parse_range(
+    key: String,
+    args: &[Entry],
+    mut kind: RangeKind,
+    modifiers: bool,
+) -> Result<ZRangeCommand, CommandError> {
+    let mut rev = false;
+    let mut with_scores = false;
+    let mut limit = None;
+
+    let mut at = 4;
+    while at < args.len() {
+        match parse_arg(args, at)?.to_uppercase().as_str() {
+            "BYSCORE" if modifiers => kind = RangeKind::Score,
+            "BYLEX" if modifiers => kind = RangeKind::Lex,
+            "REV" if modifiers => rev = true,
+            "WITHSCORES" => with_scores = true,
+            "LIMIT" => {
+                limit = Some((parse_int_arg(args, at + 1)?, parse_int_arg(args, at + 2)?));
+                at += 2;
+            }
+            _ => return Err(CommandError),
+        }
+        at += 1;
+    }
+
+    if (kind == RangeKind::Rank && limit.is_some()) || (kind == RangeKind::Lex && with_scores) {
+        return Err(CommandError);
+    }
+
+    // Reversed score and lex ranges take their bounds as `max min`.
+    let (min, max) = if rev && kind != RangeKind::Rank {
+        (parse_arg(args, 3)?, parse_arg(args, 2)?)
+    } else {
+        (parse_arg(args, 2)?, parse_arg(args, 3)?)
+    };
+    let by = match kind {
+        RangeKind::Rank => RangeBy::Rank(
+            min.parse().map_err(|_| CommandError)?,
+            max.parse().map_err(|_| CommandError)?,
+        ),
+        RangeKind::Score => RangeBy::Score(parse_score_bound(&min)?, parse_score_bound(&max)?),
+        RangeKind::Lex => RangeBy::Lex(parse_lex_bound(&min)?, parse_lex_bound(&max)?),
+    };
+
+    Ok(ZRangeCommand {
+        key,
+        by,
+        rev,
+        with_scores,
+        limit,
+    })
+}
+
+fn parse_score_bound(arg: &str) -> Result<ScoreBound, CommandError> {
+    match arg.strip_prefix('(') {
+        Some(value) => Ok(ScoreBound {
+            value: parse_score(value)?,
+            exclusive: true,
+        }),
+        None => Ok(ScoreBound {
+            value: parse_score(arg)?,
+            exclusive: false,
+        }),
+    }
+}
+
+fn parse_lex_bound(arg: &str) -> Result<LexBound, CommandError> {
+    match arg {
+        "-" => Ok(LexBound::Min),
+        "+" => Ok(LexBound::Max),
+        _ => match (arg.strip_prefix('['), arg.strip_prefix('(')) {
+            (Some(member), _) => Ok(LexBound::Inclusive(member.to_string())),
+            (_, Some(member)) => Ok(LexBound::Exclusive(member.to_string())),
+            _ => Err(CommandError),
+        },
+    }
+}
+
+/// Parses a score argument, accepting `inf`/`+inf`/`-inf` but never NaN.
+fn parse_score(arg: &str) -> Result<f64, CommandError> {
+    match arg.parse::<f64>() {
+        Ok(score) if !score.is_nan() => Ok(score),
+        _ => Err(CommandError),
+    }
+}
+
+/// Renders a score the way Redis prints doubles in replies.
+fn format_score(score: f64) -> String {
+    score.to_string()
+}
+
+/// Loads the sorted set stored at `key` together with its expiry. Missing keys
+/// yield `Ok(None)` and keys of another type yield the wrong_type reply as `Err`.
+pub(super) async fn load_zset(
+    storage: &dyn Storage,
+    key: &str,
+) -> Result<Option<(SortedSet, Option<Expiry>)>, Entry> {
+    match storage.get(key).await {
+        Some(Value {
+            value: Data::SortedSet(zset),
+            expiry,
+        }) => Ok(Some((zset, expiry))),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+/// Changes the sorted set stored at `key` in place with `f`, in one step no
+/// other client can come between, handing it an empty set if the key is
+/// missing. The key goes once the set is empty. Keys of another type are left
+/// alone and yield the wrong_type reply as `Err`.
+pub(super) async fn update_zset<T: Send>(
+    storage: &dyn Storage,
+    key: &str,
+    f: impl FnOnce(&mut SortedSet) -> T + Send,
+) -> Result<T, Entry> {
+    let (result, deleted) = storage
+        .update_with(key, |value| {
+            let existed = value.is_some();
+            let stored = value.get_or_insert_with(|| Value {
+                value: Data::SortedSet(SortedSet::default()),
+                expiry: None,
+            });
+            let Data::SortedSet(zset) = &mut stored.value else {
+                return (Err(wrong_type()), false);
+            };
+            let result = f(zset);
+            let emptied = zset.is_empty();
+            if emptied {
+                *value = None;
+            }
+            (Ok(result), existed && emptied)
+        })
+        .await;
+    if deleted {
+        KEYSPACE_EVENTS.notify(NotifyFlags::GENERIC, "del", key);
+    }
+    result
+}
+
+/// Renders `members` as a flat array, interleaving scores when requested.
+fn members_reply<'a>(
+    members: impl IntoIterator<Item = (&'a str, f64)>,
+    with_scores: bool,
+) -> Entry {
+    let mut entries = Vec::new();
+    for (member, score) in members {
+        entries.push(Entry::Text(member.to_string()));
+        if with_scores {
+            entries.push(Entry::Text(format_score(score)));
+        }
+    }
+    Entry::Array(entries)
+}
+
+#[derive(Default)]
+pub struct ZAddCommand {
+    key: String,
+    pairs: Vec<(f64, String)>,
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+    ch: bool,
+    incr: bool,
+}
+
+#[async_trait]
+impl Command for ZAddCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let updated = update_zset(storage, &self.key, |zset| {
+            let mut added = 0;
+            let mut changed = 0;
+            let mut incremented = None;
+
+            for (score, member) in self.pairs.iter() {
+                let score = match zset.score(member) {
+                    None if self.xx => continue,
+                    None => {
+                        added += 1;
+                        *score
+                    }
+                    Some(_) if self.nx => continue,
+                    Some(current) => {
+                        let score = if self.incr { current + score } else { *score };
+                        // Only INCR, with its single pair, can get here, so
+                        // nothing has been changed yet.
+                        if score.is_nan() {
+                            return None;
+                        }
+                        if (self.gt && score <= current) || (self.lt && score >= current) {
+                            continue;
+                        }
+                        if score != current {
+                            changed += 1;
+                        }
+                        score
+                    }
+                };
+                zset.insert(member.clone(), score);
+                incremented = Some(score);
+            }
+            Some((added, changed, incremented))
+        })
+        .await;
+        let (added, changed, incremented) = match updated {
+            Ok(Some(counts)) => counts,
+            Ok(None) => {
+                return Ok(Entry::error("ERR", "resulting score is not a number (NaN)"));
+            }
+            Err(reply) => return Ok(reply),
+        };
+
+        if self.incr && incremented.is_some() {
+            KEYSPACE_EVENTS.notify(NotifyFlags::ZSET, "zincr", &self.key);
+        } else if added + changed > 0 {
+            KEYSPACE_EVENTS.notify(NotifyFlags::ZSET, "zadd", &self.key);
+        }
+
+        if self.incr {
+            return match incremented {
+                Some(score) => Ok(Entry::Double(score)),
+                None => Ok(Entry::Nil),
+            };
+        }
+        let reply = if self.ch { added + changed } else { added };
+        Ok(Entry::Int(reply))
+    }
+}
+
+pub struct ZScoreCommand {
+    key: String,
+    member: String,
+}
+
+#[async_trait]
+impl Command for ZScoreCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let score = match load_zset(storage, &self.key).await {
+            Ok(found) => found.and_then(|(zset, _)| zset.score(&self.member)),
+            Err(reply) => return Ok(reply),
+        };
+        match score {
+            Some(score) => Ok(Entry::Double(score)),
+            None => Ok(Entry::Nil),
+        }
+    }
+}
+
+pub struct ZCardCommand {
+    key: String,
+}
+
+#[async_trait]
+impl Command for ZCardCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        match load_zset(storage, &self.key).await {
+            Ok(Some((zset, _))) => Ok(Entry::Int(zset.len() as i64)),
+            Ok(None) => Ok(Entry::Int(0)),
+            Err(reply) => Ok(reply),
+        }
+    }
+}
+
+pub struct ZRemCommand {
+    key: String,
+    members: Vec<String>,
+}
+
+#[async_trait]
+impl Command for ZRemCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        // The zrem event comes before the del one of the key going.
+        let removed = update_zset(storage, &self.key, |zset| {
+            let removed = self
+                .members
+                .iter()
+                .filter(|member| zset.remove(member).is_some())
+                .count();
+            if removed > 0 {
+                KEYSPACE_EVENTS.notify(NotifyFlags::ZSET, "zrem", &self.key);
+            }
+            removed
+        })
+        .await;
+        match removed {
+            Ok(removed) => Ok(Entry::Int(removed as i64)),
+            Err(reply) => Ok(reply),
+        }
+    }
+}
+
+pub struct ZRankCommand {
+    key: String,
+    member: String,
+    rev: bool,
+    with_score: bool,
+}
+
+#[async_trait]
+impl Command for ZRankCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let zset = match load_zset(storage, &self.key).await {
+            Ok(found) => found.map(|(zset, _)| zset).unwrap_or_default(),
+            Err(reply) => return Ok(reply),
+        };
+
+        let (rank, score) = match (zset.rank(&self.member), zset.score(&self.member)) {
+            (Some(rank), Some(score)) => (rank, score),
+            _ => return Ok(Entry::Nil),
+        };
+        let rank = if self.rev {
+            zset.len() - 1 - rank
+        } else {
+            rank
+        };
+
+        if self.with_score {
+            let msg = Entry::Array(vec![Entry::Int(rank as i64), Entry::Double(score)]);
+            return Ok(msg);
+        }
+        Ok(Entry::Int(rank as i64))
+    }
+}
+
+enum RangeBy {
+    Rank(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+pub struct ZRangeCommand {
+    key: String,
+    by: RangeBy,
+    rev: bool,
+    with_scores: bool,
+    limit: Option<(i64, i64)>,
+}
+
+#[async_trait]
+impl Command for ZRangeCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let zset = match load_zset(storage, &self.key).await {
+            Ok(found) => found.map(|(zset, _)| zset).unwrap_or_default(),
+            Err(reply) => return Ok(reply),
+        };
+
+        let mut members = match &self.by {
+            RangeBy::Rank(start, stop) => {
+                let len = zset.len() as i64;
+                let start = if *start < 0 {
+                    (len + start).max(0)
+                } else {
+                    *start
+                };
+                let stop = if *stop < 0 {
+                    len + stop
+                } else {
+                    (*stop).min(len - 1)
+                };
+                if start > stop || start >= len {
+                    return Ok(Entry::Array(vec![]));
+                }
+
+                let skip = start as usize;
+                let take = (stop - start + 1) as usize;
+                if self.rev {
+                    zset.iter().rev().skip(skip).take(take).collect()
+                } else {
+                    zset.iter().skip(skip).take(take).collect()
+                }
+            }
+            RangeBy::Score(min, max) => zset.range_by_score(*min, *max),
+            RangeBy::Lex(min, max) => zset.range_by_lex(min, max),
+        };
+
+        if self.rev && !matches!(self.by, RangeBy::Rank(..)) {
+            members.reverse();
+        }
+
+        // A negative offset yields nothing while a negative count means "all".
+        if let Some((offset, count)) = self.limit {
+            if offset < 0 {
+                return Ok(Entry::Array(vec![]));
+            }
+            let take = usize::try_from(count).unwrap_or(usize::MAX);
+            members = members
+                .into_iter()
+                .skip(offset as usize)
+                .take(take)
+                .collect();
+        }
+
+        Ok(members_reply(members, self.with_scores))
+    }
+}
+
+/// Pops up to `count` members from the first non-empty sorted set in `keys`,
+/// returning that key alongside the popped members.
+async fn pop_first_non_empty(
+    storage: &dyn Storage,
+    keys: &[String],
+    max: bool,
+    count: usize,
+) -> Result<Option<(String, Vec<(String, f64)>)>, Entry> {
+    for key in keys {
+        let popped = update_zset(storage, key, |zset| {
+            if zset.is_empty() {
+                return None;
+            }
+            let popped = (0..count)
+                .map_while(|_| if max { zset.pop_max() } else { zset.pop_min() })
+                .collect();
+            let event = if max { "zpopmax" } else { "zpopmin" };
+            KEYSPACE_EVENTS.notify(NotifyFlags::ZSET, event, key);
+            Some(popped)
+        })
+        .await?;
+        if let Some(popped) = popped {
+            return Ok(Some((key.clone(), popped)));
+        }
+    }
+    Ok(None)
+}
+
+pub struct ZPopCommand {
+    key: String,
+    max: bool,
+    count: Option<usize>,
+}
+
+#[async_trait]
+impl Command for ZPopCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let keys = [self.key.clone()];
+        let count = self.count.unwrap_or(1);
+        match pop_first_non_empty(storage, &keys, self.max, count).await {
+            Ok(Some((_, popped))) => Ok(members_reply(
+                popped
+                    .iter()
+                    .map(|(member, score)| (member.as_str(), *score)),
+                true,
+            )),
+            Ok(None) => Ok(Entry::Array(vec![])),
+            Err(reply) => Ok(reply),
+        }
+    }
+}
+
+pub struct BZPopCommand {
+    keys: Vec<String>,
+    max: bool,
+    timeout: Option<Duration>,
+}
+
+#[async_trait]
+impl Command for BZPopCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let reply = self.try_execute(storage).await?;
+        Ok(reply.unwrap_or(Entry::NullArray))
+    }
+
+    fn as_blocking(&self) -> Option<&dyn BlockingCommand> {
+        Some(self)
+    }
+}
+
+#[async_trait]
+impl BlockingCommand for BZPopCommand {
+    fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    async fn try_execute(&self, storage: &dyn Storage) -> Result<Option<Entry>, CommandError> {
+        match pop_first_non_empty(storage, &self.keys, self.max, 1).await {
+            Ok(Some((key, mut popped))) => {
+                let (member, score) = popped.remove(0);
+                Ok(Some(Entry::Array(vec![
+                    Entry::Text(key),
+                    Entry::Text(member),
+                    Entry::Double(score),
+                ])))
+            }
+            Ok(None) => Ok(None),
+            Err(reply) => Ok(Some(reply)),
+        }
+    }
+}
+
+pub struct ZMPopCommand {
+    keys: Vec<String>,
+    max: bool,
+    count: usize,
+    blocking: bool,
+    timeout: Option<Duration>,
+}
+
+#[async_trait]
+impl Command for ZMPopCommand {
+    async fn execute(
+        &self,
+        storage: &dyn Storage,
+        _: &mut ClientState,
+    ) -> Result<Entry, CommandError> {
+        let reply = self.try_execute(storage).await?;
+        Ok(reply.unwrap_or(Entry::NullArray))
+    }
+
+    fn as_blocking(&self) -> Option<&dyn BlockingCommand> {
+        self.blocking.then_some(self as &dyn BlockingCommand)
+    }
+}
+
+#[async_trait]
+impl BlockingCommand for ZMPopCommand {
+    fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    async fn try_execute(&self, storage: &dyn Storage) -> Result<Option<Entry>, CommandError> {
+        match pop_first_non_empty(storage, &self.keys, self.max, self.count).await {
+            Ok(Some((key, popped))) => {
+                // Replies as [key, [[member, score], ...]].
+                let pairs = popped
+                    .into_iter()
+                    .map(|(member, score)| {
+                        Entry::Array(vec![Entry::Text(member), Entry::Double(score)])
+                    })
+                    .collect();
+                Ok(Some(Entry::Array(vec![
+                    Entry::Text(key),
+                    Entry::Array(pairs),
+                ])))
+            }
+            Ok(None) => Ok(None),
+            Err(reply) => Ok(Some(reply)),
+        }
+    }
+}
@@ -1,52 +1,340 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use socket2::SockRef;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::{timeout, timeout_at, Instant},
 };
+use tokio_rustls::server::TlsStream;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    codec::{CodecError, RespCodec},
+    resp::{Entry, Limits, Protocol},
+    stats::STATS,
+};
+
+/// Bytes requested from the socket per read.
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Capacity past which an emptied read buffer is given back, so one large
+/// request does not pin its memory for the life of the connection.
+const MAX_IDLE_BUFFER: usize = 64 * 1024;
+
+/// Reply bytes queued before they are written out even though more
+/// pipelined requests are waiting.
+const WRITE_CHUNK: usize = 64 * 1024;
+
+/// Most bytes a client may buffer without completing a request unless
+/// configured otherwise, Redis's default `client-query-buffer-limit`.
+pub const DEFAULT_QUERY_BUFFER_LIMIT: usize = 1024 * 1024 * 1024;
+
+/// Source of client IDs, unique for the life of the process.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
 
 #[derive(Debug, Clone)]
 pub struct ConnectionError;
 
-pub struct Connection {
-    reader: BufReader<OwnedReadHalf>,
-    writer: OwnedWriteHalf,
+/// How many reply bytes may wait for a client that does not read them, as
+/// Redis's `client-output-buffer-limit`. A zero disables that bound.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OutputLimit {
+    /// Clients are dropped as soon as this many bytes are pending.
+    pub hard: usize,
+    /// Clients are dropped once more than this many bytes stayed pending
+    /// for `soft_time`.
+    pub soft: usize,
+    pub soft_time: Duration,
 }
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Connection {
-        let (reader, writer) = stream.into_split();
-        let reader = BufReader::new(reader);
-        Connection { reader, writer }
+/// Output limits per class of client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputLimits {
+    pub normal: OutputLimit,
+    /// Subscribers, which are sent messages whether they read them or not.
+    pub pubsub: OutputLimit,
+}
+
+impl Default for OutputLimits {
+    /// Redis's defaults: unlimited for normal clients, 32MB or 8MB for a
+    /// minute for subscribers.
+    fn default() -> Self {
+        OutputLimits {
+            normal: OutputLimit::default(),
+            pubsub: OutputLimit {
+                hard: 32 * 1024 * 1024,
+                soft: 8 * 1024 * 1024,
+                soft_time: Duration::from_secs(60),
+            },
+        }
     }
+}
 
-    pub async fn read_command(&mut self) -> Result<Option<String>, ConnectionError> {
-        let mut buffer = vec![0u8; 112]; // TODO: change size
-        let n = self
-            .reader
-            .read(&mut buffer)
-            .await
+/// A client connection over a plain TCP or a TLS stream. Requests are read
+/// and replies written in turn, so the stream is never used both ways at once.
+pub struct Connection<S = TcpStream> {
+    stream: S,
+    buffer: BytesMut,
+    /// Replies queue up here while pipelined requests are served and are
+    /// written out in one go once the client has to be waited for.
+    write_buffer: BytesMut,
+    request_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    id: u64,
+    /// Frames requests and encodes replies in the protocol set with HELLO.
+    codec: RespCodec,
+    output_limit: OutputLimit,
+    query_buffer_limit: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    /// `request_timeout` bounds how long a request may take to arrive once
+    /// its first byte has been received, `idle_timeout` how long a client may
+    /// go without sending anything at all, `None` meaning forever.
+    pub fn new(
+        stream: S,
+        request_timeout: Option<Duration>,
+        idle_timeout: Option<Duration>,
+    ) -> Connection<S> {
+        Connection {
+            stream,
+            buffer: BytesMut::new(),
+            write_buffer: BytesMut::new(),
+            request_timeout,
+            idle_timeout,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            codec: RespCodec::default(),
+            output_limit: OutputLimit::default(),
+            query_buffer_limit: DEFAULT_QUERY_BUFFER_LIMIT,
+        }
+    }
+
+    /// Rejects requests declaring bulk strings or arrays over `limits`.
+    pub fn with_limits(mut self, limits: Limits) -> Connection<S> {
+        self.codec = self.codec.with_limits(limits);
+        self
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.codec.set_protocol(protocol);
+    }
+
+    /// Lets the client go without sending anything for `idle_timeout`,
+    /// `None` meaning forever.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Bounds the replies left pending for this client from now on.
+    pub fn set_output_limit(&mut self, output_limit: OutputLimit) {
+        self.output_limit = output_limit;
+    }
+
+    /// Bounds the bytes of requests the client sent that aren't served
+    /// yet from now on, like Redis's `client-query-buffer-limit`.
+    pub fn set_query_buffer_limit(&mut self, query_buffer_limit: usize) {
+        self.query_buffer_limit = query_buffer_limit;
+    }
+
+    /// Reads until a complete request is buffered and returns it decoded.
+    /// Clients idle past the idle timeout, trickling a request past the
+    /// deadline or buffering more than the query buffer limit are rejected
+    /// with an error so the caller drops them, as are clients sending
+    /// malformed requests, once told why.
+    ///
+    /// Queued replies are flushed before waiting on the client, so requests
+    /// already buffered are answered with a single write.
+    pub async fn read_command(&mut self) -> Result<Option<Vec<Entry>>, ConnectionError> {
+        let request_timeout = self.request_timeout;
+        let idle_timeout = self.idle_timeout;
+        let mut deadline = None;
+
+        loop {
+            match self.codec.decode(&mut self.buffer) {
+                // Empty requests are skipped, as Redis does.
+                Ok(Some(request)) if request.is_empty() => continue,
+                Ok(Some(request)) => return Ok(Some(request)),
+                Ok(None) => {}
+                Err(CodecError::Protocol(err)) => {
+                    self.send_entry(&Entry::error("ERR", err.to_string()))
+                        .await?;
+                    self.flush().await?;
+                    return Err(ConnectionError);
+                }
+                Err(CodecError::Io(_)) => return Err(ConnectionError),
+            }
+            if self.buffer.len() + self.codec.partial_len() > self.query_buffer_limit {
+                STATS
+                    .query_buffer_limit_disconnections
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(ConnectionError);
+            }
+
+            if !self.write_buffer.is_empty() {
+                self.flush().await?;
+            }
+
+            let idle = self.buffer.is_empty() && self.codec.partial_len() == 0;
+            if idle && self.buffer.capacity() > MAX_IDLE_BUFFER {
+                self.buffer = BytesMut::new();
+            }
+            self.buffer.reserve(READ_CHUNK);
+            let read = self.stream.read_buf(&mut self.buffer);
+            let n = match (idle, idle_timeout, request_timeout) {
+                (true, None, _) | (false, _, None) => read.await,
+                (true, Some(idle_timeout), _) => timeout(idle_timeout, read)
+                    .await
+                    .map_err(|_| ConnectionError)?,
+                (false, _, Some(request_timeout)) => {
+                    let deadline =
+                        *deadline.get_or_insert_with(|| Instant::now() + request_timeout);
+                    timeout_at(deadline, read).await.map_err(|_| {
+                        STATS
+                            .slow_read_disconnections
+                            .fetch_add(1, Ordering::Relaxed);
+                        ConnectionError
+                    })?
+                }
+            }
             .map_err(|_| ConnectionError)?;
 
-        if n == 0 {
-            return Ok(None);
+            if n == 0 {
+                return Ok(None);
+            }
+            STATS
+                .total_net_input_bytes
+                .fetch_add(n as u64, Ordering::Relaxed);
         }
-        let msg = String::from_utf8_lossy(&buffer);
+    }
+
+    /// Queues a reply, writing it out only once enough replies piled up;
+    /// `read_command` and `flush` write out the rest. Clients with more
+    /// pending bytes than the hard output limit are dropped instead.
+    pub async fn send_entry(&mut self, entry: &Entry) -> Result<(), ConnectionError> {
+        self.codec
+            .encode(entry, &mut self.write_buffer)
+            .map_err(|_| ConnectionError)?;
+        self.queued().await
+    }
+
+    /// Queues `bytes` already encoded, such as the snapshot and writes sent
+    /// to replicas, like a reply.
+    pub async fn send_raw(&mut self, bytes: &[u8]) -> Result<(), ConnectionError> {
+        self.write_buffer.extend_from_slice(bytes);
+        self.queued().await
+    }
 
-        if msg.is_empty() {
-            return Ok(None);
+    /// Writes out what was queued once there is enough of it, or drops the
+    /// client if there is more than its hard output limit allows.
+    async fn queued(&mut self) -> Result<(), ConnectionError> {
+        let hard = self.output_limit.hard;
+        if hard > 0 && self.write_buffer.len() > hard {
+            return Err(output_limit_exceeded());
+        }
+        if self.write_buffer.len() >= WRITE_CHUNK {
+            self.flush().await?;
         }
-        Ok(Some(msg.to_string()))
+        Ok(())
     }
 
-    pub async fn send_response(&mut self, content: &str) -> Result<(), ConnectionError> {
-        println!("response being sent: {:?}", content);
-        self.writer
-            .write_all(content.as_bytes())
+    /// Writes out every queued reply. A client leaving more bytes than the
+    /// soft output limit unread for too long is dropped instead.
+    pub async fn flush(&mut self) -> Result<(), ConnectionError> {
+        let OutputLimit {
+            soft, soft_time, ..
+        } = self.output_limit;
+        let deadline = Instant::now() + soft_time;
+        while soft > 0 && self.write_buffer.len() > soft {
+            let written = timeout_at(deadline, self.stream.write_buf(&mut self.write_buffer))
+                .await
+                .map_err(|_| output_limit_exceeded())?
+                .map_err(|_| ConnectionError)?;
+            if written == 0 {
+                return Err(ConnectionError);
+            }
+        }
+
+        self.stream
+            .write_all_buf(&mut self.write_buffer)
             .await
             .map_err(|_| ConnectionError)?;
-        self.writer.flush().await.map_err(|_| ConnectionError)?;
+        self.stream.flush().await.map_err(|_| ConnectionError)?;
         Ok(())
     }
 }
+
+/// A stream clients are served over, for the TCP options changed while
+/// they are.
+pub trait Socket {
+    /// The TCP socket underneath, if there is one.
+    fn socket(&self) -> Option<SockRef<'_>>;
+}
+
+impl Socket for TcpStream {
+    fn socket(&self) -> Option<SockRef<'_>> {
+        Some(SockRef::from(self))
+    }
+}
+
+impl Socket for TlsStream<TcpStream> {
+    fn socket(&self) -> Option<SockRef<'_>> {
+        Some(SockRef::from(self.get_ref().0))
+    }
+}
+
+impl<S: Socket> Connection<S> {
+    /// Has what the client sends next acknowledged right away rather than
+    /// with a delay. The kernel goes back to delaying acknowledgements on
+    /// its own, so this is done again for every request. Only Linux has
+    /// it; elsewhere this does nothing.
+    pub fn quickack(&self) {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(socket) = self.stream.socket() {
+            let _ = socket.set_tcp_quickack(true);
+        }
+    }
+}
+
+fn output_limit_exceeded() -> ConnectionError {
+    STATS
+        .output_buffer_limit_disconnections
+        .fetch_add(1, Ordering::Relaxed);
+    ConnectionError
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn should_drop_clients_leaving_replies_unread() {
+        let (_client, server) = tokio::io::duplex(1024);
+        let mut connection = Connection::new(server, Some(Duration::from_secs(1)), None);
+        connection.set_output_limit(OutputLimit {
+            hard: 0,
+            soft: 4096,
+            soft_time: Duration::from_millis(100),
+        });
+
+        // Under the soft limit, writes may wait on the client for good.
+        let pending = Entry::Text("x".repeat(2048));
+        connection.send_entry(&pending).await.unwrap();
+        assert!(timeout(Duration::from_millis(300), connection.flush())
+            .await
+            .is_err());
+
+        let started = Instant::now();
+        let reply = Entry::Text("x".repeat(WRITE_CHUNK));
+        assert!(connection.send_entry(&reply).await.is_err());
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,415 @@
+//! Libraries of Lua functions loaded with FUNCTION LOAD and called by name
+//! with FCALL. A library is its code, starting with a `#!lua name=<name>`
+//! line, which registers its functions with `redis.register_function` when
+//! run. Only the code is kept: it is run again in a fresh interpreter each
+//! time one of its functions is called, as scripts are. Unlike the script
+//! cache, libraries are part of the dataset, saved and replicated with it.
+
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use mlua::{Lua, Table, Value};
+use tokio::sync::mpsc;
+
+use crate::{
+    resp::Entry,
+    scripting::{self, Call},
+};
+
+/// What functions may be flagged with.
+const FLAGS: &[&str] = &[
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+/// A library as loaded, with the functions its code registers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Library {
+    pub name: String,
+    pub code: String,
+    pub functions: BTreeMap<String, Function>,
+}
+
+/// What a function was registered with besides its callback.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Function {
+    pub description: Option<String>,
+    pub flags: Vec<String>,
+}
+
+impl Function {
+    /// Whether the function may only read, which FCALL_RO requires.
+    pub fn no_writes(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "no-writes")
+    }
+}
+
+impl Library {
+    /// Runs `code` to learn the functions it registers, failing with the
+    /// error replied if it is no library.
+    pub fn load(code: &str) -> Result<Library, String> {
+        let name = parse_metadata(code)?;
+        let lua = Lua::new();
+        let registered = register(&lua, None, code).map_err(|err| match err {
+            Loading::Compile(err) => format!("Error compiling function: {}", err),
+            Loading::Run(err) => format!("Error registering functions: {}", err),
+        })?;
+        if registered.is_empty() {
+            return Err("No functions registered".to_string());
+        }
+        Ok(Library {
+            name,
+            code: code.to_string(),
+            functions: registered,
+        })
+    }
+}
+
+/// The name the first line of `code` gives its library.
+fn parse_metadata(code: &str) -> Result<String, String> {
+    let Some(shebang) = code.lines().next().and_then(|line| line.strip_prefix("#!")) else {
+        return Err("Missing library metadata".to_string());
+    };
+    let mut metadata = shebang.split_whitespace();
+    let engine = metadata.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(format!("Engine '{}' not found", engine));
+    }
+    let mut name = None;
+    for field in metadata {
+        match field.split_once('=') {
+            Some(("name", value)) => name = Some(value.to_string()),
+            _ => return Err(format!("Invalid metadata value given: {}", field)),
+        }
+    }
+    let name = name.ok_or("Library name was not given")?;
+    if !is_valid_name(&name) {
+        return Err("Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+    }
+    Ok(name)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+}
+
+/// Why running the code of a library failed.
+enum Loading {
+    Compile(mlua::Error),
+    Run(mlua::Error),
+}
+
+/// Runs `code` in `lua`, set up for its functions to run commands through
+/// `calls` if given, returning the functions it registered. Their callbacks
+/// are kept in the registry under `callbacks`.
+fn register(
+    lua: &Lua,
+    calls: Option<mpsc::UnboundedSender<Call>>,
+    code: &str,
+) -> Result<BTreeMap<String, Function>, Loading> {
+    let setup = || {
+        let redis = scripting::sandbox(lua, calls)?;
+        lua.set_named_registry_value("callbacks", lua.create_table()?)?;
+        let registered = Arc::new(Mutex::new(BTreeMap::new()));
+        let registering = Arc::clone(&registered);
+        redis.raw_set(
+            "register_function",
+            lua.create_function(move |lua, args: mlua::Variadic<Value>| {
+                let (name, callback, function) = registration(&args)?;
+                let mut registered = registering.lock().unwrap();
+                if registered.contains_key(&name) {
+                    return Err(mlua::Error::runtime(
+                        "Function already exists in the library",
+                    ));
+                }
+                let callbacks: Table = lua.named_registry_value("callbacks")?;
+                callbacks.raw_set(name.as_str(), callback)?;
+                registered.insert(name, function);
+                Ok(())
+            })?,
+        )?;
+        Ok(registered)
+    };
+    let registered = setup().map_err(Loading::Run)?;
+    // Lua knows nothing of the metadata line, which is left blank for line
+    // numbers in errors to stay right.
+    let body = &code[code.find('\n').unwrap_or(code.len())..];
+    let library = lua
+        .load(body)
+        .set_name("@user_function")
+        .into_function()
+        .map_err(Loading::Compile)?;
+    library.call::<_, ()>(()).map_err(Loading::Run)?;
+    let registered = registered.lock().unwrap().clone();
+    Ok(registered)
+}
+
+/// What `redis.register_function` was given: a name and a callback, or a
+/// table naming those along with flags and a description.
+fn registration<'lua>(
+    args: &[Value<'lua>],
+) -> mlua::Result<(String, mlua::Function<'lua>, Function)> {
+    let (name, callback, function) = match args {
+        [Value::String(name), Value::Function(callback)] => (
+            name.to_str()?.to_string(),
+            callback.clone(),
+            Function::default(),
+        ),
+        [Value::Table(table)] => {
+            let name: String = table.get("function_name").map_err(|_| {
+                mlua::Error::runtime(
+                    "function_name argument given to redis.register_function must be a string",
+                )
+            })?;
+            let callback: mlua::Function = table.get("callback").map_err(|_| {
+                mlua::Error::runtime(
+                    "callback argument given to redis.register_function must be a function",
+                )
+            })?;
+            let description: Option<String> = table.get("description")?;
+            let flags: Option<Vec<String>> = table.get("flags")?;
+            let flags = flags.unwrap_or_default();
+            if let Some(unknown) = flags.iter().find(|flag| !FLAGS.contains(&flag.as_str())) {
+                return Err(mlua::Error::runtime(format!(
+                    "unknown flag given: {}",
+                    unknown
+                )));
+            }
+            let function = Function { description, flags };
+            (name, callback, function)
+        }
+        _ => {
+            return Err(mlua::Error::runtime(
+                "wrong arguments given to redis.register_function",
+            ))
+        }
+    };
+    if !is_valid_name(&name) {
+        return Err(mlua::Error::runtime("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long"));
+    }
+    Ok((name, callback, function))
+}
+
+/// Calls `function` of the library with `code` with `keys` and `args`,
+/// sending the commands it runs to `calls`, and returns what it replied.
+/// Blocks until the function is done.
+pub fn call(
+    code: &str,
+    function: &str,
+    keys: Vec<Bytes>,
+    args: Vec<Bytes>,
+    calls: mpsc::UnboundedSender<Call>,
+) -> Entry {
+    let lua = Lua::new();
+    let failed = |err: mlua::Error| Entry::error("ERR", format!("Error loading library: {}", err));
+    if let Err(Loading::Compile(err) | Loading::Run(err)) = register(&lua, Some(calls), code) {
+        return failed(err);
+    }
+    let callback = lua
+        .named_registry_value::<Table>("callbacks")
+        .and_then(|callbacks| callbacks.raw_get::<_, mlua::Function>(function));
+    let (callback, keys, args) = match (
+        callback,
+        scripting::strings(&lua, &keys),
+        scripting::strings(&lua, &args),
+    ) {
+        (Ok(callback), Ok(keys), Ok(args)) => (callback, keys, args),
+        (Err(err), ..) | (_, Err(err), _) | (.., Err(err)) => return failed(err),
+    };
+    scripting::finish(&lua, callback, (keys, args), |error| {
+        format!("{} script: {}", error, function)
+    })
+}
+
+/// Every library loaded, by name.
+pub static FUNCTIONS: Functions = Functions {
+    libraries: Mutex::new(BTreeMap::new()),
+};
+
+pub struct Functions {
+    libraries: Mutex<BTreeMap<String, Library>>,
+}
+
+/// How FUNCTION RESTORE treats the libraries loaded already.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestorePolicy {
+    /// Fails if any library restored is loaded already.
+    Append,
+    /// Replaces libraries of the same name.
+    Replace,
+    /// Deletes every library first.
+    Flush,
+}
+
+impl Functions {
+    /// Loads the library with `code`, replacing one of the same name only
+    /// with `replace`, and returns its name.
+    pub fn load(&self, code: &str, replace: bool) -> Result<String, String> {
+        let library = Library::load(code)?;
+        let name = library.name.clone();
+        add(&mut self.libraries.lock().unwrap(), library, replace)?;
+        Ok(name)
+    }
+
+    /// Adds all of `libraries` as `policy` says, or none of them if one
+    /// can't be.
+    pub fn restore(&self, libraries: Vec<Library>, policy: RestorePolicy) -> Result<(), String> {
+        let mut loaded = self.libraries.lock().unwrap();
+        let mut restored = match policy {
+            RestorePolicy::Flush => BTreeMap::new(),
+            _ => loaded.clone(),
+        };
+        for library in libraries {
+            add(&mut restored, library, policy == RestorePolicy::Replace)?;
+        }
+        *loaded = restored;
+        Ok(())
+    }
+
+    /// Deletes the library `name`, returning whether there was one.
+    pub fn delete(&self, name: &str) -> bool {
+        self.libraries.lock().unwrap().remove(name).is_some()
+    }
+
+    /// Deletes every library, returning them for the caller to drop.
+    pub fn flush(&self) -> BTreeMap<String, Library> {
+        mem::take(&mut *self.libraries.lock().unwrap())
+    }
+
+    /// Every library, in order of name.
+    pub fn libraries(&self) -> Vec<Library> {
+        self.libraries.lock().unwrap().values().cloned().collect()
+    }
+
+    /// The code of the library registering `function`, and how it did.
+    pub fn find(&self, function: &str) -> Option<(String, Function)> {
+        let libraries = self.libraries.lock().unwrap();
+        libraries.values().find_map(|library| {
+            let registered = library.functions.get(function)?;
+            Some((library.code.clone(), registered.clone()))
+        })
+    }
+}
+
+/// Adds `library` to `libraries`, failing if one of the same name is there
+/// and not to be replaced, or if another registers the same function.
+fn add(
+    libraries: &mut BTreeMap<String, Library>,
+    library: Library,
+    replace: bool,
+) -> Result<(), String> {
+    if !replace && libraries.contains_key(&library.name) {
+        return Err(format!("Library '{}' already exists", library.name));
+    }
+    let others = libraries
+        .values()
+        .filter(|other| other.name != library.name);
+    for other in others {
+        if let Some(taken) = library
+            .functions
+            .keys()
+            .find(|function| other.functions.contains_key(*function))
+        {
+            return Err(format!("Function {} already exists", taken));
+        }
+    }
+    libraries.insert(library.name.clone(), library);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_learn_the_functions_a_library_registers() {
+        let library = Library::load(
+            "#!lua name=mylib\n\
+             redis.register_function('first', function(keys, args) return 1 end)\n\
+             redis.register_function{\n\
+                 function_name = 'second',\n\
+                 callback = function(keys, args) return 2 end,\n\
+                 flags = {'no-writes'},\n\
+                 description = 'the second',\n\
+             }",
+        )
+        .unwrap();
+        assert_eq!(library.name, "mylib");
+        assert_eq!(
+            library.functions.keys().collect::<Vec<_>>(),
+            ["first", "second"]
+        );
+        assert!(!library.functions["first"].no_writes());
+        let second = &library.functions["second"];
+        assert!(second.no_writes());
+        assert_eq!(second.description.as_deref(), Some("the second"));
+
+        let load = |code: &str| Library::load(code).unwrap_err();
+        assert_eq!(load("return 1"), "Missing library metadata");
+        assert_eq!(load("#!js name=lib\n"), "Engine 'js' not found");
+        assert_eq!(
+            load("#!lua name=lib\nlocal a = 1"),
+            "No functions registered"
+        );
+        assert!(load("#!lua name=lib\nreturn (").starts_with("Error compiling function"));
+        assert!(load("#!lua name=lib\nredis.call('PING')").starts_with("Error registering"));
+    }
+
+    #[test]
+    fn should_keep_function_names_unique_across_libraries() {
+        let mut libraries = BTreeMap::new();
+        let library = |name: &str, function: &str| {
+            let code = format!(
+                "#!lua name={}\nredis.register_function('{}', function() end)",
+                name, function
+            );
+            Library::load(&code).unwrap()
+        };
+        add(&mut libraries, library("a", "f"), false).unwrap();
+        assert_eq!(
+            add(&mut libraries, library("a", "g"), false),
+            Err("Library 'a' already exists".to_string())
+        );
+        add(&mut libraries, library("a", "g"), true).unwrap();
+        assert_eq!(
+            add(&mut libraries, library("b", "g"), false),
+            Err("Function g already exists".to_string())
+        );
+        add(&mut libraries, library("b", "f"), false).unwrap();
+        assert_eq!(libraries.len(), 2);
+    }
+
+    #[test]
+    fn should_call_functions_with_keys_and_arguments() {
+        let code = "#!lua name=calls\n\
+                    redis.register_function('echo', function(keys, args)\n\
+                        return redis.call('ECHO', keys[1] .. args[1])\n\
+                    end)";
+        let (calls, mut requests) = mpsc::unbounded_channel::<Call>();
+        let called = std::thread::spawn(move || {
+            let keys = vec![Bytes::from("key")];
+            let args = vec![Bytes::from("arg")];
+            call(code, "echo", keys, args, calls)
+        });
+        let (request, _, reply) = requests.blocking_recv().unwrap();
+        assert_eq!(
+            request,
+            [
+                Entry::Text("ECHO".to_string()),
+                Entry::Text("keyarg".to_string())
+            ]
+        );
+        reply.send(Entry::Text("keyarg".to_string())).unwrap();
+        assert_eq!(called.join().unwrap(), Entry::Text("keyarg".to_string()));
+    }
+}
@@ -0,0 +1,108 @@
+/// Redis-style glob matching supporting `*`, `?`, `[...]` classes (with `^`
+/// negation and `a-z` ranges) and `\` escapes.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&pattern, &text)
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position to resume from when the last `*` has to swallow one more char.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_class(&pattern[p..], text[t]),
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(2),
+            Some(&c) => (c == text[t]).then_some(1),
+            None => None,
+        };
+
+        match step {
+            Some(width) => {
+                p += width;
+                t += 1;
+            }
+            None => match backtrack {
+                Some((star, from)) => {
+                    p = star + 1;
+                    t = from + 1;
+                    backtrack = Some((star, from + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `c` against the class starting at `pattern[0] == '['`, returning
+/// the width of the class in the pattern on success.
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let mut i = 1;
+    let negate = pattern.get(i) == Some(&'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != ']' {
+        if pattern[i] == '\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            let (lo, hi) = if pattern[i] <= pattern[i + 2] {
+                (pattern[i], pattern[i + 2])
+            } else {
+                (pattern[i + 2], pattern[i])
+            };
+            matched |= lo <= c && c <= hi;
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+
+    // An unterminated class is treated as matching up to the end of the pattern.
+    let width = (i + 1).min(pattern.len());
+    (matched != negate).then_some(width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_match_wildcards() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(glob_match("h*llo", "heeeello"));
+        assert!(glob_match("f*", "foo"));
+        assert!(!glob_match("f*", "bar"));
+        assert!(!glob_match("h?llo", "hllo"));
+    }
+
+    #[test]
+    fn should_match_classes() {
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-c]llo", "hbllo"));
+    }
+
+    #[test]
+    fn should_match_escapes() {
+        assert!(glob_match("h\\*llo", "h*llo"));
+        assert!(!glob_match("h\\*llo", "hello"));
+    }
+}
@@ -1,6 +1,26 @@
-mod command;
+pub mod access_log;
+pub mod aof;
+mod blocking;
+pub mod client;
+pub mod cluster;
+pub mod codec;
+pub mod command;
 mod connection;
+mod crc16;
+mod crc64;
+pub mod functions;
+mod glob;
+mod locks;
+pub mod notify;
+pub mod pubsub;
 mod rdb;
+pub mod replication;
 pub mod resp;
+pub mod scripting;
 pub mod server;
+mod sha1;
+pub mod stats;
 pub mod storage;
+pub mod tap;
+pub mod tls;
+pub mod tracking;
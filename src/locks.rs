@@ -0,0 +1,156 @@
+//! Locks commands take on the keys they name while they run and their writes
+//! are logged. Storage makes each change whole on its own; these make the
+//! append only file and replicas get the writes to a key in the order they
+//! were made, and let commands on several keys see them all at once, while
+//! commands on other keys run alongside. Keys deleted for having expired
+//! or to free memory are held the same way, so their deletion isn't sent
+//! ahead of a write to them still being logged.
+
+use std::{collections::hash_map::RandomState, hash::BuildHasher, sync::LazyLock};
+
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// How many locks keys are spread over. Keys sharing one only wait on each
+/// other.
+const STRIPES: usize = 1024;
+
+/// The locks on every key of the dataset.
+pub static KEY_LOCKS: LazyLock<KeyLocks> = LazyLock::new(KeyLocks::new);
+
+#[derive(Debug)]
+pub struct KeyLocks {
+    stripes: Vec<RwLock<()>>,
+    hasher: RandomState,
+}
+
+/// The locks a command holds until dropped.
+pub struct KeyGuard<'a> {
+    _read: Vec<RwLockReadGuard<'a, ()>>,
+    _write: Vec<RwLockWriteGuard<'a, ()>>,
+}
+
+impl KeyLocks {
+    pub fn new() -> Self {
+        Self {
+            stripes: (0..STRIPES).map(|_| RwLock::new(())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Locks `keys` shared, for commands only reading them.
+    pub async fn read(&self, keys: &[impl AsRef<str>]) -> KeyGuard<'_> {
+        let mut read = Vec::new();
+        for at in self.stripes_of(keys) {
+            read.push(self.stripes[at].read().await);
+        }
+        KeyGuard {
+            _read: read,
+            _write: Vec::new(),
+        }
+    }
+
+    /// Locks `keys` exclusively, for commands writing them.
+    pub async fn write(&self, keys: &[impl AsRef<str>]) -> KeyGuard<'_> {
+        let mut write = Vec::new();
+        for at in self.stripes_of(keys) {
+            write.push(self.stripes[at].write().await);
+        }
+        KeyGuard {
+            _read: Vec::new(),
+            _write: write,
+        }
+    }
+
+    /// Locks `keys` exclusively if nothing holds them, for what may run
+    /// while already holding keys of its own.
+    pub fn try_write(&self, keys: &[impl AsRef<str>]) -> Option<KeyGuard<'_>> {
+        let mut write = Vec::new();
+        for at in self.stripes_of(keys) {
+            write.push(self.stripes[at].try_write().ok()?);
+        }
+        Some(KeyGuard {
+            _read: Vec::new(),
+            _write: write,
+        })
+    }
+
+    /// Locks every key shared, for what reads keys it can't name upfront.
+    pub async fn read_all(&self) -> KeyGuard<'_> {
+        let mut read = Vec::with_capacity(STRIPES);
+        for stripe in &self.stripes {
+            read.push(stripe.read().await);
+        }
+        KeyGuard {
+            _read: read,
+            _write: Vec::new(),
+        }
+    }
+
+    /// Locks every key exclusively, for writes to keys not named upfront
+    /// and for copies of the whole dataset.
+    pub async fn write_all(&self) -> KeyGuard<'_> {
+        let mut write = Vec::with_capacity(STRIPES);
+        for stripe in &self.stripes {
+            write.push(stripe.write().await);
+        }
+        KeyGuard {
+            _read: Vec::new(),
+            _write: write,
+        }
+    }
+
+    /// The stripes holding `keys`, in the one order every command takes
+    /// them in so no two wait on each other.
+    fn stripes_of(&self, keys: &[impl AsRef<str>]) -> Vec<usize> {
+        let mut at: Vec<usize> = keys
+            .iter()
+            .map(|key| self.hasher.hash_one(key.as_ref()) as usize % STRIPES)
+            .collect();
+        at.sort_unstable();
+        at.dedup();
+        at
+    }
+}
+
+impl Default for KeyLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn should_only_hold_off_commands_on_the_same_keys() {
+        let locks = KeyLocks::new();
+        let written = locks.write(&["key", "other"]).await;
+
+        assert!(timeout(Duration::from_millis(10), locks.read(&["key"]))
+            .await
+            .is_err());
+        assert!(timeout(Duration::from_millis(10), locks.write_all())
+            .await
+            .is_err());
+        // A key sharing a stripe with them would wait too.
+        let free = (0..)
+            .map(|n| format!("key{}", n))
+            .find(|key| {
+                let at = locks.stripes_of(&[key.as_str()]);
+                at != locks.stripes_of(&["key"]) && at != locks.stripes_of(&["other"])
+            })
+            .unwrap();
+        drop(locks.write(&[&free]).await);
+        assert!(locks.try_write(&["key"]).is_none());
+        assert!(locks.try_write(&[&free]).is_some());
+
+        drop(written);
+        let _shared = locks.read(&["key"]).await;
+        let _also_shared = locks.read_all().await;
+    }
+}
@@ -1,52 +1,626 @@
+use std::env;
 use std::error::Error;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 
-use clap::Parser;
-use redis_starter_rust::server::Server;
-use redis_starter_rust::storage::{InMemoryStorage, RdbStorage, Storage};
-use tokio::sync::Mutex;
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, CommandFactory, Parser};
+use redis_starter_rust::access_log::{self, AccessLog};
+use redis_starter_rust::aof::{self, Aof, AppendFsync};
+use redis_starter_rust::cluster::{self, CLUSTER};
+use redis_starter_rust::notify::{NotifyFlags, KEYSPACE_EVENTS};
+use redis_starter_rust::replication::REPLICATION;
+use redis_starter_rust::resp::Limits;
+use redis_starter_rust::server::config::{self, parse_memory};
+use redis_starter_rust::server::{OutputLimit, OutputLimits, SaveRule, Server};
+#[cfg(feature = "disk")]
+use redis_starter_rust::storage::DiskStorage;
+use redis_starter_rust::storage::{
+    DashMapStorage, InMemoryStorage, Maxmemory, MaxmemoryPolicy, RdbStorage, Storage, COMPACT,
+    DEFAULT_SHARDS, RDB_CHECKSUM,
+};
+use redis_starter_rust::{tap, tls};
 use tokio::task;
 use tokio::time::sleep;
 
 #[derive(Parser, Debug)]
 // #[command(version, about, long_about = None)]
 struct Args {
+    /// Config file in redis.conf's syntax, whose directives are taken like
+    /// the options of the same names. Options given here win over it, and
+    /// CONFIG REWRITE writes to it
+    #[arg(value_name = "CONFIG")]
+    config_file: Option<PathBuf>,
+    /// Same as the positional config file
+    #[arg(long, conflicts_with = "config_file")]
+    config: Option<PathBuf>,
     #[arg(long)]
     dir: Option<String>,
     #[arg(long)]
     dbfilename: Option<String>,
+    /// Keep keys in an embedded on-disk store at this path instead of in
+    /// memory, for datasets larger than RAM
+    #[cfg(feature = "disk")]
+    #[arg(long, conflicts_with_all = ["dir", "dbfilename"])]
+    disk: Option<String>,
+    /// Keep keys in a DashMap rather than RwLock-guarded shards, which
+    /// only supports the noeviction maxmemory policy
+    #[arg(long, conflicts_with_all = ["dir", "dbfilename", "shards"])]
+    dashmap: bool,
+    /// Save rules as `<seconds> <changes>` pairs: the dataset is saved in
+    /// the background once that many changes are that old. Empty to never
+    /// save on its own
+    #[arg(long, value_parser = parse_save_rules, default_value = "3600 1 300 100 60 10000")]
+    save: SaveRules,
+    /// End RDB files with a checksum, and check it when loading them
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_parser = BoolishValueParser::new())]
+    rdbchecksum: bool,
+    /// Log every write to an append only file, which is loaded on startup
+    /// instead of the RDB file once there is one
+    #[arg(long, default_value_t = false, action = clap::ArgAction::Set, value_parser = BoolishValueParser::new())]
+    appendonly: bool,
+    /// Name of the append only file, in `dir`
+    #[arg(long, default_value = "appendonly.aof")]
+    appendfilename: String,
+    /// When the append only file is synced to disk: always, everysec or no
+    #[arg(long, value_parser = parse_appendfsync, default_value = "everysec")]
+    appendfsync: AppendFsync,
+    /// Rewrite the append only file starting with the dataset as an RDB
+    /// payload rather than as commands
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_parser = BoolishValueParser::new())]
+    aof_use_rdb_preamble: bool,
+    /// Write consecutive APPENDs to a key to the append only file as one,
+    /// once something else is written or the file is next flushed
+    #[arg(long, default_value_t = false, action = clap::ArgAction::Set, value_parser = BoolishValueParser::new())]
+    aof_coalesce_appends: bool,
     #[arg(long, default_value_t = 6379)]
-    port: u32,
+    port: u16,
+    /// Independently locked shards the keyspace is split into
+    #[arg(long, default_value_t = DEFAULT_SHARDS)]
+    shards: usize,
+    /// Most memory keys may take before some are evicted, sizes taking
+    /// suffixes like 100mb, 0 meaning no limit
+    #[arg(long, value_parser = parse_memory, default_value = "0")]
+    maxmemory: usize,
+    /// Which keys go once over maxmemory: noeviction, allkeys-lru,
+    /// allkeys-lfu, allkeys-random, volatile-lru, volatile-lfu,
+    /// volatile-random or volatile-ttl
+    #[arg(long, value_parser = parse_maxmemory_policy, default_value = "noeviction")]
+    maxmemory_policy: MaxmemoryPolicy,
+    /// Keyspace notifications to send, as flags like `KEA`: K and E pick
+    /// the keyspace and keyevent channels, the others classes of events
+    #[arg(long, value_parser = parse_notify_flags, default_value = "")]
+    notify_keyspace_events: NotifyFlags,
+    /// Most members of a set of integers kept as a compact intset
+    #[arg(long, default_value_t = 512)]
+    set_max_intset_entries: usize,
+    /// Most members of a set kept as a compact listpack
+    #[arg(long, default_value_t = 128)]
+    set_max_listpack_entries: usize,
+    /// Longest member of a set kept as a compact listpack, in bytes
+    #[arg(long, default_value_t = 64)]
+    set_max_listpack_value: usize,
+    /// Most members of a sorted set kept as a compact listpack
+    #[arg(long, default_value_t = 128)]
+    zset_max_listpack_entries: usize,
+    /// Longest member of a sorted set kept as a compact listpack, in bytes
+    #[arg(long, default_value_t = 64)]
+    zset_max_listpack_value: usize,
+    /// Addresses to listen on, IPv4 or IPv6, those starting with `-` only
+    /// if the host has them
+    #[arg(long, num_args = 1.., default_value = "127.0.0.1", value_parser = parse_bind_addr)]
+    bind: Vec<BindAddr>,
+    /// Record sampled key accesses to this file for offline cache simulation
+    #[arg(long)]
+    access_log: Option<String>,
+    /// Fraction of keys (0.0 to 1.0) whose accesses are recorded
+    #[arg(long, default_value_t = 1.0)]
+    access_log_sample: f64,
+    /// Size in bytes at which the access log is rotated
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    access_log_max_size: u64,
+    /// Print a recorded access log as CSV and exit
+    #[arg(long)]
+    export_access_log: Option<String>,
+    /// Follow the replication stream of the master at host:port, printing
+    /// each propagated command, instead of serving clients
+    #[arg(long)]
+    tap: Option<String>,
+    /// Replicate the master at `<host> <port>`, starting over from its
+    /// dataset
+    #[arg(long, value_parser = parse_replicaof)]
+    replicaof: Option<(String, u16)>,
+    /// Refuse writes from clients other than the master when a replica
+    #[arg(long, alias = "slave-read-only", default_value_t = true, action = clap::ArgAction::Set, value_parser = BoolishValueParser::new())]
+    replica_read_only: bool,
+    /// Serve as a node of a cluster, which keys are spread over
+    #[arg(long, default_value_t = false, action = clap::ArgAction::Set, value_parser = BoolishValueParser::new())]
+    cluster_enabled: bool,
+    /// Hash slots this node serves as a cluster node, such as `0-5460 6000`
+    #[arg(long, value_parser = parse_slot_ranges, default_value = "")]
+    cluster_slots: SlotRanges,
+    /// Another node of the cluster as `<id> <ip>:<port>[@<bus port>] [slot
+    /// ranges]`, whose slots requests are redirected to. Nodes whose bus
+    /// port isn't given are never talked to
+    #[arg(long, value_parser = parse_cluster_node)]
+    cluster_node: Vec<ClusterNode>,
+    /// Port other cluster nodes talk to this one on, 0 meaning 10000 above
+    /// the port clients connect to
+    #[arg(long, default_value_t = 0)]
+    cluster_port: u16,
+    /// Milliseconds another cluster node may leave a ping unanswered before
+    /// it is taken for failing
+    #[arg(long, default_value_t = 15000)]
+    cluster_node_timeout: u64,
+    /// Seconds a client gets to finish sending a request once it started it,
+    /// 0 meaning as long as it needs
+    #[arg(long, default_value_t = 0)]
+    request_timeout: u64,
+    /// Seconds after which idle clients are disconnected, 0 meaning never
+    #[arg(long, default_value_t = 0)]
+    timeout: u64,
+    /// Most clients connected at once; further ones are refused
+    #[arg(long, default_value_t = 10000)]
+    maxclients: usize,
+    /// Send replies without waiting to coalesce them (TCP_NODELAY)
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_parser = BoolishValueParser::new())]
+    tcp_nodelay: bool,
+    /// Seconds of silence after which clients are probed with TCP
+    /// keepalives, 0 meaning never
+    #[arg(long, default_value_t = 300)]
+    tcp_keepalive: u64,
+    /// Output buffer limit of a class of clients as `<normal|pubsub> <hard>
+    /// <soft> <soft seconds>`, sizes taking suffixes like 32mb
+    #[arg(long, value_parser = parse_output_limits)]
+    client_output_buffer_limit: Vec<ClientOutputLimits>,
+    /// Most bytes of requests not served yet a client may send before it is
+    /// dropped, at least 1mb
+    #[arg(long, value_parser = config::parse_query_buffer_limit, default_value = "1gb")]
+    client_query_buffer_limit: usize,
+    /// Password clients must authenticate with using AUTH before anything
+    /// else is served
+    #[arg(long)]
+    requirepass: Option<String>,
+    /// Longest bulk string accepted in a request, in bytes
+    #[arg(long, default_value_t = Limits::default().max_bulk_len)]
+    proto_max_bulk_len: usize,
+    /// Accept connections from one task per core, each with its own
+    /// SO_REUSEPORT socket
+    #[arg(long)]
+    reuseport: bool,
+    /// Also accept TLS connections on this port
+    #[arg(long, requires_all = ["tls_cert_file", "tls_key_file"])]
+    tls_port: Option<u16>,
+    /// PEM certificate chain presented to TLS clients
+    #[arg(long)]
+    tls_cert_file: Option<String>,
+    /// PEM private key of the TLS certificate
+    #[arg(long)]
+    tls_key_file: Option<String>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+/// An address to listen on, which like on Redis may be `optional`: left out
+/// if the host has no such address, such as `-::1` without IPv6.
+#[derive(Clone, Debug)]
+struct BindAddr {
+    ip: IpAddr,
+    optional: bool,
+}
+
+fn parse_bind_addr(addr: &str) -> Result<BindAddr, String> {
+    let (addr, optional) = match addr.strip_prefix('-') {
+        Some(addr) => (addr, true),
+        None => (addr, false),
+    };
+    let ip = addr
+        .parse()
+        .map_err(|_| format!("invalid address {}", addr))?;
+    Ok(BindAddr { ip, optional })
+}
+
+/// The addresses of `bind` there are, all but optional ones the host can't
+/// listen on.
+fn bind_ips(bind: &[BindAddr]) -> Vec<IpAddr> {
+    bind.iter()
+        .filter(|addr| {
+            let available = !addr.optional || std::net::TcpListener::bind((addr.ip, 0)).is_ok();
+            if !available {
+                eprintln!("skipping bind address {}, which is not available", addr.ip);
+            }
+            available
+        })
+        .map(|addr| addr.ip)
+        .collect()
+}
+
+/// Every bind address with `port`, bracketing IPv6 ones.
+fn listen_addrs(bind: &[IpAddr], port: u16) -> Vec<String> {
+    bind.iter()
+        .map(|ip| SocketAddr::new(*ip, port).to_string())
+        .collect()
+}
+
+fn parse_maxmemory_policy(name: &str) -> Result<MaxmemoryPolicy, String> {
+    MaxmemoryPolicy::parse(name).ok_or_else(|| format!("unknown policy {}", name))
+}
+
+fn parse_appendfsync(name: &str) -> Result<AppendFsync, String> {
+    AppendFsync::parse(name).ok_or_else(|| format!("unknown appendfsync {}", name))
+}
+
+fn parse_notify_flags(flags: &str) -> Result<NotifyFlags, String> {
+    NotifyFlags::parse(flags).ok_or_else(|| format!("invalid flags {}", flags))
+}
+
+fn parse_replicaof(master: &str) -> Result<(String, u16), String> {
+    let [host, port] = master.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err("expected <host> <port>".to_string());
+    };
+    let port = port.parse().map_err(|_| format!("invalid port {}", port))?;
+    Ok((host.to_string(), port))
+}
+
+/// Wrapped so clap takes the rules as one value rather than a list of them.
+#[derive(Clone, Debug)]
+struct SaveRules(Vec<SaveRule>);
+
+fn parse_save_rules(rules: &str) -> Result<SaveRules, String> {
+    config::parse_save_rules(rules).map(SaveRules)
+}
+
+/// Wrapped so clap takes the limits of several classes as one value.
+#[derive(Clone, Debug)]
+struct ClientOutputLimits(Vec<(String, OutputLimit)>);
+
+fn parse_output_limits(limits: &str) -> Result<ClientOutputLimits, String> {
+    config::parse_output_limits(limits).map(ClientOutputLimits)
+}
+
+/// Wrapped so clap takes the ranges as one value rather than a list of them.
+#[derive(Clone, Debug)]
+struct SlotRanges(Vec<(u16, u16)>);
+
+fn parse_slot_ranges(ranges: &str) -> Result<SlotRanges, String> {
+    cluster::parse_slot_ranges(ranges).map(SlotRanges)
+}
+
+#[derive(Clone, Debug)]
+struct ClusterNode {
+    id: String,
+    ip: String,
+    port: u16,
+    bus_port: u16,
+    slots: Vec<(u16, u16)>,
+}
+
+fn parse_cluster_node(node: &str) -> Result<ClusterNode, String> {
+    let mut words = node.split_whitespace();
+    let (Some(id), Some(addr)) = (words.next(), words.next()) else {
+        return Err("expected <id> <ip>:<port>[@<bus port>] [slot ranges]".to_string());
+    };
+    let (addr, bus_port) = addr.split_once('@').unwrap_or((addr, "0"));
+    let (ip, port) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| format!("invalid address {}", addr))?;
+    let port = port.parse().map_err(|_| format!("invalid port {}", port))?;
+    let bus_port = bus_port
+        .parse()
+        .map_err(|_| format!("invalid bus port {}", bus_port))?;
+    let slots = cluster::parse_slot_ranges(&words.collect::<Vec<_>>().join(" "))?;
+    Ok(ClusterNode {
+        id: id.to_string(),
+        ip: ip.to_string(),
+        port,
+        bus_port,
+        slots,
+    })
+}
+
+/// The arguments the server was started with, along with the directives of
+/// its config file if it was given one.
+fn parse_args() -> Result<(Args, Option<PathBuf>), Box<dyn Error>> {
     let args = Args::parse();
+    let Some(path) = args.config.clone().or(args.config_file.clone()) else {
+        return Ok((args, None));
+    };
+    let text = fs::read_to_string(&path)
+        .map_err(|err| format!("failed reading {}: {}", path.display(), err))?;
+    let directives =
+        config::parse_file(&text).map_err(|err| format!("in {}: {}", path.display(), err))?;
+    let mut given = env::args();
+    let program = given.next().unwrap_or_default();
+    let given: Vec<String> = given.collect();
+    let options = config_file_options(directives, &given)
+        .map_err(|err| format!("in {}: {}", path.display(), err))?;
+    let args = Args::parse_from([program].into_iter().chain(options).chain(given));
+    Ok((args, Some(path)))
+}
+
+/// The options a config file's directives stand for. Later directives win
+/// over earlier ones, except that save rules and output buffer limits add
+/// up, and those for options also `given` on the command line are left out
+/// for these to win. Directives no option stands for are skipped with a
+/// warning, like the many of redis.conf there is nothing to configure for.
+fn config_file_options(
+    directives: Vec<(String, Vec<String>)>,
+    given: &[String],
+) -> Result<Vec<String>, String> {
+    let command = Args::command();
+    let mut options: Vec<(String, Vec<String>)> = Vec::new();
+    for (name, values) in directives {
+        let known = command.get_arguments().any(|arg| {
+            arg.get_long() == Some(&name)
+                || arg
+                    .get_all_aliases()
+                    .is_some_and(|aliases| aliases.contains(&name.as_str()))
+        });
+        if !known {
+            eprintln!("ignoring unknown config file directive {}", name);
+            continue;
+        }
+        let given_too = given.iter().any(|arg| {
+            arg.strip_prefix("--")
+                .is_some_and(|arg| arg == name || arg.starts_with(&format!("{}=", name)))
+        });
+        if given_too {
+            continue;
+        }
+        match options.iter_mut().find(|(known, _)| *known == name) {
+            // `save ""` drops the rules so far.
+            Some((_, known)) if name == "save" && values != [""] => known.extend(values),
+            Some((_, known)) if name == "client-output-buffer-limit" => known.extend(values),
+            Some((_, known)) => *known = values,
+            None => options.push((name, values)),
+        }
+    }
+
+    let mut args = Vec::new();
+    for (name, values) in options {
+        let flag = command.get_arguments().any(|arg| {
+            arg.get_long() == Some(&name) && matches!(arg.get_action(), ArgAction::SetTrue)
+        });
+        match name.as_str() {
+            // Flags are set by naming them, and off unless they are.
+            _ if flag => match values.join(" ").to_lowercase().as_str() {
+                "yes" => args.push(format!("--{}", name)),
+                "no" => {}
+                _ => return Err(format!("{} must be yes or no", name)),
+            },
+            // One by one for addresses starting with a dash to be values.
+            "bind" => args.extend(values.iter().map(|value| format!("--bind={}", value))),
+            // Taken as one value even if it starts with a dash.
+            _ => args.push(format!("--{}={}", name, values.join(" "))),
+        }
+    }
+    Ok(args)
+}
+
+/// Where the append only file is if there is to be one.
+fn aof_path(args: &Args) -> Option<PathBuf> {
+    args.appendonly.then(|| aof_file(args))
+}
 
-    let storage: Arc<Mutex<dyn Storage>> = if args.dir == None || args.dbfilename == None {
-        let storage = InMemoryStorage::new();
-        Arc::new(Mutex::new(storage))
+/// Where the append only file is, or goes once turned on.
+fn aof_file(args: &Args) -> PathBuf {
+    let dir = args.dir.as_deref().unwrap_or(".");
+    Path::new(dir).join(&args.appendfilename)
+}
+
+/// The storage the arguments ask for, loaded with whatever it should start
+/// with.
+async fn open_storage(
+    args: &Args,
+    access_log: Option<Arc<AccessLog>>,
+) -> Result<Arc<dyn Storage>, io::Error> {
+    #[cfg(feature = "disk")]
+    if let Some(path) = &args.disk {
+        let mut storage = DiskStorage::open(path)?;
+        if let Some(access_log) = access_log {
+            storage = storage.with_access_log(access_log);
+        }
+        return Ok(Arc::new(storage));
+    }
+    if args.dashmap {
+        if args.maxmemory_policy != MaxmemoryPolicy::NoEviction {
+            return Err(io::Error::other(
+                "--dashmap only supports the noeviction maxmemory policy",
+            ));
+        }
+        let mut storage = DashMapStorage::new().with_maxmemory(args.maxmemory);
+        if let Some(access_log) = access_log {
+            storage = storage.with_access_log(access_log);
+        }
+        return Ok(Arc::new(storage));
+    }
+
+    let maxmemory = Maxmemory {
+        limit: args.maxmemory,
+        policy: args.maxmemory_policy,
+    };
+    if let (Some(dir), Some(dbfilename)) = (&args.dir, &args.dbfilename) {
+        let mut storage = RdbStorage::new(dir, dbfilename)
+            .with_shards(args.shards)
+            .with_maxmemory(maxmemory);
+        if let Some(access_log) = access_log {
+            storage = storage.with_access_log(access_log);
+        }
+        // Like on Redis, an append only file takes precedence.
+        if !aof_path(args).is_some_and(|path| path.exists()) {
+            storage.load().await?;
+        }
+        Ok(Arc::new(storage))
     } else {
-        let mut storage = RdbStorage::new(&args.dir.unwrap(), &args.dbfilename.unwrap());
-        storage.load().await.unwrap();
-        let storage = Arc::new(Mutex::new(storage));
-        let storage_clone = Arc::clone(&storage);
-
-        task::spawn(async move {
-            loop {
-                let _ = sleep(Duration::from_secs(60));
-                let storage_guard = storage.lock().await;
-                let _ = storage_guard.save().await;
+        let mut storage = InMemoryStorage::new()
+            .with_shards(args.shards)
+            .with_maxmemory(maxmemory);
+        if let Some(access_log) = access_log {
+            storage = storage.with_access_log(access_log);
+        }
+        Ok(Arc::new(storage))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let (args, config_file) = parse_args()?;
+
+    if let Some(path) = &args.export_access_log {
+        access_log::export_csv(path, &mut io::stdout().lock())?;
+        return Ok(());
+    }
+    if let Some(master) = &args.tap {
+        tap::run(master, &mut io::stdout().lock()).await?;
+        return Ok(());
+    }
+
+    let access_log = match &args.access_log {
+        Some(path) => {
+            let access_log = Arc::new(AccessLog::open(
+                path,
+                args.access_log_sample,
+                args.access_log_max_size,
+            )?);
+            let flushed = Arc::clone(&access_log);
+            task::spawn(async move {
+                loop {
+                    sleep(Duration::from_secs(1)).await;
+                    if let Err(err) = flushed.flush() {
+                        eprintln!("failed flushing access log: {}", err);
+                    }
+                }
+            });
+            Some(access_log)
+        }
+        None => None,
+    };
+
+    KEYSPACE_EVENTS.configure(args.notify_keyspace_events);
+    RDB_CHECKSUM.store(args.rdbchecksum, Ordering::Relaxed);
+    REPLICATION
+        .read_only
+        .store(args.replica_read_only, Ordering::Relaxed);
+    let bind = bind_ips(&args.bind);
+    if bind.is_empty() {
+        return Err("none of the bind addresses is available".into());
+    }
+    let cluster_port = match args.cluster_port {
+        0 => args.port.checked_add(cluster::BUS_PORT_OFFSET).ok_or(
+            "the cluster bus port is 10000 above the port, which must be 55535 or less unless --cluster-port is set",
+        )?,
+        port => port,
+    };
+    if args.cluster_enabled {
+        CLUSTER.enable(&bind[0].to_string(), args.port, cluster_port);
+        CLUSTER
+            .node_timeout
+            .store(args.cluster_node_timeout, Ordering::Relaxed);
+        CLUSTER.add_slots(&args.cluster_slots.0)?;
+        for node in &args.cluster_node {
+            CLUSTER.add_node(&node.id, &node.ip, node.port, node.bus_port, &node.slots)?;
+        }
+    }
+    for (limit, value) in [
+        (&COMPACT.set_max_intset_entries, args.set_max_intset_entries),
+        (
+            &COMPACT.set_max_listpack_entries,
+            args.set_max_listpack_entries,
+        ),
+        (&COMPACT.set_max_listpack_value, args.set_max_listpack_value),
+        (
+            &COMPACT.zset_max_listpack_entries,
+            args.zset_max_listpack_entries,
+        ),
+        (
+            &COMPACT.zset_max_listpack_value,
+            args.zset_max_listpack_value,
+        ),
+    ] {
+        limit.store(value, Ordering::Relaxed);
+    }
+    let storage = open_storage(&args, access_log).await?;
+    let aof = match aof_path(&args) {
+        Some(path) => {
+            let existed = path.exists();
+            let replayed = aof::replay(&path, &*storage).await?;
+            println!("replayed {} writes from {}", replayed, path.display());
+            let aof = Aof::open(&path, args.appendfsync)?
+                .with_rdb_preamble(args.aof_use_rdb_preamble)
+                .with_coalesce_appends(args.aof_coalesce_appends);
+            // Like on Redis, a new file starts from what the RDB file
+            // held rather than from nothing.
+            if !existed {
+                aof.begin_rewrite();
+                aof.finish_rewrite(&storage.snapshot().await, storage.used_memory())?;
             }
-        });
-        storage_clone
+            Some(Arc::new(aof))
+        }
+        None => None,
     };
 
-    let server = Server::new(storage);
-    server
-        .run(&format!("127.0.0.1:{}", args.port))
-        .await
-        .expect("Server failed");
+    let aof_file = aof_file(&args);
+
+    let mut output_limits = OutputLimits::default();
+    for (class, limit) in args
+        .client_output_buffer_limit
+        .into_iter()
+        .flat_map(|limits| limits.0)
+    {
+        match class.as_str() {
+            "pubsub" => output_limits.pubsub = limit,
+            _ => output_limits.normal = limit,
+        }
+    }
+
+    let mut server = Server::new(storage)
+        .with_request_timeout(
+            (args.request_timeout > 0).then(|| Duration::from_secs(args.request_timeout)),
+        )
+        .with_idle_timeout((args.timeout > 0).then(|| Duration::from_secs(args.timeout)))
+        .with_max_clients(args.maxclients)
+        .with_output_limits(output_limits)
+        .with_query_buffer_limit(args.client_query_buffer_limit)
+        .with_save_rules(args.save.0)
+        .with_appendfsync(args.appendfsync)
+        .with_aof_coalesce_appends(args.aof_coalesce_appends)
+        .with_aof_file(&aof_file, args.aof_use_rdb_preamble)
+        // Like on Redis, an empty password is none.
+        .with_requirepass(args.requirepass.filter(|password| !password.is_empty()))
+        .with_tcp_nodelay(args.tcp_nodelay)
+        .with_tcp_keepalive(
+            (args.tcp_keepalive > 0).then(|| Duration::from_secs(args.tcp_keepalive)),
+        )
+        .with_limits(Limits {
+            max_bulk_len: args.proto_max_bulk_len,
+            ..Limits::default()
+        });
+    if let Some(aof) = aof {
+        server = server.with_aof(aof);
+    }
+    if let Some(path) = &config_file {
+        server = server.with_config_file(path);
+    }
+    if let Some((host, port)) = &args.replicaof {
+        server = server.with_replicaof(host, *port);
+    }
+    if args.cluster_enabled {
+        server = server.with_cluster_bus(&listen_addrs(&bind, cluster_port));
+    }
+    if args.reuseport {
+        let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+        server = server.with_acceptors(cores);
+    }
+    if let (Some(port), Some(cert_file), Some(key_file)) =
+        (args.tls_port, &args.tls_cert_file, &args.tls_key_file)
+    {
+        let acceptor = tls::acceptor(cert_file, key_file)?;
+        server = server.with_tls(&listen_addrs(&bind, port), acceptor);
+    }
+    server.run(&listen_addrs(&bind, args.port)).await?;
     Ok(())
 }
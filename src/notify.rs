@@ -0,0 +1,179 @@
+//! Keyspace notifications, telling subscribers which keys changed and how.
+//!
+//! Every change is published twice as on Redis, each half enabled with
+//! `notify-keyspace-events`: on `__keyspace@<db>__:<key>` carrying the
+//! event, and on `__keyevent@<db>__:<event>` carrying the key. Nothing is
+//! sent unless a class of events and at least one of the two channels are
+//! enabled.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    ops::BitOr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        LazyLock,
+    },
+};
+
+use tokio::sync::broadcast;
+
+use crate::pubsub::PUBSUB;
+
+/// Notifications buffered for a subscriber that falls behind before it
+/// starts missing some.
+const CAPACITY: usize = 1024;
+
+/// Which notifications are sent, as configured by `notify-keyspace-events`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NotifyFlags(u16);
+
+impl NotifyFlags {
+    pub const NONE: NotifyFlags = NotifyFlags(0);
+    pub const KEYSPACE: NotifyFlags = NotifyFlags(1 << 0);
+    pub const KEYEVENT: NotifyFlags = NotifyFlags(1 << 1);
+    /// Commands that apply to any type of key, such as EXPIRE or DEL.
+    pub const GENERIC: NotifyFlags = NotifyFlags(1 << 2);
+    pub const STRING: NotifyFlags = NotifyFlags(1 << 3);
+    pub const LIST: NotifyFlags = NotifyFlags(1 << 4);
+    pub const SET: NotifyFlags = NotifyFlags(1 << 5);
+    pub const HASH: NotifyFlags = NotifyFlags(1 << 6);
+    pub const ZSET: NotifyFlags = NotifyFlags(1 << 7);
+    pub const EXPIRED: NotifyFlags = NotifyFlags(1 << 8);
+    pub const EVICTED: NotifyFlags = NotifyFlags(1 << 9);
+    pub const STREAM: NotifyFlags = NotifyFlags(1 << 10);
+
+    /// Every class of events, from GENERIC to STREAM, which `A` stands for.
+    const ALL: NotifyFlags = NotifyFlags(0x7fc);
+
+    /// Each flag with the character naming it, in the order Redis prints
+    /// them.
+    const CHARS: [(NotifyFlags, char); 11] = [
+        (NotifyFlags::GENERIC, 'g'),
+        (NotifyFlags::STRING, '$'),
+        (NotifyFlags::LIST, 'l'),
+        (NotifyFlags::SET, 's'),
+        (NotifyFlags::HASH, 'h'),
+        (NotifyFlags::ZSET, 'z'),
+        (NotifyFlags::EXPIRED, 'x'),
+        (NotifyFlags::EVICTED, 'e'),
+        (NotifyFlags::STREAM, 't'),
+        (NotifyFlags::KEYSPACE, 'K'),
+        (NotifyFlags::KEYEVENT, 'E'),
+    ];
+
+    /// Parses flags written like Redis's `notify-keyspace-events`, such as
+    /// `KEA` or `Ex`. `None` if a character names no flag.
+    pub fn parse(flags: &str) -> Option<NotifyFlags> {
+        flags.chars().try_fold(NotifyFlags::NONE, |parsed, c| {
+            let flag = match c {
+                'A' => NotifyFlags::ALL,
+                c => NotifyFlags::CHARS.iter().find(|(_, known)| *known == c)?.0,
+            };
+            Some(parsed | flag)
+        })
+    }
+
+    pub fn contains(self, flags: NotifyFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+}
+
+impl BitOr for NotifyFlags {
+    type Output = NotifyFlags;
+
+    fn bitor(self, other: NotifyFlags) -> NotifyFlags {
+        NotifyFlags(self.0 | other.0)
+    }
+}
+
+impl Display for NotifyFlags {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut flags = *self;
+        if flags.contains(NotifyFlags::ALL) {
+            f.write_str("A")?;
+            flags = NotifyFlags(flags.0 & !NotifyFlags::ALL.0);
+        }
+        for (flag, c) in NotifyFlags::CHARS {
+            if flags.contains(flag) {
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A message published on a notification channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+    pub channel: String,
+    pub message: String,
+}
+
+/// Set with `notify-keyspace-events`, off by default as on Redis.
+pub static KEYSPACE_EVENTS: KeyspaceEvents = KeyspaceEvents {
+    flags: AtomicU16::new(0),
+    sender: LazyLock::new(|| broadcast::channel(CAPACITY).0),
+};
+
+pub struct KeyspaceEvents {
+    flags: AtomicU16,
+    sender: LazyLock<broadcast::Sender<Notification>>,
+}
+
+impl KeyspaceEvents {
+    pub fn flags(&self) -> NotifyFlags {
+        NotifyFlags(self.flags.load(Ordering::Relaxed))
+    }
+
+    pub fn configure(&self, flags: NotifyFlags) {
+        self.flags.store(flags.0, Ordering::Relaxed);
+    }
+
+    /// Receives every notification sent from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.sender.subscribe()
+    }
+
+    /// Tells subscribers `event` of class `class` happened to `key`, if
+    /// notifications of that class are enabled. There being a single
+    /// database, it is always database 0.
+    pub fn notify(&self, class: NotifyFlags, event: &str, key: &str) {
+        let flags = self.flags();
+        if !flags.contains(class) {
+            return;
+        }
+        if flags.contains(NotifyFlags::KEYSPACE) {
+            self.send(format!("__keyspace@0__:{}", key), event);
+        }
+        if flags.contains(NotifyFlags::KEYEVENT) {
+            self.send(format!("__keyevent@0__:{}", event), key);
+        }
+    }
+
+    /// Publishes `message` on `channel` to clients subscribed to it and to
+    /// receivers of every notification.
+    fn send(&self, channel: String, message: &str) {
+        PUBSUB.publish(&channel, message);
+        // Nobody listening is not an error.
+        let _ = self.sender.send(Notification {
+            channel,
+            message: message.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_flags_like_redis() {
+        let flags = NotifyFlags::parse("Kx$").unwrap();
+        assert!(flags.contains(NotifyFlags::KEYSPACE | NotifyFlags::EXPIRED));
+        assert!(!flags.contains(NotifyFlags::KEYEVENT));
+        assert_eq!(flags.to_string(), "$xK");
+        assert_eq!(NotifyFlags::parse("EgA").unwrap().to_string(), "AE");
+        assert_eq!(NotifyFlags::parse("").unwrap(), NotifyFlags::NONE);
+        assert!(NotifyFlags::parse("KQ").is_none());
+    }
+}
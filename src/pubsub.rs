@@ -0,0 +1,285 @@
+//! Publish/subscribe: a message published on a channel goes to every client
+//! subscribed to it, or to a glob pattern matching it, at that moment,
+//! through the inbox the task serving each client drains. Nothing is kept
+//! for clients subscribing later. Shard channels are a namespace of their
+//! own, hashed to slots like keys so each lives on a single cluster node.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use tokio::sync::mpsc;
+
+use crate::{glob::glob_match, resp::Entry};
+
+/// A message published on a channel, as subscribers receive it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    /// The pattern the channel matched, for subscribers to a pattern.
+    pub pattern: Option<String>,
+    /// Whether the channel is a shard channel.
+    pub shard: bool,
+    pub channel: String,
+    pub message: String,
+}
+
+impl Message {
+    /// The push subscribers are sent the message as.
+    pub fn to_entry(&self) -> Entry {
+        let text = |text: &str| Entry::Text(text.to_string());
+        match &self.pattern {
+            Some(pattern) => Entry::Push(vec![
+                text("pmessage"),
+                text(pattern),
+                text(&self.channel),
+                text(&self.message),
+            ]),
+            None if self.shard => Entry::Push(vec![
+                text("smessage"),
+                text(&self.channel),
+                text(&self.message),
+            ]),
+            None => Entry::Push(vec![
+                text("message"),
+                text(&self.channel),
+                text(&self.message),
+            ]),
+        }
+    }
+}
+
+/// Where the messages for a client go.
+pub type Inbox = mpsc::UnboundedSender<Message>;
+
+/// The subscribers of every channel.
+pub static PUBSUB: PubSub = PubSub {
+    channels: Mutex::new(BTreeMap::new()),
+    patterns: Mutex::new(BTreeMap::new()),
+    shard_channels: Mutex::new(BTreeMap::new()),
+};
+
+/// The inbox of each subscriber by client ID, for every channel or pattern
+/// with subscribers.
+type Subscribers = BTreeMap<String, BTreeMap<u64, Inbox>>;
+
+pub struct PubSub {
+    channels: Mutex<Subscribers>,
+    patterns: Mutex<Subscribers>,
+    shard_channels: Mutex<Subscribers>,
+}
+
+impl PubSub {
+    /// Sends what is published on `channel` to `inbox`, that of `client`.
+    pub fn subscribe(&self, channel: &str, client: u64, inbox: &Inbox) {
+        add(&mut self.channels.lock().unwrap(), channel, client, inbox);
+    }
+
+    pub fn unsubscribe(&self, channel: &str, client: u64) {
+        remove(&mut self.channels.lock().unwrap(), channel, client);
+    }
+
+    /// Sends what is published on channels matching the glob `pattern` to
+    /// `inbox`, that of `client`.
+    pub fn psubscribe(&self, pattern: &str, client: u64, inbox: &Inbox) {
+        add(&mut self.patterns.lock().unwrap(), pattern, client, inbox);
+    }
+
+    pub fn punsubscribe(&self, pattern: &str, client: u64) {
+        remove(&mut self.patterns.lock().unwrap(), pattern, client);
+    }
+
+    /// Sends what is published on the shard channel `channel` to `inbox`,
+    /// that of `client`.
+    pub fn ssubscribe(&self, channel: &str, client: u64, inbox: &Inbox) {
+        add(
+            &mut self.shard_channels.lock().unwrap(),
+            channel,
+            client,
+            inbox,
+        );
+    }
+
+    pub fn sunsubscribe(&self, channel: &str, client: u64) {
+        remove(&mut self.shard_channels.lock().unwrap(), channel, client);
+    }
+
+    /// Sends `message` to the subscribers of `channel` and of the patterns
+    /// matching it, returning how many it went to. A client subscribed
+    /// several ways receives it as many times.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let message = Message {
+            pattern: None,
+            shard: false,
+            channel: channel.to_string(),
+            message: message.to_string(),
+        };
+        let mut received = send(&self.channels.lock().unwrap(), &message);
+        let patterns = self.patterns.lock().unwrap();
+        for (pattern, subscribers) in patterns.iter() {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            let message = Message {
+                pattern: Some(pattern.clone()),
+                ..message.clone()
+            };
+            received += send_to(subscribers, &message);
+        }
+        received
+    }
+
+    /// Sends `message` to the subscribers of the shard channel `channel`,
+    /// returning how many it went to. Patterns never match shard channels.
+    pub fn spublish(&self, channel: &str, message: &str) -> usize {
+        let message = Message {
+            pattern: None,
+            shard: true,
+            channel: channel.to_string(),
+            message: message.to_string(),
+        };
+        send(&self.shard_channels.lock().unwrap(), &message)
+    }
+
+    /// The channels with subscribers, those matching the glob `pattern`
+    /// only if given one, in order.
+    pub fn channels(&self, pattern: Option<&str>) -> Vec<String> {
+        matching(&self.channels.lock().unwrap(), pattern)
+    }
+
+    /// How many clients are subscribed to `channel`, not counting patterns.
+    pub fn numsub(&self, channel: &str) -> usize {
+        count(&self.channels.lock().unwrap(), channel)
+    }
+
+    /// The shard channels with subscribers, like `channels`.
+    pub fn shard_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        matching(&self.shard_channels.lock().unwrap(), pattern)
+    }
+
+    /// How many clients are subscribed to the shard channel `channel`.
+    pub fn shard_numsub(&self, channel: &str) -> usize {
+        count(&self.shard_channels.lock().unwrap(), channel)
+    }
+
+    /// How many patterns clients are subscribed to.
+    pub fn numpat(&self) -> usize {
+        self.patterns.lock().unwrap().len()
+    }
+}
+
+/// Sends `message` to the subscribers of its channel in `subscribers`.
+fn send(subscribers: &Subscribers, message: &Message) -> usize {
+    subscribers
+        .get(&message.channel)
+        .map_or(0, |inboxes| send_to(inboxes, message))
+}
+
+fn send_to(inboxes: &BTreeMap<u64, Inbox>, message: &Message) -> usize {
+    inboxes
+        .values()
+        .filter(|inbox| inbox.send(message.clone()).is_ok())
+        .count()
+}
+
+fn matching(subscribers: &Subscribers, pattern: Option<&str>) -> Vec<String> {
+    subscribers
+        .keys()
+        .filter(|name| pattern.is_none_or(|pattern| glob_match(pattern, name)))
+        .cloned()
+        .collect()
+}
+
+fn count(subscribers: &Subscribers, name: &str) -> usize {
+    subscribers.get(name).map_or(0, BTreeMap::len)
+}
+
+fn add(subscribers: &mut Subscribers, name: &str, client: u64, inbox: &Inbox) {
+    let inboxes = subscribers.entry(name.to_string()).or_default();
+    inboxes.insert(client, inbox.clone());
+}
+
+fn remove(subscribers: &mut Subscribers, name: &str, client: u64) {
+    if let Some(inboxes) = subscribers.get_mut(name) {
+        inboxes.remove(&client);
+        if inboxes.is_empty() {
+            subscribers.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_send_messages_to_subscribers_only() {
+        let (first, mut to_first) = mpsc::unbounded_channel();
+        let (second, mut to_second) = mpsc::unbounded_channel();
+        PUBSUB.subscribe("pubsub:news", 1, &first);
+        PUBSUB.subscribe("pubsub:news", 2, &second);
+        PUBSUB.subscribe("pubsub:other", 2, &second);
+
+        assert_eq!(PUBSUB.publish("pubsub:news", "hello"), 2);
+        let sent = Message {
+            pattern: None,
+            shard: false,
+            channel: "pubsub:news".to_string(),
+            message: "hello".to_string(),
+        };
+        assert_eq!(to_first.try_recv(), Ok(sent.clone()));
+        assert_eq!(to_second.try_recv(), Ok(sent));
+
+        PUBSUB.unsubscribe("pubsub:news", 2);
+        assert_eq!(PUBSUB.publish("pubsub:news", "again"), 1);
+        assert!(to_second.try_recv().is_err());
+        assert_eq!(PUBSUB.publish("pubsub:nobody", "lost"), 0);
+    }
+
+    #[test]
+    fn should_send_messages_to_subscribers_of_matching_patterns() {
+        let (inbox, mut messages) = mpsc::unbounded_channel();
+        PUBSUB.psubscribe("ppubsub:*", 1, &inbox);
+        PUBSUB.subscribe("ppubsub:news", 1, &inbox);
+
+        assert_eq!(PUBSUB.publish("ppubsub:news", "hello"), 2);
+        assert_eq!(messages.try_recv().unwrap().pattern, None);
+        let matched = messages.try_recv().unwrap();
+        assert_eq!(matched.pattern.as_deref(), Some("ppubsub:*"));
+        assert_eq!(matched.channel, "ppubsub:news");
+        assert_eq!(PUBSUB.publish("other:news", "hello"), 0);
+
+        PUBSUB.punsubscribe("ppubsub:*", 1);
+        assert_eq!(PUBSUB.publish("ppubsub:sports", "hello"), 0);
+    }
+
+    #[test]
+    fn should_list_channels_with_subscribers() {
+        let (inbox, _messages) = mpsc::unbounded_channel();
+        PUBSUB.subscribe("listed:b", 1, &inbox);
+        PUBSUB.subscribe("listed:a", 1, &inbox);
+        PUBSUB.subscribe("listed:a", 2, &inbox);
+        PUBSUB.subscribe("unlisted", 1, &inbox);
+
+        assert_eq!(PUBSUB.channels(Some("listed:*")), ["listed:a", "listed:b"]);
+        assert!(PUBSUB.channels(None).contains(&"unlisted".to_string()));
+        assert_eq!(PUBSUB.numsub("listed:a"), 2);
+        assert_eq!(PUBSUB.numsub("listed:c"), 0);
+    }
+
+    #[test]
+    fn should_keep_shard_channels_apart() {
+        let (inbox, mut messages) = mpsc::unbounded_channel();
+        PUBSUB.ssubscribe("spubsub:news", 1, &inbox);
+        PUBSUB.psubscribe("spubsub:*", 1, &inbox);
+
+        assert_eq!(PUBSUB.spublish("spubsub:news", "hello"), 1);
+        assert!(messages.try_recv().unwrap().shard);
+        assert!(messages.try_recv().is_err());
+        assert_eq!(PUBSUB.publish("spubsub:news", "hello"), 1);
+        assert!(!messages.try_recv().unwrap().shard);
+        assert_eq!(PUBSUB.shard_channels(Some("spubsub:*")), ["spubsub:news"]);
+        assert!(PUBSUB.channels(Some("spubsub:*")).is_empty());
+        assert_eq!(PUBSUB.shard_numsub("spubsub:news"), 1);
+
+        PUBSUB.sunsubscribe("spubsub:news", 1);
+        assert_eq!(PUBSUB.spublish("spubsub:news", "hello"), 0);
+    }
+}
@@ -1,29 +1,91 @@
 use std::{
     collections::HashMap,
     error::Error,
-    fs::{create_dir_all, File},
-    io::{self, Read, Write},
-    path::Path,
-    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    fs::{self, create_dir_all, File},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-use crate::storage::Value;
+use crate::{
+    crc64,
+    functions::{Library, FUNCTIONS},
+    storage::{Data, Expiry, SortedSet, Value, RDB_CHECKSUM},
+};
 
-#[derive(Debug)]
-struct RdbHeader {
-    version: u32,
-}
+mod listpack;
+mod stream;
+
+/// The format version written, that of Redis 7.2.
+const RDB_VERSION: u32 = 11;
+
+/// The newest format version read, that of Redis 7.4, whose hash field
+/// expiries and slot sizes are skipped.
+const NEWEST_VERSION: u32 = 12;
+
+/// The Redis version files claim to be written by, the first to write
+/// `RDB_VERSION`.
+const REDIS_VERSION: &str = "7.2.0";
+
+// The types of values. Any of them can be read, but each data type is
+// written as Redis 7.2 would, and those there is no equivalent of here,
+// lists, hashes and module types, are skipped when loading.
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+/// Scores as text, written by versions before 4.
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+/// Scores as binary doubles.
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+/// Also keeps the first and highest deleted IDs and entries ever added,
+/// and how many entries each group read.
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+/// Also keeps when each consumer was last active.
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+/// Hashes some of whose fields expire.
+const TYPE_HASH_METADATA: u8 = 24;
+const TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+// Opcodes that aren't a key, or come before one to say more about it.
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION2: u8 = 0xF6;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
 
 #[derive(Debug)]
 struct RdbEntry {
     key: String,
-    value: String,
-    expiry: Option<Instant>,
+    /// `None` for types there is no equivalent of here, which are read
+    /// past without being kept.
+    value: Option<Data>,
+    expiry: Option<Expiry>,
 }
 
-fn parse_rdb_header(buffer: &mut Bytes) -> Result<RdbHeader, String> {
+/// Reads past the magic string, returning the format version.
+fn parse_rdb_header(buffer: &mut Bytes) -> Result<u32, String> {
     // "REDIS" + 4 bytes for version
     if buffer.remaining() < 9 {
         return Err("File too short".into());
@@ -33,72 +95,325 @@ fn parse_rdb_header(buffer: &mut Bytes) -> Result<RdbHeader, String> {
         return Err("Invalid RDB file: not starting with REDIS".into());
     }
 
-    let version = buffer.get_u32();
-    Ok(RdbHeader { version })
+    let version = buffer.split_to(4);
+    let version = match std::str::from_utf8(&version)
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        Some(version) => version,
+        // Files this server wrote before it spelled the version out.
+        None => u32::from_be_bytes(version[..].try_into().unwrap()),
+    };
+    if version > NEWEST_VERSION {
+        return Err(format!("Can't handle RDB format version {}", version));
+    }
+    Ok(version)
 }
 
-// TODO: add expiry
 fn parse_rdb_entry(buffer: &mut Bytes) -> Result<Option<RdbEntry>, String> {
     if !buffer.has_remaining() {
         return Ok(None);
     }
-    let data_type = buffer.get_u8();
-    if data_type == 0xFF {
-        return Ok(None); // EOF marker
+    let mut expiry = None;
+    let value_type = loop {
+        match buffer.get_u8() {
+            OPCODE_EOF => return Ok(None),
+            OPCODE_EXPIRETIME => expiry = Some(parse_expiry(buffer, true)?),
+            OPCODE_EXPIRETIME_MS => expiry = Some(parse_expiry(buffer, false)?),
+            // How long ago and how often the key was accessed, which is
+            // started over here.
+            OPCODE_IDLE => {
+                parse_number(buffer)?;
+            }
+            OPCODE_FREQ => {
+                take(buffer, 1)?;
+            }
+            value_type => break value_type,
+        }
+        if !buffer.has_remaining() {
+            return Err("File truncated".into());
+        }
+    };
+    let key = parse_text(buffer)?;
+    let value = parse_value(buffer, value_type)?;
+    Ok(Some(RdbEntry { key, value, expiry }))
+}
+
+/// A score written as text, its length byte standing for NaN and the
+/// infinities instead where it can't be one.
+fn parse_text_score(buffer: &mut Bytes) -> Result<f64, String> {
+    let score = match take(buffer, 1)?[0] {
+        253 => f64::NAN,
+        254 => f64::INFINITY,
+        255 => f64::NEG_INFINITY,
+        len => {
+            let text = take(buffer, len as usize)?;
+            std::str::from_utf8(&text)
+                .ok()
+                .and_then(|text| text.parse().ok())
+                .ok_or("invalid score")?
+        }
+    };
+    Ok(score)
+}
+
+/// Pairs up the members and scores of a zset packed into a listpack or
+/// ziplist.
+fn packed_zset(elements: Vec<listpack::Element>) -> Result<SortedSet, String> {
+    let mut zset = SortedSet::new();
+    let mut elements = elements.into_iter();
+    while let Some(member) = elements.next() {
+        let score = elements
+            .next()
+            .map(listpack::Element::into_string)
+            .and_then(|score| score.parse().ok())
+            .ok_or("invalid score")?;
+        zset.insert(member.into_string(), score);
+    }
+    Ok(zset)
+}
+
+/// Reads past what a module saved, which it tags item by item, up to an
+/// end tag, so it can be skipped without the module.
+fn skip_module_data(buffer: &mut Bytes) -> Result<(), String> {
+    // The ID of the module.
+    parse_number(buffer)?;
+    loop {
+        match parse_number(buffer)? {
+            0 => return Ok(()),
+            // Signed and unsigned integers.
+            1 | 2 => {
+                parse_number(buffer)?;
+            }
+            3 => {
+                take(buffer, 4)?;
+            }
+            4 => {
+                take(buffer, 8)?;
+            }
+            5 => {
+                parse_string(buffer)?;
+            }
+            tag => return Err(format!("unknown module data tag {}", tag)),
+        }
     }
-    match data_type {
-        0x00 => parse_rdb_string(buffer, None),
-        0xFD => {
-            let expiry = parse_expiry(buffer, true)?;
-            parse_rdb_string(buffer, expiry)
+}
+
+/// Reads past a value of a type there is no equivalent of here.
+fn skip_value(buffer: &mut Bytes, value_type: u8) -> Result<(), String> {
+    match value_type {
+        TYPE_LIST | TYPE_LIST_QUICKLIST => {
+            for _ in 0..parse_len(buffer)? {
+                parse_string(buffer)?;
+            }
+        }
+        TYPE_HASH => {
+            for _ in 0..parse_len(buffer)? {
+                parse_string(buffer)?;
+                parse_string(buffer)?;
+            }
+        }
+        TYPE_HASH_ZIPMAP | TYPE_LIST_ZIPLIST | TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+            parse_string(buffer)?;
         }
-        0xFC => {
-            let expiry = parse_expiry(buffer, false)?;
-            parse_rdb_string(buffer, expiry)
+        TYPE_LIST_QUICKLIST_2 => {
+            for _ in 0..parse_len(buffer)? {
+                // Whether the node is packed or a single element.
+                parse_number(buffer)?;
+                parse_string(buffer)?;
+            }
         }
-        _ => Err("unknown data type".into()),
+        TYPE_MODULE_2 => skip_module_data(buffer)?,
+        TYPE_HASH_METADATA => {
+            // When the first field expires, then each field with its
+            // expiry.
+            take(buffer, 8)?;
+            for _ in 0..parse_len(buffer)? {
+                parse_number(buffer)?;
+                parse_string(buffer)?;
+                parse_string(buffer)?;
+            }
+        }
+        TYPE_HASH_LISTPACK_EX => {
+            take(buffer, 8)?;
+            parse_string(buffer)?;
+        }
+        _ => return Err(format!("unknown value type {}", value_type)),
     }
+    Ok(())
 }
 
-fn parse_rbd_metadata(buffer: &mut Bytes) -> Result<(), String> {
-    while buffer.remaining() > 0 {
-        let byte = buffer.get_u8();
-        if byte == 0xFE {
-            return Ok(());
+fn parse_value(buffer: &mut Bytes, value_type: u8) -> Result<Option<Data>, String> {
+    let value = match value_type {
+        TYPE_STRING => Data::String(parse_string(buffer)?),
+        TYPE_SET => {
+            let len = parse_len(buffer)?;
+            Data::Set(
+                (0..len)
+                    .map(|_| parse_text(buffer))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+        TYPE_SET_INTSET => {
+            let ints = listpack::read_intset(&parse_string(buffer)?)?;
+            Data::Set(ints.into_iter().map(|int| int.to_string()).collect())
+        }
+        TYPE_SET_LISTPACK => {
+            let elements = listpack::read_listpack(&parse_string(buffer)?)?;
+            Data::Set(
+                elements
+                    .into_iter()
+                    .map(listpack::Element::into_string)
+                    .collect(),
+            )
         }
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let mut zset = SortedSet::new();
+            for _ in 0..parse_len(buffer)? {
+                let member = parse_text(buffer)?;
+                let score = match value_type {
+                    TYPE_ZSET => parse_text_score(buffer)?,
+                    _ => take(buffer, 8)?.get_f64_le(),
+                };
+                zset.insert(member, score);
+            }
+            Data::SortedSet(zset)
+        }
+        TYPE_ZSET_ZIPLIST => Data::SortedSet(packed_zset(listpack::read_ziplist(&parse_string(
+            buffer,
+        )?)?)?),
+        TYPE_ZSET_LISTPACK => Data::SortedSet(packed_zset(listpack::read_listpack(
+            &parse_string(buffer)?,
+        )?)?),
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            Data::Stream(stream::parse(buffer, value_type)?)
+        }
+        _ => {
+            skip_value(buffer, value_type)?;
+            return Ok(None);
+        }
+    };
+    Ok(Some(value))
+}
+
+/// Reads the auxiliary fields describing whoever wrote the file, each a
+/// 0xFA opcode followed by a name and a value.
+fn parse_rbd_metadata(buffer: &mut Bytes) -> Result<Vec<(String, String)>, String> {
+    let mut aux = Vec::new();
+    while buffer.first() == Some(&OPCODE_AUX) {
+        buffer.advance(1);
+        aux.push((parse_text(buffer)?, parse_text(buffer)?));
+    }
+    Ok(aux)
+}
+
+/// A length as RDB files encode it, in as few bytes as it fits. The first
+/// byte's top two bits tell how: 00 keeps six bits, 01 fourteen across two
+/// bytes, 10 a 32 or 64 bit big endian number after it, and 11 says a
+/// string follows encoded the way the other six bits name.
+#[derive(Debug, PartialEq)]
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+fn take(buffer: &mut Bytes, len: usize) -> Result<Bytes, String> {
+    if buffer.remaining() < len {
+        return Err("File truncated".into());
+    }
+    Ok(buffer.split_to(len))
+}
+
+fn parse_length(buffer: &mut Bytes) -> Result<Length, String> {
+    let first = take(buffer, 1)?[0];
+    let len = match first >> 6 {
+        0b00 => (first & 0x3F) as u64,
+        0b01 => (((first & 0x3F) as u64) << 8) | take(buffer, 1)?[0] as u64,
+        0b11 => return Ok(Length::Encoded(first & 0x3F)),
+        _ if first == 0x80 => take(buffer, 4)?.get_u32() as u64,
+        _ if first == 0x81 => take(buffer, 8)?.get_u64(),
+        _ => return Err(format!("unknown length encoding {:#04x}", first)),
+    };
+    Ok(Length::Len(len))
+}
+
+/// A number written as a length, where a string encoding makes no sense.
+fn parse_number(buffer: &mut Bytes) -> Result<u64, String> {
+    match parse_length(buffer)? {
+        Length::Len(len) => Ok(len),
+        Length::Encoded(_) => Err("expected a length, found a string encoding".into()),
     }
-    Err("Metadata section did not end correctly".into())
 }
 
-fn parse_rbd_database_start(buffer: &mut Bytes) -> Result<(), String> {
-    while buffer.remaining() > 0 {
-        let byte = buffer.get_u8();
-        if byte == 0xFB {
-            // Skipping hash map size + expiry size
-            buffer.get_u8();
-            buffer.get_u8();
-            return Ok(());
+fn parse_len(buffer: &mut Bytes) -> Result<usize, String> {
+    usize::try_from(parse_number(buffer)?).map_err(|_| "length out of range".to_string())
+}
+
+fn parse_string(buffer: &mut Bytes) -> Result<Vec<u8>, String> {
+    match parse_length(buffer)? {
+        Length::Len(len) => {
+            let len = usize::try_from(len).map_err(|_| "length out of range".to_string())?;
+            Ok(take(buffer, len)?.to_vec())
+        }
+        Length::Encoded(ENC_INT8) => Ok((take(buffer, 1)?.get_i8()).to_string().into_bytes()),
+        Length::Encoded(ENC_INT16) => Ok((take(buffer, 2)?.get_i16_le()).to_string().into_bytes()),
+        Length::Encoded(ENC_INT32) => Ok((take(buffer, 4)?.get_i32_le()).to_string().into_bytes()),
+        Length::Encoded(ENC_LZF) => {
+            let compressed = parse_len(buffer)?;
+            let len = parse_len(buffer)?;
+            lzf_decompress(&take(buffer, compressed)?, len)
         }
+        Length::Encoded(encoding) => Err(format!("unknown string encoding {}", encoding)),
     }
-    Err("Database section did not start correctly".into())
 }
 
-fn parse_string(buffer: &mut Bytes) -> Result<String, String> {
-    let str_len = buffer.get_u8();
-    if buffer.remaining() < str_len as usize {
-        return Err("File truncated while reading key".into());
+/// Undoes the LZF compression Redis applies to longer strings. The input
+/// is a series of runs: a control byte below 32 copies that many bytes
+/// plus one as they are, any other copies bytes already written from a
+/// little further back.
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, String> {
+    let truncated = || "compressed string truncated".to_string();
+    let mut out = Vec::with_capacity(len);
+    let mut at = 0;
+    while at < input.len() {
+        let control = input[at] as usize;
+        at += 1;
+        if control < 32 {
+            let literal = input.get(at..at + control + 1).ok_or_else(truncated)?;
+            out.extend_from_slice(literal);
+            at += control + 1;
+            continue;
+        }
+        let mut run = control >> 5;
+        if run == 7 {
+            run += *input.get(at).ok_or_else(truncated)? as usize;
+            at += 1;
+        }
+        let back = ((control & 0x1F) << 8) + *input.get(at).ok_or_else(truncated)? as usize + 1;
+        at += 1;
+        let from = out
+            .len()
+            .checked_sub(back)
+            .ok_or("compressed string refers back too far")?;
+        // Copied a byte at a time, as a run may repeat bytes it just wrote.
+        for from in from..from + run + 2 {
+            out.push(out[from]);
+        }
     }
-    let str_bytes = buffer.split_to(str_len as usize);
-    Ok(String::from_utf8_lossy(str_bytes.as_ref()).to_string())
+    if out.len() != len {
+        return Err("compressed string has the wrong length".into());
+    }
+    Ok(out)
 }
 
-fn parse_rdb_string(
-    buffer: &mut Bytes,
-    expiry: Option<Instant>,
-) -> Result<Option<RdbEntry>, String> {
-    let key = parse_string(buffer)?;
-    let value = parse_string(buffer)?;
-    Ok(Some(RdbEntry { key, value, expiry }))
+/// A string read as text, as keys and members are kept.
+fn parse_text(buffer: &mut Bytes) -> Result<String, String> {
+    Ok(String::from_utf8_lossy(&parse_string(buffer)?).into_owned())
 }
 
 pub fn parse_rdb_file(_fn: &str) -> Result<HashMap<String, Value>, Box<dyn Error>> {
@@ -115,144 +430,523 @@ pub fn parse_rdb_file(_fn: &str) -> Result<HashMap<String, Value>, Box<dyn Error
         return Ok(HashMap::new());
     }
 
-    let mut buf = Bytes::from(buf);
+    parse_rdb(&mut Bytes::from(buf))
+}
+
+/// Reads the RDB payload at the front of `buf`, leaving whatever follows
+/// it such as the commands of an append only file starting with one.
+pub fn parse_rdb(buf: &mut Bytes) -> Result<HashMap<String, Value>, Box<dyn Error>> {
+    let file = buf.clone();
 
     // Header
-    let v = parse_rdb_header(&mut buf)?;
-    println!("Version: {}", v.version);
+    parse_rdb_header(buf)?;
 
     let mut m = HashMap::new();
 
-    // Metadata
-    parse_rbd_metadata(&mut buf)?;
-
-    // Database
-    parse_rbd_database_start(&mut buf)?;
+    // Only keys of the first database are kept, as there are no others.
+    let mut db = 0;
+    let mut skipped = 0;
     loop {
-        match parse_rdb_entry(&mut buf) {
-            Ok(Some(entry)) => {
-                println!("Parsed Entry: {:?}", entry);
-                m.insert(
-                    entry.key,
-                    Value {
-                        value: entry.value,
-                        expiry: entry.expiry,
-                    },
-                );
+        match buf.first().copied() {
+            Some(OPCODE_AUX) => {
+                parse_rbd_metadata(buf)?;
+            }
+            Some(OPCODE_SELECTDB) => {
+                buf.advance(1);
+                db = parse_number(buf)?;
             }
-            Ok(None) => {
-                println!("End of RDB file");
-                break;
+            Some(OPCODE_RESIZEDB) => {
+                // Skipping hash map size + expiry size
+                buf.advance(1);
+                parse_number(buf)?;
+                parse_number(buf)?;
             }
-            Err(err) => {
-                eprintln!("Error parsing entry: {}", err);
-                break;
+            Some(OPCODE_SLOT_INFO) => {
+                // Skipping slot + its hash map size + expiry size
+                buf.advance(1);
+                for _ in 0..3 {
+                    parse_number(buf)?;
+                }
             }
+            Some(OPCODE_MODULE_AUX) => {
+                buf.advance(1);
+                skip_module_data(buf)?;
+            }
+            Some(OPCODE_FUNCTION2) => {
+                buf.advance(1);
+                let code = parse_text(buf)?;
+                FUNCTIONS
+                    .load(&code, true)
+                    .map_err(|err| format!("Failed loading a function library: {}", err))?;
+            }
+            _ => match parse_rdb_entry(buf)? {
+                Some(RdbEntry {
+                    key,
+                    value: Some(value),
+                    expiry,
+                }) if db == 0 => {
+                    m.insert(key, Value { value, expiry });
+                }
+                Some(_) => skipped += 1,
+                None => break,
+            },
         }
     }
+    if skipped > 0 {
+        eprintln!("skipped {} keys of databases other than 0", skipped);
+    }
+    verify_checksum(&file, buf)?;
 
     Ok(m)
 }
 
-pub fn write_rdb_file(_fn: &str, map: HashMap<String, Value>) -> Result<(), io::Error> {
-    let path = Path::new(_fn);
-    if let Some(parent) = path.parent() {
-        create_dir_all(parent)?;
+/// Checks the checksum following the end of file marker against what came
+/// before it. Files written before checksums were have none, and ones
+/// written with `rdbchecksum` off have zero, so neither is checked.
+fn verify_checksum(file: &Bytes, buffer: &mut Bytes) -> Result<(), String> {
+    if !buffer.has_remaining() {
+        return Ok(());
+    }
+    let expected = take(buffer, 8)?.get_u64_le();
+    if expected == 0 || !RDB_CHECKSUM.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let actual = crc64::crc64(&file[..file.len() - buffer.remaining() - 8]);
+    if expected != actual {
+        return Err(format!(
+            "Wrong RDB checksum: expected {:#018x}, got {:#018x}",
+            expected, actual
+        ));
     }
+    Ok(())
+}
+
+/// Writes an RDB file an entry at a time, so the dataset never needs to be
+/// copied whole to be saved. Entries go to a temporary file that only
+/// replaces the previous one once complete, so a failed save leaves the
+/// last good one in place.
+pub struct RdbWriter {
+    file: BufWriter<File>,
+    /// Checksum of everything written so far.
+    crc: u64,
+    temp: PathBuf,
+    path: PathBuf,
+}
+
+impl RdbWriter {
+    /// Starts a file in place of the one at `path`, describing a server
+    /// using `used_mem` bytes.
+    pub fn create(path: &str, used_mem: usize) -> Result<RdbWriter, io::Error> {
+        let path = PathBuf::from(path);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut temp = path.clone().into_os_string();
+        temp.push(format!(".tmp-{}", std::process::id()));
+        let temp = PathBuf::from(temp);
+
+        let file = BufWriter::new(File::create(&temp)?);
+        let mut writer = RdbWriter {
+            file,
+            crc: 0,
+            temp,
+            path,
+        };
+        writer.write(&preamble(used_mem))?;
+        Ok(writer)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+        self.crc = crc64::update(self.crc, bytes);
+        self.file.write_all(bytes)
+    }
+
+    pub fn write_entry(&mut self, key: &str, value: &Value) -> Result<(), io::Error> {
+        let mut buf = BytesMut::new();
+        write_entry(&mut buf, key, value);
+        self.write(&buf)
+    }
+
+    /// Ends the file with its checksum, or zero if `rdbchecksum` is off, and
+    /// puts it in place of the previous one.
+    pub fn finish(self) -> Result<(), io::Error> {
+        self.finish_with(&[])
+    }
+
+    /// `finish`, with `tail` written after the checksum: the commands of an
+    /// append only file that starts with this payload.
+    pub fn finish_with(mut self, tail: &[u8]) -> Result<(), io::Error> {
+        self.write(&[0xFF])?;
+        self.file.write_all(&checksum(self.crc).to_le_bytes())?;
+        self.file.write_all(tail)?;
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        put_in_place(&self.temp, &self.path)
+    }
+}
 
-    let mut f = File::create(_fn)?;
+/// What every file starts with: the version, aux fields describing a server
+/// using `used_mem` bytes, then the start of database 0.
+fn preamble(used_mem: usize) -> BytesMut {
     let mut buf = BytesMut::new();
+    buf.extend_from_slice(format!("REDIS{:04}", RDB_VERSION).as_bytes());
+    let ctime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    for (key, value) in [
+        ("redis-ver", REDIS_VERSION.to_string()),
+        ("redis-bits", usize::BITS.to_string()),
+        ("ctime", ctime.to_string()),
+        ("used-mem", used_mem.to_string()),
+    ] {
+        buf.put_u8(0xFA);
+        write_rdb_string(&mut buf, key.as_bytes());
+        write_rdb_string(&mut buf, value.as_bytes());
+    }
+    // Function libraries are loaded before the keys, as on Redis.
+    for library in FUNCTIONS.libraries() {
+        buf.put_u8(OPCODE_FUNCTION2);
+        write_rdb_string(&mut buf, library.code.as_bytes());
+    }
+    // Database 0, without hints of how many keys follow as they are yet
+    // to be counted.
+    buf.extend_from_slice(b"\xFE\x00\xFB\x00\x00");
+    buf
+}
 
-    buf.extend_from_slice(b"REDIS\x00\x00\x00\x09");
-    buf.extend_from_slice(b"\xFA\xFE\x01\x01");
+fn write_entry(buf: &mut BytesMut, key: &str, value: &Value) {
+    if let Some(expiry) = value.expiry {
+        buf.put_u8(0xFC);
+        buf.put_u64_le(expiry.unix_ms());
+    }
+    write_value(buf, key, &value.value);
+}
 
-    for (k, v) in map.iter() {
-        buf.put_u8(0x00);
-        write_rdb_string(&mut buf, k);
-        write_rdb_string(&mut buf, &v.value);
+/// The checksum `crc` as written, zero if `rdbchecksum` is off.
+fn checksum(crc: u64) -> u64 {
+    match RDB_CHECKSUM.load(Ordering::Relaxed) {
+        true => crc,
+        false => 0,
     }
+}
 
+/// A whole RDB payload holding `entries`, written in memory rather than to
+/// a file, as sent to replicas.
+pub fn dump(entries: &[(String, Value)], used_mem: usize) -> Vec<u8> {
+    let mut buf = preamble(used_mem);
+    for (key, value) in entries {
+        write_entry(&mut buf, key, value);
+    }
     buf.put_u8(0xFF);
+    let crc = checksum(crc64::crc64(&buf));
+    buf.put_u64_le(crc);
+    buf.to_vec()
+}
+
+/// `value` serialized as DUMP replies it: its type and itself as written to
+/// RDB files, then the format version and a checksum of it all.
+pub fn dump_value(value: &Data) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    let value_type = value_type(value);
+    buf.put_u8(value_type);
+    write_data(&mut buf, value_type, value);
+    buf.put_u16_le(RDB_VERSION as u16);
+    let crc = crc64::crc64(&buf);
+    buf.put_u64_le(crc);
+    buf.to_vec()
+}
 
-    f.write_all(&buf)?;
+/// The value DUMP serialized into `payload`, which Redis may have written
+/// too so long as it reads the format version.
+pub fn restore_value(payload: &[u8]) -> Result<Data, String> {
+    let body = payload_body(payload).ok_or("DUMP payload version or checksum are wrong")?;
+    let bad = || "Bad data format".to_string();
+    let mut body = Bytes::copy_from_slice(body);
+    let value_type = take(&mut body, 1).map_err(|_| bad())?[0];
+    match parse_value(&mut body, value_type) {
+        Ok(Some(value)) if body.is_empty() => Ok(value),
+        _ => Err(bad()),
+    }
+}
 
+/// What `payload` holds before the format version and checksum ending it,
+/// if those are right.
+fn payload_body(payload: &[u8]) -> Option<&[u8]> {
+    let (body, footer) = payload.split_at_checked(payload.len().wrapping_sub(10))?;
+    let version = u16::from_le_bytes([footer[0], footer[1]]);
+    let crc = u64::from_le_bytes(footer[2..].try_into().unwrap());
+    // A zero checksum is what Redis writes with rdbchecksum off.
+    let checked = &payload[..payload.len() - 8];
+    if u32::from(version) > NEWEST_VERSION || (crc != 0 && crc != crc64::crc64(checked)) {
+        return None;
+    }
+    Some(body)
+}
+
+/// `libraries` serialized as FUNCTION DUMP replies them: the code of each
+/// as written to RDB files, then the format version and a checksum.
+pub fn dump_functions(libraries: &[Library]) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    for library in libraries {
+        buf.put_u8(OPCODE_FUNCTION2);
+        write_rdb_string(&mut buf, library.code.as_bytes());
+    }
+    buf.put_u16_le(RDB_VERSION as u16);
+    let crc = crc64::crc64(&buf);
+    buf.put_u64_le(crc);
+    buf.to_vec()
+}
+
+/// The code of the libraries FUNCTION DUMP serialized into `payload`.
+pub fn restore_functions(payload: &[u8]) -> Result<Vec<String>, String> {
+    let body = payload_body(payload).ok_or("payload version or checksum are wrong")?;
+    let mut body = Bytes::copy_from_slice(body);
+    let mut codes = Vec::new();
+    while body.has_remaining() {
+        if body.get_u8() != OPCODE_FUNCTION2 {
+            return Err("given type is not a function".to_string());
+        }
+        codes.push(parse_text(&mut body)?);
+    }
+    Ok(codes)
+}
+
+/// Moves the complete file at `temp` over the one at `path`, for good once
+/// this returns.
+pub fn put_in_place(temp: &Path, path: &Path) -> Result<(), io::Error> {
+    fs::rename(temp, path)?;
+    // The rename only survives a crash once the directory is synced too.
+    #[cfg(unix)]
+    {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        File::open(dir.unwrap_or(Path::new(".")))?.sync_all()?;
+    }
     Ok(())
 }
 
-fn write_rdb_string(buf: &mut BytesMut, k: &str) {
-    let key_len = k.len() as u8;
-    buf.put_u8(key_len);
-    buf.extend_from_slice(k.as_bytes());
+impl Drop for RdbWriter {
+    /// Cleans up after a save that did not finish.
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.temp);
+    }
+}
+
+/// Writes the type of `value`, `key`, then `value`, packing sets and
+/// sorted sets kept compact the way Redis packs them.
+fn write_value(buf: &mut BytesMut, key: &str, value: &Data) {
+    let value_type = value_type(value);
+    buf.put_u8(value_type);
+    write_rdb_string(buf, key.as_bytes());
+    write_data(buf, value_type, value);
 }
 
-fn parse_expiry(buf: &mut Bytes, seconds: bool) -> Result<Option<Instant>, String> {
-    let system_time = if seconds {
-        let expiry_bytes = buf.split_to(4);
-        let expiry_timestamp = u32::from_le_bytes(expiry_bytes[..].try_into().unwrap()) as u64;
-        UNIX_EPOCH + Duration::from_secs(expiry_timestamp)
+/// The type `value` is written as.
+fn value_type(value: &Data) -> u8 {
+    match value {
+        Data::String(_) => TYPE_STRING,
+        Data::Set(set) => match set.encoding() {
+            "intset" => TYPE_SET_INTSET,
+            "listpack" => TYPE_SET_LISTPACK,
+            _ => TYPE_SET,
+        },
+        Data::SortedSet(zset) => match zset.encoding() {
+            "listpack" => TYPE_ZSET_LISTPACK,
+            _ => TYPE_ZSET_2,
+        },
+        Data::Stream(_) => TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+/// Writes `value` as `value_type` says to, without its type.
+fn write_data(buf: &mut BytesMut, value_type: u8, value: &Data) {
+    match value {
+        Data::String(bytes) => write_rdb_string(buf, bytes),
+        Data::Set(set) if value_type == TYPE_SET_INTSET => {
+            let mut ints: Vec<i64> = set.iter().filter_map(|m| m.parse().ok()).collect();
+            ints.sort_unstable();
+            write_rdb_string(buf, &listpack::write_intset(&ints));
+        }
+        Data::Set(set) if value_type == TYPE_SET_LISTPACK => {
+            let elements: Vec<_> = set
+                .iter()
+                .map(|member| listpack::Element::from_bytes(member.as_bytes()))
+                .collect();
+            write_rdb_string(buf, &listpack::write_listpack(&elements));
+        }
+        Data::Set(set) => {
+            write_length(buf, set.len());
+            for member in set.iter() {
+                write_rdb_string(buf, member.as_bytes());
+            }
+        }
+        Data::SortedSet(zset) if value_type == TYPE_ZSET_LISTPACK => {
+            let elements: Vec<_> = zset
+                .iter()
+                .flat_map(|(member, score)| {
+                    [
+                        listpack::Element::from_bytes(member.as_bytes()),
+                        listpack::Element::from_bytes(score.to_string().as_bytes()),
+                    ]
+                })
+                .collect();
+            write_rdb_string(buf, &listpack::write_listpack(&elements));
+        }
+        Data::SortedSet(zset) => {
+            write_length(buf, zset.len());
+            // Highest first, so Redis can build its skiplist from the head.
+            for (member, score) in zset.iter().rev() {
+                write_rdb_string(buf, member.as_bytes());
+                buf.put_f64_le(score);
+            }
+        }
+        Data::Stream(stream) => stream::write(buf, stream),
+    }
+}
+
+fn write_number(buf: &mut BytesMut, number: u64) {
+    if number < 1 << 6 {
+        buf.put_u8(number as u8);
+    } else if number < 1 << 14 {
+        buf.put_u16(0x4000 | number as u16);
+    } else if let Ok(number) = u32::try_from(number) {
+        buf.put_u8(0x80);
+        buf.put_u32(number);
     } else {
-        let expiry_bytes = buf.split_to(8); // Extracts the first 8 bytes
-        let expiry_timestamp = u64::from_le_bytes(expiry_bytes[..].try_into().unwrap());
-        UNIX_EPOCH + Duration::from_millis(expiry_timestamp)
-    };
+        buf.put_u8(0x81);
+        buf.put_u64(number);
+    }
+}
 
-    buf.get_u8();
+fn write_length(buf: &mut BytesMut, len: usize) {
+    write_number(buf, len as u64);
+}
 
-    let now = SystemTime::now();
-    match system_time.duration_since(now) {
-        Ok(duration) => Ok(Some(Instant::now() + duration)),
-        Err(_) => Ok(Some(Instant::now() - Duration::from_secs(1))),
+/// Writes `k` with its length before it, or as the integer it spells if
+/// that takes fewer bytes and reads back the same, as Redis does.
+fn write_rdb_string(buf: &mut BytesMut, k: &[u8]) {
+    let int = std::str::from_utf8(k)
+        .ok()
+        .and_then(|k| k.parse::<i32>().ok().filter(|int| int.to_string() == k));
+    match int {
+        Some(int) if i8::try_from(int).is_ok() => {
+            buf.put_u8(0xC0 | ENC_INT8);
+            buf.put_i8(int as i8);
+        }
+        Some(int) if i16::try_from(int).is_ok() => {
+            buf.put_u8(0xC0 | ENC_INT16);
+            buf.put_i16_le(int as i16);
+        }
+        Some(int) => {
+            buf.put_u8(0xC0 | ENC_INT32);
+            buf.put_i32_le(int);
+        }
+        None => {
+            write_length(buf, k.len());
+            buf.extend_from_slice(k);
+        }
     }
 }
 
+fn parse_expiry(buf: &mut Bytes, seconds: bool) -> Result<Expiry, String> {
+    let unix_ms = if seconds {
+        take(buf, 4)?.get_u32_le() as u64 * 1000
+    } else {
+        take(buf, 8)?.get_u64_le()
+    };
+
+    // Keys that expired while the server was down are dropped on first access.
+    Ok(Expiry::from_unix_ms(unix_ms))
+}
+
+#[cfg(test)]
 mod tests {
-    // use super::*;
+    use super::*;
+    use crate::storage::{Stream, StreamId};
+
+    fn write_rdb_file(path: &str, map: HashMap<String, Value>) -> Result<(), io::Error> {
+        let mut writer = RdbWriter::create(path, 0)?;
+        for (key, value) in map.iter() {
+            writer.write_entry(key, value)?;
+        }
+        writer.finish()
+    }
 
     #[test]
     fn should_read_header_string() {
-        let tmp_file = "tmp.rdb";
+        let path = std::env::temp_dir().join(format!("header-{}.rdb", std::process::id()));
+        let path = path.to_str().unwrap();
         let given = b"REDIS\x00\x00\x00\x09\x00\x03key\x05value\xFF";
-        let mut f = File::create(tmp_file).unwrap();
+        let mut f = File::create(path).unwrap();
         f.write_all(given).unwrap();
         drop(f);
-        let result = parse_rdb_file(tmp_file).unwrap();
+        let result = parse_rdb_file(path);
+        std::fs::remove_file(path).unwrap();
+        let result = result.unwrap();
         let result = result["key"].clone();
-        assert_eq!(result.value, "value".to_string());
+        assert_eq!(result.value, Data::String(b"value".to_vec()));
     }
 
     #[test]
     fn should_write_hash() {
-        let tmp_file = "tmp.rdb";
+        let path = std::env::temp_dir().join(format!("hash-{}.rdb", std::process::id()));
+        let path = path.to_str().unwrap();
         let mut given = HashMap::new();
         given.insert(
             "foo".to_string(),
             Value {
-                value: "bar".to_string(),
+                value: Data::String(b"bar".to_vec()),
                 expiry: None,
             },
         );
 
-        let result = write_rdb_file(tmp_file, given);
+        let result = write_rdb_file(path, given);
         assert!(result.is_ok());
 
-        let mut f = File::open(tmp_file).unwrap();
+        let mut f = File::open(path).unwrap();
         let mut s = Vec::new();
-        f.read_to_end(&mut s); // Should match -> REDIS\x00\x00\x00\x09\x00\x03key\x05value\xFF
+        f.read_to_end(&mut s).unwrap();
         drop(f);
+        let read = parse_rdb_file(path);
+        std::fs::remove_file(path).unwrap();
 
-        let expected = b"REDIS\x00\x00\x00\x09\xFArandom\xFE\x00\x03key\x05value\xFF";
-        assert_eq!(s, expected);
+        // The header, aux fields and database selector come before the
+        // entry, the end of file marker and checksum after it.
+        assert!(s.starts_with(b"REDIS0011\xFA"));
+        let entry = b"\x00\x03foo\x03bar\xFF";
+        let at = s.windows(entry.len()).position(|window| window == entry);
+        assert_eq!(at, Some(s.len() - entry.len() - 8));
+        assert_eq!(read.unwrap()["foo"].value, Data::String(b"bar".to_vec()));
     }
 
     #[test]
     fn should_read_header_version() {
         let given = b"REDIS\x00\x00\x00\x09";
         let mut given = Bytes::from(given.as_slice());
-        let expected = RdbHeader { version: 9 };
         let result = parse_rdb_header(&mut given);
 
         assert!(result.is_ok());
-        assert_eq!(result.unwrap().version, expected.version);
+        assert_eq!(result.unwrap(), 9);
+    }
+
+    #[test]
+    fn should_describe_the_writer_in_aux_fields() {
+        let path = std::env::temp_dir().join(format!("aux-{}.rdb", std::process::id()));
+        let path = path.to_str().unwrap();
+        RdbWriter::create(path, 1 << 40).unwrap().finish().unwrap();
+        let written = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let mut given = Bytes::from(written);
+        assert_eq!(parse_rdb_header(&mut given).unwrap(), 11);
+        let aux = parse_rbd_metadata(&mut given).unwrap();
+        let names: Vec<&str> = aux.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(names, ["redis-ver", "redis-bits", "ctime", "used-mem"]);
+        assert_eq!(aux[0].1, REDIS_VERSION);
+        assert_eq!(aux[1].1, "64");
+        assert_eq!(aux[3].1, (1u64 << 40).to_string());
+
+        let mut given = Bytes::from_static(b"REDIS0013");
+        assert!(parse_rdb_header(&mut given).is_err());
     }
 
     #[test]
@@ -261,11 +955,237 @@ mod tests {
         let mut given = Bytes::from(given.as_slice());
         let expected = RdbEntry {
             key: "key".to_string(),
-            value: "value".to_string(),
+            value: Some(Data::String(b"value".to_vec())),
+            expiry: None,
         };
         let result = parse_rdb_entry(&mut given).unwrap().unwrap();
 
         assert_eq!(result.key, expected.key);
         assert_eq!(result.value, expected.value);
     }
+
+    #[test]
+    fn should_read_and_write_every_length_encoding() {
+        for (len, size) in [(63, 1), (64, 2), (16383, 2), (16384, 5), (1 << 32, 9)] {
+            let mut buf = BytesMut::new();
+            write_length(&mut buf, len);
+            assert_eq!(buf.len(), size);
+            let mut buf = buf.freeze();
+            assert_eq!(parse_length(&mut buf), Ok(Length::Len(len as u64)));
+            assert!(buf.is_empty());
+        }
+
+        let long = "v".repeat(300);
+        for value in [
+            "0",
+            "-128",
+            "12345",
+            "-2147483648",
+            "2147483648",
+            "007",
+            &long,
+        ] {
+            let mut buf = BytesMut::new();
+            write_rdb_string(&mut buf, value.as_bytes());
+            assert_eq!(
+                parse_string(&mut buf.freeze()),
+                Ok(value.as_bytes().to_vec())
+            );
+        }
+        let mut buf = BytesMut::new();
+        write_rdb_string(&mut buf, b"12345");
+        assert_eq!(&buf[..], b"\xC1\x39\x30");
+
+        // Ten bytes of "a": one literal, then a run copying it nine times.
+        let mut given = Bytes::from_static(b"\xC3\x05\x0A\x00a\xE0\x00\x00");
+        assert_eq!(parse_string(&mut given), Ok(b"a".repeat(10)));
+        let mut given = Bytes::from_static(b"\x81\x00");
+        assert!(parse_string(&mut given).is_err());
+    }
+
+    #[test]
+    fn should_read_back_every_type() {
+        let id = |ms| StreamId { ms, seq: 0 };
+        let mut stream = Stream::new();
+        stream.add(id(1), vec![("field".to_string(), "value".to_string())]);
+        stream.add(id(2), vec![("field".to_string(), "12".to_string())]);
+        stream.add(id(3), vec![]);
+        for ms in 4..250 {
+            stream.add(id(ms), vec![("other".to_string(), ms.to_string())]);
+        }
+        stream.trim_max_len(240, None);
+        stream.add(id(300), vec![]);
+        stream.create_group("group", StreamId::MIN);
+        stream.read_group("group", "alice", Some(2), false, 10);
+        stream.read_group("group", "bob", Some(1), false, 20);
+        stream.create_group("idle", id(7));
+        let numbers = |range: std::ops::Range<i64>| range.map(|n| n.to_string());
+        let zset = |len: usize| {
+            let mut zset = SortedSet::new();
+            for n in 0..len {
+                zset.insert(format!("m{}", n), n as f64 / 4.0 - 100.0);
+            }
+            zset.insert("inf".to_string(), f64::INFINITY);
+            zset
+        };
+        let values = [
+            Data::String(b"\x00bytes\xff".to_vec()),
+            Data::Set(numbers(-70000..-69990).collect()),
+            Data::Set(numbers(0..3).chain(["a".to_string()]).collect()),
+            Data::Set(numbers(0..600).collect()),
+            Data::SortedSet(zset(3)),
+            Data::SortedSet(zset(300)),
+            Data::Stream(stream),
+        ];
+        for value in values {
+            let mut buf = BytesMut::new();
+            write_value(&mut buf, "key", &value);
+            let mut written = buf.freeze();
+            let value_type = written.get_u8();
+            assert_eq!(parse_text(&mut written).unwrap(), "key");
+            assert_eq!(parse_value(&mut written, value_type), Ok(Some(value)));
+            assert!(written.is_empty());
+        }
+
+        let mut given = Bytes::from_static(b"\x02\x01a\x011\x01b\xFE");
+        let mut expected = SortedSet::new();
+        expected.insert("a".to_string(), 1.0);
+        expected.insert("b".to_string(), f64::INFINITY);
+        let parsed = parse_value(&mut given, TYPE_ZSET);
+        assert_eq!(parsed, Ok(Some(Data::SortedSet(expected))));
+    }
+
+    #[test]
+    fn should_load_what_redis_writes() {
+        let mut buf = BytesMut::from(&b"REDIS0012"[..]);
+        buf.put_u8(OPCODE_AUX);
+        write_rdb_string(&mut buf, b"redis-ver");
+        write_rdb_string(&mut buf, b"7.4.0");
+        // Data a module saved of its own: an unsigned 2, then "abc".
+        buf.put_u8(OPCODE_MODULE_AUX);
+        write_number(&mut buf, 1 << 40);
+        buf.extend_from_slice(b"\x02\x02\x05\x03abc\x00");
+        buf.put_u8(OPCODE_FUNCTION2);
+        write_rdb_string(
+            &mut buf,
+            b"#!lua name=rdblib\nredis.register_function('rdbfn', function() end)",
+        );
+        buf.extend_from_slice(&[OPCODE_SELECTDB, 0, OPCODE_RESIZEDB, 3, 0]);
+        buf.extend_from_slice(&[OPCODE_SLOT_INFO, 0, 3, 0]);
+        buf.put_u8(OPCODE_IDLE);
+        write_number(&mut buf, 500);
+        buf.extend_from_slice(&[OPCODE_FREQ, 10]);
+        write_value(&mut buf, "string", &Data::String(b"value".to_vec()));
+        // A list and a hash, which there are none of here.
+        buf.put_u8(TYPE_LIST_QUICKLIST_2);
+        write_rdb_string(&mut buf, b"list");
+        buf.extend_from_slice(&[1, 2]);
+        let node = listpack::write_listpack(&[listpack::Element::Int(1)]);
+        write_rdb_string(&mut buf, &node);
+        buf.put_u8(TYPE_HASH);
+        write_rdb_string(&mut buf, b"hash");
+        buf.extend_from_slice(b"\x01\x01f\x01v");
+        buf.extend_from_slice(&[OPCODE_SELECTDB, 1]);
+        write_value(&mut buf, "other", &Data::String(b"value".to_vec()));
+        buf.put_u8(OPCODE_EOF);
+        let crc = crc64::crc64(&buf);
+        buf.put_u64_le(crc);
+
+        let path = std::env::temp_dir().join(format!("redis-{}.rdb", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, &buf).unwrap();
+        let loaded = parse_rdb_file(path);
+        std::fs::write(path, b"REDIS0011\xFE\x00\x63\x01k").unwrap();
+        let unknown = parse_rdb_file(path);
+        std::fs::remove_file(path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.keys().collect::<Vec<_>>(), ["string"]);
+        assert_eq!(loaded["string"].value, Data::String(b"value".to_vec()));
+        assert!(FUNCTIONS.find("rdbfn").is_some());
+        assert_eq!(unknown.unwrap_err().to_string(), "unknown value type 99");
+    }
+
+    #[test]
+    fn should_checksum_written_files() {
+        let path = std::env::temp_dir().join(format!("checksum-{}.rdb", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_rdb_file(path, HashMap::new()).unwrap();
+        let written = std::fs::read(path).unwrap();
+        let (contents, crc) = written.split_at(written.len() - 8);
+        assert_eq!(contents.last(), Some(&0xFF));
+        assert_eq!(crc, crc64::crc64(contents).to_le_bytes());
+        let dumped = dump(&[], 0);
+        let (contents, crc) = dumped.split_at(dumped.len() - 8);
+        assert_eq!(crc, crc64::crc64(contents).to_le_bytes());
+        assert!(parse_rdb(&mut Bytes::from(dumped)).unwrap().is_empty());
+
+        let mut given =
+            b"REDIS\x00\x00\x00\x09\xFE\x00\xFB\x01\x00\x00\x03key\x05value\xFF".to_vec();
+        given.extend_from_slice(&crc64::crc64(&given).to_le_bytes());
+        std::fs::write(path, &given).unwrap();
+        assert_eq!(parse_rdb_file(path).unwrap().len(), 1);
+        given[20] = b'V';
+        std::fs::write(path, &given).unwrap();
+        let err = parse_rdb_file(path).unwrap_err();
+        assert!(err.to_string().starts_with("Wrong RDB checksum"));
+        // A zero checksum was never computed.
+        let end = given.len() - 8;
+        given[end..].fill(0);
+        std::fs::write(path, &given).unwrap();
+        assert!(parse_rdb_file(path).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_keep_expiries_as_timestamps() {
+        let path = std::env::temp_dir().join(format!("expiry-{}.rdb", std::process::id()));
+        let path = path.to_str().unwrap();
+        let unix_ms: u64 = 4_102_444_800_000;
+        let given = HashMap::from([(
+            "key".to_string(),
+            Value {
+                value: Data::String(b"value".to_vec()),
+                expiry: Some(Expiry::from_unix_ms(unix_ms)),
+            },
+        )]);
+        write_rdb_file(path, given).unwrap();
+        let written = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let mut entry = vec![0xFC];
+        entry.extend_from_slice(&unix_ms.to_le_bytes());
+        entry.extend_from_slice(b"\x00\x03key\x05value");
+        assert!(written.windows(entry.len()).any(|window| window == entry));
+
+        let mut given = Bytes::from(entry);
+        let parsed = parse_rdb_entry(&mut given).unwrap().unwrap();
+        assert_eq!(parsed.expiry, Some(Expiry::from_unix_ms(unix_ms)));
+
+        let mut given = Bytes::from_static(b"\xFD\x00\xE1\xF5\x05\x00\x03key\x05value");
+        let parsed = parse_rdb_entry(&mut given).unwrap().unwrap();
+        assert_eq!(parsed.expiry, Some(Expiry::from_unix_ms(100_000_000_000)));
+    }
+
+    #[test]
+    fn should_restore_what_dump_serialized() {
+        let mut zset = SortedSet::new();
+        zset.insert("member".to_string(), 1.5);
+        for value in [
+            Data::String(b"\x00bytes\xff".to_vec()),
+            Data::Set(["a".to_string(), "1".to_string()].into_iter().collect()),
+            Data::SortedSet(zset),
+        ] {
+            let payload = dump_value(&value);
+            assert_eq!(restore_value(&payload), Ok(value));
+        }
+
+        // The integer 10 as Redis 7.0 dumps it.
+        let redis = b"\x00\xc0\n\n\x00n\x9fWE\x0e\xaec\xbb";
+        assert_eq!(restore_value(redis), Ok(Data::String(b"10".to_vec())));
+        let mut corrupt = redis.to_vec();
+        corrupt[2] = b'\x0b';
+        assert!(restore_value(&corrupt).unwrap_err().contains("checksum"));
+        assert!(restore_value(b"\x00").is_err());
+    }
 }
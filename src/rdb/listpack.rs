@@ -0,0 +1,281 @@
+//! The packed blobs Redis keeps small collections in, which RDB files carry
+//! as plain strings: listpacks, intsets of sorted integers, and the
+//! ziplists versions before 7 used where they now use listpacks.
+
+const TRUNCATED: &str = "packed collection truncated";
+
+/// An element of a listpack or ziplist, which keep integers as such.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Element {
+    Int(i64),
+    Str(Vec<u8>),
+}
+
+impl Element {
+    /// `bytes` as an integer if they spell one the way Redis would write
+    /// it back, as Redis packs those.
+    pub fn from_bytes(bytes: &[u8]) -> Element {
+        let int = std::str::from_utf8(bytes).ok().and_then(|text| {
+            text.parse::<i64>()
+                .ok()
+                .filter(|int| int.to_string() == text)
+        });
+        match int {
+            Some(int) => Element::Int(int),
+            None => Element::Str(bytes.to_vec()),
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Element::Int(int) => Some(*int),
+            Element::Str(bytes) => std::str::from_utf8(bytes).ok()?.parse().ok(),
+        }
+    }
+
+    pub fn into_string(self) -> String {
+        match self {
+            Element::Int(int) => int.to_string(),
+            Element::Str(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        }
+    }
+}
+
+fn slice(blob: &[u8], from: usize, len: usize) -> Result<&[u8], String> {
+    blob.get(from..from + len)
+        .ok_or_else(|| TRUNCATED.to_string())
+}
+
+/// A little endian integer `N` bytes wide, sign extended.
+fn int_le<const N: usize>(blob: &[u8], from: usize) -> Result<i64, String> {
+    let bytes = slice(blob, from, N)?;
+    let mut wide = [0; 8];
+    wide[8 - N..].copy_from_slice(bytes);
+    // Shifted back down from the top so the sign carries.
+    Ok(i64::from_le_bytes(wide) >> (8 * (8 - N)))
+}
+
+/// How many bytes the length of a `len` bytes listpack entry takes after
+/// it, seven bits to a byte.
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// The elements of a listpack: a header of its size and element count,
+/// then each element's encoding, data and length, then 0xFF.
+pub fn read_listpack(blob: &[u8]) -> Result<Vec<Element>, String> {
+    let mut elements = Vec::new();
+    let mut at = 6;
+    loop {
+        let first = *blob.get(at).ok_or(TRUNCATED)?;
+        let (element, len) = match first {
+            0xFF => return Ok(elements),
+            0x00..=0x7F => (Element::Int(first as i64), 1),
+            0x80..=0xBF => {
+                let len = (first & 0x3F) as usize;
+                (Element::Str(slice(blob, at + 1, len)?.to_vec()), 1 + len)
+            }
+            0xC0..=0xDF => {
+                let int = ((first as i64 & 0x1F) << 8) | slice(blob, at + 1, 1)?[0] as i64;
+                // Thirteen bits, sign extended.
+                (Element::Int((int << 51) >> 51), 2)
+            }
+            0xE0..=0xEF => {
+                let len = ((first as usize & 0x0F) << 8) | slice(blob, at + 1, 1)?[0] as usize;
+                (Element::Str(slice(blob, at + 2, len)?.to_vec()), 2 + len)
+            }
+            0xF0 => {
+                let len = int_le::<4>(blob, at + 1)? as u32 as usize;
+                (Element::Str(slice(blob, at + 5, len)?.to_vec()), 5 + len)
+            }
+            0xF1 => (Element::Int(int_le::<2>(blob, at + 1)?), 3),
+            0xF2 => (Element::Int(int_le::<3>(blob, at + 1)?), 4),
+            0xF3 => (Element::Int(int_le::<4>(blob, at + 1)?), 5),
+            0xF4 => (Element::Int(int_le::<8>(blob, at + 1)?), 9),
+            _ => return Err(format!("unknown listpack encoding {:#04x}", first)),
+        };
+        elements.push(element);
+        at += len + backlen_size(len);
+    }
+}
+
+/// Packs `elements` into a listpack, integers in as few bytes as they fit.
+pub fn write_listpack(elements: &[Element]) -> Vec<u8> {
+    let mut blob = vec![0; 6];
+    for element in elements {
+        let start = blob.len();
+        match element {
+            Element::Int(int @ 0..=127) => blob.push(*int as u8),
+            Element::Int(int @ -4096..=4095) => {
+                let int = *int as u16 & 0x1FFF;
+                blob.extend_from_slice(&[0xC0 | (int >> 8) as u8, int as u8]);
+            }
+            Element::Int(int) => {
+                let (encoding, width) = match *int {
+                    int if i16::try_from(int).is_ok() => (0xF1, 2),
+                    int if (-(1 << 23)..1 << 23).contains(&int) => (0xF2, 3),
+                    int if i32::try_from(int).is_ok() => (0xF3, 4),
+                    _ => (0xF4, 8),
+                };
+                blob.push(encoding);
+                blob.extend_from_slice(&int.to_le_bytes()[..width]);
+            }
+            Element::Str(bytes) => {
+                let len = bytes.len();
+                if len < 64 {
+                    blob.push(0x80 | len as u8);
+                } else if len < 4096 {
+                    blob.extend_from_slice(&[0xE0 | (len >> 8) as u8, len as u8]);
+                } else {
+                    blob.push(0xF0);
+                    blob.extend_from_slice(&(len as u32).to_le_bytes());
+                }
+                blob.extend_from_slice(bytes);
+            }
+        }
+        let len = blob.len() - start;
+        let size = backlen_size(len);
+        for at in 0..size {
+            let bits = ((len >> (7 * (size - 1 - at))) & 0x7F) as u8;
+            blob.push(if at == 0 { bits } else { bits | 0x80 });
+        }
+    }
+    blob.push(0xFF);
+    let total = blob.len() as u32;
+    blob[..4].copy_from_slice(&total.to_le_bytes());
+    let count = elements.len().min(u16::MAX as usize) as u16;
+    blob[4..6].copy_from_slice(&count.to_le_bytes());
+    blob
+}
+
+/// The integers of an intset: how wide each is, how many there are, then
+/// each in ascending order.
+pub fn read_intset(blob: &[u8]) -> Result<Vec<i64>, String> {
+    let width = int_le::<4>(blob, 0)? as usize;
+    let len = int_le::<4>(blob, 4)? as u32 as usize;
+    let read = match width {
+        2 => int_le::<2>,
+        4 => int_le::<4>,
+        8 => int_le::<8>,
+        _ => return Err(format!("unknown intset width {}", width)),
+    };
+    (0..len).map(|at| read(blob, 8 + at * width)).collect()
+}
+
+/// Packs `ints`, which must be sorted, into an intset as wide as the
+/// widest of them needs.
+pub fn write_intset(ints: &[i64]) -> Vec<u8> {
+    let width = if ints.iter().all(|&int| i16::try_from(int).is_ok()) {
+        2
+    } else if ints.iter().all(|&int| i32::try_from(int).is_ok()) {
+        4
+    } else {
+        8
+    };
+    let mut blob = Vec::with_capacity(8 + ints.len() * width);
+    blob.extend_from_slice(&(width as u32).to_le_bytes());
+    blob.extend_from_slice(&(ints.len() as u32).to_le_bytes());
+    for int in ints {
+        blob.extend_from_slice(&int.to_le_bytes()[..width]);
+    }
+    blob
+}
+
+/// The elements of a ziplist: a header of its size, where its last entry
+/// is and how many there are, then each entry's previous entry length,
+/// encoding and data, then 0xFF.
+pub fn read_ziplist(blob: &[u8]) -> Result<Vec<Element>, String> {
+    let mut elements = Vec::new();
+    let mut at = 10;
+    loop {
+        match *blob.get(at).ok_or(TRUNCATED)? {
+            0xFF => return Ok(elements),
+            0xFE => at += 5,
+            _ => at += 1,
+        }
+        let first = *blob.get(at).ok_or(TRUNCATED)?;
+        let (element, len) = match first {
+            0x00..=0x3F => {
+                let len = first as usize;
+                (Element::Str(slice(blob, at + 1, len)?.to_vec()), 1 + len)
+            }
+            0x40..=0x7F => {
+                let len = ((first as usize & 0x3F) << 8) | slice(blob, at + 1, 1)?[0] as usize;
+                (Element::Str(slice(blob, at + 2, len)?.to_vec()), 2 + len)
+            }
+            0x80 => {
+                let len = u32::from_be_bytes(slice(blob, at + 1, 4)?.try_into().unwrap());
+                let len = len as usize;
+                (Element::Str(slice(blob, at + 5, len)?.to_vec()), 5 + len)
+            }
+            0xC0 => (Element::Int(int_le::<2>(blob, at + 1)?), 3),
+            0xD0 => (Element::Int(int_le::<4>(blob, at + 1)?), 5),
+            0xE0 => (Element::Int(int_le::<8>(blob, at + 1)?), 9),
+            0xF0 => (Element::Int(int_le::<3>(blob, at + 1)?), 4),
+            0xFE => (Element::Int(int_le::<1>(blob, at + 1)?), 2),
+            // The integers 0 to 12 right in the encoding.
+            0xF1..=0xFD => (Element::Int((first & 0x0F) as i64 - 1), 1),
+            _ => return Err(format!("unknown ziplist encoding {:#04x}", first)),
+        };
+        elements.push(element);
+        at += len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_read_back_packed_elements() {
+        let elements: Vec<Element> = [
+            &b"0"[..],
+            b"127",
+            b"-4096",
+            b"4095",
+            b"-32768",
+            b"8388607",
+            b"-2147483648",
+            b"9223372036854775807",
+            b"007",
+            b"",
+        ]
+        .into_iter()
+        .map(Element::from_bytes)
+        .chain([
+            Element::Str(vec![b'x'; 100]),
+            Element::Str(vec![b'y'; 5000]),
+        ])
+        .collect();
+        let blob = write_listpack(&elements);
+        assert_eq!(
+            blob.len(),
+            u32::from_le_bytes(blob[..4].try_into().unwrap()) as usize
+        );
+        assert_eq!(read_listpack(&blob), Ok(elements));
+        assert!(read_listpack(&blob[..blob.len() - 1]).is_err());
+
+        let ints = [-70000, -1, 0, 40000];
+        let blob = write_intset(&ints);
+        assert_eq!(&blob[..4], 4u32.to_le_bytes());
+        assert_eq!(read_intset(&blob), Ok(ints.to_vec()));
+
+        // "a", 12 and -2 as Redis 6 packs them.
+        let blob = b"\x13\x00\x00\x00\x0F\x00\x00\x00\x03\x00\x00\x01a\x03\xFD\x02\xFE\xFE\xFF";
+        let elements = read_ziplist(blob).unwrap();
+        assert_eq!(
+            elements,
+            [
+                Element::Str(b"a".to_vec()),
+                Element::Int(12),
+                Element::Int(-2)
+            ]
+        );
+    }
+}
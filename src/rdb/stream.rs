@@ -0,0 +1,251 @@
+//! Streams as RDB files keep them: entries packed into listpacks, each
+//! under the ID its entries are counted from, then the stream's IDs and its
+//! consumer groups with what they delivered to whom.
+
+use std::collections::BTreeMap;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use super::{
+    listpack::{self, Element},
+    parse_len, parse_number, parse_string, take, write_length, write_number, write_rdb_string,
+    TYPE_STREAM_LISTPACKS_2, TYPE_STREAM_LISTPACKS_3,
+};
+use crate::storage::{PendingEntry, Stream, StreamId};
+
+/// Most entries packed together, Redis's default `stream-node-max-entries`.
+const NODE_ENTRIES: usize = 100;
+
+const FLAG_DELETED: i64 = 1;
+/// The entry has the same fields as the first of its listpack, so only
+/// its values are kept.
+const FLAG_SAME_FIELDS: i64 = 2;
+
+/// An ID as the 16 big endian bytes listpacks are keyed by and pending
+/// entries are written as.
+fn id_bytes(id: StreamId) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&id.ms.to_be_bytes());
+    bytes[8..].copy_from_slice(&id.seq.to_be_bytes());
+    bytes
+}
+
+fn parse_id_bytes(bytes: &[u8]) -> Result<StreamId, String> {
+    let bytes: [u8; 16] = bytes.try_into().map_err(|_| "invalid stream ID")?;
+    let (ms, seq) = bytes.split_at(8);
+    Ok(StreamId {
+        ms: u64::from_be_bytes(ms.try_into().unwrap()),
+        seq: u64::from_be_bytes(seq.try_into().unwrap()),
+    })
+}
+
+fn write_id(buf: &mut BytesMut, id: StreamId) {
+    write_number(buf, id.ms);
+    write_number(buf, id.seq);
+}
+
+fn parse_id(buffer: &mut Bytes) -> Result<StreamId, String> {
+    Ok(StreamId {
+        ms: parse_number(buffer)?,
+        seq: parse_number(buffer)?,
+    })
+}
+
+/// Writes `stream` the way Redis 7.2 does, as `TYPE_STREAM_LISTPACKS_3`.
+/// What was deleted from it isn't tracked, so it is said to have had no
+/// more entries than it has.
+pub fn write(buf: &mut BytesMut, stream: &Stream) {
+    let entries: Vec<_> = stream.range(StreamId::MIN, StreamId::MAX).collect();
+    write_length(buf, entries.len().div_ceil(NODE_ENTRIES));
+    for node in entries.chunks(NODE_ENTRIES) {
+        let (&master, master_fields) = node[0];
+        let mut elements = vec![
+            Element::Int(node.len() as i64),
+            Element::Int(0),
+            Element::Int(master_fields.len() as i64),
+        ];
+        for (field, _) in master_fields {
+            elements.push(Element::from_bytes(field.as_bytes()));
+        }
+        elements.push(Element::Int(0));
+        for &(&id, fields) in node {
+            let same_fields = fields.len() == master_fields.len()
+                && fields.iter().zip(master_fields).all(|(a, b)| a.0 == b.0);
+            elements.push(Element::Int(if same_fields { FLAG_SAME_FIELDS } else { 0 }));
+            elements.push(Element::Int(id.ms.wrapping_sub(master.ms) as i64));
+            elements.push(Element::Int(id.seq.wrapping_sub(master.seq) as i64));
+            if same_fields {
+                for (_, value) in fields {
+                    elements.push(Element::from_bytes(value.as_bytes()));
+                }
+            } else {
+                elements.push(Element::Int(fields.len() as i64));
+                for (field, value) in fields {
+                    elements.push(Element::from_bytes(field.as_bytes()));
+                    elements.push(Element::from_bytes(value.as_bytes()));
+                }
+            }
+            // How many elements the entry took, for reading backwards.
+            let count = match same_fields {
+                true => fields.len() + 3,
+                false => fields.len() * 2 + 4,
+            };
+            elements.push(Element::Int(count as i64));
+        }
+        write_rdb_string(buf, &id_bytes(master));
+        write_rdb_string(buf, &listpack::write_listpack(&elements));
+    }
+
+    write_length(buf, entries.len());
+    write_id(buf, stream.last_id());
+    write_id(buf, entries.first().map_or(StreamId::MIN, |(&id, _)| id));
+    // The highest ID deleted, unknown.
+    write_id(buf, StreamId::MIN);
+    // Entries ever added.
+    write_length(buf, entries.len());
+
+    let groups: Vec<_> = stream.groups().collect();
+    write_length(buf, groups.len());
+    for (name, group) in groups {
+        write_rdb_string(buf, name.as_bytes());
+        write_id(buf, group.last_delivered());
+        // Entries the group read, unknown.
+        write_number(buf, u64::MAX);
+        write_length(buf, group.pending().len());
+        for (&id, pending) in group.pending() {
+            buf.extend_from_slice(&id_bytes(id));
+            buf.put_u64_le(pending.delivered_ms);
+            write_number(buf, pending.delivery_count);
+        }
+        let consumers: Vec<_> = group.consumers().collect();
+        write_length(buf, consumers.len());
+        for consumer in consumers {
+            write_rdb_string(buf, consumer.as_bytes());
+            let seen_ms = group.seen_ms(consumer).unwrap_or_default();
+            // Last seen, then last active, which aren't told apart here.
+            buf.put_u64_le(seen_ms);
+            buf.put_u64_le(seen_ms);
+            let pending: Vec<_> = group
+                .pending()
+                .iter()
+                .filter(|(_, pending)| pending.consumer == consumer)
+                .collect();
+            write_length(buf, pending.len());
+            for (&id, _) in pending {
+                buf.extend_from_slice(&id_bytes(id));
+            }
+        }
+    }
+}
+
+fn next(elements: &mut impl Iterator<Item = Element>) -> Result<Element, String> {
+    elements
+        .next()
+        .ok_or_else(|| "stream listpack truncated".to_string())
+}
+
+fn next_int(elements: &mut impl Iterator<Item = Element>) -> Result<i64, String> {
+    next(elements)?
+        .as_int()
+        .ok_or_else(|| "expected an integer in stream listpack".to_string())
+}
+
+fn next_string(elements: &mut impl Iterator<Item = Element>) -> Result<String, String> {
+    Ok(next(elements)?.into_string())
+}
+
+/// Adds the entries of a listpack keyed by `master` to `stream`.
+fn parse_node(stream: &mut Stream, master: StreamId, blob: &[u8]) -> Result<(), String> {
+    let elements = &mut listpack::read_listpack(blob)?.into_iter();
+    let count = next_int(elements)? + next_int(elements)?;
+    let master_fields = (0..next_int(elements)?)
+        .map(|_| next_string(elements))
+        .collect::<Result<Vec<_>, _>>()?;
+    next_int(elements)?;
+    for _ in 0..count {
+        let flags = next_int(elements)?;
+        let id = StreamId {
+            ms: master.ms.wrapping_add(next_int(elements)? as u64),
+            seq: master.seq.wrapping_add(next_int(elements)? as u64),
+        };
+        let fields = if flags & FLAG_SAME_FIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|field| Ok((field.clone(), next_string(elements)?)))
+                .collect::<Result<Vec<_>, String>>()?
+        } else {
+            (0..next_int(elements)?)
+                .map(|_| Ok((next_string(elements)?, next_string(elements)?)))
+                .collect::<Result<Vec<_>, String>>()?
+        };
+        next_int(elements)?;
+        if flags & FLAG_DELETED == 0 {
+            stream.add(id, fields);
+        }
+    }
+    Ok(())
+}
+
+/// Reads a stream written as `value_type`, any of the three versions of
+/// `TYPE_STREAM_LISTPACKS`.
+pub fn parse(buffer: &mut Bytes, value_type: u8) -> Result<Stream, String> {
+    let mut stream = Stream::new();
+    for _ in 0..parse_len(buffer)? {
+        let master = parse_id_bytes(&parse_string(buffer)?)?;
+        parse_node(&mut stream, master, &parse_string(buffer)?)?;
+    }
+    parse_len(buffer)?;
+    stream.set_last_id(parse_id(buffer)?);
+    if value_type >= TYPE_STREAM_LISTPACKS_2 {
+        // The first and highest deleted IDs, and how many entries were
+        // ever added.
+        parse_id(buffer)?;
+        parse_id(buffer)?;
+        parse_number(buffer)?;
+    }
+
+    for _ in 0..parse_len(buffer)? {
+        let name = String::from_utf8_lossy(&parse_string(buffer)?).into_owned();
+        let last_delivered = parse_id(buffer)?;
+        if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            parse_number(buffer)?;
+        }
+        if !stream.create_group(&name, last_delivered) {
+            return Err(format!("duplicate consumer group {}", name));
+        }
+        let group = stream.group_mut(&name).unwrap();
+
+        // Delivery times and counts by ID, until the consumers they went
+        // to come up.
+        let mut deliveries = BTreeMap::new();
+        for _ in 0..parse_len(buffer)? {
+            let id = parse_id_bytes(&take(buffer, 16)?)?;
+            let delivered_ms = take(buffer, 8)?.get_u64_le();
+            deliveries.insert(id, (delivered_ms, parse_number(buffer)?));
+        }
+        for _ in 0..parse_len(buffer)? {
+            let consumer = String::from_utf8_lossy(&parse_string(buffer)?).into_owned();
+            let seen_ms = take(buffer, 8)?.get_u64_le();
+            if value_type >= TYPE_STREAM_LISTPACKS_3 {
+                take(buffer, 8)?;
+            }
+            group.create_consumer(&consumer, seen_ms);
+            for _ in 0..parse_len(buffer)? {
+                let id = parse_id_bytes(&take(buffer, 16)?)?;
+                let (delivered_ms, delivery_count) = deliveries
+                    .remove(&id)
+                    .ok_or("pending entry of a consumer missing from its group")?;
+                let entry = PendingEntry {
+                    consumer: consumer.clone(),
+                    delivered_ms,
+                    delivery_count,
+                };
+                group.insert_pending(id, entry);
+            }
+        }
+        if !deliveries.is_empty() {
+            return Err("pending entry of a group without a consumer".into());
+        }
+    }
+    Ok(stream)
+}
@@ -0,0 +1,345 @@
+//! Where this server stands in replication, as `INFO replication` reports
+//! it: the master it follows if a replica, the replicas following it if
+//! a master.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use rand::Rng;
+use tokio::{
+    sync::{mpsc, Notify},
+    time::sleep_until,
+};
+
+tokio::task_local! {
+    /// Set on a replica while it applies a write its master sent.
+    static FROM_MASTER: ();
+}
+
+use crate::resp::Entry;
+
+/// Bytes at the end of the replication stream kept for replicas that
+/// reconnect to go on from, Redis's default repl-backlog-size.
+const BACKLOG_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub struct Replication {
+    /// The host and port of the master followed, if a replica.
+    master: Mutex<Option<(String, u16)>>,
+    /// Whether the dataset was loaded from the master, which is streaming
+    /// its writes.
+    pub link_up: AtomicBool,
+    /// Whether clients other than the master may not write to a replica,
+    /// set with replica-read-only.
+    pub read_only: AtomicBool,
+    /// How far into the replication stream the dataset is: bytes of writes
+    /// sent to replicas on a master, received from the master on a replica.
+    pub offset: AtomicU64,
+    /// Empty until first needed.
+    replid: Mutex<String>,
+    replicas: Mutex<Vec<Replica>>,
+    /// The last `BACKLOG_SIZE` bytes of the stream, ending at `offset`.
+    /// Locked with `replicas` only.
+    backlog: Mutex<VecDeque<u8>>,
+    /// Notified whenever a replica acknowledges an offset.
+    acked: Notify,
+}
+
+/// A replica being sent this server's writes.
+#[derive(Debug)]
+struct Replica {
+    id: u64,
+    /// Where the replica serves its own clients.
+    addr: SocketAddr,
+    feed: mpsc::UnboundedSender<Bytes>,
+    /// The offset the replica last acknowledged having.
+    acked: u64,
+}
+
+pub static REPLICATION: Replication = Replication {
+    master: Mutex::new(None),
+    link_up: AtomicBool::new(false),
+    read_only: AtomicBool::new(true),
+    offset: AtomicU64::new(0),
+    replid: Mutex::new(String::new()),
+    replicas: Mutex::new(Vec::new()),
+    backlog: Mutex::new(VecDeque::new()),
+    acked: Notify::const_new(),
+};
+
+impl Replication {
+    /// Becomes a replica of the master at `host` and `port`, or a master
+    /// with `None`. Replicas of a new replica are let go, as the dataset they
+    /// copied is about to be replaced.
+    pub fn set_master(&self, master: Option<(String, u16)>) {
+        if master.is_some() {
+            self.replicas.lock().unwrap().clear();
+        }
+        *self.master.lock().unwrap() = master;
+        self.link_up.store(false, Ordering::Relaxed);
+    }
+
+    pub fn master(&self) -> Option<(String, u16)> {
+        self.master.lock().unwrap().clone()
+    }
+
+    /// Runs `applied`, a write the master sent, as such.
+    pub async fn from_master<F: Future>(&self, applied: F) -> F::Output {
+        FROM_MASTER.scope((), applied).await
+    }
+
+    /// Whether what runs is a write the master sent.
+    pub fn applying(&self) -> bool {
+        FROM_MASTER.try_with(|_| ()).is_ok()
+    }
+
+    /// Whether writes from clients are refused, as this is a read only
+    /// replica.
+    pub fn refuses_writes(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed) && self.master.lock().unwrap().is_some()
+    }
+
+    /// The 40 hex digits naming the history of this dataset: picked at
+    /// random on a master, that of the master on a replica.
+    pub fn replid(&self) -> String {
+        let mut replid = self.replid.lock().unwrap();
+        if replid.is_empty() {
+            *replid = random_id();
+        }
+        replid.clone()
+    }
+
+    /// Takes on the history of the master, fully resynchronized with at
+    /// `offset`.
+    pub fn resync(&self, replid: &str, offset: u64) {
+        let _replicas = self.replicas.lock().unwrap();
+        *self.replid.lock().unwrap() = replid.to_string();
+        self.offset.store(offset, Ordering::Relaxed);
+        self.backlog.lock().unwrap().clear();
+    }
+
+    /// Starts a new history for a dataset replaced wholesale. Replicas are
+    /// let go, to come back for a copy of it, as no stream leads there from
+    /// what they have.
+    pub fn start_over(&self) {
+        let mut replicas = self.replicas.lock().unwrap();
+        replicas.clear();
+        *self.replid.lock().unwrap() = random_id();
+        self.backlog.lock().unwrap().clear();
+    }
+
+    /// Registers the client `id` as a replica serving at `addr`. It stays
+    /// one until the feed returned is dropped.
+    ///
+    /// A replica with the stream of `replid` up to `from` goes on from there
+    /// if the backlog still has what it missed, which is fed to it first.
+    /// Otherwise the replica is to be sent the dataset as of the current
+    /// offset, and `false` is returned.
+    pub fn add_replica(
+        &'static self,
+        id: u64,
+        addr: SocketAddr,
+        from: Option<(&str, u64)>,
+    ) -> (ReplicaFeed, bool) {
+        let mut replicas = self.replicas.lock().unwrap();
+        let (feed, receiver) = mpsc::unbounded_channel();
+        let offset = self.offset.load(Ordering::Relaxed);
+        let missed = from.and_then(|(replid, from)| {
+            let backlog = self.backlog.lock().unwrap();
+            let start = offset - backlog.len() as u64;
+            if replid != self.replid() || from < start || from > offset {
+                return None;
+            }
+            let missed: Vec<u8> = backlog.range((from - start) as usize..).copied().collect();
+            let _ = feed.send(Bytes::from(missed));
+            Some(from)
+        });
+        replicas.push(Replica {
+            id,
+            addr,
+            feed,
+            acked: missed.unwrap_or(offset),
+        });
+        let feed = ReplicaFeed {
+            replication: self,
+            id,
+            receiver,
+        };
+        (feed, missed.is_some())
+    }
+
+    /// Sends every replica the encoded `stream`, moving the offset past it:
+    /// writes made on a master, what the master sent on a replica. Called
+    /// in the order they come.
+    pub fn propagate(&self, stream: Bytes) {
+        let replicas = self.replicas.lock().unwrap();
+        let mut backlog = self.backlog.lock().unwrap();
+        backlog.extend(&stream);
+        let overflow = backlog.len().saturating_sub(BACKLOG_SIZE);
+        backlog.drain(..overflow);
+        self.offset
+            .fetch_add(stream.len() as u64, Ordering::Relaxed);
+        for replica in replicas.iter() {
+            let _ = replica.feed.send(stream.clone());
+        }
+    }
+
+    /// Has replicas delete `key`, which expired or was evicted here rather
+    /// than deleted by a command, as they don't expire or evict keys on
+    /// their own. A replica leaves it to its master.
+    pub fn propagate_deletion(&self, key: &str) {
+        if self.master().is_none() {
+            self.propagate(encode(&["DEL", key]));
+        }
+    }
+
+    /// Pings the replicas there are through the stream, for them to tell
+    /// a master gone silent from one without writes.
+    pub fn ping_replicas(&self) {
+        if !self.replicas.lock().unwrap().is_empty() {
+            self.propagate(encode(&["PING"]));
+        }
+    }
+
+    /// Waits for `replicas` replicas to have every write made so far, asking
+    /// them where they are, for up to `timeout` or forever. Returns how many
+    /// do, which may be less on timing out or more.
+    pub async fn wait(&self, replicas: usize, timeout: Option<Duration>) -> usize {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let offset = self.offset.load(Ordering::Relaxed);
+        if self.acked(offset) < replicas {
+            self.propagate(encode(&["REPLCONF", "GETACK", "*"]));
+        }
+        loop {
+            let acked = self.acked.notified();
+            tokio::pin!(acked);
+            acked.as_mut().enable();
+            let count = self.acked(offset);
+            if count >= replicas {
+                return count;
+            }
+            match deadline {
+                Some(deadline) => tokio::select! {
+                    _ = sleep_until(deadline.into()) => return self.acked(offset),
+                    _ = acked => {}
+                },
+                None => acked.await,
+            }
+        }
+    }
+
+    /// How many replicas acknowledged having the stream up to `offset`.
+    fn acked(&self, offset: u64) -> usize {
+        let replicas = self.replicas.lock().unwrap();
+        replicas
+            .iter()
+            .filter(|replica| replica.acked >= offset)
+            .count()
+    }
+
+    /// Renders the `# Replication` section of INFO.
+    pub fn info(&self) -> String {
+        let offset = self.offset.load(Ordering::Relaxed);
+        let Some((host, port)) = self.master() else {
+            let replicas = self.replicas.lock().unwrap();
+            let mut info = format!(
+                "# Replication\r\nrole:master\r\nconnected_slaves:{}\r\n",
+                replicas.len()
+            );
+            for (at, replica) in replicas.iter().enumerate() {
+                info.push_str(&format!(
+                    "slave{}:ip={},port={},state=online,offset={}\r\n",
+                    at,
+                    replica.addr.ip(),
+                    replica.addr.port(),
+                    replica.acked
+                ));
+            }
+            info.push_str(&format!(
+                "master_replid:{}\r\nmaster_repl_offset:{}\r\n",
+                self.replid(),
+                offset
+            ));
+            return info;
+        };
+        let link_up = self.link_up.load(Ordering::Relaxed);
+        format!(
+            "# Replication\r\n\
+             role:slave\r\n\
+             master_host:{}\r\n\
+             master_port:{}\r\n\
+             master_link_status:{}\r\n\
+             slave_repl_offset:{}\r\n\
+             master_replid:{}\r\n\
+             master_repl_offset:{}\r\n",
+            host,
+            port,
+            if link_up { "up" } else { "down" },
+            offset,
+            self.replid(),
+            offset,
+        )
+    }
+}
+
+/// 40 hex digits picked at random, as Redis names histories and nodes.
+pub(crate) fn random_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..40)
+        .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+        .collect()
+}
+
+/// `request` as sent through the stream.
+fn encode(request: &[&str]) -> Bytes {
+    let request = request.iter().map(|arg| Entry::Text(arg.to_string()));
+    let mut buf = BytesMut::new();
+    Entry::Array(request.collect()).encode(&mut buf);
+    buf.freeze()
+}
+
+/// What is sent to a replica, which stops being one once this is dropped.
+#[derive(Debug)]
+pub struct ReplicaFeed {
+    replication: &'static Replication,
+    id: u64,
+    receiver: mpsc::UnboundedReceiver<Bytes>,
+}
+
+impl ReplicaFeed {
+    /// The next writes to send the replica, as many as are waiting, or
+    /// `None` once it was let go.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        let first = self.receiver.recv().await?;
+        let mut writes = Vec::from(first);
+        while let Ok(write) = self.receiver.try_recv() {
+            writes.extend_from_slice(&write);
+        }
+        Some(Bytes::from(writes))
+    }
+
+    /// Records the replica having the stream up to `offset`.
+    pub fn ack(&self, offset: u64) {
+        let mut replicas = self.replication.replicas.lock().unwrap();
+        if let Some(replica) = replicas.iter_mut().find(|replica| replica.id == self.id) {
+            replica.acked = offset;
+        }
+        self.replication.acked.notify_waiters();
+    }
+}
+
+impl Drop for ReplicaFeed {
+    fn drop(&mut self) {
+        let mut replicas = self.replication.replicas.lock().unwrap();
+        replicas.retain(|replica| replica.id != self.id);
+    }
+}
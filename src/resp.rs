@@ -1,65 +1,712 @@
-use pest::iterators::Pair;
-use pest_derive::Parser;
+use std::fmt::{Display, Formatter};
 
-#[derive(Parser)]
-#[grammar = "resp.pest"]
-pub struct RESPParser;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-#[derive(Debug, PartialEq)]
+/// Longest header line (`*<count>`, `$<len>`, ...) accepted in a request.
+const MAX_LINE: usize = 64 * 1024;
+
+/// Deepest arrays may nest in one another, so a request of nothing but
+/// array headers can't run the decoder out of stack.
+const MAX_DEPTH: usize = 32;
+
+/// Bounds on what a request may declare, checked before anything is
+/// buffered or allocated for it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    /// Longest bulk string, Redis's `proto-max-bulk-len`.
+    pub max_bulk_len: usize,
+    /// Most elements in one array.
+    pub max_multibulk_len: usize,
+}
+
+impl Default for Limits {
+    /// Redis's limits: 512MB bulk strings and 1024*1024 arguments.
+    fn default() -> Self {
+        Limits {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_multibulk_len: 1024 * 1024,
+        }
+    }
+}
+
+/// Protocol version a client negotiated with HELLO.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl Protocol {
+    pub fn version(self) -> i64 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+/// A RESP value. The RESP3-only variants fall back to their RESP2 renderings
+/// (maps and sets as flat arrays, doubles and big numbers as bulk strings,
+/// booleans as integers) for clients that did not switch protocols.
+#[derive(Clone, Debug, PartialEq)]
 pub enum Entry {
-    Int(i32),
+    Int(i64),
     Text(String),
+    /// Bulk string whose payload need not be UTF-8, such as a stored value.
+    Bulk(Bytes),
     SimpleText(String),
+    /// Error reply made of a code such as `ERR` or `WRONGTYPE` and a message.
+    Error(String, String),
+    Array(Vec<Entry>),
+    Map(Vec<(Entry, Entry)>),
+    Set(Vec<Entry>),
+    Double(f64),
+    Boolean(bool),
+    /// Integer of arbitrary size, kept as its decimal digits.
+    BigNumber(String),
+    /// Out-of-band data such as pub/sub messages.
+    Push(Vec<Entry>),
+    /// Null bulk string, `$-1` in RESP2.
     Nil,
+    /// Null array, `*-1` in RESP2, such as a blocking pop timing out.
+    NullArray,
 }
 
-impl ToString for Entry {
-    fn to_string(&self) -> String {
+impl Entry {
+    pub fn error(code: &str, message: impl Into<String>) -> Entry {
+        Entry::Error(code.to_string(), message.into())
+    }
+
+    pub fn ok() -> Entry {
+        Entry::SimpleText("OK".to_string())
+    }
+
+    /// Appends the RESP2 encoding of this entry to `buf`.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        self.encode_as(Protocol::Resp2, buf)
+    }
+
+    /// Appends the encoding of this entry in `protocol` to `buf`.
+    pub fn encode_as(&self, protocol: Protocol, buf: &mut BytesMut) {
+        let resp3 = protocol == Protocol::Resp3;
         match self {
-            Entry::Text(text) => format!("${}\r\n{}\r\n", text.len(), text),
-            Entry::SimpleText(text) => format!("+{}\r\n", text),
-            Entry::Int(text) => format!(":{}\r\n", text),
-            Entry::Nil => format!("$-1\r\n"),
+            Entry::Text(text) => encode_bulk(text.as_bytes(), buf),
+            Entry::Bulk(bytes) => encode_bulk(bytes, buf),
+            Entry::SimpleText(text) => encode_line(b'+', text.as_bytes(), buf),
+            Entry::Error(code, message) => {
+                encode_line(b'-', format!("{} {}", code, message).as_bytes(), buf)
+            }
+            Entry::Int(number) => encode_header(b':', *number, buf),
+            Entry::Array(entries) => encode_aggregate(b'*', entries, protocol, buf),
+            Entry::Set(entries) if resp3 => encode_aggregate(b'~', entries, protocol, buf),
+            Entry::Set(entries) => encode_aggregate(b'*', entries, protocol, buf),
+            Entry::Push(entries) if resp3 => encode_aggregate(b'>', entries, protocol, buf),
+            Entry::Push(entries) => encode_aggregate(b'*', entries, protocol, buf),
+            Entry::Map(pairs) => {
+                if resp3 {
+                    encode_header(b'%', pairs.len() as i64, buf);
+                } else {
+                    encode_header(b'*', 2 * pairs.len() as i64, buf);
+                }
+                for (key, value) in pairs {
+                    key.encode_as(protocol, buf);
+                    value.encode_as(protocol, buf);
+                }
+            }
+            Entry::Double(number) if resp3 => {
+                encode_line(b',', format_double(*number).as_bytes(), buf)
+            }
+            Entry::Double(number) => encode_bulk(format_double(*number).as_bytes(), buf),
+            Entry::Boolean(true) if resp3 => buf.put_slice(b"#t\r\n"),
+            Entry::Boolean(false) if resp3 => buf.put_slice(b"#f\r\n"),
+            Entry::Boolean(value) => encode_header(b':', *value as i64, buf),
+            Entry::BigNumber(digits) if resp3 => encode_line(b'(', digits.as_bytes(), buf),
+            Entry::BigNumber(digits) => encode_bulk(digits.as_bytes(), buf),
+            Entry::Nil | Entry::NullArray if resp3 => buf.put_slice(b"_\r\n"),
+            Entry::Nil => buf.put_slice(b"$-1\r\n"),
+            Entry::NullArray => buf.put_slice(b"*-1\r\n"),
         }
     }
 }
 
-pub struct Array(pub Vec<Entry>);
+/// Renders a double the way scores are shown: shortest exact form, with
+/// `inf`, `-inf` and `nan` for the special values.
+pub fn format_double(number: f64) -> String {
+    if number.is_nan() {
+        "nan".to_string()
+    } else {
+        number.to_string()
+    }
+}
+
+fn encode_aggregate(kind: u8, entries: &[Entry], protocol: Protocol, buf: &mut BytesMut) {
+    encode_header(kind, entries.len() as i64, buf);
+    for entry in entries {
+        entry.encode_as(protocol, buf);
+    }
+}
 
-impl ToString for Array {
-    fn to_string(&self) -> String {
-        let mut result = format!("*{}\r\n", self.0.len());
-        for entry in self.0.iter() {
-            result.push_str(&entry.to_string());
+fn encode_header(kind: u8, len: i64, buf: &mut BytesMut) {
+    buf.put_u8(kind);
+    buf.put_slice(len.to_string().as_bytes());
+    buf.put_slice(b"\r\n");
+}
+
+fn encode_line(kind: u8, line: &[u8], buf: &mut BytesMut) {
+    buf.put_u8(kind);
+    buf.put_slice(line);
+    buf.put_slice(b"\r\n");
+}
+
+fn encode_bulk(payload: &[u8], buf: &mut BytesMut) {
+    buf.reserve(payload.len() + 16);
+    encode_header(b'$', payload.len() as i64, buf);
+    buf.put_slice(payload);
+    buf.put_slice(b"\r\n");
+}
+
+/// Renders through `encode`, lossily where a bulk payload is not UTF-8.
+impl Display for Entry {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+        f.write_str(&String::from_utf8_lossy(&buf))
+    }
+}
+
+/// Input that is not valid RESP; the message follows `-ERR Protocol error: `.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolError(pub String);
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "Protocol error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Decodes the request at the front of `buf` and removes its bytes, leaving
+/// `buf` untouched and returning `Ok(None)` while the request is incomplete.
+///
+/// Bulk strings are read by their declared length, so payloads may contain
+/// CRLF or NUL bytes. Payloads that are not UTF-8 are kept as raw bytes in
+/// an `Entry::Bulk`, such as what DUMP serialized.
+///
+/// Requests not starting with `*` are inline commands, a line of arguments
+/// separated by spaces as typed into telnet.
+///
+/// Lengths over `limits` are rejected as soon as their header is read.
+pub fn decode(buf: &mut BytesMut, limits: &Limits) -> Result<Option<Vec<Entry>>, ProtocolError> {
+    let mut at = 0;
+    let entries = match buf.first() {
+        None => return Ok(None),
+        // A null array request carries no command, like an empty one.
+        Some(b'*') => array(buf, &mut at, limits, 0)?.map(|array| match array {
+            Entry::Array(entries) => entries,
+            _ => Vec::new(),
+        }),
+        Some(_) => inline(buf, &mut at)?,
+    };
+    if entries.is_some() {
+        buf.advance(at);
+    }
+    Ok(entries)
+}
+
+/// A multibulk request decoded so far, kept across reads so each read only
+/// decodes the arguments it completed instead of the whole request again.
+#[derive(Clone, Debug, Default)]
+pub struct Partial {
+    count: usize,
+    args: Vec<Entry>,
+    /// Bytes of the request already removed from the buffer.
+    len: usize,
+}
+
+impl Partial {
+    /// Bytes of the request already decoded.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Like `decode`, except that the header and each whole argument of a
+/// multibulk request are removed from `buf` as soon as they are read, and
+/// kept in `partial` until the rest of the request arrives.
+pub fn decode_partial(
+    buf: &mut BytesMut,
+    partial: &mut Option<Partial>,
+    limits: &Limits,
+) -> Result<Option<Vec<Entry>>, ProtocolError> {
+    let mut request = match partial.take() {
+        Some(request) => request,
+        None if buf.first() != Some(&b'*') => return decode(buf, limits),
+        None => {
+            let mut at = 0;
+            let Some(line) = line(buf, &mut at)? else {
+                return Ok(None);
+            };
+            let count = parse_len(&line[1..], "multibulk length", limits.max_multibulk_len)?;
+            buf.advance(at);
+            match count {
+                Some(count) => Partial {
+                    count,
+                    args: Vec::with_capacity(count.min(1024)),
+                    len: at,
+                },
+                None => return Ok(Some(Vec::new())),
+            }
         }
-        result
+    };
+    while request.args.len() < request.count {
+        let mut at = 0;
+        let decoded = match buf.first() {
+            None => None,
+            Some(b'*') => array(buf, &mut at, limits, 1)?,
+            Some(_) => entry(buf, &mut at, limits)?,
+        };
+        let Some(arg) = decoded else {
+            *partial = Some(request);
+            return Ok(None);
+        };
+        buf.advance(at);
+        request.args.push(arg);
+        request.len += at;
     }
+    Ok(Some(request.args))
 }
 
-pub fn extract_string_value(pair: Pair<Rule>) -> &str {
-    pair.into_inner()
-        .find(|p| p.as_rule() == Rule::text)
-        .expect("Expected at least one string")
-        .as_str()
+/// An `Entry::Array`, or `Entry::NullArray` for `*-1`, nested in `depth`
+/// others.
+fn array(
+    buf: &[u8],
+    at: &mut usize,
+    limits: &Limits,
+    depth: usize,
+) -> Result<Option<Entry>, ProtocolError> {
+    if depth > MAX_DEPTH {
+        return Err(ProtocolError("too deeply nested multibulk".to_string()));
+    }
+    let Some(line) = line(buf, at)? else {
+        return Ok(None);
+    };
+    let count = match line.split_first() {
+        Some((b'*', count)) => parse_len(count, "multibulk length", limits.max_multibulk_len)?,
+        _ => return Err(unexpected(b'*', line)),
+    };
+    let Some(count) = count else {
+        return Ok(Some(Entry::NullArray));
+    };
+
+    let mut entries = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let decoded = match buf.get(*at) {
+            None => return Ok(None),
+            Some(b'*') => array(buf, at, limits, depth + 1)?,
+            Some(_) => entry(buf, at, limits)?,
+        };
+        match decoded {
+            Some(entry) => entries.push(entry),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(Entry::Array(entries)))
 }
 
-// Helper function to extract the integer from a `Pair` for `int`
-pub fn extract_int_value(pair: Pair<Rule>) -> i32 {
-    pair.into_inner()
-        .next()
-        .expect("Expected number after ':'")
-        .as_str()
-        .parse::<i32>()
-        .expect("failed to parse number")
+fn entry(buf: &[u8], at: &mut usize, limits: &Limits) -> Result<Option<Entry>, ProtocolError> {
+    let Some(line) = line(buf, at)? else {
+        return Ok(None);
+    };
+
+    match line.split_first() {
+        Some((b'$', len)) => {
+            let Some(len) = parse_len(len, "bulk length", limits.max_bulk_len)? else {
+                return Ok(Some(Entry::Nil));
+            };
+            let end = *at + len;
+            if buf.len() < end + 2 {
+                return Ok(None);
+            }
+            if &buf[end..end + 2] != b"\r\n" {
+                return Err(ProtocolError("expected CRLF after bulk".to_string()));
+            }
+            let payload = &buf[*at..end];
+            *at = end + 2;
+            match std::str::from_utf8(payload) {
+                Ok(text) => Ok(Some(Entry::Text(text.to_string()))),
+                Err(_) => Ok(Some(Entry::Bulk(Bytes::copy_from_slice(payload)))),
+            }
+        }
+        Some((b':', number)) => std::str::from_utf8(number)
+            .ok()
+            .and_then(|number| number.parse().ok())
+            .map(|number| Some(Entry::Int(number)))
+            .ok_or_else(|| ProtocolError("invalid integer".to_string())),
+        Some((b'+', text)) => Ok(Some(Entry::SimpleText(
+            String::from_utf8_lossy(text).into_owned(),
+        ))),
+        _ => Err(unexpected(b'$', line)),
+    }
 }
 
-pub fn extract_array_entries(pair: Pair<Rule>) -> Vec<Entry> {
-    pair.into_inner()
-        .filter_map(|p| match p.as_rule() {
-            Rule::int => Some(Entry::Int(extract_int_value(p))),
-            Rule::string => Some(Entry::Text(extract_string_value(p).to_string())),
-            // Rule::array => Some(ArrayEntry::Array(extract_array_entries(p))),
-            _ => None,
-        })
-        .collect()
+fn inline(buf: &[u8], at: &mut usize) -> Result<Option<Vec<Entry>>, ProtocolError> {
+    let window = &buf[..buf.len().min(MAX_LINE)];
+    let Some(end) = window.iter().position(|byte| *byte == b'\n') else {
+        if window.len() >= MAX_LINE {
+            return Err(ProtocolError("too big inline request".to_string()));
+        }
+        return Ok(None);
+    };
+    let line = window[..end].strip_suffix(b"\r").unwrap_or(&window[..end]);
+    *at = end + 1;
+
+    let args = split_args(line)
+        .ok_or_else(|| ProtocolError("unbalanced quotes in request".to_string()))?;
+    Ok(Some(
+        args.into_iter()
+            .map(|arg| match String::from_utf8(arg) {
+                Ok(text) => Entry::Text(text),
+                // Such as keys spelled out with `\xff` escapes.
+                Err(err) => Entry::Bulk(err.into_bytes().into()),
+            })
+            .collect(),
+    ))
+}
+
+/// Splits a line into arguments the way Redis does for inline requests and
+/// config files: words are separated by whitespace, double quotes allow
+/// `\n`, `\xff`-style escapes and single quotes only `\'`. `None` if a
+/// quote is left open or a closing quote is not followed by a space.
+pub fn split_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut at = 0;
+    loop {
+        while line.get(at).is_some_and(u8::is_ascii_whitespace) {
+            at += 1;
+        }
+        if at == line.len() {
+            return Some(args);
+        }
+        let mut arg = Vec::new();
+        let mut quote = None;
+        while let Some(&byte) = line.get(at) {
+            at += 1;
+            match (quote, byte) {
+                (None, b'"' | b'\'') => quote = Some(byte),
+                (None, byte) if byte.is_ascii_whitespace() => break,
+                (None, byte) => arg.push(byte),
+                // A closing quote must end the argument.
+                (Some(closing), byte) if byte == closing => {
+                    if line.get(at).is_some_and(|next| !next.is_ascii_whitespace()) {
+                        return None;
+                    }
+                    quote = None;
+                    break;
+                }
+                (Some(b'"'), b'\\') => {
+                    let hex = line
+                        .get(at + 1..at + 3)
+                        .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+                    match (line.get(at), hex) {
+                        (Some(b'x'), Some(hex)) => {
+                            let hex = std::str::from_utf8(hex).expect("checked hex digits");
+                            arg.push(u8::from_str_radix(hex, 16).expect("checked hex digits"));
+                            at += 3;
+                        }
+                        (Some(&escaped), _) => {
+                            arg.push(match escaped {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                escaped => escaped,
+                            });
+                            at += 1;
+                        }
+                        (None, _) => return None,
+                    }
+                }
+                (Some(b'\''), b'\\') if line.get(at) == Some(&b'\'') => {
+                    arg.push(b'\'');
+                    at += 1;
+                }
+                (Some(_), byte) => arg.push(byte),
+            }
+        }
+        if quote.is_some() {
+            return None;
+        }
+        args.push(arg);
+    }
+}
+
+/// The line starting at `at` without its CRLF, advancing `at` past it.
+fn line<'a>(buf: &'a [u8], at: &mut usize) -> Result<Option<&'a [u8]>, ProtocolError> {
+    let window = &buf[*at..buf.len().min(*at + MAX_LINE)];
+    match window.windows(2).position(|pair| pair == b"\r\n") {
+        Some(len) => {
+            let line = &window[..len];
+            *at += len + 2;
+            Ok(Some(line))
+        }
+        None if window.len() >= MAX_LINE => Err(ProtocolError("too big line".to_string())),
+        None => Ok(None),
+    }
+}
+
+/// Parses a length of at most `max`, `None` standing for the null value
+/// written `-1`.
+fn parse_len(digits: &[u8], what: &str, max: usize) -> Result<Option<usize>, ProtocolError> {
+    let len: i64 = std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| ProtocolError(format!("invalid {}", what)))?;
+    match len {
+        -1 => Ok(None),
+        len => usize::try_from(len)
+            .ok()
+            .filter(|len| *len <= max)
+            .map(Some)
+            .ok_or_else(|| ProtocolError(format!("invalid {}", what))),
+    }
+}
+
+fn unexpected(expected: u8, line: &[u8]) -> ProtocolError {
+    let got = line.first().map_or(' ', |byte| *byte as char);
+    ProtocolError(format!("expected '{}', got '{}'", expected as char, got))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(input: &[u8]) -> (Result<Option<Vec<Entry>>, ProtocolError>, usize) {
+        let mut buf = BytesMut::from(input);
+        let decoded = decode(&mut buf, &Limits::default());
+        (decoded, buf.len())
+    }
+
+    fn texts(texts: &[&str]) -> Vec<Entry> {
+        texts
+            .iter()
+            .map(|text| Entry::Text(text.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn should_wait_for_complete_frames() {
+        let frame = b"*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n";
+        for len in 0..frame.len() {
+            assert_eq!(decode_all(&frame[..len]), (Ok(None), len));
+        }
+        assert_eq!(decode_all(frame), (Ok(Some(texts(&["ECHO", "hey"]))), 0));
+        assert_eq!(
+            decode_all(b"*2\r\n$-1\r\n*-1\r\n"),
+            (Ok(Some(vec![Entry::Nil, Entry::NullArray])), 0)
+        );
+        assert_eq!(decode_all(b"*-1\r\n"), (Ok(Some(vec![])), 0));
+    }
+
+    #[test]
+    fn should_decode_one_frame_at_a_time() {
+        let mut buf = BytesMut::from(&b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nPI"[..]);
+        assert_eq!(
+            decode(&mut buf, &Limits::default()),
+            Ok(Some(texts(&["PING"])))
+        );
+        assert_eq!(&buf[..], b"*1\r\n$4\r\nPI");
+        assert_eq!(decode(&mut buf, &Limits::default()), Ok(None));
+    }
+
+    #[test]
+    fn should_take_arguments_as_they_arrive() {
+        let frame = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nvalue\r\nPING\r\n";
+        let mut buf = BytesMut::new();
+        let mut partial = None;
+        let mut decoded = Vec::new();
+        for byte in frame {
+            buf.put_u8(*byte);
+            if let Some(request) =
+                decode_partial(&mut buf, &mut partial, &Limits::default()).unwrap()
+            {
+                decoded.push(request);
+            }
+            // Only what is left of an incomplete argument stays buffered.
+            assert!(buf.len() <= 11);
+        }
+        assert_eq!(decoded, [texts(&["SET", "k", "value"]), texts(&["PING"])]);
+        assert!(partial.is_none() && buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$1"[..]);
+        assert_eq!(
+            decode_partial(&mut buf, &mut partial, &Limits::default()),
+            Ok(None)
+        );
+        assert_eq!(partial.as_ref().map(Partial::len), Some(13));
+        assert_eq!(&buf[..], b"$1");
+    }
+
+    #[test]
+    fn should_keep_binary_payloads_intact() {
+        let frame = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\na\r\n\0b\r\n";
+        assert_eq!(
+            decode_all(frame),
+            (Ok(Some(texts(&["SET", "k", "a\r\n\0b"]))), 0)
+        );
+        let (decoded, _) = decode_all(b"*1\r\n$2\r\n\xff\xfe\r\n");
+        assert_eq!(
+            decoded,
+            Ok(Some(vec![Entry::Bulk(Bytes::from_static(b"\xff\xfe"))]))
+        );
+    }
+
+    #[test]
+    fn should_decode_nested_arrays() {
+        let frame = b"*3\r\n$4\r\nEXEC\r\n*2\r\n$3\r\nGET\r\n*0\r\n:5\r\n";
+        let decoded = vec![
+            Entry::Text("EXEC".to_string()),
+            Entry::Array(vec![Entry::Text("GET".to_string()), Entry::Array(vec![])]),
+            Entry::Int(5),
+        ];
+        assert_eq!(decode_all(frame), (Ok(Some(decoded.clone())), 0));
+        for len in 0..frame.len() {
+            assert_eq!(decode_all(&frame[..len]).0, Ok(None));
+        }
+
+        let mut buf = BytesMut::new();
+        Entry::Array(decoded.clone()).encode(&mut buf);
+        assert_eq!(decode(&mut buf, &Limits::default()), Ok(Some(decoded)));
+    }
+
+    #[test]
+    fn should_refuse_arrays_nested_too_deep() {
+        let nested = b"*1\r\n".repeat(200_000);
+        assert!(decode_all(&nested).0.is_err());
+        let mut buf = BytesMut::from(&nested[..]);
+        assert!(decode_partial(&mut buf, &mut None, &Limits::default()).is_err());
+
+        let mut frame = b"*1\r\n".repeat(MAX_DEPTH + 1);
+        frame.extend_from_slice(b"*0\r\n");
+        assert!(decode_all(&frame).0.is_err());
+        let frame = &frame[b"*1\r\n".len()..];
+        assert!(decode_all(frame).0.unwrap().is_some());
+    }
+
+    #[test]
+    fn should_encode_entries() {
+        let mut buf = BytesMut::new();
+        Entry::Array(vec![
+            Entry::Int(-3),
+            Entry::Text("hey".to_string()),
+            Entry::Bulk(Bytes::from_static(b"\xff\r\n")),
+            Entry::ok(),
+            Entry::error("ERR", "no"),
+            Entry::Array(vec![Entry::Nil, Entry::NullArray]),
+        ])
+        .encode(&mut buf);
+        assert_eq!(
+            &buf[..],
+            &b"*6\r\n:-3\r\n$3\r\nhey\r\n$3\r\n\xff\r\n\r\n+OK\r\n-ERR no\r\n\
+               *2\r\n$-1\r\n*-1\r\n"[..]
+        );
+        assert_eq!(Entry::Int(7).to_string(), ":7\r\n");
+    }
+
+    #[test]
+    fn should_encode_resp3_types_for_either_protocol() {
+        let entry = Entry::Array(vec![
+            Entry::Map(vec![(Entry::Text("a".to_string()), Entry::Double(1.5))]),
+            Entry::Set(vec![Entry::Boolean(true)]),
+            Entry::BigNumber("12345678901234567890".to_string()),
+            Entry::Push(vec![Entry::Nil]),
+            Entry::Double(f64::NEG_INFINITY),
+        ]);
+
+        let mut buf = BytesMut::new();
+        entry.encode_as(Protocol::Resp3, &mut buf);
+        assert_eq!(
+            &buf[..],
+            &b"*5\r\n%1\r\n$1\r\na\r\n,1.5\r\n~1\r\n#t\r\n\
+               (12345678901234567890\r\n>1\r\n_\r\n,-inf\r\n"[..]
+        );
+
+        let mut buf = BytesMut::new();
+        entry.encode_as(Protocol::Resp2, &mut buf);
+        assert_eq!(
+            &buf[..],
+            &b"*5\r\n*2\r\n$1\r\na\r\n$3\r\n1.5\r\n*1\r\n:1\r\n\
+               $20\r\n12345678901234567890\r\n*1\r\n$-1\r\n$4\r\n-inf\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn should_decode_inline_commands() {
+        assert_eq!(decode_all(b"PING\r\n"), (Ok(Some(texts(&["PING"]))), 0));
+        assert_eq!(
+            decode_all(b"  SET foo   bar\n*1"),
+            (Ok(Some(texts(&["SET", "foo", "bar"]))), 2)
+        );
+        assert_eq!(
+            decode_all(b"SET \"a b\\x41\\n\" 'it\\'s' \"\"\r\n"),
+            (Ok(Some(texts(&["SET", "a bA\n", "it's", ""]))), 0)
+        );
+        assert_eq!(
+            decode_all(b"GET \"\\xff\\x41\"\r\n"),
+            (
+                Ok(Some(vec![
+                    Entry::Text("GET".to_string()),
+                    Entry::Bulk(Bytes::from_static(b"\xffA"))
+                ])),
+                0
+            )
+        );
+        assert_eq!(decode_all(b"\r\n"), (Ok(Some(vec![])), 0));
+        assert_eq!(decode_all(b"PIN"), (Ok(None), 3));
+
+        assert!(decode_all(b"SET \"open\r\n").0.is_err());
+        assert!(decode_all(b"SET \"a\"b\r\n").0.is_err());
+        assert!(decode_all(&vec![b'a'; MAX_LINE + 1]).0.is_err());
+    }
+
+    #[test]
+    fn should_reject_malformed_input() {
+        assert!(decode_all(b"*x\r\n").0.is_err());
+        assert!(decode_all(b"*1\r\n$3\r\nabcd\r\n").0.is_err());
+        assert!(decode_all(&vec![b'*'; MAX_LINE + 1]).0.is_err());
+    }
+
+    #[test]
+    fn should_enforce_limits() {
+        let limits = Limits {
+            max_bulk_len: 4,
+            max_multibulk_len: 2,
+        };
+        let decode_limited = |input: &[u8]| decode(&mut BytesMut::from(input), &limits);
+
+        assert_eq!(
+            decode_limited(b"*2\r\n$4\r\nECHO\r\n$4\r\nabcd\r\n"),
+            Ok(Some(texts(&["ECHO", "abcd"])))
+        );
+        // Rejected before the payload arrives.
+        assert_eq!(
+            decode_limited(b"*2\r\n$4\r\nECHO\r\n$5\r\n"),
+            Err(ProtocolError("invalid bulk length".to_string()))
+        );
+        assert_eq!(
+            decode_limited(b"*3\r\n"),
+            Err(ProtocolError("invalid multibulk length".to_string()))
+        );
+        assert_eq!(
+            decode_limited(b"*1\r\n*3\r\n"),
+            Err(ProtocolError("invalid multibulk length".to_string()))
+        );
+        assert_eq!(decode_limited(b"*1\r\n$-1\r\n"), Ok(Some(vec![Entry::Nil])));
+    }
 }
@@ -0,0 +1,519 @@
+//! Lua scripts run by EVAL and EVALSHA. Each runs on a blocking thread in
+//! an interpreter of its own, with the keys and arguments it was given as
+//! `KEYS` and `ARGV`. The commands it runs through `redis.call` and
+//! `redis.pcall` are handed back to the task serving the client, which runs
+//! them one at a time while the script waits for their replies. Scripts are
+//! cached by the SHA-1 of their body for EVALSHA to run them again.
+//!
+//! Values cross between Lua and RESP the way Redis converts them: status
+//! replies and errors become tables with an `ok` or `err` field, nil
+//! replies `false`, and Lua numbers integers.
+//!
+//! Like on Redis 7, what scripts write is replicated and logged as the
+//! writes they made rather than as the script, and `redis.set_repl` picks
+//! whether the writes after it go to the append only file, to replicas,
+//! to both or nowhere.
+
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::Bytes;
+use mlua::{Function, IntoLuaMulti, Lua, Table, Value, Variadic};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{resp::Entry, sha1::sha1_hex};
+
+/// A command a script runs, with where its writes go and where its reply
+/// goes.
+pub type Call = (Vec<Entry>, Repl, oneshot::Sender<Entry>);
+
+/// A write a script made, as it is logged and propagated, with where it
+/// goes.
+pub type Effect = (Vec<Entry>, Repl);
+
+/// Where the writes of a script go, the flags of `redis.set_repl`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Repl(u8);
+
+impl Repl {
+    pub const NONE: Repl = Repl(0);
+    pub const AOF: Repl = Repl(1);
+    pub const REPLICA: Repl = Repl(2);
+    pub const ALL: Repl = Repl(3);
+
+    /// Whether the append only file gets the writes.
+    pub fn to_aof(self) -> bool {
+        self.0 & Repl::AOF.0 != 0
+    }
+
+    /// Whether replicas get the writes.
+    pub fn to_replicas(self) -> bool {
+        self.0 & Repl::REPLICA.0 != 0
+    }
+}
+
+/// Every script run or loaded so far, by SHA-1.
+pub static SCRIPTS: Scripts = Scripts {
+    scripts: Mutex::new(BTreeMap::new()),
+};
+
+pub struct Scripts {
+    scripts: Mutex<BTreeMap<String, String>>,
+}
+
+impl Scripts {
+    /// Caches `body`, returning its SHA-1.
+    pub fn add(&self, body: &str) -> String {
+        let sha = sha1_hex(body.as_bytes());
+        let mut scripts = self.scripts.lock().unwrap();
+        scripts
+            .entry(sha.clone())
+            .or_insert_with(|| body.to_string());
+        sha
+    }
+
+    /// The body of the script cached under `sha`, in any case.
+    pub fn get(&self, sha: &str) -> Option<String> {
+        let scripts = self.scripts.lock().unwrap();
+        scripts.get(&sha.to_ascii_lowercase()).cloned()
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        let scripts = self.scripts.lock().unwrap();
+        scripts.contains_key(&sha.to_ascii_lowercase())
+    }
+
+    /// Empties the cache, returning what it held for the caller to drop.
+    pub fn flush(&self) -> BTreeMap<String, String> {
+        mem::take(&mut *self.scripts.lock().unwrap())
+    }
+}
+
+/// Checks `body` compiles, returning the error replied if not.
+pub fn compile(body: &str) -> Result<(), Entry> {
+    let lua = Lua::new();
+    let compiled = lua.load(body).set_name("@user_script").into_function();
+    compiled.map(|_| ()).map_err(compile_error)
+}
+
+fn compile_error(err: mlua::Error) -> Entry {
+    Entry::error(
+        "ERR",
+        format!("Error compiling script (new function): {}", err),
+    )
+}
+
+/// Runs `body` with `keys` and `args`, sending the commands it runs to
+/// `calls`, and returns what it replied. Blocks until the script is done.
+pub fn run(
+    body: &str,
+    sha: &str,
+    keys: Vec<Bytes>,
+    args: Vec<Bytes>,
+    calls: mpsc::UnboundedSender<Call>,
+) -> Entry {
+    let lua = Lua::new();
+    let script = match prepare(&lua, body, keys, args, calls) {
+        Ok(script) => script,
+        Err(err) => return compile_error(err),
+    };
+    finish(&lua, script, (), |error| {
+        format!("Error running script (call to f_{}): {}", sha, error)
+    })
+}
+
+/// Calls `script` with `args` and returns what it replied, an error saying
+/// `failed` with what went wrong if it raised one other than a command's.
+pub(crate) fn finish<'lua>(
+    lua: &'lua Lua,
+    script: Function<'lua>,
+    args: impl IntoLuaMulti<'lua>,
+    failed: impl FnOnce(String) -> String,
+) -> Entry {
+    // Errors are caught in Lua, for those redis.call raised to keep the
+    // table they were raised with.
+    let pcall: Function = match lua.globals().get("pcall") {
+        Ok(pcall) => pcall,
+        Err(err) => return Entry::error("ERR", err.to_string()),
+    };
+    let result = pcall.call::<_, (bool, Value)>((script, args));
+    match result {
+        Ok((true, result)) => to_entry(result),
+        Ok((false, Value::Table(error))) if error.contains_key("err").unwrap_or(false) => {
+            to_entry(Value::Table(error))
+        }
+        Ok((false, error)) => {
+            let error = match error {
+                Value::String(error) => error.to_string_lossy().into_owned(),
+                other => format!("{:?}", other),
+            };
+            Entry::error("ERR", failed(error))
+        }
+        Err(err) => Entry::error("ERR", format!("Error running script: {}", err)),
+    }
+}
+
+/// Sets up the globals scripts see and compiles `body`.
+fn prepare<'lua>(
+    lua: &'lua Lua,
+    body: &str,
+    keys: Vec<Bytes>,
+    args: Vec<Bytes>,
+    calls: mpsc::UnboundedSender<Call>,
+) -> mlua::Result<Function<'lua>> {
+    sandbox(lua, Some(calls))?;
+    let globals = lua.globals();
+    globals.raw_set("KEYS", strings(lua, &keys)?)?;
+    globals.raw_set("ARGV", strings(lua, &args)?)?;
+    lua.load(body).set_name("@user_script").into_function()
+}
+
+/// `values` as a Lua sequence of strings.
+pub(crate) fn strings<'lua>(lua: &'lua Lua, values: &[Bytes]) -> mlua::Result<Table<'lua>> {
+    lua.create_sequence_from(
+        values
+            .iter()
+            .map(|value| lua.create_string(value))
+            .collect::<mlua::Result<Vec<_>>>()?,
+    )
+}
+
+/// Takes away what scripts may not use and sets up the `redis` table,
+/// which is returned. Only with `calls` can scripts run commands, which
+/// go there.
+pub(crate) fn sandbox<'lua>(
+    lua: &'lua Lua,
+    calls: Option<mpsc::UnboundedSender<Call>>,
+) -> mlua::Result<Table<'lua>> {
+    let globals = lua.globals();
+    // Scripts only get at the dataset, never at files or processes.
+    for unsafe_global in ["os", "io", "loadfile", "dofile", "require", "package"] {
+        globals.raw_set(unsafe_global, Value::Nil)?;
+    }
+
+    let redis = lua.create_table()?;
+    globals.raw_set("redis", redis.clone())?;
+    if let Some(calls) = calls {
+        let repl = Arc::new(AtomicU8::new(Repl::ALL.0));
+        let set_repl = Arc::clone(&repl);
+        redis.raw_set(
+            "set_repl",
+            lua.create_function(move |_, flags: i64| match u8::try_from(flags) {
+                Ok(flags) if flags <= Repl::ALL.0 => {
+                    set_repl.store(flags, Ordering::Relaxed);
+                    Ok(())
+                }
+                _ => Err(mlua::Error::runtime(
+                    "Invalid replication flags. Use REPL_AOF, REPL_REPLICA, REPL_ALL or REPL_NONE.",
+                )),
+            })?,
+        )?;
+        redis.raw_set(
+            "pcall",
+            lua.create_function(move |lua, args: Variadic<Value>| {
+                let request = to_request(&args)?;
+                let (reply, replied) = oneshot::channel();
+                let repl = Repl(repl.load(Ordering::Relaxed));
+                calls
+                    .send((request, repl, reply))
+                    .map_err(|_| mlua::Error::runtime("the script was aborted"))?;
+                let reply = replied
+                    .blocking_recv()
+                    .map_err(|_| mlua::Error::runtime("the script was aborted"))?;
+                to_lua(lua, &reply)
+            })?,
+        )?;
+        lua.load(
+            r#"
+            function redis.call(...)
+                local reply = redis.pcall(...)
+                if type(reply) == "table" and reply.err then
+                    error(reply)
+                end
+                return reply
+            end
+            "#,
+        )
+        .exec()?;
+    }
+    redis.raw_set(
+        "status_reply",
+        lua.create_function(|lua, status: mlua::String| lua.create_table_from([("ok", status)]))?,
+    )?;
+    redis.raw_set(
+        "error_reply",
+        lua.create_function(|lua, error: mlua::String| lua.create_table_from([("err", error)]))?,
+    )?;
+    redis.raw_set(
+        "sha1hex",
+        lua.create_function(|_, text: mlua::String| Ok(sha1_hex(text.as_bytes())))?,
+    )?;
+    // Writes are always replicated as they were made, as this asks for.
+    redis.raw_set("replicate_commands", lua.create_function(|_, ()| Ok(true))?)?;
+    for (name, repl) in [
+        ("REPL_NONE", Repl::NONE),
+        ("REPL_AOF", Repl::AOF),
+        ("REPL_SLAVE", Repl::REPLICA),
+        ("REPL_REPLICA", Repl::REPLICA),
+        ("REPL_ALL", Repl::ALL),
+    ] {
+        redis.raw_set(name, repl.0)?;
+    }
+    // Logs have nowhere to go; the levels are there for scripts naming them.
+    redis.raw_set("log", lua.create_function(|_, _: Variadic<Value>| Ok(()))?)?;
+    for (at, level) in ["LOG_DEBUG", "LOG_VERBOSE", "LOG_NOTICE", "LOG_WARNING"]
+        .into_iter()
+        .enumerate()
+    {
+        redis.raw_set(level, at)?;
+    }
+    Ok(redis)
+}
+
+/// The request for a command `redis.call` was given the arguments of.
+fn to_request(args: &[Value]) -> mlua::Result<Vec<Entry>> {
+    if args.is_empty() {
+        return Err(mlua::Error::runtime(
+            "Please specify at least one argument for this redis lib call",
+        ));
+    }
+    args.iter()
+        .map(|arg| match arg {
+            Value::String(arg) => Ok(match arg.to_str() {
+                Ok(text) => Entry::Text(text.to_string()),
+                Err(_) => Entry::Bulk(Bytes::copy_from_slice(arg.as_bytes())),
+            }),
+            Value::Integer(number) => Ok(Entry::Text(number.to_string())),
+            Value::Number(number) => Ok(Entry::Text(number.to_string())),
+            _ => Err(mlua::Error::runtime(
+                "Lua redis lib command arguments must be strings or integers",
+            )),
+        })
+        .collect()
+}
+
+/// A reply as a script sees it.
+fn to_lua<'lua>(lua: &'lua Lua, reply: &Entry) -> mlua::Result<Value<'lua>> {
+    let sequence = |entries: &[Entry]| {
+        let values = entries
+            .iter()
+            .map(|entry| to_lua(lua, entry))
+            .collect::<mlua::Result<Vec<_>>>()?;
+        lua.create_sequence_from(values).map(Value::Table)
+    };
+    Ok(match reply {
+        Entry::Int(number) => Value::Integer(*number),
+        Entry::Text(text) => Value::String(lua.create_string(text)?),
+        Entry::Bulk(bytes) => Value::String(lua.create_string(bytes)?),
+        Entry::SimpleText(status) => {
+            Value::Table(lua.create_table_from([("ok", status.as_str())])?)
+        }
+        Entry::Error(code, message) => {
+            let error = format!("{} {}", code, message);
+            Value::Table(lua.create_table_from([("err", error)])?)
+        }
+        Entry::Array(entries) | Entry::Set(entries) | Entry::Push(entries) => sequence(entries)?,
+        // What the RESP2 clients scripts stand for would get.
+        Entry::Map(pairs) => {
+            let flat: Vec<Entry> = pairs
+                .iter()
+                .flat_map(|(key, value)| [key.clone(), value.clone()])
+                .collect();
+            sequence(&flat)?
+        }
+        Entry::Double(number) => {
+            Value::String(lua.create_string(crate::resp::format_double(*number))?)
+        }
+        Entry::BigNumber(digits) => Value::String(lua.create_string(digits)?),
+        Entry::Boolean(value) => Value::Integer(*value as i64),
+        Entry::Nil | Entry::NullArray => Value::Boolean(false),
+    })
+}
+
+/// What a script returned, as its reply.
+fn to_entry(value: Value) -> Entry {
+    match value {
+        Value::Boolean(true) => Entry::Int(1),
+        Value::Integer(number) => Entry::Int(number),
+        // Like on Redis, fractions are dropped.
+        Value::Number(number) => Entry::Int(number as i64),
+        Value::String(text) => match text.to_str() {
+            Ok(text) => Entry::Text(text.to_string()),
+            Err(_) => Entry::Bulk(Bytes::copy_from_slice(text.as_bytes())),
+        },
+        Value::Table(table) => table_to_entry(table),
+        _ => Entry::Nil,
+    }
+}
+
+/// A table with an `err` or `ok` field is an error or status reply, any
+/// other an array up to its first nil.
+fn table_to_entry(table: Table) -> Entry {
+    if let Ok(Value::String(error)) = table.raw_get::<_, Value>("err") {
+        let error = error.to_string_lossy();
+        return match error.split_once(' ') {
+            Some((code, message)) => Entry::error(code, message),
+            None => Entry::error("ERR", error),
+        };
+    }
+    if let Ok(Value::String(status)) = table.raw_get::<_, Value>("ok") {
+        return Entry::SimpleText(status.to_string_lossy().into_owned());
+    }
+    let entries = table
+        .sequence_values::<Value>()
+        .map_while(Result::ok)
+        .map(to_entry)
+        .collect();
+    Entry::Array(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `body` answering every command it runs with `reply`.
+    fn run_answering(
+        body: &str,
+        keys: &[&str],
+        args: &[&str],
+        reply: Entry,
+    ) -> (Entry, Vec<(Vec<Entry>, Repl)>) {
+        let (calls, mut requests) = mpsc::unbounded_channel::<Call>();
+        let bytes = |values: &[&str]| {
+            values
+                .iter()
+                .map(|value| Bytes::copy_from_slice(value.as_bytes()))
+                .collect()
+        };
+        let (keys, args) = (bytes(keys), bytes(args));
+        let body = body.to_string();
+        let script = std::thread::spawn(move || run(&body, "sha", keys, args, calls));
+        let mut called = Vec::new();
+        while let Some((request, repl, replied)) = requests.blocking_recv() {
+            called.push((request, repl));
+            let _ = replied.send(reply.clone());
+        }
+        (script.join().unwrap(), called)
+    }
+
+    #[test]
+    fn should_convert_lua_values_like_redis() {
+        let run = |body: &str| run_answering(body, &[], &[], Entry::Nil).0;
+        assert_eq!(run("return 3.7"), Entry::Int(3));
+        assert_eq!(run("return 'text'"), Entry::Text("text".to_string()));
+        assert_eq!(run("return true"), Entry::Int(1));
+        assert_eq!(run("return false"), Entry::Nil);
+        assert_eq!(
+            run("return {1, 'two', {3}, nil, 5}"),
+            Entry::Array(vec![
+                Entry::Int(1),
+                Entry::Text("two".to_string()),
+                Entry::Array(vec![Entry::Int(3)]),
+            ])
+        );
+        assert_eq!(
+            run("return redis.status_reply('FINE')"),
+            Entry::SimpleText("FINE".to_string())
+        );
+        assert_eq!(
+            run("return redis.error_reply('CUSTOM went wrong')"),
+            Entry::error("CUSTOM", "went wrong")
+        );
+        assert_eq!(
+            run("return redis.sha1hex('')"),
+            Entry::Text("da39a3ee5e6b4b0d3255bfef95601890afd80709".to_string())
+        );
+    }
+
+    #[test]
+    fn should_pass_keys_and_arguments_to_commands() {
+        let (reply, called) = run_answering(
+            "return redis.call('SET', KEYS[1], ARGV[1], 'EX', 10)",
+            &["key"],
+            &["value"],
+            Entry::ok(),
+        );
+        assert_eq!(reply, Entry::ok());
+        let text = |text: &str| Entry::Text(text.to_string());
+        assert_eq!(
+            called,
+            [(
+                vec![
+                    text("SET"),
+                    text("key"),
+                    text("value"),
+                    text("EX"),
+                    text("10")
+                ],
+                Repl::ALL
+            )]
+        );
+    }
+
+    #[test]
+    fn should_send_writes_where_set_repl_says() {
+        let (reply, called) = run_answering(
+            "redis.set_repl(redis.REPL_AOF)\n\
+             redis.call('SET', 'k', 'v')\n\
+             redis.set_repl(redis.REPL_NONE)\n\
+             redis.call('DEL', 'k')\n\
+             return redis.replicate_commands()",
+            &[],
+            &[],
+            Entry::ok(),
+        );
+        assert_eq!(reply, Entry::Int(1));
+        let repls: Vec<Repl> = called.into_iter().map(|(_, repl)| repl).collect();
+        assert_eq!(repls, [Repl::AOF, Repl::NONE]);
+
+        let (reply, called) = run_answering("redis.set_repl(7)", &[], &[], Entry::ok());
+        assert!(matches!(reply, Entry::Error(..)), "{:?}", reply);
+        assert!(called.is_empty());
+    }
+
+    #[test]
+    fn should_raise_errors_from_call_but_not_pcall() {
+        let wrong_type = Entry::error(
+            "WRONGTYPE",
+            "Operation against a key holding the wrong kind of value",
+        );
+        let (reply, _) = run_answering(
+            "redis.call('GET', 'k'); return 1",
+            &[],
+            &[],
+            wrong_type.clone(),
+        );
+        assert_eq!(reply, wrong_type);
+        let (reply, _) = run_answering(
+            "local reply = redis.pcall('GET', 'k'); return reply.err",
+            &[],
+            &[],
+            wrong_type,
+        );
+        assert_eq!(
+            reply,
+            Entry::Text(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_string()
+            )
+        );
+
+        let (reply, _) = run_answering("return nope()", &[], &[], Entry::Nil);
+        let Entry::Error(code, message) = reply else {
+            panic!("a failing script should reply with an error");
+        };
+        assert_eq!(code, "ERR");
+        assert!(
+            message.starts_with("Error running script (call to f_sha): user_script:1:"),
+            "{}",
+            message
+        );
+        let (reply, _) = run_answering("return (", &[], &[], Entry::Nil);
+        assert!(matches!(reply, Entry::Error(..)), "{:?}", reply);
+    }
+}
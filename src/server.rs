@@ -1,70 +1,1206 @@
-use crate::command::CommandParser;
-use crate::connection::Connection;
-use crate::resp::*;
-use crate::storage::Storage;
-use pest::Parser;
-use std::sync::Arc;
-use tokio::{net::TcpListener, sync::Mutex, task};
+use crate::aof::{self, Aof, AppendFsync, AOF_STATUS};
+use crate::blocking::execute_blocking;
+use crate::client::{ClientState, Registration, CLIENTS, PAUSE};
+use crate::cluster::{bus, Route, CLUSTER};
+use crate::command::{self, CommandParser};
+use crate::connection::{Connection, ConnectionError, Socket};
+use crate::locks::{KeyGuard, KEY_LOCKS};
+use crate::replication::REPLICATION;
+use crate::resp::{Entry, Limits, Protocol};
+use crate::scripting::{Effect, Repl};
+use crate::stats::STATS;
+use crate::storage::{unix_time_ms, Expiry, Persistence, Storage, Value, ACTIVE_EXPIRE};
+use crate::tracking::TRACKING;
+use bytes::BytesMut;
+use socket2::{SockRef, TcpKeepalive};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::Notify,
+    task::{self, JoinHandle, JoinSet},
+    time::sleep,
+};
+use tokio_rustls::TlsAcceptor;
 
-#[derive(Debug, Clone)]
-pub struct ServerError;
+pub use crate::connection::{OutputLimit, OutputLimits, DEFAULT_QUERY_BUFFER_LIMIT};
+
+pub mod config;
+mod master;
+mod replica;
+
+use config::{Config, ConfigRequest, Settings};
+
+/// Pending connections the kernel queues per listener, Redis's default
+/// tcp-backlog.
+const LISTEN_BACKLOG: u32 = 511;
+
+/// Default number of clients served at once, as on Redis.
+const DEFAULT_MAX_CLIENTS: usize = 10000;
+
+/// How often keys that expired without being accessed are looked for, as
+/// with Redis's default hz of 10.
+const EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often save rules are checked against the changes made.
+const SAVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the append only file is synced with `appendfsync everysec`.
+const AOF_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long after a failed save saving is tried again, as on Redis.
+const SAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How often replicas are pinged, Redis's default repl-ping-replica-period.
+const REPLICA_PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a replica waits before connecting to its master again, at first
+/// and at most as it keeps failing to.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Redis's default save rules: after an hour if a key changed, five
+/// minutes if 100 did and a minute if 10000 did.
+pub const DEFAULT_SAVE_RULES: [SaveRule; 3] = [
+    SaveRule::new(3600, 1),
+    SaveRule::new(300, 100),
+    SaveRule::new(60, 10000),
+];
+
+/// Default interval between keepalive probes on idle clients, Redis's
+/// default tcp-keepalive.
+const DEFAULT_TCP_KEEPALIVE: Duration = Duration::from_secs(300);
+
+/// Saves the dataset in the background once at least `changes` keys were
+/// written or deleted and `after` has passed since the last save, like a
+/// `save <seconds> <changes>` line of Redis's configuration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SaveRule {
+    pub after: Duration,
+    pub changes: u64,
+}
+
+impl SaveRule {
+    pub const fn new(seconds: u64, changes: u64) -> SaveRule {
+        SaveRule {
+            after: Duration::from_secs(seconds),
+            changes,
+        }
+    }
+}
+
+/// Whether any of `rules` calls for a save given where saving stands at
+/// `now`, in seconds since the epoch.
+fn should_save(rules: &[SaveRule], saves: &Persistence, now: u64) -> bool {
+    if saves.in_progress
+        || (!saves.last_save_ok && now < saves.last_attempt + SAVE_RETRY_DELAY.as_secs())
+    {
+        return false;
+    }
+    rules.iter().any(|rule| {
+        saves.changes_since_save >= rule.changes
+            && now.saturating_sub(saves.last_save) >= rule.after.as_secs()
+    })
+}
+
+/// The server could not listen on its address.
+#[derive(Debug)]
+pub struct ServerError(io::Error);
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "failed to listen: {}", self.0)
+    }
+}
+
+impl std::error::Error for ServerError {}
 
 pub struct Server {
-    storage: Arc<Mutex<dyn Storage>>,
+    storage: Arc<dyn Storage>,
+    request_timeout: Option<Duration>,
+    tls: Option<(Vec<String>, TlsAcceptor)>,
+    acceptors: usize,
+    tcp_nodelay: bool,
+    settings: Settings,
+    aof: Option<Arc<Aof>>,
+    aof_file: AofFile,
+    replicaof: Option<(String, u16)>,
+    cluster_bus: Vec<String>,
+    config_file: Option<PathBuf>,
 }
 
 impl Server {
-    pub fn new(storage: Arc<Mutex<dyn Storage>>) -> Self {
-        Server { storage }
-    }
-
-    pub async fn run(&self, addr: &str) -> Result<(), ServerError> {
-        println!("Logs from your program will appear here!");
-        let listener = TcpListener::bind(addr).await.expect("failed to bind");
-
-        loop {
-            let (stream, _) = listener.accept().await.expect("failed to accept listener");
-
-            let storage = Arc::clone(&self.storage);
-            task::spawn(async move {
-                let mut connection = Connection::new(stream);
-
-                loop {
-                    if let Some(str) = connection.read_command().await.unwrap() {
-                        let result = RESPParser::parse(Rule::array, &str)
-                            .expect("failed step 1 of parsing")
-                            .next()
-                            .expect("failed step 2 of parsing");
-
-                        let entries = extract_array_entries(result);
-
-                        let cmd = match CommandParser::new(&entries) {
-                            Ok(command) => command,
-                            Err(_) => {
-                                connection
-                                    .send_response("-ERR unknown command\r\n")
-                                    .await
-                                    .expect("failed to send error");
-                                continue;
-                            }
-                        };
-
-                        let msg = {
-                            let storage_guard = storage.lock().await;
-                            cmd.execute(&*storage_guard)
-                                .await
-                                .expect("failed executing command")
-                        };
-                        connection
-                            .send_response(&msg)
-                            .await
-                            .expect("failed to send response");
-                    } else {
-                        println!("no message, continuing...");
-                        break;
-                    }
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Server {
+            storage,
+            request_timeout: None,
+            tls: None,
+            acceptors: 1,
+            tcp_nodelay: true,
+            settings: Settings::default(),
+            aof: None,
+            aof_file: AofFile::default(),
+            replicaof: None,
+            cluster_bus: Vec::new(),
+            config_file: None,
+        }
+    }
+
+    /// Drops clients that take longer than `request_timeout` to finish
+    /// sending a request they started, `None` letting them take as long as
+    /// they need, as on Redis.
+    pub fn with_request_timeout(mut self, request_timeout: Option<Duration>) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Drops clients that send nothing for `idle_timeout`, `None` keeping
+    /// them forever as Redis does with `timeout 0`.
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.settings.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Turns clients away once `max_clients` are connected.
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.settings.max_clients = max_clients;
+        self
+    }
+
+    /// Also serves clients over TLS on `addrs`, terminated by `acceptor`.
+    pub fn with_tls(mut self, addrs: &[String], acceptor: TlsAcceptor) -> Self {
+        self.tls = Some((addrs.to_vec(), acceptor));
+        self
+    }
+
+    /// Runs `acceptors` accept loops per address, each on its own socket
+    /// bound with SO_REUSEPORT so the kernel spreads new connections over
+    /// them instead of one task accepting them all.
+    pub fn with_acceptors(mut self, acceptors: usize) -> Self {
+        self.acceptors = acceptors.max(1);
+        self
+    }
+
+    /// Replies with a protocol error and drops clients whose requests
+    /// declare bulk strings or arrays longer than `limits` allow.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.settings.limits = limits;
+        self
+    }
+
+    /// Whether replies are sent right away rather than held back by Nagle's
+    /// algorithm to be coalesced, which is on by default.
+    pub fn with_tcp_nodelay(mut self, tcp_nodelay: bool) -> Self {
+        self.tcp_nodelay = tcp_nodelay;
+        self
+    }
+
+    /// Probes clients that have been silent for `tcp_keepalive` so dead
+    /// peers are noticed, `None` turning keepalive off.
+    pub fn with_tcp_keepalive(mut self, tcp_keepalive: Option<Duration>) -> Self {
+        self.settings.tcp_keepalive = tcp_keepalive;
+        self
+    }
+
+    /// Drops clients that leave more replies unread than `output_limits`
+    /// allow for their class.
+    pub fn with_output_limits(mut self, output_limits: OutputLimits) -> Self {
+        self.settings.output_limits = output_limits;
+        self
+    }
+
+    /// Drops clients that send more than `query_buffer_limit` bytes of
+    /// requests not served yet.
+    pub fn with_query_buffer_limit(mut self, query_buffer_limit: usize) -> Self {
+        self.settings.query_buffer_limit = query_buffer_limit;
+        self
+    }
+
+    /// Saves the dataset in the background whenever one of `save_rules`
+    /// applies, never if there are none.
+    pub fn with_save_rules(mut self, save_rules: Vec<SaveRule>) -> Self {
+        self.settings.save_rules = save_rules;
+        self
+    }
+
+    /// Only serves clients once they authenticate with `requirepass`, if
+    /// there is one.
+    pub fn with_requirepass(mut self, requirepass: Option<String>) -> Self {
+        self.settings.requirepass = requirepass;
+        self
+    }
+
+    /// Remembers the server was started with the config file at `path`,
+    /// which CONFIG REWRITE writes to.
+    pub fn with_config_file(mut self, path: &Path) -> Self {
+        self.config_file = Some(path.to_path_buf());
+        self
+    }
+
+    /// How often the append only file is synced to disk, reported by
+    /// CONFIG GET even while there is none.
+    pub fn with_appendfsync(mut self, appendfsync: AppendFsync) -> Self {
+        self.settings.appendfsync = appendfsync;
+        self
+    }
+
+    /// Whether consecutive APPENDs to a key are logged as one, reported by
+    /// CONFIG GET even while there is no append only file.
+    pub fn with_aof_coalesce_appends(mut self, aof_coalesce_appends: bool) -> Self {
+        self.settings.aof_coalesce_appends = aof_coalesce_appends;
+        self
+    }
+
+    /// Logs every write to `aof`, synced to disk as often as it says.
+    pub fn with_aof(mut self, aof: Arc<Aof>) -> Self {
+        self.settings.appendfsync = aof.fsync();
+        self.settings.aof_coalesce_appends = aof.coalesce_appends();
+        self.aof_file.path = aof.path().to_path_buf();
+        self.aof = Some(aof);
+        self
+    }
+
+    /// Where the append only file goes once CONFIG SET appendonly turns it
+    /// on, and whether its rewrites start with an RDB payload.
+    pub fn with_aof_file(mut self, path: &Path, rdb_preamble: bool) -> Self {
+        self.aof_file = AofFile {
+            path: path.to_path_buf(),
+            rdb_preamble,
+        };
+        self
+    }
+
+    /// Replicates the master at `host` and `port`: its dataset replaces
+    /// this one, then every write it propagates is applied.
+    pub fn with_replicaof(mut self, host: &str, port: u16) -> Self {
+        self.replicaof = Some((host.to_string(), port));
+        self
+    }
+
+    /// Talks to other cluster nodes over the cluster bus, listening for them
+    /// on every address in `addrs`.
+    pub fn with_cluster_bus(mut self, addrs: &[String]) -> Self {
+        self.cluster_bus = addrs.to_vec();
+        self
+    }
+
+    /// Listens on every address in `addrs`, and on the TLS ones if set, with
+    /// one accept loop per listener all serving the same storage. Returns
+    /// once a client asks for a SHUTDOWN or the process is sent SIGTERM, or
+    /// if one of the addresses cannot be listened on.
+    pub async fn run(&self, addrs: &[String]) -> Result<(), ServerError> {
+        // Caught before clients can connect, for it not to stop the server.
+        #[cfg(unix)]
+        let hangups = match &self.config_file {
+            Some(_) => signal(SignalKind::hangup())
+                .map_err(|err| eprintln!("not reloading the config file on SIGHUP: {}", err))
+                .ok(),
+            None => None,
+        };
+        #[cfg(unix)]
+        let terminations = signal(SignalKind::terminate())
+            .map_err(|err| eprintln!("not shutting down cleanly on SIGTERM: {}", err))
+            .ok();
+        let mut listeners = Vec::new();
+        for addr in addrs {
+            for listener in bind(addr, self.acceptors).await.map_err(ServerError)? {
+                listeners.push((listener, None));
+            }
+        }
+        if let Some((tls_addrs, acceptor)) = &self.tls {
+            for addr in tls_addrs {
+                for listener in bind(addr, self.acceptors).await.map_err(ServerError)? {
+                    listeners.push((listener, Some(acceptor.clone())));
+                }
+            }
+        }
+
+        let mut bus_listeners = Vec::new();
+        for addr in &self.cluster_bus {
+            bus_listeners.push(TcpListener::bind(addr).await.map_err(ServerError)?);
+        }
+
+        let mut context = self.context();
+        // Masters are told where replicas of them can be reached.
+        context.listening_port = addrs
+            .first()
+            .and_then(|addr| addr.rsplit(':').next()?.parse().ok())
+            .unwrap_or_default();
+        let mut tasks = JoinSet::new();
+        for (listener, acceptor) in listeners {
+            tasks.spawn(accept_clients(listener, acceptor, context.clone()));
+        }
+
+        if !self.cluster_bus.is_empty() {
+            for listener in bus_listeners {
+                tasks.spawn(bus::accept(listener));
+            }
+            tasks.spawn(bus::cron());
+        }
+
+        // Keys nobody reads again would otherwise stay around for good.
+        let storage = Arc::clone(&self.storage);
+        tasks.spawn(async move {
+            loop {
+                sleep(EXPIRE_INTERVAL).await;
+                if expires_actively() {
+                    storage.purge_expired().await;
+                }
+            }
+        });
+        // Deleted on time too, for notifications and blocked clients not to
+        // wait for sampling to come across them.
+        let storage = Arc::clone(&self.storage);
+        tasks.spawn(async move {
+            loop {
+                if expires_actively() {
+                    storage.expire_due().await;
+                } else {
+                    sleep(EXPIRE_INTERVAL).await;
+                }
+            }
+        });
+
+        // Rules may be set with CONFIG SET even if there were none.
+        let storage = Arc::clone(&self.storage);
+        let config = Arc::clone(&context.config);
+        tasks.spawn(async move {
+            loop {
+                sleep(SAVE_CHECK_INTERVAL).await;
+                let now = unix_time_ms() / 1000;
+                let persistence = storage.persistence();
+                if config.read(|settings| should_save(&settings.save_rules, &persistence, now)) {
+                    storage.bgsave();
+                }
+            }
+        });
+
+        context.replicate(self.replicaof.clone());
+        // Masters ping their replicas, for those to notice if they stop.
+        tasks.spawn(async move {
+            loop {
+                sleep(REPLICA_PING_INTERVAL).await;
+                if REPLICATION.master().is_none() {
+                    REPLICATION.ping_replicas();
+                }
+            }
+        });
+
+        AOF_STATUS
+            .enabled
+            .store(self.aof.is_some(), Ordering::Relaxed);
+        // Both appendonly and appendfsync may be switched with CONFIG SET.
+        let aof_context = context.clone();
+        tasks.spawn(async move {
+            loop {
+                sleep(AOF_SYNC_INTERVAL).await;
+                let Some(aof) = aof_context.aof() else {
+                    continue;
+                };
+                // APPENDs held back to be written as one are written out
+                // each time, synced or not.
+                let flushed = match aof.fsync() {
+                    AppendFsync::Everysec => aof.sync(),
+                    _ => aof.flush(),
+                };
+                if let Err(err) = flushed {
+                    eprintln!("failed flushing append only file: {}", err);
+                }
+            }
+        });
+
+        // Like CONFIG SET with whatever the config file says by then.
+        #[cfg(unix)]
+        if let Some(mut hangups) = hangups {
+            let context = context.clone();
+            tasks.spawn(async move {
+                while hangups.recv().await.is_some() {
+                    config::reload(&context).await;
+                }
+            });
+        }
+
+        // Like SHUTDOWN, for what the append only file holds back to be
+        // written.
+        #[cfg(unix)]
+        if let Some(mut terminations) = terminations {
+            let context = context.clone();
+            tasks.spawn(async move {
+                if terminations.recv().await.is_some() {
+                    context.shutdown.notify_one();
                 }
             });
         }
+
+        // Dropping the tasks stops listening; clients still connected are
+        // closed once the runtime goes away.
+        context.shutdown.notified().await;
+        eprintln!("shutting down");
+        if let Some(aof) = context.aof() {
+            if let Err(err) = aof.sync() {
+                eprintln!("failed syncing append only file: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    fn context(&self) -> Context {
+        Context {
+            storage: Arc::clone(&self.storage),
+            request_timeout: self.request_timeout,
+            tcp_nodelay: self.tcp_nodelay,
+            config: Arc::new(Config::new(self.settings.clone())),
+            clients: Arc::new(AtomicUsize::new(0)),
+            aof: Arc::new(Mutex::new(self.aof.clone())),
+            stopped_aof: Arc::new(Mutex::new(None)),
+            aof_file: self.aof_file.clone(),
+            shutdown: Arc::new(Notify::new()),
+            listening_port: 0,
+            link: Arc::new(Mutex::new(None)),
+            config_file: self.config_file.clone(),
+        }
+    }
+}
+
+/// Whether keys are deleted once they expire even if nobody accesses them.
+/// Replicas wait for their master to tell them to instead.
+fn expires_actively() -> bool {
+    ACTIVE_EXPIRE.load(Ordering::Relaxed) && REPLICATION.master().is_none()
+}
+
+/// Binds `acceptors` listeners to `addr`, sharing it with SO_REUSEPORT when
+/// there are several.
+async fn bind(addr: &str, acceptors: usize) -> io::Result<Vec<TcpListener>> {
+    if acceptors == 1 {
+        return Ok(vec![TcpListener::bind(addr).await?]);
+    }
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    (0..acceptors)
+        .map(|_| {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            socket.set_reuseaddr(true)?;
+            set_reuseport(&socket)?;
+            socket.bind(addr)?;
+            socket.listen(LISTEN_BACKLOG)
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn set_reuseport(socket: &TcpSocket) -> io::Result<()> {
+    socket.set_reuseport(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuseport(_: &TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not available on this platform",
+    ))
+}
+
+/// Where the append only file goes and how it is rewritten, for CONFIG SET
+/// appendonly to start one.
+#[derive(Clone, Debug)]
+struct AofFile {
+    path: PathBuf,
+    rdb_preamble: bool,
+}
+
+impl Default for AofFile {
+    fn default() -> Self {
+        AofFile {
+            path: PathBuf::from("appendonly.aof"),
+            rdb_preamble: true,
+        }
+    }
+}
+
+/// What every client task needs from the server.
+#[derive(Clone)]
+struct Context {
+    storage: Arc<dyn Storage>,
+    request_timeout: Option<Duration>,
+    tcp_nodelay: bool,
+    /// What CONFIG SET may change while clients are served.
+    config: Arc<Config>,
+    /// Clients connected, which may be no more than maxclients.
+    clients: Arc<AtomicUsize>,
+    /// The append only file writes are logged to, while appendonly is on.
+    aof: Arc<Mutex<Option<Arc<Aof>>>>,
+    /// The one last turned off, which may still be rewritten.
+    stopped_aof: Arc<Mutex<Option<Arc<Aof>>>>,
+    aof_file: AofFile,
+    /// Notified when a client asked the server to stop.
+    shutdown: Arc<Notify>,
+    /// Port clients connect to, told to the master followed.
+    listening_port: u16,
+    /// The task following the master, on a replica.
+    link: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Where CONFIG REWRITE writes the parameters to.
+    config_file: Option<PathBuf>,
+}
+
+impl Context {
+    /// Follows the master at `host` and `port` from now on, or no master
+    /// with `None`, dropping the link to the one followed so far.
+    fn replicate(&self, master: Option<(String, u16)>) {
+        let mut link = self.link.lock().unwrap();
+        if let Some(link) = link.take() {
+            link.abort();
+        }
+        REPLICATION.set_master(master.clone());
+        let Some((host, port)) = master else {
+            return;
+        };
+        let context = self.clone();
+        *link = Some(tokio::spawn(async move {
+            let mut resume = false;
+            let mut backoff = MIN_RECONNECT_DELAY;
+            loop {
+                let listening_port = context.listening_port;
+                let followed = replica::follow(&host, port, listening_port, resume, &context).await;
+                if let Err(err) = followed {
+                    eprintln!("replication with {}:{} failed: {}", host, port, err);
+                }
+                // A link that was up is tried again soon, going on from where
+                // it broke; one that can't be set up less and less often.
+                if REPLICATION.link_up.swap(false, Ordering::Relaxed) {
+                    resume = true;
+                    backoff = MIN_RECONNECT_DELAY;
+                }
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }));
+    }
+
+    /// Locks what `request` gets to, the keys it names or every key, shared
+    /// unless it writes.
+    async fn lock(&self, request: &[Entry], write: bool) -> KeyGuard<'_> {
+        match (command::reaches_every_key(request), write) {
+            (true, true) => KEY_LOCKS.write_all().await,
+            (true, false) => KEY_LOCKS.read_all().await,
+            (false, true) => KEY_LOCKS.write(&command::keys(request)).await,
+            (false, false) => KEY_LOCKS.read(&command::keys(request)).await,
+        }
+    }
+
+    /// Starts rewriting the append only file from a copy of the dataset,
+    /// made while writes are held off so nothing appended meanwhile is
+    /// missing from it or in it twice. Replies why not if it can't.
+    async fn bgrewriteaof(&self) -> Result<(), Entry> {
+        let _write = KEY_LOCKS.write_all().await;
+        let Some(aof) = self.aof() else {
+            return Err(Entry::error("ERR", "Append only file is off"));
+        };
+        self.rewrite_aof(aof).await
+    }
+
+    /// `bgrewriteaof` with every key held already.
+    async fn rewrite_aof(&self, aof: Arc<Aof>) -> Result<(), Entry> {
+        if !aof.begin_rewrite() {
+            return Err(Entry::error(
+                "ERR",
+                "Background append only file rewriting already in progress",
+            ));
+        }
+        let entries = self.storage.snapshot().await;
+        self.finish_rewrite(aof, entries);
+        Ok(())
+    }
+
+    /// Starts the append only file and replicas over from the dataset, once
+    /// it was replaced wholesale with every key held.
+    async fn start_over(&self) {
+        REPLICATION.start_over();
+        if let Some(aof) = self.aof() {
+            if let Err(refused) = self.rewrite_aof(aof).await {
+                eprintln!("failed rewriting append only file: {}", refused);
+            }
+        }
+    }
+
+    /// Starts or stops logging writes to the append only file, failing with
+    /// why if it can't. A file started is seeded with a rewrite of the
+    /// dataset as it stands, made like BGREWRITEAOF's; a file stopped is
+    /// synced and left as it is, for a later start to replace.
+    async fn switch_aof(&self, on: bool) -> Result<(), String> {
+        let _write = KEY_LOCKS.write_all().await;
+        if !on {
+            if let Some(aof) = self.aof.lock().unwrap().take() {
+                AOF_STATUS.enabled.store(false, Ordering::Relaxed);
+                // A rewrite under way still puts its file in place.
+                if let Err(err) = aof.sync() {
+                    eprintln!("failed syncing append only file: {}", err);
+                }
+                *self.stopped_aof.lock().unwrap() = Some(aof);
+            }
+            return Ok(());
+        }
+        if self.aof().is_some() {
+            return Ok(());
+        }
+        // It would put its file over the new one.
+        let stopped = self.stopped_aof.lock().unwrap().take();
+        if stopped.as_ref().is_some_and(|aof| aof.rewriting()) {
+            *self.stopped_aof.lock().unwrap() = stopped;
+            return Err("Background append only file rewriting already in progress".to_string());
+        }
+        let (fsync, coalesce_appends) = self
+            .config
+            .read(|settings| (settings.appendfsync, settings.aof_coalesce_appends));
+        let aof = Aof::open(&self.aof_file.path, fsync)
+            .map_err(|err| format!("opening {}: {}", self.aof_file.path.display(), err))?
+            .with_rdb_preamble(self.aof_file.rdb_preamble)
+            .with_coalesce_appends(coalesce_appends);
+        aof.begin_rewrite();
+        let entries = self.storage.snapshot().await;
+        let aof = Arc::new(aof);
+        *self.aof.lock().unwrap() = Some(Arc::clone(&aof));
+        AOF_STATUS.enabled.store(true, Ordering::Relaxed);
+        self.finish_rewrite(aof, entries);
+        Ok(())
+    }
+
+    /// Writes `entries`, copied as a rewrite of `aof` began, in the
+    /// background.
+    fn finish_rewrite(&self, aof: Arc<Aof>, entries: Vec<(String, Value)>) {
+        let used_mem = self.storage.used_memory();
+        task::spawn_blocking(move || {
+            if let Err(err) = aof.finish_rewrite(&entries, used_mem) {
+                eprintln!("background append only file rewrite failed: {}", err);
+            }
+        });
+    }
+
+    /// The append only file, if appendonly is on.
+    fn aof(&self) -> Option<Arc<Aof>> {
+        self.aof.lock().unwrap().clone()
+    }
+
+    /// Appends the write `request` to the append only file if there is one,
+    /// and propagates it to replicas if a master, with the `expiry` it gave
+    /// a key if any. Like Redis, failing to append is only reported: the
+    /// write was made anyway.
+    fn log_write(&self, request: &[Entry], reply: &Entry, expiry: Option<Expiry>) {
+        if let Some(request) = aof::propagated(request, reply, expiry) {
+            self.log(request, Repl::ALL);
+        }
+    }
+
+    /// `log_write` for what a script wrote, each write going only where
+    /// the script said.
+    fn log_effects(&self, effects: Vec<Effect>) {
+        for (request, repl) in effects {
+            self.log(request, repl);
+        }
+    }
+
+    fn log(&self, request: Vec<Entry>, repl: Repl) {
+        let request = Entry::Array(request);
+        let mut buf = BytesMut::new();
+        request.encode(&mut buf);
+        if let Some(aof) = self.aof().filter(|_| repl.to_aof()) {
+            if let Err(err) = aof.write(&request, &buf) {
+                eprintln!("failed appending to {}: {}", aof.path().display(), err);
+            }
+        }
+        // A replica's replicas are forwarded what its master sends instead.
+        if repl.to_replicas() && REPLICATION.master().is_none() {
+            REPLICATION.propagate(buf.freeze());
+        }
+    }
+}
+
+/// Serves every client connecting to `listener` on its own task, over TLS
+/// if given an `acceptor`.
+async fn accept_clients(listener: TcpListener, acceptor: Option<TlsAcceptor>, context: Context) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // Running out of file descriptors and the like only affects the
+            // client being accepted.
+            Err(err) => {
+                eprintln!("failed accepting client: {}", err);
+                continue;
+            }
+        };
+
+        let laddr = match stream.local_addr() {
+            Ok(laddr) => laddr,
+            Err(err) => {
+                eprintln!("failed accepting client: {}", err);
+                continue;
+            }
+        };
+        let addrs = (addr, laddr);
+        // Like Redis, a client is still served if its socket can't be tuned.
+        if let Err(err) = tune(&stream, &context) {
+            eprintln!("failed setting socket options of {}: {}", addr, err);
+        }
+
+        let max_clients = context.config.read(|settings| settings.max_clients);
+        let permit = ClientPermit::acquire(&context.clients, max_clients);
+        if permit.is_none() {
+            STATS.rejected_connections.fetch_add(1, Ordering::Relaxed);
+        }
+        let context = context.clone();
+        let acceptor = acceptor.clone();
+        task::spawn(async move {
+            match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => handle(stream, addrs, permit, context).await,
+                    Err(err) => eprintln!("failed TLS handshake: {}", err),
+                },
+                None => handle(stream, addrs, permit, context).await,
+            }
+        });
+    }
+}
+
+/// A client counted against maxclients until it is dropped.
+struct ClientPermit(Arc<AtomicUsize>);
+
+impl ClientPermit {
+    /// Counts one more of `clients`, unless `max_clients` are counted
+    /// already.
+    fn acquire(clients: &Arc<AtomicUsize>, max_clients: usize) -> Option<ClientPermit> {
+        clients
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |connected| {
+                (connected < max_clients).then_some(connected + 1)
+            })
+            .ok()?;
+        Some(ClientPermit(Arc::clone(clients)))
+    }
+}
+
+impl Drop for ClientPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Applies the configured TCP options to an accepted client's socket.
+fn tune(stream: &TcpStream, context: &Context) -> io::Result<()> {
+    stream.set_nodelay(context.tcp_nodelay)?;
+    if let Some(time) = context.config.read(|settings| settings.tcp_keepalive) {
+        SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+    }
+    Ok(())
+}
+
+/// Serves a freshly accepted client, or turns it away if it got no `permit`.
+/// `addrs` are the client's address and the one it connected to.
+async fn handle<S: AsyncRead + AsyncWrite + Unpin + Socket>(
+    mut stream: S,
+    (addr, laddr): (SocketAddr, SocketAddr),
+    permit: Option<ClientPermit>,
+    context: Context,
+) {
+    // Like Redis, clients over the limit are told why before being closed
+    // rather than left waiting in the backlog.
+    let Some(permit) = permit else {
+        let _ = stream
+            .write_all(b"-ERR max number of clients reached\r\n")
+            .await;
+        let _ = stream.shutdown().await;
+        return;
+    };
+
+    let (idle_timeout, limits, output_limit, query_buffer_limit) =
+        context.config.read(|settings| {
+            let output_limit = settings.output_limits.normal;
+            let query_buffer_limit = settings.query_buffer_limit;
+            (
+                settings.idle_timeout,
+                settings.limits,
+                output_limit,
+                query_buffer_limit,
+            )
+        });
+    let mut connection =
+        Connection::new(stream, context.request_timeout, idle_timeout).with_limits(limits);
+    connection.set_output_limit(output_limit);
+    connection.set_query_buffer_limit(query_buffer_limit);
+    let registration = CLIENTS.register(connection.id(), addr, laddr);
+    // A client dropped for an error is gone either way.
+    let _ = serve(&mut connection, &context, &registration, addr).await;
+    drop(permit);
+}
+
+/// Whether any of `keys` is missing, such as keys already moved to another
+/// node.
+async fn missing(storage: &dyn Storage, keys: &[impl AsRef<str>]) -> bool {
+    for key in keys {
+        if storage.get(key.as_ref()).await.is_none() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Checks the user and password an AUTH or HELLO `request` gives against
+/// `requirepass`, the default user's password if it has one. Only the
+/// default user exists, which takes any password without one. Whether the
+/// request authenticates the client, or why it is refused.
+fn check_password(request: &[Entry], requirepass: Option<&str>) -> Result<bool, Entry> {
+    let args: Vec<&str> = request
+        .iter()
+        .map(|arg| match arg {
+            Entry::Text(arg) => arg.as_str(),
+            _ => "",
+        })
+        .collect();
+    let auth = args[0].eq_ignore_ascii_case("AUTH");
+    let (user, password) = match args[..] {
+        [_, _] if auth && requirepass.is_none() => {
+            return Err(Entry::error(
+                "ERR",
+                "AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?",
+            ))
+        }
+        [_, password] if auth => ("default", password),
+        [_, user, password] if auth => (user, password),
+        _ if auth => return Err(Entry::error("ERR", "syntax error")),
+        _ => match hello_credentials(&args) {
+            Some(credentials) => credentials,
+            None => return Ok(false),
+        },
+    };
+    match user == "default" && requirepass.is_none_or(|requirepass| requirepass == password) {
+        true => Ok(true),
+        false => Err(Entry::error(
+            "WRONGPASS",
+            "invalid username-password pair or user is disabled.",
+        )),
+    }
+}
+
+/// The user and password of a HELLO request's AUTH option, if it has one.
+/// Its other options are checked once it runs.
+fn hello_credentials<'a>(args: &[&'a str]) -> Option<(&'a str, &'a str)> {
+    let mut at = 2;
+    loop {
+        let option = args.get(at)?;
+        if option.eq_ignore_ascii_case("AUTH") {
+            return Some((args.get(at + 1)?, args.get(at + 2)?));
+        }
+        if !option.eq_ignore_ascii_case("SETNAME") {
+            return None;
+        }
+        at += 2;
+    }
+}
+
+/// Answers requests until the client disconnects or is killed, or serves
+/// it as a replica once it asks to be one. Bad requests are told so and the
+/// client kept; errors only come from the connection itself, which is then
+/// closed.
+async fn serve<S: AsyncRead + AsyncWrite + Unpin + Socket>(
+    connection: &mut Connection<S>,
+    context: &Context,
+    registration: &Registration,
+    addr: SocketAddr,
+) -> Result<(), ConnectionError> {
+    let storage = &context.storage;
+    let mut client = ClientState::new(connection.id());
+    client.password_required = context
+        .config
+        .read(|settings| settings.requirepass.is_some());
+    client.authenticated = !client.password_required;
+    registration.accept_invalidations(&client);
+    loop {
+        let entries = tokio::select! {
+            read = connection.read_command() => match read? {
+                Some(entries) => entries,
+                None => return Ok(()),
+            },
+            Some(message) = client.messages.recv() => {
+                connection.send_entry(&message.to_entry()).await?;
+                continue;
+            }
+            Some(invalidation) = client.invalidations.recv() => {
+                if let Some(entry) = invalidation.to_entry(&client) {
+                    connection.send_entry(&entry).await?;
+                }
+                continue;
+            }
+            // Replies to earlier requests, including a CLIENT KILL that
+            // killed this very client, still go out.
+            _ = registration.killed() => return connection.flush().await,
+        };
+        if client.quickack {
+            connection.quickack();
+        }
+        let name = match entries.first() {
+            Some(Entry::Text(name)) => name.as_str(),
+            _ => "",
+        };
+        registration.update(&client, name);
+
+        let cmd = match CommandParser::new(&entries) {
+            Ok(command) => command,
+            Err(err) => {
+                connection.send_entry(&err.reply(&entries)).await?;
+                continue;
+            }
+        };
+
+        // Until a client authenticates, AUTH and HELLO are all it may send.
+        let requirepass = context.config.read(|settings| settings.requirepass.clone());
+        client.password_required = requirepass.is_some();
+        let authenticates = ["AUTH", "HELLO"]
+            .iter()
+            .any(|command| name.eq_ignore_ascii_case(command));
+        if authenticates {
+            match check_password(&entries, requirepass.as_deref()) {
+                Ok(authenticated) => client.authenticated |= authenticated,
+                Err(refused) => {
+                    connection.send_entry(&refused).await?;
+                    continue;
+                }
+            }
+        }
+        if !client.authenticated {
+            let refused = match name.eq_ignore_ascii_case("HELLO") {
+                true => Entry::error(
+                    "NOAUTH",
+                    "HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time",
+                ),
+                false => Entry::error("NOAUTH", "Authentication required."),
+            };
+            connection.send_entry(&refused).await?;
+            continue;
+        }
+
+        if client.subscribed()
+            && client.protocol == Protocol::Resp2
+            && !command::is_subscriber_command(&entries)
+        {
+            let refused = Entry::error(
+                "ERR",
+                format!(
+                    "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / RESET are allowed in this context",
+                    name.to_lowercase()
+                ),
+            );
+            connection.send_entry(&refused).await?;
+            continue;
+        }
+
+        // Cluster nodes only serve keys in their own slots, and those of
+        // slots being imported to clients that asked.
+        let asking = mem::take(&mut client.asking) || name.eq_ignore_ascii_case("RESTORE-ASKING");
+        let caching = mem::take(&mut client.caching);
+        if CLUSTER.enabled() {
+            let keys = command::keys(&entries);
+            let redirect = match CLUSTER.route(&keys, asking) {
+                Route::Here => None,
+                Route::Redirect(redirect) => Some(redirect),
+                // MIGRATE moves what is left of a slot being migrated.
+                Route::IfPresent(_) if name.eq_ignore_ascii_case("MIGRATE") => None,
+                Route::IfPresent(redirect) => {
+                    let _read = KEY_LOCKS.read(&keys).await;
+                    missing(&**storage, &keys).await.then_some(redirect)
+                }
+            };
+            if let Some(redirect) = redirect {
+                connection.send_entry(&redirect).await?;
+                continue;
+            }
+        }
+
+        let write = command::is_write(&entries);
+        // Writes come from the master only, through its own link.
+        if write && REPLICATION.refuses_writes() {
+            let refused = Entry::error("READONLY", "You can't write against a read only replica.");
+            connection.send_entry(&refused).await?;
+            continue;
+        }
+
+        // Like blocking commands, paused ones let earlier replies go out
+        // first.
+        if PAUSE.holds(write) {
+            connection.flush().await?;
+            tokio::select! {
+                _ = PAUSE.wait(write) => {}
+                _ = registration.killed() => return Ok(()),
+            }
+        }
+
+        let reply = match cmd.as_blocking() {
+            Some(blocking) => {
+                // Answer earlier pipelined requests before possibly waiting a
+                // long time.
+                connection.flush().await?;
+                let served = |reply: &Entry| context.log_write(&entries, reply, None);
+                let keys = command::keys(&entries);
+                tokio::select! {
+                    reply = execute_blocking(&**storage, &KEY_LOCKS, &keys, blocking, served) => reply,
+                    _ = registration.killed() => return Ok(()),
+                }
+            }
+            None if write => {
+                let _write = context.lock(&entries, true).await;
+                // Like on Redis, commands that only shrink the dataset still
+                // run when nothing more can be evicted.
+                if !storage.evict().await && command::is_denyoom(&entries) {
+                    Ok(Entry::error(
+                        "OOM",
+                        "command not allowed when used memory > 'maxmemory'.",
+                    ))
+                } else {
+                    let reply = cmd.execute(&**storage, &mut client).await;
+                    if mem::take(&mut client.reloaded) {
+                        context.start_over().await;
+                    }
+                    let expiry = client.expiry.take();
+                    // Scripts wrote whatever they did even if they failed.
+                    match (client.script_effects.take(), &reply) {
+                        (Some(effects), _) => context.log_effects(effects),
+                        (None, Ok(reply)) => context.log_write(&entries, reply, expiry),
+                        (None, Err(_)) => {}
+                    }
+                    reply
+                }
+            }
+            None => {
+                let _read = context.lock(&entries, false).await;
+                cmd.execute(&**storage, &mut client).await
+            }
+        };
+        STATS
+            .total_commands_processed
+            .fetch_add(1, Ordering::Relaxed);
+        // Clients that read keys they track are told once those change.
+        let tracked = match &client.tracking {
+            Some(tracking) => !write && tracking.remembers_reads(caching),
+            None => false,
+        };
+        if tracked || (write && reply.is_ok()) {
+            let keys = command::keys(&entries);
+            match write {
+                true => TRACKING.invalidate(&keys, Some(client.id())),
+                false => TRACKING.remember(client.id(), &keys),
+            }
+        }
+        let reply = match mem::take(&mut client.rewrite_aof) {
+            true => match context.bgrewriteaof().await {
+                Ok(()) => reply,
+                Err(refused) => Ok(refused),
+            },
+            false => reply,
+        };
+        // Replicas may take long to catch up, so earlier replies go out first.
+        let reply = match client.wait_replicas.take() {
+            Some((replicas, timeout)) => {
+                connection.flush().await?;
+                tokio::select! {
+                    acked = REPLICATION.wait(replicas, timeout) => Ok(Entry::Int(acked as i64)),
+                    _ = registration.killed() => return Ok(()),
+                }
+            }
+            None => reply,
+        };
+        if let Some(master) = client.replicaof.take() {
+            context.replicate(master);
+        }
+        let reply = match client.config.take() {
+            Some(ConfigRequest::Get(patterns)) => Ok(config::get(context, &patterns)),
+            Some(ConfigRequest::Set(changes)) => Ok(config::set(context, &changes).await),
+            Some(ConfigRequest::Rewrite) => Ok(config::rewrite(context)),
+            None => reply,
+        };
+        // The reply to PSYNC is the start of the replica's feed.
+        if let Some((replid, offset)) = client.sync_replica.take() {
+            let from = (replid.as_str(), offset);
+            return master::sync_replica(connection, context, &client, from, addr, registration)
+                .await;
+        }
+        // Like on Redis, a successful SHUTDOWN is not replied to.
+        if client.shutdown {
+            connection.flush().await?;
+            context.shutdown.notify_one();
+            return Ok(());
+        }
+        // HELLO may have switched protocols; its own reply already uses the
+        // new one.
+        connection.set_protocol(client.protocol);
+        // Subscribing moves the client into the pubsub class, and CONFIG
+        // SET may have changed the limits or the idle timeout. Subscribers
+        // wait on others, not idle, as do blocked clients, which aren't
+        // read from until served.
+        let (output_limits, query_buffer_limit, idle_timeout) = context.config.read(|settings| {
+            let idle_timeout = settings.idle_timeout;
+            (
+                settings.output_limits,
+                settings.query_buffer_limit,
+                idle_timeout,
+            )
+        });
+        connection.set_output_limit(match client.subscribed() {
+            true => output_limits.pubsub,
+            false => output_limits.normal,
+        });
+        connection.set_query_buffer_limit(query_buffer_limit);
+        connection.set_idle_timeout(idle_timeout.filter(|_| !client.subscribed()));
+        registration.update(&client, name);
+        for reply in mem::take(&mut client.replies) {
+            connection.send_entry(&reply).await?;
+        }
+        let reply = reply.unwrap_or_else(|err| {
+            eprintln!("failed executing {:?}: {}", entries.first(), err);
+            Entry::error("ERR", "failed executing command")
+        });
+        connection.send_entry(&reply).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_save_once_a_rule_applies() {
+        let saves = Persistence {
+            changes_since_save: 100,
+            last_save: 1000,
+            last_attempt: 1000,
+            in_progress: false,
+            last_save_ok: true,
+        };
+        let rules = DEFAULT_SAVE_RULES;
+        assert!(!should_save(&rules, &saves, 1299));
+        assert!(should_save(&rules, &saves, 1300));
+        assert!(!should_save(&[], &saves, 1300));
+        let running = Persistence {
+            in_progress: true,
+            ..saves
+        };
+        assert!(!should_save(&rules, &running, 1300));
+
+        // A failed save is retried, though not right away.
+        let failed = Persistence {
+            last_attempt: 1298,
+            last_save_ok: false,
+            ..saves
+        };
+        assert!(!should_save(&rules, &failed, 1300));
+        assert!(should_save(&rules, &failed, 1303));
     }
 }
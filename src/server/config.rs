@@ -0,0 +1,954 @@
+//! Parameters a server is configured with, read with CONFIG GET and changed
+//! with CONFIG SET while it runs. They are named, written and parsed as in
+//! Redis's configuration: sizes take suffixes like `100mb`, flags are `yes`
+//! or `no`. Those the server itself goes by are kept in its `Config`; the
+//! others stay where they are used, such as the storage for maxmemory.
+//! Most parameters belong to the server rather than to the storage, so the
+//! server answers CONFIG requests itself.
+//!
+//! A server may be started with a config file in redis.conf's syntax, one
+//! directive per line. CONFIG REWRITE writes the parameters as they stand
+//! back to it, and SIGHUP has them read from it again.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::aof::AppendFsync;
+use crate::cluster::CLUSTER;
+use crate::glob::glob_match;
+use crate::notify::{NotifyFlags, KEYSPACE_EVENTS};
+use crate::rdb;
+use crate::replication::REPLICATION;
+use crate::resp::{self, Entry, Limits};
+use crate::storage::{Maxmemory, MaxmemoryPolicy, COMPACT, RDB_CHECKSUM};
+
+use super::{
+    Context, OutputLimit, OutputLimits, SaveRule, DEFAULT_MAX_CLIENTS, DEFAULT_QUERY_BUFFER_LIMIT,
+    DEFAULT_SAVE_RULES, DEFAULT_TCP_KEEPALIVE,
+};
+
+/// What a client asked CONFIG for, for the server to answer.
+#[derive(Clone, Debug)]
+pub enum ConfigRequest {
+    /// The parameters matching any of these glob patterns.
+    Get(Vec<String>),
+    /// Parameters and the values to change them to, all at once.
+    Set(Vec<(String, String)>),
+    /// The parameters as they stand written to the config file.
+    Rewrite,
+}
+
+/// The parameters the server reads as it serves clients, so changes to them
+/// apply from then on. A client goes by the idle timeout set once it sends
+/// its next request.
+#[derive(Clone, Debug)]
+pub(super) struct Settings {
+    pub idle_timeout: Option<Duration>,
+    pub max_clients: usize,
+    pub tcp_keepalive: Option<Duration>,
+    pub output_limits: OutputLimits,
+    pub query_buffer_limit: usize,
+    pub save_rules: Vec<SaveRule>,
+    pub appendfsync: AppendFsync,
+    pub aof_coalesce_appends: bool,
+    pub limits: Limits,
+    /// The password clients must authenticate with, if any.
+    pub requirepass: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            idle_timeout: None,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            tcp_keepalive: Some(DEFAULT_TCP_KEEPALIVE),
+            output_limits: OutputLimits::default(),
+            query_buffer_limit: DEFAULT_QUERY_BUFFER_LIMIT,
+            save_rules: DEFAULT_SAVE_RULES.to_vec(),
+            appendfsync: AppendFsync::default(),
+            aof_coalesce_appends: false,
+            limits: Limits::default(),
+            requirepass: None,
+        }
+    }
+}
+
+/// A server's settings as they stand, shared by every client task.
+#[derive(Debug)]
+pub(super) struct Config(Mutex<Settings>);
+
+impl Config {
+    pub fn new(settings: Settings) -> Config {
+        Config(Mutex::new(settings))
+    }
+
+    /// What `f` reads of the settings.
+    pub fn read<T>(&self, f: impl FnOnce(&Settings) -> T) -> T {
+        f(&self.0.lock().unwrap())
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Settings)) {
+        f(&mut self.0.lock().unwrap())
+    }
+}
+
+/// A parameter as CONFIG names it.
+struct Parameter {
+    name: &'static str,
+    /// A former name it is also known by.
+    alias: Option<&'static str>,
+    /// What it is unless configured otherwise.
+    default: &'static str,
+    get: fn(&Context, &Settings) -> String,
+    /// `None` for parameters only taken on startup.
+    set: Option<Set>,
+}
+
+/// Checks a value for a parameter, failing with why if it can't be set to
+/// it, and otherwise gives the change that sets it.
+type Set = fn(&Context, &str) -> Result<Change, String>;
+
+/// Sets a parameter to a value already checked, which can't fail.
+type Change = Box<dyn FnOnce(&Context, &mut Settings) + Send>;
+
+const PARAMETERS: &[Parameter] = &[
+    Parameter {
+        name: "aof-coalesce-appends",
+        alias: None,
+        default: "no",
+        get: |_, settings| yes_no(settings.aof_coalesce_appends),
+        set: Some(|_, value| {
+            let coalesce_appends = parse_yes_no(value)?;
+            Ok(Box::new(move |context, settings| {
+                settings.aof_coalesce_appends = coalesce_appends;
+                if let Some(aof) = context.aof() {
+                    aof.set_coalesce_appends(coalesce_appends);
+                }
+            }))
+        }),
+    },
+    Parameter {
+        name: "appendfsync",
+        alias: None,
+        default: "everysec",
+        get: |_, settings| settings.appendfsync.name().to_string(),
+        set: Some(|_, value| {
+            let fsync = AppendFsync::parse(value)
+                .ok_or("argument(s) must be one of the following: always, everysec, no")?;
+            Ok(Box::new(move |context, settings| {
+                settings.appendfsync = fsync;
+                if let Some(aof) = context.aof() {
+                    aof.set_fsync(fsync);
+                }
+            }))
+        }),
+    },
+    Parameter {
+        name: "appendonly",
+        alias: None,
+        default: "no",
+        get: |context, _| yes_no(context.aof().is_some()),
+        // Only checked here: `apply` switches appending itself before the
+        // other changes, as that takes a copy of the dataset and may fail.
+        set: Some(|_, value| {
+            parse_yes_no(value)?;
+            Ok(Box::new(|_, _| {}))
+        }),
+    },
+    Parameter {
+        name: "client-output-buffer-limit",
+        alias: None,
+        default: "normal 0 0 0 pubsub 33554432 8388608 60",
+        get: |_, settings| {
+            let limits = settings.output_limits;
+            [("normal", limits.normal), ("pubsub", limits.pubsub)]
+                .iter()
+                .map(|(class, limit)| {
+                    let soft_seconds = limit.soft_time.as_secs();
+                    format!("{} {} {} {}", class, limit.hard, limit.soft, soft_seconds)
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        },
+        set: Some(|_, value| {
+            let limits = parse_output_limits(value)?;
+            Ok(Box::new(move |_, settings| {
+                for (class, limit) in limits {
+                    match class.as_str() {
+                        "pubsub" => settings.output_limits.pubsub = limit,
+                        _ => settings.output_limits.normal = limit,
+                    }
+                }
+            }))
+        }),
+    },
+    Parameter {
+        name: "client-query-buffer-limit",
+        alias: None,
+        default: "1073741824",
+        get: |_, settings| settings.query_buffer_limit.to_string(),
+        set: Some(|_, value| {
+            let limit = parse_query_buffer_limit(value)?;
+            Ok(Box::new(move |_, settings| {
+                settings.query_buffer_limit = limit
+            }))
+        }),
+    },
+    Parameter {
+        name: "cluster-enabled",
+        alias: None,
+        default: "no",
+        get: |_, _| yes_no(CLUSTER.enabled()),
+        set: None,
+    },
+    Parameter {
+        name: "cluster-node-timeout",
+        alias: None,
+        default: "15000",
+        get: |_, _| CLUSTER.node_timeout.load(Ordering::Relaxed).to_string(),
+        set: Some(|_, value| {
+            let timeout = parse_integer(value)?;
+            Ok(Box::new(move |_, _| {
+                CLUSTER.node_timeout.store(timeout, Ordering::Relaxed)
+            }))
+        }),
+    },
+    Parameter {
+        name: "dbfilename",
+        alias: None,
+        default: "",
+        get: |context, _| context.storage.config().path,
+        set: None,
+    },
+    Parameter {
+        name: "dir",
+        alias: None,
+        default: "",
+        get: |context, _| context.storage.config().dir,
+        set: None,
+    },
+    Parameter {
+        name: "maxclients",
+        alias: None,
+        default: "10000",
+        get: |_, settings| settings.max_clients.to_string(),
+        set: Some(|_, value| {
+            let max_clients = parse_integer(value)?;
+            if max_clients == 0 {
+                return Err("argument must be between 1 and 4294967295 inclusive".to_string());
+            }
+            Ok(Box::new(move |_, settings| {
+                settings.max_clients = max_clients
+            }))
+        }),
+    },
+    Parameter {
+        name: "maxmemory",
+        alias: None,
+        default: "0",
+        get: |context, _| context.storage.maxmemory().limit.to_string(),
+        set: Some(|context, value| {
+            let limit = parse_memory(value).map_err(|_| "argument must be a memory value")?;
+            check_maxmemory(context)?;
+            Ok(Box::new(move |context, _| {
+                let maxmemory = context.storage.maxmemory();
+                let _ = context
+                    .storage
+                    .set_maxmemory(Maxmemory { limit, ..maxmemory });
+            }))
+        }),
+    },
+    Parameter {
+        name: "maxmemory-policy",
+        alias: None,
+        default: "noeviction",
+        get: |context, _| context.storage.maxmemory().policy.to_string(),
+        set: Some(|context, value| {
+            let policy = MaxmemoryPolicy::parse(value).ok_or(
+                "argument(s) must be one of the following: volatile-lru, volatile-lfu, volatile-random, volatile-ttl, allkeys-lru, allkeys-lfu, allkeys-random, noeviction",
+            )?;
+            check_maxmemory(context)?;
+            Ok(Box::new(move |context, _| {
+                let maxmemory = context.storage.maxmemory();
+                let _ = context.storage.set_maxmemory(Maxmemory {
+                    policy,
+                    ..maxmemory
+                });
+            }))
+        }),
+    },
+    Parameter {
+        name: "notify-keyspace-events",
+        alias: None,
+        default: "",
+        get: |_, _| KEYSPACE_EVENTS.flags().to_string(),
+        set: Some(|_, value| {
+            let flags = NotifyFlags::parse(value)
+                .ok_or("Invalid event class character. Use 'Ag$lshzxeKEtmdn'.")?;
+            Ok(Box::new(move |_, _| KEYSPACE_EVENTS.configure(flags)))
+        }),
+    },
+    Parameter {
+        name: "port",
+        alias: None,
+        default: "6379",
+        get: |context, _| context.listening_port.to_string(),
+        set: None,
+    },
+    Parameter {
+        name: "proto-max-bulk-len",
+        alias: None,
+        default: "536870912",
+        get: |_, settings| settings.limits.max_bulk_len.to_string(),
+        set: Some(|_, value| {
+            let max_bulk_len =
+                parse_memory(value).map_err(|_| "argument must be a memory value")?;
+            Ok(Box::new(move |_, settings| {
+                settings.limits.max_bulk_len = max_bulk_len
+            }))
+        }),
+    },
+    Parameter {
+        name: "rdbchecksum",
+        alias: None,
+        default: "yes",
+        get: |_, _| yes_no(RDB_CHECKSUM.load(Ordering::Relaxed)),
+        set: Some(|_, value| {
+            let checksum = parse_yes_no(value)?;
+            Ok(Box::new(move |_, _| {
+                RDB_CHECKSUM.store(checksum, Ordering::Relaxed)
+            }))
+        }),
+    },
+    Parameter {
+        name: "replica-read-only",
+        alias: Some("slave-read-only"),
+        default: "yes",
+        get: |_, _| yes_no(REPLICATION.read_only.load(Ordering::Relaxed)),
+        set: Some(|_, value| {
+            let read_only = parse_yes_no(value)?;
+            Ok(Box::new(move |_, _| {
+                REPLICATION.read_only.store(read_only, Ordering::Relaxed)
+            }))
+        }),
+    },
+    Parameter {
+        name: "requirepass",
+        alias: None,
+        default: "",
+        get: |_, settings| settings.requirepass.clone().unwrap_or_default(),
+        set: Some(|_, value| {
+            let password = (!value.is_empty()).then(|| value.to_string());
+            Ok(Box::new(move |_, settings| settings.requirepass = password))
+        }),
+    },
+    Parameter {
+        name: "save",
+        alias: None,
+        default: "3600 1 300 100 60 10000",
+        get: |_, settings| {
+            settings
+                .save_rules
+                .iter()
+                .map(|rule| format!("{} {}", rule.after.as_secs(), rule.changes))
+                .collect::<Vec<_>>()
+                .join(" ")
+        },
+        set: Some(|_, value| {
+            let rules = parse_save_rules(value).map_err(|_| "Invalid save parameters")?;
+            Ok(Box::new(move |_, settings| settings.save_rules = rules))
+        }),
+    },
+    Parameter {
+        name: "set-max-intset-entries",
+        alias: None,
+        default: "512",
+        get: |_, _| get_count(&COMPACT.set_max_intset_entries),
+        set: Some(|_, value| set_count(&COMPACT.set_max_intset_entries, value)),
+    },
+    Parameter {
+        name: "set-max-listpack-entries",
+        alias: None,
+        default: "128",
+        get: |_, _| get_count(&COMPACT.set_max_listpack_entries),
+        set: Some(|_, value| set_count(&COMPACT.set_max_listpack_entries, value)),
+    },
+    Parameter {
+        name: "set-max-listpack-value",
+        alias: None,
+        default: "64",
+        get: |_, _| get_count(&COMPACT.set_max_listpack_value),
+        set: Some(|_, value| set_count(&COMPACT.set_max_listpack_value, value)),
+    },
+    Parameter {
+        name: "tcp-keepalive",
+        alias: None,
+        default: "300",
+        get: |_, settings| {
+            let keepalive = settings.tcp_keepalive;
+            keepalive.map_or(0, |time| time.as_secs()).to_string()
+        },
+        set: Some(|_, value| {
+            let seconds = parse_integer(value)?;
+            let keepalive = (seconds > 0).then(|| Duration::from_secs(seconds));
+            Ok(Box::new(move |_, settings| {
+                settings.tcp_keepalive = keepalive
+            }))
+        }),
+    },
+    Parameter {
+        name: "timeout",
+        alias: None,
+        default: "0",
+        get: |_, settings| {
+            let timeout = settings.idle_timeout;
+            timeout.map_or(0, |time| time.as_secs()).to_string()
+        },
+        set: Some(|_, value| {
+            let seconds = parse_integer(value)?;
+            let timeout = (seconds > 0).then(|| Duration::from_secs(seconds));
+            Ok(Box::new(move |_, settings| settings.idle_timeout = timeout))
+        }),
+    },
+    Parameter {
+        name: "zset-max-listpack-entries",
+        alias: None,
+        default: "128",
+        get: |_, _| get_count(&COMPACT.zset_max_listpack_entries),
+        set: Some(|_, value| set_count(&COMPACT.zset_max_listpack_entries, value)),
+    },
+    Parameter {
+        name: "zset-max-listpack-value",
+        alias: None,
+        default: "64",
+        get: |_, _| get_count(&COMPACT.zset_max_listpack_value),
+        set: Some(|_, value| set_count(&COMPACT.zset_max_listpack_value, value)),
+    },
+];
+
+/// The parameter named `name`, or once named so.
+fn find(name: &str) -> Option<&'static Parameter> {
+    PARAMETERS
+        .iter()
+        .find(|parameter| parameter.name == name || parameter.alias == Some(name))
+}
+
+/// Every parameter whose name matches one of `patterns`, once each, in
+/// their order. Former names only match when given in full, and parameters
+/// are then replied under them.
+pub(super) fn get(context: &Context, patterns: &[String]) -> Entry {
+    let mut found: Vec<(&str, &Parameter)> = Vec::new();
+    for parameter in PARAMETERS {
+        for pattern in patterns {
+            let pattern = pattern.to_lowercase();
+            let name = match parameter.alias {
+                Some(alias) if alias == pattern => alias,
+                _ if glob_match(&pattern, parameter.name) => parameter.name,
+                _ => continue,
+            };
+            if !found.iter().any(|(known, _)| *known == name) {
+                found.push((name, parameter));
+            }
+        }
+    }
+    let found = context.config.read(|settings| {
+        found
+            .into_iter()
+            .map(|(name, parameter)| {
+                let value = (parameter.get)(context, settings);
+                (Entry::Text(name.to_string()), Entry::Text(value))
+            })
+            .collect()
+    });
+    Entry::Map(found)
+}
+
+/// Changes every parameter of `changes`, or none if any of them can't be.
+pub(super) async fn set(context: &Context, changes: &[(String, String)]) -> Entry {
+    let failed = |name: &str, reason: &str| {
+        Entry::error(
+            "ERR",
+            format!(
+                "CONFIG SET failed (possibly related to argument '{}') - {}",
+                name, reason
+            ),
+        )
+    };
+    let mut parameters: Vec<(&Parameter, &str)> = Vec::new();
+    for (name, value) in changes {
+        let name = name.to_lowercase();
+        let Some(parameter) = find(&name) else {
+            return Entry::error(
+                "ERR",
+                format!(
+                    "Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                ),
+            );
+        };
+        if parameter.set.is_none() {
+            return failed(&name, "can't set immutable config");
+        }
+        if parameters
+            .iter()
+            .any(|(known, _)| known.name == parameter.name)
+        {
+            return failed(&name, "duplicate parameter");
+        }
+        parameters.push((parameter, value));
+    }
+
+    match apply(context, &parameters).await {
+        Ok(()) => Entry::ok(),
+        Err((name, reason)) => failed(name, &reason),
+    }
+}
+
+/// Sets every parameter of `parameters` to its value, or none if any of
+/// them can't be, failing with which and why. Only parameters CONFIG SET
+/// may change may be given. Every value is checked first, then all are set
+/// at once with the settings locked, so nothing reading them sees some
+/// changed and not others.
+async fn apply(
+    context: &Context,
+    parameters: &[(&Parameter, &str)],
+) -> Result<(), (&'static str, String)> {
+    let mut changes: Vec<Change> = Vec::new();
+    let mut appendonly = None;
+    for &(parameter, value) in parameters {
+        let set = parameter.set.expect("immutable parameters were refused");
+        changes.push(set(context, value).map_err(|reason| (parameter.name, reason))?);
+        if parameter.name == "appendonly" {
+            appendonly = parse_yes_no(value).ok();
+        }
+    }
+    // The only change that may still fail, so before any other is made.
+    if let Some(on) = appendonly {
+        context
+            .switch_aof(on)
+            .await
+            .map_err(|reason| ("appendonly", reason))?;
+    }
+    context.config.update(|settings| {
+        for change in changes {
+            change(context, settings);
+        }
+    });
+    Ok(())
+}
+
+/// Sets every parameter CONFIG SET may change to what the config file the
+/// server was started with says now, all of them or none, and logs which
+/// changed. Those the file leaves out stay as they are, and those only
+/// taken on startup are left for a restart. The server has no log level
+/// nor ACL file to reload, logging everything to stdout and stderr and
+/// having no users but the default one.
+pub(super) async fn reload(context: &Context) {
+    let Some(path) = &context.config_file else {
+        return;
+    };
+    let directives = fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| parse_file(&text));
+    let directives = match directives {
+        Ok(directives) => directives,
+        Err(err) => {
+            eprintln!("not reloading {}: {}", path.display(), err);
+            return;
+        }
+    };
+    // Directives add up or win over earlier ones as on startup.
+    let mut values: Vec<(&Parameter, Vec<String>)> = Vec::new();
+    for (name, args) in directives {
+        let Some(parameter) = find(&name).filter(|parameter| parameter.set.is_some()) else {
+            continue;
+        };
+        match values
+            .iter_mut()
+            .find(|(known, _)| known.name == parameter.name)
+        {
+            Some((_, known)) if parameter.name == "save" && args != [""] => known.extend(args),
+            Some((_, known)) if parameter.name == "client-output-buffer-limit" => {
+                known.extend(args)
+            }
+            Some((_, known)) => *known = args,
+            None => values.push((parameter, args)),
+        }
+    }
+    let values: Vec<(&Parameter, String)> = values
+        .into_iter()
+        .map(|(parameter, args)| (parameter, args.join(" ")))
+        .collect();
+    let current = |context: &Context| -> Vec<String> {
+        context.config.read(|settings| {
+            values
+                .iter()
+                .map(|(parameter, _)| (parameter.get)(context, settings))
+                .collect()
+        })
+    };
+    let before = current(context);
+    let parameters: Vec<(&Parameter, &str)> = values
+        .iter()
+        .map(|(parameter, value)| (*parameter, value.as_str()))
+        .collect();
+    if let Err((name, reason)) = apply(context, &parameters).await {
+        eprintln!("not reloading {}: {}: {}", path.display(), name, reason);
+        return;
+    }
+    eprintln!("reloaded {}", path.display());
+    for (((parameter, _), before), after) in values.iter().zip(before).zip(current(context)) {
+        if after != before {
+            eprintln!(
+                "{} changed from {:?} to {:?}",
+                parameter.name, before, after
+            );
+        }
+    }
+}
+
+/// Writes every parameter CONFIG SET may change to the config file the
+/// server was started with, keeping the rest of the file as it was.
+pub(super) fn rewrite(context: &Context) -> Entry {
+    let Some(path) = &context.config_file else {
+        return Entry::error("ERR", "The server is running without a config file");
+    };
+    let values: Vec<(&Parameter, String)> = context.config.read(|settings| {
+        PARAMETERS
+            .iter()
+            .filter(|parameter| parameter.set.is_some())
+            .map(|parameter| (parameter, (parameter.get)(context, settings)))
+            .collect()
+    });
+    match write_config_file(path, &values) {
+        Ok(()) => Entry::ok(),
+        Err(err) => Entry::error("ERR", format!("Rewriting config file: {}", err)),
+    }
+}
+
+/// Replaces the config file at `path` with one holding `values`, all at
+/// once for it never to be left half written.
+fn write_config_file(path: &Path, values: &[(&Parameter, String)]) -> io::Result<()> {
+    // Like on Redis, a file removed since is written anew.
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".tmp-{}", std::process::id()));
+    let temp = Path::new(&temp);
+    fs::write(temp, rewritten(&text, values))?;
+    rdb::put_in_place(temp, path)
+}
+
+/// Comes before the parameters CONFIG REWRITE added to a config file, once.
+const REWRITE_SIGNATURE: &str = "# Generated by CONFIG REWRITE";
+
+/// The config file `text` with `values` written in. The first line setting
+/// a parameter is changed to its value and any later ones dropped, while
+/// parameters the file doesn't set are added at the end unless they have
+/// their default. Other lines, comments included, stay as they are.
+fn rewritten(text: &str, values: &[(&Parameter, String)]) -> String {
+    let mut written: Vec<&str> = Vec::new();
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let name = match split_args(line) {
+            Ok(args) if !line.trim_start().starts_with('#') => {
+                args.first().map(|name| name.to_lowercase())
+            }
+            _ => None,
+        };
+        let value = name.and_then(|name| {
+            values.iter().find(|(parameter, _)| {
+                parameter.name == name || parameter.alias == Some(name.as_str())
+            })
+        });
+        match value {
+            Some((parameter, _)) if written.contains(&parameter.name) => {}
+            Some((parameter, value)) => {
+                written.push(parameter.name);
+                lines.push(directive(parameter.name, value));
+            }
+            None => lines.push(line.to_string()),
+        }
+    }
+
+    let missing: Vec<String> = values
+        .iter()
+        .filter(|(parameter, value)| {
+            !written.contains(&parameter.name) && value != parameter.default
+        })
+        .map(|(parameter, value)| directive(parameter.name, value))
+        .collect();
+    // Those added by an earlier rewrite are already under the signature.
+    if !missing.is_empty() && !lines.iter().any(|line| line == REWRITE_SIGNATURE) {
+        lines.push(REWRITE_SIGNATURE.to_string());
+    }
+    lines.extend(missing);
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// Parameters whose value is a list of words rather than a single one.
+const WORD_LISTS: &[&str] = &["save", "client-output-buffer-limit"];
+
+/// A config file line setting `name` to `value`, quoted where it would
+/// otherwise be read back differently.
+fn directive(name: &str, value: &str) -> String {
+    match WORD_LISTS.contains(&name) && !value.trim().is_empty() {
+        true => format!(
+            "{} {}",
+            name,
+            value.split_whitespace().collect::<Vec<_>>().join(" ")
+        ),
+        false => format!("{} {}", name, quoted(value)),
+    }
+}
+
+/// `value` as one word of a config file line: as it is if it reads back
+/// that way, in double quotes with escapes otherwise.
+fn quoted(value: &str) -> String {
+    let plain = |byte: &u8| byte.is_ascii_graphic() && !matches!(byte, b'"' | b'\'' | b'\\');
+    if !value.is_empty() && value.bytes().all(|byte| plain(&byte)) {
+        return value.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for char in value.chars() {
+        match char {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(char);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            char if char.is_ascii_control() => quoted.push_str(&format!("\\x{:02x}", char as u8)),
+            char => quoted.push(char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The words of a config file line, split like inline requests are.
+fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let args = resp::split_args(line.as_bytes()).ok_or("unbalanced quotes")?;
+    args.into_iter()
+        .map(|arg| String::from_utf8(arg).map_err(|_| "invalid UTF-8".to_string()))
+        .collect()
+}
+
+/// The directives of a config file in redis.conf's syntax, as their names
+/// in lower case and their arguments. Blank lines and comments are skipped.
+pub fn parse_file(text: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    let mut directives = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let at_line = |reason: &str| format!("line {}: {}", number + 1, reason);
+        let mut args = split_args(line).map_err(|err| at_line(&err))?;
+        let name = args.remove(0).to_lowercase();
+        if args.is_empty() {
+            return Err(at_line(&format!("{} takes arguments", name)));
+        }
+        directives.push((name, args));
+    }
+    Ok(directives)
+}
+
+fn yes_no(enabled: bool) -> String {
+    match enabled {
+        true => "yes".to_string(),
+        false => "no".to_string(),
+    }
+}
+
+fn parse_yes_no(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string()),
+    }
+}
+
+fn parse_integer<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| "argument couldn't be parsed into an integer".to_string())
+}
+
+fn get_count(count: &AtomicUsize) -> String {
+    count.load(Ordering::Relaxed).to_string()
+}
+
+fn set_count(count: &'static AtomicUsize, value: &str) -> Result<Change, String> {
+    let value = parse_integer(value)?;
+    Ok(Box::new(move |_, _| count.store(value, Ordering::Relaxed)))
+}
+
+/// Fails with why if the storage can't take a maxmemory, which giving it the
+/// one it has finds out without changing anything.
+fn check_maxmemory(context: &Context) -> Result<(), String> {
+    context.storage.set_maxmemory(context.storage.maxmemory())
+}
+
+/// A size in bytes such as `8mb`, with Redis's suffixes: `k`, `m` and `g`
+/// count in powers of 1000, `kb`, `mb` and `gb` in powers of 1024.
+pub fn parse_memory(size: &str) -> Result<usize, String> {
+    let size = size.to_lowercase();
+    let digits = size.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit: usize = match &size[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        unit => return Err(format!("unknown unit {}", unit)),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|count| count.checked_mul(unit))
+        .ok_or_else(|| format!("invalid size {}", size))
+}
+
+/// A query buffer limit, which like on Redis is at least 1mb.
+pub fn parse_query_buffer_limit(value: &str) -> Result<usize, String> {
+    let limit = parse_memory(value).map_err(|_| "argument must be a memory value")?;
+    if limit < 1024 * 1024 {
+        return Err(format!(
+            "argument must be between 1048576 and {} inclusive",
+            i64::MAX
+        ));
+    }
+    Ok(limit)
+}
+
+/// Save rules as `<seconds> <changes>` pairs, none if empty.
+pub fn parse_save_rules(rules: &str) -> Result<Vec<SaveRule>, String> {
+    let numbers = rules
+        .split_whitespace()
+        .map(|number| number.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("invalid save rules {}", rules))?;
+    if !numbers.len().is_multiple_of(2) {
+        return Err("expected <seconds> <changes> pairs".to_string());
+    }
+    Ok(numbers
+        .chunks(2)
+        .map(|pair| SaveRule::new(pair[0], pair[1]))
+        .collect())
+}
+
+/// Output buffer limits of classes of clients, each as `<normal|pubsub>
+/// <hard> <soft> <soft seconds>`, with the classes in lower case.
+pub fn parse_output_limits(limits: &str) -> Result<Vec<(String, OutputLimit)>, String> {
+    let words: Vec<&str> = limits.split_whitespace().collect();
+    if !words.len().is_multiple_of(4) {
+        return Err("Wrong number of arguments in buffer limit configuration.".to_string());
+    }
+    words
+        .chunks(4)
+        .map(|limit| parse_output_limit(&limit.join(" ")))
+        .collect()
+}
+
+fn parse_output_limit(limit: &str) -> Result<(String, OutputLimit), String> {
+    let [class, hard, soft, soft_seconds] = limit.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err("expected <class> <hard> <soft> <soft seconds>".to_string());
+    };
+    let class = class.to_lowercase();
+    if class != "normal" && class != "pubsub" {
+        return Err(format!("unknown client class {}", class));
+    }
+    let soft_seconds = soft_seconds
+        .parse()
+        .map_err(|_| format!("invalid soft seconds {}", soft_seconds))?;
+    let limit = OutputLimit {
+        hard: parse_memory(hard)?,
+        soft: parse_memory(soft)?,
+        soft_time: Duration::from_secs(soft_seconds),
+    };
+    Ok((class, limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_split_config_lines_like_redis() {
+        let args = |line: &str| split_args(line).unwrap();
+        assert_eq!(args("  save 60   100 "), ["save", "60", "100"]);
+        assert_eq!(
+            args(r#"requirepass "a b\"\x41\n""#),
+            ["requirepass", "a b\"A\n"]
+        );
+        assert_eq!(args(r"requirepass 'it\'s'"), ["requirepass", "it's"]);
+        assert_eq!(args(r#"save """#), ["save", ""]);
+        assert!(args("").is_empty());
+        assert!(split_args(r#"requirepass "open"#).is_err());
+        assert!(split_args(r#"requirepass "a"b"#).is_err());
+
+        let text = "# comment\n\nMaxMemory 1mb\n  save \"\"\n";
+        assert_eq!(
+            parse_file(text).unwrap(),
+            [
+                ("maxmemory".to_string(), vec!["1mb".to_string()]),
+                ("save".to_string(), vec![String::new()]),
+            ]
+        );
+        assert_eq!(
+            parse_file("port 1\ndir 'x\n").unwrap_err(),
+            "line 2: unbalanced quotes"
+        );
+        assert_eq!(
+            parse_file("appendonly\n").unwrap_err(),
+            "line 1: appendonly takes arguments"
+        );
+    }
+
+    #[test]
+    fn should_rewrite_config_files_in_place() {
+        let value = |name: &str, value: &str| (find(name).unwrap(), value.to_string());
+        let values = [
+            value("maxmemory", "1048576"),
+            value("replica-read-only", "no"),
+            value("save", "3600 1 300 100"),
+            value("requirepass", "a \"b\""),
+            value("notify-keyspace-events", ""),
+            value("timeout", "0"),
+        ];
+        let text = "# Memory\nmaxmemory 1mb\nslave-read-only yes\nport 7000\nmaxmemory 2mb\n";
+        assert_eq!(
+            rewritten(text, &values),
+            "# Memory\nmaxmemory 1048576\nreplica-read-only no\nport 7000\n\
+             # Generated by CONFIG REWRITE\nsave 3600 1 300 100\nrequirepass \"a \\\"b\\\"\"\n"
+        );
+        let read = parse_file(&rewritten(text, &values)).unwrap();
+        assert_eq!(
+            read[4],
+            ("requirepass".to_string(), vec!["a \"b\"".to_string()])
+        );
+        let line = directive("requirepass", "tab\there\\ 'quoted'\u{1}");
+        assert_eq!(line, r#"requirepass "tab\there\\ 'quoted'\x01""#);
+        assert_eq!(split_args(&line).unwrap()[1], "tab\there\\ 'quoted'\u{1}");
+
+        // Rewriting again leaves the file as it is.
+        let once = rewritten(text, &values);
+        assert_eq!(rewritten(&once, &values), once);
+        let empty = [value("save", "")];
+        assert_eq!(
+            rewritten("", &empty),
+            "# Generated by CONFIG REWRITE\nsave \"\"\n"
+        );
+    }
+}
@@ -0,0 +1,113 @@
+//! Serving replicas: a client that asked with PSYNC is sent a snapshot of
+//! the dataset, then stays registered as a replica until it disconnects.
+
+use std::{net::SocketAddr, sync::atomic::Ordering};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task,
+};
+
+use super::Context;
+use crate::{
+    client::{ClientState, Registration},
+    connection::{Connection, ConnectionError},
+    locks::KEY_LOCKS,
+    rdb,
+    replication::REPLICATION,
+    resp::Entry,
+    stats::STATS,
+};
+
+/// Synchronizes the replica on `connection`, whose address is `addr`, then
+/// keeps it until it disconnects, is killed or is let go. The replica goes
+/// on with the stream of the replication ID it asked for from the offset it
+/// asked for if it can, and is sent the dataset otherwise.
+pub(super) async fn sync_replica<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    context: &Context,
+    client: &ClientState,
+    (replid, offset): (&str, i64),
+    addr: SocketAddr,
+    registration: &Registration,
+) -> Result<(), ConnectionError> {
+    // Where the replica serves, if it said; otherwise where it connects from.
+    let addr = SocketAddr::new(addr.ip(), client.listening_port.unwrap_or(addr.port()));
+    // Offsets asked for are of the first byte wanted, counting from 1.
+    let from = u64::try_from(offset - 1).ok().map(|from| (replid, from));
+    // Registered along with the copy, so writes made after are sent to it
+    // and those made before are in the copy.
+    let (mut feed, copy) = {
+        let _write = KEY_LOCKS.write_all().await;
+        match REPLICATION.add_replica(connection.id(), addr, from) {
+            (feed, true) => (feed, None),
+            (feed, false) => {
+                let entries = context.storage.snapshot().await;
+                (
+                    feed,
+                    Some((entries, REPLICATION.offset.load(Ordering::Relaxed))),
+                )
+            }
+        }
+    };
+    match copy {
+        Some((entries, offset)) => {
+            STATS.sync_full.fetch_add(1, Ordering::Relaxed);
+            if from.is_some() {
+                STATS.sync_partial_err.fetch_add(1, Ordering::Relaxed);
+            }
+            let resync = format!("FULLRESYNC {} {}", REPLICATION.replid(), offset);
+            connection.send_entry(&Entry::SimpleText(resync)).await?;
+            let used_mem = context.storage.used_memory();
+            let snapshot = task::spawn_blocking(move || rdb::dump(&entries, used_mem))
+                .await
+                .map_err(|_| ConnectionError)?;
+            // Unlike a bulk string, the snapshot isn't followed by CRLF.
+            connection
+                .send_raw(format!("${}\r\n", snapshot.len()).as_bytes())
+                .await?;
+            connection.send_raw(&snapshot).await?;
+        }
+        None => {
+            STATS.sync_partial_ok.fetch_add(1, Ordering::Relaxed);
+            let resync = format!("CONTINUE {}", REPLICATION.replid());
+            connection.send_entry(&Entry::SimpleText(resync)).await?;
+        }
+    }
+    connection.flush().await?;
+
+    // Replicas only talk to acknowledge what they got.
+    connection.set_idle_timeout(None);
+    loop {
+        tokio::select! {
+            writes = feed.recv() => match writes {
+                Some(writes) => {
+                    connection.send_raw(&writes).await?;
+                    connection.flush().await?;
+                }
+                None => return Ok(()),
+            },
+            read = connection.read_command() => match read? {
+                Some(request) => {
+                    if let Some(offset) = acked(&request) {
+                        feed.ack(offset);
+                    }
+                }
+                None => return Ok(()),
+            },
+            _ = registration.killed() => return Ok(()),
+        }
+    }
+}
+
+/// The offset in a replica's REPLCONF ACK.
+fn acked(request: &[Entry]) -> Option<u64> {
+    match request {
+        [Entry::Text(name), Entry::Text(subcommand), Entry::Text(offset), ..]
+            if name.eq_ignore_ascii_case("REPLCONF") && subcommand.eq_ignore_ascii_case("ACK") =>
+        {
+            offset.parse().ok()
+        }
+        _ => None,
+    }
+}
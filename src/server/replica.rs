@@ -0,0 +1,264 @@
+//! Following a master as its replica: the dataset starts over from the
+//! snapshot the master sends on a full resynchronization, then every write
+//! it propagates is applied in order.
+
+use std::{io, mem, sync::atomic::Ordering, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, BufReader},
+    net::TcpStream,
+    time::{interval_at, sleep_until, timeout, Instant},
+};
+
+use super::Context;
+use crate::{
+    client::ClientState,
+    command::{self, CommandParser},
+    functions::FUNCTIONS,
+    locks::KEY_LOCKS,
+    rdb,
+    replication::REPLICATION,
+    resp::{self, Entry, Limits},
+    tap::{self, protocol_error, Resync},
+    tracking::TRACKING,
+};
+
+/// How long the master may stay silent before the link is taken for dead,
+/// Redis's default repl-timeout.
+const MASTER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the master is told how far into the stream the replica is.
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Replicates the master at `host` and `port`, telling it this server
+/// listens on `listening_port`, until the link breaks. With `resume`, the
+/// stream followed before is gone on with if the master still can.
+pub(super) async fn follow(
+    host: &str,
+    port: u16,
+    listening_port: u16,
+    resume: bool,
+    context: &Context,
+) -> io::Result<()> {
+    let handshake = async {
+        let (reader, mut writer) = TcpStream::connect((host, port)).await?.into_split();
+        let mut reader = BufReader::new(reader);
+        // The master is asked for the byte after the last one got.
+        let replid = REPLICATION.replid();
+        let from = resume.then(|| {
+            (
+                replid.as_str(),
+                REPLICATION.offset.load(Ordering::Relaxed) + 1,
+            )
+        });
+        let resync =
+            tap::request_sync(&mut reader, &mut writer, Some(listening_port), from).await?;
+        io::Result::Ok((reader, writer, resync))
+    };
+    let (mut reader, mut writer, resync) = timeout(MASTER_TIMEOUT, handshake)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    let mut offset = match resync {
+        Resync::Full(replid, offset) => {
+            let len = tap::snapshot_len(&mut reader).await?;
+            let len = usize::try_from(len)
+                .map_err(|_| protocol_error(format!("snapshot of {} bytes too large", len)))?;
+            let mut snapshot = vec![0; len];
+            reader.read_exact(&mut snapshot).await?;
+            // The master's libraries replace the replica's, like its keys.
+            FUNCTIONS.flush();
+            let keys = rdb::parse_rdb(&mut Bytes::from(snapshot)).map_err(|err| {
+                protocol_error(format!("failed parsing the master's snapshot: {}", err))
+            })?;
+            {
+                let _write = KEY_LOCKS.write_all().await;
+                context.storage.load_keys(keys, false).await;
+            }
+            println!("full resync with {} at offset {}", replid, offset);
+            REPLICATION.resync(&replid, offset);
+            offset
+        }
+        Resync::Partial => {
+            let offset = REPLICATION.offset.load(Ordering::Relaxed);
+            println!("partial resync with {}:{} at offset {}", host, port, offset);
+            offset
+        }
+    };
+    REPLICATION.link_up.store(true, Ordering::Relaxed);
+
+    // Whatever the master accepted is applied.
+    let limits = Limits {
+        max_bulk_len: usize::MAX,
+        max_multibulk_len: usize::MAX,
+    };
+    let mut client = ClientState::new(0);
+    let mut buffer = BytesMut::with_capacity(16 * 1024);
+    let mut partial = None;
+    // Bytes of the request being decoded, taken from the buffer already.
+    let mut taken = 0;
+    let mut acks = interval_at(Instant::now() + ACK_INTERVAL, ACK_INTERVAL);
+    let mut deadline = Instant::now() + MASTER_TIMEOUT;
+    loop {
+        loop {
+            let buffered = buffer.len();
+            let decoded = resp::decode_partial(&mut buffer, &mut partial, &limits)
+                .map_err(|err| protocol_error(err.to_string()))?;
+            taken += buffered - buffer.len();
+            let Some(request) = decoded else {
+                break;
+            };
+            // The offset acknowledged is that of what came before.
+            if is_getack(&request) {
+                REPLICATION.propagate(encode(&request));
+                let acked = offset.to_string();
+                tap::send(&mut writer, &["REPLCONF", "ACK", &acked]).await?;
+            } else {
+                apply(context, &mut client, &request).await;
+            }
+            offset += mem::take(&mut taken) as u64;
+        }
+        tokio::select! {
+            read = reader.read_buf(&mut buffer) => {
+                if read? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                deadline = Instant::now() + MASTER_TIMEOUT;
+            }
+            _ = acks.tick() => {
+                tap::send(&mut writer, &["REPLCONF", "ACK", &offset.to_string()]).await?;
+            }
+            _ = sleep_until(deadline) => return Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+}
+
+/// Whether `request` is the master's REPLCONF GETACK, asking where the
+/// replica is in the stream.
+fn is_getack(request: &[Entry]) -> bool {
+    matches!(
+        request,
+        [Entry::Text(name), Entry::Text(subcommand), ..]
+            if name.eq_ignore_ascii_case("REPLCONF") && subcommand.eq_ignore_ascii_case("GETACK")
+    )
+}
+
+/// `request` as the master sent it, as far as can be told.
+fn encode(request: &[Entry]) -> Bytes {
+    let mut stream = BytesMut::new();
+    Entry::Array(request.to_vec()).encode(&mut stream);
+    stream.freeze()
+}
+
+/// Runs a request propagated by the master like a write of any client,
+/// without replying to it. What fails is only reported: the master applied
+/// it already.
+async fn apply(context: &Context, client: &mut ClientState, request: &[Entry]) {
+    let _write = context.lock(request, true).await;
+    // Replicas of this one are sent the stream as is, along with the write
+    // for copies of the dataset to have both or neither.
+    REPLICATION.propagate(encode(request));
+    let cmd = match CommandParser::new(request) {
+        Ok(cmd) => cmd,
+        Err(err) => {
+            let reply = err.reply(request).to_string();
+            eprintln!("master sent {:?}: {}", request.first(), reply.trim_end());
+            return;
+        }
+    };
+    // Keys it expired are there still, until the master deletes them.
+    let reply = REPLICATION
+        .from_master(cmd.execute(&*context.storage, client))
+        .await;
+    let expiry = client.expiry.take();
+    // Masters running scripts as such, as older Redis did, are followed by
+    // logging what they wrote.
+    let effects = client.script_effects.take();
+    match reply {
+        Ok(reply) if command::is_write(request) => {
+            TRACKING.invalidate(&command::keys(request), None);
+            match effects {
+                Some(effects) => context.log_effects(effects),
+                None => context.log_write(request, &reply, expiry),
+            }
+        }
+        Ok(_) => {}
+        Err(err) => eprintln!("failed applying {:?} from master: {}", request.first(), err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        server::Server,
+        storage::{Data, InMemoryStorage, Storage, Value},
+    };
+
+    async fn read_request(stream: &mut BufReader<TcpStream>) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        let count: usize = line.trim_end()[1..].parse().unwrap();
+        for _ in 0..count * 2 {
+            line.clear();
+            stream.read_line(&mut line).await.unwrap();
+            lines.push(line.trim_end().to_string());
+        }
+        lines.into_iter().skip(1).step_by(2).collect()
+    }
+
+    #[tokio::test]
+    async fn should_load_the_snapshot_then_apply_the_feed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let master = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            for (expected, reply) in [
+                (&["PING"][..], "+PONG\r\n"),
+                (&["REPLCONF", "listening-port", "7000"], "+OK\r\n"),
+                (&["REPLCONF", "capa", "psync2"], "+OK\r\n"),
+                (
+                    &["PSYNC", "?", "-1"],
+                    "+FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 0\r\n",
+                ),
+            ] {
+                assert_eq!(read_request(&mut stream).await, expected);
+                stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+            // The snapshot of an empty dataset without a checksum, then a
+            // ping and a write.
+            let mut feed = b"$18\r\nREDIS0011\xFF\0\0\0\0\0\0\0\0".to_vec();
+            feed.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+            feed.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n");
+            feed.extend_from_slice(b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n");
+            stream.get_mut().write_all(&feed).await.unwrap();
+            read_request(&mut stream).await
+        });
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let stale = Value {
+            value: Data::String(b"old".to_vec()),
+            expiry: None,
+        };
+        storage.set("stale".to_string(), stale).await;
+        let context = Server::new(storage.clone()).context();
+        let followed = follow("127.0.0.1", port, 7000, false, &context).await;
+        assert_eq!(followed.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        // 14 bytes of PING and 33 of SET.
+        assert_eq!(master.await.unwrap(), ["REPLCONF", "ACK", "47"]);
+        assert!(storage.get("stale").await.is_none());
+        let value = storage.get("key").await.unwrap();
+        assert_eq!(value.value, Data::String(b"value".to_vec()));
+    }
+}
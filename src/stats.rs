@@ -0,0 +1,130 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Server-wide counters reported by `INFO stats`.
+#[derive(Debug)]
+pub struct Stats {
+    pub total_net_input_bytes: AtomicU64,
+    /// Commands run, whatever they replied.
+    pub total_commands_processed: AtomicU64,
+    /// Clients dropped for not completing a request before the deadline.
+    pub slow_read_disconnections: AtomicU64,
+    /// Clients dropped for buffering a request larger than allowed.
+    pub query_buffer_limit_disconnections: AtomicU64,
+    /// Clients dropped for leaving too many reply bytes unread.
+    pub output_buffer_limit_disconnections: AtomicU64,
+    /// Keys deleted once they expired, on access or by the active cycle.
+    pub expired_keys: AtomicU64,
+    /// Keys deleted to stay within maxmemory.
+    pub evicted_keys: AtomicU64,
+    /// Keys read that were found.
+    pub keyspace_hits: AtomicU64,
+    /// Keys read that were missing or expired.
+    pub keyspace_misses: AtomicU64,
+    /// Clients turned away for exceeding the client limit.
+    pub rejected_connections: AtomicU64,
+    /// Replicas sent the whole dataset.
+    pub sync_full: AtomicU64,
+    /// Replicas that went on from where they were in the stream.
+    pub sync_partial_ok: AtomicU64,
+    /// Replicas that asked to go on but were sent the whole dataset.
+    pub sync_partial_err: AtomicU64,
+}
+
+pub static STATS: Stats = Stats {
+    total_net_input_bytes: AtomicU64::new(0),
+    total_commands_processed: AtomicU64::new(0),
+    slow_read_disconnections: AtomicU64::new(0),
+    query_buffer_limit_disconnections: AtomicU64::new(0),
+    output_buffer_limit_disconnections: AtomicU64::new(0),
+    expired_keys: AtomicU64::new(0),
+    evicted_keys: AtomicU64::new(0),
+    keyspace_hits: AtomicU64::new(0),
+    keyspace_misses: AtomicU64::new(0),
+    rejected_connections: AtomicU64::new(0),
+    sync_full: AtomicU64::new(0),
+    sync_partial_ok: AtomicU64::new(0),
+    sync_partial_err: AtomicU64::new(0),
+};
+
+/// The counters of `Stats` read at one point in time, for code embedding the
+/// server to monitor it without going through INFO.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub total_net_input_bytes: u64,
+    pub total_commands_processed: u64,
+    pub slow_read_disconnections: u64,
+    pub query_buffer_limit_disconnections: u64,
+    pub output_buffer_limit_disconnections: u64,
+    pub expired_keys: u64,
+    pub evicted_keys: u64,
+    pub keyspace_hits: u64,
+    pub keyspace_misses: u64,
+    pub rejected_connections: u64,
+    pub sync_full: u64,
+    pub sync_partial_ok: u64,
+    pub sync_partial_err: u64,
+}
+
+impl Stats {
+    /// Counts a read of a key, found or not.
+    pub fn lookup(&self, hit: bool) {
+        let counter = match hit {
+            true => &self.keyspace_hits,
+            false => &self.keyspace_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Metrics {
+            total_net_input_bytes: load(&self.total_net_input_bytes),
+            total_commands_processed: load(&self.total_commands_processed),
+            slow_read_disconnections: load(&self.slow_read_disconnections),
+            query_buffer_limit_disconnections: load(&self.query_buffer_limit_disconnections),
+            output_buffer_limit_disconnections: load(&self.output_buffer_limit_disconnections),
+            expired_keys: load(&self.expired_keys),
+            evicted_keys: load(&self.evicted_keys),
+            keyspace_hits: load(&self.keyspace_hits),
+            keyspace_misses: load(&self.keyspace_misses),
+            rejected_connections: load(&self.rejected_connections),
+            sync_full: load(&self.sync_full),
+            sync_partial_ok: load(&self.sync_partial_ok),
+            sync_partial_err: load(&self.sync_partial_err),
+        }
+    }
+
+    /// Renders the `# Stats` section of INFO.
+    pub fn info(&self) -> String {
+        let metrics = self.metrics();
+        format!(
+            "# Stats\r\n\
+             total_commands_processed:{}\r\n\
+             total_net_input_bytes:{}\r\n\
+             slow_read_disconnections:{}\r\n\
+             client_query_buffer_limit_disconnections:{}\r\n\
+             client_output_buffer_limit_disconnections:{}\r\n\
+             expired_keys:{}\r\n\
+             evicted_keys:{}\r\n\
+             keyspace_hits:{}\r\n\
+             keyspace_misses:{}\r\n\
+             rejected_connections:{}\r\n\
+             sync_full:{}\r\n\
+             sync_partial_ok:{}\r\n\
+             sync_partial_err:{}\r\n",
+            metrics.total_commands_processed,
+            metrics.total_net_input_bytes,
+            metrics.slow_read_disconnections,
+            metrics.query_buffer_limit_disconnections,
+            metrics.output_buffer_limit_disconnections,
+            metrics.expired_keys,
+            metrics.evicted_keys,
+            metrics.keyspace_hits,
+            metrics.keyspace_misses,
+            metrics.rejected_connections,
+            metrics.sync_full,
+            metrics.sync_partial_ok,
+            metrics.sync_partial_err,
+        )
+    }
+}
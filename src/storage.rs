@@ -1,23 +1,574 @@
-use crate::rdb::{parse_rdb_file, write_rdb_file};
+use crate::access_log::{AccessLog, AccessOp};
+use crate::blocking::Waiters;
+use crate::rdb::{parse_rdb_file, RdbWriter};
+use crate::replication::REPLICATION;
+use crate::stats::STATS;
 use async_trait::async_trait;
 use regex::Regex;
-use std::{collections::HashMap, io, time::Instant};
-use tokio::sync::RwLock;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display, Formatter},
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc, task};
+
+pub mod bitmap;
+mod dash;
+#[cfg(feature = "disk")]
+mod disk;
+mod encoding;
+pub mod geo;
+pub mod hyperloglog;
+mod keyspace;
+mod set;
+mod stream;
+mod timer;
+mod zset;
+
+pub use dash::DashMapStorage;
+#[cfg(feature = "disk")]
+pub use disk::DiskStorage;
+use keyspace::Keyspace;
+pub use keyspace::DEFAULT_SHARDS;
+pub use set::Set;
+
+pub use stream::{
+    Claim, ClaimOptions, ConsumerGroup, Fields, NewId, PendingEntry, Stream, StreamId,
+};
+pub use zset::{LexBound, ScoreBound, SortedSet};
+
+/// Keys with an expiry checked per shard and round of an active expiration
+/// cycle, as on Redis.
+const EXPIRE_SAMPLES: usize = 20;
+
+/// Longest an active expiration cycle keeps going.
+const EXPIRE_BUDGET: Duration = Duration::from_millis(25);
+
+/// Keys sampled per key evicted, Redis's default maxmemory-samples.
+const EVICTION_SAMPLES: usize = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Data {
+    /// Raw bytes rather than text, so strings can double as bitmaps.
+    String(Vec<u8>),
+    Set(Set),
+    SortedSet(SortedSet),
+    Stream(Stream),
+}
+
+impl Data {
+    /// Estimated bytes the value takes in memory, counting what holds it
+    /// together only roughly.
+    pub fn memory_usage(&self) -> usize {
+        match self {
+            Data::String(bytes) => bytes.len(),
+            Data::Set(set) => set.memory_usage(),
+            Data::SortedSet(zset) => zset.memory_usage(),
+            Data::Stream(stream) => stream.memory_usage(),
+        }
+    }
+
+    /// The name OBJECT ENCODING gives the way the value is kept. Strings are
+    /// all kept as bytes, so they are named the way Redis would keep them.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Data::String(bytes) => {
+                let int = std::str::from_utf8(bytes)
+                    .ok()
+                    .and_then(|text| text.parse::<i64>().ok().filter(|i| i.to_string() == text));
+                match int {
+                    Some(_) => "int",
+                    None if bytes.len() <= 44 => "embstr",
+                    None => "raw",
+                }
+            }
+            Data::Set(set) => set.encoding(),
+            Data::SortedSet(zset) => zset.encoding(),
+            Data::Stream(_) => "stream",
+        }
+    }
+}
+
+/// Sizes up to which sets and sorted sets keep their compact encodings,
+/// past which they turn into full structures for good. Start at Redis's
+/// defaults.
+#[derive(Debug)]
+pub struct CompactLimits {
+    /// Most members of a set of integers kept as a sorted array of them.
+    pub set_max_intset_entries: AtomicUsize,
+    /// Most members of any other set kept packed one after the other.
+    pub set_max_listpack_entries: AtomicUsize,
+    /// Longest member of a packed set.
+    pub set_max_listpack_value: AtomicUsize,
+    /// Most members of a sorted set kept packed in score order.
+    pub zset_max_listpack_entries: AtomicUsize,
+    /// Longest member of a packed sorted set.
+    pub zset_max_listpack_value: AtomicUsize,
+}
+
+pub static COMPACT: CompactLimits = CompactLimits {
+    set_max_intset_entries: AtomicUsize::new(512),
+    set_max_listpack_entries: AtomicUsize::new(128),
+    set_max_listpack_value: AtomicUsize::new(64),
+    zset_max_listpack_entries: AtomicUsize::new(128),
+    zset_max_listpack_value: AtomicUsize::new(64),
+};
+
+/// Whether keys are deleted once they expire even if nobody accesses them,
+/// turned off with DEBUG SET-ACTIVE-EXPIRE.
+pub static ACTIVE_EXPIRE: AtomicBool = AtomicBool::new(true);
+
+/// What becomes of a key found to have expired.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OnExpired {
+    /// It is deleted, and replicas told to delete it too.
+    Delete,
+    /// Reads take it for missing, but it stays until the master deletes it.
+    Hide,
+    /// It is there still, to the master's writes.
+    Keep,
+}
+
+/// How a key found to have expired is dealt with here and now. Only masters
+/// delete keys for having expired, so their replicas hold the same ones
+/// whatever their clocks say: a replica's readers don't see them, but its
+/// master's writes do until the master's DEL comes, as they were made while
+/// the key was there on the master. Clients writing to a replica that
+/// takes writes delete them for themselves.
+pub(crate) fn on_expired(write: bool) -> OnExpired {
+    if REPLICATION.master().is_none() {
+        OnExpired::Delete
+    } else if REPLICATION.applying() {
+        OnExpired::Keep
+    } else if write {
+        OnExpired::Delete
+    } else {
+        OnExpired::Hide
+    }
+}
+
+/// Whether RDB files end with a checksum of what they hold, checked when
+/// loading them. Files written without one have it as zero and are loaded
+/// unchecked, as on Redis.
+pub static RDB_CHECKSUM: AtomicBool = AtomicBool::new(true);
+
+impl CompactLimits {
+    /// The limit set with the option called `name`.
+    pub fn limit(&self, name: &str) -> Option<&AtomicUsize> {
+        match name {
+            "set-max-intset-entries" => Some(&self.set_max_intset_entries),
+            "set-max-listpack-entries" => Some(&self.set_max_listpack_entries),
+            "set-max-listpack-value" => Some(&self.set_max_listpack_value),
+            "zset-max-listpack-entries" => Some(&self.zset_max_listpack_entries),
+            "zset-max-listpack-value" => Some(&self.zset_max_listpack_value),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Value {
-    pub value: String,
-    pub expiry: Option<Instant>,
+    pub value: Data,
+    pub expiry: Option<Expiry>,
+}
+
+impl Value {
+    /// Bytes the value takes once encoded to be kept outside of memory.
+    pub fn serialized_len(&self) -> usize {
+        encoding::encode(self).len()
+    }
+}
+
+/// When a key expires, in milliseconds since the Unix epoch. Unlike an
+/// `Instant`, it still means the same once saved to disk or sent to another
+/// server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Expiry(u64);
+
+impl Expiry {
+    pub fn from_unix_ms(unix_ms: u64) -> Expiry {
+        Expiry(unix_ms)
+    }
+
+    /// `ttl` from now, `None` if that is too far away to represent.
+    pub fn after(ttl: Duration) -> Option<Expiry> {
+        let ttl = u64::try_from(ttl.as_millis()).ok()?;
+        unix_time_ms().checked_add(ttl).map(Expiry)
+    }
+
+    pub fn unix_ms(self) -> u64 {
+        self.0
+    }
+
+    /// Whether the key is gone at `now`, in milliseconds since the epoch.
+    pub fn has_passed(self, now: u64) -> bool {
+        now > self.0
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch.
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Which keys go once more memory is used than `maxmemory` allows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MaxmemoryPolicy {
+    /// None, writes that would take more memory are refused instead.
+    #[default]
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    AllKeysRandom,
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    /// The key with an expiry closest to it.
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    const NAMES: [(MaxmemoryPolicy, &'static str); 8] = [
+        (MaxmemoryPolicy::NoEviction, "noeviction"),
+        (MaxmemoryPolicy::AllKeysLru, "allkeys-lru"),
+        (MaxmemoryPolicy::AllKeysLfu, "allkeys-lfu"),
+        (MaxmemoryPolicy::AllKeysRandom, "allkeys-random"),
+        (MaxmemoryPolicy::VolatileLru, "volatile-lru"),
+        (MaxmemoryPolicy::VolatileLfu, "volatile-lfu"),
+        (MaxmemoryPolicy::VolatileRandom, "volatile-random"),
+        (MaxmemoryPolicy::VolatileTtl, "volatile-ttl"),
+    ];
+
+    /// The policy named as in Redis's configuration, regardless of case.
+    pub fn parse(name: &str) -> Option<MaxmemoryPolicy> {
+        Self::NAMES
+            .iter()
+            .find(|(_, known)| known.eq_ignore_ascii_case(name))
+            .map(|(policy, _)| *policy)
+    }
+
+    /// Whether only keys with an expiry may be evicted.
+    pub fn is_volatile(self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::VolatileLru
+                | MaxmemoryPolicy::VolatileLfu
+                | MaxmemoryPolicy::VolatileRandom
+                | MaxmemoryPolicy::VolatileTtl
+        )
+    }
+
+    /// Whether keys are picked by how often they are accessed, which is
+    /// only tracked then.
+    pub fn is_lfu(self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::AllKeysLfu | MaxmemoryPolicy::VolatileLfu
+        )
+    }
+
+    pub fn is_random(self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::AllKeysRandom | MaxmemoryPolicy::VolatileRandom
+        )
+    }
+}
+
+impl Display for MaxmemoryPolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let (_, name) = Self::NAMES
+            .iter()
+            .find(|(policy, _)| policy == self)
+            .expect("every policy is named");
+        f.write_str(name)
+    }
+}
+
+/// How much memory keys may take and what happens beyond that.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Maxmemory {
+    /// In bytes, 0 meaning no limit as on Redis.
+    pub limit: usize,
+    pub policy: MaxmemoryPolicy,
+}
+
+/// A change `Storage::update` makes to the value under a key, returning
+/// whether it changed anything. One that didn't must leave the value as it
+/// found it.
+pub type Update<'a> = dyn FnMut(&mut Option<Value>) -> bool + Send + 'a;
+
+/// A change `Storage::update_many` makes to the values under several keys.
+pub type UpdateMany<'a> = dyn FnMut(&mut WriteView) + Send + 'a;
+
+/// The values under the keys `Storage::update_many` holds, `None` for
+/// missing ones, all changed in one step.
+#[derive(Debug, Default)]
+pub struct WriteView {
+    values: BTreeMap<String, Option<Value>>,
+    /// Keys handed out by `get_mut`, the only ones counting as written.
+    changed: BTreeSet<String>,
+}
+
+impl WriteView {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.values.get(key)?.as_ref()
+    }
+
+    /// The value under `key`, which must be one of the keys held: any other
+    /// key is not protected from other clients and panics. The key counts as
+    /// written, so keys only read should go through `get`.
+    pub fn get_mut(&mut self, key: &str) -> &mut Option<Value> {
+        let value = self
+            .values
+            .get_mut(key)
+            .unwrap_or_else(|| panic!("{} is not part of the view", key));
+        self.changed.insert(key.to_string());
+        value
+    }
+
+    fn insert(&mut self, key: &str, value: Option<Value>) {
+        self.values.insert(key.to_string(), value);
+    }
+
+    /// Every key held with the value left under it and whether it was
+    /// written, in key order.
+    fn into_values(self) -> impl Iterator<Item = (String, Option<Value>, bool)> {
+        let changed = self.changed;
+        self.values.into_iter().map(move |(key, value)| {
+            let written = changed.contains(&key);
+            (key, value, written)
+        })
+    }
 }
 
+/// Where keys live. Whole values can be read and written with `get` and
+/// `set`, but a command changing a value should rather go through `update`
+/// (or `update_with`), which reads and writes it in one go so no other
+/// client can change the key in between, and without copying the value.
+/// Commands changing several keys go through `update_many` the same way.
 #[async_trait]
 pub trait Storage: Send + Sync {
     async fn set(&self, key: String, value: Value);
     async fn get(&self, key: &str) -> Option<Value>;
+    async fn del(&self, key: &str) -> bool;
+    /// Runs `f` on the value under `key`, `None` if there is none, and
+    /// stores what it leaves, deleting the key if it leaves `None`. Nothing
+    /// counts as written if `f` returns that it changed nothing.
+    async fn update(&self, key: &str, f: &mut Update<'_>);
+    /// `update` for several keys at once: `f` sees their values together,
+    /// and what it leaves is stored before any other client gets to them.
+    async fn update_many(&self, keys: &[&str], f: &mut UpdateMany<'_>);
     async fn save(&self) -> Result<(), io::Error>;
-    async fn load(&mut self) -> Result<(), io::Error>;
+    async fn load(&self) -> Result<(), io::Error>;
     async fn keys(&self, key: &str) -> Option<Vec<String>>;
-    async fn config(&self) -> RdbConfig;
+    /// A copy of every key not expired yet with its value.
+    async fn snapshot(&self) -> Vec<(String, Value)>;
+    fn config(&self) -> RdbConfig;
+    /// Deletes some of the keys that expired without being accessed since,
+    /// returning how many.
+    async fn purge_expired(&self) -> usize;
+    /// Deletes every key whose deadline has passed, then waits until the
+    /// next one passes or a key gets an earlier one, so keys go close to
+    /// when they expire. Returns how many keys it deleted.
+    async fn expire_due(&self) -> usize;
+    /// The access frequency LFU eviction goes by of `key`, without counting
+    /// this as an access. `None` if the key is missing or not tracked.
+    async fn frequency(&self, key: &str) -> Option<u8>;
+    /// Evicts keys until no more memory is used than maxmemory allows,
+    /// returning false if the policy left too much in use.
+    async fn evict(&self) -> bool;
+    /// Estimated bytes taken by every key and value.
+    fn used_memory(&self) -> usize;
+    fn maxmemory(&self) -> Maxmemory;
+    /// Goes by `maxmemory` from now on, failing with why if it can't.
+    fn set_maxmemory(&self, maxmemory: Maxmemory) -> Result<(), String>;
+    /// Starts saving the dataset in the background, returning false if a
+    /// save is already under way.
+    fn bgsave(&self) -> bool;
+    fn persistence(&self) -> Persistence;
+    fn waiters(&self) -> Arc<Waiters>;
+}
+
+impl<'s> dyn Storage + 's {
+    /// `update` for a closure that runs once and returns something, such
+    /// as the reply of the command running it.
+    pub async fn update_with<T: Send>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&mut Option<Value>) -> T + Send,
+    ) -> T {
+        self.update_if_with(key, |value| (f(value), true)).await
+    }
+
+    /// `update_with` for a closure that also returns whether it changed the
+    /// value, so one that only looked at it wakes no blocked client and
+    /// leaves no write behind.
+    pub async fn update_if_with<T: Send>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&mut Option<Value>) -> (T, bool) + Send,
+    ) -> T {
+        let mut f = Some(f);
+        let mut result = None;
+        self.update(key, &mut |value| {
+            let (returned, changed) = f.take().expect("update runs the closure once")(value);
+            result = Some(returned);
+            changed
+        })
+        .await;
+        result.expect("update runs the closure")
+    }
+
+    /// `update_many` for a closure that runs once and returns something.
+    pub async fn update_many_with<T: Send>(
+        &self,
+        keys: &[&str],
+        f: impl FnOnce(&mut WriteView) -> T + Send,
+    ) -> T {
+        let mut f = Some(f);
+        let mut result = None;
+        self.update_many(keys, &mut |view| {
+            if let Some(f) = f.take() {
+                result = Some(f(view));
+            }
+        })
+        .await;
+        result.expect("update_many runs the closure")
+    }
+
+    /// Sets every key of `keys`, such as those of a loaded RDB file, after
+    /// deleting all others unless merging them in.
+    pub async fn load_keys(&self, keys: impl IntoIterator<Item = (String, Value)>, merge: bool) {
+        if !merge {
+            for key in self.keys("*").await.unwrap_or_default() {
+                self.del(&key).await;
+            }
+        }
+        for (key, value) in keys {
+            self.set(key, value).await;
+        }
+    }
+}
+
+/// Where saving the dataset stands, as INFO persistence reports it.
+#[derive(Clone, Copy, Debug)]
+pub struct Persistence {
+    /// Keys written or deleted since the last successful save.
+    pub changes_since_save: u64,
+    /// When the dataset was last saved successfully, or else loaded, in
+    /// seconds since the epoch.
+    pub last_save: u64,
+    /// When a save was last attempted, in seconds since the epoch.
+    pub last_attempt: u64,
+    pub in_progress: bool,
+    pub last_save_ok: bool,
+}
+
+/// Bookkeeping of saves, shared with the task saving in the background.
+#[derive(Debug)]
+struct Saves {
+    in_progress: AtomicBool,
+    /// Changes the keyspace had seen when the last successful save began.
+    changes_at_save: AtomicU64,
+    last_save: AtomicU64,
+    last_attempt: AtomicU64,
+    last_save_ok: AtomicBool,
+}
+
+impl Default for Saves {
+    fn default() -> Self {
+        let now = unix_time_ms() / 1000;
+        Saves {
+            in_progress: AtomicBool::new(false),
+            changes_at_save: AtomicU64::new(0),
+            last_save: AtomicU64::new(now),
+            last_attempt: AtomicU64::new(now),
+            last_save_ok: AtomicBool::new(true),
+        }
+    }
+}
+
+impl Saves {
+    /// Claims the right to save, returning false if a save is already under
+    /// way.
+    fn begin(&self) -> bool {
+        !self.in_progress.swap(true, Ordering::AcqRel)
+    }
+
+    /// Records how a save begun when the keyspace had seen `changes` went.
+    fn end(&self, changes: u64, ok: bool) {
+        let now = unix_time_ms() / 1000;
+        if ok {
+            self.changes_at_save.store(changes, Ordering::Relaxed);
+            self.last_save.store(now, Ordering::Relaxed);
+        }
+        self.last_attempt.store(now, Ordering::Relaxed);
+        self.last_save_ok.store(ok, Ordering::Relaxed);
+        self.in_progress.store(false, Ordering::Release);
+    }
+
+    /// Where saves stand once the dataset has seen `changes` in all.
+    fn report(&self, changes: u64) -> Persistence {
+        Persistence {
+            changes_since_save: changes - self.changes_at_save.load(Ordering::Relaxed),
+            last_save: self.last_save.load(Ordering::Relaxed),
+            last_attempt: self.last_attempt.load(Ordering::Relaxed),
+            in_progress: self.in_progress.load(Ordering::Acquire),
+            last_save_ok: self.last_save_ok.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Saves `map` to `path`, unless a save is under way already.
+async fn save(map: &Keyspace, saves: &Saves, path: &str) -> Result<(), io::Error> {
+    if !saves.begin() {
+        return Err(io::Error::other("Background save already in progress"));
+    }
+    let changes = map.changes();
+    let saved = write_keyspace(map, path).await;
+    saves.end(changes, saved.is_ok());
+    saved
+}
+
+/// Writes `map` out a shard at a time, so writers are held up by one shard
+/// at most. Each shard is copied while the one before is written out on a
+/// blocking thread, so no more than two shards' worth of it is ever copied
+/// and the runtime never waits on the disk.
+async fn write_keyspace(map: &Keyspace, path: &str) -> Result<(), io::Error> {
+    let mut writer = RdbWriter::create(path, map.used_memory())?;
+    let (copied, mut shards) = mpsc::channel::<Vec<(String, Value)>>(1);
+    let writing = task::spawn_blocking(move || {
+        while let Some(entries) = shards.blocking_recv() {
+            for (key, value) in entries {
+                writer.write_entry(&key, &value)?;
+            }
+        }
+        writer.finish()
+    });
+    for at in 0..map.shard_count() {
+        // Only refused once writing failed, which `writing` tells.
+        if copied.send(map.snapshot_shard(at).await).await.is_err() {
+            break;
+        }
+    }
+    drop(copied);
+    writing.await.map_err(io::Error::other)?
+}
+
+async fn snapshot(map: &Keyspace) -> Vec<(String, Value)> {
+    let mut entries = Vec::new();
+    for at in 0..map.shard_count() {
+        entries.extend(map.snapshot_shard(at).await);
+    }
+    entries
 }
 
 #[derive(Clone, Debug)]
@@ -32,46 +583,94 @@ impl RdbConfig {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct InMemoryStorage {
-    map: RwLock<HashMap<String, Value>>,
+    map: Keyspace,
+    maxmemory: Mutex<Maxmemory>,
+    /// Saves do nothing, but are accounted for all the same.
+    saves: Saves,
+    waiters: Arc<Waiters>,
+    access_log: Option<Arc<AccessLog>>,
 }
 
 impl InMemoryStorage {
     pub fn new() -> Self {
         Self {
-            map: RwLock::new(HashMap::new()),
+            map: Keyspace::default(),
+            maxmemory: Mutex::default(),
+            saves: Saves::default(),
+            waiters: Arc::new(Waiters::new()),
+            access_log: None,
         }
     }
+
+    /// Spreads keys over `shards` independently locked shards, one at the
+    /// least. Must be set before any key is stored.
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        self.map = Keyspace::new(shards);
+        self
+    }
+
+    /// Evicts keys as `maxmemory` says once they take more memory than it
+    /// allows.
+    pub fn with_maxmemory(mut self, maxmemory: Maxmemory) -> Self {
+        self.maxmemory = Mutex::new(maxmemory);
+        self
+    }
+
+    pub fn with_access_log(mut self, access_log: Arc<AccessLog>) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
 }
 
 #[async_trait]
 impl Storage for InMemoryStorage {
     async fn set(&self, key: String, value: Value) {
-        self.map.write().await.insert(key, value);
+        let existed = self.map.insert(key.clone(), value).await;
+        record_access(&self.access_log, &key, AccessOp::Set, existed);
+        self.waiters.wake(&key);
     }
 
     async fn get(&self, key: &str) -> Option<Value> {
-        let value = self.map.read().await.get(key)?.clone();
-        if let Some(expiry) = value.expiry {
-            if Instant::now() > expiry {
-                return None;
-            }
+        let value = self.map.get(key).await;
+        record_read(&self.access_log, key, value.is_some());
+        value
+    }
+
+    async fn del(&self, key: &str) -> bool {
+        let removed = self.map.remove(key).await;
+        record_access(&self.access_log, key, AccessOp::Del, removed);
+        removed
+    }
+
+    async fn update(&self, key: &str, f: &mut Update<'_>) {
+        if let Some((existed, stored)) = self.map.update(key, f).await {
+            record_update(&self.access_log, &self.waiters, key, existed, stored);
+        }
+    }
+
+    async fn update_many(&self, keys: &[&str], f: &mut UpdateMany<'_>) {
+        for (key, existed, stored) in self.map.update_many(keys, f).await {
+            record_update(&self.access_log, &self.waiters, &key, existed, stored);
         }
-        Some(value)
     }
 
     async fn save(&self) -> Result<(), io::Error> {
+        if !self.saves.begin() {
+            return Err(io::Error::other("Background save already in progress"));
+        }
+        self.saves.end(self.map.changes(), true);
         Ok(())
     }
 
-    async fn load(&mut self) -> Result<(), io::Error> {
+    async fn load(&self) -> Result<(), io::Error> {
         Ok(())
     }
 
     async fn keys(&self, k: &str) -> Option<Vec<String>> {
-        let map = self.map.read().await;
-        let keys: Vec<&str> = map.keys().map(String::as_str).collect();
+        let keys = self.map.keys().await;
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
         Some(
             needle_in_haystack(k, &keys)
                 .into_iter()
@@ -80,18 +679,73 @@ impl Storage for InMemoryStorage {
         )
     }
 
-    async fn config(&self) -> RdbConfig {
+    async fn snapshot(&self) -> Vec<(String, Value)> {
+        snapshot(&self.map).await
+    }
+
+    fn config(&self) -> RdbConfig {
         RdbConfig {
             dir: "".to_string(),
             path: "".to_string(),
         }
     }
+
+    async fn purge_expired(&self) -> usize {
+        purge_expired(&self.map).await
+    }
+
+    async fn expire_due(&self) -> usize {
+        self.map.expire_due().await
+    }
+
+    async fn frequency(&self, key: &str) -> Option<u8> {
+        self.map.frequency(key).await
+    }
+
+    async fn evict(&self) -> bool {
+        let maxmemory = *self.maxmemory.lock().unwrap();
+        evict(&self.map, maxmemory).await
+    }
+
+    fn used_memory(&self) -> usize {
+        self.map.used_memory()
+    }
+
+    fn maxmemory(&self) -> Maxmemory {
+        *self.maxmemory.lock().unwrap()
+    }
+
+    fn set_maxmemory(&self, maxmemory: Maxmemory) -> Result<(), String> {
+        *self.maxmemory.lock().unwrap() = maxmemory;
+        Ok(())
+    }
+
+    fn bgsave(&self) -> bool {
+        if !self.saves.begin() {
+            return false;
+        }
+        self.saves.end(self.map.changes(), true);
+        true
+    }
+
+    fn persistence(&self) -> Persistence {
+        self.saves.report(self.map.changes())
+    }
+
+    fn waiters(&self) -> Arc<Waiters> {
+        Arc::clone(&self.waiters)
+    }
 }
 
 #[derive(Debug)]
 pub struct RdbStorage {
     config: RdbConfig,
-    map: RwLock<HashMap<String, Value>>,
+    /// Shared with the task saving in the background.
+    map: Arc<Keyspace>,
+    maxmemory: Mutex<Maxmemory>,
+    saves: Arc<Saves>,
+    waiters: Arc<Waiters>,
+    access_log: Option<Arc<AccessLog>>,
 }
 
 impl RdbStorage {
@@ -100,44 +754,82 @@ impl RdbStorage {
         let path = path.to_string();
         Self {
             config: RdbConfig { dir, path },
-            map: RwLock::new(HashMap::new()),
+            map: Arc::new(Keyspace::default()),
+            maxmemory: Mutex::default(),
+            saves: Arc::new(Saves::default()),
+            waiters: Arc::new(Waiters::new()),
+            access_log: None,
         }
     }
+
+    /// Spreads keys over `shards` independently locked shards, one at the
+    /// least. Must be set before the file is loaded.
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        self.map = Arc::new(Keyspace::new(shards));
+        self
+    }
+
+    /// Evicts keys as `maxmemory` says once they take more memory than it
+    /// allows.
+    pub fn with_maxmemory(mut self, maxmemory: Maxmemory) -> Self {
+        self.maxmemory = Mutex::new(maxmemory);
+        self
+    }
+
+    pub fn with_access_log(mut self, access_log: Arc<AccessLog>) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
 }
 
 #[async_trait]
 impl Storage for RdbStorage {
     async fn set(&self, key: String, value: Value) {
-        self.map.write().await.insert(key, value);
+        let existed = self.map.insert(key.clone(), value).await;
+        record_access(&self.access_log, &key, AccessOp::Set, existed);
+        self.waiters.wake(&key);
     }
 
     async fn get(&self, key: &str) -> Option<Value> {
-        let value = self.map.read().await.get(key)?.clone();
-        if let Some(expiry) = value.expiry {
-            if Instant::now() > expiry {
-                return None;
-            }
+        let value = self.map.get(key).await;
+        record_read(&self.access_log, key, value.is_some());
+        value
+    }
+
+    async fn del(&self, key: &str) -> bool {
+        let removed = self.map.remove(key).await;
+        record_access(&self.access_log, key, AccessOp::Del, removed);
+        removed
+    }
+
+    async fn update(&self, key: &str, f: &mut Update<'_>) {
+        if let Some((existed, stored)) = self.map.update(key, f).await {
+            record_update(&self.access_log, &self.waiters, key, existed, stored);
+        }
+    }
+
+    async fn update_many(&self, keys: &[&str], f: &mut UpdateMany<'_>) {
+        for (key, existed, stored) in self.map.update_many(keys, f).await {
+            record_update(&self.access_log, &self.waiters, &key, existed, stored);
         }
-        Some(value)
     }
 
     async fn save(&self) -> Result<(), io::Error> {
-        let m = self.map.read().await.clone();
-        write_rdb_file(&self.config.config_file(), m)
+        save(&self.map, &self.saves, &self.config.config_file()).await
     }
 
-    async fn load(&mut self) -> Result<(), io::Error> {
-        println!("loading file... {:?}", self.config);
+    async fn load(&self) -> Result<(), io::Error> {
         let map = parse_rdb_file(&self.config.config_file())
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed parsing file"))
-            .unwrap();
-        self.map = RwLock::new(map);
+            .map_err(|err| io::Error::other(format!("failed parsing file: {}", err)))?;
+        self.map.replace(map).await;
+        // What was just loaded is as good as saved.
+        self.saves.end(self.map.changes(), true);
         Ok(())
     }
 
     async fn keys(&self, k: &str) -> Option<Vec<String>> {
-        let map = self.map.read().await;
-        let keys: Vec<&str> = map.keys().map(String::as_str).collect();
+        let keys = self.map.keys().await;
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
         Some(
             needle_in_haystack(k, &keys)
                 .into_iter()
@@ -146,9 +838,124 @@ impl Storage for RdbStorage {
         )
     }
 
-    async fn config(&self) -> RdbConfig {
+    async fn snapshot(&self) -> Vec<(String, Value)> {
+        snapshot(&self.map).await
+    }
+
+    fn config(&self) -> RdbConfig {
         self.config.clone()
     }
+
+    async fn purge_expired(&self) -> usize {
+        purge_expired(&self.map).await
+    }
+
+    async fn expire_due(&self) -> usize {
+        self.map.expire_due().await
+    }
+
+    async fn frequency(&self, key: &str) -> Option<u8> {
+        self.map.frequency(key).await
+    }
+
+    async fn evict(&self) -> bool {
+        let maxmemory = *self.maxmemory.lock().unwrap();
+        evict(&self.map, maxmemory).await
+    }
+
+    fn used_memory(&self) -> usize {
+        self.map.used_memory()
+    }
+
+    fn maxmemory(&self) -> Maxmemory {
+        *self.maxmemory.lock().unwrap()
+    }
+
+    fn set_maxmemory(&self, maxmemory: Maxmemory) -> Result<(), String> {
+        *self.maxmemory.lock().unwrap() = maxmemory;
+        Ok(())
+    }
+
+    fn bgsave(&self) -> bool {
+        if !self.saves.begin() {
+            return false;
+        }
+        let map = Arc::clone(&self.map);
+        let saves = Arc::clone(&self.saves);
+        let path = self.config.config_file();
+        tokio::spawn(async move {
+            let changes = map.changes();
+            let saved = write_keyspace(&map, &path).await;
+            if let Err(err) = &saved {
+                eprintln!("background save failed: {}", err);
+            }
+            saves.end(changes, saved.is_ok());
+        });
+        true
+    }
+
+    fn persistence(&self) -> Persistence {
+        self.saves.report(self.map.changes())
+    }
+
+    fn waiters(&self) -> Arc<Waiters> {
+        Arc::clone(&self.waiters)
+    }
+}
+
+/// Runs one active expiration cycle over `map`: like Redis, sampled keys
+/// are checked round after round for as long as a quarter of them turn out
+/// expired and the time budget allows.
+async fn purge_expired(map: &Keyspace) -> usize {
+    let started = Instant::now();
+    let mut purged = 0;
+    loop {
+        let (checked, expired) = map.purge_expired(EXPIRE_SAMPLES).await;
+        purged += expired;
+        if expired * 4 <= checked || started.elapsed() > EXPIRE_BUDGET {
+            return purged;
+        }
+    }
+}
+
+/// Evicts keys from `map` one at a time until it fits in `maxmemory`,
+/// returning false if the policy ran out of keys to evict first.
+async fn evict(map: &Keyspace, maxmemory: Maxmemory) -> bool {
+    while maxmemory.limit > 0 && map.used_memory() > maxmemory.limit {
+        if !map.evict(maxmemory.policy, EVICTION_SAMPLES).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// Accounts for an update of `key`, which existed before or not and is
+/// stored after or not, waking clients waiting for it if it is.
+fn record_update(
+    access_log: &Option<Arc<AccessLog>>,
+    waiters: &Waiters,
+    key: &str,
+    existed: bool,
+    stored: bool,
+) {
+    if stored {
+        record_access(access_log, key, AccessOp::Set, existed);
+        waiters.wake(key);
+    } else if existed {
+        record_access(access_log, key, AccessOp::Del, true);
+    }
+}
+
+/// Accounts for a read of `key`, which found it or not.
+fn record_read(access_log: &Option<Arc<AccessLog>>, key: &str, hit: bool) {
+    STATS.lookup(hit);
+    record_access(access_log, key, AccessOp::Get, hit);
+}
+
+fn record_access(access_log: &Option<Arc<AccessLog>>, key: &str, op: AccessOp, hit: bool) {
+    if let Some(access_log) = access_log {
+        access_log.record(key, op, hit);
+    }
 }
 
 fn needle_in_haystack<'a>(key: &str, haystack: &[&'a str]) -> Vec<&'a str> {
@@ -162,7 +969,7 @@ fn needle_in_haystack<'a>(key: &str, haystack: &[&'a str]) -> Vec<&'a str> {
     let re = Regex::new(&needle).unwrap();
     haystack
         .iter()
-        .map(|&it| it)
+        .copied()
         .filter(|&it| re.is_match(it))
         .collect()
 }
@@ -171,6 +978,203 @@ fn needle_in_haystack<'a>(key: &str, haystack: &[&'a str]) -> Vec<&'a str> {
 mod tests {
     use super::*;
 
+    fn string(text: &str) -> Value {
+        Value {
+            value: Data::String(text.as_bytes().to_vec()),
+            expiry: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_keep_memory_under_maxmemory() {
+        let full = |policy| {
+            InMemoryStorage::new().with_maxmemory(Maxmemory {
+                limit: 1000,
+                policy,
+            })
+        };
+        let storage = full(MaxmemoryPolicy::AllKeysLru);
+        for key in 0..100 {
+            storage.set(key.to_string(), string("value")).await;
+            assert!(storage.evict().await);
+        }
+        assert!(storage.used_memory() <= 1000);
+        assert!(storage.used_memory() > 500);
+        assert!(storage.get("0").await.is_none());
+
+        let storage = full(MaxmemoryPolicy::VolatileLru);
+        storage
+            .set("big".to_string(), string(&"v".repeat(2000)))
+            .await;
+        assert!(!storage.evict().await);
+        storage.del("big").await;
+        assert_eq!(storage.used_memory(), 0);
+        assert!(storage.evict().await);
+    }
+
+    #[tokio::test]
+    async fn should_update_values_in_place() {
+        let storage: &dyn Storage = &InMemoryStorage::new();
+        let expired = Value {
+            expiry: Some(Expiry::from_unix_ms(unix_time_ms() - 1000)),
+            ..string("old")
+        };
+        storage.set("key".to_string(), expired).await;
+
+        let append = |value: &mut Option<Value>| {
+            let found = value.is_some();
+            let value = value.get_or_insert_with(|| string(""));
+            if let Data::String(bytes) = &mut value.value {
+                bytes.extend_from_slice(b"x");
+            }
+            found
+        };
+        assert!(!storage.update_with("key", append).await);
+        assert!(storage.update_with("key", append).await);
+        assert_eq!(storage.get("key").await.unwrap().value, string("xx").value);
+        assert!(storage.get("key").await.unwrap().expiry.is_none());
+
+        storage.update_with("key", |value| *value = None).await;
+        assert!(storage.get("key").await.is_none());
+        assert_eq!(storage.used_memory(), 0);
+    }
+
+    #[tokio::test]
+    async fn should_update_many_keys_at_once() {
+        let storages: [&dyn Storage; 2] = [&InMemoryStorage::new(), &DashMapStorage::new()];
+        for storage in storages {
+            storage.set("from".to_string(), string("value")).await;
+            // The same key twice is a single entry in the view.
+            let keys = ["to", "from", "to"];
+            let found = storage
+                .update_many_with(&keys, |view| {
+                    let found = view.get("to").is_none();
+                    let value = view.get_mut("from").take();
+                    *view.get_mut("to") = value;
+                    found
+                })
+                .await;
+            assert!(found);
+            assert!(storage.get("from").await.is_none());
+            assert_eq!(
+                storage.get("to").await.unwrap().value,
+                string("value").value
+            );
+
+            storage
+                .update_many_with(&["to"], |view| *view.get_mut("to") = None)
+                .await;
+            assert!(storage.get("to").await.is_none());
+            assert_eq!(storage.used_memory(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_wake_nobody_on_updates_changing_nothing() {
+        let storages: [&dyn Storage; 2] = [&InMemoryStorage::new(), &DashMapStorage::new()];
+        for storage in storages {
+            storage.set("key".to_string(), string("value")).await;
+            let keys = ["key".to_string()];
+            let notify = storage.waiters().register(&keys);
+
+            let found = storage
+                .update_if_with("key", |value| (value.is_some(), false))
+                .await;
+            assert!(found);
+            storage
+                .update_many_with(&["key", "other"], |view| view.get("key").is_some())
+                .await;
+            let woken = tokio::time::timeout(Duration::from_millis(10), notify.notified()).await;
+            assert!(woken.is_err());
+            assert_eq!(
+                storage.get("key").await.unwrap().value,
+                string("value").value
+            );
+            assert!(storage.get("other").await.is_none());
+
+            storage.update_with("key", |_| ()).await;
+            let woken = tokio::time::timeout(Duration::from_millis(10), notify.notified()).await;
+            assert!(woken.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn should_save_a_shard_at_a_time() {
+        let dir = std::env::temp_dir().join(format!("save-{}", std::process::id()));
+        let storage = RdbStorage::new(dir.to_str().unwrap(), "dump.rdb").with_shards(4);
+        for key in 0..20 {
+            storage.set(format!("key{}", key), string("value")).await;
+        }
+        let set = Value {
+            value: Data::Set(Set::from_iter(["member".to_string()])),
+            expiry: None,
+        };
+        storage.set("set".to_string(), set).await;
+        storage.save().await.unwrap();
+
+        let written = std::fs::read(dir.join("dump.rdb")).unwrap();
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        for key in 0..20 {
+            let key = format!("key{}", key);
+            let mut entry = vec![0x00, key.len() as u8];
+            entry.extend_from_slice(key.as_bytes());
+            entry.extend_from_slice(b"\x05value");
+            assert!(written.windows(entry.len()).any(|window| window == entry));
+        }
+        assert!(written.windows(6).any(|window| window == b"member"));
+        // The end of file marker, then the checksum.
+        assert_eq!(written[written.len() - 9], 0xFF);
+        // The temporary file was renamed into place.
+        assert_eq!(files, 1);
+    }
+
+    #[tokio::test]
+    async fn should_keep_expiries_across_restarts() {
+        let dir = std::env::temp_dir().join(format!("restart-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let storage = RdbStorage::new(dir, "dump.rdb");
+        let expiry = Expiry::after(Duration::from_secs(100));
+        let volatile = Value {
+            value: Data::String(b"value".to_vec()),
+            expiry,
+        };
+        storage.set("volatile".to_string(), volatile).await;
+        storage.set("persistent".to_string(), string("value")).await;
+        storage.save().await.unwrap();
+
+        let restarted = RdbStorage::new(dir, "dump.rdb");
+        let loaded = restarted.load().await;
+        std::fs::remove_dir_all(dir).unwrap();
+        loaded.unwrap();
+        assert_eq!(restarted.get("volatile").await.unwrap().expiry, expiry);
+        assert_eq!(restarted.get("persistent").await.unwrap().expiry, None);
+    }
+
+    #[tokio::test]
+    async fn should_save_in_the_background_one_save_at_a_time() {
+        let dir = std::env::temp_dir().join(format!("bgsave-{}", std::process::id()));
+        let storage = RdbStorage::new(dir.to_str().unwrap(), "dump.rdb");
+        storage.set("key".to_string(), string("value")).await;
+        storage.set("key".to_string(), string("value")).await;
+        assert_eq!(storage.persistence().changes_since_save, 2);
+
+        assert!(storage.bgsave());
+        // The save can't have run yet on this single threaded runtime.
+        assert!(!storage.bgsave());
+        assert!(storage.save().await.is_err());
+        while storage.persistence().in_progress {
+            tokio::task::yield_now().await;
+        }
+
+        let saves = storage.persistence();
+        let written = std::fs::read(dir.join("dump.rdb"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(written.is_ok());
+        assert!(saves.last_save_ok);
+        assert_eq!(saves.changes_since_save, 0);
+    }
+
     #[test]
     fn should_match_asterisk() {
         let needle = "*";
@@ -0,0 +1,331 @@
+//! Bit-level operations on string values. Bit 0 is the most significant bit
+//! of the first byte, as in Redis.
+
+/// Unit the range arguments of BITCOUNT and BITPOS are expressed in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitUnit {
+    Byte,
+    Bit,
+}
+
+/// Inclusive range of bytes or bits, negative indexes counting from the end.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BitRange {
+    pub start: i64,
+    pub end: i64,
+    pub unit: BitUnit,
+}
+
+impl BitRange {
+    /// Resolves the range to inclusive bit offsets within `len` bytes, `None`
+    /// if it selects nothing.
+    fn resolve(&self, len: usize) -> Option<(usize, usize)> {
+        let total = match self.unit {
+            BitUnit::Byte => len as i64,
+            BitUnit::Bit => len as i64 * 8,
+        };
+        let normalize = |index: i64| {
+            if index < 0 {
+                (total + index).max(0)
+            } else {
+                index
+            }
+        };
+        let start = normalize(self.start);
+        let end = normalize(self.end).min(total - 1);
+        if start > end {
+            return None;
+        }
+
+        let (start, end) = (start as usize, end as usize);
+        match self.unit {
+            BitUnit::Byte => Some((start * 8, end * 8 + 7)),
+            BitUnit::Bit => Some((start, end)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+pub fn get_bit(bytes: &[u8], offset: usize) -> bool {
+    bytes
+        .get(offset / 8)
+        .is_some_and(|byte| byte & (0x80 >> (offset % 8)) != 0)
+}
+
+/// Sets the bit at `offset`, zero-padding `bytes` as needed, and returns its
+/// previous value.
+pub fn set_bit(bytes: &mut Vec<u8>, offset: usize, value: bool) -> bool {
+    if bytes.len() <= offset / 8 {
+        bytes.resize(offset / 8 + 1, 0);
+    }
+    let byte = &mut bytes[offset / 8];
+    let mask = 0x80 >> (offset % 8);
+    let previous = *byte & mask != 0;
+    if value {
+        *byte |= mask;
+    } else {
+        *byte &= !mask;
+    }
+    previous
+}
+
+/// Bits of byte `index` that fall within the inclusive bit range.
+fn byte_mask(index: usize, first: usize, last: usize) -> u8 {
+    let from = if index == first / 8 { first % 8 } else { 0 };
+    let to = if index == last / 8 { last % 8 } else { 7 };
+    (0xFF >> from) & (0xFF << (7 - to))
+}
+
+/// Number of set bits, in `range` when given.
+pub fn count(bytes: &[u8], range: Option<BitRange>) -> usize {
+    let (first, last) = match range {
+        Some(range) => match range.resolve(bytes.len()) {
+            Some(bits) => bits,
+            None => return 0,
+        },
+        None if bytes.is_empty() => return 0,
+        None => (0, bytes.len() * 8 - 1),
+    };
+
+    (first / 8..=last / 8)
+        .map(|index| (bytes[index] & byte_mask(index, first, last)).count_ones() as usize)
+        .sum()
+}
+
+/// Offset of the first bit equal to `bit` within `range`, or -1.
+///
+/// When looking for a clear bit without an explicit end, the value is
+/// treated as padded with zeros, so a fully set value yields the offset
+/// right past it.
+pub fn position(bytes: &[u8], bit: bool, range: BitRange, has_end: bool) -> i64 {
+    let Some((first, last)) = range.resolve(bytes.len()) else {
+        return -1;
+    };
+
+    for (index, byte) in bytes.iter().enumerate().take(last / 8 + 1).skip(first / 8) {
+        let candidates = if bit { *byte } else { !byte };
+        let candidates = candidates & byte_mask(index, first, last);
+        if candidates != 0 {
+            return (index * 8 + candidates.leading_zeros() as usize) as i64;
+        }
+    }
+    if !bit && !has_end {
+        return last as i64 + 1;
+    }
+    -1
+}
+
+/// Combines `sources` byte by byte, shorter ones being zero-padded to the
+/// longest. `BitOp::Not` only looks at the first source.
+pub fn bit_op(op: BitOp, sources: &[impl AsRef<[u8]>]) -> Vec<u8> {
+    if op == BitOp::Not {
+        return sources
+            .first()
+            .map(|source| source.as_ref().iter().map(|byte| !byte).collect())
+            .unwrap_or_default();
+    }
+
+    let len = sources
+        .iter()
+        .map(|source| source.as_ref().len())
+        .max()
+        .unwrap_or(0);
+    (0..len)
+        .map(|index| {
+            let mut bytes = sources
+                .iter()
+                .map(|source| source.as_ref().get(index).copied().unwrap_or(0));
+            let first = bytes.next().unwrap_or(0);
+            bytes.fold(first, |acc, byte| match op {
+                BitOp::And => acc & byte,
+                BitOp::Or => acc | byte,
+                BitOp::Xor => acc ^ byte,
+                BitOp::Not => unreachable!(),
+            })
+        })
+        .collect()
+}
+
+/// Integer type of a BITFIELD field: `i1` to `i64` or `u1` to `u63`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FieldType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+/// What BITFIELD does when a write does not fit its field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+impl FieldType {
+    /// Parses `i<bits>` or `u<bits>`.
+    pub fn parse(text: &str) -> Option<FieldType> {
+        let (signed, bits) = match text.as_bytes().first()? {
+            b'i' | b'I' => (true, &text[1..]),
+            b'u' | b'U' => (false, &text[1..]),
+            _ => return None,
+        };
+        let bits: u32 = bits.parse().ok()?;
+        let max = if signed { 64 } else { 63 };
+        (1..=max)
+            .contains(&bits)
+            .then_some(FieldType { signed, bits })
+    }
+
+    fn range(&self) -> (i128, i128) {
+        if self.signed {
+            (-(1 << (self.bits - 1)), (1 << (self.bits - 1)) - 1)
+        } else {
+            (0, (1 << self.bits) - 1)
+        }
+    }
+
+    /// Computes `value + delta` as this type, `None` when it overflows under
+    /// `Overflow::Fail`.
+    pub fn add(&self, value: i128, delta: i128, overflow: Overflow) -> Option<i64> {
+        let (min, max) = self.range();
+        let sum = value + delta;
+        if (min..=max).contains(&sum) {
+            return Some(sum as i64);
+        }
+
+        match overflow {
+            Overflow::Fail => None,
+            Overflow::Sat => Some(sum.clamp(min, max) as i64),
+            Overflow::Wrap => {
+                let wrapped = sum.rem_euclid(1 << self.bits);
+                if self.signed && wrapped > max {
+                    Some((wrapped - (1 << self.bits)) as i64)
+                } else {
+                    Some(wrapped as i64)
+                }
+            }
+        }
+    }
+}
+
+/// Reads the field of type `ty` at bit `offset`, zero-padded past the end.
+pub fn get_field(bytes: &[u8], offset: usize, ty: FieldType) -> i64 {
+    let bits = ty.bits as usize;
+    let raw = (0..bits).fold(0u64, |acc, i| {
+        (acc << 1) | get_bit(bytes, offset + i) as u64
+    });
+    if ty.signed && bits < 64 {
+        // Sign-extend from the field width.
+        ((raw << (64 - bits)) as i64) >> (64 - bits)
+    } else {
+        raw as i64
+    }
+}
+
+/// Writes the low `ty.bits` bits of `value` at bit `offset`.
+pub fn set_field(bytes: &mut Vec<u8>, offset: usize, ty: FieldType, value: i64) {
+    let bits = ty.bits as usize;
+    for i in 0..bits {
+        let bit = (value as u64 >> (bits - 1 - i)) & 1 == 1;
+        set_bit(bytes, offset + i, bit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(range: (i64, i64)) -> BitRange {
+        BitRange {
+            start: range.0,
+            end: range.1,
+            unit: BitUnit::Byte,
+        }
+    }
+
+    fn bits(range: (i64, i64)) -> BitRange {
+        BitRange {
+            start: range.0,
+            end: range.1,
+            unit: BitUnit::Bit,
+        }
+    }
+
+    #[test]
+    fn should_set_and_get_bits() {
+        let mut value = Vec::new();
+        assert!(!set_bit(&mut value, 7, true));
+        assert_eq!(value, vec![0x01]);
+        assert!(set_bit(&mut value, 7, false));
+        set_bit(&mut value, 9, true);
+        assert_eq!(value, vec![0x00, 0x40]);
+        assert!(get_bit(&value, 9));
+        assert!(!get_bit(&value, 100));
+    }
+
+    #[test]
+    fn should_count_bits_in_ranges() {
+        let value = b"foobar";
+        assert_eq!(count(value, None), 26);
+        assert_eq!(count(value, Some(bytes((0, 0)))), 4);
+        assert_eq!(count(value, Some(bytes((1, 1)))), 6);
+        assert_eq!(count(value, Some(bytes((-2, -1)))), 7);
+        assert_eq!(count(value, Some(bits((5, 30)))), 17);
+        assert_eq!(count(value, Some(bytes((3, 1)))), 0);
+    }
+
+    #[test]
+    fn should_find_bit_positions() {
+        let value = [0xFF, 0xF0, 0x00];
+        assert_eq!(position(&value, false, bytes((0, -1)), false), 12);
+        assert_eq!(position(&value, true, bytes((2, -1)), false), -1);
+        assert_eq!(position(&value, true, bits((7, 15)), true), 7);
+        assert_eq!(position(&[0xFF], false, bytes((0, -1)), false), 8);
+        assert_eq!(position(&[0xFF], false, bytes((0, -1)), true), -1);
+    }
+
+    #[test]
+    fn should_read_and_write_fields() {
+        let i8 = FieldType::parse("i8").unwrap();
+        let u4 = FieldType::parse("u4").unwrap();
+        assert_eq!(FieldType::parse("u64"), None);
+        assert_eq!(FieldType::parse("x8"), None);
+
+        let mut value = Vec::new();
+        set_field(&mut value, 4, i8, -2);
+        assert_eq!(value, vec![0x0F, 0xE0]);
+        assert_eq!(get_field(&value, 4, i8), -2);
+        assert_eq!(get_field(&value, 4, u4), 15);
+        assert_eq!(get_field(&value, 100, i8), 0);
+    }
+
+    #[test]
+    fn should_handle_field_overflows() {
+        let i8 = FieldType::parse("i8").unwrap();
+        let u2 = FieldType::parse("u2").unwrap();
+
+        assert_eq!(i8.add(127, 1, Overflow::Wrap), Some(-128));
+        assert_eq!(i8.add(127, 1, Overflow::Sat), Some(127));
+        assert_eq!(i8.add(-128, -1, Overflow::Sat), Some(-128));
+        assert_eq!(i8.add(127, 1, Overflow::Fail), None);
+        assert_eq!(u2.add(3, 2, Overflow::Wrap), Some(1));
+        assert_eq!(u2.add(0, -1, Overflow::Wrap), Some(3));
+        assert_eq!(u2.add(0, -1, Overflow::Sat), Some(0));
+    }
+
+    #[test]
+    fn should_combine_values() {
+        let sources = vec![vec![0b1100, 0xFF], vec![0b1010]];
+        assert_eq!(bit_op(BitOp::And, &sources), vec![0b1000, 0x00]);
+        assert_eq!(bit_op(BitOp::Or, &sources), vec![0b1110, 0xFF]);
+        assert_eq!(bit_op(BitOp::Xor, &sources), vec![0b0110, 0xFF]);
+        assert_eq!(bit_op(BitOp::Not, &sources), vec![!0b1100, 0x00]);
+    }
+}
@@ -0,0 +1,521 @@
+//! Keys in a `DashMap` instead of the RwLock-guarded shards of `Keyspace`,
+//! for workloads where contention on the shard locks matters more than
+//! eviction.
+//!
+//! A DashMap can't pick keys at random, so keys with an expiry are indexed
+//! by deadline for the active expiration cycle rather than sampled, and
+//! nothing is ever evicted: past maxmemory, commands that would grow the
+//! dataset are refused as with noeviction.
+
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet},
+    hash::BuildHasher,
+    io, mem,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use dashmap::{mapref::entry::Entry, DashMap};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::{
+    keyspace::size_of, needle_in_haystack, on_expired, record_access, record_read, record_update,
+    timer::Timer, unix_time_ms, Data, Expiry, Maxmemory, MaxmemoryPolicy, OnExpired, Persistence,
+    RdbConfig, Saves, Storage, Update, UpdateMany, Value, WriteView, DEFAULT_SHARDS, EXPIRE_BUDGET,
+};
+use crate::{
+    access_log::{AccessLog, AccessOp},
+    blocking::Waiters,
+    locks::KEY_LOCKS,
+    notify::{NotifyFlags, KEYSPACE_EVENTS},
+    replication::REPLICATION,
+    stats::STATS,
+    tracking::TRACKING,
+};
+
+/// What a stored value accounts for: its estimated size and its expiry.
+type Footprint = (usize, Option<Expiry>);
+
+fn footprint(key: &str, value: &Value) -> Footprint {
+    (size_of(key, value), value.expiry)
+}
+
+fn expired(value: &Value, now: u64) -> bool {
+    value.expiry.is_some_and(|expiry| expiry.has_passed(now))
+}
+
+/// Locks keys are spread over by hash. Every access to a key holds its own
+/// shared but `update_many`, which holds those of its keys alone so nobody
+/// sees or changes them halfway through, as a DashMap can't lock several
+/// entries at once.
+#[derive(Debug)]
+struct Stripes {
+    locks: Box<[RwLock<()>]>,
+    hasher: RandomState,
+}
+
+impl Default for Stripes {
+    fn default() -> Self {
+        Self {
+            locks: (0..DEFAULT_SHARDS).map(|_| RwLock::new(())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl Stripes {
+    fn at(&self, key: &str) -> usize {
+        self.hasher.hash_one(key) as usize % self.locks.len()
+    }
+
+    async fn share(&self, key: &str) -> RwLockReadGuard<'_, ()> {
+        self.locks[self.at(key)].read().await
+    }
+
+    /// Holds the stripes of `keys` alone, locked in order so two holders
+    /// never wait on each other.
+    async fn hold(&self, keys: &[&str]) -> Vec<RwLockWriteGuard<'_, ()>> {
+        let mut at: Vec<usize> = keys.iter().map(|key| self.at(key)).collect();
+        at.sort_unstable();
+        at.dedup();
+        let mut held = Vec::with_capacity(at.len());
+        for at in at {
+            held.push(self.locks[at].write().await);
+        }
+        held
+    }
+
+    async fn share_all(&self) -> Vec<RwLockReadGuard<'_, ()>> {
+        let mut shared = Vec::with_capacity(self.locks.len());
+        for lock in self.locks.iter() {
+            shared.push(lock.read().await);
+        }
+        shared
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DashMapStorage {
+    map: DashMap<String, Value>,
+    /// Keys with an expiry, ordered by when they expire.
+    deadlines: Mutex<BTreeSet<(Expiry, String)>>,
+    timer: Timer,
+    /// Estimated bytes taken by every key and value.
+    used: AtomicUsize,
+    /// Keys written or deleted so far, for saves to tell how much changed.
+    changes: AtomicU64,
+    stripes: Stripes,
+    /// In bytes, 0 meaning no limit.
+    maxmemory: AtomicUsize,
+    /// Saves do nothing, but are accounted for all the same.
+    saves: Saves,
+    waiters: Arc<Waiters>,
+    access_log: Option<Arc<AccessLog>>,
+}
+
+impl DashMapStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuses commands that would grow the dataset once keys take more
+    /// than `limit` bytes, 0 meaning no limit.
+    pub fn with_maxmemory(mut self, limit: usize) -> Self {
+        self.maxmemory = AtomicUsize::new(limit);
+        self
+    }
+
+    pub fn with_access_log(mut self, access_log: Arc<AccessLog>) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Accounts for `key` going from `before` to `after`, `None` meaning
+    /// missing. Called with the key's entry locked, so the deadline index
+    /// moves in step with the map.
+    fn changed(&self, key: &str, before: Option<Footprint>, after: Option<Footprint>) {
+        if let Some((size, _)) = after {
+            self.used.fetch_add(size, Ordering::Relaxed);
+        }
+        if let Some((size, _)) = before {
+            self.used.fetch_sub(size, Ordering::Relaxed);
+        }
+        let (was, is) = (before.and_then(|(_, e)| e), after.and_then(|(_, e)| e));
+        if was != is {
+            let mut deadlines = self.deadlines.lock().unwrap();
+            if let Some(was) = was {
+                deadlines.remove(&(was, key.to_string()));
+            }
+            if let Some(is) = is {
+                deadlines.insert((is, key.to_string()));
+                self.timer.schedule(is);
+            }
+        }
+        if before.is_some() || after.is_some() {
+            self.changes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Deletes `key` if it has expired by `now`, returning whether it did.
+    /// Writes the master sent leave it be.
+    fn remove_expired(&self, key: &str, now: u64) -> bool {
+        let Entry::Occupied(entry) = self.map.entry(key.to_string()) else {
+            return false;
+        };
+        if !expired(entry.get(), now) || on_expired(true) == OnExpired::Keep {
+            return false;
+        }
+        let (key, value) = entry.remove_entry();
+        self.changed(&key, Some(footprint(&key, &value)), None);
+        STATS.expired_keys.fetch_add(1, Ordering::Relaxed);
+        KEYSPACE_EVENTS.notify(NotifyFlags::EXPIRED, "expired", &key);
+        REPLICATION.propagate_deletion(&key);
+        TRACKING.invalidate(&[&key], None);
+        true
+    }
+}
+
+#[async_trait]
+impl Storage for DashMapStorage {
+    async fn set(&self, key: String, value: Value) {
+        let _shared = self.stripes.share(&key).await;
+        let after = footprint(&key, &value);
+        let existed = match self.map.entry(key.clone()) {
+            Entry::Occupied(mut entry) => {
+                let before = footprint(&key, entry.get());
+                entry.insert(value);
+                self.changed(&key, Some(before), Some(after));
+                true
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                self.changed(&key, None, Some(after));
+                false
+            }
+        };
+        record_access(&self.access_log, &key, AccessOp::Set, existed);
+        self.waiters.wake(&key);
+    }
+
+    async fn get(&self, key: &str) -> Option<Value> {
+        let _shared = self.stripes.share(key).await;
+        let now = unix_time_ms();
+        // The entry must be let go before deleting it, or the shard would
+        // deadlock.
+        let value = self.map.get(key).map(|entry| entry.clone());
+        let value = match value {
+            Some(value) if expired(&value, now) => match on_expired(false) {
+                OnExpired::Delete => {
+                    self.remove_expired(key, now);
+                    None
+                }
+                OnExpired::Hide => None,
+                OnExpired::Keep => Some(value),
+            },
+            value => value,
+        };
+        record_read(&self.access_log, key, value.is_some());
+        value
+    }
+
+    async fn del(&self, key: &str) -> bool {
+        let _shared = self.stripes.share(key).await;
+        self.remove_expired(key, unix_time_ms());
+        let removed = match self.map.entry(key.to_string()) {
+            Entry::Occupied(entry) => {
+                let (key, value) = entry.remove_entry();
+                self.changed(&key, Some(footprint(&key, &value)), None);
+                true
+            }
+            Entry::Vacant(_) => false,
+        };
+        record_access(&self.access_log, key, AccessOp::Del, removed);
+        removed
+    }
+
+    async fn update(&self, key: &str, f: &mut Update<'_>) {
+        let _shared = self.stripes.share(key).await;
+        self.remove_expired(key, unix_time_ms());
+        let (existed, stored) = match self.map.entry(key.to_string()) {
+            Entry::Occupied(mut entry) => {
+                let before = footprint(key, entry.get());
+                // The value is moved out rather than cloned, an empty
+                // string standing in while `f` runs.
+                let mut value = Some(Value {
+                    value: mem::replace(&mut entry.get_mut().value, Data::String(Vec::new())),
+                    expiry: entry.get().expiry,
+                });
+                let changed = f(&mut value);
+                match value {
+                    Some(value) if !changed => {
+                        entry.get_mut().value = value.value;
+                        return;
+                    }
+                    Some(value) => {
+                        let after = footprint(key, &value);
+                        entry.insert(value);
+                        self.changed(key, Some(before), Some(after));
+                        (true, true)
+                    }
+                    None if !changed => return,
+                    None => {
+                        entry.remove();
+                        self.changed(key, Some(before), None);
+                        (true, false)
+                    }
+                }
+            }
+            Entry::Vacant(entry) => {
+                let mut value = None;
+                let changed = f(&mut value);
+                match value {
+                    Some(value) if changed => {
+                        let after = footprint(key, &value);
+                        entry.insert(value);
+                        self.changed(key, None, Some(after));
+                        (false, true)
+                    }
+                    _ => return,
+                }
+            }
+        };
+        record_update(&self.access_log, &self.waiters, key, existed, stored);
+    }
+
+    async fn update_many(&self, keys: &[&str], f: &mut UpdateMany<'_>) {
+        let _alone = self.stripes.hold(keys).await;
+        let now = unix_time_ms();
+        // Nobody else can get to the keys, so their values can be moved out
+        // of the map and back, an empty string standing in meanwhile. Only
+        // whole-map walks see it, and those skip it or wait.
+        let mut view = WriteView::default();
+        let mut before = BTreeMap::new();
+        for &key in keys {
+            if !before.contains_key(key) {
+                self.remove_expired(key, now);
+                let value = self.map.get_mut(key).map(|mut entry| Value {
+                    value: mem::replace(&mut entry.value, Data::String(Vec::new())),
+                    expiry: entry.expiry,
+                });
+                before.insert(key, value.as_ref().map(|value| footprint(key, value)));
+                view.insert(key, value);
+            }
+        }
+        f(&mut view);
+        // Both are ordered by key.
+        for ((key, value, written), before) in view.into_values().zip(before.into_values()) {
+            if !written {
+                if let (Some(value), Some(mut entry)) = (value, self.map.get_mut(&key)) {
+                    entry.value = value.value;
+                }
+                continue;
+            }
+            let after = value.as_ref().map(|value| footprint(&key, value));
+            match value {
+                Some(value) => {
+                    self.map.insert(key.clone(), value);
+                }
+                None => {
+                    self.map.remove(&key);
+                }
+            }
+            self.changed(&key, before, after);
+            record_update(
+                &self.access_log,
+                &self.waiters,
+                &key,
+                before.is_some(),
+                after.is_some(),
+            );
+        }
+    }
+
+    async fn save(&self) -> Result<(), io::Error> {
+        if !self.saves.begin() {
+            return Err(io::Error::other("Background save already in progress"));
+        }
+        self.saves.end(self.changes.load(Ordering::Relaxed), true);
+        Ok(())
+    }
+
+    async fn load(&self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    async fn keys(&self, k: &str) -> Option<Vec<String>> {
+        let now = unix_time_ms();
+        let keys: Vec<String> = self
+            .map
+            .iter()
+            .filter(|entry| !expired(entry.value(), now))
+            .map(|entry| entry.key().clone())
+            .collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        Some(
+            needle_in_haystack(k, &keys)
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        )
+    }
+
+    async fn snapshot(&self) -> Vec<(String, Value)> {
+        let _shared = self.stripes.share_all().await;
+        let now = unix_time_ms();
+        self.map
+            .iter()
+            .filter(|entry| !expired(entry.value(), now))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    fn config(&self) -> RdbConfig {
+        RdbConfig {
+            dir: "".to_string(),
+            path: "".to_string(),
+        }
+    }
+
+    /// Deletes the keys whose deadline has passed, earliest first, until
+    /// none is left or the time budget runs out.
+    async fn purge_expired(&self) -> usize {
+        let now = unix_time_ms();
+        let started = Instant::now();
+        let mut purged = 0;
+        while started.elapsed() <= EXPIRE_BUDGET {
+            let due = {
+                let mut deadlines = self.deadlines.lock().unwrap();
+                match deadlines.first() {
+                    Some((expiry, _)) if expiry.has_passed(now) => deadlines.pop_first(),
+                    _ => None,
+                }
+            };
+            let Some((_, key)) = due else {
+                break;
+            };
+            // Held like a command writing it would, for the deletion to be
+            // propagated after any write to it.
+            let _held = KEY_LOCKS.write(&[&key]).await;
+            let _shared = self.stripes.share(&key).await;
+            if self.remove_expired(&key, now) {
+                purged += 1;
+            }
+        }
+        purged
+    }
+
+    async fn expire_due(&self) -> usize {
+        self.timer.reset();
+        let purged = self.purge_expired().await;
+        let next = self
+            .deadlines
+            .lock()
+            .unwrap()
+            .first()
+            .map(|(expiry, _)| *expiry);
+        self.timer.wait(next).await;
+        purged
+    }
+
+    /// Only noeviction is supported, so access frequencies aren't tracked.
+    async fn frequency(&self, _: &str) -> Option<u8> {
+        None
+    }
+
+    async fn evict(&self) -> bool {
+        let maxmemory = self.maxmemory.load(Ordering::Relaxed);
+        maxmemory == 0 || self.used_memory() <= maxmemory
+    }
+
+    fn used_memory(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    fn maxmemory(&self) -> Maxmemory {
+        Maxmemory {
+            limit: self.maxmemory.load(Ordering::Relaxed),
+            ..Maxmemory::default()
+        }
+    }
+
+    fn set_maxmemory(&self, maxmemory: Maxmemory) -> Result<(), String> {
+        if maxmemory.policy != MaxmemoryPolicy::NoEviction {
+            return Err("only noeviction is supported with --dashmap".to_string());
+        }
+        self.maxmemory.store(maxmemory.limit, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn bgsave(&self) -> bool {
+        if !self.saves.begin() {
+            return false;
+        }
+        self.saves.end(self.changes.load(Ordering::Relaxed), true);
+        true
+    }
+
+    fn persistence(&self) -> Persistence {
+        self.saves.report(self.changes.load(Ordering::Relaxed))
+    }
+
+    fn waiters(&self) -> Arc<Waiters> {
+        Arc::clone(&self.waiters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn string(text: &str) -> Value {
+        Value {
+            value: Data::String(text.as_bytes().to_vec()),
+            expiry: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_store_and_expire_keys() {
+        let dash = DashMapStorage::new().with_maxmemory(1000);
+        let storage: &dyn Storage = &dash;
+        storage.set("key".to_string(), string("value")).await;
+        storage
+            .update_with("key", |value| match value {
+                Some(Value {
+                    value: Data::String(bytes),
+                    ..
+                }) => bytes.extend_from_slice(b"!"),
+                _ => unreachable!(),
+            })
+            .await;
+        assert_eq!(
+            storage.get("key").await.unwrap().value,
+            string("value!").value
+        );
+
+        let expiring = Value {
+            expiry: Expiry::after(Duration::from_millis(1)),
+            ..string("value")
+        };
+        storage.set("expiring".to_string(), expiring).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(storage.purge_expired().await, 1);
+        assert!(dash.deadlines.lock().unwrap().is_empty());
+        assert_eq!(storage.keys("*").await, Some(vec!["key".to_string()]));
+
+        storage
+            .set("big".to_string(), string(&"v".repeat(1000)))
+            .await;
+        assert!(!storage.evict().await);
+        assert!(storage.del("big").await);
+        assert!(storage.del("key").await);
+        assert_eq!(storage.used_memory(), 0);
+        assert!(storage.evict().await);
+    }
+}
@@ -0,0 +1,488 @@
+//! Keys kept in an embedded on-disk store rather than in memory, for
+//! datasets larger than RAM.
+//!
+//! Values are encoded with `encoding` into a sled tree, a second tree
+//! indexing the keys with an expiry by deadline so the active expiration
+//! cycle reads the expired ones first instead of sampling. The store
+//! persists every write on its own; saving only makes sure what was written
+//! has reached the disk.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use tokio::sync::{Mutex, MutexGuard};
+
+use super::{
+    encoding::{decode, decode_expiry, encode},
+    needle_in_haystack, on_expired, record_access, record_read, record_update,
+    timer::Timer,
+    unix_time_ms, Expiry, Maxmemory, OnExpired, Persistence, RdbConfig, Saves, Storage, Update,
+    UpdateMany, Value, WriteView, DEFAULT_SHARDS, EXPIRE_BUDGET,
+};
+use crate::{
+    access_log::{AccessLog, AccessOp},
+    blocking::Waiters,
+    locks::KEY_LOCKS,
+    notify::{NotifyFlags, KEYSPACE_EVENTS},
+    replication::REPLICATION,
+    stats::STATS,
+    tracking::TRACKING,
+};
+
+/// Name of the tree indexing keys by when they expire.
+const EXPIRES: &str = "expires";
+
+pub struct DiskStorage {
+    path: String,
+    /// Values under their key in the default tree.
+    db: sled::Db,
+    /// Keys with an expiry as their deadline in big endian followed by the
+    /// key, so they sort by deadline.
+    expires: sled::Tree,
+    /// Held while a key is read and written back, so writes to one key
+    /// don't interleave; keys are spread over them by hash.
+    locks: Box<[Mutex<()>]>,
+    hasher: RandomState,
+    /// Keys written or deleted so far, for saves to tell how much changed.
+    changes: AtomicU64,
+    timer: Timer,
+    saves: Arc<Saves>,
+    waiters: Arc<Waiters>,
+    access_log: Option<Arc<AccessLog>>,
+}
+
+impl DiskStorage {
+    /// Opens the store at `path`, creating it if missing. Whatever it
+    /// already holds is served right away, so there is nothing to load.
+    pub fn open(path: &str) -> Result<Self, io::Error> {
+        let db = sled::open(path).map_err(io::Error::other)?;
+        let expires = db.open_tree(EXPIRES).map_err(io::Error::other)?;
+        Ok(Self {
+            path: path.to_string(),
+            db,
+            expires,
+            locks: (0..DEFAULT_SHARDS).map(|_| Mutex::new(())).collect(),
+            hasher: RandomState::new(),
+            changes: AtomicU64::new(0),
+            timer: Timer::default(),
+            saves: Arc::new(Saves::default()),
+            waiters: Arc::new(Waiters::new()),
+            access_log: None,
+        })
+    }
+
+    pub fn with_access_log(mut self, access_log: Arc<AccessLog>) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    fn lock_at(&self, key: &str) -> usize {
+        self.hasher.hash_one(key) as usize % self.locks.len()
+    }
+
+    async fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        self.locks[self.lock_at(key)].lock().await
+    }
+
+    /// Stores the encoded `value` under `key`, or deletes the key if
+    /// `None`, keeping the expiry index in step. Returns whether there was
+    /// a value before. The key must be locked.
+    fn write(&self, key: &str, value: Option<&Value>) -> Result<bool, sled::Error> {
+        let expiry = value.and_then(|value| value.expiry);
+        if let Some(expiry) = expiry {
+            self.expires.insert(deadline_key(expiry, key), &[])?;
+            self.timer.schedule(expiry);
+        }
+        let previous = match value {
+            Some(value) => self.db.insert(key, encode(value))?,
+            None => self.db.remove(key)?,
+        };
+        let replaced = previous.as_deref().and_then(decode_expiry).flatten();
+        if let Some(replaced) = replaced.filter(|&replaced| Some(replaced) != expiry) {
+            self.expires.remove(deadline_key(replaced, key))?;
+        }
+        if previous.is_some() || value.is_some() {
+            self.changes.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(previous.is_some())
+    }
+
+    /// The value under `key` unless it is missing or expired by `now`, in
+    /// which case it is deleted. Writes the master sent get it either way.
+    /// The key must be locked.
+    fn read_live(&self, key: &str, now: u64) -> Result<Option<Value>, sled::Error> {
+        let Some(bytes) = self.db.get(key)? else {
+            return Ok(None);
+        };
+        match decode(&bytes) {
+            Some(value) if !expired(&value, now) || on_expired(true) == OnExpired::Keep => {
+                Ok(Some(value))
+            }
+            Some(_) => {
+                self.write(key, None)?;
+                STATS.expired_keys.fetch_add(1, Ordering::Relaxed);
+                KEYSPACE_EVENTS.notify(NotifyFlags::EXPIRED, "expired", key);
+                REPLICATION.propagate_deletion(key);
+                TRACKING.invalidate(&[key], None);
+                Ok(None)
+            }
+            None => Err(corrupted(key)),
+        }
+    }
+
+    /// The earliest deadline indexed, with the index entry holding it.
+    fn first_deadline(&self) -> Result<Option<(Expiry, sled::IVec)>, sled::Error> {
+        let Some((indexed, _)) = self.expires.first()? else {
+            return Ok(None);
+        };
+        let deadline = u64::from_be_bytes(indexed[..8].try_into().unwrap());
+        Ok(Some((Expiry::from_unix_ms(deadline), indexed)))
+    }
+
+    /// Deletes the keys whose deadline has passed, earliest first, until
+    /// none is left or the time budget runs out.
+    async fn purge(&self, now: u64) -> Result<usize, sled::Error> {
+        let started = Instant::now();
+        let mut purged = 0;
+        while started.elapsed() <= EXPIRE_BUDGET {
+            let Some((deadline, indexed)) = self.first_deadline()? else {
+                break;
+            };
+            if !deadline.has_passed(now) {
+                break;
+            }
+            let key = String::from_utf8_lossy(&indexed[8..]).into_owned();
+            // Held like a command writing it would, for the deletion to be
+            // propagated after any write to it.
+            let _held = KEY_LOCKS.write(&[&key]).await;
+            let _lock = self.lock(&key).await;
+            // The key may have been given another expiry or deleted since,
+            // or a crash may have left the entry behind without it.
+            self.expires.remove(&indexed)?;
+            if self.db.contains_key(&key)? && self.read_live(&key, now)?.is_none() {
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+}
+
+fn deadline_key(expiry: Expiry, key: &str) -> Vec<u8> {
+    let mut indexed = expiry.unix_ms().to_be_bytes().to_vec();
+    indexed.extend_from_slice(key.as_bytes());
+    indexed
+}
+
+fn expired(value: &Value, now: u64) -> bool {
+    value.expiry.is_some_and(|expiry| expiry.has_passed(now))
+}
+
+fn corrupted(key: &str) -> sled::Error {
+    sled::Error::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("value of {} can't be decoded", key),
+    ))
+}
+
+/// Reports errors of the store to the log, since the `Storage` interface
+/// has no way of failing short of saves.
+trait LogError<T> {
+    fn or_log(self, what: &str) -> T;
+}
+
+impl<T: Default> LogError<T> for Result<T, sled::Error> {
+    fn or_log(self, what: &str) -> T {
+        self.unwrap_or_else(|err| {
+            eprintln!("disk storage failed {}: {}", what, err);
+            T::default()
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for DiskStorage {
+    async fn set(&self, key: String, value: Value) {
+        let _lock = self.lock(&key).await;
+        let existed = self.write(&key, Some(&value)).or_log("writing");
+        record_access(&self.access_log, &key, AccessOp::Set, existed);
+        self.waiters.wake(&key);
+    }
+
+    async fn get(&self, key: &str) -> Option<Value> {
+        let now = unix_time_ms();
+        // Expired values are deleted, which takes the lock, but that is
+        // rare enough for reads to go without it otherwise.
+        let value = match self
+            .db
+            .get(key)
+            .or_log("reading")
+            .as_deref()
+            .and_then(decode)
+        {
+            Some(value) if expired(&value, now) => match on_expired(false) {
+                OnExpired::Hide => None,
+                _ => {
+                    let _lock = self.lock(key).await;
+                    self.read_live(key, now).or_log("reading")
+                }
+            },
+            value => value,
+        };
+        record_read(&self.access_log, key, value.is_some());
+        value
+    }
+
+    async fn del(&self, key: &str) -> bool {
+        let _lock = self.lock(key).await;
+        let removed = self
+            .read_live(key, unix_time_ms())
+            .or_log("reading")
+            .is_some()
+            && self.write(key, None).or_log("deleting");
+        record_access(&self.access_log, key, AccessOp::Del, removed);
+        removed
+    }
+
+    async fn update(&self, key: &str, f: &mut Update<'_>) {
+        let _lock = self.lock(key).await;
+        let mut value = self.read_live(key, unix_time_ms()).or_log("reading");
+        let existed = value.is_some();
+        if !f(&mut value) || (value.is_none() && !existed) {
+            return;
+        }
+        self.write(key, value.as_ref()).or_log("writing");
+        record_update(
+            &self.access_log,
+            &self.waiters,
+            key,
+            existed,
+            value.is_some(),
+        );
+    }
+
+    /// The keys are locked in order, so two of these never wait on each
+    /// other. Each value is written on its own though, so a crash halfway
+    /// may leave only some of them stored.
+    async fn update_many(&self, keys: &[&str], f: &mut UpdateMany<'_>) {
+        let mut at: Vec<usize> = keys.iter().map(|key| self.lock_at(key)).collect();
+        at.sort_unstable();
+        at.dedup();
+        let mut locked = Vec::with_capacity(at.len());
+        for at in at {
+            locked.push(self.locks[at].lock().await);
+        }
+        let now = unix_time_ms();
+        let mut view = WriteView::default();
+        for &key in keys {
+            let value = self.read_live(key, now).or_log("reading");
+            view.insert(key, value);
+        }
+        let existed: Vec<bool> = view.values.values().map(Option::is_some).collect();
+        f(&mut view);
+        for ((key, value, written), existed) in view.into_values().zip(existed) {
+            if !written {
+                continue;
+            }
+            if value.is_some() || existed {
+                self.write(&key, value.as_ref()).or_log("writing");
+            }
+            record_update(
+                &self.access_log,
+                &self.waiters,
+                &key,
+                existed,
+                value.is_some(),
+            );
+        }
+    }
+
+    async fn save(&self) -> Result<(), io::Error> {
+        if !self.saves.begin() {
+            return Err(io::Error::other("Background save already in progress"));
+        }
+        let changes = self.changes.load(Ordering::Relaxed);
+        let flushed = self.db.flush_async().await;
+        self.saves.end(changes, flushed.is_ok());
+        flushed.map(|_| ()).map_err(io::Error::other)
+    }
+
+    async fn load(&self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    async fn keys(&self, k: &str) -> Option<Vec<String>> {
+        let now = unix_time_ms();
+        let mut keys = Vec::new();
+        for entry in self.db.iter() {
+            let (key, bytes) = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    eprintln!("disk storage failed listing keys: {}", err);
+                    break;
+                }
+            };
+            let expiry = decode_expiry(&bytes).flatten();
+            if expiry.is_none_or(|expiry| !expiry.has_passed(now)) {
+                keys.push(String::from_utf8_lossy(&key).into_owned());
+            }
+        }
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        Some(
+            needle_in_haystack(k, &keys)
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        )
+    }
+
+    async fn snapshot(&self) -> Vec<(String, Value)> {
+        let now = unix_time_ms();
+        let mut entries = Vec::new();
+        for entry in self.db.iter() {
+            let (key, bytes) = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    eprintln!("disk storage failed copying keys: {}", err);
+                    break;
+                }
+            };
+            if let Some(value) = decode(&bytes).filter(|value| !expired(value, now)) {
+                entries.push((String::from_utf8_lossy(&key).into_owned(), value));
+            }
+        }
+        entries
+    }
+
+    fn config(&self) -> RdbConfig {
+        RdbConfig {
+            dir: self.path.clone(),
+            path: "".to_string(),
+        }
+    }
+
+    async fn purge_expired(&self) -> usize {
+        self.purge(unix_time_ms()).await.or_log("expiring keys")
+    }
+
+    async fn expire_due(&self) -> usize {
+        self.timer.reset();
+        let purged = self.purge_expired().await;
+        let next = self.first_deadline().or_log("expiring keys");
+        self.timer.wait(next.map(|(deadline, _)| deadline)).await;
+        purged
+    }
+
+    /// Nothing is ever evicted, so access frequencies aren't tracked.
+    async fn frequency(&self, _: &str) -> Option<u8> {
+        None
+    }
+
+    /// Values take no memory to speak of, so there is never anything to
+    /// evict.
+    async fn evict(&self) -> bool {
+        true
+    }
+
+    /// Only what sled caches is held in memory, which it bounds itself.
+    fn used_memory(&self) -> usize {
+        0
+    }
+
+    fn maxmemory(&self) -> Maxmemory {
+        Maxmemory::default()
+    }
+
+    fn set_maxmemory(&self, _: Maxmemory) -> Result<(), String> {
+        Err("not supported with keys kept on disk".to_string())
+    }
+
+    fn bgsave(&self) -> bool {
+        if !self.saves.begin() {
+            return false;
+        }
+        let db = self.db.clone();
+        let saves = Arc::clone(&self.saves);
+        let changes = self.changes.load(Ordering::Relaxed);
+        tokio::spawn(async move {
+            let flushed = db.flush_async().await;
+            if let Err(err) = &flushed {
+                eprintln!("background save failed: {}", err);
+            }
+            saves.end(changes, flushed.is_ok());
+        });
+        true
+    }
+
+    fn persistence(&self) -> Persistence {
+        self.saves.report(self.changes.load(Ordering::Relaxed))
+    }
+
+    fn waiters(&self) -> Arc<Waiters> {
+        Arc::clone(&self.waiters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::storage::Data;
+
+    fn string(text: &str) -> Value {
+        Value {
+            value: Data::String(text.as_bytes().to_vec()),
+            expiry: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_keep_keys_on_disk() {
+        let dir = std::env::temp_dir().join(format!("disk-{}", std::process::id()));
+        let path = dir.to_str().unwrap();
+        let disk = DiskStorage::open(path).unwrap();
+        let storage: &dyn Storage = &disk;
+        storage.set("key".to_string(), string("value")).await;
+        storage
+            .update_with("key", |value| {
+                if let Some(Value {
+                    value: Data::String(bytes),
+                    ..
+                }) = value
+                {
+                    bytes.extend_from_slice(b"!");
+                }
+            })
+            .await;
+        storage.set("gone".to_string(), string("value")).await;
+        assert!(storage.del("gone").await);
+        let expiring = Value {
+            expiry: Expiry::after(Duration::from_millis(1)),
+            ..string("value")
+        };
+        storage.set("expiring".to_string(), expiring).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(storage.purge_expired().await, 1);
+        storage.save().await.unwrap();
+        assert_eq!(storage.persistence().changes_since_save, 0);
+
+        // The store stays locked until closed.
+        drop(disk);
+        let storage = DiskStorage::open(path).unwrap();
+        let value = storage.get("key").await.map(|value| value.value);
+        let keys = storage.keys("*").await;
+        drop(storage);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(value, Some(Data::String(b"value!".to_vec())));
+        assert_eq!(keys, Some(vec!["key".to_string()]));
+    }
+}
@@ -0,0 +1,198 @@
+//! A compact binary form of values, for keeping them outside of memory.
+//!
+//! A value is written as its expiry, if any, followed by a tag naming its
+//! type and the data itself, lengths and numbers as fixed width big endian
+//! integers. The expiry coming first lets it be read without decoding the
+//! rest.
+
+use super::{Data, Value};
+#[cfg(feature = "disk")]
+use super::{Expiry, Set, SortedSet, Stream};
+
+const STRING: u8 = 0;
+const SET: u8 = 1;
+const SORTED_SET: u8 = 2;
+const STREAM: u8 = 3;
+
+/// Bytes being written.
+#[derive(Default)]
+pub struct Encoder(Vec<u8>);
+
+impl Encoder {
+    pub fn u8(&mut self, byte: u8) {
+        self.0.push(byte);
+    }
+
+    pub fn u64(&mut self, number: u64) {
+        self.0.extend_from_slice(&number.to_be_bytes());
+    }
+
+    pub fn f64(&mut self, number: f64) {
+        self.u64(number.to_bits());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    pub fn str(&mut self, text: &str) {
+        self.bytes(text.as_bytes());
+    }
+}
+
+/// Bytes being read, each read `None` once they run out or don't make
+/// sense.
+#[cfg(feature = "disk")]
+pub struct Decoder<'a>(&'a [u8]);
+
+#[cfg(feature = "disk")]
+impl Decoder<'_> {
+    pub fn u8(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(byte)
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        let (number, rest) = self.0.split_first_chunk::<8>()?;
+        self.0 = rest;
+        Some(u64::from_be_bytes(*number))
+    }
+
+    pub fn f64(&mut self) -> Option<f64> {
+        self.u64().map(f64::from_bits)
+    }
+
+    /// A length, checked against what is left so a corrupted one can't
+    /// make us allocate more than that.
+    pub fn len(&mut self) -> Option<usize> {
+        usize::try_from(self.u64()?)
+            .ok()
+            .filter(|&len| len <= self.0.len())
+    }
+
+    pub fn bytes(&mut self) -> Option<Vec<u8>> {
+        let len = self.len()?;
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes.to_vec())
+    }
+
+    pub fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?).ok()
+    }
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Encoder::default();
+    match value.expiry {
+        Some(expiry) => {
+            out.u8(1);
+            out.u64(expiry.unix_ms());
+        }
+        None => out.u8(0),
+    }
+    match &value.value {
+        Data::String(bytes) => {
+            out.u8(STRING);
+            out.bytes(bytes);
+        }
+        Data::Set(members) => {
+            out.u8(SET);
+            out.u64(members.len() as u64);
+            for member in members.iter() {
+                out.str(&member);
+            }
+        }
+        Data::SortedSet(zset) => {
+            out.u8(SORTED_SET);
+            out.u64(zset.len() as u64);
+            for (member, score) in zset.iter() {
+                out.str(member);
+                out.f64(score);
+            }
+        }
+        Data::Stream(stream) => {
+            out.u8(STREAM);
+            stream.encode(&mut out);
+        }
+    }
+    out.0
+}
+
+/// When an encoded value expires, reading no further than that.
+#[cfg(feature = "disk")]
+pub fn decode_expiry(bytes: &[u8]) -> Option<Option<Expiry>> {
+    let mut input = Decoder(bytes);
+    match input.u8()? {
+        0 => Some(None),
+        _ => Some(Some(Expiry::from_unix_ms(input.u64()?))),
+    }
+}
+
+/// The value `encode` wrote, `None` if `bytes` hold no such thing.
+#[cfg(feature = "disk")]
+pub fn decode(bytes: &[u8]) -> Option<Value> {
+    let mut input = Decoder(bytes);
+    let expiry = match input.u8()? {
+        0 => None,
+        _ => Some(Expiry::from_unix_ms(input.u64()?)),
+    };
+    let value = match input.u8()? {
+        STRING => Data::String(input.bytes()?),
+        SET => {
+            let len = input.len()?;
+            let members = (0..len).map(|_| input.string()).collect::<Option<Set>>()?;
+            Data::Set(members)
+        }
+        SORTED_SET => {
+            let mut zset = SortedSet::new();
+            for _ in 0..input.len()? {
+                let member = input.string()?;
+                zset.insert(member, input.f64()?);
+            }
+            Data::SortedSet(zset)
+        }
+        STREAM => Data::Stream(Stream::decode(&mut input)?),
+        _ => return None,
+    };
+    Some(Value { value, expiry })
+}
+
+#[cfg(all(test, feature = "disk"))]
+mod tests {
+    use super::*;
+    use crate::storage::StreamId;
+
+    #[test]
+    fn should_decode_what_was_encoded() {
+        let id = |ms| StreamId { ms, seq: 0 };
+        let mut stream = Stream::new();
+        stream.add(id(1), vec![("field".to_string(), "value".to_string())]);
+        stream.add(id(2), vec![]);
+        stream.create_group("group", StreamId::MIN);
+        stream.read_group("group", "consumer", Some(1), false, 10);
+        let mut zset = SortedSet::new();
+        zset.insert("member".to_string(), -1.5);
+        let values = [
+            Data::String(b"\x00bytes\xff".to_vec()),
+            Data::Set(Set::from_iter(["a".to_string(), "b".to_string()])),
+            Data::SortedSet(zset),
+            Data::Stream(stream),
+        ];
+
+        for value in values {
+            let value = Value {
+                value,
+                expiry: Some(Expiry::from_unix_ms(42)),
+            };
+            let encoded = encode(&value);
+            let decoded = decode(&encoded).unwrap();
+            assert_eq!(decoded.value, value.value);
+            assert_eq!(decoded.expiry, value.expiry);
+            assert_eq!(decode_expiry(&encoded), Some(value.expiry));
+            assert!(decode(&encoded[..encoded.len() - 1]).is_none());
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Geohashes stored as sorted set scores, following Redis: 26 bits of
+//! longitude and latitude interleaved into a 52 bit integer, which a double
+//! holds exactly.
+
+pub const LON_MIN: f64 = -180.0;
+pub const LON_MAX: f64 = 180.0;
+/// Latitudes past these cannot be projected to Web Mercator.
+pub const LAT_MIN: f64 = -85.051_128_78;
+pub const LAT_MAX: f64 = 85.051_128_78;
+
+/// Bits per coordinate in a stored geohash.
+const STEP_MAX: u32 = 26;
+
+const EARTH_RADIUS: f64 = 6_372_797.560_856;
+
+/// Half the circumference of the earth in Web Mercator.
+const MERCATOR_MAX: f64 = 20_037_726.37;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Point {
+    pub lon: f64,
+    pub lat: f64,
+}
+
+impl Point {
+    /// The point, or `None` if it lies outside what geohashes can encode.
+    pub fn new(lon: f64, lat: f64) -> Option<Point> {
+        ((LON_MIN..=LON_MAX).contains(&lon) && (LAT_MIN..=LAT_MAX).contains(&lat))
+            .then_some(Point { lon, lat })
+    }
+}
+
+/// Area searched by GEOSEARCH, in meters around its center.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+impl Shape {
+    /// Distance from `center` to `point` if the point lies within the shape.
+    pub fn distance_within(&self, center: Point, point: Point) -> Option<f64> {
+        match *self {
+            Shape::Radius(radius) => Some(distance(center, point)).filter(|d| *d <= radius),
+            Shape::Box { width, height } => {
+                let lat_distance = EARTH_RADIUS * (point.lat - center.lat).to_radians().abs();
+                if lat_distance > height / 2.0 {
+                    return None;
+                }
+                let lon_distance = distance(
+                    Point {
+                        lon: center.lon,
+                        lat: point.lat,
+                    },
+                    point,
+                );
+                if lon_distance > width / 2.0 {
+                    return None;
+                }
+                Some(distance(center, point))
+            }
+        }
+    }
+
+    /// Half the extent of the shape along each axis, in meters.
+    fn half_extents(&self) -> (f64, f64) {
+        match *self {
+            Shape::Radius(radius) => (radius, radius),
+            Shape::Box { width, height } => (width / 2.0, height / 2.0),
+        }
+    }
+}
+
+/// Encodes `point` as the score stored in the sorted set.
+pub fn encode(point: Point) -> u64 {
+    let (lon, lat) = cell_indexes(point, STEP_MAX);
+    interleave(lat, lon)
+}
+
+/// Center of the cell `hash` designates.
+pub fn decode(hash: u64) -> Point {
+    let (lat, lon) = deinterleave(hash);
+    let scale = (1u64 << STEP_MAX) as f64;
+    let lon_min = LON_MIN + (LON_MAX - LON_MIN) * lon as f64 / scale;
+    let lon_max = LON_MIN + (LON_MAX - LON_MIN) * (lon + 1) as f64 / scale;
+    let lat_min = LAT_MIN + (LAT_MAX - LAT_MIN) * lat as f64 / scale;
+    let lat_max = LAT_MIN + (LAT_MAX - LAT_MIN) * (lat + 1) as f64 / scale;
+    Point {
+        lon: ((lon_min + lon_max) / 2.0).clamp(LON_MIN, LON_MAX),
+        lat: ((lat_min + lat_max) / 2.0).clamp(LAT_MIN, LAT_MAX),
+    }
+}
+
+/// Great-circle distance in meters, using the same earth radius as Redis.
+pub fn distance(from: Point, to: Point) -> f64 {
+    let (lat1, lat2) = (from.lat.to_radians(), to.lat.to_radians());
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let v = ((to.lon.to_radians() - from.lon.to_radians()) / 2.0).sin();
+    2.0 * EARTH_RADIUS * (u * u + lat1.cos() * lat2.cos() * v * v).sqrt().asin()
+}
+
+/// Score ranges, each `[min, max)`, whose members may lie within `shape`
+/// around `center`: the cell holding the center and its eight neighbours, at
+/// a precision where they cover the whole shape.
+pub fn search_ranges(center: Point, shape: Shape) -> Vec<(u64, u64)> {
+    let (half_width, half_height) = shape.half_extents();
+    let mut step = estimate_step(half_width.max(half_height), center.lat);
+    while step > 1 && !neighbours_cover(center, half_width, half_height, step) {
+        step -= 1;
+    }
+
+    let cells = 1u64 << step;
+    let (lon, lat) = cell_indexes(center, step);
+    let shift = 2 * (STEP_MAX - step);
+    let mut ranges = Vec::with_capacity(9);
+    for lat in [lat.wrapping_sub(1), lat, lat + 1] {
+        if lat >= cells {
+            continue;
+        }
+        for lon in [(lon + cells - 1) % cells, lon, (lon + 1) % cells] {
+            let hash = interleave(lat, lon);
+            let range = (hash << shift, (hash + 1) << shift);
+            if !ranges.contains(&range) {
+                ranges.push(range);
+            }
+        }
+    }
+    ranges
+}
+
+/// Precision at which cells are about as large as `radius`.
+fn estimate_step(radius: f64, lat: f64) -> u32 {
+    if radius == 0.0 {
+        return STEP_MAX;
+    }
+    let mut step: i32 = 1;
+    let mut range = radius;
+    while range < MERCATOR_MAX {
+        range *= 2.0;
+        step += 1;
+    }
+    // Include the radius in most cases, and widen cells towards the poles.
+    step -= 2;
+    if lat.abs() > 66.0 {
+        step -= 1;
+    }
+    if lat.abs() > 80.0 {
+        step -= 1;
+    }
+    step.clamp(1, STEP_MAX as i32) as u32
+}
+
+/// Whether the cells around the one holding `center` span the box of the
+/// given half extents at precision `step`.
+fn neighbours_cover(center: Point, half_width: f64, half_height: f64, step: u32) -> bool {
+    let cells = (1u64 << step) as f64;
+    let cell_lon = (LON_MAX - LON_MIN) / cells;
+    let cell_lat = (LAT_MAX - LAT_MIN) / cells;
+    let (lon, lat) = cell_indexes(center, step);
+
+    let lat_delta = (half_height / EARTH_RADIUS).to_degrees();
+    let lat_low = LAT_MIN + (lat as f64 - 1.0) * cell_lat;
+    let lat_high = LAT_MIN + (lat as f64 + 2.0) * cell_lat;
+    let lat_covered = (center.lat - lat_delta <= LAT_MIN || center.lat - lat_delta >= lat_low)
+        && (center.lat + lat_delta >= LAT_MAX || center.lat + lat_delta <= lat_high);
+
+    // Longitude degrees shrink towards the poles; check at the widest latitude.
+    let widest = (center.lat.abs() + lat_delta).min(90.0);
+    let lon_delta = (half_width / EARTH_RADIUS / widest.to_radians().cos()).to_degrees();
+    let lon_covered = cells <= 3.0
+        || (center.lon - lon_delta >= LON_MIN + (lon as f64 - 1.0) * cell_lon
+            && center.lon + lon_delta <= LON_MIN + (lon as f64 + 2.0) * cell_lon);
+
+    lat_covered && lon_covered
+}
+
+/// Longitude and latitude cell indexes of `point` at precision `step`.
+fn cell_indexes(point: Point, step: u32) -> (u64, u64) {
+    let cells = (1u64 << step) as f64;
+    let lon = (point.lon - LON_MIN) / (LON_MAX - LON_MIN) * cells;
+    let lat = (point.lat - LAT_MIN) / (LAT_MAX - LAT_MIN) * cells;
+    let max = (1u64 << step) - 1;
+    ((lon as u64).min(max), (lat as u64).min(max))
+}
+
+/// Spreads `even` over the even bits and `odd` over the odd bits.
+fn interleave(even: u64, odd: u64) -> u64 {
+    (0..STEP_MAX).fold(0, |acc, bit| {
+        acc | ((even >> bit) & 1) << (2 * bit) | ((odd >> bit) & 1) << (2 * bit + 1)
+    })
+}
+
+fn deinterleave(hash: u64) -> (u64, u64) {
+    (0..STEP_MAX).fold((0, 0), |(even, odd), bit| {
+        (
+            even | ((hash >> (2 * bit)) & 1) << bit,
+            odd | ((hash >> (2 * bit + 1)) & 1) << bit,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn palermo() -> Point {
+        Point::new(13.361389, 38.115556).unwrap()
+    }
+
+    fn catania() -> Point {
+        Point::new(15.087269, 37.502669).unwrap()
+    }
+
+    #[test]
+    fn should_round_trip_through_geohashes() {
+        let decoded = decode(encode(palermo()));
+        // GEOPOS Sicily Palermo on Redis.
+        assert!((decoded.lon - 13.361_389_338_970_184).abs() < 1e-9);
+        assert!((decoded.lat - 38.115_556_395_496_3).abs() < 1e-9);
+        assert!(encode(palermo()) < 1 << 52);
+        assert_eq!(Point::new(181.0, 0.0), None);
+        assert_eq!(Point::new(0.0, 86.0), None);
+    }
+
+    #[test]
+    fn should_measure_distances_like_redis() {
+        let meters = distance(decode(encode(palermo())), decode(encode(catania())));
+        assert_eq!(format!("{:.4}", meters), "166274.1516");
+    }
+
+    #[test]
+    fn should_cover_searched_shapes() {
+        let center = Point::new(15.0, 37.0).unwrap();
+        for shape in [
+            Shape::Radius(200_000.0),
+            Shape::Box {
+                width: 400_000.0,
+                height: 400_000.0,
+            },
+        ] {
+            let ranges = search_ranges(center, shape);
+            for city in [palermo(), catania()] {
+                let hash = encode(city);
+                assert!(ranges.iter().any(|(min, max)| (*min..*max).contains(&hash)));
+                assert!(shape.distance_within(center, city).is_some());
+            }
+        }
+        assert_eq!(
+            Shape::Radius(100_000.0).distance_within(center, palermo()),
+            None
+        );
+    }
+}
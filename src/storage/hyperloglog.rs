@@ -0,0 +1,402 @@
+//! HyperLogLog cardinality estimation stored in string values, using the same
+//! layout as Redis so values move freely between the two through dumps.
+//!
+//! A value starts with a 16 byte header: the `HYLL` magic, the encoding, three
+//! unused bytes and the cached cardinality as a little endian `u64` whose most
+//! significant bit marks the cache stale. Registers follow either densely
+//! packed, 6 bits each, or run-length encoded in the sparse format.
+
+/// Number of index bits taken from each hash.
+const P: u32 = 14;
+
+/// Number of registers.
+const REGISTERS: usize = 1 << P;
+
+const REGISTER_BITS: usize = 6;
+
+const HEADER_LEN: usize = 16;
+
+const DENSE_LEN: usize = HEADER_LEN + REGISTERS * REGISTER_BITS / 8;
+
+const MAGIC: &[u8; 4] = b"HYLL";
+
+const DENSE: u8 = 0;
+const SPARSE: u8 = 1;
+
+/// Largest register value the sparse `VAL` opcode can hold.
+const SPARSE_MAX_VALUE: u8 = 32;
+
+/// Size past which sparse values are promoted to the dense encoding, matching
+/// the default of Redis's `hll-sparse-max-bytes`.
+const SPARSE_MAX_BYTES: usize = 3000;
+
+const SEED: u64 = 0xadc8_3b19;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HllError {
+    /// The string is not a HyperLogLog at all.
+    NotHll,
+    /// The header is fine but the registers cannot be decoded.
+    Corrupted,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+    dense: bool,
+    cached: Option<u64>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog::new()
+    }
+}
+
+impl HyperLogLog {
+    /// An empty HyperLogLog, stored sparse until it outgrows that encoding.
+    pub fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; REGISTERS],
+            dense: false,
+            cached: Some(0),
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<HyperLogLog, HllError> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+            return Err(HllError::NotHll);
+        }
+
+        let mut card = [0; 8];
+        card.copy_from_slice(&bytes[8..HEADER_LEN]);
+        let cached = (card[7] & 0x80 == 0).then(|| u64::from_le_bytes(card));
+
+        let (registers, dense) = match bytes[4] {
+            DENSE if bytes.len() == DENSE_LEN => (decode_dense(&bytes[HEADER_LEN..]), true),
+            DENSE => return Err(HllError::NotHll),
+            SPARSE => (decode_sparse(&bytes[HEADER_LEN..])?, false),
+            _ => return Err(HllError::NotHll),
+        };
+        Ok(HyperLogLog {
+            registers,
+            dense,
+            cached,
+        })
+    }
+
+    /// Encodes the registers, sparse while they fit in that encoding and
+    /// dense from then on.
+    pub fn encode(&self) -> Vec<u8> {
+        let sparse = (!self.dense)
+            .then(|| encode_sparse(&self.registers))
+            .flatten()
+            .filter(|sparse| HEADER_LEN + sparse.len() <= SPARSE_MAX_BYTES);
+
+        let mut bytes = Vec::with_capacity(DENSE_LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(if sparse.is_some() { SPARSE } else { DENSE });
+        bytes.extend_from_slice(&[0; 3]);
+        let card = match self.cached {
+            Some(count) => count.to_le_bytes(),
+            None => {
+                let mut stale = [0; 8];
+                stale[7] = 0x80;
+                stale
+            }
+        };
+        bytes.extend_from_slice(&card);
+        match sparse {
+            Some(sparse) => bytes.extend_from_slice(&sparse),
+            None => bytes.extend_from_slice(&encode_dense(&self.registers)),
+        }
+        bytes
+    }
+
+    pub fn is_dense(&self) -> bool {
+        self.dense
+    }
+
+    /// Adds an element, returning whether any register changed.
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let (index, count) = hash_element(element);
+        if self.registers[index] >= count {
+            return false;
+        }
+        self.registers[index] = count;
+        self.cached = None;
+        true
+    }
+
+    /// Folds `other` into this HyperLogLog, which then estimates their union.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+            if *other > *register {
+                *register = *other;
+                self.cached = None;
+            }
+        }
+    }
+
+    /// Switches to the dense encoding for good.
+    pub fn promote(&mut self) {
+        self.dense = true;
+    }
+
+    /// Estimated cardinality, served from the cache when it is valid.
+    pub fn count(&mut self) -> u64 {
+        *self.cached.get_or_insert_with(|| estimate(&self.registers))
+    }
+}
+
+/// Register index and run length of trailing zeros (plus one) for `element`.
+fn hash_element(element: &[u8]) -> (usize, u8) {
+    let hash = murmur_hash64a(element, SEED);
+    let index = hash as usize & (REGISTERS - 1);
+    // Setting a bit past the remaining 50 bounds the count.
+    let rest = (hash >> P) | (1 << (64 - P));
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+/// MurmurHash64A, the hash Redis feeds HyperLogLogs with.
+fn murmur_hash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= (*byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// Ertl's improved estimator, as used by Redis.
+fn estimate(registers: &[u8]) -> u64 {
+    const Q: usize = 64 - P as usize;
+    const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+    let mut histogram = [0u32; 64];
+    for register in registers {
+        histogram[*register as usize] += 1;
+    }
+
+    let m = REGISTERS as f64;
+    let mut z = m * tau((m - histogram[Q + 1] as f64) / m);
+    for count in histogram[1..=Q].iter().rev() {
+        z += *count as f64;
+        z *= 0.5;
+    }
+    z += m * sigma(histogram[0] as f64 / m);
+    (ALPHA_INF * m * m / z).round() as u64
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if z == previous {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z == previous {
+            return z / 3.0;
+        }
+    }
+}
+
+/// Unpacks 6 bit registers stored least significant bit first.
+fn decode_dense(bytes: &[u8]) -> Vec<u8> {
+    (0..REGISTERS)
+        .map(|index| {
+            let bit = index * REGISTER_BITS;
+            let (byte, shift) = (bit / 8, bit % 8);
+            let low = bytes[byte] as u16 >> shift;
+            let high = bytes
+                .get(byte + 1)
+                .map_or(0, |next| (*next as u16) << (8 - shift));
+            ((low | high) & 0x3f) as u8
+        })
+        .collect()
+}
+
+fn encode_dense(registers: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0u8; REGISTERS * REGISTER_BITS / 8];
+    for (index, register) in registers.iter().enumerate() {
+        let bit = index * REGISTER_BITS;
+        let (byte, shift) = (bit / 8, bit % 8);
+        let value = (*register as u16 & 0x3f) << shift;
+        bytes[byte] |= value as u8;
+        if let Some(next) = bytes.get_mut(byte + 1) {
+            *next |= (value >> 8) as u8;
+        }
+    }
+    bytes
+}
+
+/// Expands the sparse opcodes: `00xxxxxx` for up to 64 empty registers,
+/// `01xxxxxx yyyyyyyy` for up to 16384, and `1vvvvvxx` for up to 4 registers
+/// holding `v + 1`.
+fn decode_sparse(bytes: &[u8]) -> Result<Vec<u8>, HllError> {
+    let mut registers = Vec::with_capacity(REGISTERS);
+    let mut at = 0;
+    while at < bytes.len() {
+        let opcode = bytes[at];
+        let (value, run) = match opcode >> 6 {
+            0b00 => (0, (opcode & 0x3f) as usize + 1),
+            0b01 => {
+                let low = *bytes.get(at + 1).ok_or(HllError::Corrupted)?;
+                at += 1;
+                (0, (((opcode & 0x3f) as usize) << 8 | low as usize) + 1)
+            }
+            _ => (((opcode >> 2) & 0x1f) + 1, (opcode & 0x03) as usize + 1),
+        };
+        at += 1;
+        if registers.len() + run > REGISTERS {
+            return Err(HllError::Corrupted);
+        }
+        registers.resize(registers.len() + run, value);
+    }
+
+    if registers.len() != REGISTERS {
+        return Err(HllError::Corrupted);
+    }
+    Ok(registers)
+}
+
+/// Run-length encodes the registers, `None` if one is too large for the
+/// sparse format.
+fn encode_sparse(registers: &[u8]) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut at = 0;
+    while at < registers.len() {
+        let value = registers[at];
+        let run = registers[at..].iter().take_while(|r| **r == value).count();
+        at += run;
+
+        if value > SPARSE_MAX_VALUE {
+            return None;
+        }
+        let mut left = run;
+        while left > 0 {
+            if value > 0 {
+                let len = left.min(4);
+                bytes.push(0x80 | (value - 1) << 2 | (len - 1) as u8);
+                left -= len;
+            } else if left > 64 {
+                let len = left.min(REGISTERS) - 1;
+                bytes.push(0x40 | (len >> 8) as u8);
+                bytes.push(len as u8);
+                left -= len + 1;
+            } else {
+                bytes.push((left - 1) as u8);
+                left = 0;
+            }
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_match_redis_encoding() {
+        // What `PFADD hll` leaves behind on Redis: one XZERO covering every register.
+        let empty = b"HYLL\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x7f\xff";
+        assert_eq!(HyperLogLog::new().encode(), empty.to_vec());
+
+        let mut hll = HyperLogLog::decode(empty).unwrap();
+        for element in ["a", "b", "c"] {
+            assert!(hll.add(element.as_bytes()));
+        }
+        assert!(!hll.add(b"a"));
+        assert_eq!(hll.count(), 3);
+        assert_eq!(HyperLogLog::decode(&hll.encode()), Ok(hll));
+    }
+
+    #[test]
+    fn should_estimate_cardinalities() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.count(), 0);
+        for i in 0..100_000 {
+            hll.add(format!("element:{}", i).as_bytes());
+        }
+        let count = hll.count() as f64;
+        assert!((count - 100_000.0).abs() / 100_000.0 < 0.02, "{}", count);
+    }
+
+    #[test]
+    fn should_promote_to_dense_when_sparse_grows() {
+        let mut hll = HyperLogLog::new();
+        for i in 0..5_000 {
+            hll.add(format!("{}", i).as_bytes());
+        }
+        let encoded = hll.encode();
+        assert_eq!(encoded[4], DENSE);
+        assert_eq!(encoded.len(), DENSE_LEN);
+
+        let decoded = HyperLogLog::decode(&encoded).unwrap();
+        assert!(decoded.is_dense());
+        assert_eq!(decoded.registers, hll.registers);
+    }
+
+    #[test]
+    fn should_merge_into_union() {
+        let mut left = HyperLogLog::new();
+        let mut right = HyperLogLog::new();
+        for i in 0..1_000 {
+            left.add(format!("{}", i).as_bytes());
+            right.add(format!("{}", i + 500).as_bytes());
+        }
+        left.merge(&right);
+        let count = left.count() as f64;
+        assert!((count - 1_500.0).abs() / 1_500.0 < 0.02, "{}", count);
+    }
+
+    #[test]
+    fn should_reject_invalid_values() {
+        assert_eq!(HyperLogLog::decode(b"foobar"), Err(HllError::NotHll));
+        let mut truncated = HyperLogLog::new().encode();
+        truncated.pop();
+        assert_eq!(HyperLogLog::decode(&truncated), Err(HllError::Corrupted));
+    }
+}
@@ -0,0 +1,807 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet, HashMap},
+    hash::BuildHasher,
+    mem,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use rand::Rng;
+use tokio::sync::RwLock;
+
+use super::{
+    on_expired, timer::Timer, unix_time_ms, Data, Expiry, MaxmemoryPolicy, OnExpired, Update,
+    UpdateMany, Value, WriteView,
+};
+use crate::{
+    locks::KEY_LOCKS,
+    notify::{NotifyFlags, KEYSPACE_EVENTS},
+    replication::REPLICATION,
+    stats::STATS,
+    tracking::TRACKING,
+};
+
+/// What a key costs on top of its name and value: the table slot, the
+/// sampling indexes and the access bookkeeping, roughly.
+const KEY_OVERHEAD: usize = 96;
+
+/// How many times eviction samples again for a key no command holds before
+/// giving up.
+const EVICTION_TRIES: usize = 3;
+
+/// Shards used unless configured otherwise.
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// Where the access frequency of a new key starts, so it gets some time to
+/// be accessed before LFU eviction picks it.
+const LFU_INIT: u64 = 5;
+
+/// How much harder each increment of the access frequency gets, Redis's
+/// default lfu-log-factor.
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+/// Minutes without access for the access frequency to drop by one, Redis's
+/// default lfu-decay-time.
+const LFU_DECAY_MINUTES: u64 = 1;
+
+/// Every key of a database, spread over independently locked shards by the
+/// hash of the key so writers to different keys rarely wait on each other.
+#[derive(Debug)]
+pub struct Keyspace {
+    shards: Box<[RwLock<Shard>]>,
+    hasher: RandomState,
+    /// Estimated bytes taken by every key and value.
+    used: AtomicUsize,
+    /// Keys written or deleted so far, for saves to tell how much changed.
+    changes: AtomicU64,
+    timer: Timer,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    entries: HashMap<String, Slot>,
+    /// Every key, for eviction to sample.
+    all: SampledKeys,
+    /// Keys with an expiry, for the active expiration cycle and the
+    /// volatile eviction policies to sample.
+    volatile: SampledKeys,
+    /// Keys with an expiry in the order they expire, for them to be
+    /// deleted on time.
+    deadlines: BTreeSet<(Expiry, String)>,
+}
+
+/// A stored value with what eviction needs to know about it.
+#[derive(Debug)]
+struct Slot {
+    value: Value,
+    /// Estimated bytes taken by the key and value.
+    size: usize,
+    /// When the key was last read or written, in milliseconds since the
+    /// epoch. Atomic so reads can bump it under a shared lock.
+    accessed: AtomicU64,
+    frequency: Frequency,
+}
+
+impl Slot {
+    fn touch(&self, now: u64) {
+        self.accessed.store(now, Ordering::Relaxed);
+        self.frequency.hit(now);
+    }
+}
+
+/// How often a key is accessed, as Redis tracks it for LFU eviction: an
+/// 8 bit counter that grows logarithmically with accesses and drops as
+/// minutes go by without any, packed with the minute it last changed.
+#[derive(Debug)]
+struct Frequency(AtomicU64);
+
+impl Frequency {
+    fn new(now: u64) -> Self {
+        Frequency(AtomicU64::new((now / 60_000) << 8 | LFU_INIT))
+    }
+
+    /// The counter at `now`, in milliseconds since the epoch.
+    fn get(&self, now: u64) -> u64 {
+        let packed = self.0.load(Ordering::Relaxed);
+        let idle = (now / 60_000).saturating_sub(packed >> 8);
+        (packed & 0xff).saturating_sub(idle / LFU_DECAY_MINUTES)
+    }
+
+    /// Counts an access at `now`. The more accesses counted already, the
+    /// less likely another one bumps the counter.
+    fn hit(&self, now: u64) {
+        let mut counter = self.get(now);
+        let odds = 1.0 / ((counter.saturating_sub(LFU_INIT)) as f64 * LFU_LOG_FACTOR + 1.0);
+        if counter < 255 && rand::thread_rng().gen::<f64>() < odds {
+            counter += 1;
+        }
+        self.0
+            .store((now / 60_000) << 8 | counter, Ordering::Relaxed);
+    }
+}
+
+impl Clone for Frequency {
+    fn clone(&self) -> Self {
+        Frequency(AtomicU64::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+/// A set of keys that can be sampled at random in constant time.
+#[derive(Debug, Default)]
+struct SampledKeys {
+    keys: Vec<String>,
+    positions: HashMap<String, usize>,
+}
+
+impl SampledKeys {
+    fn insert(&mut self, key: &str) {
+        if !self.positions.contains_key(key) {
+            self.positions.insert(key.to_string(), self.keys.len());
+            self.keys.push(key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(at) = self.positions.remove(key) {
+            self.keys.swap_remove(at);
+            if let Some(moved) = self.keys.get(at) {
+                self.positions.insert(moved.clone(), at);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.positions.clear();
+    }
+
+    fn sample(&self) -> Option<&str> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let at = rand::thread_rng().gen_range(0..self.keys.len());
+        Some(&self.keys[at])
+    }
+}
+
+impl Shard {
+    /// Stores `value` under `key`, returning the size of the value it
+    /// replaced if any.
+    fn insert(&mut self, key: String, value: Value, size: usize, now: u64) -> Option<usize> {
+        match value.expiry {
+            Some(_) => self.volatile.insert(&key),
+            None => self.volatile.remove(&key),
+        }
+        self.all.insert(&key);
+        let before = self.entries.get(&key).and_then(|slot| slot.value.expiry);
+        if before != value.expiry {
+            if let Some(before) = before {
+                self.deadlines.remove(&(before, key.clone()));
+            }
+            if let Some(after) = value.expiry {
+                self.deadlines.insert((after, key.clone()));
+            }
+        }
+        // Overwriting a key counts as one more access to it.
+        let frequency = match self.entries.get(&key) {
+            Some(slot) => {
+                slot.frequency.hit(now);
+                slot.frequency.clone()
+            }
+            None => Frequency::new(now),
+        };
+        let slot = Slot {
+            value,
+            size,
+            accessed: AtomicU64::new(now),
+            frequency,
+        };
+        self.entries.insert(key, slot).map(|slot| slot.size)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Slot> {
+        self.volatile.remove(key);
+        self.all.remove(key);
+        let slot = self.entries.remove(key)?;
+        if let Some(expiry) = slot.value.expiry {
+            self.deadlines.remove(&(expiry, key.to_string()));
+        }
+        Some(slot)
+    }
+
+    /// Deletes `key` if it has expired by `now`, in milliseconds since the
+    /// epoch, returning the size it took if it did. Writes the master sent
+    /// leave it be.
+    fn remove_expired(&mut self, key: &str, now: u64) -> Option<usize> {
+        if !self
+            .entries
+            .get(key)
+            .is_some_and(|slot| expired(&slot.value, now))
+            || on_expired(true) == OnExpired::Keep
+        {
+            return None;
+        }
+        let size = self.remove(key).map(|slot| slot.size);
+        STATS.expired_keys.fetch_add(1, Ordering::Relaxed);
+        KEYSPACE_EVENTS.notify(NotifyFlags::EXPIRED, "expired", key);
+        REPLICATION.propagate_deletion(key);
+        TRACKING.invalidate(&[key], None);
+        size
+    }
+}
+
+fn expired(value: &Value, now: u64) -> bool {
+    value.expiry.is_some_and(|expiry| expiry.has_passed(now))
+}
+
+/// Estimated bytes `key` and `value` take once stored.
+pub(super) fn size_of(key: &str, value: &Value) -> usize {
+    KEY_OVERHEAD + key.len() + value.value.memory_usage()
+}
+
+impl Default for Keyspace {
+    fn default() -> Self {
+        Keyspace::new(DEFAULT_SHARDS)
+    }
+}
+
+impl Keyspace {
+    /// An empty keyspace over `shards` shards, at least one.
+    pub fn new(shards: usize) -> Self {
+        Keyspace {
+            shards: (0..shards.max(1))
+                .map(|_| RwLock::new(Shard::default()))
+                .collect(),
+            hasher: RandomState::new(),
+            used: AtomicUsize::new(0),
+            changes: AtomicU64::new(0),
+            timer: Timer::default(),
+        }
+    }
+
+    fn shard_at(&self, key: &str) -> usize {
+        self.hasher.hash_one(key) as usize % self.shards.len()
+    }
+
+    fn shard(&self, key: &str) -> &RwLock<Shard> {
+        &self.shards[self.shard_at(key)]
+    }
+
+    /// Estimated bytes taken by every key and value.
+    pub fn used_memory(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// How many times a key was written or deleted since the keyspace was
+    /// created.
+    pub fn changes(&self) -> u64 {
+        self.changes.load(Ordering::Relaxed)
+    }
+
+    fn release(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    /// Accounts for a value of `size` bytes written in place of one of
+    /// `replaced` bytes if any.
+    fn stored(&self, size: usize, replaced: Option<usize>) {
+        self.used.fetch_add(size, Ordering::Relaxed);
+        if let Some(replaced) = replaced {
+            self.release(replaced);
+        }
+        self.changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Accounts for a value of `size` bytes deleted.
+    fn deleted(&self, size: usize) {
+        self.release(size);
+        self.changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Stores `value` under `key`, returning whether it replaced one.
+    pub async fn insert(&self, key: String, value: Value) -> bool {
+        let size = size_of(&key, &value);
+        if let Some(expiry) = value.expiry {
+            self.timer.schedule(expiry);
+        }
+        let mut shard = self.shard(&key).write().await;
+        let replaced = shard.insert(key, value, size, unix_time_ms());
+        self.stored(size, replaced);
+        replaced.is_some()
+    }
+
+    /// The value under `key` unless it is missing or expired, in which case
+    /// it is deleted on the spot if this is a master.
+    pub async fn get(&self, key: &str) -> Option<Value> {
+        let now = unix_time_ms();
+        let shard = self.shard(key);
+        match shard.read().await.entries.get(key) {
+            Some(slot) if !expired(&slot.value, now) || on_expired(false) == OnExpired::Keep => {
+                slot.touch(now);
+                return Some(slot.value.clone());
+            }
+            Some(_) => {}
+            None => return None,
+        }
+        if on_expired(false) == OnExpired::Hide {
+            return None;
+        }
+        if let Some(size) = shard.write().await.remove_expired(key, now) {
+            self.deleted(size);
+        }
+        None
+    }
+
+    /// The access frequency of `key` unless it is missing or expired, without
+    /// counting this as an access.
+    pub async fn frequency(&self, key: &str) -> Option<u8> {
+        let now = unix_time_ms();
+        let shard = self.shard(key).read().await;
+        let slot = shard
+            .entries
+            .get(key)
+            .filter(|slot| !expired(&slot.value, now))?;
+        Some(slot.frequency.get(now) as u8)
+    }
+
+    /// Runs `f` on the value under `key`, `None` if missing or expired, and
+    /// stores what it leaves there, deleting the key if it leaves `None`.
+    /// The key's shard stays locked throughout, so nothing else gets to it
+    /// in between. Returns whether the key existed before and whether it
+    /// does after, or `None` if `f` changed nothing.
+    pub async fn update(&self, key: &str, f: &mut Update<'_>) -> Option<(bool, bool)> {
+        let now = unix_time_ms();
+        let mut shard = self.shard(key).write().await;
+        let mut value = self.take(&mut shard, key, now);
+        let existed = value.is_some();
+        if !f(&mut value) {
+            // Only the data was moved out, so the slot is still there to
+            // take it back as it was.
+            if let (Some(value), Some(slot)) = (value, shard.entries.get_mut(key)) {
+                slot.value.value = value.value;
+            }
+            return None;
+        }
+        Some((existed, self.put(&mut shard, key, value, now)))
+    }
+
+    /// `update` for several keys, `f` seeing all their values at once. The
+    /// shards holding them are locked in order, so two such updates never
+    /// wait on each other, and stay locked until every value is stored.
+    /// Returns each key with whether it existed before and whether it does
+    /// after.
+    pub async fn update_many(
+        &self,
+        keys: &[&str],
+        f: &mut UpdateMany<'_>,
+    ) -> Vec<(String, bool, bool)> {
+        let now = unix_time_ms();
+        let mut at: Vec<usize> = keys.iter().map(|key| self.shard_at(key)).collect();
+        at.sort_unstable();
+        at.dedup();
+        let mut locked = BTreeMap::new();
+        for at in at {
+            locked.insert(at, self.shards[at].write().await);
+        }
+
+        let mut view = WriteView::default();
+        for &key in keys {
+            if !view.values.contains_key(key) {
+                let shard = locked
+                    .get_mut(&self.shard_at(key))
+                    .expect("shard is locked");
+                let value = self.take(shard, key, now);
+                view.insert(key, value);
+            }
+        }
+        f(&mut view);
+        let mut changed = Vec::with_capacity(view.values.len());
+        for (key, value, written) in view.into_values() {
+            let shard = locked
+                .get_mut(&self.shard_at(&key))
+                .expect("shard is locked");
+            if !written {
+                if let (Some(value), Some(slot)) = (value, shard.entries.get_mut(&key)) {
+                    slot.value.value = value.value;
+                }
+                continue;
+            }
+            let existed = shard.entries.contains_key(&key);
+            let stored = self.put(shard, &key, value, now);
+            changed.push((key, existed, stored));
+        }
+        changed
+    }
+
+    /// Moves the value under `key` out of `shard` for an update to change,
+    /// `None` if missing or expired. Rather than cloned, the value is
+    /// replaced by an empty string until `put` stores it back.
+    fn take(&self, shard: &mut Shard, key: &str, now: u64) -> Option<Value> {
+        if let Some(size) = shard.remove_expired(key, now) {
+            self.deleted(size);
+        }
+        shard.entries.get_mut(key).map(|slot| Value {
+            value: mem::replace(&mut slot.value.value, Data::String(Vec::new())),
+            expiry: slot.value.expiry,
+        })
+    }
+
+    /// Stores what an update left under `key`, deleting the key if it left
+    /// `None`. Returns whether the key is there after.
+    fn put(&self, shard: &mut Shard, key: &str, value: Option<Value>, now: u64) -> bool {
+        match value {
+            Some(value) => {
+                let size = size_of(key, &value);
+                if let Some(expiry) = value.expiry {
+                    self.timer.schedule(expiry);
+                }
+                let replaced = shard.insert(key.to_string(), value, size, now);
+                self.stored(size, replaced);
+                true
+            }
+            None => {
+                if let Some(slot) = shard.remove(key) {
+                    self.deleted(slot.size);
+                }
+                false
+            }
+        }
+    }
+
+    pub async fn remove(&self, key: &str) -> bool {
+        let removed = self.shard(key).write().await.remove(key);
+        if let Some(slot) = &removed {
+            self.deleted(slot.size);
+        }
+        removed.is_some()
+    }
+
+    /// Every key not expired yet, in no particular order.
+    pub async fn keys(&self) -> Vec<String> {
+        let now = unix_time_ms();
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().await;
+            let live = shard
+                .entries
+                .iter()
+                .filter(|(_, slot)| !expired(&slot.value, now));
+            keys.extend(live.map(|(key, _)| key.clone()));
+        }
+        keys
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// A copy of the entries of shard `at` not expired yet, so a whole
+    /// keyspace can be gone through a shard at a time without ever copying
+    /// all of it.
+    pub async fn snapshot_shard(&self, at: usize) -> Vec<(String, Value)> {
+        let now = unix_time_ms();
+        let shard = self.shards[at].read().await;
+        shard
+            .entries
+            .iter()
+            .filter(|(_, slot)| !expired(&slot.value, now))
+            .map(|(key, slot)| (key.clone(), slot.value.clone()))
+            .collect()
+    }
+
+    /// Replaces every entry with those in `map`.
+    pub async fn replace(&self, map: HashMap<String, Value>) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().await;
+            let freed: usize = shard.entries.values().map(|slot| slot.size).sum();
+            self.release(freed);
+            shard.entries.clear();
+            shard.all.clear();
+            shard.volatile.clear();
+            shard.deadlines.clear();
+        }
+        for (key, value) in map {
+            self.insert(key, value).await;
+        }
+    }
+
+    /// Checks up to `samples` keys with an expiry picked at random in every
+    /// shard and deletes the expired ones. Returns how many keys were
+    /// checked and how many of them deleted.
+    pub async fn purge_expired(&self, samples: usize) -> (usize, usize) {
+        let now = unix_time_ms();
+        let (mut checked, mut purged) = (0, 0);
+        for (at, shard) in self.shards.iter().enumerate() {
+            let mut due = Vec::new();
+            {
+                let shard = shard.read().await;
+                for _ in 0..samples.min(shard.volatile.keys.len()) {
+                    let Some(key) = shard.volatile.sample() else {
+                        break;
+                    };
+                    checked += 1;
+                    if expired(&shard.entries[key].value, now) {
+                        due.push(key.to_string());
+                    }
+                }
+            }
+            for key in due {
+                if self.expire(at, &key, now).await {
+                    purged += 1;
+                }
+            }
+        }
+        (checked, purged)
+    }
+
+    /// Deletes every key whose deadline has passed, going by deadline rather
+    /// than sampling, then waits until the next one passes or a key gets an
+    /// earlier one. Returns how many keys it deleted.
+    pub async fn expire_due(&self) -> usize {
+        self.timer.reset();
+        let now = unix_time_ms();
+        let (mut purged, mut next) = (0, None);
+        for (at, shard) in self.shards.iter().enumerate() {
+            let mut due = Vec::new();
+            {
+                let shard = shard.read().await;
+                for (expiry, key) in shard.deadlines.iter() {
+                    if !expiry.has_passed(now) {
+                        next = Some(next.map_or(*expiry, |next: Expiry| next.min(*expiry)));
+                        break;
+                    }
+                    due.push(key.clone());
+                }
+            }
+            for key in due {
+                if self.expire(at, &key, now).await {
+                    purged += 1;
+                }
+            }
+        }
+        self.timer.wait(next).await;
+        purged
+    }
+
+    /// Deletes `key`, in the shard at `at`, if it has expired by `now`,
+    /// returning whether it did. The key is held like a command writing it
+    /// would, for the deletion to be propagated after any write to it.
+    async fn expire(&self, at: usize, key: &str, now: u64) -> bool {
+        let _held = KEY_LOCKS.write(&[key]).await;
+        let size = self.shards[at].write().await.remove_expired(key, now);
+        if let Some(size) = size {
+            self.deleted(size);
+        }
+        size.is_some()
+    }
+
+    /// Deletes the key `policy` finds the best to lose out of `samples` keys
+    /// picked at random, as Redis approximates LRU and LFU rather than
+    /// tracking every key in order. Returns false if there was no key the
+    /// policy may delete.
+    pub async fn evict(&self, policy: MaxmemoryPolicy, samples: usize) -> bool {
+        if policy == MaxmemoryPolicy::NoEviction {
+            return false;
+        }
+        for _ in 0..EVICTION_TRIES {
+            let Some((at, key)) = self.pick(policy, samples).await else {
+                return false;
+            };
+            // Keys held by commands, the one evicting among them, are passed
+            // over: their writes are to be propagated before the deletion.
+            let Some(_held) = KEY_LOCKS.try_write(&[&key]) else {
+                continue;
+            };
+            // The key may have gone while no lock was held, which frees
+            // memory all the same.
+            if let Some(slot) = self.shards[at].write().await.remove(&key) {
+                self.deleted(slot.size);
+                STATS.evicted_keys.fetch_add(1, Ordering::Relaxed);
+                KEYSPACE_EVENTS.notify(NotifyFlags::EVICTED, "evicted", &key);
+                REPLICATION.propagate_deletion(&key);
+                TRACKING.invalidate(&[&key], None);
+            }
+            return true;
+        }
+        false
+    }
+
+    /// The key `policy` finds the best to lose out of `samples` keys picked
+    /// at random, with its shard.
+    async fn pick(&self, policy: MaxmemoryPolicy, samples: usize) -> Option<(usize, String)> {
+        let mut best: Option<(usize, String, (u64, u64))> = None;
+        for _ in 0..samples.max(1) {
+            let (at, key, rank) = self.sample(policy).await?;
+            if best.as_ref().is_none_or(|(_, _, best)| rank < *best) {
+                best = Some((at, key, rank));
+            }
+            if policy.is_random() {
+                break;
+            }
+        }
+        best.map(|(at, key, _)| (at, key))
+    }
+
+    /// A key `policy` may evict from a random shard that has one, with its
+    /// shard and a rank that is lower for better candidates.
+    async fn sample(&self, policy: MaxmemoryPolicy) -> Option<(usize, String, (u64, u64))> {
+        let start = rand::thread_rng().gen_range(0..self.shards.len());
+        for offset in 0..self.shards.len() {
+            let at = (start + offset) % self.shards.len();
+            let shard = self.shards[at].read().await;
+            let keys = match policy.is_volatile() {
+                true => &shard.volatile,
+                false => &shard.all,
+            };
+            let Some(key) = keys.sample() else {
+                continue;
+            };
+            let slot = &shard.entries[key];
+            let accessed = slot.accessed.load(Ordering::Relaxed);
+            let rank = match policy {
+                MaxmemoryPolicy::AllKeysLfu | MaxmemoryPolicy::VolatileLfu => {
+                    (slot.frequency.get(unix_time_ms()), accessed)
+                }
+                MaxmemoryPolicy::VolatileTtl => {
+                    (slot.value.expiry.map_or(u64::MAX, |e| e.unix_ms()), 0)
+                }
+                _ => (accessed, 0),
+            };
+            return Some((at, key.to_string(), rank));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::storage::{Data, Expiry};
+
+    fn string(text: &str) -> Value {
+        Value {
+            value: Data::String(text.as_bytes().to_vec()),
+            expiry: None,
+        }
+    }
+
+    /// A copy of every entry not expired yet.
+    async fn snapshot(keyspace: &Keyspace) -> HashMap<String, Value> {
+        let mut map = HashMap::new();
+        for at in 0..keyspace.shard_count() {
+            map.extend(keyspace.snapshot_shard(at).await);
+        }
+        map
+    }
+
+    fn expiring(expiry: Expiry) -> Value {
+        Value {
+            expiry: Some(expiry),
+            ..string("v")
+        }
+    }
+
+    #[tokio::test]
+    async fn should_spread_keys_over_shards() {
+        let keyspace = Keyspace::new(4);
+        for key in 0..100 {
+            assert!(!keyspace.insert(key.to_string(), string("v")).await);
+        }
+        assert!(keyspace.insert("7".to_string(), string("w")).await);
+        assert!(keyspace
+            .shards
+            .iter()
+            .all(|shard| !shard.try_read().unwrap().entries.is_empty()));
+
+        assert_eq!(keyspace.get("7").await.unwrap().value, string("w").value);
+        assert!(keyspace.remove("7").await);
+        assert!(keyspace.get("7").await.is_none());
+        assert_eq!(keyspace.keys().await.len(), 99);
+
+        let snapshot = snapshot(&keyspace).await;
+        keyspace
+            .replace(HashMap::from([("only".to_string(), string("v"))]))
+            .await;
+        assert_eq!(snapshot.len(), 99);
+        assert_eq!(keyspace.keys().await, vec!["only".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn should_delete_expired_keys() {
+        let keyspace = Keyspace::new(2);
+        let past = Expiry::from_unix_ms(unix_time_ms() - 1000);
+        let future = Expiry::after(Duration::from_secs(60)).unwrap();
+        keyspace.insert("read".to_string(), expiring(past)).await;
+        for key in 0..50 {
+            keyspace.insert(format!("old{}", key), expiring(past)).await;
+            keyspace
+                .insert(format!("new{}", key), expiring(future))
+                .await;
+        }
+        // Persisting a key takes it out of the sampled ones.
+        keyspace.insert("new0".to_string(), string("v")).await;
+
+        assert!(keyspace.get("read").await.is_none());
+        assert!(!keyspace.remove("read").await);
+        assert_eq!(keyspace.keys().await.len(), 50);
+
+        let mut purged = 0;
+        while purged < 50 {
+            let (checked, expired) = keyspace.purge_expired(20).await;
+            assert!(checked > 0);
+            purged += expired;
+        }
+        assert_eq!(keyspace.purge_expired(20).await.1, 0);
+        assert_eq!(snapshot(&keyspace).await.len(), 50);
+    }
+
+    #[tokio::test]
+    async fn should_delete_keys_when_they_expire() {
+        let keyspace = Keyspace::new(2);
+        let soon = Expiry::after(Duration::from_millis(30)).unwrap();
+        let later = Expiry::after(Duration::from_secs(60)).unwrap();
+        keyspace.insert("soon".to_string(), expiring(later)).await;
+        keyspace.insert("soon".to_string(), expiring(soon)).await;
+        keyspace.insert("later".to_string(), expiring(later)).await;
+        keyspace.insert("kept".to_string(), expiring(soon)).await;
+        keyspace.insert("kept".to_string(), string("v")).await;
+
+        // Woken early by the keys just stored, then by the first deadline,
+        // after which it deletes the key and waits for the next one.
+        let changes = keyspace.changes();
+        while keyspace.changes() == changes {
+            let due = keyspace.expire_due();
+            let _ = tokio::time::timeout(Duration::from_millis(100), due).await;
+        }
+        assert!(unix_time_ms() > soon.unix_ms());
+        assert_eq!(keyspace.changes(), changes + 1);
+        let mut keys = keyspace.keys().await;
+        keys.sort();
+        assert_eq!(keys, ["kept", "later"]);
+    }
+
+    #[test]
+    fn should_count_accesses_logarithmically() {
+        let now = unix_time_ms();
+        let frequency = Frequency::new(now);
+        assert_eq!(frequency.get(now), LFU_INIT);
+        for _ in 0..1000 {
+            frequency.hit(now);
+        }
+        let counter = frequency.get(now);
+        assert!((10..40).contains(&counter), "{}", counter);
+        assert_eq!(frequency.get(now + 3 * 60_000), counter - 3);
+        assert_eq!(frequency.get(now + 1000 * 60_000), 0);
+    }
+
+    #[tokio::test]
+    async fn should_evict_the_key_the_policy_picks() {
+        let keyspace = Keyspace::new(2);
+        let soon = Expiry::after(Duration::from_secs(10)).unwrap();
+        let later = Expiry::after(Duration::from_secs(60)).unwrap();
+        keyspace.insert("idle".to_string(), string("v")).await;
+        keyspace.insert("soon".to_string(), expiring(soon)).await;
+        keyspace.insert("later".to_string(), expiring(later)).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        for _ in 0..3 {
+            keyspace.get("soon").await;
+            keyspace.get("later").await;
+        }
+        keyspace.get("later").await;
+
+        // With two keys, every one is all but sure to be sampled.
+        assert!(keyspace.evict(MaxmemoryPolicy::VolatileTtl, 64).await);
+        assert!(keyspace.get("soon").await.is_none());
+        assert!(keyspace.evict(MaxmemoryPolicy::AllKeysLru, 64).await);
+        assert!(keyspace.get("idle").await.is_none());
+        keyspace.insert("once".to_string(), string("v")).await;
+        assert!(keyspace.evict(MaxmemoryPolicy::AllKeysLfu, 64).await);
+        assert_eq!(keyspace.keys().await, vec!["later".to_string()]);
+
+        assert!(!keyspace.evict(MaxmemoryPolicy::NoEviction, 64).await);
+        assert!(keyspace.evict(MaxmemoryPolicy::VolatileRandom, 1).await);
+        assert!(!keyspace.evict(MaxmemoryPolicy::AllKeysRandom, 1).await);
+        assert_eq!(keyspace.used_memory(), 0);
+    }
+}
@@ -0,0 +1,199 @@
+use std::{borrow::Cow, collections::HashSet, sync::atomic::Ordering};
+
+use super::COMPACT;
+
+/// How a set keeps its members. Small sets start compact and turn into a
+/// hash table for good once they outgrow the limits in `COMPACT`, as on
+/// Redis.
+#[derive(Clone, Debug)]
+enum Members {
+    /// Members that are all integers, sorted.
+    Ints(Vec<i64>),
+    /// Members one after the other, found by walking them.
+    Packed(Vec<String>),
+    Table(HashSet<String>),
+}
+
+/// An unordered collection of distinct strings.
+#[derive(Clone, Debug)]
+pub struct Set(Members);
+
+/// `member` as the integer it spells, if it spells one the same way Redis
+/// would write it back, so no member changes once stored as an integer.
+fn as_int(member: &str) -> Option<i64> {
+    member
+        .parse::<i64>()
+        .ok()
+        .filter(|int| int.to_string() == member)
+}
+
+fn fits_packed(len: usize, member: &str) -> bool {
+    len <= COMPACT.set_max_listpack_entries.load(Ordering::Relaxed)
+        && member.len() <= COMPACT.set_max_listpack_value.load(Ordering::Relaxed)
+}
+
+impl Default for Set {
+    fn default() -> Self {
+        Set(Members::Ints(Vec::new()))
+    }
+}
+
+impl PartialEq for Set {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|member| other.contains(&member))
+    }
+}
+
+impl FromIterator<String> for Set {
+    fn from_iter<I: IntoIterator<Item = String>>(members: I) -> Self {
+        let mut set = Set::new();
+        for member in members {
+            set.insert(member);
+        }
+        set
+    }
+}
+
+impl Set {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.0 {
+            Members::Ints(ints) => ints.len(),
+            Members::Packed(members) => members.len(),
+            Members::Table(members) => members.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Estimated bytes taken, far less for compact sets.
+    pub fn memory_usage(&self) -> usize {
+        match &self.0 {
+            Members::Ints(ints) => 8 * ints.len(),
+            Members::Packed(members) => members.iter().map(|member| member.len() + 2).sum(),
+            Members::Table(members) => members.iter().map(|member| member.len() + 32).sum(),
+        }
+    }
+
+    /// The name OBJECT ENCODING gives the way members are kept.
+    pub fn encoding(&self) -> &'static str {
+        match &self.0 {
+            Members::Ints(_) => "intset",
+            Members::Packed(_) => "listpack",
+            Members::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn contains(&self, member: &str) -> bool {
+        match &self.0 {
+            Members::Ints(ints) => {
+                as_int(member).is_some_and(|int| ints.binary_search(&int).is_ok())
+            }
+            Members::Packed(members) => members.iter().any(|m| m == member),
+            Members::Table(members) => members.contains(member),
+        }
+    }
+
+    /// Adds `member`, returning whether it was missing.
+    pub fn insert(&mut self, member: String) -> bool {
+        if self.contains(&member) {
+            return false;
+        }
+        let len = self.len() + 1;
+        match &mut self.0 {
+            Members::Ints(ints) => match as_int(&member) {
+                Some(int) if len <= COMPACT.set_max_intset_entries.load(Ordering::Relaxed) => {
+                    let at = ints.partition_point(|&other| other < int);
+                    ints.insert(at, int);
+                }
+                _ => {
+                    let members = ints.iter().map(i64::to_string);
+                    if fits_packed(len, &member) {
+                        self.0 = Members::Packed(members.chain([member]).collect());
+                    } else {
+                        self.0 = Members::Table(members.chain([member]).collect());
+                    }
+                }
+            },
+            Members::Packed(members) => {
+                if fits_packed(len, &member) {
+                    members.push(member);
+                } else {
+                    let mut members: HashSet<String> = members.drain(..).collect();
+                    members.insert(member);
+                    self.0 = Members::Table(members);
+                }
+            }
+            Members::Table(members) => {
+                members.insert(member);
+            }
+        }
+        true
+    }
+
+    /// Removes `member`, returning whether it was there.
+    pub fn remove(&mut self, member: &str) -> bool {
+        match &mut self.0 {
+            Members::Ints(ints) => match as_int(member).map(|int| ints.binary_search(&int)) {
+                Some(Ok(at)) => {
+                    ints.remove(at);
+                    true
+                }
+                _ => false,
+            },
+            Members::Packed(members) => match members.iter().position(|m| m == member) {
+                Some(at) => {
+                    members.swap_remove(at);
+                    true
+                }
+                None => false,
+            },
+            Members::Table(members) => members.remove(member),
+        }
+    }
+
+    /// Iterates members in no particular order, integers spelled out.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Cow<'_, str>> + '_> {
+        match &self.0 {
+            Members::Ints(ints) => Box::new(ints.iter().map(|int| Cow::Owned(int.to_string()))),
+            Members::Packed(members) => Box::new(members.iter().map(|m| Cow::Borrowed(m.as_str()))),
+            Members::Table(members) => Box::new(members.iter().map(|m| Cow::Borrowed(m.as_str()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(members: impl IntoIterator<Item = impl ToString>) -> Set {
+        members.into_iter().map(|m| m.to_string()).collect()
+    }
+
+    #[test]
+    fn should_outgrow_compact_encodings() {
+        let mut ints = set(0..512);
+        assert_eq!(ints.encoding(), "intset");
+        assert!(ints.contains("511"));
+        assert!(!ints.contains("0511"));
+        ints.insert("512".to_string());
+        assert_eq!(ints.encoding(), "hashtable");
+        assert_eq!(ints, set(0..513));
+
+        let mut packed = set(["1", "2"]);
+        packed.insert("a".to_string());
+        assert_eq!(packed.encoding(), "listpack");
+        assert!(packed.remove("1"));
+        assert!(!packed.remove("1"));
+        assert_eq!(packed, set(["2", "a"]));
+        packed.insert("a".repeat(65));
+        assert_eq!(packed.encoding(), "hashtable");
+        assert_eq!(packed.len(), 3);
+        assert!(set(["1", "2"]).memory_usage() < packed.memory_usage());
+    }
+}
@@ -0,0 +1,628 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+};
+
+#[cfg(feature = "disk")]
+use super::encoding::Decoder;
+use super::encoding::Encoder;
+
+/// Entry ID made of a millisecond timestamp and a sequence number.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Parses `ms-seq`, or a bare `ms` completed with `default_seq`.
+    pub fn parse(text: &str, default_seq: u64) -> Option<StreamId> {
+        match text.split_once('-') {
+            Some((ms, seq)) => Some(StreamId {
+                ms: ms.parse().ok()?,
+                seq: seq.parse().ok()?,
+            }),
+            None => Some(StreamId {
+                ms: text.parse().ok()?,
+                seq: default_seq,
+            }),
+        }
+    }
+
+    /// The smallest ID greater than this one.
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_add(1)?,
+                seq: 0,
+            }),
+        }
+    }
+
+    /// The largest ID smaller than this one.
+    pub fn prev(self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_sub(1)?,
+                seq: u64::MAX,
+            }),
+        }
+    }
+}
+
+impl Display for StreamId {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID requested for a new entry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NewId {
+    /// `*`: current time, sequence bumped if the clock did not advance.
+    Auto,
+    /// `ms-*`: given time, next free sequence within it.
+    AutoSeq(u64),
+    /// `ms-seq`: used verbatim.
+    Explicit(StreamId),
+}
+
+pub type Fields = Vec<(String, String)>;
+
+/// An entry delivered to a consumer and not acknowledged yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingEntry {
+    pub consumer: String,
+    pub delivered_ms: u64,
+    pub delivery_count: u64,
+}
+
+/// How a successful claim updates the pending entry.
+#[derive(Clone, Debug)]
+pub struct ClaimOptions {
+    pub min_idle_ms: u64,
+    /// Adds entries missing from the pending list as long as they are still
+    /// in the stream.
+    pub force: bool,
+    pub delivered_ms: u64,
+    /// Delivery count to set; otherwise it is bumped unless `just_id`.
+    pub retry_count: Option<u64>,
+    pub just_id: bool,
+}
+
+/// Outcome of claiming a single entry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Claim {
+    Claimed,
+    /// The entry was removed from the stream, so it was dropped from the
+    /// pending list instead.
+    Deleted,
+    Skipped,
+}
+
+/// Delivery state shared by the consumers of a group.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConsumerGroup {
+    last_delivered: StreamId,
+    pending: BTreeMap<StreamId, PendingEntry>,
+    /// Consumer names with the time they were last active.
+    consumers: BTreeMap<String, u64>,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_delivered: StreamId) -> Self {
+        Self {
+            last_delivered,
+            ..Self::default()
+        }
+    }
+
+    pub fn last_delivered(&self) -> StreamId {
+        self.last_delivered
+    }
+
+    pub fn set_last_delivered(&mut self, id: StreamId) {
+        self.last_delivered = id;
+    }
+
+    pub fn pending(&self) -> &BTreeMap<StreamId, PendingEntry> {
+        &self.pending
+    }
+
+    pub fn consumers(&self) -> impl Iterator<Item = &str> {
+        self.consumers.keys().map(String::as_str)
+    }
+
+    /// When `consumer` was last active, if it exists.
+    pub fn seen_ms(&self, consumer: &str) -> Option<u64> {
+        self.consumers.get(consumer).copied()
+    }
+
+    /// Tracks `id` as pending as it was when saved, for loading a group
+    /// back.
+    pub fn insert_pending(&mut self, id: StreamId, entry: PendingEntry) {
+        self.pending.insert(id, entry);
+    }
+
+    /// Registers `name`, returning whether it did not exist yet.
+    pub fn create_consumer(&mut self, name: &str, now_ms: u64) -> bool {
+        if self.consumers.contains_key(name) {
+            return false;
+        }
+        self.consumers.insert(name.to_string(), now_ms);
+        true
+    }
+
+    /// Removes `name` along with its pending entries, returning how many it
+    /// had, or `None` if there was no such consumer.
+    pub fn delete_consumer(&mut self, name: &str) -> Option<usize> {
+        self.consumers.remove(name)?;
+        let before = self.pending.len();
+        self.pending.retain(|_, entry| entry.consumer != name);
+        Some(before - self.pending.len())
+    }
+
+    /// Drops `id` from the pending list, returning whether it was there.
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        self.pending.remove(&id).is_some()
+    }
+
+    fn touch(&mut self, consumer: &str, now_ms: u64) {
+        self.consumers.insert(consumer.to_string(), now_ms);
+    }
+}
+
+/// Append-only log of field/value entries ordered by ID.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+    groups: BTreeMap<String, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Estimated bytes taken by the entries and consumer groups.
+    pub fn memory_usage(&self) -> usize {
+        let entries: usize = self
+            .entries
+            .values()
+            .flatten()
+            .map(|(field, value)| field.len() + value.len() + 48)
+            .sum();
+        let groups: usize = self
+            .groups
+            .iter()
+            .map(|(name, group)| {
+                let consumers: usize = group.consumers.keys().map(|name| name.len() + 32).sum();
+                name.len() + consumers + group.pending.len() * 64
+            })
+            .sum();
+        entries + self.entries.len() * 32 + groups
+    }
+
+    /// Highest ID ever added, even if that entry was trimmed since.
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// Sets the highest ID ever added, for loading a stream whose last
+    /// entries were trimmed back.
+    pub fn set_last_id(&mut self, id: StreamId) {
+        self.last_id = id;
+    }
+
+    /// Resolves `requested` against the stream's last ID, returning `None` if
+    /// the result would not be strictly greater than it.
+    pub fn next_id(&self, requested: NewId, now_ms: u64) -> Option<StreamId> {
+        let id = match requested {
+            NewId::Auto if now_ms > self.last_id.ms => StreamId { ms: now_ms, seq: 0 },
+            NewId::Auto => self.last_id.next()?,
+            NewId::AutoSeq(ms) if ms == self.last_id.ms => self.last_id.next()?,
+            NewId::AutoSeq(ms) => StreamId {
+                ms,
+                seq: (ms == 0) as u64,
+            },
+            NewId::Explicit(id) => id,
+        };
+        (id > self.last_id).then_some(id)
+    }
+
+    /// Appends an entry; `id` must come from `next_id`.
+    pub fn add(&mut self, id: StreamId, fields: Fields) {
+        self.entries.insert(id, fields);
+        self.last_id = id;
+    }
+
+    /// Drops the oldest entries until at most `max_len` remain.
+    pub fn trim_max_len(&mut self, max_len: usize, limit: Option<usize>) -> usize {
+        let excess = self.entries.len().saturating_sub(max_len);
+        let excess = limit.map_or(excess, |limit| excess.min(limit));
+        for _ in 0..excess {
+            self.entries.pop_first();
+        }
+        excess
+    }
+
+    /// Drops entries with an ID lower than `min_id`.
+    pub fn trim_min_id(&mut self, min_id: StreamId, limit: Option<usize>) -> usize {
+        let mut removed = 0;
+        while let Some(entry) = self.entries.first_entry() {
+            if *entry.key() >= min_id || limit.is_some_and(|limit| removed >= limit) {
+                break;
+            }
+            entry.remove();
+            removed += 1;
+        }
+        removed
+    }
+
+    pub fn get(&self, id: StreamId) -> Option<&Fields> {
+        self.entries.get(&id)
+    }
+
+    pub fn group(&self, name: &str) -> Option<&ConsumerGroup> {
+        self.groups.get(name)
+    }
+
+    pub fn group_mut(&mut self, name: &str) -> Option<&mut ConsumerGroup> {
+        self.groups.get_mut(name)
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (&str, &ConsumerGroup)> {
+        self.groups
+            .iter()
+            .map(|(name, group)| (name.as_str(), group))
+    }
+
+    /// Creates a group delivering entries after `last_delivered`, returning
+    /// whether it did not exist yet.
+    pub fn create_group(&mut self, name: &str, last_delivered: StreamId) -> bool {
+        if self.groups.contains_key(name) {
+            return false;
+        }
+        self.groups
+            .insert(name.to_string(), ConsumerGroup::new(last_delivered));
+        true
+    }
+
+    pub fn destroy_group(&mut self, name: &str) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// Delivers to `consumer` up to `count` entries the group has not seen,
+    /// tracking them as pending unless `no_ack`.
+    pub fn read_group(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        count: Option<usize>,
+        no_ack: bool,
+        now_ms: u64,
+    ) -> Vec<(StreamId, Fields)> {
+        let Some(group) = self.groups.get_mut(group) else {
+            return Vec::new();
+        };
+        group.touch(consumer, now_ms);
+        let Some(start) = group.last_delivered.next() else {
+            return Vec::new();
+        };
+
+        let delivered: Vec<(StreamId, Fields)> = self
+            .entries
+            .range(start..)
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, fields.clone()))
+            .collect();
+        for (id, _) in &delivered {
+            group.last_delivered = *id;
+            if !no_ack {
+                group.pending.insert(
+                    *id,
+                    PendingEntry {
+                        consumer: consumer.to_string(),
+                        delivered_ms: now_ms,
+                        delivery_count: 1,
+                    },
+                );
+            }
+        }
+        delivered
+    }
+
+    /// Entries pending for `consumer` with IDs greater than `after`, with
+    /// `None` fields for entries deleted from the stream since delivery.
+    pub fn read_pending(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        after: StreamId,
+        count: Option<usize>,
+        now_ms: u64,
+    ) -> Vec<(StreamId, Option<Fields>)> {
+        let Some(group) = self.groups.get_mut(group) else {
+            return Vec::new();
+        };
+        group.touch(consumer, now_ms);
+        let Some(start) = after.next() else {
+            return Vec::new();
+        };
+
+        group
+            .pending
+            .range(start..)
+            .filter(|(_, entry)| entry.consumer == consumer)
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, _)| (*id, self.entries.get(id).cloned()))
+            .collect()
+    }
+
+    /// Transfers the pending entry `id` to `consumer` if it has been idle for
+    /// at least `options.min_idle_ms`.
+    pub fn claim(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        id: StreamId,
+        options: &ClaimOptions,
+        now_ms: u64,
+    ) -> Claim {
+        let Some(group) = self.groups.get_mut(group) else {
+            return Claim::Skipped;
+        };
+        if !self.entries.contains_key(&id) {
+            return match group.pending.remove(&id) {
+                Some(_) => Claim::Deleted,
+                None => Claim::Skipped,
+            };
+        }
+        if options.force {
+            group.pending.entry(id).or_insert_with(|| PendingEntry {
+                consumer: consumer.to_string(),
+                delivered_ms: now_ms,
+                delivery_count: 0,
+            });
+        }
+        let Some(entry) = group.pending.get_mut(&id) else {
+            return Claim::Skipped;
+        };
+        if now_ms.saturating_sub(entry.delivered_ms) < options.min_idle_ms {
+            return Claim::Skipped;
+        }
+
+        entry.consumer = consumer.to_string();
+        entry.delivered_ms = options.delivered_ms;
+        match options.retry_count {
+            Some(count) => entry.delivery_count = count,
+            None if !options.just_id => entry.delivery_count += 1,
+            None => {}
+        }
+        group.touch(consumer, now_ms);
+        Claim::Claimed
+    }
+
+    /// Entries with IDs in `start..=end`, in ascending order.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl DoubleEndedIterator<Item = (&StreamId, &Fields)> {
+        // BTreeMap::range panics on inverted bounds, so those yield nothing.
+        self.entries
+            .range(start..=end.max(start))
+            .filter(move |_| start <= end)
+    }
+
+    /// Writes the stream out with its consumer groups, for `decode` to read
+    /// back.
+    pub(super) fn encode(&self, out: &mut Encoder) {
+        let id = |out: &mut Encoder, id: StreamId| {
+            out.u64(id.ms);
+            out.u64(id.seq);
+        };
+        id(out, self.last_id);
+        out.u64(self.entries.len() as u64);
+        for (&entry, fields) in &self.entries {
+            id(out, entry);
+            out.u64(fields.len() as u64);
+            for (field, value) in fields {
+                out.str(field);
+                out.str(value);
+            }
+        }
+        out.u64(self.groups.len() as u64);
+        for (name, group) in &self.groups {
+            out.str(name);
+            id(out, group.last_delivered);
+            out.u64(group.pending.len() as u64);
+            for (&entry, pending) in &group.pending {
+                id(out, entry);
+                out.str(&pending.consumer);
+                out.u64(pending.delivered_ms);
+                out.u64(pending.delivery_count);
+            }
+            out.u64(group.consumers.len() as u64);
+            for (consumer, seen_ms) in &group.consumers {
+                out.str(consumer);
+                out.u64(*seen_ms);
+            }
+        }
+    }
+
+    #[cfg(feature = "disk")]
+    pub(super) fn decode(input: &mut Decoder) -> Option<Stream> {
+        let id = |input: &mut Decoder| {
+            Some(StreamId {
+                ms: input.u64()?,
+                seq: input.u64()?,
+            })
+        };
+        let mut stream = Stream {
+            last_id: id(input)?,
+            ..Stream::default()
+        };
+        for _ in 0..input.len()? {
+            let entry = id(input)?;
+            let fields = (0..input.len()?)
+                .map(|_| Some((input.string()?, input.string()?)))
+                .collect::<Option<_>>()?;
+            stream.entries.insert(entry, fields);
+        }
+        for _ in 0..input.len()? {
+            let name = input.string()?;
+            let mut group = ConsumerGroup::new(id(input)?);
+            for _ in 0..input.len()? {
+                let entry = id(input)?;
+                let pending = PendingEntry {
+                    consumer: input.string()?,
+                    delivered_ms: input.u64()?,
+                    delivery_count: input.u64()?,
+                };
+                group.pending.insert(entry, pending);
+            }
+            for _ in 0..input.len()? {
+                let consumer = input.string()?;
+                group.consumers.insert(consumer, input.u64()?);
+            }
+            stream.groups.insert(name, group);
+        }
+        Some(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    #[test]
+    fn should_parse_ids() {
+        assert_eq!(StreamId::parse("5-3", 0), Some(id(5, 3)));
+        assert_eq!(StreamId::parse("5", u64::MAX), Some(id(5, u64::MAX)));
+        assert_eq!(StreamId::parse("5-x", 0), None);
+        assert_eq!(id(5, 3).to_string(), "5-3");
+    }
+
+    #[test]
+    fn should_generate_increasing_ids() {
+        let mut stream = Stream::new();
+        assert_eq!(stream.next_id(NewId::Explicit(id(0, 0)), 0), None);
+        assert_eq!(stream.next_id(NewId::AutoSeq(0), 0), Some(id(0, 1)));
+
+        stream.add(id(10, 0), vec![]);
+        assert_eq!(stream.next_id(NewId::Auto, 5), Some(id(10, 1)));
+        assert_eq!(stream.next_id(NewId::Auto, 20), Some(id(20, 0)));
+        assert_eq!(stream.next_id(NewId::AutoSeq(10), 0), Some(id(10, 1)));
+        assert_eq!(stream.next_id(NewId::AutoSeq(9), 0), None);
+        assert_eq!(stream.next_id(NewId::Explicit(id(10, 0)), 0), None);
+    }
+
+    #[test]
+    fn should_trim() {
+        let mut stream = Stream::new();
+        for ms in 1..=5 {
+            stream.add(id(ms, 0), vec![]);
+        }
+
+        assert_eq!(stream.trim_max_len(3, None), 2);
+        assert_eq!(stream.trim_min_id(id(5, 0), Some(1)), 1);
+        assert_eq!(stream.len(), 2);
+        assert_eq!(stream.last_id(), id(5, 0));
+    }
+
+    #[test]
+    fn should_range_inclusively() {
+        let mut stream = Stream::new();
+        for ms in 1..=5 {
+            stream.add(id(ms, 0), vec![("f".to_string(), ms.to_string())]);
+        }
+
+        let ids: Vec<u64> = stream
+            .range(id(2, 0), id(4, 0))
+            .map(|(id, _)| id.ms)
+            .collect();
+        assert_eq!(ids, vec![2, 3, 4]);
+        assert_eq!(stream.range(id(4, 0), id(2, 0)).count(), 0);
+    }
+
+    #[test]
+    fn should_track_deliveries_per_group() {
+        let mut stream = Stream::new();
+        for ms in 1..=3 {
+            stream.add(id(ms, 0), vec![]);
+        }
+        assert!(stream.create_group("g", id(1, 0)));
+        assert!(!stream.create_group("g", StreamId::MIN));
+
+        let read = stream.read_group("g", "alice", Some(1), false, 100);
+        assert_eq!(read, vec![(id(2, 0), vec![])]);
+        let read = stream.read_group("g", "bob", None, false, 100);
+        assert_eq!(read, vec![(id(3, 0), vec![])]);
+        assert!(stream.read_group("g", "bob", None, false, 100).is_empty());
+
+        let pending = stream.read_pending("g", "alice", StreamId::MIN, None, 100);
+        assert_eq!(pending, vec![(id(2, 0), Some(vec![]))]);
+        let group = stream.group_mut("g").unwrap();
+        assert!(group.ack(id(2, 0)));
+        assert!(!group.ack(id(2, 0)));
+        assert_eq!(group.delete_consumer("bob"), Some(1));
+        assert!(group.pending().is_empty());
+    }
+
+    #[test]
+    fn should_claim_idle_entries() {
+        let mut stream = Stream::new();
+        stream.add(id(1, 0), vec![]);
+        stream.add(id(2, 0), vec![]);
+        stream.create_group("g", StreamId::MIN);
+        stream.read_group("g", "alice", None, false, 100);
+        stream.trim_max_len(1, None);
+
+        let options = ClaimOptions {
+            min_idle_ms: 50,
+            force: false,
+            delivered_ms: 120,
+            retry_count: None,
+            just_id: false,
+        };
+        assert_eq!(
+            stream.claim("g", "bob", id(2, 0), &options, 120),
+            Claim::Skipped
+        );
+        assert_eq!(
+            stream.claim("g", "bob", id(2, 0), &options, 150),
+            Claim::Claimed
+        );
+        assert_eq!(
+            stream.claim("g", "bob", id(1, 0), &options, 150),
+            Claim::Deleted
+        );
+
+        let pending = &stream.group("g").unwrap().pending()[&id(2, 0)];
+        assert_eq!(pending.consumer, "bob");
+        assert_eq!(pending.delivery_count, 2);
+        assert_eq!(stream.group("g").unwrap().pending().len(), 1);
+    }
+}
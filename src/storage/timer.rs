@@ -0,0 +1,89 @@
+//! Wakes whoever deletes expired keys when the earliest deadline passes, so
+//! keys go close to when they expire rather than whenever sampling finds
+//! them.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tokio::{sync::Notify, time::sleep};
+
+use super::{unix_time_ms, Expiry};
+
+#[derive(Debug)]
+pub struct Timer {
+    /// The deadline being waited for, in milliseconds since the epoch,
+    /// `u64::MAX` if none.
+    next: AtomicU64,
+    sooner: Notify,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer {
+            next: AtomicU64::new(u64::MAX),
+            sooner: Notify::new(),
+        }
+    }
+}
+
+impl Timer {
+    /// Tells the waiter a key now expires at `expiry`, waking it if that is
+    /// sooner than what it waits for.
+    pub fn schedule(&self, expiry: Expiry) {
+        if self.next.fetch_min(expiry.unix_ms(), Ordering::Relaxed) > expiry.unix_ms() {
+            self.sooner.notify_one();
+        }
+    }
+
+    /// Forgets the deadline waited for, before looking for the next one.
+    /// Whatever is scheduled from then on still counts.
+    pub fn reset(&self) {
+        self.next.store(u64::MAX, Ordering::Relaxed);
+    }
+
+    /// Waits until `next`, or an earlier deadline scheduled since `reset`,
+    /// has passed, or until a sooner one is scheduled. A deadline scheduled
+    /// while nobody waited may end the next wait early, so callers look for
+    /// what is due again each time.
+    pub async fn wait(&self, next: Option<Expiry>) {
+        let next = next.map_or(u64::MAX, Expiry::unix_ms);
+        let next = self.next.fetch_min(next, Ordering::Relaxed).min(next);
+        if next == u64::MAX {
+            self.sooner.notified().await;
+            return;
+        }
+        // A deadline has passed once the clock is beyond it.
+        let left = (next + 1).saturating_sub(unix_time_ms());
+        tokio::select! {
+            _ = sleep(Duration::from_millis(left)) => {}
+            _ = self.sooner.notified() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Instant};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn should_wake_for_sooner_deadlines() {
+        let timer = Arc::new(Timer::default());
+        let started = Instant::now();
+        let waiter = Arc::clone(&timer);
+        let waited = tokio::spawn(async move {
+            waiter.wait(Expiry::after(Duration::from_secs(60))).await;
+            waiter.reset();
+            waiter.wait(Expiry::after(Duration::from_millis(30))).await;
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        timer.schedule(Expiry::after(Duration::from_millis(10)).unwrap());
+        waited.await.unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(40));
+        assert!(elapsed < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,426 @@
+use std::{
+    cmp,
+    collections::{btree_set, BTreeSet, HashMap},
+    slice,
+    sync::atomic::Ordering,
+};
+
+use super::COMPACT;
+
+/// Score wrapper giving `f64` the total order sorted sets rely on. NaN is
+/// rejected before it ever reaches a set.
+#[derive(Clone, Copy, Debug)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// One end of a score range, as written `1.5`, `(1.5`, `-inf` or `+inf`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScoreBound {
+    pub value: f64,
+    pub exclusive: bool,
+}
+
+/// One end of a lexicographic range, as written `[a`, `(a`, `-` or `+`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LexBound {
+    Min,
+    Max,
+    Inclusive(String),
+    Exclusive(String),
+}
+
+impl LexBound {
+    fn admits_from_below(&self, member: &str) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(bound) => member >= bound.as_str(),
+            LexBound::Exclusive(bound) => member > bound.as_str(),
+        }
+    }
+
+    fn admits_from_above(&self, member: &str) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member <= bound.as_str(),
+            LexBound::Exclusive(bound) => member < bound.as_str(),
+        }
+    }
+}
+
+/// How a sorted set keeps its members. Small ones are packed in order and
+/// searched by walking them, turning into a map and a tree for good once
+/// they outgrow the limits in `COMPACT`, as on Redis.
+#[derive(Clone, Debug)]
+enum Members {
+    Packed(Vec<(Score, String)>),
+    Table {
+        scores: HashMap<String, f64>,
+        ordered: BTreeSet<(Score, String)>,
+    },
+}
+
+/// Members ordered by score, ties broken lexicographically, with O(1) score
+/// lookup by member once large.
+#[derive(Clone, Debug)]
+pub struct SortedSet(Members);
+
+impl Default for SortedSet {
+    fn default() -> Self {
+        SortedSet(Members::Packed(Vec::new()))
+    }
+}
+
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+/// Members of a sorted set in order, however it keeps them.
+enum Iter<'a> {
+    Packed(slice::Iter<'a, (Score, String)>),
+    Table(btree_set::Range<'a, (Score, String)>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (score, member) = match self {
+            Iter::Packed(iter) => iter.next()?,
+            Iter::Table(iter) => iter.next()?,
+        };
+        Some((member.as_str(), score.0))
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (score, member) = match self {
+            Iter::Packed(iter) => iter.next_back()?,
+            Iter::Table(iter) => iter.next_back()?,
+        };
+        Some((member.as_str(), score.0))
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.0 {
+            Members::Packed(members) => members.len(),
+            Members::Table { scores, .. } => scores.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Estimated bytes taken, each member of a large set being kept twice.
+    pub fn memory_usage(&self) -> usize {
+        match &self.0 {
+            Members::Packed(members) => members.iter().map(|(_, m)| m.len() + 10).sum(),
+            Members::Table { scores, .. } => scores.keys().map(|m| 2 * m.len() + 64).sum(),
+        }
+    }
+
+    /// The name OBJECT ENCODING gives the way members are kept.
+    pub fn encoding(&self) -> &'static str {
+        match &self.0 {
+            Members::Packed(_) => "listpack",
+            Members::Table { .. } => "skiplist",
+        }
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        match &self.0 {
+            Members::Packed(members) => members
+                .iter()
+                .find(|(_, m)| m == member)
+                .map(|(score, _)| score.0),
+            Members::Table { scores, .. } => scores.get(member).copied(),
+        }
+    }
+
+    /// Sets the score of `member`, returning its previous score if it was
+    /// already present.
+    pub fn insert(&mut self, member: String, score: f64) -> Option<f64> {
+        // Adding zero folds -0.0 into 0.0 so both sort as the same score.
+        let score = score + 0.0;
+        let previous = self.remove(&member);
+        let fits = self.len() < COMPACT.zset_max_listpack_entries.load(Ordering::Relaxed)
+            && member.len() <= COMPACT.zset_max_listpack_value.load(Ordering::Relaxed);
+        if let Members::Packed(members) = &mut self.0 {
+            if !fits {
+                let ordered: BTreeSet<_> = members.drain(..).collect();
+                let scores = ordered.iter().map(|(s, m)| (m.clone(), s.0)).collect();
+                self.0 = Members::Table { scores, ordered };
+            }
+        }
+        let entry = (Score(score), member);
+        match &mut self.0 {
+            Members::Packed(members) => {
+                let at = members.partition_point(|other| *other < entry);
+                members.insert(at, entry);
+            }
+            Members::Table { scores, ordered } => {
+                scores.insert(entry.1.clone(), score);
+                ordered.insert(entry);
+            }
+        }
+        previous
+    }
+
+    pub fn remove(&mut self, member: &str) -> Option<f64> {
+        match &mut self.0 {
+            Members::Packed(members) => {
+                let at = members.iter().position(|(_, m)| m == member)?;
+                Some(members.remove(at).0 .0)
+            }
+            Members::Table { scores, ordered } => {
+                let score = scores.remove(member)?;
+                ordered.remove(&(Score(score), member.to_string()));
+                Some(score)
+            }
+        }
+    }
+
+    /// Removes and returns the member with the lowest score.
+    pub fn pop_min(&mut self) -> Option<(String, f64)> {
+        let (score, member) = match &mut self.0 {
+            Members::Packed(members) if members.is_empty() => return None,
+            Members::Packed(members) => members.remove(0),
+            Members::Table { scores, ordered } => {
+                let (score, member) = ordered.pop_first()?;
+                scores.remove(&member);
+                (score, member)
+            }
+        };
+        Some((member, score.0))
+    }
+
+    /// Removes and returns the member with the highest score.
+    pub fn pop_max(&mut self) -> Option<(String, f64)> {
+        let (score, member) = match &mut self.0 {
+            Members::Packed(members) => members.pop()?,
+            Members::Table { scores, ordered } => {
+                let (score, member) = ordered.pop_last()?;
+                scores.remove(&member);
+                (score, member)
+            }
+        };
+        Some((member, score.0))
+    }
+
+    /// Zero-based position of `member` in ascending score order.
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        match &self.0 {
+            Members::Packed(members) => members.iter().position(|(_, m)| m == member),
+            Members::Table { scores, ordered } => {
+                let score = *scores.get(member)?;
+                Some(ordered.range(..(Score(score), member.to_string())).count())
+            }
+        }
+    }
+
+    /// Members whose score lies between `min` and `max`, in ascending order.
+    pub fn range_by_score(&self, min: ScoreBound, max: ScoreBound) -> Vec<(&str, f64)> {
+        let from = (Score(min.value), String::new());
+        let iter = match &self.0 {
+            Members::Packed(members) => {
+                let at = members.partition_point(|entry| *entry < from);
+                Iter::Packed(members[at..].iter())
+            }
+            Members::Table { ordered, .. } => Iter::Table(ordered.range(from..)),
+        };
+        iter.skip_while(|(_, score)| min.exclusive && *score == min.value)
+            .take_while(|(_, score)| *score < max.value || (!max.exclusive && *score == max.value))
+            .collect()
+    }
+
+    /// Members between `min` and `max` in lexicographic order. Like Redis,
+    /// this assumes every member shares the same score.
+    pub fn range_by_lex(&self, min: &LexBound, max: &LexBound) -> Vec<(&str, f64)> {
+        self.iter()
+            .skip_while(|(member, _)| !min.admits_from_below(member))
+            .take_while(|(member, _)| max.admits_from_above(member))
+            .collect()
+    }
+
+    /// Iterates members from the lowest to the highest score.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&str, f64)> {
+        match &self.0 {
+            Members::Packed(members) => Iter::Packed(members.iter()),
+            Members::Table { ordered, .. } => Iter::Table(ordered.range(..)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_order_by_score_then_member() {
+        let mut zset = SortedSet::new();
+        zset.insert("b".to_string(), 1.0);
+        zset.insert("a".to_string(), 1.0);
+        zset.insert("c".to_string(), 0.5);
+
+        let members: Vec<&str> = zset.iter().map(|(member, _)| member).collect();
+        assert_eq!(members, vec!["c", "a", "b"]);
+    }
+
+    #[test]
+    fn should_reorder_on_score_update() {
+        let mut zset = SortedSet::new();
+        zset.insert("a".to_string(), 1.0);
+        zset.insert("b".to_string(), 2.0);
+
+        assert_eq!(zset.insert("a".to_string(), 3.0), Some(1.0));
+        assert_eq!(zset.len(), 2);
+        let members: Vec<&str> = zset.iter().map(|(member, _)| member).collect();
+        assert_eq!(members, vec!["b", "a"]);
+    }
+
+    #[test]
+    fn should_rank_members() {
+        let mut zset = SortedSet::new();
+        zset.insert("a".to_string(), 3.0);
+        zset.insert("b".to_string(), 1.0);
+        zset.insert("c".to_string(), 2.0);
+
+        assert_eq!(zset.rank("b"), Some(0));
+        assert_eq!(zset.rank("a"), Some(2));
+        assert_eq!(zset.rank("missing"), None);
+    }
+
+    #[test]
+    fn should_range_by_score() {
+        let mut zset = SortedSet::new();
+        for (member, score) in [("a", 1.0), ("b", 2.0), ("c", 2.0), ("d", 3.0)] {
+            zset.insert(member.to_string(), score);
+        }
+
+        let inclusive = |value| ScoreBound {
+            value,
+            exclusive: false,
+        };
+        let exclusive = |value| ScoreBound {
+            value,
+            exclusive: true,
+        };
+        let members = |range: Vec<(&str, f64)>| -> Vec<String> {
+            range.into_iter().map(|(m, _)| m.to_string()).collect()
+        };
+
+        assert_eq!(
+            members(zset.range_by_score(inclusive(2.0), inclusive(3.0))),
+            vec!["b", "c", "d"]
+        );
+        assert_eq!(
+            members(zset.range_by_score(exclusive(1.0), exclusive(3.0))),
+            vec!["b", "c"]
+        );
+        assert_eq!(
+            members(zset.range_by_score(inclusive(f64::NEG_INFINITY), exclusive(2.0))),
+            vec!["a"]
+        );
+        assert!(zset
+            .range_by_score(inclusive(3.0), inclusive(1.0))
+            .is_empty());
+    }
+
+    #[test]
+    fn should_range_by_lex() {
+        let mut zset = SortedSet::new();
+        for member in ["a", "b", "c", "d"] {
+            zset.insert(member.to_string(), 0.0);
+        }
+
+        let members = |range: Vec<(&str, f64)>| -> Vec<String> {
+            range.into_iter().map(|(m, _)| m.to_string()).collect()
+        };
+
+        assert_eq!(
+            members(zset.range_by_lex(&LexBound::Min, &LexBound::Exclusive("c".into()))),
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            members(zset.range_by_lex(&LexBound::Inclusive("b".into()), &LexBound::Max)),
+            vec!["b", "c", "d"]
+        );
+        assert!(zset.range_by_lex(&LexBound::Max, &LexBound::Min).is_empty());
+    }
+
+    #[test]
+    fn should_pop_extremes() {
+        let mut zset = SortedSet::new();
+        zset.insert("a".to_string(), 1.0);
+        zset.insert("b".to_string(), 2.0);
+        zset.insert("c".to_string(), 3.0);
+
+        assert_eq!(zset.pop_min(), Some(("a".to_string(), 1.0)));
+        assert_eq!(zset.pop_max(), Some(("c".to_string(), 3.0)));
+        assert_eq!(zset.len(), 1);
+        assert_eq!(zset.score("b"), Some(2.0));
+    }
+
+    #[test]
+    fn should_remove_member() {
+        let mut zset = SortedSet::new();
+        zset.insert("a".to_string(), -0.0);
+
+        assert_eq!(zset.remove("a"), Some(0.0));
+        assert_eq!(zset.remove("a"), None);
+        assert!(zset.is_empty());
+        assert_eq!(zset.iter().count(), 0);
+    }
+
+    #[test]
+    fn should_outgrow_packed_encoding() {
+        let mut zset = SortedSet::new();
+        for score in (0..128).rev() {
+            zset.insert(format!("m{}", score), score as f64);
+        }
+        assert_eq!(zset.encoding(), "listpack");
+        let packed = zset.clone();
+        assert_eq!(zset.rank("m5"), Some(5));
+
+        zset.insert("m128".to_string(), 128.0);
+        assert_eq!(zset.encoding(), "skiplist");
+        assert_eq!(zset.remove("m128"), Some(128.0));
+        assert_eq!(zset, packed);
+        assert_eq!(zset.rank("m5"), Some(5));
+        assert!(packed.memory_usage() < zset.memory_usage());
+
+        let mut long = SortedSet::new();
+        long.insert("m".repeat(65), 0.0);
+        assert_eq!(long.encoding(), "skiplist");
+    }
+}
@@ -0,0 +1,272 @@
+//! Debugging tap on a master's replication stream.
+//!
+//! Connects like a replica would, asks for a full resynchronization, skips
+//! the RDB snapshot and then prints every command the master propagates
+//! together with its replication offset and timing.
+
+use std::{
+    io::{self, Write},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    time::{interval_at, Instant},
+};
+use tokio_util::codec::Decoder;
+
+use crate::{codec::RespCodec, resp::Entry};
+
+/// How often the offset is acknowledged, as real replicas do, so the master
+/// does not time the tap out.
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub(crate) fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Taps the master at `addr`, writing one line per replicated command to
+/// `out` until the master closes the connection.
+pub async fn run(addr: &str, out: &mut impl Write) -> io::Result<()> {
+    let (reader, mut writer) = TcpStream::connect(addr).await?.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let (replid, mut offset) = request_full_resync(&mut reader, &mut writer, None).await?;
+    writeln!(out, "# full resync with {} at offset {}", replid, offset)?;
+
+    let snapshot_len = skip_snapshot(&mut reader).await?;
+    writeln!(out, "# skipped {} byte RDB snapshot", snapshot_len)?;
+
+    let started = Instant::now();
+    let mut previous = started;
+    let mut buffer = BytesMut::with_capacity(16 * 1024);
+    let mut acks = interval_at(started + ACK_INTERVAL, ACK_INTERVAL);
+    let mut codec = RespCodec::default();
+    loop {
+        loop {
+            // Arguments are taken from the buffer as they arrive.
+            let buffered = buffer.len();
+            let decoded = codec
+                .decode(&mut buffer)
+                .map_err(|err| protocol_error(err.to_string()))?;
+            offset += (buffered - buffer.len()) as u64;
+            let Some(command) = decoded else {
+                break;
+            };
+
+            let now = Instant::now();
+            writeln!(
+                out,
+                "{:>12} {:>10.3}s {:>+9.3}ms {}",
+                offset,
+                (now - started).as_secs_f64(),
+                (now - previous).as_secs_f64() * 1000.0,
+                describe(&command)
+            )?;
+            previous = now;
+        }
+        out.flush()?;
+
+        tokio::select! {
+            read = reader.read_buf(&mut buffer) => if read? == 0 {
+                return Ok(());
+            },
+            _ = acks.tick() => {
+                let acked = offset.to_string();
+                send(&mut writer, &["REPLCONF", "ACK", &acked]).await?;
+            }
+        }
+    }
+}
+
+/// Renders a propagated command with each argument quoted, like MONITOR.
+fn describe(command: &[Entry]) -> String {
+    command
+        .iter()
+        .map(|entry| match entry {
+            Entry::Text(text) | Entry::SimpleText(text) => format!("{:?}", text),
+            other => other.to_string().trim_end().to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Asks the master for a full resynchronization, as a replica listening on
+/// `listening_port` if there is one, and returns the replication ID and
+/// offset the master starts from. The snapshot follows.
+pub(crate) async fn request_full_resync(
+    reader: &mut BufReader<impl AsyncReadExt + Unpin>,
+    writer: &mut OwnedWriteHalf,
+    listening_port: Option<u16>,
+) -> io::Result<(String, u64)> {
+    match request_sync(reader, writer, listening_port, None).await? {
+        Resync::Full(replid, offset) => Ok((replid, offset)),
+        Resync::Partial => Err(protocol_error(
+            "PSYNC continued a stream never started".to_string(),
+        )),
+    }
+}
+
+/// How the master answered PSYNC.
+pub(crate) enum Resync {
+    /// The snapshot follows, taken at this replication ID and offset.
+    Full(String, u64),
+    /// The stream goes on from where it was asked to.
+    Partial,
+}
+
+/// Asks the master to be synchronized, going on with the stream of the
+/// replication ID given from the offset given if possible.
+pub(crate) async fn request_sync(
+    reader: &mut BufReader<impl AsyncReadExt + Unpin>,
+    writer: &mut OwnedWriteHalf,
+    listening_port: Option<u16>,
+    from: Option<(&str, u64)>,
+) -> io::Result<Resync> {
+    send(writer, &["PING"]).await?;
+    expect_ok(reader, "PING").await?;
+    if let Some(port) = listening_port {
+        send(writer, &["REPLCONF", "listening-port", &port.to_string()]).await?;
+        expect_ok(reader, "REPLCONF").await?;
+    }
+    send(writer, &["REPLCONF", "capa", "psync2"]).await?;
+    expect_ok(reader, "REPLCONF").await?;
+    match from {
+        Some((replid, offset)) => send(writer, &["PSYNC", replid, &offset.to_string()]).await?,
+        None => send(writer, &["PSYNC", "?", "-1"]).await?,
+    }
+
+    let reply = read_line(reader).await?;
+    match reply.split(' ').collect::<Vec<_>>().as_slice() {
+        ["+FULLRESYNC", replid, offset] => {
+            let offset = offset
+                .parse::<u64>()
+                .map_err(|_| protocol_error(format!("bad offset in {:?}", reply)))?;
+            Ok(Resync::Full(replid.to_string(), offset))
+        }
+        ["+CONTINUE", ..] => Ok(Resync::Partial),
+        _ => Err(protocol_error(format!("PSYNC refused: {:?}", reply))),
+    }
+}
+
+pub(crate) async fn send(writer: &mut OwnedWriteHalf, args: &[&str]) -> io::Result<()> {
+    let mut request = format!("*{}\r\n", args.len());
+    for arg in args {
+        request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    writer.write_all(request.as_bytes()).await
+}
+
+async fn read_line(reader: &mut BufReader<impl AsyncReadExt + Unpin>) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Accepts any status reply to `command`, rejecting errors.
+async fn expect_ok(
+    reader: &mut BufReader<impl AsyncReadExt + Unpin>,
+    command: &str,
+) -> io::Result<()> {
+    let reply = read_line(reader).await?;
+    if reply.starts_with('+') {
+        Ok(())
+    } else {
+        Err(protocol_error(format!("{} failed: {:?}", command, reply)))
+    }
+}
+
+/// Reads the `$<len>` header of the snapshot the master sends after
+/// FULLRESYNC and returns its length. Empty lines are keepalives sent while
+/// it is dumped.
+pub(crate) async fn snapshot_len(
+    reader: &mut BufReader<impl AsyncReadExt + Unpin>,
+) -> io::Result<u64> {
+    let header = loop {
+        let line = read_line(reader).await?;
+        if !line.is_empty() {
+            break line;
+        }
+    };
+    header
+        .strip_prefix('$')
+        .and_then(|len| len.parse::<u64>().ok())
+        .ok_or_else(|| protocol_error(format!("expected RDB snapshot, got {:?}", header)))
+}
+
+/// Reads past the snapshot the master sends after FULLRESYNC and returns
+/// its length.
+async fn skip_snapshot(reader: &mut BufReader<impl AsyncReadExt + Unpin>) -> io::Result<u64> {
+    let len = snapshot_len(reader).await?;
+    let skipped = tokio::io::copy(&mut reader.take(len), &mut tokio::io::sink()).await?;
+    if skipped < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn read_request(stream: &mut BufReader<TcpStream>) -> Vec<String> {
+        let count = read_line(stream).await.unwrap()[1..].parse().unwrap();
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            read_line(stream).await.unwrap();
+            args.push(read_line(stream).await.unwrap());
+        }
+        args
+    }
+
+    #[tokio::test]
+    async fn should_print_replicated_commands() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let master = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            for (expected, reply) in [
+                ("PING", "+PONG\r\n"),
+                ("REPLCONF", "+OK\r\n"),
+                (
+                    "PSYNC",
+                    "+FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 100\r\n",
+                ),
+            ] {
+                assert_eq!(read_request(&mut stream).await[0], expected);
+                stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+            stream
+                .get_mut()
+                .write_all(
+                    b"\n$5\r\nREDIS*1\r\n$4\r\nPING\r\n\
+                      *3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n",
+                )
+                .await
+                .unwrap();
+        });
+
+        let mut out = Vec::new();
+        run(&addr, &mut out).await.unwrap();
+        master.await.unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            "# full resync with 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb at offset 100"
+        );
+        assert_eq!(lines[1], "# skipped 5 byte RDB snapshot");
+        assert!(lines[2].trim_start().starts_with("114 "));
+        assert!(lines[2].ends_with("\"PING\""));
+        assert!(lines[3].trim_start().starts_with("147 "));
+        assert!(lines[3].ends_with("\"SET\" \"key\" \"value\""));
+    }
+}
@@ -0,0 +1,31 @@
+//! TLS termination for clients connecting on the TLS port.
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    sync::Arc,
+};
+
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Builds an acceptor presenting the PEM encoded certificate chain in
+/// `cert_file` with the private key in `key_file`.
+pub fn acceptor(cert_file: &str, key_file: &str) -> io::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_file)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate found in {}", cert_file)));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_file)?))?
+        .ok_or_else(|| invalid(format!("no private key found in {}", key_file)))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| invalid(err.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,210 @@
+//! Client side caching: clients turning tracking on with CLIENT TRACKING
+//! are told when keys they read change, for them to drop what they cached
+//! of those. Each key read is remembered until it changes, and is then
+//! forgotten until read again. Broadcasting clients instead hear of every
+//! key changing that starts with one of their prefixes, read or not.
+//!
+//! Invalidations go to the tracking client itself, as an `invalidate` push
+//! on RESP3, or to the client it redirects them to, which on RESP2 gets
+//! them as messages on `__redis__:invalidate` if subscribed to it.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    client::ClientState,
+    resp::{Entry, Protocol},
+};
+
+/// The channel RESP2 clients hear of invalidations on.
+pub const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// Keys that changed, as a client caching them is told.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invalidation {
+    pub keys: Vec<String>,
+}
+
+impl Invalidation {
+    /// What `client` is sent, nothing if on RESP2 and not subscribed to
+    /// `INVALIDATE_CHANNEL`.
+    pub fn to_entry(&self, client: &ClientState) -> Option<Entry> {
+        let keys = Entry::Array(self.keys.iter().cloned().map(Entry::Text).collect());
+        let text = |text: &str| Entry::Text(text.to_string());
+        match client.protocol {
+            Protocol::Resp3 => Some(Entry::Push(vec![text("invalidate"), keys])),
+            _ if client.subscriptions.contains(INVALIDATE_CHANNEL) => Some(Entry::Push(vec![
+                text("message"),
+                text(INVALIDATE_CHANNEL),
+                keys,
+            ])),
+            _ => None,
+        }
+    }
+}
+
+/// Where the invalidations for a client go.
+pub type Invalidator = mpsc::UnboundedSender<Invalidation>;
+
+/// How a client tracks keys, as told with CLIENT TRACKING ON.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrackingOptions {
+    /// The client invalidations go to instead of this one.
+    pub redirect: Option<u64>,
+    /// Whether every key changing is told of, rather than only those read.
+    pub bcast: bool,
+    /// What keys must start with to be told of when broadcasting, any key
+    /// if none.
+    pub prefixes: Vec<String>,
+    /// Whether keys are only remembered when CLIENT CACHING YES was sent
+    /// right before reading them.
+    pub optin: bool,
+    /// Whether keys are remembered unless CLIENT CACHING NO was sent right
+    /// before reading them.
+    pub optout: bool,
+    /// Whether keys the client changes itself are left out.
+    pub noloop: bool,
+}
+
+impl TrackingOptions {
+    /// Whether the keys a command reads are remembered, `caching` being
+    /// what CLIENT CACHING told right before it.
+    pub fn remembers_reads(&self, caching: Option<bool>) -> bool {
+        match self {
+            TrackingOptions { bcast: true, .. } => false,
+            TrackingOptions { optin: true, .. } => caching == Some(true),
+            TrackingOptions { optout: true, .. } => caching != Some(false),
+            _ => true,
+        }
+    }
+}
+
+/// Every tracking client and the keys each one read.
+pub static TRACKING: Tracking = Tracking {
+    state: Mutex::new(State {
+        trackers: BTreeMap::new(),
+        keys: BTreeMap::new(),
+    }),
+};
+
+pub struct Tracking {
+    state: Mutex<State>,
+}
+
+struct State {
+    trackers: BTreeMap<u64, Tracker>,
+    /// The ids of the clients that read each key since it last changed.
+    /// Clients that stopped tracking are only dropped once the key changes.
+    keys: BTreeMap<String, BTreeSet<u64>>,
+}
+
+struct Tracker {
+    options: TrackingOptions,
+    to: Invalidator,
+}
+
+impl Tracking {
+    /// Has `client` told of keys changing as `options` say, through `to`.
+    /// Keys it read while tracking already are still remembered.
+    pub fn enable(&self, client: u64, options: TrackingOptions, to: Invalidator) {
+        let mut state = self.state.lock().unwrap();
+        state.trackers.insert(client, Tracker { options, to });
+    }
+
+    pub fn disable(&self, client: u64) {
+        self.state.lock().unwrap().trackers.remove(&client);
+    }
+
+    /// Remembers `client` read `keys`, for it to be told once they change.
+    pub fn remember(&self, client: u64, keys: &[impl AsRef<str>]) {
+        let mut state = self.state.lock().unwrap();
+        for key in keys {
+            state
+                .keys
+                .entry(key.as_ref().to_string())
+                .or_default()
+                .insert(client);
+        }
+    }
+
+    /// Tells the clients tracking `keys` they changed, leaving out the one
+    /// that changed them, `by`, if it asked not to be told.
+    pub fn invalidate(&self, keys: &[impl AsRef<str>], by: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        if state.trackers.is_empty() {
+            return;
+        }
+        let mut invalidated: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        for key in keys {
+            let key = key.as_ref();
+            let readers = state.keys.remove(key).unwrap_or_default();
+            let broadcasts = state.trackers.iter().filter(|(_, tracker)| {
+                tracker.options.bcast
+                    && (tracker.options.prefixes.is_empty()
+                        || tracker
+                            .options
+                            .prefixes
+                            .iter()
+                            .any(|prefix| key.starts_with(prefix.as_str())))
+            });
+            let told: BTreeSet<u64> = broadcasts.map(|(id, _)| *id).chain(readers).collect();
+            for id in told {
+                invalidated.entry(id).or_default().push(key.to_string());
+            }
+        }
+        for (id, keys) in invalidated {
+            let Some(tracker) = state.trackers.get(&id) else {
+                continue;
+            };
+            if tracker.options.noloop && by == Some(id) {
+                continue;
+            }
+            // A client gone in the meantime has nothing cached left.
+            let _ = tracker.to.send(Invalidation { keys });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_tell_readers_once_of_keys_changing() {
+        let (to, mut invalidations) = mpsc::unbounded_channel();
+        TRACKING.enable(101, TrackingOptions::default(), to);
+        TRACKING.remember(101, &["tracking:a", "tracking:b"]);
+
+        TRACKING.invalidate(&["tracking:a", "tracking:c"], None);
+        let told = invalidations.try_recv().unwrap();
+        assert_eq!(told.keys, ["tracking:a"]);
+        TRACKING.invalidate(&["tracking:a"], None);
+        assert!(invalidations.try_recv().is_err());
+
+        TRACKING.disable(101);
+        TRACKING.invalidate(&["tracking:b"], None);
+        assert!(invalidations.try_recv().is_err());
+    }
+
+    #[test]
+    fn should_broadcast_keys_with_a_prefix() {
+        let (to, mut invalidations) = mpsc::unbounded_channel();
+        let options = TrackingOptions {
+            bcast: true,
+            prefixes: vec!["bcast:".to_string()],
+            noloop: true,
+            ..TrackingOptions::default()
+        };
+        TRACKING.enable(102, options, to);
+
+        TRACKING.invalidate(&["bcast:a", "other:a"], None);
+        assert_eq!(invalidations.try_recv().unwrap().keys, ["bcast:a"]);
+        TRACKING.invalidate(&["bcast:a"], Some(102));
+        assert!(invalidations.try_recv().is_err());
+        TRACKING.disable(102);
+    }
+}
@@ -0,0 +1,341 @@
+#![cfg(feature = "integration")]
+
+//! Cluster nodes each run as their own process, as cluster state is
+//! server-wide.
+
+use common::{eventually, free_port, Server};
+use redis::{Commands, Connection, Value};
+
+mod common;
+
+fn cluster(con: &mut Connection, subcommand: &str) -> redis::RedisResult<Value> {
+    redis::cmd("CLUSTER").arg(subcommand).query(con)
+}
+
+/// Starts a cluster node with `args` on `port`, with a cluster bus port of
+/// its own: free ports are too high for theirs to be 10000 above them.
+fn start_node(port: u16, args: &[&str]) -> Server {
+    let bus_port = free_port().to_string();
+    let mut args = args.to_vec();
+    args.extend(["--cluster-enabled", "true", "--cluster-port", &bus_port]);
+    Server::start_on(port, &args)
+}
+
+#[test]
+fn should_refuse_cluster_commands_unless_enabled() {
+    let server = Server::start(&[]);
+    let mut con = server.connect();
+    let refused = cluster(&mut con, "INFO").unwrap_err();
+    assert!(
+        refused.to_string().contains("cluster support disabled"),
+        "{}",
+        refused
+    );
+    let info: String = redis::cmd("INFO").arg("cluster").query(&mut con).unwrap();
+    assert_eq!(info, "# Cluster\r\ncluster_enabled:0\r\n");
+}
+
+#[test]
+fn should_describe_a_node_serving_no_slots_yet() {
+    let node = start_node(free_port(), &[]);
+    let mut con = node.connect();
+    let info: String = redis::cmd("INFO").arg("cluster").query(&mut con).unwrap();
+    assert_eq!(info, "# Cluster\r\ncluster_enabled:1\r\n");
+
+    let info: String = redis::cmd("CLUSTER").arg("INFO").query(&mut con).unwrap();
+    assert!(
+        info.starts_with("cluster_state:fail\r\ncluster_slots_assigned:0\r\n"),
+        "{}",
+        info
+    );
+    assert!(info.contains("cluster_known_nodes:1\r\n"), "{}", info);
+
+    let myid: String = redis::cmd("CLUSTER").arg("MYID").query(&mut con).unwrap();
+    assert_eq!(myid.len(), 40);
+    assert!(myid.chars().all(|digit| digit.is_ascii_hexdigit()));
+    let again: String = redis::cmd("CLUSTER").arg("MYID").query(&mut con).unwrap();
+    assert_eq!(again, myid);
+
+    assert_eq!(cluster(&mut con, "SLOTS").unwrap(), Value::Bulk(vec![]));
+    let Value::Bulk(shards) = cluster(&mut con, "SHARDS").unwrap() else {
+        panic!("CLUSTER SHARDS replied with no array");
+    };
+    assert_eq!(shards.len(), 1);
+    let shard = format!("{:?}", shards[0]);
+    assert!(shard.contains(&myid), "{}", shard);
+}
+
+#[test]
+fn should_redirect_requests_for_keys_served_elsewhere() {
+    // "bar" hashes to slot 5061, "foo" to 12182 and "a" to 15495.
+    let peer = "e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca";
+    let node = start_node(
+        free_port(),
+        &[
+            "--cluster-slots",
+            "0-8191",
+            "--cluster-node",
+            &format!("{} 127.0.0.1:7001 8192-12182", peer),
+        ],
+    );
+    let mut con = node.connect();
+    let _: () = con.set("bar", "value").unwrap();
+    let _: () = con.set("{bar}.other", "value").unwrap();
+
+    let moved = con.get::<_, Option<String>>("foo").unwrap_err();
+    assert_eq!(moved.code(), Some("MOVED"));
+    assert!(
+        moved.to_string().contains("12182 127.0.0.1:7001"),
+        "{}",
+        moved
+    );
+    let crossed = redis::cmd("SMOVE")
+        .arg("{bar}.set")
+        .arg("foo")
+        .arg("member")
+        .query::<usize>(&mut con)
+        .unwrap_err();
+    assert_eq!(crossed.code(), Some("CROSSSLOT"));
+    // Shard channels are served where keys of the same name would be.
+    let moved = redis::cmd("SPUBLISH")
+        .arg("foo")
+        .arg("message")
+        .query::<usize>(&mut con)
+        .unwrap_err();
+    assert_eq!(moved.code(), Some("MOVED"));
+    let received: usize = redis::cmd("SPUBLISH")
+        .arg("bar")
+        .arg("message")
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(received, 0);
+    let down = con.get::<_, Option<String>>("a").unwrap_err();
+    assert_eq!(down.code(), Some("CLUSTERDOWN"), "{}", down);
+
+    let slot: u16 = redis::cmd("CLUSTER")
+        .arg("KEYSLOT")
+        .arg("a")
+        .query(&mut con)
+        .unwrap();
+    let _: () = redis::cmd("CLUSTER")
+        .arg("ADDSLOTS")
+        .arg(slot)
+        .query(&mut con)
+        .unwrap();
+    let _: () = con.set("a", "value").unwrap();
+    let busy = redis::cmd("CLUSTER")
+        .arg("ADDSLOTSRANGE")
+        .arg(100)
+        .arg(200)
+        .query::<()>(&mut con)
+        .unwrap_err();
+    assert!(
+        busy.to_string().contains("Slot 100 is already busy"),
+        "{}",
+        busy
+    );
+
+    let info: String = redis::cmd("CLUSTER").arg("INFO").query(&mut con).unwrap();
+    assert!(info.contains("cluster_known_nodes:2\r\n"), "{}", info);
+    assert!(info.contains("cluster_size:2\r\n"), "{}", info);
+}
+
+#[test]
+fn should_move_a_slot_to_another_node() {
+    // "foo" hashes to slot 12182, as do keys tagged with it.
+    let (a, b) = (free_port(), free_port());
+    let (id_a, id_b) = ("a".repeat(40), "b".repeat(40));
+    let source = start_node(
+        a,
+        &[
+            "--cluster-slots",
+            "0-16383",
+            "--cluster-node",
+            &format!("{} 127.0.0.1:{}", id_b, b),
+        ],
+    );
+    let target = start_node(
+        b,
+        &[
+            "--cluster-node",
+            &format!("{} 127.0.0.1:{} 0-16383", id_a, a),
+        ],
+    );
+    let mut to_source = source.connect();
+    let mut to_target = target.connect();
+    let _: () = to_source.set("foo", "value").unwrap();
+    let _: () = to_source.sadd("{foo}.set", &["a", "b"]).unwrap();
+    let setslot = |con: &mut Connection, state: &str, id: &str| -> redis::RedisResult<()> {
+        redis::cmd("CLUSTER")
+            .arg("SETSLOT")
+            .arg(12182)
+            .arg(state)
+            .arg(id)
+            .query(con)
+    };
+    setslot(&mut to_target, "IMPORTING", &id_a).unwrap();
+    setslot(&mut to_source, "MIGRATING", &id_b).unwrap();
+
+    // Keys still there are served, others asked of the target, which only
+    // serves them to clients that asked.
+    let value: Option<String> = to_source.get("foo").unwrap();
+    assert_eq!(value.as_deref(), Some("value"));
+    let ask = format!("12182 127.0.0.1:{}", b);
+    let asked = to_source
+        .get::<_, Option<String>>("{foo}.gone")
+        .unwrap_err();
+    assert_eq!(asked.code(), Some("ASK"));
+    assert!(asked.to_string().contains(&ask), "{}", asked);
+    let moved = to_target.get::<_, Option<String>>("foo").unwrap_err();
+    assert_eq!(moved.code(), Some("MOVED"));
+    let _: () = redis::cmd("ASKING").query(&mut to_target).unwrap();
+    let value: Option<String> = to_target.get("{foo}.gone").unwrap();
+    assert_eq!(value, None);
+
+    let keys: Vec<String> = redis::cmd("CLUSTER")
+        .arg("GETKEYSINSLOT")
+        .arg(12182)
+        .arg(10)
+        .query(&mut to_source)
+        .unwrap();
+    assert_eq!(keys, ["foo", "{foo}.set"]);
+    let migrated: String = redis::cmd("MIGRATE")
+        .arg("127.0.0.1")
+        .arg(b)
+        .arg("")
+        .arg(0)
+        .arg(5000)
+        .arg("KEYS")
+        .arg(&keys)
+        .query(&mut to_source)
+        .unwrap();
+    assert_eq!(migrated, "OK");
+    let asked = to_source.get::<_, Option<String>>("foo").unwrap_err();
+    assert_eq!(asked.code(), Some("ASK"));
+    let count: usize = redis::cmd("CLUSTER")
+        .arg("COUNTKEYSINSLOT")
+        .arg(12182)
+        .query(&mut to_source)
+        .unwrap();
+    assert_eq!(count, 0);
+
+    let myid: String = redis::cmd("CLUSTER")
+        .arg("MYID")
+        .query(&mut to_target)
+        .unwrap();
+    setslot(&mut to_target, "NODE", &myid).unwrap();
+    setslot(&mut to_source, "NODE", &id_b).unwrap();
+    let moved = to_source.get::<_, Option<String>>("foo").unwrap_err();
+    assert_eq!(moved.code(), Some("MOVED"));
+    assert!(moved.to_string().contains(&ask), "{}", moved);
+    let value: Option<String> = to_target.get("foo").unwrap();
+    assert_eq!(value.as_deref(), Some("value"));
+    assert_eq!(to_target.scard::<_, usize>("{foo}.set").unwrap(), 2);
+}
+
+#[test]
+fn should_restore_what_was_dumped() {
+    let server = Server::start(&[]);
+    let mut con = server.connect();
+    let _: () = con.sadd("set", &["a", "b"]).unwrap();
+    let dumped: Vec<u8> = redis::cmd("DUMP").arg("set").query(&mut con).unwrap();
+
+    let restore = |con: &mut Connection, key: &str, options: &[&str]| -> redis::RedisResult<()> {
+        redis::cmd("RESTORE")
+            .arg(key)
+            .arg(0)
+            .arg(&dumped)
+            .arg(options)
+            .query(con)
+    };
+    restore(&mut con, "copy", &[]).unwrap();
+    assert_eq!(con.scard::<_, usize>("copy").unwrap(), 2);
+    let busy = restore(&mut con, "copy", &[]).unwrap_err();
+    assert_eq!(busy.code(), Some("BUSYKEY"));
+    restore(&mut con, "copy", &["REPLACE"]).unwrap();
+
+    let mut corrupt = dumped.clone();
+    corrupt[1] ^= 0xff;
+    let refused = redis::cmd("RESTORE")
+        .arg("other")
+        .arg(0)
+        .arg(corrupt)
+        .query::<()>(&mut con)
+        .unwrap_err();
+    assert!(refused.to_string().contains("checksum"), "{}", refused);
+    let missing: Option<Vec<u8>> = redis::cmd("DUMP").arg("missing").query(&mut con).unwrap();
+    assert_eq!(missing, None);
+}
+
+fn cluster_info(con: &mut Connection) -> String {
+    redis::cmd("CLUSTER").arg("INFO").query(con).unwrap()
+}
+
+#[test]
+fn should_discover_nodes_over_the_cluster_bus() {
+    // "foo" hashes to slot 12182, served by the third node.
+    let ports = [free_port(), free_port(), free_port()];
+    let bus_ports = [free_port(), free_port(), free_port()];
+    let slots = ["0-5460", "5461-10922", "10923-16383"];
+    let mut nodes: Vec<Server> = (0..3)
+        .map(|at| {
+            Server::start_on(
+                ports[at],
+                &[
+                    "--cluster-enabled",
+                    "true",
+                    "--cluster-port",
+                    &bus_ports[at].to_string(),
+                    "--cluster-slots",
+                    slots[at],
+                    "--cluster-node-timeout",
+                    "500",
+                ],
+            )
+        })
+        .collect();
+    let mut cons: Vec<Connection> = nodes.iter().map(Server::connect).collect();
+    let meet = |con: &mut Connection, at: usize| {
+        let _: () = redis::cmd("CLUSTER")
+            .arg("MEET")
+            .arg("127.0.0.1")
+            .arg(ports[at])
+            .arg(bus_ports[at])
+            .query(con)
+            .unwrap();
+    };
+    // The first node only meets the second, which tells it of the third.
+    meet(&mut cons[0], 1);
+    meet(&mut cons[1], 2);
+    for con in &mut cons {
+        eventually(|| {
+            let info = cluster_info(con);
+            info.contains("cluster_state:ok\r\n") && info.contains("cluster_known_nodes:3\r\n")
+        });
+    }
+    let moved = cons[0].get::<_, Option<String>>("foo").unwrap_err();
+    assert_eq!(moved.code(), Some("MOVED"));
+    let to = format!("12182 127.0.0.1:{}", ports[2]);
+    assert!(moved.to_string().contains(&to), "{}", moved);
+
+    let listed: String = redis::cmd("CLUSTER")
+        .arg("NODES")
+        .query(&mut cons[0])
+        .unwrap();
+    assert_eq!(listed.lines().count(), 3, "{}", listed);
+    assert!(listed.contains("myself,master"), "{}", listed);
+    let third = format!("127.0.0.1:{}@{} master", ports[2], bus_ports[2]);
+    assert!(listed.contains(&third), "{}", listed);
+    assert!(listed.contains("10923-16383"), "{}", listed);
+
+    // Nodes that stop answering are taken for failing.
+    drop(nodes.pop());
+    eventually(|| {
+        let listed: String = redis::cmd("CLUSTER")
+            .arg("NODES")
+            .query(&mut cons[0])
+            .unwrap();
+        listed.contains("master,fail?")
+    });
+    assert!(cluster_info(&mut cons[0]).contains("cluster_slots_pfail:5461\r\n"));
+}
@@ -0,0 +1,102 @@
+//! Running the server as its own process, for tests of state that is
+//! server-wide.
+
+#![allow(dead_code)]
+
+use std::{
+    net::{TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use redis::Connection;
+
+/// A server process, killed once dropped.
+pub struct Server {
+    process: Child,
+    pub port: u16,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+impl Server {
+    /// Starts a server with `args` on a free port once it accepts clients.
+    pub fn start(args: &[&str]) -> Server {
+        Server::start_on(free_port(), args)
+    }
+
+    /// Starts a server with `args` on `port` once it accepts clients, for
+    /// servers that must know of each other's ports.
+    pub fn start_on(port: u16, args: &[&str]) -> Server {
+        let process = Command::new(env!("CARGO_BIN_EXE_redis-starter-rust"))
+            .args(["--port", &port.to_string(), "--save", ""])
+            .args(args)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server { process, port };
+        for _ in 0..100 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                return server;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("server never listened on {}", port);
+    }
+
+    pub fn replica_of(master: &Server) -> Server {
+        Server::start(&["--replicaof", &format!("127.0.0.1 {}", master.port)])
+    }
+
+    /// Sends the server SIGHUP.
+    #[cfg(unix)]
+    pub fn hang_up(&self) {
+        let sent = Command::new("kill")
+            .args(["-HUP", &self.process.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(sent.success());
+    }
+
+    /// Sends the server SIGTERM and waits for it to exit.
+    #[cfg(unix)]
+    pub fn terminate(mut self) {
+        let sent = Command::new("kill")
+            .args(["-TERM", &self.process.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(sent.success());
+        self.process.wait().unwrap();
+    }
+
+    pub fn connect(&self) -> Connection {
+        redis::Client::open(format!("redis://127.0.0.1:{}/", self.port))
+            .unwrap()
+            .get_connection()
+            .unwrap()
+    }
+}
+
+/// A port nothing listens on.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Waits for `done` to hold, for up to five seconds.
+pub fn eventually(mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
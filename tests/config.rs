@@ -0,0 +1,145 @@
+#![cfg(feature = "integration")]
+
+//! Servers given a config file run as their own process, for it to be read
+//! on startup like the command line.
+
+use std::fs;
+
+use common::{eventually, Server};
+use redis::{Commands, Connection, RedisResult};
+
+mod common;
+
+fn config_get(con: &mut Connection, patterns: &[&str]) -> Vec<String> {
+    redis::cmd("CONFIG")
+        .arg("GET")
+        .arg(patterns)
+        .query(con)
+        .unwrap()
+}
+
+fn auth(con: &mut Connection, password: &str) -> RedisResult<String> {
+    redis::cmd("AUTH").arg(password).query(con)
+}
+
+#[test]
+fn should_start_from_a_config_file_and_rewrite_it() {
+    let path = std::env::temp_dir().join(format!("resip-{}.conf", std::process::id()));
+    let path_arg = path.to_str().unwrap();
+    fs::write(
+        &path,
+        "# Limits\nmaxmemory 1mb\nrequirepass \"secret\"\n\nappendfsync no\nmaxmemory 2mb\n",
+    )
+    .unwrap();
+    let server = Server::start(&[path_arg]);
+    let mut con = server.connect();
+
+    let refused: RedisResult<Option<String>> = con.get("key");
+    assert!(refused.unwrap_err().to_string().contains("NOAUTH"));
+    let refused = auth(&mut con, "wrong").unwrap_err();
+    assert!(refused.to_string().contains("WRONGPASS"), "{}", refused);
+    assert_eq!(auth(&mut con, "secret").unwrap(), "OK");
+    // Later lines win over earlier ones.
+    assert_eq!(
+        config_get(&mut con, &["maxmemory", "appendfsync", "requirepass"]),
+        [
+            "appendfsync",
+            "no",
+            "maxmemory",
+            "2097152",
+            "requirepass",
+            "secret"
+        ]
+    );
+
+    // Lines are changed where they are, comments kept and parameters the
+    // file didn't set added at its end.
+    let _: () = redis::cmd("CONFIG")
+        .arg(&["SET", "maxmemory", "3mb", "timeout", "30"])
+        .query(&mut con)
+        .unwrap();
+    let _: () = redis::cmd("CONFIG").arg("REWRITE").query(&mut con).unwrap();
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "# Limits\nmaxmemory 3145728\nrequirepass secret\n\nappendfsync no\n\
+         # Generated by CONFIG REWRITE\nsave \"\"\ntimeout 30\n"
+    );
+    drop(server);
+
+    // The command line wins over the file.
+    let server = Server::start(&["--config", path_arg, "--maxmemory", "5mb"]);
+    let mut con = server.connect();
+    assert_eq!(auth(&mut con, "secret").unwrap(), "OK");
+    assert_eq!(
+        config_get(&mut con, &["maxmemory", "timeout"]),
+        ["maxmemory", "5242880", "timeout", "30"]
+    );
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn should_take_flags_and_skip_unknown_directives() {
+    let path = std::env::temp_dir().join(format!("resip-flags-{}.conf", std::process::id()));
+    fs::write(
+        &path,
+        "daemonize no\nreuseport yes\ndashmap no\nbind 127.0.0.1 -::1\n",
+    )
+    .unwrap();
+    let server = Server::start(&[path.to_str().unwrap()]);
+    let mut con = server.connect();
+    let reply: String = redis::cmd("PING").query(&mut con).unwrap();
+    assert_eq!(reply, "PONG");
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn should_reload_the_config_file_on_sighup() {
+    let path = std::env::temp_dir().join(format!("resip-reload-{}.conf", std::process::id()));
+    fs::write(&path, "maxmemory 1mb\ntimeout 10\n").unwrap();
+    let server = Server::start(&[path.to_str().unwrap()]);
+    let mut con = server.connect();
+
+    // Nothing changes unless everything can.
+    fs::write(&path, "timeout 20\nmaxmemory lots\n").unwrap();
+    server.hang_up();
+    fs::write(&path, "maxmemory 2mb\nport 1\n").unwrap();
+    server.hang_up();
+    eventually(|| config_get(&mut con, &["maxmemory"]) == ["maxmemory", "2097152"]);
+    // Left out or only taken on startup, they stay as they are.
+    let port = server.port.to_string();
+    assert_eq!(
+        config_get(&mut con, &["timeout", "port"]),
+        ["port", port.as_str(), "timeout", "10"]
+    );
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn should_write_coalesced_appends_before_exiting_on_sigterm() {
+    let dir = std::env::temp_dir().join(format!("coalesced-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let server = Server::start(&[
+        "--dir",
+        dir.to_str().unwrap(),
+        "--appendonly",
+        "yes",
+        "--aof-coalesce-appends",
+        "yes",
+    ]);
+    let mut con = server.connect();
+    assert_eq!(config_get(&mut con, &["aof-coalesce-appends"])[1], "yes");
+    for piece in ["a", "b", "c"] {
+        let _: usize = con.append("key", piece).unwrap();
+    }
+    server.terminate();
+
+    let written = fs::read(dir.join("appendonly.aof")).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(
+        written.ends_with(b"$3\r\nkey\r\n$3\r\nabc\r\n"),
+        "{:?}",
+        written
+    );
+}
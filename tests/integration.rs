@@ -0,0 +1,1645 @@
+#![cfg(feature = "integration")]
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use redis::{Commands, Connection};
+use redis_starter_rust::{
+    aof::{self, Aof, AppendFsync},
+    resp::Limits,
+    server::{OutputLimit, OutputLimits, Server},
+    stats::STATS,
+    storage::{Data, InMemoryStorage, RdbStorage, Storage, Value},
+    tls,
+};
+use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+/// Starts a server with an empty in-memory dataset on a free port and
+/// returns a client connected to it.
+fn connect() -> Connection {
+    let addr = start_server();
+    connect_to(&addr)
+}
+
+fn connect_to(addr: &str) -> Connection {
+    redis::Client::open(format!("redis://{}/", addr))
+        .unwrap()
+        .get_connection()
+        .unwrap()
+}
+
+/// Reads raw replies from `client` until `done` holds for all read so far.
+fn read_until(client: &mut TcpStream, done: impl Fn(&str) -> bool) -> String {
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut reply = Vec::new();
+    let mut chunk = [0; 1024];
+    while !done(&String::from_utf8_lossy(&reply)) {
+        let n = client.read(&mut chunk).unwrap();
+        assert!(n > 0, "server closed the connection");
+        reply.extend_from_slice(&chunk[..n]);
+    }
+    String::from_utf8(reply).unwrap()
+}
+
+fn start_server() -> String {
+    start_configured_server(|server| server)
+}
+
+/// An address on a port nobody listens on.
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+fn start_configured_server(configure: impl FnOnce(Server) -> Server + Send + 'static) -> String {
+    let addr = free_addr();
+    start_server_on(vec![addr.clone()], configure);
+    addr
+}
+
+/// Starts a server listening on all of `addrs` once it accepts clients on
+/// the first one.
+fn start_server_on(addrs: Vec<String>, configure: impl FnOnce(Server) -> Server + Send + 'static) {
+    let first = addrs[0].clone();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async move {
+            let storage: Arc<dyn Storage> = Arc::new(InMemoryStorage::new());
+            let _ = configure(Server::new(storage)).run(&addrs).await;
+        });
+    });
+
+    for _ in 0..50 {
+        if TcpStream::connect(&first).is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn should_ping_and_echo() {
+    let mut con = connect();
+
+    let pong: String = redis::cmd("PING").query(&mut con).unwrap();
+    assert_eq!(pong, "PONG");
+    let echo: String = redis::cmd("ECHO").arg("hey").query(&mut con).unwrap();
+    assert_eq!(echo, "hey");
+}
+
+#[test]
+fn should_deliver_published_messages_to_subscribers() {
+    let addr = start_server();
+    let mut publisher = connect_to(&addr);
+    let mut subscriber = connect_to(&addr);
+    let mut pubsub = subscriber.as_pubsub();
+    pubsub.subscribe("news").unwrap();
+
+    let received: usize = publisher.publish("news", "hello").unwrap();
+    assert_eq!(received, 1);
+    let message = pubsub.get_message().unwrap();
+    assert_eq!(message.get_channel_name(), "news");
+    assert_eq!(message.get_payload::<String>().unwrap(), "hello");
+    let received: usize = publisher.publish("other", "hello").unwrap();
+    assert_eq!(received, 0);
+
+    pubsub.psubscribe("n*s").unwrap();
+    let received: usize = publisher.publish("news", "again").unwrap();
+    assert_eq!(received, 2);
+    let messages = [pubsub.get_message().unwrap(), pubsub.get_message().unwrap()];
+    let matched = messages
+        .iter()
+        .find(|message| message.from_pattern())
+        .unwrap();
+    assert_eq!(matched.get_pattern::<String>().unwrap(), "n*s");
+    assert_eq!(matched.get_channel_name(), "news");
+
+    let mut introspect = connect_to(&addr);
+    let channels: Vec<String> = redis::cmd("PUBSUB")
+        .arg("CHANNELS")
+        .arg("n*")
+        .query(&mut introspect)
+        .unwrap();
+    assert_eq!(channels, ["news"]);
+    let counts: Vec<(String, usize)> = redis::cmd("PUBSUB")
+        .arg("NUMSUB")
+        .arg("news")
+        .arg("other")
+        .query(&mut introspect)
+        .unwrap();
+    assert_eq!(counts, [("news".to_string(), 1), ("other".to_string(), 0)]);
+    let patterns: usize = redis::cmd("PUBSUB")
+        .arg("NUMPAT")
+        .query(&mut introspect)
+        .unwrap();
+    assert_eq!(patterns, 1);
+
+    pubsub.unsubscribe("news").unwrap();
+    pubsub.punsubscribe("n*s").unwrap();
+    let received: usize = publisher.publish("news", "hello").unwrap();
+    assert_eq!(received, 0);
+}
+
+#[test]
+fn should_only_let_subscribers_manage_subscriptions() {
+    let addr = start_server();
+    let mut client = TcpStream::connect(&addr).unwrap();
+    client
+        .write_all(b"SUBSCRIBE news\r\nGET key\r\nPING\r\n")
+        .unwrap();
+    let replies = read_until(&mut client, |replies| replies.ends_with("$0\r\n\r\n"));
+    assert_eq!(
+        replies,
+        "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n\
+         -ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / RESET are allowed in this context\r\n\
+         *2\r\n$4\r\npong\r\n$0\r\n\r\n"
+    );
+
+    // Subscriptions end with RESET, and with the client.
+    client.write_all(b"RESET\r\nGET key\r\n").unwrap();
+    let replies = read_until(&mut client, |replies| replies.ends_with("$-1\r\n"));
+    assert_eq!(replies, "+RESET\r\n$-1\r\n");
+    let mut publisher = connect_to(&addr);
+    let received: usize = publisher.publish("news", "hello").unwrap();
+    assert_eq!(received, 0);
+}
+
+#[test]
+fn should_authenticate_again_after_reset() {
+    let addr = start_configured_server(|server| server.with_requirepass(Some("pw".into())));
+    let mut client = TcpStream::connect(&addr).unwrap();
+    client
+        .write_all(b"AUTH pw\r\nGET key\r\nRESET\r\nGET key\r\n")
+        .unwrap();
+    let replies = read_until(&mut client, |replies| replies.ends_with("required.\r\n"));
+    assert_eq!(
+        replies,
+        "+OK\r\n$-1\r\n+RESET\r\n-NOAUTH Authentication required.\r\n"
+    );
+}
+
+#[test]
+fn should_deliver_shard_messages_to_shard_subscribers() {
+    let addr = start_server();
+    let mut client = TcpStream::connect(&addr).unwrap();
+    client
+        .write_all(
+            b"SSUBSCRIBE orders
+",
+        )
+        .unwrap();
+    let replies = read_until(&mut client, |replies| replies.ends_with(":1\r\n"));
+    assert_eq!(replies, "*3\r\n$10\r\nssubscribe\r\n$6\r\norders\r\n:1\r\n");
+
+    let mut publisher = connect_to(&addr);
+    let received: usize = publisher.publish("orders", "ignored").unwrap();
+    assert_eq!(received, 0);
+    let received: usize = redis::cmd("SPUBLISH")
+        .arg("orders")
+        .arg("placed")
+        .query(&mut publisher)
+        .unwrap();
+    assert_eq!(received, 1);
+    let replies = read_until(&mut client, |replies| replies.ends_with("placed\r\n"));
+    assert_eq!(
+        replies,
+        "*3\r\n$8\r\nsmessage\r\n$6\r\norders\r\n$6\r\nplaced\r\n"
+    );
+
+    let channels: Vec<String> = redis::cmd("PUBSUB")
+        .arg("SHARDCHANNELS")
+        .query(&mut publisher)
+        .unwrap();
+    assert_eq!(channels, ["orders"]);
+    let channels: Vec<String> = redis::cmd("PUBSUB")
+        .arg("CHANNELS")
+        .arg("orders")
+        .query(&mut publisher)
+        .unwrap();
+    assert!(channels.is_empty());
+}
+
+#[test]
+fn should_invalidate_tracked_keys() {
+    let addr = start_server();
+    let mut writer = connect_to(&addr);
+    let _: () = writer.set("tracked", "before").unwrap();
+
+    // On RESP3 the tracking client itself is pushed invalidations.
+    let mut client = TcpStream::connect(&addr).unwrap();
+    client
+        .write_all(b"HELLO 3\r\nCLIENT TRACKING ON\r\nGET tracked\r\n")
+        .unwrap();
+    read_until(&mut client, |replies| replies.ends_with("$6\r\nbefore\r\n"));
+    let _: () = writer.set("tracked", "after").unwrap();
+    let pushed = read_until(&mut client, |replies| replies.ends_with("tracked\r\n"));
+    assert_eq!(pushed, ">2\r\n$10\r\ninvalidate\r\n*1\r\n$7\r\ntracked\r\n");
+
+    // On RESP2 they are redirected to a client subscribed to them.
+    let mut subscriber = TcpStream::connect(&addr).unwrap();
+    subscriber
+        .write_all(b"CLIENT ID\r\nSUBSCRIBE __redis__:invalidate\r\n")
+        .unwrap();
+    let replies = read_until(&mut subscriber, |replies| replies.ends_with(":1\r\n"));
+    let id = replies.lines().next().unwrap().trim_start_matches(':');
+    let mut tracking = TcpStream::connect(&addr).unwrap();
+    tracking
+        .write_all(format!("CLIENT TRACKING ON REDIRECT {}\r\nGET tracked\r\n", id).as_bytes())
+        .unwrap();
+    let replies = read_until(&mut tracking, |replies| replies.ends_with("after\r\n"));
+    assert_eq!(replies, "+OK\r\n$5\r\nafter\r\n");
+    let _: () = writer.del("tracked").unwrap();
+    let message = read_until(&mut subscriber, |replies| replies.ends_with("tracked\r\n"));
+    assert_eq!(
+        message,
+        "*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*1\r\n$7\r\ntracked\r\n"
+    );
+}
+
+#[test]
+fn should_run_lua_scripts() {
+    let mut con = connect();
+    let set = "redis.call('SET', KEYS[1], ARGV[1]); return redis.call('GET', KEYS[1])";
+    let value: String = redis::cmd("EVAL")
+        .arg(set)
+        .arg(1)
+        .arg("scripted")
+        .arg("value")
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(value, "value");
+    let value: String = con.get("scripted").unwrap();
+    assert_eq!(value, "value");
+
+    // Run once, a script can be run again by its SHA-1.
+    let missing = redis::cmd("EVALSHA")
+        .arg("f2ec1c5d9f3b6a5b7e6e0b3f4f0d8e6c2a7b9c1d")
+        .arg(0)
+        .query::<String>(&mut con)
+        .unwrap_err();
+    assert_eq!(missing.code(), Some("NOSCRIPT"));
+    let get = "return redis.call('GET', KEYS[1])";
+    let _: () = con.sadd("scripted:set", "member").unwrap();
+    let failed = redis::cmd("EVAL")
+        .arg(get)
+        .arg(1)
+        .arg("scripted:set")
+        .query::<String>(&mut con)
+        .unwrap_err();
+    assert_eq!(failed.code(), Some("WRONGTYPE"));
+    let value: String = redis::cmd("EVALSHA")
+        .arg("d3c21d0c2b9ca22f82737626a27bcaf5d288f99f")
+        .arg(1)
+        .arg("scripted")
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(value, "value");
+    let caught: String = redis::cmd("EVAL")
+        .arg("return redis.pcall('GET', KEYS[1])['err']")
+        .arg(1)
+        .arg("scripted:set")
+        .query(&mut con)
+        .unwrap();
+    assert!(caught.starts_with("WRONGTYPE"), "{}", caught);
+    // More keys than arguments is refused rather than looked for.
+    let refused = redis::cmd("EVAL")
+        .arg("return 1")
+        .arg("9223372036854775807")
+        .arg("a")
+        .query::<String>(&mut con)
+        .unwrap_err();
+    assert!(refused.to_string().contains("greater than"), "{}", refused);
+
+    // Scripts can be cached without running them, until flushed.
+    let sha: String = redis::cmd("SCRIPT")
+        .arg("LOAD")
+        .arg(get)
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(sha, "d3c21d0c2b9ca22f82737626a27bcaf5d288f99f");
+    let exists: Vec<i64> = redis::cmd("SCRIPT")
+        .arg("EXISTS")
+        .arg(sha.to_uppercase())
+        .arg("f2ec1c5d9f3b6a5b7e6e0b3f4f0d8e6c2a7b9c1d")
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(exists, [1, 0]);
+    let broken = redis::cmd("SCRIPT")
+        .arg("LOAD")
+        .arg("return (")
+        .query::<String>(&mut con)
+        .unwrap_err();
+    assert!(
+        broken.to_string().contains("Error compiling script"),
+        "{}",
+        broken
+    );
+    let _: () = redis::cmd("SCRIPT")
+        .arg("FLUSH")
+        .arg("ASYNC")
+        .query(&mut con)
+        .unwrap();
+    let exists: Vec<i64> = redis::cmd("SCRIPT")
+        .arg("EXISTS")
+        .arg(&sha)
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(exists, [0]);
+    // Which is all clients need to load scripts when missing.
+    let value: String = redis::Script::new(get)
+        .key("scripted")
+        .invoke(&mut con)
+        .unwrap();
+    assert_eq!(value, "value");
+}
+
+#[test]
+fn should_call_functions_of_loaded_libraries() {
+    let mut con = connect();
+    let code = "#!lua name=testlib\n\
+                redis.register_function('setget', function(keys, args)\n\
+                    redis.call('SET', keys[1], args[1])\n\
+                    return redis.call('GET', keys[1])\n\
+                end)\n\
+                redis.register_function{\n\
+                    function_name = 'peek',\n\
+                    callback = function(keys) return redis.call('SET', keys[1], 'x') end,\n\
+                    flags = {'no-writes'},\n\
+                }";
+    let name: String = redis::cmd("FUNCTION")
+        .arg("LOAD")
+        .arg(code)
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(name, "testlib");
+    let exists = redis::cmd("FUNCTION")
+        .arg("LOAD")
+        .arg(code)
+        .query::<String>(&mut con)
+        .unwrap_err();
+    assert!(exists.to_string().contains("already exists"), "{}", exists);
+
+    let value: String = redis::cmd("FCALL")
+        .arg("setget")
+        .arg(1)
+        .arg("functional")
+        .arg("value")
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(value, "value");
+    let refused = redis::cmd("FCALL_RO")
+        .arg("setget")
+        .arg(1)
+        .arg("functional")
+        .arg("value")
+        .query::<String>(&mut con)
+        .unwrap_err();
+    assert!(refused.to_string().contains("write flag"), "{}", refused);
+    let refused = redis::cmd("FCALL_RO")
+        .arg("peek")
+        .arg(1)
+        .arg("functional")
+        .query::<String>(&mut con)
+        .unwrap_err();
+    assert!(
+        refused.to_string().contains("read-only scripts"),
+        "{}",
+        refused
+    );
+    // More keys than arguments is refused rather than looked for.
+    for (call, function) in [("FCALL", "setget"), ("FCALL_RO", "peek")] {
+        let refused = redis::cmd(call)
+            .arg(function)
+            .arg("9223372036854775807")
+            .arg("functional")
+            .query::<String>(&mut con)
+            .unwrap_err();
+        assert!(refused.to_string().contains("greater than"), "{}", refused);
+    }
+
+    let listed: Vec<Vec<redis::Value>> = redis::cmd("FUNCTION")
+        .arg("LIST")
+        .arg("LIBRARYNAME")
+        .arg("test*")
+        .arg("WITHCODE")
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0][1], redis::Value::Data(b"testlib".to_vec()));
+    assert_eq!(listed[0][7], redis::Value::Data(code.as_bytes().to_vec()));
+
+    // What was dumped is all there is once restored.
+    let dumped: Vec<u8> = redis::cmd("FUNCTION").arg("DUMP").query(&mut con).unwrap();
+    let _: () = redis::cmd("FUNCTION")
+        .arg("DELETE")
+        .arg("testlib")
+        .query(&mut con)
+        .unwrap();
+    let missing = redis::cmd("FCALL")
+        .arg("setget")
+        .arg(0)
+        .query::<String>(&mut con)
+        .unwrap_err();
+    assert!(
+        missing.to_string().contains("Function not found"),
+        "{}",
+        missing
+    );
+    let _: () = redis::cmd("FUNCTION")
+        .arg("RESTORE")
+        .arg(&dumped)
+        .query(&mut con)
+        .unwrap();
+    let conflict = redis::cmd("FUNCTION")
+        .arg("RESTORE")
+        .arg(&dumped)
+        .arg("APPEND")
+        .query::<()>(&mut con)
+        .unwrap_err();
+    assert!(
+        conflict.to_string().contains("already exists"),
+        "{}",
+        conflict
+    );
+    let _: () = redis::cmd("FUNCTION")
+        .arg("RESTORE")
+        .arg(&dumped)
+        .arg("REPLACE")
+        .query(&mut con)
+        .unwrap();
+    let value: String = redis::cmd("FCALL")
+        .arg("setget")
+        .arg(1)
+        .arg("functional")
+        .arg("again")
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(value, "again");
+    let _: () = redis::cmd("FUNCTION")
+        .arg("FLUSH")
+        .arg("ASYNC")
+        .query(&mut con)
+        .unwrap();
+    let listed: Vec<redis::Value> = redis::cmd("FUNCTION").arg("LIST").query(&mut con).unwrap();
+    assert!(listed.is_empty());
+}
+
+#[test]
+fn should_set_and_get_with_expiry() {
+    let mut con = connect();
+
+    let _: () = con.set("key", "value").unwrap();
+    let value: String = con.get("key").unwrap();
+    assert_eq!(value, "value");
+
+    let _: () = redis::cmd("SET")
+        .arg("short")
+        .arg("lived")
+        .arg("PX")
+        .arg(50)
+        .query(&mut con)
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let value: Option<String> = con.get("short").unwrap();
+    assert_eq!(value, None);
+}
+
+#[test]
+fn should_manage_sets() {
+    let mut con = connect();
+
+    let added: i32 = con.sadd("set", &["a", "b", "c"]).unwrap();
+    assert_eq!(added, 3);
+    let moved: i32 = con.smove("set", "other", "a").unwrap();
+    assert_eq!(moved, 1);
+
+    let mut members: Vec<String> = con.smembers("set").unwrap();
+    members.sort();
+    assert_eq!(members, vec!["b", "c"]);
+    let popped: Vec<String> = redis::cmd("SPOP")
+        .arg("set")
+        .arg(5)
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(popped.len(), 2);
+    let card: i32 = con.scard("set").unwrap();
+    assert_eq!(card, 0);
+}
+
+#[test]
+fn should_apply_concurrent_writes_atomically() {
+    let addr = start_server();
+    let writers: Vec<_> = (0..8)
+        .map(|writer| {
+            let addr = addr.clone();
+            thread::spawn(move || {
+                let mut con = connect_to(&addr);
+                for member in 0..100 {
+                    let _: i32 = con.sadd("set", format!("{}-{}", writer, member)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let mut con = connect_to(&addr);
+    let card: i32 = con.scard("set").unwrap();
+    assert_eq!(card, 800);
+}
+
+#[test]
+fn should_order_sorted_sets() {
+    let mut con = connect();
+
+    let added: i32 = con
+        .zadd_multiple("zset", &[(2, "b"), (1, "a"), (3, "c")])
+        .unwrap();
+    assert_eq!(added, 3);
+
+    let members: Vec<(String, f64)> = con.zrange_withscores("zset", 0, -1).unwrap();
+    assert_eq!(
+        members,
+        vec![
+            ("a".to_string(), 1.0),
+            ("b".to_string(), 2.0),
+            ("c".to_string(), 3.0)
+        ]
+    );
+}
+
+#[test]
+fn should_report_object_encodings() {
+    let mut con = connect();
+    let _: () = con.set("int", 12).unwrap();
+    let _: () = con.set("text", "value").unwrap();
+    let _: i32 = con.sadd("ints", &[1, 2, 3]).unwrap();
+    let _: i32 = con.sadd("mixed", &["1", "a"]).unwrap();
+    let _: i32 = con.sadd("large", (0..200).collect::<Vec<_>>()).unwrap();
+    let _: i32 = con.sadd("large", "a").unwrap();
+    let _: i32 = con.zadd("zset", "a", 1).unwrap();
+    let _: i32 = con.zadd("long", "a".repeat(100), 1).unwrap();
+
+    for (key, expected) in [
+        ("int", Some("int")),
+        ("text", Some("embstr")),
+        ("ints", Some("intset")),
+        ("mixed", Some("listpack")),
+        ("large", Some("hashtable")),
+        ("zset", Some("listpack")),
+        ("long", Some("skiplist")),
+        ("missing", None),
+    ] {
+        let encoding: Option<String> = redis::cmd("OBJECT")
+            .arg("ENCODING")
+            .arg(key)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(encoding.as_deref(), expected, "{}", key);
+    }
+}
+
+#[test]
+fn should_debug_objects_and_sleep() {
+    let mut con = connect();
+    let _: i32 = con.sadd("set", &[1, 2, 3]).unwrap();
+
+    let object: String = redis::cmd("DEBUG")
+        .arg("OBJECT")
+        .arg("set")
+        .query(&mut con)
+        .unwrap();
+    assert!(
+        object.contains(" encoding:intset serializedlength:"),
+        "{}",
+        object
+    );
+    let missing: redis::RedisResult<String> = redis::cmd("DEBUG")
+        .arg("OBJECT")
+        .arg("missing")
+        .query(&mut con);
+    assert!(missing.is_err());
+
+    let started = Instant::now();
+    let _: () = redis::cmd("DEBUG")
+        .arg("SLEEP")
+        .arg(0.1)
+        .query(&mut con)
+        .unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(100));
+    for enabled in [0, 1] {
+        let _: () = redis::cmd("DEBUG")
+            .arg("SET-ACTIVE-EXPIRE")
+            .arg(enabled)
+            .query(&mut con)
+            .unwrap();
+    }
+    // Requests still go through once acknowledged right away.
+    let _: () = redis::cmd("DEBUG")
+        .arg("QUICKACK")
+        .arg(1)
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(con.scard::<_, i32>("set").unwrap(), 3);
+}
+
+#[test]
+fn should_load_an_rdb_file_into_the_running_server() {
+    let dir = std::env::temp_dir().join(format!("loadrdb-{}", std::process::id()));
+    let saved = RdbStorage::new(dir.to_str().unwrap(), "dump.rdb");
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        for key in ["a", "b"] {
+            let value = Value {
+                value: Data::String(b"saved".to_vec()),
+                expiry: None,
+            };
+            saved.set(key.to_string(), value).await;
+        }
+        saved.save().await.unwrap();
+    });
+    let path = dir.join("dump.rdb");
+
+    let mut con = connect();
+    let _: () = con.set("a", "live").unwrap();
+    let _: () = con.set("c", "live").unwrap();
+    let loaded: i32 = redis::cmd("DEBUG")
+        .arg("LOADRDB")
+        .arg(path.to_str().unwrap())
+        .arg("MERGE")
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(loaded, 2);
+    for (key, expected) in [("a", "saved"), ("b", "saved"), ("c", "live")] {
+        assert_eq!(con.get::<_, String>(key).unwrap(), expected);
+    }
+
+    let _: () = con.set("c", "live").unwrap();
+    let _: i32 = redis::cmd("DEBUG")
+        .arg("LOADRDB")
+        .arg(path.to_str().unwrap())
+        .query(&mut con)
+        .unwrap();
+    let missing: redis::RedisResult<i32> = redis::cmd("DEBUG")
+        .arg("LOADRDB")
+        .arg(dir.join("missing.rdb").to_str().unwrap())
+        .query(&mut con);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(con.get::<_, Option<String>>("c").unwrap(), None);
+    assert!(missing.is_err());
+}
+
+#[test]
+fn should_wake_blocked_pop_on_write() {
+    let addr = start_server();
+    let mut blocked = connect_to(&addr);
+    let mut writer = connect_to(&addr);
+
+    let waiter = thread::spawn(move || -> (String, String, f64) {
+        redis::cmd("BZPOPMIN")
+            .arg("zset")
+            .arg(0)
+            .query(&mut blocked)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(50));
+    let _: i32 = writer.zadd("zset", "a", 1).unwrap();
+
+    assert_eq!(
+        waiter.join().unwrap(),
+        ("zset".to_string(), "a".to_string(), 1.0)
+    );
+}
+
+#[test]
+fn should_time_out_blocked_pop() {
+    let mut con = connect();
+
+    let popped: Option<(String, String, f64)> = redis::cmd("BZPOPMAX")
+        .arg("zset")
+        .arg(0.05)
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(popped, None);
+}
+
+#[test]
+fn should_treat_strings_as_bitmaps() {
+    let mut con = connect();
+
+    let previous: i32 = con.setbit("bits", 9, true).unwrap();
+    assert_eq!(previous, 0);
+    let bit: i32 = con.getbit("bits", 9).unwrap();
+    assert_eq!(bit, 1);
+
+    let _: () = con.set("text", "foobar").unwrap();
+    let count: i32 = con.bitcount("text").unwrap();
+    assert_eq!(count, 26);
+    let len: i32 = redis::cmd("BITOP")
+        .arg(&["OR", "both", "bits", "text"])
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(len, 6);
+    let position: i32 = redis::cmd("BITPOS")
+        .arg("both")
+        .arg(1)
+        .arg(1)
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(position, 9);
+}
+
+#[test]
+fn should_search_geospatial_members() {
+    let mut con = connect();
+
+    let added: i32 = redis::cmd("GEOADD")
+        .arg("Sicily")
+        .arg(&["13.361389", "38.115556", "Palermo"])
+        .arg(&["15.087269", "37.502669", "Catania"])
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(added, 2);
+
+    let distance: String = redis::cmd("GEODIST")
+        .arg(&["Sicily", "Palermo", "Catania", "km"])
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(distance, "166.2742");
+    let positions: Vec<Option<Vec<f64>>> = redis::cmd("GEOPOS")
+        .arg(&["Sicily", "Palermo", "Rome"])
+        .query(&mut con)
+        .unwrap();
+    assert!((positions[0].as_ref().unwrap()[0] - 13.361389).abs() < 1e-5);
+    assert_eq!(positions[1], None);
+
+    let found: Vec<Vec<String>> = redis::cmd("GEOSEARCH")
+        .arg(&["Sicily", "FROMLONLAT", "15", "37", "BYRADIUS", "200", "km"])
+        .arg(&["ASC", "WITHDIST"])
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(
+        found,
+        vec![vec!["Catania", "56.4413"], vec!["Palermo", "190.4424"]]
+    );
+    let found: Vec<String> = redis::cmd("GEOSEARCH")
+        .arg(&[
+            "Sicily",
+            "FROMMEMBER",
+            "Palermo",
+            "BYBOX",
+            "100",
+            "100",
+            "km",
+        ])
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(found, vec!["Palermo"]);
+}
+
+#[test]
+fn should_count_distinct_elements_with_hyperloglogs() {
+    let mut con = connect();
+
+    let changed: i32 = con.pfadd("visits", &["a", "b", "c"]).unwrap();
+    assert_eq!(changed, 1);
+    let changed: i32 = con.pfadd("visits", "a").unwrap();
+    assert_eq!(changed, 0);
+    let count: i32 = con.pfcount("visits").unwrap();
+    assert_eq!(count, 3);
+
+    let _: i32 = con.pfadd("more", &["c", "d"]).unwrap();
+    let count: i32 = con.pfcount(&["visits", "more"]).unwrap();
+    assert_eq!(count, 4);
+    let _: () = redis::cmd("PFMERGE")
+        .arg(&["all", "visits", "more"])
+        .query(&mut con)
+        .unwrap();
+    let count: i32 = con.pfcount("all").unwrap();
+    assert_eq!(count, 4);
+
+    let _: () = con.set("text", "foobar").unwrap();
+    let rejected: redis::RedisResult<i32> = con.pfcount("text");
+    assert!(rejected.is_err());
+}
+
+#[test]
+fn should_append_and_range_streams() {
+    let mut con = connect();
+
+    for (id, value) in [("1-1", "a"), ("1-2", "b"), ("2-0", "c")] {
+        let added: String = redis::cmd("XADD")
+            .arg("stream")
+            .arg(id)
+            .arg("field")
+            .arg(value)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(added, id);
+    }
+    let rejected: redis::RedisResult<String> = redis::cmd("XADD")
+        .arg("stream")
+        .arg("1-5")
+        .arg("field")
+        .arg("d")
+        .query(&mut con);
+    assert!(rejected.is_err());
+
+    let len: i32 = redis::cmd("XLEN").arg("stream").query(&mut con).unwrap();
+    assert_eq!(len, 3);
+    let entries: Vec<Vec<redis::Value>> = redis::cmd("XREVRANGE")
+        .arg("stream")
+        .arg("+")
+        .arg("(1-1")
+        .query(&mut con)
+        .unwrap();
+    let entries: Vec<(String, Vec<String>)> = entries
+        .iter()
+        .map(|entry| {
+            (
+                redis::from_redis_value(&entry[0]).unwrap(),
+                redis::from_redis_value(&entry[1]).unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        entries,
+        vec![
+            (
+                "2-0".to_string(),
+                vec!["field".to_string(), "c".to_string()]
+            ),
+            (
+                "1-2".to_string(),
+                vec!["field".to_string(), "b".to_string()]
+            ),
+        ]
+    );
+}
+
+#[test]
+fn should_deliver_streams_to_consumer_groups() {
+    let addr = start_server();
+    let mut blocked = connect_to(&addr);
+    let mut writer = connect_to(&addr);
+
+    let _: () = redis::cmd("XGROUP")
+        .arg(&["CREATE", "stream", "group", "$", "MKSTREAM"])
+        .query(&mut writer)
+        .unwrap();
+    let waiter = thread::spawn(move || -> Vec<redis::Value> {
+        redis::cmd("XREADGROUP")
+            .arg(&[
+                "GROUP", "group", "alice", "BLOCK", "0", "STREAMS", "stream", ">",
+            ])
+            .query(&mut blocked)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(50));
+    let _: String = redis::cmd("XADD")
+        .arg(&["stream", "1-0", "field", "value"])
+        .query(&mut writer)
+        .unwrap();
+    assert_eq!(waiter.join().unwrap().len(), 1);
+
+    let pending: (i32, String, String, Vec<Vec<String>>) = redis::cmd("XPENDING")
+        .arg(&["stream", "group"])
+        .query(&mut writer)
+        .unwrap();
+    assert_eq!(
+        pending,
+        (
+            1,
+            "1-0".to_string(),
+            "1-0".to_string(),
+            vec![vec!["alice".to_string(), "1".to_string()]]
+        )
+    );
+    let acked: i32 = redis::cmd("XACK")
+        .arg(&["stream", "group", "1-0"])
+        .query(&mut writer)
+        .unwrap();
+    assert_eq!(acked, 1);
+}
+
+#[test]
+fn should_reject_wrong_type() {
+    let mut con = connect();
+
+    let _: () = con.set("key", "value").unwrap();
+    let result: redis::RedisResult<i32> = con.sadd("key", "member");
+    assert_eq!(result.unwrap_err().code(), Some("WRONGTYPE"));
+}
+
+#[test]
+fn should_drop_clients_trickling_a_request() {
+    let addr = start_configured_server(|server| {
+        server.with_request_timeout(Some(Duration::from_millis(100)))
+    });
+
+    let mut slow = TcpStream::connect(&addr).unwrap();
+    slow.write_all(b"*3\r\n$3\r\nSET\r\n").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    let _ = slow.write_all(b"$1\r\nk\r\n$1\r\nv\r\n");
+    let mut reply = Vec::new();
+    let _ = slow.read_to_end(&mut reply);
+    assert!(reply.is_empty());
+
+    let mut con = connect_to(&addr);
+    let info: String = redis::cmd("INFO").arg("stats").query(&mut con).unwrap();
+    assert!(!info.contains("slow_read_disconnections:0\r\n"));
+
+    // By default they take as long as they need.
+    let mut patient = TcpStream::connect(start_server()).unwrap();
+    patient.write_all(b"*3\r\n$3\r\nSET\r\n").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    patient.write_all(b"$1\r\nk\r\n$1\r\nv\r\n").unwrap();
+    assert_eq!(
+        read_until(&mut patient, |reply| reply.ends_with("\r\n")),
+        "+OK\r\n"
+    );
+}
+
+#[test]
+fn should_drop_idle_clients() {
+    let addr = start_configured_server(|server| {
+        server.with_idle_timeout(Some(Duration::from_millis(100)))
+    });
+
+    let mut idle = TcpStream::connect(&addr).unwrap();
+    idle.write_all(b"PING\r\n").unwrap();
+    assert_eq!(
+        read_until(&mut idle, |reply| reply.ends_with("\r\n")),
+        "+PONG\r\n"
+    );
+    // Subscribers and blocked clients are waiting on others instead.
+    let mut subscriber = TcpStream::connect(&addr).unwrap();
+    subscriber.write_all(b"SUBSCRIBE news\r\n").unwrap();
+    read_until(&mut subscriber, |reply| reply.ends_with(":1\r\n"));
+    let mut blocked = TcpStream::connect(&addr).unwrap();
+    blocked.write_all(b"BZPOPMIN zset 0\r\n").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    let mut rest = Vec::new();
+    idle.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    let mut con = connect_to(&addr);
+    let receivers: usize = con.publish("news", "hi").unwrap();
+    assert_eq!(receivers, 1);
+    let _: () = con.zadd("zset", "a", 1).unwrap();
+    let popped = read_until(&mut blocked, |reply| reply.ends_with("\r\n1\r\n"));
+    assert!(popped.starts_with("*3\r\n"), "{}", popped);
+}
+
+#[test]
+fn should_refuse_clients_over_the_limit() {
+    let addr = start_configured_server(|server| server.with_max_clients(1));
+    // Let the probe connection made while starting the server go away.
+    thread::sleep(Duration::from_millis(100));
+
+    let mut first = TcpStream::connect(&addr).unwrap();
+    first.write_all(b"PING\r\n").unwrap();
+    assert_eq!(
+        read_until(&mut first, |reply| reply.ends_with("\r\n")),
+        "+PONG\r\n"
+    );
+
+    let mut second = TcpStream::connect(&addr).unwrap();
+    let mut reply = String::new();
+    second.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "-ERR max number of clients reached\r\n");
+
+    drop(first);
+    thread::sleep(Duration::from_millis(100));
+    let mut con = connect_to(&addr);
+    let info: String = redis::cmd("INFO").arg("stats").query(&mut con).unwrap();
+    assert!(!info.contains("rejected_connections:0\r\n"));
+}
+
+#[test]
+fn should_reconfigure_the_server_while_it_runs() {
+    let addr = start_server();
+    let mut con = connect_to(&addr);
+    let get = |con: &mut Connection, patterns: &[&str]| -> Vec<String> {
+        redis::cmd("CONFIG")
+            .arg("GET")
+            .arg(patterns)
+            .query(con)
+            .unwrap()
+    };
+    let set = |con: &mut Connection, changes: &[&str]| {
+        redis::cmd("CONFIG")
+            .arg("SET")
+            .arg(changes)
+            .query::<()>(con)
+    };
+
+    assert_eq!(
+        get(&mut con, &["maxmemory*"]),
+        ["maxmemory", "0", "maxmemory-policy", "noeviction"]
+    );
+    assert_eq!(
+        get(&mut con, &["TIMEOUT", "save", "time*"]),
+        ["save", "3600 1 300 100 60 10000", "timeout", "0"]
+    );
+    assert_eq!(
+        get(&mut con, &["slave-read-only"]),
+        ["slave-read-only", "yes"]
+    );
+    assert!(get(&mut con, &["nosuch*"]).is_empty());
+
+    set(
+        &mut con,
+        &[
+            "maxmemory",
+            "1mb",
+            "maxmemory-policy",
+            "allkeys-lru",
+            "save",
+            "",
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        get(&mut con, &["maxmemory*", "save"]),
+        [
+            "maxmemory",
+            "1048576",
+            "maxmemory-policy",
+            "allkeys-lru",
+            "save",
+            ""
+        ]
+    );
+
+    // Nothing changes unless everything can.
+    let err = set(
+        &mut con,
+        &["maxmemory", "2mb", "maxmemory-policy", "sometimes"],
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("argument 'maxmemory-policy'"));
+    let err = set(&mut con, &["maxmemory", "2mb", "port", "1"]).unwrap_err();
+    assert!(err.to_string().contains("can't set immutable config"));
+    let err = set(&mut con, &["nosuch", "1"]).unwrap_err();
+    assert!(err.to_string().contains("Unknown option"));
+    assert_eq!(get(&mut con, &["maxmemory"]), ["maxmemory", "1048576"]);
+    let rewrite: redis::RedisResult<String> = redis::cmd("CONFIG").arg("REWRITE").query(&mut con);
+    assert!(rewrite
+        .unwrap_err()
+        .to_string()
+        .contains("running without a config file"));
+
+    // Clients connecting from then on are held to the new limit.
+    set(&mut con, &["maxclients", "1"]).unwrap();
+    let mut refused = TcpStream::connect(&addr).unwrap();
+    let mut reply = String::new();
+    refused.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "-ERR max number of clients reached\r\n");
+}
+
+#[test]
+fn should_reject_oversized_requests() {
+    let addr = start_configured_server(|server| {
+        server.with_limits(Limits {
+            max_bulk_len: 16,
+            ..Limits::default()
+        })
+    });
+
+    let mut client = TcpStream::connect(&addr).unwrap();
+    client.write_all(b"*2\r\n$4\r\nECHO\r\n$17\r\n").unwrap();
+    let mut reply = String::new();
+    client.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "-ERR Protocol error: invalid bulk length\r\n");
+
+    let mut client = TcpStream::connect(&addr).unwrap();
+    client.write_all(b"*1048577\r\n").unwrap();
+    let mut reply = String::new();
+    client.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "-ERR Protocol error: invalid multibulk length\r\n");
+}
+
+#[test]
+fn should_reject_arrays_nested_too_deep() {
+    let addr = start_server();
+    let mut client = TcpStream::connect(&addr).unwrap();
+    client.write_all(&b"*1\r\n".repeat(1000)).unwrap();
+    let mut reply = String::new();
+    client.read_to_string(&mut reply).unwrap();
+    assert_eq!(
+        reply,
+        "-ERR Protocol error: too deeply nested multibulk\r\n"
+    );
+    // The server is still up for everyone else.
+    let pong: String = redis::cmd("PING").query(&mut connect_to(&addr)).unwrap();
+    assert_eq!(pong, "PONG");
+}
+
+#[test]
+fn should_drop_clients_over_output_limits() {
+    let addr = start_configured_server(|server| {
+        server.with_output_limits(OutputLimits {
+            normal: OutputLimit {
+                hard: 1024 * 1024,
+                ..OutputLimit::default()
+            },
+            ..OutputLimits::default()
+        })
+    });
+    let mut con = connect_to(&addr);
+    let _: () = con.set("small", "v").unwrap();
+    let _: () = con.set("big", "x".repeat(2 * 1024 * 1024)).unwrap();
+
+    // The reply is dropped with the client rather than queued.
+    let mut client = TcpStream::connect(&addr).unwrap();
+    client.write_all(b"GET big\r\n").unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).unwrap();
+    assert!(reply.is_empty());
+
+    let info: String = redis::cmd("INFO").arg("stats").query(&mut con).unwrap();
+    assert!(!info.contains("client_output_buffer_limit_disconnections:0\r\n"));
+    let reply: String = con.get("small").unwrap();
+    assert_eq!(reply, "v");
+}
+
+#[test]
+fn should_drop_clients_over_the_query_buffer_limit() {
+    let addr = start_server();
+    let mut con = connect_to(&addr);
+    let refused = redis::cmd("CONFIG")
+        .arg("SET")
+        .arg("client-query-buffer-limit")
+        .arg("1kb")
+        .query::<()>(&mut con);
+    assert!(refused.is_err());
+    let _: () = redis::cmd("CONFIG")
+        .arg("SET")
+        .arg("client-query-buffer-limit")
+        .arg("1mb")
+        .query(&mut con)
+        .unwrap();
+
+    // Requests that fit are served however their arguments are split.
+    let mut client = TcpStream::connect(&addr).unwrap();
+    let value = "x".repeat(600 * 1024);
+    let request = format!("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$614400\r\n{}\r\n", value);
+    client.write_all(request.as_bytes()).unwrap();
+    assert_eq!(
+        read_until(&mut client, |reply| reply.ends_with("\r\n")),
+        "+OK\r\n"
+    );
+
+    client
+        .write_all(format!("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$2097152\r\n{}", value).as_bytes())
+        .unwrap();
+    let _ = client.write_all(value.as_bytes());
+    let mut reply = Vec::new();
+    let _ = client.read_to_end(&mut reply);
+    assert!(reply.is_empty());
+
+    let info: String = redis::cmd("INFO").arg("stats").query(&mut con).unwrap();
+    assert!(!info.contains("client_query_buffer_limit_disconnections:0\r\n"));
+}
+
+#[test]
+fn should_hold_writes_while_paused() {
+    let addr = start_server();
+    let mut con = connect_to(&addr);
+    let _: () = con.set("key", "before").unwrap();
+    let reply: String = redis::cmd("CLIENT")
+        .arg("PAUSE")
+        .arg(10000)
+        .arg("WRITE")
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(reply, "OK");
+
+    let writer = thread::spawn({
+        let addr = addr.clone();
+        move || {
+            let mut con = connect_to(&addr);
+            let started = Instant::now();
+            let _: () = con.set("key", "after").unwrap();
+            started.elapsed()
+        }
+    });
+    thread::sleep(Duration::from_millis(200));
+    // Reads go on while writes wait.
+    let reply: String = con.get("key").unwrap();
+    assert_eq!(reply, "before");
+
+    let reply: String = redis::cmd("CLIENT").arg("UNPAUSE").query(&mut con).unwrap();
+    assert_eq!(reply, "OK");
+    let waited = writer.join().unwrap();
+    assert!(waited >= Duration::from_millis(200) && waited < Duration::from_secs(5));
+    let reply: String = con.get("key").unwrap();
+    assert_eq!(reply, "after");
+}
+
+#[test]
+fn should_save_in_the_background() {
+    let mut con = connect();
+    let before: i64 = redis::cmd("LASTSAVE").query(&mut con).unwrap();
+    let _: () = con.set("key", "value").unwrap();
+    let info: String = redis::cmd("INFO")
+        .arg("persistence")
+        .query(&mut con)
+        .unwrap();
+    assert!(info.contains("rdb_changes_since_last_save:1\r\n"));
+
+    let started: String = redis::cmd("BGSAVE").query(&mut con).unwrap();
+    assert_eq!(started, "Background saving started");
+    let after: i64 = redis::cmd("LASTSAVE").query(&mut con).unwrap();
+    assert!(after >= before);
+    let info: String = redis::cmd("INFO")
+        .arg("persistence")
+        .query(&mut con)
+        .unwrap();
+    assert!(info.contains("rdb_changes_since_last_save:0\r\n"));
+}
+
+#[test]
+fn should_log_writes_to_the_append_only_file() {
+    let path = std::env::temp_dir().join(format!("integration-{}.aof", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let aof = Arc::new(Aof::open(&path, AppendFsync::Always).unwrap());
+    let addr = start_configured_server(move |server| server.with_aof(aof));
+    let mut con = connect_to(&addr);
+    let _: () = redis::cmd("SET")
+        .arg("key")
+        .arg("value")
+        .arg("EX")
+        .arg(100)
+        .query(&mut con)
+        .unwrap();
+    let id: String = redis::cmd("XADD")
+        .arg("stream")
+        .arg("*")
+        .arg("field")
+        .arg("value")
+        .query(&mut con)
+        .unwrap();
+    let _: String = con.get("key").unwrap();
+
+    let storage = InMemoryStorage::new();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let replayed = runtime.block_on(aof::replay(&path, &storage));
+    let logged = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    // Reads are left out, and what depended on the time pinned down.
+    assert_eq!(replayed.unwrap(), 2);
+    assert!(logged.contains("PXAT") && logged.contains(&id));
+    let key = runtime.block_on(storage.get("key")).unwrap();
+    assert!(key.expiry.is_some());
+}
+
+#[test]
+fn should_rewrite_the_append_only_file() {
+    let mut off = connect();
+    let refused: redis::RedisResult<String> = redis::cmd("BGREWRITEAOF").query(&mut off);
+    assert!(refused.is_err());
+
+    let path = std::env::temp_dir().join(format!("rewrite-{}.aof", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let aof = Arc::new(Aof::open(&path, AppendFsync::Always).unwrap());
+    let addr = start_configured_server(move |server| server.with_aof(aof));
+    let mut con = connect_to(&addr);
+    let _: () = con.set("key", "old").unwrap();
+    let _: () = con.set("key", "new").unwrap();
+    let started: String = redis::cmd("BGREWRITEAOF").query(&mut con).unwrap();
+    assert_eq!(started, "Background append only file rewriting started");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !fs::read(&path).unwrap().starts_with(b"REDIS") {
+        assert!(Instant::now() < deadline, "rewrite never finished");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let _: () = con.sadd("set", "member").unwrap();
+    let info: String = redis::cmd("INFO")
+        .arg("persistence")
+        .query(&mut con)
+        .unwrap();
+    assert!(info.contains("aof_enabled:1\r\n"), "{}", info);
+    assert!(info.contains("aof_last_write_status:ok\r\n"), "{}", info);
+
+    let storage = InMemoryStorage::new();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let replayed = runtime.block_on(aof::replay(&path, &storage));
+    fs::remove_file(&path).unwrap();
+    // The two SETs became a key of the RDB preamble.
+    assert_eq!(replayed.unwrap(), 1);
+    let key = runtime.block_on(storage.get("key")).unwrap();
+    assert_eq!(key.value, Data::String(b"new".to_vec()));
+    assert!(runtime.block_on(storage.get("set")).is_some());
+}
+
+#[test]
+fn should_switch_the_append_only_file_on_and_off() {
+    let path = std::env::temp_dir().join(format!("switched-{}.aof", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let file = path.clone();
+    let addr = start_configured_server(move |server| server.with_aof_file(&file, true));
+    let mut con = connect_to(&addr);
+    let appendonly = |con: &mut Connection, value: &str| {
+        redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("appendonly")
+            .arg(value)
+            .query::<()>(con)
+    };
+    let _: () = con.set("before", "value").unwrap();
+    assert!(appendonly(&mut con, "maybe").is_err());
+    assert!(!path.exists());
+
+    // Started from the dataset as it stands, then appended to.
+    appendonly(&mut con, "yes").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !fs::read(&path).unwrap().starts_with(b"REDIS") {
+        assert!(Instant::now() < deadline, "rewrite never finished");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let _: () = con.set("while", "value").unwrap();
+    let on: Vec<String> = redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("appendonly")
+        .query(&mut con)
+        .unwrap();
+    assert_eq!(on, ["appendonly", "yes"]);
+    appendonly(&mut con, "no").unwrap();
+    let _: () = con.set("after", "value").unwrap();
+
+    let storage = InMemoryStorage::new();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let replayed = runtime.block_on(aof::replay(&path, &storage));
+    fs::remove_file(&path).unwrap();
+    assert_eq!(replayed.unwrap(), 1);
+    assert!(runtime.block_on(storage.get("before")).is_some());
+    assert!(runtime.block_on(storage.get("while")).is_some());
+    assert!(runtime.block_on(storage.get("after")).is_none());
+}
+
+#[test]
+fn should_count_hits_misses_and_commands() {
+    let mut con = connect();
+    let _: () = con.set("key", "value").unwrap();
+    // Other tests run alongside on the same counters, so they only ever
+    // grow by at least as much as this one made them.
+    let before = STATS.metrics();
+    let _: Option<String> = con.get("key").unwrap();
+    let _: Option<String> = con.get("missing").unwrap();
+    let _: Option<String> = con.get("missing").unwrap();
+    let after = STATS.metrics();
+    assert!(after.keyspace_hits > before.keyspace_hits);
+    assert!(after.keyspace_misses >= before.keyspace_misses + 2);
+    assert!(after.total_commands_processed >= before.total_commands_processed + 3);
+
+    let info: String = redis::cmd("INFO").arg("stats").query(&mut con).unwrap();
+    assert!(info.contains("keyspace_hits:"));
+    assert!(info.contains("keyspace_misses:"));
+    assert!(!info.contains("total_commands_processed:0\r\n"));
+}
+
+#[test]
+fn should_listen_on_every_address() {
+    let addrs = vec![free_addr(), free_addr()];
+    start_server_on(addrs.clone(), |server| server);
+
+    let mut first = connect_to(&addrs[0]);
+    let mut second = connect_to(&addrs[1]);
+    let _: () = first.set("key", "value").unwrap();
+    let value: String = second.get("key").unwrap();
+    assert_eq!(value, "value");
+}
+
+#[test]
+fn should_list_name_and_kill_clients() {
+    let addr = start_server();
+    let mut victim = connect_to(&addr);
+    let mut killer = connect_to(&addr);
+
+    let _: () = redis::cmd("CLIENT")
+        .arg("SETNAME")
+        .arg("victim")
+        .query(&mut victim)
+        .unwrap();
+    let name: String = redis::cmd("CLIENT")
+        .arg("GETNAME")
+        .query(&mut victim)
+        .unwrap();
+    assert_eq!(name, "victim");
+    let id: u64 = redis::cmd("CLIENT").arg("ID").query(&mut victim).unwrap();
+
+    let list: String = redis::cmd("CLIENT")
+        .arg("LIST")
+        .arg("ID")
+        .arg(id)
+        .query(&mut killer)
+        .unwrap();
+    assert!(list.starts_with(&format!("id={} ", id)));
+    assert!(list.contains(" name=victim "));
+    assert!(list.contains(" cmd=client "));
+    let info: String = redis::cmd("CLIENT").arg("INFO").query(&mut killer).unwrap();
+    assert!(!info.contains("name=victim"));
+
+    let killed: i64 = redis::cmd("CLIENT")
+        .arg("KILL")
+        .arg("ID")
+        .arg(id)
+        .query(&mut killer)
+        .unwrap();
+    assert_eq!(killed, 1);
+    assert!(redis::cmd("PING").query::<String>(&mut victim).is_err());
+    let result: redis::RedisResult<()> = redis::cmd("CLIENT")
+        .arg("KILL")
+        .arg("127.0.0.1:1")
+        .query(&mut killer);
+    assert_eq!(result.unwrap_err().detail(), Some("No such client"));
+}
+
+#[test]
+fn should_stop_listening_on_shutdown() {
+    let addr = start_server();
+    let mut con = connect_to(&addr);
+
+    let result: redis::RedisResult<()> = redis::cmd("SHUTDOWN").arg("MAYBE").query(&mut con);
+    assert!(result.is_err());
+    let mut raw = TcpStream::connect(&addr).unwrap();
+    raw.write_all(b"SHUTDOWN NOSAVE\r\n").unwrap();
+    let mut reply = Vec::new();
+    raw.read_to_end(&mut reply).unwrap();
+    assert!(reply.is_empty());
+
+    thread::sleep(Duration::from_millis(100));
+    assert!(TcpStream::connect(&addr).is_err());
+}
+
+#[test]
+fn should_share_the_port_between_acceptors() {
+    let addr = start_configured_server(|server| server.with_acceptors(4));
+
+    let mut clients: Vec<Connection> = (0..8).map(|_| connect_to(&addr)).collect();
+    for (i, con) in clients.iter_mut().enumerate() {
+        let _: () = con.set(format!("key{}", i), i).unwrap();
+    }
+    let value: usize = clients[0].get("key7").unwrap();
+    assert_eq!(value, 7);
+}
+
+#[test]
+fn should_serve_clients_over_tls() {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("resip-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+    fs::write(&cert_file, certified.cert.pem()).unwrap();
+    fs::write(&key_file, certified.key_pair.serialize_pem()).unwrap();
+    let acceptor = tls::acceptor(cert_file.to_str().unwrap(), key_file.to_str().unwrap()).unwrap();
+
+    let tls_addr = free_addr();
+    let server_tls_addr = tls_addr.clone();
+    start_configured_server(move |server| server.with_tls(&[server_tls_addr], acceptor));
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let session = ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+    let stream = TcpStream::connect(&tls_addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut client = StreamOwned::new(session, stream);
+
+    client
+        .write_all(b"*2\r\n$4\r\nECHO\r\n$6\r\nsecret\r\n")
+        .unwrap();
+    let mut reply = [0; 9];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"+secret\r\n");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn should_answer_pipelined_commands() {
+    let mut con = connect();
+
+    let (first, second): (i32, i32) = redis::pipe()
+        .sadd("set", "a")
+        .sadd("set", "b")
+        .query(&mut con)
+        .unwrap();
+    assert_eq!((first, second), (1, 1));
+}
+
+#[test]
+fn should_store_values_containing_protocol_bytes() {
+    let mut con = connect();
+
+    let value = "line one\r\nline two\0*1\r\n$4\r\nPING\r\n";
+    let _: () = con.set("raw", value).unwrap();
+    let stored: String = con.get("raw").unwrap();
+    assert_eq!(stored, value);
+}
+
+#[test]
+fn should_reject_malformed_requests() {
+    let addr = start_configured_server(|server| server);
+
+    let mut client = TcpStream::connect(&addr).unwrap();
+    client.write_all(b"*1\r\n$x\r\n").unwrap();
+    let mut reply = String::new();
+    client.read_to_string(&mut reply).unwrap();
+    assert!(reply.starts_with("-ERR Protocol error"));
+}
+
+#[test]
+fn should_switch_to_resp3_with_hello() {
+    let addr = start_server();
+
+    let mut client = TcpStream::connect(&addr).unwrap();
+    client
+        .write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n*2\r\n$3\r\nGET\r\n$7\r\nmissing\r\n")
+        .unwrap();
+
+    let reply = read_until(&mut client, |reply| reply.ends_with("_\r\n"));
+    assert!(reply.starts_with("%7\r\n$6\r\nserver\r\n$5\r\nredis\r\n"));
+    assert!(reply.contains("$5\r\nproto\r\n:3\r\n"));
+}
+
+#[test]
+fn should_accept_inline_commands() {
+    let addr = start_server();
+
+    let mut client = TcpStream::connect(&addr).unwrap();
+    client
+        .write_all(b"PING\r\nSET greeting \"hello world\"\r\n\r\nGET greeting\n")
+        .unwrap();
+
+    let expected = "+PONG\r\n+OK\r\n$11\r\nhello world\r\n";
+    let reply = read_until(&mut client, |reply| reply.len() >= expected.len());
+    assert_eq!(reply, expected);
+}
+
+#[test]
+fn should_tell_null_arrays_from_null_bulk_strings() {
+    let addr = start_server();
+
+    let mut client = TcpStream::connect(&addr).unwrap();
+    client
+        .write_all(
+            b"*3\r\n$8\r\nBZPOPMIN\r\n$4\r\nnone\r\n$4\r\n0.01\r\n\
+              *2\r\n$3\r\nGET\r\n$4\r\nnone\r\n",
+        )
+        .unwrap();
+    let reply = read_until(&mut client, |reply| reply.len() >= 10);
+    assert_eq!(reply, "*-1\r\n$-1\r\n");
+}
+
+#[test]
+fn should_assemble_requests_larger_than_a_read() {
+    let addr = start_server();
+
+    let value = "0123456789abcdef".repeat(256 * 1024);
+    let request = format!(
+        "*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n${}\r\n{}\r\n",
+        value.len(),
+        value
+    );
+    let mut client = TcpStream::connect(&addr).unwrap();
+    for chunk in request.as_bytes().chunks(1000) {
+        client.write_all(chunk).unwrap();
+    }
+    let reply = read_until(&mut client, |reply| reply.len() >= 5);
+    assert_eq!(reply, "+OK\r\n");
+
+    let mut con = connect_to(&addr);
+    let stored: String = con.get("big").unwrap();
+    assert_eq!(stored, value);
+}
+
+#[test]
+fn should_answer_a_pipelined_batch_before_blocking() {
+    let addr = start_server();
+
+    let mut client = TcpStream::connect(&addr).unwrap();
+    let batch =
+        "*1\r\n$4\r\nPING\r\n".repeat(100) + "*3\r\n$8\r\nBZPOPMIN\r\n$4\r\nnone\r\n$1\r\n5\r\n";
+    client.write_all(batch.as_bytes()).unwrap();
+
+    // The replies to the PINGs must not wait for the blocked pop.
+    let started = Instant::now();
+    let reply = read_until(&mut client, |reply| reply.len() >= 700);
+    assert_eq!(reply, "+PONG\r\n".repeat(100));
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
@@ -0,0 +1,413 @@
+#![cfg(feature = "integration")]
+
+//! Masters and replicas each run as their own process, as replication state
+//! is server-wide.
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use common::{eventually, Server};
+use redis::{Commands, Connection};
+
+mod common;
+
+fn info_replication(con: &mut Connection) -> String {
+    redis::cmd("INFO").arg("replication").query(con).unwrap()
+}
+
+#[test]
+fn should_send_the_dataset_to_new_replicas() {
+    let master = Server::start(&[]);
+    let mut to_master = master.connect();
+    let _: () = to_master.set("key", "value").unwrap();
+
+    let replica = Server::replica_of(&master);
+    let mut to_replica = replica.connect();
+    eventually(|| info_replication(&mut to_replica).contains("master_link_status:up"));
+    let value: Option<String> = to_replica.get("key").unwrap();
+    assert_eq!(value.as_deref(), Some("value"));
+
+    let info = info_replication(&mut to_master);
+    assert!(
+        info.contains("role:master\r\nconnected_slaves:1\r\n"),
+        "{}",
+        info
+    );
+    let listed = format!("slave0:ip=127.0.0.1,port={},", replica.port);
+    assert!(info.contains(&listed), "{}", info);
+    let replid = info_replication(&mut to_replica)
+        .lines()
+        .find_map(|line| line.strip_prefix("master_replid:").map(String::from));
+    assert!(info.contains(&format!("master_replid:{}", replid.unwrap())));
+
+    drop(replica);
+    eventually(|| info_replication(&mut to_master).contains("connected_slaves:0"));
+}
+
+fn offset(info: &str, field: &str) -> u64 {
+    info.lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn should_propagate_writes_to_replicas() {
+    let master = Server::start(&[]);
+    let mut to_master = master.connect();
+    let replica = Server::replica_of(&master);
+    let mut to_replica = replica.connect();
+    eventually(|| info_replication(&mut to_replica).contains("master_link_status:up"));
+
+    let _: () = to_master.set("key", "value").unwrap();
+    let _: () = to_master.sadd("set", &["a", "b"]).unwrap();
+    let _: Option<String> = to_master.get("key").unwrap();
+    eventually(|| {
+        to_replica
+            .get::<_, Option<String>>("key")
+            .unwrap()
+            .is_some()
+    });
+    eventually(|| to_replica.scard::<_, usize>("set").unwrap() == 2);
+
+    // Only the writes count: 33 bytes of SET and 37 of SADD.
+    let info = info_replication(&mut to_master);
+    assert_eq!(offset(&info, "master_repl_offset"), 70, "{}", info);
+    eventually(|| offset(&info_replication(&mut to_replica), "slave_repl_offset") == 70);
+}
+
+#[test]
+fn should_wait_for_replicas_to_acknowledge_writes() {
+    let master = Server::start(&[]);
+    let mut to_master = master.connect();
+    let replicas = [Server::replica_of(&master), Server::replica_of(&master)];
+    eventually(|| info_replication(&mut to_master).contains("connected_slaves:2"));
+
+    let _: () = to_master.set("key", "value").unwrap();
+    let wait = |con: &mut Connection, replicas: usize, timeout: u64| -> usize {
+        redis::cmd("WAIT")
+            .arg(replicas)
+            .arg(timeout)
+            .query(con)
+            .unwrap()
+    };
+    assert_eq!(wait(&mut to_master, 2, 0), 2);
+    // Asking for more replicas than follow times out with those that do.
+    let started = Instant::now();
+    assert_eq!(wait(&mut to_master, 3, 100), 2);
+    assert!(started.elapsed() >= Duration::from_millis(100));
+
+    let mut to_replica = replicas[0].connect();
+    let refused = redis::cmd("WAIT")
+        .arg(0)
+        .arg(0)
+        .query::<usize>(&mut to_replica)
+        .unwrap_err();
+    assert!(
+        refused.to_string().contains("replica instances"),
+        "{}",
+        refused
+    );
+}
+
+#[test]
+fn should_only_take_writes_from_the_master_on_replicas() {
+    let master = Server::start(&[]);
+    let mut to_master = master.connect();
+    let replica = Server::replica_of(&master);
+    let mut to_replica = replica.connect();
+    eventually(|| info_replication(&mut to_replica).contains("master_link_status:up"));
+
+    let refused = to_replica.set::<_, _, ()>("key", "mine").unwrap_err();
+    assert_eq!(refused.code(), Some("READONLY"));
+    let _: () = to_master.set("key", "value").unwrap();
+    eventually(|| to_replica.get::<_, Option<String>>("key").unwrap() == Some("value".to_string()));
+    // PFCOUNT only reads, even though it works the cardinality out.
+    let _: () = to_master.pfadd("hll", &["a", "b"]).unwrap();
+    eventually(|| to_replica.pfcount::<_, usize>("hll").unwrap() == 2);
+
+    let writable = Server::start(&[
+        "--replicaof",
+        &format!("127.0.0.1 {}", master.port),
+        "--replica-read-only",
+        "false",
+    ]);
+    let _: () = writable.connect().set("key", "mine").unwrap();
+}
+
+#[test]
+fn should_switch_roles_at_runtime() {
+    let master = Server::start(&[]);
+    let mut to_master = master.connect();
+    let _: () = to_master.set("key", "value").unwrap();
+    let server = Server::start(&[]);
+    let mut to_server = server.connect();
+    let _: () = to_server.set("other", "value").unwrap();
+
+    let replicaof = |con: &mut Connection, host: &str, port: &str| -> String {
+        redis::cmd("REPLICAOF")
+            .arg(host)
+            .arg(port)
+            .query(con)
+            .unwrap()
+    };
+    let port = master.port.to_string();
+    assert_eq!(replicaof(&mut to_server, "127.0.0.1", &port), "OK");
+    eventually(|| info_replication(&mut to_server).contains("master_link_status:up"));
+    assert_eq!(
+        replicaof(&mut to_server, "127.0.0.1", &port),
+        "OK Already connected to specified master"
+    );
+    let value: Option<String> = to_server.get("key").unwrap();
+    assert_eq!(value.as_deref(), Some("value"));
+    let other: Option<String> = to_server.get("other").unwrap();
+    assert_eq!(other, None);
+    assert!(info_replication(&mut to_master).contains("connected_slaves:1"));
+
+    assert_eq!(replicaof(&mut to_server, "NO", "ONE"), "OK");
+    assert!(info_replication(&mut to_server).contains("role:master"));
+    let _: () = to_server.set("key", "mine").unwrap();
+    eventually(|| info_replication(&mut to_master).contains("connected_slaves:0"));
+    let value: Option<String> = to_master.get("key").unwrap();
+    assert_eq!(value.as_deref(), Some("value"));
+}
+
+#[test]
+fn should_go_on_from_where_a_broken_link_left_off() {
+    let master = Server::start(&[]);
+    let mut to_master = master.connect();
+    let replica = Server::replica_of(&master);
+    let mut to_replica = replica.connect();
+    eventually(|| info_replication(&mut to_replica).contains("master_link_status:up"));
+    let _: () = to_master.set("before", "value").unwrap();
+
+    let clients: String = redis::cmd("CLIENT")
+        .arg("LIST")
+        .query(&mut to_master)
+        .unwrap();
+    let link = clients
+        .lines()
+        .find(|client| client.contains("cmd=psync"))
+        .and_then(|client| client.strip_prefix("id="))
+        .and_then(|client| client.split(' ').next())
+        .unwrap()
+        .to_string();
+    let killed: usize = redis::cmd("CLIENT")
+        .arg("KILL")
+        .arg("ID")
+        .arg(&link)
+        .query(&mut to_master)
+        .unwrap();
+    assert_eq!(killed, 1);
+    let _: () = to_master.set("after", "value").unwrap();
+
+    eventually(|| {
+        to_replica
+            .get::<_, Option<String>>("after")
+            .unwrap()
+            .is_some()
+    });
+    let stats: String = redis::cmd("INFO")
+        .arg("stats")
+        .query(&mut to_master)
+        .unwrap();
+    assert!(stats.contains("sync_full:1\r\n"), "{}", stats);
+    assert!(stats.contains("sync_partial_ok:1\r\n"), "{}", stats);
+    let before: Option<String> = to_replica.get("before").unwrap();
+    assert_eq!(before.as_deref(), Some("value"));
+}
+
+#[test]
+fn should_delete_keys_on_replicas_once_expired_on_the_master() {
+    let master = Server::start(&[]);
+    let mut to_master = master.connect();
+    let replica = Server::replica_of(&master);
+    let mut to_replica = replica.connect();
+    eventually(|| info_replication(&mut to_replica).contains("master_link_status:up"));
+
+    let _: () = redis::cmd("SET")
+        .arg("key")
+        .arg("value")
+        .arg("PX")
+        .arg(100)
+        .query(&mut to_master)
+        .unwrap();
+    let written = offset(&info_replication(&mut to_master), "master_repl_offset");
+    // 22 bytes of DEL, which the replica is sent too.
+    let deleted = written + 22;
+    eventually(|| offset(&info_replication(&mut to_master), "master_repl_offset") == deleted);
+    eventually(|| offset(&info_replication(&mut to_replica), "slave_repl_offset") == deleted);
+    // Gone from the replica without it ever expiring a key itself, not
+    // just hidden from readers.
+    eventually(|| to_replica.keys::<_, Vec<String>>("*").unwrap().is_empty());
+    eventually(|| {
+        let memory: String = redis::cmd("INFO")
+            .arg("memory")
+            .query(&mut to_replica)
+            .unwrap();
+        memory.contains("used_memory:0\r\n")
+    });
+    let stats: String = redis::cmd("INFO")
+        .arg("stats")
+        .query(&mut to_replica)
+        .unwrap();
+    assert!(stats.contains("expired_keys:0\r\n"), "{}", stats);
+    let stats: String = redis::cmd("INFO")
+        .arg("stats")
+        .query(&mut to_master)
+        .unwrap();
+    assert!(stats.contains("expired_keys:1\r\n"), "{}", stats);
+}
+
+fn read_until(client: &mut TcpStream, done: impl Fn(&str) -> bool) -> String {
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut reply = Vec::new();
+    let mut chunk = [0; 1024];
+    while !done(&String::from_utf8_lossy(&reply)) {
+        let n = client.read(&mut chunk).unwrap();
+        assert!(n > 0, "server closed the connection");
+        reply.extend_from_slice(&chunk[..n]);
+    }
+    String::from_utf8(reply).unwrap()
+}
+
+#[test]
+fn should_hide_expired_keys_on_replicas_until_the_master_deletes_them() {
+    let master = Server::start(&[]);
+    let mut to_master = master.connect();
+    // The master only deletes the key once it comes across it.
+    let _: () = redis::cmd("DEBUG")
+        .arg("SET-ACTIVE-EXPIRE")
+        .arg(0)
+        .query(&mut to_master)
+        .unwrap();
+    let replica = Server::replica_of(&master);
+    let mut to_replica = replica.connect();
+    eventually(|| info_replication(&mut to_replica).contains("master_link_status:up"));
+    let wait =
+        |con: &mut Connection| -> usize { redis::cmd("WAIT").arg(1).arg(0).query(con).unwrap() };
+    let used_memory = |con: &mut Connection| -> String {
+        let memory: String = redis::cmd("INFO").arg("memory").query(con).unwrap();
+        memory
+            .lines()
+            .find_map(|line| line.strip_prefix("used_memory:").map(String::from))
+            .unwrap()
+    };
+
+    let _: () = redis::cmd("SET")
+        .arg("key")
+        .arg("value")
+        .arg("PX")
+        .arg(100)
+        .query(&mut to_master)
+        .unwrap();
+    assert_eq!(wait(&mut to_master), 1);
+    std::thread::sleep(Duration::from_millis(150));
+    let mut tracking = TcpStream::connect(("127.0.0.1", replica.port)).unwrap();
+    tracking
+        .write_all(b"HELLO 3\r\nCLIENT TRACKING ON\r\nGET key\r\n")
+        .unwrap();
+    read_until(&mut tracking, |replies| replies.ends_with("+OK\r\n_\r\n"));
+    // Read as missing, but still there.
+    assert_ne!(used_memory(&mut to_replica), "0");
+
+    // Reading it on the master deletes it there, and WAIT waits for the
+    // replica to have deleted it too.
+    let read: Option<String> = to_master.get("key").unwrap();
+    assert_eq!(read, None);
+    assert_eq!(wait(&mut to_master), 1);
+    assert_eq!(used_memory(&mut to_replica), "0");
+    let pushed = read_until(&mut tracking, |replies| replies.ends_with("key\r\n"));
+    assert_eq!(pushed, ">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nkey\r\n");
+    let stats: String = redis::cmd("INFO")
+        .arg("stats")
+        .query(&mut to_replica)
+        .unwrap();
+    assert!(stats.contains("expired_keys:0\r\n"), "{}", stats);
+}
+
+#[test]
+fn should_replicate_what_scripts_wrote() {
+    let master = Server::start(&[]);
+    let mut to_master = master.connect();
+    let replica = Server::replica_of(&master);
+    let mut to_replica = replica.connect();
+    eventually(|| info_replication(&mut to_replica).contains("master_link_status:up"));
+
+    let _: () = to_master.sadd("set", &["a", "b", "c"]).unwrap();
+    // Replicas get what was popped rather than a pop of their own.
+    let popped: String = redis::cmd("EVAL")
+        .arg(
+            "redis.set_repl(redis.REPL_AOF)\n\
+              redis.call('SET', 'unseen', 'value')\n\
+              redis.set_repl(redis.REPL_ALL)\n\
+              return redis.call('SPOP', KEYS[1])",
+        )
+        .arg(1)
+        .arg("set")
+        .query(&mut to_master)
+        .unwrap();
+    let _: () = to_master.set("after", "value").unwrap();
+
+    eventually(|| {
+        let after: Option<String> = to_replica.get("after").unwrap();
+        after.is_some()
+    });
+    let left: Vec<String> = to_replica.smembers("set").unwrap();
+    assert_eq!(left.len(), 2);
+    assert!(!left.contains(&popped));
+    let unseen: Option<String> = to_replica.get("unseen").unwrap();
+    assert_eq!(unseen, None);
+}
+
+#[test]
+fn should_start_replicas_and_the_aof_over_after_loading_an_rdb_file() {
+    let dir = std::env::temp_dir().join(format!("loadrdb-replicated-{}", std::process::id()));
+    let (saved, logged) = (dir.join("saved"), dir.join("logged"));
+    std::fs::create_dir_all(&saved).unwrap();
+    std::fs::create_dir_all(&logged).unwrap();
+    let source = Server::start(&["--dir", saved.to_str().unwrap(), "--dbfilename", "dump.rdb"]);
+    let _: () = source.connect().set("key", "saved").unwrap();
+    let _: () = redis::cmd("SAVE").query(&mut source.connect()).unwrap();
+    let path = saved.join("dump.rdb");
+
+    let args = ["--dir", logged.to_str().unwrap(), "--appendonly", "yes"];
+    let master = Server::start(&args);
+    let mut to_master = master.connect();
+    let replica = Server::replica_of(&master);
+    let mut to_replica = replica.connect();
+    eventually(|| info_replication(&mut to_replica).contains("master_link_status:up"));
+    let _: () = to_master.set("replaced", "value").unwrap();
+    let loaded: i32 = redis::cmd("DEBUG")
+        .arg("LOADRDB")
+        .arg(path.to_str().unwrap())
+        .query(&mut to_master)
+        .unwrap();
+    assert_eq!(loaded, 1);
+    eventually(|| to_replica.get::<_, Option<String>>("key").unwrap() == Some("saved".to_string()));
+    assert_eq!(
+        to_replica.get::<_, Option<String>>("replaced").unwrap(),
+        None
+    );
+    let refused = redis::cmd("DEBUG")
+        .arg("LOADRDB")
+        .arg(path.to_str().unwrap())
+        .query::<i32>(&mut to_replica)
+        .unwrap_err();
+    assert_eq!(refused.code(), Some("READONLY"));
+
+    drop(master);
+    let restarted = Server::start(&args);
+    let mut to_restarted = restarted.connect();
+    let key: Option<String> = to_restarted.get("key").unwrap();
+    let replaced: Option<String> = to_restarted.get("replaced").unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(key.as_deref(), Some("saved"));
+    assert_eq!(replaced, None);
+}